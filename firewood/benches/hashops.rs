@@ -5,6 +5,7 @@
 
 use criterion::{criterion_group, criterion_main, profiler::Profiler, BatchSize, Criterion};
 use firewood::{
+    bench::replay::{replay, Recorder, Trace},
     db::{BatchOp, DbConfig},
     merkle::{Bincode, Merkle, TrieHash, TRIE_HASH_LEN},
     shale::{
@@ -14,7 +15,7 @@ use firewood::{
         LinearStore, ObjCache, Storable, StoredView,
     },
     storage::WalConfig,
-    v2::api::{Db, Proposal},
+    v2::api::{Db, DbView, Proposal},
 };
 use pprof::ProfilerGuard;
 use rand::{distributions::Alphanumeric, rngs::StdRng, Rng, SeedableRng};
@@ -77,7 +78,7 @@ fn bench_trie_hash(criterion: &mut Criterion) {
             b.iter(|| ZERO_HASH.serialize(&mut to).unwrap());
         })
         .bench_function("hydrate", |b| {
-            b.iter(|| TrieHash::deserialize(0, &store).unwrap());
+            b.iter(|| <TrieHash>::deserialize(0, &store).unwrap());
         });
 }
 
@@ -130,8 +131,9 @@ fn bench_merkle<const N: usize>(criterion: &mut Criterion) {
                 },
                 #[allow(clippy::unwrap_used)]
                 |(mut merkle, sentinel_addr, keys)| {
-                    keys.into_iter()
-                        .for_each(|key| merkle.insert(key, vec![b'v'], sentinel_addr).unwrap())
+                    for key in keys {
+                        merkle.insert(key, vec![b'v'], sentinel_addr).unwrap();
+                    }
                 },
                 BatchSize::SmallInput,
             );
@@ -187,10 +189,70 @@ fn bench_db<const N: usize>(criterion: &mut Criterion) {
         });
 }
 
+// Replays a trace of proofs and reads against a DB of N random keys. The trace is recorded from
+// the DB itself, unless FIREWOOD_REPLAY_TRACE names a trace file to replay instead, see
+// firewood::bench::replay
+fn bench_replay<const N: usize>(criterion: &mut Criterion) {
+    const KEY_LEN: usize = 8;
+    let mut rng = StdRng::seed_from_u64(1234);
+    let keys: Vec<Vec<u8>> = repeat_with(|| {
+        (&mut rng)
+            .sample_iter(&Alphanumeric)
+            .take(KEY_LEN)
+            .collect()
+    })
+    .take(N)
+    .collect();
+
+    #[allow(clippy::unwrap_used)]
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    #[allow(clippy::unwrap_used)]
+    let (rev, trace) = runtime.block_on(async {
+        let db_path = std::env::temp_dir().join("benchmark_replay_db");
+        let cfg = DbConfig::builder().truncate(true).build();
+        let db = firewood::db::Db::new(db_path, &cfg).await.unwrap();
+        let batch: Vec<_> = keys
+            .iter()
+            .map(|key| BatchOp::Put {
+                key,
+                value: vec![b'v'],
+            })
+            .collect();
+        Arc::new(db.propose(batch).await.unwrap())
+            .commit()
+            .await
+            .unwrap();
+        let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+
+        let trace = match std::env::var_os("FIREWOOD_REPLAY_TRACE") {
+            Some(path) => Trace::load(path).unwrap(),
+            None => {
+                let recorder = Recorder::new();
+                let recorded = recorder.view(rev.clone());
+                for key in keys.iter().step_by(10) {
+                    recorded.single_key_proof(key).await.unwrap();
+                    recorded.val(key).await.unwrap();
+                }
+                recorder.take()
+            }
+        };
+        (rev, trace)
+    });
+
+    #[allow(clippy::unwrap_used)]
+    criterion
+        .benchmark_group("Replay")
+        .sample_size(30)
+        .bench_function("trace", |b| {
+            b.to_async(&runtime)
+                .iter(|| async { replay(&*rev, &trace).await.unwrap() });
+        });
+}
+
 criterion_group! {
     name = benches;
     config = Criterion::default().with_profiler(FlamegraphProfiler::Init(100));
-    targets = bench_trie_hash, bench_merkle::<3>, bench_db::<100>
+    targets = bench_trie_hash, bench_merkle::<3>, bench_db::<100>, bench_replay::<10_000>
 }
 
 criterion_main!(benches);
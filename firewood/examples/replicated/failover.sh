@@ -0,0 +1,44 @@
+#!/usr/bin/env bash
+# Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+# See the file LICENSE.md for licensing terms.
+
+# Runs the primary and the standby of the replicated example as two processes, kills the
+# primary while it is committing, and checks that the standby took over.
+#
+#   firewood/examples/replicated/failover.sh [seconds the primary runs for]
+
+set -euo pipefail
+
+RUN_FOR=${1:-3}
+ADDR=${ADDR:-127.0.0.1:7400}
+WORK=$(mktemp -d)
+trap 'kill $(jobs -p) 2>/dev/null || true; rm -rf "$WORK"' EXIT
+
+cd "$(dirname "$0")/../.."
+cargo build --release --example replicated
+TARGET=$(cargo metadata --format-version 1 --no-deps | sed -n 's/.*"target_directory":"\([^"]*\)".*/\1/p')
+BIN="$TARGET/release/examples/replicated"
+
+"$BIN" standby --db "$WORK/standby" --listen "$ADDR" >"$WORK/standby.log" 2>&1 &
+STANDBY=$!
+# give the standby time to listen
+sleep 1
+
+"$BIN" primary --db "$WORK/primary" --standby "$ADDR" --commits 1000000 >"$WORK/primary.log" 2>&1 &
+PRIMARY=$!
+sleep "$RUN_FOR"
+
+echo "killing the primary"
+kill -9 "$PRIMARY"
+wait "$PRIMARY" 2>/dev/null || true
+cat "$WORK/primary.log"
+
+if ! wait "$STANDBY"; then
+    cat "$WORK/standby.log"
+    echo "the standby failed to take over" >&2
+    exit 1
+fi
+cat "$WORK/standby.log"
+grep -q "^promoted at" "$WORK/standby.log"
+grep -q "^standby done at" "$WORK/standby.log"
+echo "the standby took over"
@@ -0,0 +1,305 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+// A primary and a standby database on two nodes, and the failover from one to the other. This
+// is a reference for deployments that can't afford to lose their database with its node.
+//
+// - The standby starts empty and is bootstrapped with the latest revision of the primary,
+//   which the primary replicates over the connection with Db::replicate_to.
+// - The primary then ships the delta of every commit, which is the change feed of the standby,
+//   and the standby applies them with Standby::follow. Every delta is checked against the
+//   root hash of the primary, so the standby only ever holds revisions the primary had.
+// - Once the connection is lost, the standby stops following and is promoted, and takes over
+//   the writes where the primary left off.
+//
+// The two nodes are two processes, see failover.sh, which kills the primary half-way. The
+// failover is also run in a single process by the test of this example.
+
+mod wire;
+
+use clap::Parser;
+use firewood::{
+    db::{BatchOp, BatchSink, Db, DbConfig},
+    merkle::TrieHash,
+    v2::{
+        api::{self, Db as _, DbView as _, HashKey, Proposal as _},
+        standby::{Delta, Standby},
+    },
+};
+use futures::stream;
+use std::{
+    error::Error,
+    io::{self, BufReader, ErrorKind},
+    net::{TcpListener, TcpStream},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use tokio::{sync::mpsc, task::block_in_place};
+use wire::Frame;
+
+/// Commits of the primary before the standby connects, which it is bootstrapped with.
+const SEED_COMMITS: u32 = 100;
+/// Keys put by a commit.
+const KEYS_PER_COMMIT: u32 = 4;
+/// Pairs of a range sent to bootstrap the standby.
+const RANGE_LEN: usize = 256;
+
+#[derive(Parser, Debug)]
+enum Role {
+    /// Writes to its database, shipping every commit to the standby.
+    Primary {
+        #[arg(long, default_value = "replicated_primary")]
+        db: PathBuf,
+        /// The address the standby listens on.
+        #[arg(long, default_value = "127.0.0.1:7400")]
+        standby: String,
+        #[arg(long, default_value_t = 1000)]
+        commits: u32,
+        #[arg(long, default_value_t = 10)]
+        interval_ms: u64,
+    },
+    /// Follows the primary, and takes over the writes once the primary is lost.
+    Standby {
+        #[arg(long, default_value = "replicated_standby")]
+        db: PathBuf,
+        #[arg(long, default_value = "127.0.0.1:7400")]
+        listen: String,
+        /// Commits made once promoted.
+        #[arg(long, default_value_t = 100)]
+        commits: u32,
+    },
+}
+
+/// cargo run --release --example replicated -- standby, then
+/// cargo run --release --example replicated -- primary
+#[tokio::main(flavor = "multi_thread")]
+async fn main() -> Result<(), Box<dyn Error>> {
+    match Role::parse() {
+        Role::Primary {
+            db,
+            standby,
+            commits,
+            interval_ms,
+        } => {
+            let db = open(&db).await?;
+            for i in 0..SEED_COMMITS {
+                commit(&db, i).await?;
+            }
+            let stream = TcpStream::connect(&standby)?;
+            let root_hash = primary(
+                &db,
+                &stream,
+                SEED_COMMITS..SEED_COMMITS + commits,
+                Duration::from_millis(interval_ms),
+            )
+            .await?;
+            println!("primary done at {}", hex::encode(root_hash));
+        }
+        Role::Standby {
+            db,
+            listen,
+            commits,
+        } => {
+            let db = open(&db).await?;
+            let (stream, primary) = TcpListener::bind(&listen)?.accept()?;
+            println!("following the primary at {primary}");
+            let (db, applied) = follow(db, stream).await?;
+
+            let next = SEED_COMMITS + applied as u32;
+            println!(
+                "promoted at {}, taking over from commit {next}",
+                hex::encode(db.root_hash().await?)
+            );
+            for i in next..next + commits {
+                commit(&db, i).await?;
+            }
+            println!("standby done at {}", hex::encode(db.root_hash().await?));
+        }
+    }
+    Ok(())
+}
+
+async fn open(path: &Path) -> Result<Db, api::Error> {
+    let cfg = DbConfig::builder().truncate(true).build();
+    Db::new(path, &cfg).await
+}
+
+fn key(n: u32) -> Vec<u8> {
+    format!("key{n:08}").into_bytes()
+}
+
+/// Makes the `i`th commit of the example, which puts a few keys and deletes a key put by an
+/// earlier commit, and returns its root hash. Commits are the same whichever node makes them.
+async fn commit(db: &Db, i: u32) -> Result<HashKey, api::Error> {
+    let value = format!("commit {i}").into_bytes();
+    let mut batch: Vec<_> = (0..KEYS_PER_COMMIT)
+        .map(|k| BatchOp::Put {
+            key: key(i * KEYS_PER_COMMIT + k),
+            value: value.clone(),
+        })
+        .collect();
+    batch.push(BatchOp::Delete { key: key(i) });
+
+    Arc::new(db.propose(batch).await?).commit().await?;
+    db.root_hash().await
+}
+
+/// Writes the frames of a replication to the standby.
+struct WireSink<'a>(&'a TcpStream);
+
+#[async_trait::async_trait]
+impl BatchSink for WireSink<'_> {
+    async fn resume_after(&self) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        // the standby starts empty
+        Ok(None)
+    }
+
+    async fn write_range(
+        &self,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        block_in_place(|| wire::write_frame(&mut &*self.0, &Frame::Range(pairs)))?;
+        Ok(())
+    }
+
+    async fn finish(&self, root_hash: HashKey) -> Result<(), Box<dyn Error + Send + Sync>> {
+        block_in_place(|| wire::write_frame(&mut &*self.0, &Frame::Finish(root_hash)))?;
+        Ok(())
+    }
+}
+
+/// Bootstraps the standby at the other end of `stream` with the latest revision of `db`, then
+/// makes the `commits`, shipping each of them. Returns the root hash of the last one.
+async fn primary(
+    db: &Db,
+    stream: &TcpStream,
+    commits: Range<u32>,
+    interval: Duration,
+) -> Result<HashKey, Box<dyn Error>> {
+    let root_hash = db.root_hash().await?;
+    let pairs = db
+        .replicate_to(&TrieHash(root_hash), &WireSink(stream), RANGE_LEN)
+        .await?;
+    println!("bootstrapped the standby with {pairs} pairs");
+
+    let mut base = db.revision(root_hash).await?;
+    for i in commits {
+        let root_hash = commit(db, i).await?;
+        let rev = db.revision(root_hash).await?;
+        let delta = Delta::between(&*base, &*rev).await?;
+        block_in_place(|| {
+            wire::write_frame(&mut &*stream, &Frame::Delta(delta))?;
+            thread::sleep(interval);
+            Ok::<_, io::Error>(())
+        })?;
+        base = rev;
+    }
+    Ok(base.root_hash().await?)
+}
+
+/// The frames received from the primary, read by a thread of their own. The channel is closed
+/// once the connection is lost.
+fn receive(stream: TcpStream) -> mpsc::UnboundedReceiver<Frame> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        let mut stream = BufReader::new(stream);
+        loop {
+            match wire::read_frame(&mut stream) {
+                Ok(Some(frame)) => {
+                    if tx.send(frame).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                // a frame cut short is dropped, the standby stays at the commit before
+                Err(e) => {
+                    eprintln!("connection to the primary lost: {e}");
+                    break;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Bootstraps the empty `db` from the primary at the other end of `stream`, then follows the
+/// primary until the connection is lost. Returns the promoted database with the number of
+/// commits of the primary applied after the bootstrap.
+async fn follow(db: Db, stream: TcpStream) -> Result<(Db, u64), Box<dyn Error>> {
+    let mut frames = receive(stream);
+    loop {
+        match frames.recv().await {
+            Some(Frame::Range(pairs)) => db.write_range(pairs).await.map_err(|e| e.to_string())?,
+            Some(Frame::Finish(root_hash)) => {
+                db.finish(root_hash).await.map_err(|e| e.to_string())?;
+                break;
+            }
+            _ => return Err("the primary was lost before the standby was bootstrapped".into()),
+        }
+    }
+
+    let standby = Standby::new(db).await?;
+    let deltas = stream::unfold(frames, |mut frames| async move {
+        let delta = match frames.recv().await? {
+            Frame::Delta(delta) => Ok(delta),
+            _ => Err(api::Error::IO(io::Error::new(
+                ErrorKind::InvalidData,
+                "unexpected frame",
+            ))),
+        };
+        Some((delta, frames))
+    });
+    let applied = standby.follow(deltas).await?;
+    println!(
+        "applied {applied} commits of the primary, up to {}",
+        hex::encode(standby.root_hash())
+    );
+
+    Ok((standby.promote(), applied))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failover() {
+        let dir = std::env::temp_dir().join("firewood_example_replicated");
+        std::fs::create_dir_all(&dir).unwrap();
+        let primary_db = open(&dir.join("primary")).await.unwrap();
+        let standby_db = open(&dir.join("standby")).await.unwrap();
+        for i in 0..SEED_COMMITS {
+            commit(&primary_db, i).await.unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let to_standby = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (from_primary, _) = listener.accept().unwrap();
+
+        // the primary is lost once it made its commits
+        let commits = SEED_COMMITS..SEED_COMMITS + 20;
+        let (shipped, followed) = tokio::join!(
+            async {
+                let shipped = primary(&primary_db, &to_standby, commits.clone(), Duration::ZERO);
+                let shipped = shipped.await.unwrap();
+                drop(to_standby);
+                shipped
+            },
+            async { follow(standby_db, from_primary).await.unwrap() }
+        );
+        let (standby_db, applied) = followed;
+        assert_eq!(applied, commits.len() as u64);
+        assert_eq!(standby_db.root_hash().await.unwrap(), shipped);
+
+        // the promoted standby takes the writes, and makes the commits the primary would have
+        let next = SEED_COMMITS + applied as u32;
+        assert_eq!(
+            commit(&standby_db, next).await.unwrap(),
+            commit(&primary_db, next).await.unwrap()
+        );
+    }
+}
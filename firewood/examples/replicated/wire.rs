@@ -0,0 +1,145 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The frames the primary sends the standby over their connection.
+//!
+//! A frame is a tag byte followed by its fields. Numbers are little-endian `u32`s, hashes are
+//! their 32 bytes, and keys and values are prefixed with their length.
+
+use firewood::v2::{api::HashKey, diff::KeyDiff, standby::Delta};
+use std::io::{self, ErrorKind, Read, Write};
+
+const RANGE: u8 = 0;
+const FINISH: u8 = 1;
+const DELTA: u8 = 2;
+
+const PUT: u8 = 0;
+const DELETE: u8 = 1;
+
+#[derive(Debug)]
+pub enum Frame {
+    /// Consecutive pairs of the revision the standby starts from.
+    Range(Vec<(Vec<u8>, Vec<u8>)>),
+    /// The end of the revision the standby starts from, with its root hash.
+    Finish(HashKey),
+    /// A commit of the primary.
+    Delta(Delta),
+}
+
+pub fn write_frame(w: &mut impl Write, frame: &Frame) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    match frame {
+        Frame::Range(pairs) => {
+            bytes.push(RANGE);
+            put_len(&mut bytes, pairs.len());
+            for (key, value) in pairs {
+                put_field(&mut bytes, key);
+                put_field(&mut bytes, value);
+            }
+        }
+        Frame::Finish(root_hash) => {
+            bytes.push(FINISH);
+            bytes.extend_from_slice(root_hash);
+        }
+        Frame::Delta(delta) => {
+            bytes.push(DELTA);
+            bytes.extend_from_slice(&delta.base);
+            bytes.extend_from_slice(&delta.root);
+            put_len(&mut bytes, delta.diffs.len());
+            for diff in &delta.diffs {
+                match diff {
+                    KeyDiff::Added { key, value }
+                    | KeyDiff::Changed {
+                        key, new: value, ..
+                    } => {
+                        bytes.push(PUT);
+                        put_field(&mut bytes, key);
+                        put_field(&mut bytes, value);
+                    }
+                    KeyDiff::Removed { key, .. } => {
+                        bytes.push(DELETE);
+                        put_field(&mut bytes, key);
+                    }
+                }
+            }
+        }
+    }
+    w.write_all(&bytes)?;
+    w.flush()
+}
+
+/// Reads the next frame, or `None` if the connection was closed between two frames. A
+/// connection closed in the middle of a frame fails with [ErrorKind::UnexpectedEof].
+pub fn read_frame(r: &mut impl Read) -> io::Result<Option<Frame>> {
+    let mut tag = [0];
+    if r.read(&mut tag)? == 0 {
+        return Ok(None);
+    }
+
+    let frame = match tag {
+        [RANGE] => {
+            let len = len(r)?;
+            let mut pairs = Vec::with_capacity(len);
+            for _ in 0..len {
+                pairs.push((field(r)?, field(r)?));
+            }
+            Frame::Range(pairs)
+        }
+        [FINISH] => Frame::Finish(hash(r)?),
+        [DELTA] => {
+            let (base, root) = (hash(r)?, hash(r)?);
+            let len = len(r)?;
+            let mut diffs = Vec::with_capacity(len);
+            for _ in 0..len {
+                let mut op = [0];
+                r.read_exact(&mut op)?;
+                // only the new values are shipped, which is all a standby needs to apply a delta
+                diffs.push(match op {
+                    [PUT] => KeyDiff::Added {
+                        key: field(r)?.into(),
+                        value: field(r)?,
+                    },
+                    [DELETE] => KeyDiff::Removed {
+                        key: field(r)?.into(),
+                        value: Vec::new(),
+                    },
+                    _ => return Err(invalid()),
+                });
+            }
+            Frame::Delta(Delta { base, root, diffs })
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(Some(frame))
+}
+
+fn put_len(bytes: &mut Vec<u8>, len: usize) {
+    bytes.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+fn put_field(bytes: &mut Vec<u8>, field: &[u8]) {
+    put_len(bytes, field.len());
+    bytes.extend_from_slice(field);
+}
+
+fn len(r: &mut impl Read) -> io::Result<usize> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    Ok(u32::from_le_bytes(len) as usize)
+}
+
+fn field(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut field = vec![0; len(r)?];
+    r.read_exact(&mut field)?;
+    Ok(field)
+}
+
+fn hash(r: &mut impl Read) -> io::Result<HashKey> {
+    let mut hash = HashKey::default();
+    r.read_exact(&mut hash)?;
+    Ok(hash)
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "invalid frame")
+}
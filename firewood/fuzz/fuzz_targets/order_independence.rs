@@ -0,0 +1,20 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Run with `cargo +nightly fuzz run order_independence` from the `firewood` directory.
+
+#![no_main]
+
+use firewood::reference::{check_order_independence, UnorderedOps};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let unordered = UnorderedOps::from_bytes(data);
+    // the seed of the shuffles comes from the input too, so a failure reproduces
+    let seed = data
+        .iter()
+        .fold(0, |seed: u64, &byte| seed.rotate_left(8) ^ byte as u64);
+    if let Err(e) = check_order_independence(&unordered, 4, seed) {
+        panic!("{e}");
+    }
+});
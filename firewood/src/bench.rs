@@ -0,0 +1,6 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Tools to measure the performance of a database on workloads shaped like the ones it serves.
+
+pub mod replay;
@@ -0,0 +1,392 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Traces of the reads served by a database, to replay them against another one with timing.
+//!
+//! A [Recorder] hands out views that forward every read to the view they wrap and add it to a
+//! [Trace]: the keys read and proven, and the bounds of the range proofs in either direction. A
+//! trace is saved to a file, one read per line, and [replay]ed later on, against a database with
+//! the same keys, to compare the latency of the reads before and after a change on a
+//! production-shaped workload.
+//!
+//! In a trace file, a read is its kind followed by its arguments, the keys in hex after a `0x`,
+//! and a missing bound or limit as a `-`:
+//!
+//! ```text
+//! get 0x6b6579
+//! prove 0x6b6579
+//! range 0x00 - 16
+//! range-rev - 0xff -
+//! ```
+//!
+//! The iterations over the keys of a view aren't recorded, as their length depends on when the
+//! stream is dropped.
+
+use crate::{
+    db::OpLatency,
+    v2::api::{DbView, Error, HashKey, KeyType, Proof, RangeProof},
+};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// A read of a [Trace].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceOp {
+    /// A read of the value of a key, see [DbView::val].
+    Get(Box<[u8]>),
+    /// A proof of a key, see [DbView::single_key_proof].
+    Prove(Box<[u8]>),
+    /// A proof of a range of keys, see [DbView::range_proof], or [DbView::range_proof_rev] if
+    /// `reverse`.
+    RangeProof {
+        first_key: Option<Box<[u8]>>,
+        last_key: Option<Box<[u8]>>,
+        limit: Option<usize>,
+        reverse: bool,
+    },
+}
+
+struct Key<'a>(Option<&'a [u8]>);
+
+impl fmt::Display for Key<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(key) => write!(f, "0x{}", hex::encode(key)),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+fn parse_key(s: &str) -> Result<Option<Box<[u8]>>, String> {
+    if s == "-" {
+        return Ok(None);
+    }
+    let hex = s
+        .strip_prefix("0x")
+        .ok_or_else(|| format!("key {s:?} doesn't start with 0x"))?;
+    let key = hex::decode(hex).map_err(|e| format!("key {s:?}: {e}"))?;
+    Ok(Some(key.into()))
+}
+
+impl fmt::Display for TraceOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Get(key) => write!(f, "get {}", Key(Some(key))),
+            Self::Prove(key) => write!(f, "prove {}", Key(Some(key))),
+            Self::RangeProof {
+                first_key,
+                last_key,
+                limit,
+                reverse,
+            } => {
+                write!(
+                    f,
+                    "{} {} {} ",
+                    if *reverse { "range-rev" } else { "range" },
+                    Key(first_key.as_deref()),
+                    Key(last_key.as_deref())
+                )?;
+                match limit {
+                    Some(limit) => write!(f, "{limit}"),
+                    None => write!(f, "-"),
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for TraceOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<_> = s.split_whitespace().collect();
+        let required = |key: Option<Box<[u8]>>| key.ok_or_else(|| format!("{s:?} has no key"));
+        match words.as_slice() {
+            ["get", key] => Ok(Self::Get(required(parse_key(key)?)?)),
+            ["prove", key] => Ok(Self::Prove(required(parse_key(key)?)?)),
+            [kind @ ("range" | "range-rev"), first_key, last_key, limit] => Ok(Self::RangeProof {
+                first_key: parse_key(first_key)?,
+                last_key: parse_key(last_key)?,
+                limit: match *limit {
+                    "-" => None,
+                    limit => Some(limit.parse().map_err(|e| format!("limit {limit:?}: {e}"))?),
+                },
+                reverse: *kind == "range-rev",
+            }),
+            _ => Err(format!("{s:?} is not a read")),
+        }
+    }
+}
+
+/// The reads served by a database, in the order they were served.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub ops: Vec<TraceOp>,
+}
+
+impl Trace {
+    /// Writes the reads of the trace to `writer`, one per line.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for op in &self.ops {
+            writeln!(writer, "{op}")?;
+        }
+        Ok(())
+    }
+
+    /// Reads a trace written by [Trace::write], skipping the blank lines.
+    pub fn read<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut ops = Vec::new();
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let op = line.parse().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {e}", number + 1),
+                )
+            })?;
+            ops.push(op);
+        }
+        Ok(Self { ops })
+    }
+
+    /// Writes the trace to the file at `path`, see [Trace::write].
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Reads the trace in the file at `path`, see [Trace::read].
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+}
+
+/// Records the reads of the views it hands out to a [Trace]. Its clones record to the same
+/// trace.
+#[derive(Debug, Clone, Default)]
+pub struct Recorder(Arc<Mutex<Vec<TraceOp>>>);
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A view recording its reads and forwarding them to `view`.
+    pub fn view<V>(&self, view: Arc<V>) -> Recorded<V> {
+        Recorded {
+            view,
+            recorder: self.clone(),
+        }
+    }
+
+    /// The reads recorded so far.
+    pub fn trace(&self) -> Trace {
+        Trace {
+            ops: self.0.lock().clone(),
+        }
+    }
+
+    /// The reads recorded so far, which are no longer recorded afterwards.
+    pub fn take(&self) -> Trace {
+        Trace {
+            ops: std::mem::take(&mut *self.0.lock()),
+        }
+    }
+
+    fn record(&self, op: TraceOp) {
+        self.0.lock().push(op);
+    }
+}
+
+/// A view that records its reads, see [Recorder::view].
+#[derive(Debug)]
+pub struct Recorded<V> {
+    view: Arc<V>,
+    recorder: Recorder,
+}
+
+#[async_trait]
+impl<V: DbView + Send + Sync> DbView for Recorded<V> {
+    type Stream<'a>
+        = V::Stream<'a>
+    where
+        Self: 'a;
+
+    async fn root_hash(&self) -> Result<HashKey, Error> {
+        self.view.root_hash().await
+    }
+
+    async fn val<K: KeyType>(&self, key: K) -> Result<Option<Vec<u8>>, Error> {
+        self.recorder.record(TraceOp::Get(key.as_ref().into()));
+        self.view.val(key).await
+    }
+
+    async fn single_key_proof<K: KeyType>(&self, key: K) -> Result<Option<Proof<Vec<u8>>>, Error> {
+        self.recorder.record(TraceOp::Prove(key.as_ref().into()));
+        self.view.single_key_proof(key).await
+    }
+
+    async fn range_proof<K: KeyType, T: Send + Sync>(
+        &self,
+        first_key: Option<K>,
+        last_key: Option<K>,
+        limit: Option<usize>,
+    ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, Error> {
+        self.recorder.record(TraceOp::RangeProof {
+            first_key: first_key.as_ref().map(|key| key.as_ref().into()),
+            last_key: last_key.as_ref().map(|key| key.as_ref().into()),
+            limit,
+            reverse: false,
+        });
+        self.view
+            .range_proof::<_, T>(first_key, last_key, limit)
+            .await
+    }
+
+    async fn range_proof_rev<K: KeyType, T: Send + Sync>(
+        &self,
+        first_key: Option<K>,
+        last_key: Option<K>,
+        limit: Option<usize>,
+    ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, Error> {
+        self.recorder.record(TraceOp::RangeProof {
+            first_key: first_key.as_ref().map(|key| key.as_ref().into()),
+            last_key: last_key.as_ref().map(|key| key.as_ref().into()),
+            limit,
+            reverse: true,
+        });
+        self.view
+            .range_proof_rev::<_, T>(first_key, last_key, limit)
+            .await
+    }
+
+    fn iter_option<K: KeyType>(&self, first_key: Option<K>) -> Result<Self::Stream<'_>, Error> {
+        self.view.iter_option(first_key)
+    }
+
+    fn iter_rev_option<K: KeyType>(&self, last_key: Option<K>) -> Result<Self::Stream<'_>, Error> {
+        self.view.iter_rev_option(last_key)
+    }
+}
+
+/// The latencies of the reads of a [Trace] replayed against a view, by kind of read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub get: OpLatency,
+    pub prove: OpLatency,
+    pub range_proof: OpLatency,
+    /// Number of reads that found something: a value, a proof or a range proof.
+    pub found: u64,
+}
+
+fn latency(mut samples: Vec<Duration>) -> OpLatency {
+    samples.sort_unstable();
+    OpLatency {
+        count: samples.len() as u64,
+        total: samples.iter().sum(),
+        max: samples.last().copied().unwrap_or_default(),
+        samples,
+    }
+}
+
+/// Replays the reads of `trace` against `view`, one after the other, timing each of them. Stops
+/// at the first read that fails.
+pub async fn replay<V: DbView + Sync>(view: &V, trace: &Trace) -> Result<Report, Error> {
+    let mut get = Vec::new();
+    let mut prove = Vec::new();
+    let mut range_proof = Vec::new();
+    let mut found = 0;
+
+    for op in &trace.ops {
+        let start = Instant::now();
+        let (latencies, hit) = match op {
+            TraceOp::Get(key) => (&mut get, view.val(key).await?.is_some()),
+            TraceOp::Prove(key) => (&mut prove, view.single_key_proof(key).await?.is_some()),
+            TraceOp::RangeProof {
+                first_key,
+                last_key,
+                limit,
+                reverse,
+            } => {
+                let (first_key, last_key) = (first_key.as_ref(), last_key.as_ref());
+                let proof = if *reverse {
+                    view.range_proof_rev::<_, Vec<u8>>(first_key, last_key, *limit)
+                        .await?
+                } else {
+                    view.range_proof::<_, Vec<u8>>(first_key, last_key, *limit)
+                        .await?
+                };
+                (&mut range_proof, proof.is_some())
+            }
+        };
+        latencies.push(start.elapsed());
+        found += u64::from(hit);
+    }
+
+    Ok(Report {
+        get: latency(get),
+        prove: latency(prove),
+        range_proof: latency(range_proof),
+        found,
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_file_format() {
+        let trace = Trace {
+            ops: vec![
+                TraceOp::Get(b"key".to_vec().into()),
+                TraceOp::Prove(Box::default()),
+                TraceOp::RangeProof {
+                    first_key: Some(vec![0].into()),
+                    last_key: None,
+                    limit: Some(16),
+                    reverse: false,
+                },
+                TraceOp::RangeProof {
+                    first_key: None,
+                    last_key: Some(vec![0xff].into()),
+                    limit: None,
+                    reverse: true,
+                },
+            ],
+        };
+        let mut file = Vec::new();
+        trace.write(&mut file).unwrap();
+        assert_eq!(
+            String::from_utf8(file.clone()).unwrap(),
+            "get 0x6b6579\nprove 0x\nrange 0x00 - 16\nrange-rev - 0xff -\n"
+        );
+        assert_eq!(Trace::read(&file[..]).unwrap(), trace);
+
+        for bad in [
+            "get -",
+            "get 6b",
+            "prove 0xzz",
+            "range - - x",
+            "put 0x00 0x00",
+        ] {
+            let error = Trace::read(format!("get 0x00\n\n{bad}\n").as_bytes()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(error.to_string().starts_with("line 3: "), "{error}");
+        }
+    }
+}
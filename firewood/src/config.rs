@@ -1,7 +1,10 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
+pub use crate::merkle::NodeEncoding;
+use crate::shale::allocator::{Allocator, NextFit};
 pub use crate::storage::{buffer::DiskBufferConfig, WalConfig};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 use typed_builder::TypedBuilder;
 
 /// Database configuration.
@@ -31,10 +34,23 @@ pub struct DbConfig {
     /// Maximum steps of walk to recycle a freed item.
     #[builder(default = 10)]
     pub payload_max_walk: u64,
+    /// How the item stash picks the free space it allocates trie nodes from, see
+    /// [allocator](crate::shale::allocator). The strategy can be changed when the DB is reopened.
+    #[builder(default = Arc::new(NextFit))]
+    pub payload_allocator: Arc<dyn Allocator>,
     /// Region size in bits (should be not greater than `payload_file_nbit`). One file is
     /// partitioned into multiple regions. Just use the default value.
     #[builder(default = 22)]
     pub payload_regn_nbit: u64,
+    /// Alignment in bits of the chunks trie nodes are stored in (should be not greater than
+    /// `payload_regn_nbit`). Every chunk starts at, and is padded up to, a multiple of the
+    /// alignment, so that a node written or read never straddles two blocks of a device whose
+    /// blocks are that size, at the cost of the padding. Zero packs the chunks back to back.
+    ///
+    /// The alignment is recorded when the DB is created; the one of an existing DB overrides
+    /// this one.
+    #[builder(default = 0)]
+    pub payload_align_nbit: u64,
     /// Maximum cached pages for the free list of the item stash.
     #[builder(default = 16384)] // 64M total size by default
     pub root_hash_ncached_pages: usize,
@@ -49,9 +65,123 @@ pub struct DbConfig {
     /// existing contents will be lost.
     #[builder(default = false)]
     pub truncate: bool,
+    /// Whether to open an existing DB read-only. Any number of read-only handles, from any
+    /// number of processes, can be open next to the single writer. A read-only handle reads the
+    /// store files as the writer leaves them: it doesn't replay the Wal, doesn't see the changes
+    /// still buffered by the writer, and can't propose changes.
+    #[builder(default = false)]
+    pub read_only: bool,
+    /// Whether to rehash every trie node as it is read and compare the result against the hash
+    /// recorded for it. A mismatch is reported as [MerkleError::HashMismatch] instead of silently
+    /// returning corrupted data. This is expensive and intended for high-assurance deployments
+    /// and debugging.
+    ///
+    /// [MerkleError::HashMismatch]: crate::merkle::MerkleError::HashMismatch
+    #[builder(default = false)]
+    pub verify_hashes_on_read: bool,
+    /// Values of at most this many bytes are stored inline in their parent branch node, instead
+    /// of in a leaf node of their own, which saves a node read per lookup and a node write per
+    /// update of small values such as nonces and counters. The trie, and so its root hash, is
+    /// the same either way. Zero disables inlining.
+    ///
+    /// The threshold is recorded when the DB is created; the one of an existing DB overrides
+    /// this one.
+    #[builder(default = 0)]
+    pub inline_value_threshold: usize,
+    /// The format the trie nodes are written to the store in, see [NodeEncoding]. The standard
+    /// formats let external tooling parse the store files, at some cost in size and speed; the
+    /// trie, and so its root hash, is the same in every format.
+    ///
+    /// The encoding is recorded when the DB is created; the one of an existing DB overrides
+    /// this one.
+    #[builder(default)]
+    pub node_encoding: NodeEncoding,
+    /// Whether to defer allocating the trie nodes a batch creates until the proposal is
+    /// complete. The nodes of the batch are then allocated together, laid out depth first, and
+    /// the nodes the batch creates and deletes itself never take any space. With the
+    /// [Bump](crate::shale::allocator::Bump) allocator, the nodes of a batch are contiguous.
+    #[builder(default = false)]
+    pub delayed_allocation: bool,
+    /// Number of levels of the trie, from the root down, whose nodes are kept in a hot segment
+    /// of the item stash of their own. Every lookup reads the upper levels, so the pages of the
+    /// segment are pinned in the page cache, and, as they are in store files of their own (the
+    /// ones of the addresses from [HOT_SEGMENT_BASE](crate::shale::compact::HOT_SEGMENT_BASE)
+    /// up), those can be kept on faster media, see `hot_segment_dir`. The nodes are moved to the
+    /// segment at the end of each batch. Zero keeps every node in the same segment.
+    ///
+    /// The number of levels is recorded when the DB is created; the one of an existing DB
+    /// overrides this one.
+    #[builder(default = 0)]
+    pub hot_levels: usize,
+    /// The directory of the store files of the hot segment, see `hot_levels`, such as one on
+    /// faster media than the DB. It is created if it doesn't exist, and emptied when the DB is
+    /// created or truncated, so it must not be shared with anything else. None keeps them with
+    /// the other store files of the DB.
+    ///
+    /// The directory is recorded when the DB is created; the one of an existing DB overrides
+    /// this one.
+    #[builder(default)]
+    pub hot_segment_dir: Option<PathBuf>,
+    /// Whether to keep the free list of the item stash indexed in memory, so that freed space
+    /// is found by size instead of by walking at most `payload_max_walk` entries of the list.
+    /// The index is built when the DB is opened, which reads the whole free list and checks it
+    /// against the chunks it points at, and takes memory in proportion to the free list.
+    #[builder(default = false)]
+    pub free_list_index: bool,
+    /// Whether to allocate the store files whole on disk as they're first written to, rather
+    /// than leaving holes in them for the writes to fill, and the blocks of each Wal record
+    /// ahead of writing it. Running out of space then fails the commit that takes the new space
+    /// with [DbError::NoSpace](crate::db::DbError::NoSpace), instead of the writes the disk
+    /// buffer does in the background. On file systems that copy blocks on write, such as btrfs
+    /// and ZFS, space can still run out later.
+    #[builder(default = false)]
+    pub preallocate: bool,
+    /// Bytes to leave free on the file system of the DB. A commit that, by an estimate from the
+    /// pages it changes, would leave less fails with
+    /// [DbError::NoSpace](crate::db::DbError::NoSpace) before changing anything, and the DB
+    /// stays at the revision before it.
+    #[builder(default = 0)]
+    pub min_free_space: u64,
+    /// Maximum number of hot trie node addresses saved to the cache manifest when the DB is
+    /// closed. Those nodes are pre-loaded in the background the next time the DB is opened, so
+    /// that it doesn't start with a cold cache. Set to zero to disable the manifest.
+    #[builder(default = 1 << 16)] // 512K manifest by default
+    pub cache_manifest_nobjs: usize,
+    /// Ceiling in bytes on the memory held by the trie node caches, the page caches, the pages
+    /// changed by pending proposals, the disk buffer and the serialization buffers, see
+    /// [MemoryBudget](crate::memory_budget::MemoryBudget). Above it, cached trie nodes are
+    /// evicted first, then cached pages. The memory of proposals and of the disk buffer can't be
+    /// evicted; it only pushes the caches out. Zero means no ceiling.
+    #[builder(default = 0)]
+    pub memory_budget: usize,
+    /// Bytes of pages and writes a proposal stages in memory above which it spills them to
+    /// temporary files in `overlay_spill_dir`, so that a single batch can change more than fits
+    /// in memory. The spilled changes are read back as the batch needs them, and merged back in
+    /// order when the proposal is committed. Zero never spills.
+    #[builder(default = 0)]
+    pub overlay_spill_threshold: usize,
+    /// Where proposals spill their changes, see `overlay_spill_threshold`. Defaults to the
+    /// temporary directory of the system.
+    #[builder(default = std::env::temp_dir())]
+    pub overlay_spill_dir: PathBuf,
     /// Config for accessing a version of the DB.
     #[builder(default = DbRevConfig::builder().build())]
     pub rev: DbRevConfig,
+    /// Config for adapting the size of the trie node caches to memory pressure.
+    #[builder(default = AdaptiveCacheConfig::builder().build())]
+    pub adaptive_cache: AdaptiveCacheConfig,
+    /// Config for profiling the key prefixes read and written.
+    #[builder(default = HotKeyConfig::builder().build())]
+    pub hot_keys: HotKeyConfig,
+    /// Config for the latency stats of the operations of the DB.
+    #[builder(default = OpStatsConfig::builder().build())]
+    pub op_stats: OpStatsConfig,
+    /// Config for caching the keys found missing from the latest revision.
+    #[builder(default = NegativeCacheConfig::builder().build())]
+    pub negative_cache: NegativeCacheConfig,
+    /// Config for limiting the disk reads of the work the DB does in the background.
+    #[builder(default = BackgroundIoConfig::builder().build())]
+    pub background_io: BackgroundIoConfig,
     /// Config for the disk buffer.
     #[builder(default = DiskBufferConfig::builder().build())]
     pub buffer: DiskBufferConfig,
@@ -66,4 +196,142 @@ pub struct DbRevConfig {
     /// Maximum cached Trie objects.
     #[builder(default = 1 << 20)]
     pub merkle_ncached_objs: usize,
+    /// Maximum estimated bytes of the cached Trie objects, counting their encoded size and the
+    /// fixed size of each. The objects are evicted when either limit is reached.
+    #[builder(default)]
+    pub merkle_cache_bytes: Option<usize>,
+}
+
+/// Config for adapting the size of the trie node caches to the memory pressure of the system.
+///
+/// On Linux, memory usage is measured against the memory limit of the cgroup the process runs
+/// in (falling back to the memory of the whole system), and stalls are read from the pressure
+/// stall information (`memory.pressure`) where the kernel provides it. The caches shrink when
+/// usage goes above `high_watermark`, or when tasks stall on memory, and only grow back once
+/// usage is below `low_watermark`. The gap between the two keeps the caches from oscillating.
+/// Elsewhere, the caches keep their configured size.
+#[derive(TypedBuilder, Clone, Debug)]
+pub struct AdaptiveCacheConfig {
+    /// Whether to adapt the cache size, in place of [DbRevConfig::merkle_cache_bytes]. The
+    /// caches still never hold more than [DbRevConfig::merkle_ncached_objs].
+    #[builder(default = false)]
+    pub enabled: bool,
+    /// The caches never shrink below this many estimated bytes of trie objects.
+    #[builder(default = 16 << 20)]
+    pub min_cache_bytes: usize,
+    /// The caches never grow beyond this many estimated bytes of trie objects, which is where
+    /// they start.
+    #[builder(default = 1 << 30)]
+    pub max_cache_bytes: usize,
+    /// Fraction of the memory limit in use above which the caches shrink.
+    #[builder(default = 0.9)]
+    pub high_watermark: f64,
+    /// Fraction of the memory limit in use below which the caches grow.
+    #[builder(default = 0.75)]
+    pub low_watermark: f64,
+    /// Percentage of time over the last 10 seconds that some tasks were stalled on memory,
+    /// above which the caches shrink regardless of usage.
+    #[builder(default = 10.0)]
+    pub stall_threshold: f64,
+    /// How often the memory pressure is sampled.
+    #[builder(default = Duration::from_secs(1))]
+    pub interval: Duration,
+}
+
+/// Config for the sampling profiler of the key prefixes a DB reads and writes, see
+/// [Db::hot_keys](crate::db::Db::hot_keys).
+///
+/// The reads go through the revisions the DB hands out, and the writes through the batches of
+/// its proposals. One in `sample_rate` of them is counted under the first `prefix_len` bytes of
+/// its key. The counts cover the last `window`, give or take a sixth of it.
+#[derive(TypedBuilder, Clone, Debug)]
+pub struct HotKeyConfig {
+    /// Whether to profile the keys.
+    #[builder(default = false)]
+    pub enabled: bool,
+    /// One in this many reads and writes is counted.
+    #[builder(default = 16)]
+    pub sample_rate: u64,
+    /// Number of leading bytes of the keys that are counted together.
+    #[builder(default = 4)]
+    pub prefix_len: usize,
+    /// How far back the counts go.
+    #[builder(default = Duration::from_secs(60))]
+    pub window: Duration,
+}
+
+/// Config for the latency stats of the operations of a DB, see
+/// [Db::op_stats](crate::db::Db::op_stats).
+///
+/// The reads, proofs and iterations go through the revisions and proposals the DB hands out, and
+/// the inserts and commits through its proposals. Every operation is counted, and the latencies
+/// of up to `reservoir_size` of them, sampled uniformly, are kept per type of operation.
+#[derive(TypedBuilder, Clone, Debug)]
+pub struct OpStatsConfig {
+    /// Whether to time the operations.
+    #[builder(default = false)]
+    pub enabled: bool,
+    /// Maximum number of latencies kept per type of operation.
+    #[builder(default = 1024)]
+    pub reservoir_size: usize,
+}
+
+/// Config for the cache of the keys found missing from the latest revision of a DB, see
+/// [Db::negative_cache_stats](crate::db::Db::negative_cache_stats).
+///
+/// The lookups go through the latest revision the DB hands out. Up to `capacity` of the keys
+/// they found missing are kept, the least recently looked up dropped first, until the next
+/// commit starts over with none.
+#[derive(TypedBuilder, Clone, Debug)]
+pub struct NegativeCacheConfig {
+    /// Whether to cache the missing keys.
+    #[builder(default = false)]
+    pub enabled: bool,
+    /// Maximum number of missing keys cached.
+    #[builder(default = 1 << 16)]
+    pub capacity: usize,
+}
+
+/// Config for the share of disk reads the work a DB does in the background gets, such as
+/// pre-loading the nodes of the cache manifest. Above the limits, that work is slowed down so
+/// that it doesn't compete with the reads and commits made on behalf of the user, which aren't
+/// limited. The limits are averaged over `burst`: background work that was idle may briefly go
+/// faster.
+#[derive(TypedBuilder, Clone, Debug)]
+pub struct BackgroundIoConfig {
+    /// Maximum reads per second. Zero means no limit.
+    #[builder(default = 0)]
+    pub iops: u64,
+    /// Maximum bytes read per second. Zero means no limit.
+    #[builder(default = 0)]
+    pub bytes_per_sec: u64,
+    /// How long the unused share accumulates for.
+    #[builder(default = Duration::from_millis(100))]
+    pub burst: Duration,
+}
+
+/// Config for serving proofs, see [ProofServer](crate::db::ProofServer).
+#[derive(TypedBuilder, Clone, Debug)]
+pub struct ProofServerConfig {
+    /// Number of threads traversing the trie.
+    #[builder(default = 4)]
+    pub threads: usize,
+    /// How long a request is held for identical requests to join it before it is served.
+    #[builder(default = Duration::from_millis(1))]
+    pub coalesce_window: Duration,
+    /// Maximum number of revisions kept open, with their caches, between requests.
+    #[builder(default = 4)]
+    pub ncached_revisions: usize,
+}
+
+/// Config for committing tiny batches together, see [CommitBatcher](crate::db::CommitBatcher).
+#[derive(TypedBuilder, Clone, Debug)]
+pub struct CommitBatchingConfig {
+    /// How long the first batch of a commit waits for more batches to join it.
+    #[builder(default = Duration::from_millis(5))]
+    pub interval: Duration,
+    /// Maximum number of batches committed together. A commit holding that many batches is
+    /// made without waiting for the rest of the interval.
+    #[builder(default = 256)]
+    pub max_batches: usize,
 }
@@ -2,15 +2,20 @@
 // See the file LICENSE.md for licensing terms.
 
 pub use crate::{
-    config::{DbConfig, DbRevConfig},
+    config::{
+        AdaptiveCacheConfig, BackgroundIoConfig, CommitBatchingConfig, DbConfig, DbRevConfig,
+        HotKeyConfig, NegativeCacheConfig, NodeEncoding, OpStatsConfig, ProofServerConfig,
+    },
+    memory_budget::{MemoryBudget, MemoryConsumer},
+    merkle::{CorruptionReport, Health},
     storage::{buffer::DiskBufferConfig, WalConfig},
     v2::api::{Batch, BatchOp, Proposal},
 };
 use crate::{
     file,
     merkle::{
-        Bincode, Key, Merkle, MerkleError, MerkleKeyValueStream, Proof, ProofError, TrieHash,
-        TRIE_HASH_LEN,
+        Bincode, Child, DegenerateNode, DivergingNode, Key, Merkle, MerkleError,
+        MerkleKeyValueStream, Proof, ProofError, Quarantine, TrieHash, TrieVisitor, TRIE_HASH_LEN,
     },
     storage::{
         buffer::{DiskBuffer, DiskBufferRequester},
@@ -22,41 +27,127 @@ use crate::{
 use crate::{
     merkle,
     shale::{
-        self, compact::StoreHeader, disk_address::DiskAddress, LinearStore, Obj, ShaleError,
-        Storable, StoreId, StoredView,
+        self,
+        allocator::Allocator,
+        compact::{StoreHeader, HOT_SEGMENT_BASE},
+        disk_address::DiskAddress,
+        free_index::FreeIndex,
+        LinearStore, Obj, ShaleError, Storable, StoreId, StoreStats, StoredView,
     },
 };
 use aiofut::AioError;
 use async_trait::async_trait;
-use bytemuck::{cast_slice, Pod, Zeroable};
+use bytemuck::{Pod, Zeroable};
+use futures::{Stream, StreamExt, TryStreamExt};
 
 use metered::metered;
 use parking_lot::{Mutex, RwLock};
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     error::Error,
+    ffi::OsString,
     fmt,
+    future::ready,
     io::{Cursor, ErrorKind, Write},
     mem::size_of,
     num::NonZeroUsize,
-    ops::Deref,
-    os::fd::{AsFd, BorrowedFd},
-    path::Path,
-    sync::Arc,
+    ops::{Deref, Range},
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::ffi::{OsStrExt, OsStringExt},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
     thread::JoinHandle,
+    time::{Duration, Instant},
 };
 use tokio::task::block_in_place;
 
+mod adaptive_cache;
+mod batch_validator;
+mod cache_manifest;
+mod change_filter;
+mod commit_batcher;
+mod commit_hook;
+mod disk_space;
+mod dump;
+mod freeze;
+mod hot_keys;
+mod io_scheduler;
+mod lock;
+mod mapped;
+mod multi_commit;
+mod negative_cache;
+mod op_stats;
+mod proof_server;
 mod proposal;
+mod read_planner;
+mod reclaim;
+mod replicate;
+mod revision_index;
+mod secondary_index;
+mod sequence;
+mod shutdown;
+mod stats;
+mod write_batch;
+
+use self::{
+    adaptive_cache::CacheTuner,
+    batch_validator::BatchValidators,
+    cache_manifest::CachePrimer,
+    change_filter::ChangedKeys,
+    commit_hook::CommitHooks,
+    disk_space::DiskSpace,
+    freeze::WriteFence,
+    hot_keys::{Access, HotKeys},
+    io_scheduler::IoScheduler,
+    lock::DbLock,
+    negative_cache::{MissingKeys, NegativeCache},
+    op_stats::OpRecorder,
+    proposal::ProposalBase,
+    secondary_index::{SecondaryIndex, SecondaryIndexes},
+    sequence::Sequences,
+};
+pub use self::{
+    batch_validator::BatchValidator,
+    commit_batcher::{CommitBatcher, CommitBatcherStats},
+    commit_hook::CommitHook,
+    dump::DumpPage,
+    freeze::WriteFreeze,
+    hot_keys::HotPrefix,
+    mapped::MappedRev,
+    multi_commit::{MultiCommit, PreparedCommit},
+    negative_cache::NegativeCacheStats,
+    op_stats::{Op, OpLatency, OpStats, TimedStream},
+    proof_server::{ProofServer, ProofServerStats},
+    replicate::BatchSink,
+    revision_index::{RevisionEntry, MAX_ANNOTATION_LEN},
+    secondary_index::IndexKeyExtractor,
+    stats::{AllocatorStats, CacheStats, IoStats, StatsDelta, StatsSnapshot, WalStats},
+    write_batch::WriteBatch,
+};
 
-use self::proposal::ProposalBase;
-
-const MERKLE_META_STORE_ID: StoreId = 0x0;
-const MERKLE_PAYLOAD_STORE_ID: StoreId = 0x1;
-const ROOT_HASH_STORE_ID: StoreId = 0x2;
+/// The store of the headers of the trie and of its allocator, see [Db::space_stats].
+pub const MERKLE_META_STORE_ID: StoreId = 0x0;
+/// The store of the nodes of the trie, see [Db::space_stats].
+pub const MERKLE_PAYLOAD_STORE_ID: StoreId = 0x1;
+/// The store of the root hashes of the revisions, see [Db::space_stats].
+pub const ROOT_HASH_STORE_ID: StoreId = 0x2;
 const RESERVED_STORE_ID: u64 = 0x1000;
 
-const MAGIC_STR: &[u8; 16] = b"firewood v0.1\0\0\0";
+/// Identifies the format of the files of a DB, which can't be opened by a build of another
+/// format, e.g. with fewer [DbParams] or another layout of the trie nodes.
+const MAGIC_STR: &[u8; 16] = b"firewood v0.2\0\0\0";
+/// Where the diagnostics of a DB are written, in its directory.
+const DIAGNOSTICS_DIR: &str = "diagnostics";
+/// Where the reports of the corrupted nodes of a DB are written, in its directory.
+const QUARANTINE_DIR: &str = "quarantine";
+/// The file recording the [hot segment dir](DbConfig::hot_segment_dir) a DB was created with, in
+/// its directory.
+const HOT_SEGMENT_DIR_FILE: &str = "hot_segment_dir";
+/// Rough number of bytes a batch writes to the payload store for each operation, on top of its
+/// keys and values: the header of the leaf, and a share of the branches rewritten above it.
+const NODE_BYTES_PER_OP: usize = 128;
 
 #[derive(Debug)]
 #[non_exhaustive]
@@ -70,6 +161,42 @@ pub enum DbError {
     Shale(ShaleError),
     IO(std::io::Error),
     InvalidProposal,
+    CommitHook(Box<dyn Error + Send + Sync>),
+    KeyExists(Vec<u8>),
+    BatchRejected(String),
+    /// Another handle, in this process or another one, has the DB open for writing, or is
+    /// truncating it. `pid` is the process of the writer, if it is known.
+    AlreadyOpen {
+        pid: Option<u32>,
+    },
+    ReadOnly,
+    /// No secondary index with this name is registered, see [Db::register_index].
+    UnknownIndex(String),
+    /// The root hash of a proposal isn't the one its committer expected, see
+    /// [Proposal::commit_with_expected_root]. `dump` is where the nodes where the proposal diverges
+    /// from its parent were written, unless that failed.
+    RootMismatch {
+        expected: TrieHash,
+        computed: TrieHash,
+        dump: Option<PathBuf>,
+    },
+    /// The DB was closed, see [Db::close].
+    Closed,
+    /// [Db::close] gave up waiting for the DB to drain.
+    CloseTimeout,
+    /// [Db::freeze_writes] gave up waiting for the commits in flight.
+    FreezeTimeout,
+    /// A commit needs more disk space than the file system of the DB has, after
+    /// [DbConfig::min_free_space]: `needed` counts the space left free, and `available` is
+    /// what is left on the file system.
+    NoSpace {
+        needed: u64,
+        available: u64,
+    },
+    /// A range read by [Db::replicate_to] doesn't match its proof, or the sink failed.
+    Replication(Box<dyn Error + Send + Sync>),
+    /// No revision the DB retains has this root hash.
+    RevisionNotFound(TrieHash),
 }
 
 impl fmt::Display for DbError {
@@ -84,6 +211,38 @@ impl fmt::Display for DbError {
             DbError::IO(e) => write!(f, "I/O error: {e:?}"),
             DbError::Shale(e) => write!(f, "shale error: {e:?}"),
             DbError::InvalidProposal => write!(f, "invalid proposal"),
+            DbError::CommitHook(e) => write!(f, "commit hook error: {e}"),
+            DbError::KeyExists(key) => write!(f, "key already exists: {key:?}"),
+            DbError::BatchRejected(reason) => write!(f, "batch rejected: {reason}"),
+            DbError::AlreadyOpen { pid: Some(pid) } => {
+                write!(f, "database is already open by process {pid}")
+            }
+            DbError::AlreadyOpen { pid: None } => write!(f, "database is already open"),
+            DbError::ReadOnly => write!(f, "database is open read-only"),
+            DbError::UnknownIndex(name) => write!(f, "unknown secondary index: {name}"),
+            DbError::RootMismatch {
+                expected,
+                computed,
+                dump,
+            } => {
+                write!(
+                    f,
+                    "root hash mismatch: expected {expected:?}, computed {computed:?}"
+                )?;
+                match dump {
+                    Some(dump) => write!(f, ", diverging nodes dumped to {}", dump.display()),
+                    None => Ok(()),
+                }
+            }
+            DbError::Closed => write!(f, "database is closed"),
+            DbError::CloseTimeout => write!(f, "timed out closing the database"),
+            DbError::FreezeTimeout => write!(f, "timed out freezing the writes of the database"),
+            DbError::NoSpace { needed, available } => write!(
+                f,
+                "not enough disk space: {needed} bytes needed, {available} available"
+            ),
+            DbError::Replication(e) => write!(f, "replication error: {e}"),
+            DbError::RevisionNotFound(hash) => write!(f, "no revision with root hash {hash:?}"),
         }
     }
 }
@@ -105,8 +264,7 @@ impl Error for DbError {}
 /// DbParams contains the constants that are fixed upon the creation of the DB, this ensures the
 /// correct parameters are used when the DB is opened later (the parameters here will override the
 /// parameters in [DbConfig] if the DB already exists).
-#[repr(C)]
-#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct DbParams {
     magic: [u8; 16],
     meta_file_nbit: u64,
@@ -115,6 +273,68 @@ struct DbParams {
     wal_file_nbit: u64,
     wal_block_nbit: u64,
     root_hash_file_nbit: u64,
+    inline_value_threshold: u64,
+    /// Length of the hashes the trie was built with, see [TRIE_HASH_LEN].
+    hash_len: u64,
+    /// The format new trie nodes are written in, see [NodeEncoding].
+    node_encoding: u64,
+    /// Alignment of the chunks of the payload store, see [DbConfig::payload_align_nbit].
+    payload_align_nbit: u64,
+    /// Levels of the trie kept in the hot segment of the store, see [DbConfig::hot_levels].
+    hot_levels: u64,
+}
+
+impl DbParams {
+    const SIZE: usize = 16 + 11 * size_of::<u64>();
+
+    /// The parameters as they are stored at the head of the meta store, the magic string then
+    /// every field as a little-endian u64, in declaration order.
+    fn to_le_bytes(self) -> [u8; Self::SIZE] {
+        let fields = [
+            self.meta_file_nbit,
+            self.payload_file_nbit,
+            self.payload_regn_nbit,
+            self.wal_file_nbit,
+            self.wal_block_nbit,
+            self.root_hash_file_nbit,
+            self.inline_value_threshold,
+            self.hash_len,
+            self.node_encoding,
+            self.payload_align_nbit,
+            self.hot_levels,
+        ];
+        let mut bytes = [0; Self::SIZE];
+        let (magic, rest) = bytes.split_at_mut(self.magic.len());
+        magic.copy_from_slice(&self.magic);
+        for (chunk, field) in rest.chunks_exact_mut(size_of::<u64>()).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_le_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let (magic, rest) = bytes.split_at(16);
+        let mut fields = [0; 11];
+        for (field, chunk) in fields.iter_mut().zip(rest.chunks_exact(size_of::<u64>())) {
+            *field = u64::from_le_bytes(chunk.try_into().expect("chunks of 8 bytes"));
+        }
+        let [meta_file_nbit, payload_file_nbit, payload_regn_nbit, wal_file_nbit, wal_block_nbit, root_hash_file_nbit, inline_value_threshold, hash_len, node_encoding, payload_align_nbit, hot_levels] =
+            fields;
+        Self {
+            magic: magic.try_into().expect("the magic string is 16 bytes"),
+            meta_file_nbit,
+            payload_file_nbit,
+            payload_regn_nbit,
+            wal_file_nbit,
+            wal_block_nbit,
+            root_hash_file_nbit,
+            inline_value_threshold,
+            hash_len,
+            node_encoding,
+            payload_align_nbit,
+            hot_levels,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -187,11 +407,39 @@ fn get_sub_universe_from_empty_delta(
     get_sub_universe_from_deltas(sub_universe, StoreDelta::default(), StoreDelta::default())
 }
 
-/// mutable DB-wide metadata, it keeps track of the root of the top-level trie.
+/// Pins the pages of `segment`, the hot segment of the trie in `payload`, from `pinned` on, see
+/// [DbConfig::hot_levels]. Returns how far the segment is pinned then.
+fn pin_hot_segment<S: LinearStore>(
+    payload: &S,
+    segment: Option<Range<usize>>,
+    pinned: usize,
+) -> Result<usize, DbError> {
+    let Some(segment) = segment else {
+        return Ok(pinned);
+    };
+    let start = segment.start.max(pinned);
+    if segment.end > start {
+        payload.pin_region(start, (segment.end - start) as u64)?;
+    }
+    Ok(segment.end.max(pinned))
+}
+
+/// An error reading a [MerkleKeyValueStream], as its message, since an [api::Error] can't be
+/// shared between threads.
+fn stream_error(e: api::Error) -> DbError {
+    DbError::IO(std::io::Error::other(e.to_string()))
+}
+
+/// mutable DB-wide metadata, it keeps track of the root of the top-level trie and of the number
+/// of keys and value bytes stored in it, and of the root of the trie of the secondary indexes.
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
 struct DbHeader {
     sentinel_addr: DiskAddress,
+    key_count: u64,
+    value_bytes: u64,
+    /// Null until an indexed value is first written, see [secondary_index].
+    index_sentinel_addr: DiskAddress,
 }
 
 impl DbHeader {
@@ -200,6 +448,9 @@ impl DbHeader {
     pub const fn new_empty() -> Self {
         Self {
             sentinel_addr: DiskAddress::null(),
+            key_count: 0,
+            value_bytes: 0,
+            index_sentinel_addr: DiskAddress::null(),
         }
     }
 }
@@ -213,12 +464,16 @@ impl Storable for DbHeader {
                 size: Self::MSIZE,
             })?;
         let root_bytes = root_bytes.as_deref();
-        let root_bytes = root_bytes.as_slice();
+        let (sentinel_addr, counts) = root_bytes.split_at(DiskAddress::SERIALIZED_LEN as usize);
+        let (key_count, rest) = counts.split_at(size_of::<u64>());
+        let (value_bytes, index_sentinel_addr) = rest.split_at(size_of::<u64>());
 
+        const SIZES: &str = "Self::MSIZE == 2 * DiskAddress::MSIZE + 2 * 8";
         Ok(Self {
-            sentinel_addr: root_bytes
-                .try_into()
-                .expect("Self::MSIZE == DiskAddress:MSIZE"),
+            sentinel_addr: sentinel_addr.try_into().expect(SIZES),
+            key_count: u64::from_le_bytes(key_count.try_into().expect(SIZES)),
+            value_bytes: u64::from_le_bytes(value_bytes.try_into().expect(SIZES)),
+            index_sentinel_addr: index_sentinel_addr.try_into().expect(SIZES),
         })
     }
 
@@ -229,6 +484,9 @@ impl Storable for DbHeader {
     fn serialize(&self, to: &mut [u8]) -> Result<(), ShaleError> {
         let mut cur = Cursor::new(to);
         cur.write_all(&self.sentinel_addr.to_le_bytes())?;
+        cur.write_all(&self.key_count.to_le_bytes())?;
+        cur.write_all(&self.value_bytes.to_le_bytes())?;
+        cur.write_all(&self.index_sentinel_addr.to_le_bytes())?;
         Ok(())
     }
 }
@@ -245,6 +503,17 @@ impl Universe<StoreRevShared> {
             merkle: self.merkle.to_mem_store_r(),
         }
     }
+
+    /// Re-bases the stores on the stores of `base`, see [StoreRevShared::set_base_store]. This
+    /// changes every view sharing these stores.
+    fn set_base_store(&mut self, base: &Universe<StoreRevShared>) {
+        self.merkle
+            .meta
+            .set_base_store(base.merkle.meta.inner().clone());
+        self.merkle
+            .payload
+            .set_base_store(base.merkle.payload.inner().clone());
+    }
 }
 
 impl Universe<StoreRevMut> {
@@ -253,6 +522,28 @@ impl Universe<StoreRevMut> {
             merkle: self.merkle.new_from_other(),
         }
     }
+
+    /// Reserves room for the payload pages `batch` is expected to change, so that a large batch
+    /// grows the overlay in a single step.
+    fn reserve_for_batch<K: KeyType, V: ValueType>(&self, batch: &Batch<K, V>) {
+        let bytes: usize = batch
+            .iter()
+            .map(|op| {
+                NODE_BYTES_PER_OP
+                    + match op {
+                        BatchOp::Put { key, value } => key.as_ref().len() + value.as_ref().len(),
+                        BatchOp::Delete { key } => key.as_ref().len(),
+                        BatchOp::Move { key, new_key, .. } => {
+                            key.as_ref().len() + new_key.as_ref().len()
+                        }
+                        BatchOp::DeletePrefix { prefix } => prefix.as_ref().len(),
+                    }
+            })
+            .sum();
+        self.merkle
+            .payload
+            .reserve_pages(bytes.div_ceil(1 << PAGE_SIZE_NBIT));
+    }
 }
 
 impl Universe<Arc<CachedStore>> {
@@ -282,11 +573,17 @@ impl<T: MemStoreR + 'static> Universe<Arc<T>> {
 pub struct DbRev<T> {
     header: shale::Obj<DbHeader>,
     merkle: Merkle<T, Bincode>,
+    hot_keys: HotKeys,
+    op_stats: OpRecorder,
+    missing_keys: MissingKeys,
 }
 
 #[async_trait]
 impl<T: LinearStore> api::DbView for DbRev<T> {
-    type Stream<'a> = MerkleKeyValueStream<'a, T, Bincode> where Self: 'a;
+    type Stream<'a>
+        = TimedStream<MerkleKeyValueStream<'a, T, Bincode>>
+    where
+        Self: 'a;
 
     async fn root_hash(&self) -> Result<api::HashKey, api::Error> {
         self.merkle
@@ -296,19 +593,37 @@ impl<T: LinearStore> api::DbView for DbRev<T> {
     }
 
     async fn val<K: api::KeyType>(&self, key: K) -> Result<Option<Vec<u8>>, api::Error> {
-        let obj_ref = self.merkle.get(key, self.header.sentinel_addr);
+        self.hot_keys.record(key.as_ref(), Access::Read);
+        let _timer = self.op_stats.start(Op::Get);
+        if self.missing_keys.contains(key.as_ref()) {
+            return Ok(None);
+        }
+        let obj_ref = self.merkle.get(key.as_ref(), self.header.sentinel_addr);
         match obj_ref {
             Err(e) => Err(api::Error::IO(std::io::Error::new(ErrorKind::Other, e))),
+            Ok(None) => {
+                self.missing_keys.insert(key.as_ref());
+                Ok(None)
+            }
             Ok(obj) => Ok(obj.map(|inner| inner.deref().to_owned())),
         }
     }
 
+    async fn val_many<K: api::KeyType>(
+        &self,
+        keys: Vec<K>,
+    ) -> Vec<Result<Option<Vec<u8>>, api::Error>> {
+        block_in_place(|| self.read_many(&keys))
+            .into_iter()
+            .map(|value| value.map_err(|e| api::Error::InternalError(Box::new(e))))
+            .collect()
+    }
+
     async fn single_key_proof<K: api::KeyType>(
         &self,
         key: K,
     ) -> Result<Option<Proof<Vec<u8>>>, api::Error> {
-        self.merkle
-            .prove(key, self.header.sentinel_addr)
+        self.prove(key)
             .map(Some)
             .map_err(|e| api::Error::IO(std::io::Error::new(ErrorKind::Other, e)))
     }
@@ -325,16 +640,32 @@ impl<T: LinearStore> api::DbView for DbRev<T> {
             .map_err(|e| api::Error::InternalError(Box::new(e)))
     }
 
+    async fn range_proof_rev<K: api::KeyType, V>(
+        &self,
+        first_key: Option<K>,
+        last_key: Option<K>,
+        limit: Option<usize>,
+    ) -> Result<Option<api::RangeProof<Vec<u8>, Vec<u8>>>, api::Error> {
+        self.merkle
+            .range_proof_rev(self.header.sentinel_addr, first_key, last_key, limit)
+            .await
+    }
+
     fn iter_option<K: KeyType>(
         &self,
         first_key: Option<K>,
     ) -> Result<Self::Stream<'_>, api::Error> {
-        Ok(match first_key {
-            None => self.merkle.key_value_iter(self.header.sentinel_addr),
-            Some(key) => self
-                .merkle
-                .key_value_iter_from_key(self.header.sentinel_addr, key.as_ref().into()),
-        })
+        Ok(self.timed(match first_key {
+            None => self.stream(),
+            Some(key) => self.stream_from(key.as_ref().into()),
+        }))
+    }
+
+    fn iter_rev_option<K: KeyType>(
+        &self,
+        last_key: Option<K>,
+    ) -> Result<Self::Stream<'_>, api::Error> {
+        Ok(self.timed(self.stream_rev(last_key.map(|key| key.as_ref().into()))))
     }
 }
 
@@ -348,6 +679,65 @@ impl<T: LinearStore> DbRev<T> {
             .key_value_iter_from_key(self.header.sentinel_addr, start_key)
     }
 
+    /// Stream the key-value pairs in descending key order, starting at `last_key` (or at the last
+    /// key before it), or at the last key if there is no `last_key`.
+    pub fn stream_rev(
+        &self,
+        last_key: Option<Key>,
+    ) -> merkle::MerkleKeyValueStream<'_, T, Bincode> {
+        self.merkle
+            .key_value_iter_rev(self.header.sentinel_addr, last_key)
+    }
+
+    /// Record the reads and writes of this revision in the profiler of its DB.
+    fn with_hot_keys(mut self, hot_keys: HotKeys) -> Self {
+        self.hot_keys = hot_keys;
+        self
+    }
+
+    /// Record the latency of the operations on this revision in the stats of its DB.
+    fn with_op_stats(mut self, op_stats: OpRecorder) -> Self {
+        self.op_stats = op_stats;
+        self
+    }
+
+    /// Answer the lookups of the keys found missing from this revision from `missing_keys`, see
+    /// [Db::negative_cache_stats].
+    fn with_missing_keys(mut self, missing_keys: MissingKeys) -> Self {
+        self.missing_keys = missing_keys;
+        self
+    }
+
+    /// Report the nodes of this revision that fail to read to the quarantine of its DB, see
+    /// [Db::health].
+    fn with_quarantine(mut self, quarantine: Option<&Quarantine>) -> Self {
+        if let Some(quarantine) = quarantine {
+            self.merkle.set_quarantine(quarantine.clone());
+        }
+        self
+    }
+
+    /// Mark this revision as suspect, by its `root_hash`, once one of its nodes fails to read.
+    fn with_revision_hash(mut self, root_hash: TrieHash) -> Self {
+        if let Some(quarantine) = self.merkle.quarantine() {
+            let quarantine = quarantine.for_revision(Some(root_hash));
+            self.merkle.set_quarantine(quarantine);
+        }
+        self
+    }
+
+    /// Record the latency of each step of `stream` in the stats of the DB of this revision.
+    fn timed<S>(&self, stream: S) -> TimedStream<S> {
+        TimedStream::new(stream, self.op_stats.clone())
+    }
+
+    /// Allocate from the free list indexed by `index`, if any, see
+    /// [DbConfig::free_list_index].
+    fn with_free_index(mut self, index: Option<FreeIndex>) -> Self {
+        self.merkle = self.merkle.with_free_index(index);
+        self
+    }
+
     /// Get root hash of the generic key-value storage.
     pub fn kv_root_hash(&self) -> Result<TrieHash, DbError> {
         self.merkle
@@ -355,12 +745,200 @@ impl<T: LinearStore> DbRev<T> {
             .map_err(DbError::Merkle)
     }
 
+    /// Get the number of keys and value bytes in the generic key-value storage.
+    pub fn counts(&self) -> TrieCounts {
+        TrieCounts {
+            keys: self.header.key_count,
+            value_bytes: self.header.value_bytes,
+        }
+    }
+
     /// Get a value associated with a key.
     pub fn kv_get<K: AsRef<[u8]>>(&self, key: K) -> Option<Vec<u8>> {
-        let obj_ref = self.merkle.get(key, self.header.sentinel_addr);
-        match obj_ref {
-            Err(_) => None,
-            Ok(obj) => obj.map(|o| o.to_vec()),
+        self.get_value(key.as_ref()).ok().flatten()
+    }
+
+    /// Like [DbRev::kv_get], with the error of a read that failed.
+    fn get_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        self.hot_keys.record(key, Access::Read);
+        let _timer = self.op_stats.start(Op::Get);
+        if self.missing_keys.contains(key) {
+            return Ok(None);
+        }
+        match self.merkle.get(key, self.header.sentinel_addr) {
+            Err(e) => Err(DbError::Merkle(e)),
+            Ok(None) => {
+                self.missing_keys.insert(key);
+                Ok(None)
+            }
+            Ok(obj) => Ok(obj.map(|o| o.to_vec())),
+        }
+    }
+
+    /// Get the values of `keys`, in the order of `keys`. Keys close together in the trie, by an
+    /// estimate from the number of keys, are read with a single stream rather than a traversal
+    /// each, which reads fewer nodes when the keys are clustered, see [read_planner].
+    pub async fn kv_get_many<K: AsRef<[u8]> + Sync>(
+        &self,
+        keys: &[K],
+    ) -> Result<Vec<Option<Vec<u8>>>, DbError> {
+        block_in_place(|| self.read_many(keys))
+            .into_iter()
+            .collect()
+    }
+
+    /// Reads the values of `keys`, in the order of `keys`, with the result of the read of each.
+    /// The runs of keys planned by [read_planner] are read by as many threads as there are cores,
+    /// so that the reads of the nodes that aren't cached are issued concurrently, and the nodes
+    /// the runs have in common, such as the upper ones, are shared through the cache of the trie.
+    fn read_many<K: AsRef<[u8]> + Sync>(
+        &self,
+        keys: &[K],
+    ) -> Vec<Result<Option<Vec<u8>>, DbError>> {
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        #[allow(clippy::indexing_slicing)]
+        order.sort_by(|a, b| keys[*a].as_ref().cmp(keys[*b].as_ref()));
+        #[allow(clippy::indexing_slicing)]
+        let sorted: Vec<&[u8]> = order.iter().map(|i| keys[*i].as_ref()).collect();
+
+        let runs = read_planner::plan(&sorted, self.header.key_count);
+        let threads = std::thread::available_parallelism()
+            .map_or(1, NonZeroUsize::get)
+            .min(runs.len())
+            .max(1);
+        #[allow(clippy::indexing_slicing)]
+        let read = |thread: usize| {
+            runs.iter()
+                .skip(thread)
+                .step_by(threads)
+                .map(|run| (run.clone(), self.read_run(&sorted[run.clone()])))
+                .collect::<Vec<_>>()
+        };
+        let read: Vec<_> = if threads > 1 {
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = (0..threads)
+                    .map(|thread| scope.spawn(move || read(thread)))
+                    .collect();
+                #[allow(clippy::unwrap_used)]
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().unwrap())
+                    .collect()
+            })
+        } else {
+            read(0)
+        };
+
+        let mut values: Vec<_> = (0..keys.len()).map(|_| Ok(None)).collect();
+        for (run, run_values) in read {
+            #[allow(clippy::indexing_slicing)]
+            let run_order = &order[run];
+            match run_values {
+                Ok(run_values) => {
+                    for (i, value) in run_order.iter().zip(run_values) {
+                        #[allow(clippy::indexing_slicing)]
+                        (values[*i] = Ok(value));
+                    }
+                }
+                // the stream of the run failed, and with it the reads of all the keys of the run:
+                // the key of the run first in `keys` gets the error, so that it is the one
+                // kv_get_many returns, and the others an error with its message
+                Err(e) => {
+                    let message = e.to_string();
+                    for i in run_order {
+                        let derived = DbError::IO(std::io::Error::other(message.clone()));
+                        #[allow(clippy::indexing_slicing)]
+                        (values[*i] = Err(derived));
+                    }
+                    if let Some(first) = run_order.iter().min() {
+                        #[allow(clippy::indexing_slicing)]
+                        (values[*first] = Err(e));
+                    }
+                }
+            }
+        }
+        values
+    }
+
+    /// Reads the values of a run of sorted `keys` planned by [read_planner]: a single key with a
+    /// traversal, and more with a stream from the first of them.
+    fn read_run(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, DbError> {
+        let first = match keys {
+            [] => return Ok(Vec::new()),
+            [key] => return Ok(vec![self.get_value(key)?]),
+            [first, ..] => first,
+        };
+
+        let mut stream = self.stream_from((*first).into());
+        let mut next_pair = || {
+            futures::executor::block_on(stream.next())
+                .transpose()
+                .map_err(stream_error)
+        };
+        let mut next = next_pair()?;
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            self.hot_keys.record(key, Access::Read);
+            // skip the keys of the trie before `key`
+            while let Some((streamed, _)) = &next {
+                if &**streamed >= *key {
+                    break;
+                }
+                next = next_pair()?;
+            }
+            values.push(match &next {
+                Some((streamed, value)) if &**streamed == *key => Some(value.clone()),
+                _ => None,
+            });
+        }
+        Ok(values)
+    }
+
+    /// Get the keys whose values have `index_key` in the secondary index `name`, in key order,
+    /// see [Db::register_index].
+    pub fn index_get<K: AsRef<[u8]>>(
+        &self,
+        name: &str,
+        index_key: K,
+    ) -> Result<Vec<Vec<u8>>, DbError> {
+        let sentinel_addr = self.header.index_sentinel_addr;
+        if sentinel_addr.is_null() {
+            return Ok(Vec::new());
+        }
+        let entry_key = secondary_index::entry_key(name, index_key.as_ref());
+        self.index_entry(&entry_key, sentinel_addr)
+    }
+
+    /// The sorted keys of an entry of the secondary indexes.
+    fn index_entry(
+        &self,
+        entry_key: &[u8],
+        sentinel_addr: DiskAddress,
+    ) -> Result<Vec<Vec<u8>>, DbError> {
+        match self
+            .merkle
+            .get(entry_key, sentinel_addr)
+            .map_err(DbError::Merkle)?
+        {
+            Some(entry) => secondary_index::decode_entry(&entry),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Get the last number of the sequence `name` committed in this revision, 0 if none was, see
+    /// [Db::next_sequence].
+    pub fn sequence(&self, name: &str) -> Result<u64, DbError> {
+        let sentinel_addr = self.header.index_sentinel_addr;
+        if sentinel_addr.is_null() {
+            return Ok(0);
+        }
+        match self
+            .merkle
+            .get(sequence::key(name), sentinel_addr)
+            .map_err(DbError::Merkle)?
+        {
+            Some(last) => sequence::decode(&last),
+            None => Ok(0),
         }
     }
 
@@ -371,11 +949,79 @@ impl<T: LinearStore> DbRev<T> {
             .map_err(DbError::Merkle)
     }
 
+    /// Stream the key-value pairs under `prefix`, such as the storage of an account, in pages of
+    /// at most `page_len` pairs, each with the proof of its range if `prove`, see [DumpPage].
+    /// The pages are read as the stream is polled, so the pairs are never all in memory.
+    pub fn dump_prefix<'a>(
+        &'a self,
+        prefix: &'a [u8],
+        page_len: usize,
+        prove: bool,
+    ) -> Result<impl Stream<Item = Result<DumpPage, DbError>> + 'a, DbError> {
+        if page_len == 0 {
+            return Err(DbError::InvalidParams);
+        }
+        Ok(dump::pages(self, prefix, page_len, prove))
+    }
+
     pub fn prove<K: AsRef<[u8]>>(&self, key: K) -> Result<Proof<Vec<u8>>, MerkleError> {
+        let _timer = self.op_stats.start(Op::Prove);
         self.merkle.prove::<K>(key, self.header.sentinel_addr)
     }
 
-    /// Verifies a range proof is valid for a set of keys.
+    /// Get the nodes of the trie of the generic key-value storage with a degenerate shape, see
+    /// [Merkle::audit] and [Db::repair_trie].
+    pub fn audit(&self) -> Result<Vec<DegenerateNode>, DbError> {
+        self.merkle
+            .audit(self.header.sentinel_addr)
+            .map_err(DbError::Merkle)
+    }
+
+    /// Walk the trie of the generic key-value storage with `visitor`, see [Merkle::walk].
+    pub fn walk<V: TrieVisitor>(&self, visitor: &mut V) -> Result<bool, DbError> {
+        self.merkle
+            .walk(self.header.sentinel_addr, visitor)
+            .map_err(DbError::Merkle)
+    }
+
+    /// The range of the payload store the nodes of the upper levels of the trie are kept in, if
+    /// the DB has any, see [DbConfig::hot_levels].
+    pub fn hot_segment(&self) -> Option<Range<usize>> {
+        self.merkle.hot_segment()
+    }
+
+    /// Returns the nodes of this revision that differ from `other`, in key order, see
+    /// [DivergingNode]. At most `limit` nodes are returned.
+    pub fn diverging_nodes<U: LinearStore>(
+        &self,
+        other: &DbRev<U>,
+        limit: usize,
+    ) -> Result<Vec<DivergingNode>, DbError> {
+        self.merkle
+            .diverging_nodes(
+                self.header.sentinel_addr,
+                &other.merkle,
+                other.header.sentinel_addr,
+                limit,
+            )
+            .map_err(DbError::Merkle)
+    }
+
+    /// Whether every key of this revision is in `other` with the same value, see
+    /// [Merkle::is_subset].
+    pub fn is_subset<U: LinearStore>(&self, other: &DbRev<U>) -> Result<bool, DbError> {
+        self.merkle
+            .is_subset(
+                self.header.sentinel_addr,
+                &other.merkle,
+                other.header.sentinel_addr,
+            )
+            .map_err(DbError::Merkle)
+    }
+
+    /// Verifies a range proof is valid for a set of keys against the root hash of this
+    /// revision, see [Proof::verify_range_proof]. Returns whether the revision has keys after
+    /// the range.
     pub fn verify_range_proof<N: AsRef<[u8]> + Send, K: AsRef<[u8]>, V: AsRef<[u8]>>(
         &self,
         proof: Proof<N>,
@@ -392,23 +1038,384 @@ impl<T: LinearStore> DbRev<T> {
 }
 
 impl DbRev<StoreRevMut> {
-    fn borrow_split(&mut self) -> (&mut shale::Obj<DbHeader>, &mut Merkle<StoreRevMut, Bincode>) {
-        (&mut self.header, &mut self.merkle)
+    fn flush_dirty(&mut self) -> Result<(), DbError> {
+        self.header.flush_dirty();
+        self.merkle
+            .place_hot_levels(self.header.sentinel_addr)
+            .map_err(DbError::Merkle)?;
+        self.merkle.flush_dirty().map_err(DbError::Merkle)
     }
 
-    fn flush_dirty(&mut self) -> Option<()> {
-        self.header.flush_dirty();
-        self.merkle.flush_dirty()?;
-        Some(())
+    /// Apply the operations of a batch, keeping the counts in the header and the secondary
+    /// `indexes` up to date.
+    fn apply_batch<K: KeyType, V: ValueType>(
+        &mut self,
+        data: Batch<K, V>,
+        indexes: &SecondaryIndexes,
+    ) -> Result<ChangedKeys, DbError> {
+        let sentinel_addr = self.header.sentinel_addr;
+        let TrieCounts {
+            keys: mut key_count,
+            mut value_bytes,
+        } = self.counts();
+        let indexes = indexes.read();
+        // the old values are only needed to update the indexes
+        let indexed = !indexes.is_empty();
+        // the inserts are timed while the revision they change is borrowed
+        let op_stats = self.op_stats.clone();
+        let mut changed = ChangedKeys::default();
+        let mut dropped = Vec::new();
+
+        for op in data {
+            match op {
+                BatchOp::Put { key, value } => {
+                    self.hot_keys.record(key.as_ref(), Access::Write);
+                    changed.insert(key.as_ref());
+                    let _timer = op_stats.start(Op::Insert);
+                    // the insert returns the length of the old value, which is only read for
+                    // the indexes
+                    if indexed {
+                        let (_, old) = self.get_old(&key, true)?;
+                        self.update_indexes(&indexes, &key, old.as_deref(), Some(value.as_ref()))?;
+                    }
+                    let old_len = self
+                        .merkle
+                        .insert(key, value.as_ref().to_vec(), sentinel_addr)
+                        .map_err(DbError::Merkle)?;
+
+                    match old_len {
+                        Some(old_len) => value_bytes = value_bytes.saturating_sub(old_len as u64),
+                        None => key_count += 1,
+                    }
+                    value_bytes += value.as_ref().len() as u64;
+                }
+                BatchOp::Delete { key } => {
+                    self.hot_keys.record(key.as_ref(), Access::Write);
+                    changed.insert(key.as_ref());
+                    let old = self
+                        .merkle
+                        .remove(&key, sentinel_addr)
+                        .map_err(DbError::Merkle)?;
+
+                    if let Some(old) = old {
+                        self.update_indexes(&indexes, &key, Some(&old), None)?;
+                        key_count = key_count.saturating_sub(1);
+                        value_bytes = value_bytes.saturating_sub(old.len() as u64);
+                    }
+                }
+                BatchOp::Move {
+                    key,
+                    new_key,
+                    overwrite,
+                } => {
+                    self.hot_keys.record(key.as_ref(), Access::Write);
+                    self.hot_keys.record(new_key.as_ref(), Access::Write);
+                    changed.insert(key.as_ref());
+                    changed.insert(new_key.as_ref());
+                    let (overwritten, overwritten_value) = self.get_old(&new_key, indexed)?;
+
+                    if key.as_ref() == new_key.as_ref() {
+                        overwritten.ok_or(DbError::KeyNotFound)?;
+                        continue;
+                    }
+                    if overwritten.is_some() && !overwrite {
+                        return Err(DbError::KeyExists(new_key.as_ref().to_vec()));
+                    }
+
+                    // the removed value is moved into its new node as is
+                    let value = self
+                        .merkle
+                        .remove(&key, sentinel_addr)
+                        .map_err(DbError::Merkle)?
+                        .ok_or(DbError::KeyNotFound)?;
+                    self.update_indexes(&indexes, &key, Some(&value), None)?;
+                    self.update_indexes(
+                        &indexes,
+                        &new_key,
+                        overwritten_value.as_deref(),
+                        Some(&value),
+                    )?;
+                    self.merkle
+                        .insert(new_key, value, sentinel_addr)
+                        .map_err(DbError::Merkle)?;
+
+                    if let Some(old_len) = overwritten {
+                        key_count = key_count.saturating_sub(1);
+                        value_bytes = value_bytes.saturating_sub(old_len);
+                    }
+                }
+                BatchOp::DeletePrefix { prefix } => {
+                    self.hot_keys.record(prefix.as_ref(), Access::Write);
+                    changed.insert_prefix(prefix.as_ref());
+                    // only the indexes need every key removed, so the subtree is walked for
+                    // them alone; the counts follow as reclaim_dropped frees its nodes
+                    let subtree = if indexed {
+                        let mut removed = Vec::new();
+                        let subtree = self
+                            .merkle
+                            .remove_prefix(&prefix, sentinel_addr, |key, value| {
+                                removed.push((key.to_vec(), value.to_vec()));
+                            })
+                            .map_err(DbError::Merkle)?;
+                        for (key, value) in removed {
+                            self.update_indexes(&indexes, &key, Some(&value), None)?;
+                        }
+                        subtree
+                    } else {
+                        self.merkle
+                            .drop_prefix(&prefix, sentinel_addr)
+                            .map_err(DbError::Merkle)?
+                    };
+                    match subtree {
+                        Some(Child::Node(root)) => dropped.push(root),
+                        Some(Child::Inline(leaf)) => {
+                            key_count = key_count.saturating_sub(1);
+                            value_bytes = value_bytes.saturating_sub(leaf.value.len() as u64);
+                        }
+                        None => (),
+                    }
+                }
+            }
+        }
+        self.reclaim_dropped(dropped, |value| {
+            key_count = key_count.saturating_sub(1);
+            value_bytes = value_bytes.saturating_sub(value.len() as u64);
+        })?;
+
+        if (key_count, value_bytes) != (self.header.key_count, self.header.value_bytes) {
+            #[allow(clippy::unwrap_used)]
+            self.header
+                .modify(|header| {
+                    header.key_count = key_count;
+                    header.value_bytes = value_bytes;
+                })
+                .unwrap();
+        }
+
+        Ok(changed)
+    }
+
+    /// The length of the value of `key`, and the value itself if it is `needed`.
+    fn get_old<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+        needed: bool,
+    ) -> Result<(Option<u64>, Option<Vec<u8>>), DbError> {
+        let old = self
+            .merkle
+            .get(key, self.header.sentinel_addr)
+            .map_err(DbError::Merkle)?;
+        Ok(match old {
+            Some(old) => (Some(old.len() as u64), needed.then(|| old.to_vec())),
+            None => (None, None),
+        })
+    }
+
+    /// Moves `key` to the entries of the `indexes` for its `new` value, out of the entries for its
+    /// `old` one.
+    fn update_indexes<K: AsRef<[u8]>>(
+        &mut self,
+        indexes: &[SecondaryIndex],
+        key: K,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<(), DbError> {
+        let key = key.as_ref();
+        for update in indexes.iter().filter_map(|index| index.update(old, new)) {
+            let sentinel_addr = self.index_sentinel_addr()?;
+
+            if let Some(entry_key) = update.remove {
+                let mut keys = self.index_entry(&entry_key, sentinel_addr)?;
+                if let Ok(i) = keys.binary_search_by(|k| k.as_slice().cmp(key)) {
+                    keys.remove(i);
+                }
+                if keys.is_empty() {
+                    self.merkle
+                        .remove(entry_key, sentinel_addr)
+                        .map_err(DbError::Merkle)?;
+                } else {
+                    self.merkle
+                        .insert(
+                            entry_key,
+                            secondary_index::encode_entry(&keys),
+                            sentinel_addr,
+                        )
+                        .map_err(DbError::Merkle)?;
+                }
+            }
+
+            if let Some(entry_key) = update.add {
+                let mut keys = self.index_entry(&entry_key, sentinel_addr)?;
+                if let Err(i) = keys.binary_search_by(|k| k.as_slice().cmp(key)) {
+                    keys.insert(i, key.to_vec());
+                }
+                self.merkle
+                    .insert(
+                        entry_key,
+                        secondary_index::encode_entry(&keys),
+                        sentinel_addr,
+                    )
+                    .map_err(DbError::Merkle)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the last numbers handed out of the `sequences` that changed since this revision.
+    fn write_sequences(&mut self, sequences: &Sequences) -> Result<(), DbError> {
+        for (name, last) in sequences.last_numbers() {
+            if self.sequence(&name)? == last {
+                continue;
+            }
+            let sentinel_addr = self.index_sentinel_addr()?;
+            self.merkle
+                .insert(
+                    sequence::key(&name),
+                    sequence::encode(last).to_vec(),
+                    sentinel_addr,
+                )
+                .map_err(DbError::Merkle)?;
+        }
+        Ok(())
+    }
+
+    /// Frees up to [reclaim::NODES_PER_BATCH] nodes of the subtrees `dropped` by this batch and
+    /// of the ones left by the batches before it, see [reclaim]. The roots of the rest are left
+    /// for the next batches. Calls `freed` with the value of each key of the freed nodes.
+    fn reclaim_dropped(
+        &mut self,
+        mut dropped: Vec<DiskAddress>,
+        freed: impl FnMut(&[u8]),
+    ) -> Result<(), DbError> {
+        let sentinel_addr = self.header.index_sentinel_addr;
+        let left = if sentinel_addr.is_null() {
+            None
+        } else {
+            self.merkle
+                .get(reclaim::KEY, sentinel_addr)
+                .map_err(DbError::Merkle)?
+                .map(|left| reclaim::decode(&left))
+                .transpose()?
+        };
+        let was_left = left.is_some();
+        dropped.extend(left.into_iter().flatten());
+        if dropped.is_empty() {
+            return Ok(());
+        }
+
+        self.merkle
+            .reclaim(&mut dropped, reclaim::NODES_PER_BATCH, freed)
+            .map_err(DbError::Merkle)?;
+        if !dropped.is_empty() {
+            let sentinel_addr = self.index_sentinel_addr()?;
+            self.merkle
+                .insert(reclaim::KEY, reclaim::encode(&dropped), sentinel_addr)
+                .map_err(DbError::Merkle)?;
+        } else if was_left {
+            self.merkle
+                .remove(reclaim::KEY, sentinel_addr)
+                .map_err(DbError::Merkle)?;
+        }
+        Ok(())
+    }
+
+    /// The sentinel of the trie of the secondary indexes, which is created on first use.
+    fn index_sentinel_addr(&mut self) -> Result<DiskAddress, DbError> {
+        if self.header.index_sentinel_addr.is_null() {
+            let sentinel_addr = self.merkle.init_sentinel().map_err(DbError::Merkle)?;
+            #[allow(clippy::unwrap_used)]
+            self.header
+                .modify(|header| header.index_sentinel_addr = sentinel_addr)
+                .unwrap();
+        }
+        Ok(self.header.index_sentinel_addr)
+    }
+}
+
+/// The number of keys and the total size of their values in a trie.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrieCounts {
+    pub keys: u64,
+    pub value_bytes: u64,
+}
+
+/// What committing a batch would result in, see [Db::dry_run].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DryRun {
+    /// Root hash of the revision the batch would produce.
+    pub root_hash: TrieHash,
+    /// Counts of the revision the batch would produce.
+    pub counts: TrieCounts,
+    /// Number of store pages the batch changes, which is what committing it would write.
+    pub changed_pages: usize,
+}
+
+impl DryRun {
+    /// Applies `data` to `rev`, a revision on top of the in-memory `store`.
+    fn new<K: KeyType, V: ValueType>(
+        store: &Universe<StoreRevMut>,
+        mut rev: DbRev<StoreRevMut>,
+        data: Batch<K, V>,
+        indexes: &SecondaryIndexes,
+    ) -> Result<Self, DbError> {
+        rev.apply_batch(data, indexes)?;
+        let root_hash = rev.kv_root_hash()?;
+        #[allow(clippy::unwrap_used)]
+        rev.flush_dirty().unwrap();
+
+        Ok(Self {
+            root_hash,
+            counts: rev.counts(),
+            changed_pages: store.merkle.meta.delta_pages() + store.merkle.payload.delta_pages(),
+        })
+    }
+}
+
+/// How a DB was recovered from its Wal when it was opened, see [Db::recovery_report].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Number of Wal records replayed on top of the store files.
+    pub records_replayed: usize,
+    /// Number of writes of the replayed records the store files already held, which were
+    /// skipped. A crash after the pages of a commit were partially written leaves some of them.
+    pub writes_skipped: usize,
+    /// Number of writes of the replayed records made over bytes that held neither what the
+    /// record found there nor what it wrote, see
+    /// [ReplayStats::diverged](crate::storage::buffer::ReplayStats::diverged).
+    pub writes_diverged: usize,
+    /// Whether the Wal ended with a record that was only partially written, most likely because
+    /// the process crashed in the middle of writing it. Whatever that record held is lost.
+    pub truncated_tail: bool,
+    /// Root hash of the recovered revision.
+    pub root_hash: TrieHash,
+    /// Time spent replaying the Wal.
+    pub elapsed: Duration,
+    /// Whether the DB was last closed with [Db::close] and hasn't changed since, in which case
+    /// the Wal was only scanned and nothing was replayed.
+    pub clean_shutdown: bool,
+}
+
+impl DbRev<StoreRevShared> {
+    /// The stores of this revision, shared with it.
+    fn universe(&self) -> Universe<StoreRevShared> {
+        let (meta, payload) = self.merkle.linear_stores();
+        Universe {
+            merkle: SubUniverse::new(meta, payload),
+        }
     }
 }
 
 impl From<DbRev<StoreRevMut>> for DbRev<StoreRevShared> {
     fn from(mut value: DbRev<StoreRevMut>) -> Self {
-        value.flush_dirty();
+        #[allow(clippy::unwrap_used)]
+        value.flush_dirty().unwrap();
         DbRev {
             header: value.header,
             merkle: value.merkle.into(),
+            hot_keys: value.hot_keys,
+            op_stats: value.op_stats,
+            missing_keys: value.missing_keys,
         }
     }
 }
@@ -421,12 +1428,25 @@ struct DbInner {
     // Whether to reset the store headers when creating a new store on top of the cached store.
     reset_store_headers: bool,
     root_hash_staging: StoreRevMut,
+    // Set by `Db::close`, after which nothing is committed.
+    closed: bool,
+    // Held by the commits while they write, see `Db::freeze_writes`.
+    write_fence: WriteFence,
+    // Checked by the commits before they change anything, see `DbConfig::min_free_space`.
+    disk_space: DiskSpace,
+    // How far the pages of the hot segment of the trie are pinned, see `DbConfig::hot_levels`.
+    hot_pinned: usize,
+    // Released only after the disk thread has stopped writing.
+    _lock: DbLock,
 }
 
 impl Drop for DbInner {
     fn drop(&mut self) {
-        self.disk_requester.shutdown();
-        self.disk_thread.take().map(JoinHandle::join);
+        // `Db::close` may have stopped the disk thread already
+        if let Some(disk_thread) = self.disk_thread.take() {
+            self.disk_requester.shutdown();
+            let _ = disk_thread.join();
+        }
     }
 }
 
@@ -436,15 +1456,41 @@ impl api::Db for Db {
 
     type Proposal = proposal::Proposal;
 
+    /// A revision is opened once for all the handles to it, so that the queries of a revision
+    /// share its node cache, and only the first of them rewinds the Wal, which blocks the
+    /// commits. It is closed once every handle to it is dropped.
     async fn revision(&self, root_hash: HashKey) -> Result<Arc<Self::Historical>, api::Error> {
-        let rev = self.get_revision(&TrieHash(root_hash));
-        if let Some(rev) = rev {
-            Ok(Arc::new(rev))
-        } else {
-            Err(api::Error::HashNotFound {
-                provided: root_hash,
-            })
+        if let Some(rev) = self
+            .opened_revisions
+            .lock()
+            .get(&root_hash)
+            .and_then(Weak::upgrade)
+        {
+            return Ok(rev);
         }
+
+        let rev = match self.get_revision(&TrieHash(root_hash)) {
+            Some(rev) => Arc::new(rev),
+            // nothing was committed to a new DB, so the root hash of its empty trie isn't recorded
+            None => {
+                let latest = self.latest_revision();
+                if latest.kv_root_hash().ok() != Some(TrieHash(root_hash)) {
+                    return Err(api::Error::HashNotFound {
+                        provided: root_hash,
+                    });
+                }
+                return Ok(latest);
+            }
+        };
+
+        let mut opened = self.opened_revisions.lock();
+        opened.retain(|_, rev| rev.strong_count() > 0);
+        // another query may have opened the same revision meanwhile
+        if let Some(rev) = opened.get(&root_hash).and_then(Weak::upgrade) {
+            return Ok(rev);
+        }
+        opened.insert(root_hash, Arc::downgrade(&rev));
+        Ok(rev)
     }
 
     async fn root_hash(&self) -> Result<HashKey, api::Error> {
@@ -466,6 +1512,7 @@ pub struct DbRevInner<T> {
     max_revisions: usize,
     base: Universe<StoreRevShared>,
     base_revision: Arc<DbRev<T>>,
+    negative_cache: NegativeCache,
 }
 
 /// Firewood database handle.
@@ -473,20 +1520,49 @@ pub struct DbRevInner<T> {
 pub struct Db {
     inner: Arc<RwLock<DbInner>>,
     revisions: Arc<Mutex<DbRevInner<StoreRevShared>>>,
+    /// The revisions opened by [api::Db::revision] that still have a handle.
+    opened_revisions: Mutex<HashMap<HashKey, Weak<DbRev<StoreRevShared>>>>,
     payload_regn_nbit: u64,
     metrics: Arc<DbMetrics>,
     cfg: DbConfig,
+    cache_manifest: PathBuf,
+    cache_primer: CachePrimer,
+    cache_tuner: CacheTuner,
+    commit_hooks: CommitHooks,
+    batch_validators: BatchValidators,
+    secondary_indexes: SecondaryIndexes,
+    sequences: Sequences,
+    hot_keys: HotKeys,
+    op_stats: OpRecorder,
+    memory_budget: MemoryBudget,
+    recovery_report: Option<RecoveryReport>,
+    quarantine: Quarantine,
+    path: PathBuf,
+    diagnostics: PathBuf,
+    // Set once `close` has shut the DB down, leaving nothing to do when it is dropped.
+    closed: bool,
+}
+
+impl Drop for Db {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+
+        // the primer reads through the disk buffer, so it has to stop before the buffer does
+        self.cache_primer.stop();
+        self.cache_tuner.stop();
+        self.save_cache_manifest(&self.revisions.lock().base_revision);
+    }
 }
 
 #[metered(registry = DbMetrics, visibility = pub)]
 impl Db {
-    const PARAM_SIZE: u64 = size_of::<DbParams>() as u64;
+    const PARAM_SIZE: u64 = DbParams::SIZE as u64;
+    /// Where the StoreHeader of the hot segment of the trie is, see [DbConfig::hot_levels].
+    const HOT_HEADER_OFFSET: u64 = Db::PARAM_SIZE + DbHeader::MSIZE + StoreHeader::SERIALIZED_LEN;
 
     pub async fn new<P: AsRef<Path>>(db_path: P, cfg: &DbConfig) -> Result<Self, api::Error> {
-        if cfg.truncate {
-            let _ = tokio::fs::remove_dir_all(db_path.as_ref()).await;
-        }
-
         #[cfg(feature = "logger")]
         // initialize the logger, but ignore if this fails. This could fail because the calling
         // library already initialized the logger or if you're opening a second database
@@ -496,20 +1572,61 @@ impl Db {
             .map_err(|e| api::Error::InternalError(Box::new(e)))
     }
 
+    /// Opens the latest revision of the DB in `db_path`, closed with [Db::close], to be read
+    /// straight from its memory-mapped store files, see [MmapStore](shale::mmap::MmapStore),
+    /// rather than through the page caches of an open DB. Of `cfg`, only the parameters of the
+    /// revisions are used. No writer can open the DB until the revision is dropped.
+    ///
+    /// Fails with [DbError::AlreadyOpen] if the DB is open for writing, and with
+    /// [DbError::InvalidParams] if it wasn't closed since it was last written to, as some of its
+    /// changes might then only be in the Wal, or if its hot segment is in a directory of its
+    /// own, see [DbConfig::hot_segment_dir].
+    pub fn open_mapped<P: AsRef<Path>>(db_path: P, cfg: &DbConfig) -> Result<MappedRev, DbError> {
+        mapped::open(db_path.as_ref(), cfg)
+    }
+
     /// Open a database.
-    fn new_internal<P: AsRef<Path>>(db_path: P, cfg: DbConfig) -> Result<Self, DbError> {
-        let open_options = if cfg.truncate {
-            file::Options::Truncate
+    fn new_internal<P: AsRef<Path>>(db_path: P, mut cfg: DbConfig) -> Result<Self, DbError> {
+        let (db_path, lock, reset_store_headers) = if cfg.read_only {
+            if cfg.truncate {
+                return Err(DbError::InvalidParams);
+            }
+            let db_path = db_path.as_ref().to_path_buf();
+            if !db_path.is_dir() {
+                return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+            }
+            let lock = DbLock::reader(&db_path)?;
+            (db_path, lock, false)
         } else {
-            file::Options::NoTruncate
+            let (db_path, created) = file::open_dir(db_path, file::Options::NoTruncate)?;
+            let lock = DbLock::writer(&db_path)?;
+            if cfg.truncate {
+                let _no_readers = DbLock::no_readers(&db_path)?;
+                file::clear_dir(&db_path, &[lock::LOCK_FILE, lock::READERS_LOCK_FILE])?;
+            }
+            (db_path, lock, created || cfg.truncate)
         };
 
-        let (db_path, reset_store_headers) = file::open_dir(db_path, open_options)?;
-
         let merkle_path = file::touch_dir("merkle", &db_path)?;
         let merkle_meta_path = file::touch_dir("meta", &merkle_path)?;
         let merkle_payload_path = file::touch_dir("compact", &merkle_path)?;
         let root_hash_path = file::touch_dir("root_hash", &db_path)?;
+        let hot_segment_record = db_path.join(HOT_SEGMENT_DIR_FILE);
+        if reset_store_headers {
+            if let Some(hot_segment_dir) = &mut cfg.hot_segment_dir {
+                std::fs::create_dir_all(&*hot_segment_dir)?;
+                *hot_segment_dir = std::fs::canonicalize(&*hot_segment_dir)?;
+                file::clear_dir(hot_segment_dir, &[])?;
+                std::fs::write(&hot_segment_record, hot_segment_dir.as_os_str().as_bytes())?;
+            }
+        } else {
+            // the files of the hot segment are where they were when the DB was created
+            cfg.hot_segment_dir = match std::fs::read(&hot_segment_record) {
+                Ok(dir) => Some(PathBuf::from(OsString::from_vec(dir))),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+        }
 
         let meta_file = crate::file::File::new(0, RESERVED_STORE_ID, &merkle_meta_path)?;
         let meta_fd = meta_file.as_fd();
@@ -518,26 +1635,41 @@ impl Db {
             // initialize DbParams
             if cfg.payload_file_nbit < cfg.payload_regn_nbit
                 || cfg.payload_regn_nbit < PAGE_SIZE_NBIT
+                || cfg.payload_regn_nbit < cfg.payload_align_nbit
             {
                 return Err(DbError::InvalidParams);
             }
             Self::initialize_header_on_disk(&cfg, meta_fd)?;
         }
 
-        // read DbParams
-        let mut header_bytes = [0; size_of::<DbParams>()];
-        nix::sys::uio::pread(meta_fd, &mut header_bytes, 0).map_err(DbError::System)?;
+        let params = Self::read_params(meta_fd)?;
         drop(meta_file);
-        #[allow(clippy::indexing_slicing)]
-        let params: DbParams = cast_slice(&header_bytes)[0];
+
+        // the trie already on disk was built with this threshold, and proposals copy the config
+        cfg.inline_value_threshold = params.inline_value_threshold as usize;
+        cfg.node_encoding =
+            NodeEncoding::from_u64(params.node_encoding).ok_or(DbError::InvalidParams)?;
+        cfg.payload_align_nbit = params.payload_align_nbit;
+        cfg.hot_levels = params.hot_levels as usize;
+        // the hot segment starts a file of its own, see StoreConfig::split_dir
+        if cfg.hot_segment_dir.is_some()
+            && u64::from(HOT_SEGMENT_BASE.trailing_zeros()) < params.payload_file_nbit
+        {
+            return Err(DbError::InvalidParams);
+        }
+
+        let memory_budget = MemoryBudget::new(cfg.memory_budget);
+        // a reader never writes the files, so it has nothing to allocate
+        let preallocate = cfg.preallocate && !cfg.read_only;
 
         let (sender, inbound) = tokio::sync::mpsc::unbounded_channel();
-        let disk_requester = DiskBufferRequester::new(sender);
+        let disk_requester = DiskBufferRequester::new(sender).with_memory_budget(&memory_budget);
 
         let wal_config = WalConfig::builder()
             .file_nbit(params.wal_file_nbit)
             .block_nbit(params.wal_block_nbit)
             .max_revisions(cfg.wal.max_revisions)
+            .preallocate(preallocate)
             .build();
 
         let disk_buffer =
@@ -559,10 +1691,12 @@ impl Db {
                 .store_id(ROOT_HASH_STORE_ID)
                 .file_nbit(params.root_hash_file_nbit)
                 .rootdir(root_hash_path)
+                .preallocate(preallocate)
                 .build(),
             disk_requester.clone(),
         )
         .unwrap()
+        .with_memory_budget(&memory_budget)
         .into();
 
         #[allow(clippy::unwrap_used)]
@@ -575,10 +1709,12 @@ impl Db {
                         .store_id(MERKLE_META_STORE_ID)
                         .file_nbit(params.meta_file_nbit)
                         .rootdir(merkle_meta_path)
+                        .preallocate(preallocate)
                         .build(),
                     disk_requester.clone(),
                 )
                 .unwrap()
+                .with_memory_budget(&memory_budget)
                 .into(),
                 CachedStore::new(
                     &StoreConfig::builder()
@@ -587,10 +1723,17 @@ impl Db {
                         .store_id(MERKLE_PAYLOAD_STORE_ID)
                         .file_nbit(params.payload_file_nbit)
                         .rootdir(merkle_payload_path)
+                        .preallocate(preallocate)
+                        .split_dir(
+                            cfg.hot_segment_dir
+                                .clone()
+                                .map(|dir| (HOT_SEGMENT_BASE, dir)),
+                        )
                         .build(),
                     disk_requester.clone(),
                 )
                 .unwrap()
+                .with_memory_budget(&memory_budget)
                 .into(),
             ),
         };
@@ -605,8 +1748,24 @@ impl Db {
             disk_requester.reg_cached_store(cached_store.id(), cached_store.clone_files());
         });
 
-        // recover from Wal
-        disk_requester.init_wal("wal", &db_path);
+        // recover from Wal, which only the writer may do, and which the DB doesn't need if it was
+        // closed cleanly since it was last written to
+        let wal_recovery = if cfg.read_only {
+            None
+        } else {
+            let started = Instant::now();
+            // a marker that can't be read is as good as none
+            let clean_shutdown = matches!(
+                shutdown::take(&db_path).and_then(
+                    |marker| marker.map_or(Ok(false), |marker| marker.is_current(&db_path))
+                ),
+                Ok(true)
+            );
+            let wal_report = disk_requester
+                .recover_wal("wal", &db_path, !clean_shutdown)
+                .map_err(std::io::Error::other)?;
+            Some((wal_report, clean_shutdown, started.elapsed()))
+        };
 
         let base = Universe {
             merkle: get_sub_universe_from_empty_delta(&data_cache.merkle),
@@ -616,6 +1775,10 @@ impl Db {
         let meta: StoreRevMut = base.merkle.meta.clone().into();
         let payload: StoreRevMut = base.merkle.payload.clone().into();
 
+        // the DB parameters and headers, read by every revision and proposal, are kept out of the
+        // reach of the page cache eviction, once the Wal has brought them up to date
+        meta.pin_region(0, RESERVED_STORE_ID)?;
+
         // get references to the DbHeader and the StoreHeader
         // for free space management
         let db_header_ref = Db::get_db_header_ref(&meta)?;
@@ -623,13 +1786,112 @@ impl Db {
             Db::get_payload_header_ref(&meta, Db::PARAM_SIZE + DbHeader::MSIZE)?;
         let header_refs = (db_header_ref, merkle_payload_header_ref);
 
+        let hot_keys = HotKeys::new(&cfg.hot_keys);
+        let op_stats = OpRecorder::new(&cfg.op_stats);
+        let quarantine = Quarantine::new(db_path.join(QUARANTINE_DIR));
         let base_revision = Db::new_revision::<StoreRevMut, _>(
             header_refs,
             (meta, payload),
             params.payload_regn_nbit,
+            cfg.payload_align_nbit,
             cfg.payload_max_walk,
             &cfg.rev,
-        )?;
+            cfg.verify_hashes_on_read,
+            cfg.inline_value_threshold,
+            cfg.node_encoding,
+            cfg.delayed_allocation,
+            cfg.hot_levels,
+            &memory_budget,
+            &cfg.payload_allocator,
+        )?
+        .with_hot_keys(hot_keys.clone())
+        .with_op_stats(op_stats.clone())
+        .with_quarantine(Some(&quarantine));
+
+        // a header corrupted on disk fails the open rather than the allocations made from it
+        base_revision
+            .merkle
+            .check_store_header()
+            .map_err(DbError::Merkle)?;
+
+        // the free list is indexed once the Wal is replayed, and handed from each revision to
+        // the proposals on top of it from then on
+        let base_revision = if cfg.free_list_index && !cfg.read_only {
+            let free_index = base_revision
+                .merkle
+                .build_free_index()
+                .map_err(DbError::Merkle)?;
+            base_revision.with_free_index(Some(free_index))
+        } else {
+            base_revision
+        };
+        // the upper levels of the trie are read by every lookup, so they're never evicted
+        let hot_pinned =
+            pin_hot_segment(&base.merkle.payload, base_revision.merkle.hot_segment(), 0)?;
+        let base_revision = match base_revision.kv_root_hash() {
+            Ok(root_hash) => base_revision.with_revision_hash(root_hash),
+            Err(_) => base_revision,
+        };
+        let negative_cache = NegativeCache::new(&cfg.negative_cache);
+        let base_revision: Arc<DbRev<StoreRevShared>> =
+            Arc::new(DbRev::from(base_revision).with_missing_keys(negative_cache.for_revision()));
+
+        let recovery_report = match wal_recovery {
+            Some(((wal_report, replay_stats), clean_shutdown, elapsed)) => Some(RecoveryReport {
+                records_replayed: wal_report.nrecords,
+                writes_skipped: replay_stats.skipped,
+                writes_diverged: replay_stats.diverged,
+                truncated_tail: wal_report.truncated_tail,
+                root_hash: base_revision.kv_root_hash()?,
+                elapsed,
+                clean_shutdown,
+            }),
+            None => None,
+        };
+
+        // pre-load the nodes that were hot when the DB was last closed, as long as the manifest
+        // was written for the revision we just opened
+        let cache_manifest = db_path.join(cache_manifest::CACHE_MANIFEST_FILE);
+        let manifest = if cfg.read_only {
+            Ok(None)
+        } else {
+            cache_manifest::take(&cache_manifest)
+        };
+        let cache_primer = match manifest {
+            Ok(Some((root_hash, addrs)))
+                if cfg.cache_manifest_nobjs > 0
+                    && base_revision.kv_root_hash().ok() == Some(root_hash) =>
+            {
+                CachePrimer::spawn(
+                    base_revision.clone(),
+                    addrs,
+                    IoScheduler::new(&cfg.background_io),
+                )
+            }
+            _ => CachePrimer::default(),
+        };
+
+        // a read-only handle has no Wal to rewind, so the revision it opened is the only one
+        let (root_hashes, max_revisions) = if cfg.read_only {
+            (VecDeque::from([base_revision.kv_root_hash()?]), 1)
+        } else {
+            (VecDeque::new(), cfg.wal.max_revisions as usize)
+        };
+
+        let revisions = Arc::new(Mutex::new(DbRevInner {
+            inner: VecDeque::new(),
+            root_hashes,
+            max_revisions,
+            base,
+            base_revision,
+            negative_cache,
+        }));
+
+        let cache_tuner = if cfg.adaptive_cache.enabled {
+            CacheTuner::spawn(cfg.adaptive_cache.clone(), revisions.clone())
+        } else {
+            CacheTuner::fixed()
+        };
 
         Ok(Self {
             inner: Arc::new(RwLock::new(DbInner {
@@ -638,17 +1900,32 @@ impl Db {
                 cached_store: data_cache,
                 reset_store_headers,
                 root_hash_staging: StoreRevMut::new(root_hash_cache),
+                closed: false,
+                write_fence: WriteFence::default(),
+                disk_space: DiskSpace::new(db_path.clone(), cfg.min_free_space),
+                hot_pinned,
+                _lock: lock,
             })),
-            revisions: Arc::new(Mutex::new(DbRevInner {
-                inner: VecDeque::new(),
-                root_hashes: VecDeque::new(),
-                max_revisions: cfg.wal.max_revisions as usize,
-                base,
-                base_revision: Arc::new(base_revision.into()),
-            })),
+            revisions,
+            opened_revisions: Mutex::default(),
             payload_regn_nbit: params.payload_regn_nbit,
             metrics: Arc::new(DbMetrics::default()),
             cfg: cfg.clone(),
+            cache_manifest,
+            cache_primer,
+            cache_tuner,
+            commit_hooks: CommitHooks::default(),
+            batch_validators: BatchValidators::default(),
+            secondary_indexes: SecondaryIndexes::default(),
+            sequences: Sequences::default(),
+            hot_keys,
+            op_stats,
+            memory_budget,
+            recovery_report,
+            quarantine,
+            diagnostics: db_path.join(DIAGNOSTICS_DIR),
+            path: db_path,
+            closed: false,
         })
     }
 
@@ -657,53 +1934,78 @@ impl Db {
         // DbParams
         // DbHeader (just a pointer to the sentinel)
         // StoreHeader for future allocations
-        let (params, hdr, csh);
-        let header_bytes: Vec<u8> = {
-            params = DbParams {
-                magic: *MAGIC_STR,
-                meta_file_nbit: cfg.meta_file_nbit,
-                payload_file_nbit: cfg.payload_file_nbit,
-                payload_regn_nbit: cfg.payload_regn_nbit,
-                wal_file_nbit: cfg.wal.file_nbit,
-                wal_block_nbit: cfg.wal.block_nbit,
-                root_hash_file_nbit: cfg.root_hash_file_nbit,
-            };
-            let bytes = bytemuck::bytes_of(&params);
-            bytes.iter()
+        // StoreHeader for the allocations of the hot segment, if the DB has one
+        let params = DbParams {
+            magic: *MAGIC_STR,
+            meta_file_nbit: cfg.meta_file_nbit,
+            payload_file_nbit: cfg.payload_file_nbit,
+            payload_regn_nbit: cfg.payload_regn_nbit,
+            wal_file_nbit: cfg.wal.file_nbit,
+            wal_block_nbit: cfg.wal.block_nbit,
+            root_hash_file_nbit: cfg.root_hash_file_nbit,
+            inline_value_threshold: cfg.inline_value_threshold as u64,
+            hash_len: TRIE_HASH_LEN as u64,
+            node_encoding: cfg.node_encoding.to_u64(),
+            payload_align_nbit: cfg.payload_align_nbit,
+            hot_levels: cfg.hot_levels as u64,
+        };
+        let hdr = DbHeader::new_empty();
+        let store_reserved =
+            NonZeroUsize::new(RESERVED_STORE_ID as usize).expect("RESERVED_STORE_ID is non-zero");
+        let csh = StoreHeader::new(store_reserved, store_reserved);
+        let hot_base = NonZeroUsize::new(HOT_SEGMENT_BASE).expect("HOT_SEGMENT_BASE is non-zero");
+        let hot_csh = (cfg.hot_levels > 0).then(|| StoreHeader::new(hot_base, hot_base));
+
+        // every part is written in its little-endian serialized form, see the crate docs
+        let mut header_bytes = params.to_le_bytes().to_vec();
+        let hdr_offset = header_bytes.len();
+        let hot_csh_len = hot_csh.as_ref().map_or(0, StoreHeader::serialized_len);
+        header_bytes.resize(
+            hdr_offset + (hdr.serialized_len() + csh.serialized_len() + hot_csh_len) as usize,
+            0,
+        );
+        #[allow(clippy::indexing_slicing)]
+        {
+            let (hdr_bytes, csh_bytes) =
+                header_bytes[hdr_offset..].split_at_mut(hdr.serialized_len() as usize);
+            let (csh_bytes, hot_csh_bytes) = csh_bytes.split_at_mut(csh.serialized_len() as usize);
+            hdr.serialize(hdr_bytes)?;
+            csh.serialize(csh_bytes)?;
+            if let Some(hot_csh) = hot_csh {
+                hot_csh.serialize(hot_csh_bytes)?;
+            }
         }
-        .chain({
-            // compute the DbHeader as bytes
-            hdr = DbHeader::new_empty();
-            bytemuck::bytes_of(&hdr)
-        })
-        .chain({
-            // write out the StoreHeader
-            let store_reserved = NonZeroUsize::new(RESERVED_STORE_ID as usize)
-                .expect("RESERVED_STORE_ID is non-zero");
-            csh = StoreHeader::new(store_reserved, store_reserved);
-            bytemuck::bytes_of(&csh)
-        })
-        .copied()
-        .collect();
 
         nix::sys::uio::pwrite(fd0, &header_bytes, 0).map_err(DbError::System)?;
         Ok(())
     }
 
+    /// Spills the trie nodes a proposal stages past [DbConfig::overlay_spill_threshold].
+    fn with_spill(&self, store: StoreRevMut) -> StoreRevMut {
+        match self.cfg.overlay_spill_threshold {
+            0 => store,
+            threshold => store.with_spill(threshold, &self.cfg.overlay_spill_dir),
+        }
+    }
+
     /// Create a new mutable store and an alterable revision of the DB on top.
     fn new_store(
         &self,
         cached_store: &Universe<Arc<CachedStore>>,
         reset_store_headers: bool,
+        free_index: Option<FreeIndex>,
     ) -> Result<(Universe<StoreRevMut>, DbRev<StoreRevMut>), DbError> {
         let mut offset = Db::PARAM_SIZE as usize;
         let db_header: DiskAddress = DiskAddress::from(offset);
         offset += DbHeader::MSIZE as usize;
         let merkle_payload_header: DiskAddress = DiskAddress::from(offset);
         offset += StoreHeader::SERIALIZED_LEN as usize;
+        let hot_payload_header: DiskAddress = DiskAddress::from(offset);
+        offset += StoreHeader::SERIALIZED_LEN as usize;
         assert!(offset <= RESERVED_STORE_ID as usize);
 
-        let mut merkle_meta_store = StoreRevMut::new(cached_store.merkle.meta.clone());
+        let mut merkle_meta_store = StoreRevMut::new(cached_store.merkle.meta.clone())
+            .with_memory_budget(&self.memory_budget);
 
         if reset_store_headers {
             // initialize store headers
@@ -720,12 +2022,23 @@ impl Db {
                 db_header.into(),
                 &shale::to_dehydrated(&DbHeader::new_empty())?,
             )?;
+            if self.cfg.hot_levels > 0 {
+                #[allow(clippy::unwrap_used)]
+                let hot_base = NonZeroUsize::new(HOT_SEGMENT_BASE).unwrap();
+                merkle_meta_store.write(
+                    hot_payload_header.into(),
+                    &shale::to_dehydrated(&StoreHeader::new(hot_base, hot_base))?,
+                )?;
+            }
         }
 
         let store = Universe {
             merkle: SubUniverse::new(
                 merkle_meta_store,
-                StoreRevMut::new(cached_store.merkle.payload.clone()),
+                self.with_spill(
+                    StoreRevMut::new(cached_store.merkle.payload.clone())
+                        .with_memory_budget(&self.memory_budget),
+                ),
             ),
         };
 
@@ -740,15 +2053,50 @@ impl Db {
             header_refs,
             (store.merkle.meta.clone(), store.merkle.payload.clone()),
             self.payload_regn_nbit,
+            self.cfg.payload_align_nbit,
             self.cfg.payload_max_walk,
-            &self.cfg.rev,
-        )?;
+            &self.rev_config(),
+            self.cfg.verify_hashes_on_read,
+            self.cfg.inline_value_threshold,
+            self.cfg.node_encoding,
+            self.cfg.delayed_allocation,
+            self.cfg.hot_levels,
+            &self.memory_budget,
+            &self.cfg.payload_allocator,
+        )?
+        .with_free_index(free_index);
         #[allow(clippy::unwrap_used)]
         rev.flush_dirty().unwrap();
 
         Ok((store, rev))
     }
 
+    /// The revision config for new revisions and proposals, with the cache size picked by the
+    /// [CacheTuner].
+    fn rev_config(&self) -> DbRevConfig {
+        DbRevConfig {
+            merkle_cache_bytes: self.cache_max_bytes(),
+            ..self.cfg.rev.clone()
+        }
+    }
+
+    /// Reads the [DbParams] at the start of the meta store, failing if they are of another format.
+    fn read_params(meta_fd: BorrowedFd) -> Result<DbParams, DbError> {
+        let mut header_bytes = [0; DbParams::SIZE];
+        nix::sys::uio::pread(meta_fd, &mut header_bytes, 0).map_err(DbError::System)?;
+        let params = DbParams::from_le_bytes(&header_bytes);
+
+        // the parameters that follow, and the nodes, are laid out as the format of the magic string
+        if params.magic != *MAGIC_STR {
+            return Err(DbError::InvalidParams);
+        }
+        // the hashes stored in the trie can't be read with another length
+        if params.hash_len != TRIE_HASH_LEN as u64 {
+            return Err(DbError::InvalidParams);
+        }
+        Ok(params)
+    }
+
     fn get_payload_header_ref<K: LinearStore>(
         meta_ref: &K,
         header_offset: u64,
@@ -767,12 +2115,21 @@ impl Db {
         StoredView::addr_to_obj(meta_ref, db_header, DbHeader::MSIZE).map_err(Into::into)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn new_revision<K: LinearStore, T: Into<K>>(
         header_refs: (Obj<DbHeader>, Obj<StoreHeader>),
         merkle: (T, T),
         payload_regn_nbit: u64,
+        payload_align_nbit: u64,
         payload_max_walk: u64,
         cfg: &DbRevConfig,
+        verify_hashes_on_read: bool,
+        inline_value_threshold: usize,
+        node_encoding: NodeEncoding,
+        delayed_allocation: bool,
+        hot_levels: usize,
+        memory_budget: &MemoryBudget,
+        allocator: &Arc<dyn Allocator>,
     ) -> Result<DbRev<K>, DbError> {
         // TODO: This should be a compile time check
         const DB_OFFSET: u64 = Db::PARAM_SIZE;
@@ -782,21 +2139,38 @@ impl Db {
         let mut db_header_ref = header_refs.0;
         let merkle_payload_header_ref = header_refs.1;
 
-        let merkle_meta = merkle.0.into();
+        let merkle_meta: K = merkle.0.into();
         let merkle_payload = merkle.1.into();
 
+        let hot_header_ref = (hot_levels > 0)
+            .then(|| Db::get_payload_header_ref(&merkle_meta, Db::HOT_HEADER_OFFSET))
+            .transpose()?;
+
         #[allow(clippy::unwrap_used)]
         let merkle_store = shale::compact::Store::new(
             merkle_meta,
             merkle_payload,
             merkle_payload_header_ref,
-            shale::ObjCache::new(cfg.merkle_ncached_objs),
+            shale::ObjCache::new(cfg.merkle_ncached_objs)
+                .with_max_bytes(cfg.merkle_cache_bytes)
+                .with_memory_budget(memory_budget),
             payload_max_walk,
             payload_regn_nbit,
         )
-        .unwrap();
-
-        let merkle = Merkle::new(merkle_store);
+        .unwrap()
+        .with_allocator(allocator.clone())
+        .with_alignment(payload_align_nbit);
+
+        let mut merkle = Merkle::new(merkle_store)
+            .with_hash_verification(verify_hashes_on_read)
+            .with_inline_value_threshold(inline_value_threshold)
+            .with_node_encoding(node_encoding)
+            .with_delayed_allocation(delayed_allocation);
+        if let Some(hot_header_ref) = hot_header_ref {
+            merkle = merkle
+                .with_hot_levels(hot_header_ref, hot_levels)
+                .map_err(DbError::Merkle)?;
+        }
 
         if db_header_ref.sentinel_addr.is_null() {
             let mut err = Ok(());
@@ -816,6 +2190,9 @@ impl Db {
         Ok(DbRev {
             header: db_header_ref,
             merkle,
+            hot_keys: HotKeys::default(),
+            op_stats: OpRecorder::default(),
+            missing_keys: MissingKeys::default(),
         })
     }
 
@@ -824,50 +2201,178 @@ impl Db {
         &self,
         data: Batch<K, V>,
     ) -> Result<proposal::Proposal, DbError> {
+        self.new_merged_proposal(vec![data])
+            .map(|(proposal, _)| proposal)
+    }
+
+    /// Create a single proposal applying `batches` one after the other, and return it with the
+    /// root hash the trie had after each of them. Each batch is checked by the
+    /// [BatchValidator]s on its own.
+    pub(crate) fn new_merged_proposal<K: KeyType, V: ValueType>(
+        &self,
+        batches: Vec<Batch<K, V>>,
+    ) -> Result<(proposal::Proposal, Vec<TrieHash>), DbError> {
+        if self.cfg.read_only {
+            return Err(DbError::ReadOnly);
+        }
+        for data in &batches {
+            self.batch_validators.validate(data)?;
+        }
+
+        // the revisions are locked before the store, in the order commits and readers lock them,
+        // and the store is held until the proposal is built on the latest revision
+        let revisions = self.revisions.lock();
         let mut inner = self.inner.write();
+        let base_revision = Arc::clone(&revisions.base_revision);
+        drop(revisions);
+
         let reset_store_headers = inner.reset_store_headers;
-        let (store, mut rev) = self.new_store(&inner.cached_store, reset_store_headers)?;
+        let (store, rev) = self.new_store(
+            &inner.cached_store,
+            reset_store_headers,
+            base_revision.merkle.free_index(),
+        )?;
+        let mut rev = rev
+            .with_hot_keys(self.hot_keys.clone())
+            .with_op_stats(self.op_stats.clone())
+            .with_quarantine(Some(&self.quarantine));
+        for data in &batches {
+            store.reserve_for_batch(data);
+        }
 
         // Flip the reset flag after resetting the store headers.
         if reset_store_headers {
             inner.reset_store_headers = false;
         }
 
-        data.into_iter().try_for_each(|op| -> Result<(), DbError> {
-            match op {
-                BatchOp::Put { key, value } => {
-                    let (header, merkle) = rev.borrow_split();
-                    merkle
-                        .insert(key, value.as_ref().to_vec(), header.sentinel_addr)
-                        .map_err(DbError::Merkle)?;
-                    Ok(())
-                }
-                BatchOp::Delete { key } => {
-                    let (header, merkle) = rev.borrow_split();
-                    merkle
-                        .remove(key, header.sentinel_addr)
-                        .map_err(DbError::Merkle)?;
-                    Ok(())
-                }
-            }
-        })?;
+        let mut changed = ChangedKeys::default();
+        let mut root_hashes = Vec::with_capacity(batches.len());
+        for data in batches {
+            changed.extend(rev.apply_batch(data, &self.secondary_indexes)?);
+            root_hashes.push(rev.kv_root_hash()?);
+        }
+        let changes = changed.to_filter();
+        rev.write_sequences(&self.sequences)?;
 
         // Calculated the root hash before flushing so it can be persisted.
-        let root_hash = rev.kv_root_hash()?;
+        let root_hash = match root_hashes.last() {
+            Some(root_hash) => *root_hash,
+            None => rev.kv_root_hash()?,
+        };
         #[allow(clippy::unwrap_used)]
         rev.flush_dirty().unwrap();
 
-        let parent = ProposalBase::View(Arc::clone(&self.revisions.lock().base_revision));
-        Ok(proposal::Proposal {
+        let parent = ProposalBase::View(base_revision);
+        let proposal = proposal::Proposal {
             m: Arc::clone(&self.inner),
             r: Arc::clone(&self.revisions),
-            cfg: self.cfg.clone(),
+            cfg: DbConfig {
+                rev: self.rev_config(),
+                ..self.cfg.clone()
+            },
+            hooks: self.commit_hooks.clone(),
+            validators: self.batch_validators.clone(),
+            indexes: self.secondary_indexes.clone(),
+            sequences: self.sequences.clone(),
+            budget: self.memory_budget.clone(),
+            diagnostics: self.diagnostics.clone(),
             rev,
             store,
             committed: Arc::new(Mutex::new(false)),
             root_hash,
+            annotation: String::new(),
+            changes,
             parent,
-        })
+        };
+        Ok((proposal, root_hashes))
+    }
+
+    /// Compute what committing `data` on top of the latest revision would result in, without
+    /// creating a proposal. The batch is applied to an in-memory overlay that is dropped
+    /// afterwards, so nothing is written to the Wal or the store files, and the registered
+    /// [BatchValidator]s run as they would for a proposal.
+    pub fn dry_run<K: KeyType, V: ValueType>(&self, data: Batch<K, V>) -> Result<DryRun, DbError> {
+        if self.cfg.read_only {
+            return Err(DbError::ReadOnly);
+        }
+        self.batch_validators.validate(&data)?;
+
+        let inner = self.inner.read();
+        let (store, rev) = self.new_store(&inner.cached_store, inner.reset_store_headers, None)?;
+        drop(inner);
+        store.reserve_for_batch(&data);
+
+        DryRun::new(&store, rev, data, &self.secondary_indexes)
+    }
+
+    /// List the revisions the DB retains, from the latest one, with what is recorded about them.
+    /// Any of their root hashes can be passed to [Db::get_revision]. A read-only handle only
+    /// lists the latest revision, as it has no Wal to read the others from.
+    pub fn revision_index(&self) -> Result<Vec<RevisionEntry>, DbError> {
+        let max_revisions = self.revisions.lock().max_revisions;
+        let inner = self.inner.read();
+
+        let mut entries: Vec<_> = if self.cfg.read_only {
+            revision_index::decode(&inner.root_hash_staging, 0)
+                .into_iter()
+                .collect()
+        } else {
+            inner
+                .disk_requester
+                .collect_ash(max_revisions)
+                .map_err(|e| DbError::IO(std::io::Error::other(e)))?
+                .iter()
+                .filter_map(|ash| ash.0.get(&ROOT_HASH_STORE_ID))
+                .enumerate()
+                .filter_map(|(index, ash)| {
+                    let store = StoreRevShared::from_ash(Arc::new(ZeroStore::default()), &ash.redo);
+                    revision_index::decode(&store, index)
+                })
+                .collect()
+        };
+        revision_index::set_size_deltas(&mut entries);
+        Ok(entries)
+    }
+
+    /// Whether `key` may have been written or removed by the commits since the revision of
+    /// `root_hash`, answered from the filters of the keys each commit changed rather than by
+    /// diffing the tries. It never answers that a key didn't change when it did, but does answer
+    /// that it did for about 1% of the keys that didn't, and for all of them if one of the
+    /// commits was made before the filters were recorded. Returns [DbError::RevisionNotFound] if
+    /// the DB doesn't retain the revision. A read-only handle only knows the latest revision,
+    /// since which no key changed.
+    pub fn changed_since<K: KeyType>(&self, root_hash: &TrieHash, key: K) -> Result<bool, DbError> {
+        let max_revisions = self.revisions.lock().max_revisions;
+        let inner = self.inner.read();
+
+        if self.cfg.read_only {
+            return match revision_index::decode(&inner.root_hash_staging, 0) {
+                Some(entry) if entry.root_hash == *root_hash => Ok(false),
+                _ => Err(DbError::RevisionNotFound(*root_hash)),
+            };
+        }
+
+        let ashes = inner
+            .disk_requester
+            .collect_ash(max_revisions)
+            .map_err(|e| DbError::IO(std::io::Error::other(e)))?;
+        let mut changed = false;
+        for ash in ashes
+            .iter()
+            .filter_map(|ash| ash.0.get(&ROOT_HASH_STORE_ID))
+        {
+            let store = StoreRevShared::from_ash(Arc::new(ZeroStore::default()), &ash.redo);
+            let Some(entry) = revision_index::decode(&store, 0) else {
+                continue;
+            };
+            if entry.root_hash == *root_hash {
+                return Ok(changed);
+            }
+            changed = changed
+                || revision_index::decode_changes(&store)
+                    .is_none_or(|changes| changes.may_contain(key.as_ref()));
+        }
+        Err(DbError::RevisionNotFound(*root_hash))
     }
 
     /// Get a handle that grants the access to any committed state of the entire DB,
@@ -949,6 +2454,12 @@ impl Db {
             #[allow(clippy::indexing_slicing)]
             &revisions.inner[nback - 1]
         };
+        // the latest revision shares the keys found missing from it with its other views
+        let missing_keys = if nback == 0 {
+            revisions.base_revision.missing_keys.clone()
+        } else {
+            MissingKeys::default()
+        };
         // Release the lock after we find the revision
         drop(inner_lock);
 
@@ -967,14 +2478,28 @@ impl Db {
             header_refs,
             (store.merkle.meta.clone(), store.merkle.payload.clone()),
             self.payload_regn_nbit,
+            self.cfg.payload_align_nbit,
             0,
-            &self.cfg.rev,
+            &self.rev_config(),
+            self.cfg.verify_hashes_on_read,
+            self.cfg.inline_value_threshold,
+            self.cfg.node_encoding,
+            self.cfg.delayed_allocation,
+            self.cfg.hot_levels,
+            &self.memory_budget,
+            &self.cfg.payload_allocator,
         )
         .unwrap()
+        .with_hot_keys(self.hot_keys.clone())
+        .with_op_stats(self.op_stats.clone())
+        .with_quarantine(Some(&self.quarantine))
+        .with_revision_hash(*root_hash)
+        .with_missing_keys(missing_keys)
         .into()
     }
 
-    /// Dump the Trie of the latest generic key-value storage.
+    /// Dump the Trie of the latest generic key-value storage. To read its key-value pairs, see
+    /// [api::Db::kv_iter] instead.
     pub fn kv_dump(&self, w: &mut dyn Write) -> Result<(), DbError> {
         self.revisions.lock().base_revision.kv_dump(w)
     }
@@ -983,7 +2508,413 @@ impl Db {
         self.revisions.lock().base_revision.kv_root_hash()
     }
 
+    /// Get the latest committed revision.
+    pub(crate) fn latest_revision(&self) -> Arc<DbRev<StoreRevShared>> {
+        self.revisions.lock().base_revision.clone()
+    }
+
+    /// Waits until every commit made so far is in the Wal and its pages are written to the store
+    /// files.
+    pub(crate) fn flush_writes(&self) -> Result<(), DbError> {
+        self.inner
+            .read()
+            .disk_requester
+            .flush()
+            .map_err(|e| DbError::IO(std::io::Error::other(e)))
+    }
+
+    /// The `top_n` key prefixes read and written the most over the last
+    /// [HotKeyConfig::window], with the estimated number of reads and writes of each, from the
+    /// most accessed one. Empty unless [HotKeyConfig::enabled] is set. The reads are those of
+    /// the revisions and proposals of the DB, and the writes those of the batches proposed.
+    pub fn hot_keys(&self, top_n: usize) -> Vec<HotPrefix> {
+        self.hot_keys.top(top_n)
+    }
+
+    /// The latencies of the operations on the revisions and proposals of the DB, by type of
+    /// operation, since it was opened. Empty unless [OpStatsConfig::enabled] is set.
+    pub fn op_stats(&self) -> OpStats {
+        self.op_stats.stats()
+    }
+
+    /// The lookups of missing keys answered by the negative cache, since the DB was opened, and
+    /// the keys cached for the latest revision. Empty unless [NegativeCacheConfig::enabled] is
+    /// set.
+    pub fn negative_cache_stats(&self) -> NegativeCacheStats {
+        let revisions = self.revisions.lock();
+        revisions
+            .negative_cache
+            .stats(&revisions.base_revision.missing_keys)
+    }
+
+    /// The I/O of each store of the DB since it was opened, by [StoreId], to tell the I/O of the
+    /// nodes of the trie, in the [MERKLE_PAYLOAD_STORE_ID], from that of their metadata and of
+    /// the root hashes. The revisions and proposals of a store all count into its
+    /// [StoreStats], see [LinearStore::stats].
+    pub fn space_stats(&self) -> HashMap<StoreId, StoreStats> {
+        let inner = self.inner.read();
+        let stores = &inner.cached_store.merkle;
+        HashMap::from([
+            (MERKLE_META_STORE_ID, stores.meta.stats()),
+            (MERKLE_PAYLOAD_STORE_ID, stores.payload.stats()),
+            (ROOT_HASH_STORE_ID, inner.root_hash_staging.stats()),
+        ])
+    }
+
+    /// The counters of the caches, the reads of the stores, the space of the allocator and the
+    /// writes to the Wal, see [StatsSnapshot].
+    pub fn stats_snapshot(&self) -> StatsSnapshot {
+        let latest = self.latest_revision();
+        let negative_cache = self.negative_cache_stats();
+        let used = |consumer| self.memory_budget.used(consumer) as u64;
+
+        let inner = self.inner.read();
+        let stores = &inner.cached_store.merkle;
+        let (meta, payload) = (stores.meta.page_reads(), stores.payload.page_reads());
+        let wal = inner.disk_requester.wal_writes();
+        drop(inner);
+
+        let (free_chunks, free_bytes) = latest.merkle.free_space().unwrap_or_default();
+        StatsSnapshot {
+            cache: CacheStats {
+                obj_cache_max_bytes: self.cache_max_bytes().map_or(0, |max| max as u64),
+                obj_cache_bytes: used(MemoryConsumer::ObjCache),
+                page_cache_bytes: used(MemoryConsumer::PageCache),
+                page_cache_hits: meta.cache_hits + payload.cache_hits,
+                negative_cache_hits: negative_cache.hits,
+                negative_cache_misses: negative_cache.misses,
+                node_pool_bytes: crate::shale::pool::pooled() as u64,
+            },
+            io: IoStats {
+                buffer_hits: meta.buffer_hits + payload.buffer_hits,
+                file_reads: meta.file_reads + payload.file_reads,
+            },
+            allocator: AllocatorStats {
+                payload_bytes: latest.merkle.data_tail() as u64,
+                free_chunks: free_chunks as u64,
+                free_bytes,
+            },
+            wal: WalStats {
+                records: wal.records,
+                record_bytes: wal.record_bytes,
+                pages_written: wal.pages,
+                buffered_bytes: used(MemoryConsumer::WalBuffer),
+            },
+        }
+    }
+
     pub fn metrics(&self) -> Arc<DbMetrics> {
         self.metrics.clone()
     }
+
+    /// Get the number of keys and value bytes of the latest revision, without scanning the trie.
+    /// The keys of a large [BatchOp::DeletePrefix](api::BatchOp::DeletePrefix) are still counted
+    /// until the batches that follow free their nodes.
+    pub fn counts(&self) -> TrieCounts {
+        self.revisions.lock().base_revision.counts()
+    }
+
+    /// Drops the trie nodes cached by the latest revision and the revisions opened by
+    /// [api::Db::revision] that aren't modified, to release memory on demand, e.g. after a bulk
+    /// import, and returns the estimated bytes released. The caches fill up again as nodes are
+    /// read, up to [Db::cache_max_bytes].
+    pub fn shrink_caches(&self) -> usize {
+        let opened: Vec<_> = self
+            .opened_revisions
+            .lock()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        self.latest_revision().merkle.clear_clean_cache()
+            + opened
+                .iter()
+                .map(|rev| rev.merkle.clear_clean_cache())
+                .sum::<usize>()
+    }
+
+    /// Get the maximum estimated bytes of the trie objects cached per revision, if they are
+    /// limited. This is [DbRevConfig::merkle_cache_bytes] unless the caches adapt to memory
+    /// pressure, see [AdaptiveCacheConfig].
+    pub fn cache_max_bytes(&self) -> Option<usize> {
+        self.cache_tuner
+            .max_bytes()
+            .or(self.cfg.rev.merkle_cache_bytes)
+    }
+
+    /// Get the budget the caches and buffers of the DB are accounted to, see
+    /// [DbConfig::memory_budget].
+    pub const fn memory_budget(&self) -> &MemoryBudget {
+        &self.memory_budget
+    }
+
+    /// What was found in the Wal when the DB was opened, so that callers can tell an abnormal
+    /// recovery, such as one that dropped a [truncated tail](RecoveryReport::truncated_tail),
+    /// from a clean one. `None` for a read-only handle, which doesn't replay the Wal.
+    pub const fn recovery_report(&self) -> Option<&RecoveryReport> {
+        self.recovery_report.as_ref()
+    }
+
+    /// The nodes found corrupted since the DB was opened, because they failed to decode or, with
+    /// [DbConfig::verify_hashes_on_read], their hash didn't match, and the revisions they were
+    /// read from, which are suspect. Each corrupted node is also reported to a file of the
+    /// `quarantine` directory of the DB, with a hexdump of the bytes around it.
+    pub fn health(&self) -> Health {
+        self.quarantine.health()
+    }
+
+    /// Close the DB, making sure everything it committed is on disk. New commits are rejected with
+    /// [DbError::Closed], including those of the proposals still around, then the commits in
+    /// flight and the background tasks are waited for, the pages they wrote are flushed and synced
+    /// to disk, and a clean-shutdown marker is written that lets the next open skip replaying
+    /// the Wal, see [RecoveryReport::clean_shutdown].
+    ///
+    /// Returns [DbError::CloseTimeout] if draining takes longer than `timeout`. The DB is then
+    /// shut down as if it were dropped, without the marker.
+    pub async fn close(mut self, timeout: Duration) -> Result<(), DbError> {
+        block_in_place(|| self.close_internal(Instant::now() + timeout))
+    }
+
+    /// Hold off the commits of the DB while an external tool, such as an LVM, ZFS or EBS
+    /// snapshot, captures an image of its files. The commits in flight are waited for and the
+    /// pages they wrote flushed to the store files, so that the image holds every commit made
+    /// before the freeze, and the commits made after it wait until the returned [WriteFreeze] is
+    /// thawed or dropped. Reads and proposals go on as usual.
+    ///
+    /// The files aren't synced: a tool that needs them on stable storage, as LVM does, freezes
+    /// the file system as well. Returns [DbError::FreezeTimeout] if the commits in flight take
+    /// longer than `timeout`, and [DbError::ReadOnly] for a read-only handle.
+    pub async fn freeze_writes(&self, timeout: Duration) -> Result<WriteFreeze, DbError> {
+        if self.cfg.read_only {
+            return Err(DbError::ReadOnly);
+        }
+
+        block_in_place(|| {
+            let fence = self.inner.read().write_fence.clone();
+            let freeze = fence
+                .freeze_until(Instant::now() + timeout)
+                .ok_or(DbError::FreezeTimeout)?;
+            self.flush_writes()?;
+            Ok(freeze)
+        })
+    }
+
+    fn close_internal(&mut self, deadline: Instant) -> Result<(), DbError> {
+        // the primer reads through the disk buffer, so it has to stop before the buffer does
+        self.cache_primer.stop();
+        self.cache_tuner.stop();
+
+        // commits hold both locks, in this order, while they write
+        let revisions = self
+            .revisions
+            .try_lock_until(deadline)
+            .ok_or(DbError::CloseTimeout)?;
+        let mut inner = self
+            .inner
+            .try_write_until(deadline)
+            .ok_or(DbError::CloseTimeout)?;
+        inner.closed = true;
+        let base_revision = revisions.base_revision.clone();
+        drop(revisions);
+
+        if self.cfg.read_only {
+            self.closed = true;
+            return Ok(());
+        }
+
+        let root_hash = base_revision.kv_root_hash()?;
+        self.save_cache_manifest(&base_revision);
+
+        // the disk thread exits once it has written every page queued before the shutdown
+        inner.disk_requester.shutdown();
+        while inner
+            .disk_thread
+            .as_ref()
+            .is_some_and(|disk_thread| !disk_thread.is_finished())
+        {
+            if Instant::now() >= deadline {
+                return Err(DbError::CloseTimeout);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let _ = inner.disk_thread.take().map(JoinHandle::join);
+        self.closed = true;
+
+        file::sync_dir(&self.path)?;
+        shutdown::save(&self.path, &root_hash)?;
+        Ok(())
+    }
+
+    /// Save the addresses of the hottest nodes of `base_revision` to pre-load them when the DB
+    /// is opened again.
+    fn save_cache_manifest(&self, base_revision: &DbRev<StoreRevShared>) {
+        if self.cfg.cache_manifest_nobjs == 0 || self.cfg.read_only {
+            return;
+        }
+
+        let Ok(root_hash) = base_revision.kv_root_hash() else {
+            return;
+        };
+        let addrs = base_revision
+            .merkle
+            .cached_node_addresses(self.cfg.cache_manifest_nobjs);
+
+        // the manifest is only a hint for the next open, so failing to write it is not an error
+        let _ = cache_manifest::save(&self.cache_manifest, &root_hash, &addrs);
+    }
+
+    /// Register a [CommitHook] to be invoked for every subsequent commit, after any hooks that
+    /// were registered before it.
+    pub fn register_commit_hook(&self, hook: Arc<dyn CommitHook>) {
+        self.commit_hooks.register(hook);
+    }
+
+    /// Register a [BatchValidator] to check every subsequent batch, including the batches of
+    /// proposals built on top of other proposals, before it is applied. Validators run in
+    /// registration order, and the first rejection fails the proposal with
+    /// [DbError::BatchRejected].
+    pub fn register_batch_validator<F>(&self, validator: F)
+    where
+        F: FnMut(&[BatchOp<&[u8], &[u8]>]) -> Result<(), String> + Send + 'static,
+    {
+        self.batch_validators.register(Box::new(validator));
+    }
+
+    /// Register a secondary index `name`, which maps the index key `extract` returns for a value
+    /// to the keys holding such a value, see [Db::index_get]. The index is kept up to date by
+    /// every subsequent batch, in a trie of its own that is committed along with the values.
+    ///
+    /// Indexes are not persisted, so they have to be registered again every time the DB is
+    /// opened, before anything is written: values written without the index registered are
+    /// missing from it. Fails with [DbError::InvalidParams] if there is already an index with
+    /// the same name, or if the name is empty or longer than 255 bytes.
+    pub fn register_index<F>(&self, name: impl Into<String>, extract: F) -> Result<(), DbError>
+    where
+        F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.secondary_indexes
+            .register(name.into(), Box::new(extract))
+    }
+
+    /// Get the keys whose values have `index_key` in the secondary index `name` in the latest
+    /// revision, in key order. Fails with [DbError::UnknownIndex] if no such index is registered.
+    pub fn index_get<K: AsRef<[u8]>>(
+        &self,
+        name: &str,
+        index_key: K,
+    ) -> Result<Vec<Vec<u8>>, DbError> {
+        if !self.secondary_indexes.contains(name) {
+            return Err(DbError::UnknownIndex(name.to_string()));
+        }
+        let base_revision = self.revisions.lock().base_revision.clone();
+        base_revision.index_get(name, index_key)
+    }
+
+    /// Hand out the next number of the sequence `name`, starting at 1. Numbers are never handed
+    /// out twice by the same handle, and no number committed is ever handed out again.
+    ///
+    /// The sequence is committed with the batches: every proposal created afterwards records
+    /// the last number handed out next to its values, in the trie of the secondary indexes, and
+    /// [DbRev::sequence] reads it back. Once the DB is reopened, after a crash or not, the
+    /// numbers handed out since the last commit of such a proposal are handed out again, so a
+    /// number should only be relied on once the batch it was handed out for is committed. Fails
+    /// with [DbError::ReadOnly] on a read-only handle.
+    pub fn next_sequence(&self, name: &str) -> Result<u64, DbError> {
+        if self.cfg.read_only {
+            return Err(DbError::ReadOnly);
+        }
+        self.sequences.next(name, || {
+            let base_revision = self.revisions.lock().base_revision.clone();
+            base_revision.sequence(name)
+        })
+    }
+
+    /// Normalize the degenerate nodes of the latest revision, see [DbRev::audit]. The keys under
+    /// the [subtree_prefixes](merkle::subtree_prefixes) of the nodes are deleted and put again
+    /// in a proposal, which is committed. The keys and values stay the same, but the root hash
+    /// becomes the one of the normalized trie. Returns the degenerate nodes found, and commits
+    /// nothing if there are none.
+    pub async fn repair_trie(&self) -> Result<Vec<DegenerateNode>, DbError> {
+        let latest = self.latest_revision();
+        let degenerate = latest.audit()?;
+        if degenerate.is_empty() {
+            return Ok(degenerate);
+        }
+
+        let mut batch = Vec::new();
+        for prefix in merkle::subtree_prefixes(&degenerate) {
+            let pairs: Vec<_> = latest
+                .stream_from(prefix.clone().into_boxed_slice())
+                .take_while(|pair| {
+                    ready(
+                        pair.as_ref()
+                            .map_or(true, |(key, _)| key.starts_with(&prefix)),
+                    )
+                })
+                .map_ok(|(key, value)| BatchOp::Put {
+                    key: key.into_vec(),
+                    value,
+                })
+                .try_collect()
+                .await
+                .map_err(stream_error)?;
+            batch.push(BatchOp::DeletePrefix { prefix });
+            batch.extend(pairs);
+        }
+
+        block_in_place(|| self.new_proposal(batch)?.commit_sync())?;
+        Ok(degenerate)
+    }
+
+    /// Stream the key-value pairs of the revision with `root_hash` into `sink`, in ranges of at
+    /// most `range_len` pairs, each checked against a proof of the revision first. The
+    /// revision is kept open until the replication is done, however many commits happen
+    /// meanwhile. The replication resumes after the last key the sink wrote, see
+    /// [BatchSink::resume_after], and ends with [BatchSink::finish]. Returns the number of pairs
+    /// written.
+    ///
+    /// Fails with [DbError::RevisionNotFound] if no revision has `root_hash`, and with
+    /// [DbError::Replication] if a range doesn't match its proof or the sink fails.
+    pub async fn replicate_to<S: BatchSink + ?Sized>(
+        &self,
+        root_hash: &TrieHash,
+        sink: &S,
+        range_len: usize,
+    ) -> Result<u64, DbError> {
+        if range_len == 0 {
+            return Err(DbError::InvalidParams);
+        }
+        let rev = self
+            .get_revision(root_hash)
+            .ok_or(DbError::RevisionNotFound(*root_hash))?;
+
+        let mut start = sink
+            .resume_after()
+            .await
+            .map_err(DbError::Replication)?
+            .map(|last| replicate::successor(&last));
+        let mut written = 0;
+        loop {
+            let pairs =
+                replicate::verified_range(&rev, root_hash.0, start.as_deref(), range_len).await?;
+            let Some((last, _)) = pairs.last() else {
+                break;
+            };
+            let next = replicate::successor(last);
+            let len = pairs.len();
+
+            sink.write_range(pairs)
+                .await
+                .map_err(DbError::Replication)?;
+            written += len as u64;
+            if len < range_len {
+                break;
+            }
+            start = Some(next);
+        }
+
+        sink.finish(root_hash.0)
+            .await
+            .map_err(DbError::Replication)?;
+        Ok(written)
+    }
 }
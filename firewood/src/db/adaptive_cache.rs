@@ -0,0 +1,383 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use super::DbRevInner;
+use crate::{config::AdaptiveCacheConfig, logger::debug, storage::StoreRevShared};
+use parking_lot::Mutex;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+/// A sample of the memory pressure the process is under.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct MemoryPressure {
+    /// Fraction of the memory limit in use, not counting reclaimable page cache.
+    usage: f64,
+    /// Percentage of time over the last 10 seconds that some tasks were stalled on memory, if
+    /// the kernel reports it.
+    stall: Option<f64>,
+}
+
+/// Reads the memory pressure from the cgroup and proc file systems.
+#[derive(Debug)]
+struct MemoryProbe {
+    cgroup_root: PathBuf,
+    proc_root: PathBuf,
+}
+
+impl MemoryProbe {
+    fn system() -> Self {
+        Self {
+            cgroup_root: "/sys/fs/cgroup".into(),
+            proc_root: "/proc".into(),
+        }
+    }
+
+    /// Returns `None` if the memory usage can't be determined, e.g. when not running on Linux.
+    fn sample(&self) -> Option<MemoryPressure> {
+        let meminfo = fs::read_to_string(self.proc_root.join("meminfo")).ok()?;
+        let total = meminfo_kb(&meminfo, "MemTotal")? * 1024;
+        let available = meminfo_kb(&meminfo, "MemAvailable")? * 1024;
+
+        let cgroup = self.cgroup_usage();
+
+        let usage = match cgroup {
+            // a limit above the memory of the machine doesn't limit anything
+            Some(CgroupUsage {
+                used,
+                limit: Some(limit),
+                ..
+            }) if limit < total => used as f64 / limit as f64,
+            _ => total.saturating_sub(available) as f64 / total as f64,
+        };
+
+        let stall = cgroup
+            .and_then(|cgroup| cgroup.pressure_file)
+            .or_else(|| Some(self.proc_root.join("pressure/memory")))
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|pressure| some_avg10(&pressure));
+
+        Some(MemoryPressure { usage, stall })
+    }
+
+    /// Finds the memory cgroup of this process in `/proc/self/cgroup`, trying both the v2
+    /// (unified) and the v1 hierarchy. Inside a container, the cgroup is usually mounted at the
+    /// root, so that is tried too.
+    fn cgroup_usage(&self) -> Option<CgroupUsage> {
+        let cgroups = fs::read_to_string(self.proc_root.join("self/cgroup")).ok()?;
+
+        cgroups.lines().find_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let (id, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+            let path = path.trim_start_matches('/');
+
+            if id == "0" && controllers.is_empty() {
+                [self.cgroup_root.join(path), self.cgroup_root.clone()]
+                    .iter()
+                    .find_map(|dir| CgroupUsage::read_v2(dir))
+            } else if controllers.split(',').any(|c| c == "memory") {
+                let root = self.cgroup_root.join("memory");
+                [root.join(path), root]
+                    .iter()
+                    .find_map(|dir| CgroupUsage::read_v1(dir))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct CgroupUsage {
+    /// Bytes in use, without the inactive page cache.
+    used: u64,
+    limit: Option<u64>,
+    pressure_file: Option<PathBuf>,
+}
+
+impl CgroupUsage {
+    fn read_v2(dir: &Path) -> Option<Self> {
+        let current = read_u64(&dir.join("memory.current"))?;
+        let limit = read_u64(&dir.join("memory.max"));
+        let inactive = fs::read_to_string(dir.join("memory.stat"))
+            .ok()
+            .and_then(|stat| stat_value(&stat, "inactive_file"))
+            .unwrap_or(0);
+        let pressure_file = dir.join("memory.pressure");
+
+        Some(Self {
+            used: current.saturating_sub(inactive),
+            limit,
+            pressure_file: pressure_file.exists().then_some(pressure_file),
+        })
+    }
+
+    fn read_v1(dir: &Path) -> Option<Self> {
+        let usage = read_u64(&dir.join("memory.usage_in_bytes"))?;
+        let limit = read_u64(&dir.join("memory.limit_in_bytes"));
+        let inactive = fs::read_to_string(dir.join("memory.stat"))
+            .ok()
+            .and_then(|stat| stat_value(&stat, "total_inactive_file"))
+            .unwrap_or(0);
+
+        Some(Self {
+            used: usage.saturating_sub(inactive),
+            limit,
+            pressure_file: None,
+        })
+    }
+}
+
+/// Reads a file holding a single number. Files holding `max` (no limit) read as `None`.
+fn read_u64(path: &Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn meminfo_kb(meminfo: &str, field: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let value = line.strip_prefix(field)?.strip_prefix(':')?;
+        value.trim().trim_end_matches("kB").trim().parse().ok()
+    })
+}
+
+fn stat_value(stat: &str, field: &str) -> Option<u64> {
+    stat.lines().find_map(|line| {
+        let (name, value) = line.split_once(' ')?;
+        (name == field).then(|| value.trim().parse().ok())?
+    })
+}
+
+/// Parses `avg10` of the `some` line of a pressure stall information file, which looks like
+/// `some avg10=0.00 avg60=0.00 avg300=0.00 total=0`.
+fn some_avg10(pressure: &str) -> Option<f64> {
+    pressure
+        .lines()
+        .find_map(|line| line.strip_prefix("some "))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+/// Computes the next byte limit of the caches. Shrinking halves the limit, so the caches back
+/// off quickly, while growing only adds a quarter. In between the watermarks, the limit is kept.
+pub(super) fn next_max_bytes(
+    current: usize,
+    pressure: MemoryPressure,
+    cfg: &AdaptiveCacheConfig,
+) -> usize {
+    let max = cfg.max_cache_bytes;
+    let min = cfg.min_cache_bytes.min(max);
+    let stalled = pressure
+        .stall
+        .is_some_and(|stall| stall >= cfg.stall_threshold);
+
+    if stalled || pressure.usage >= cfg.high_watermark {
+        (current / 2).clamp(min, max)
+    } else if pressure.usage <= cfg.low_watermark {
+        current
+            .saturating_add(current / 4)
+            .saturating_add(1)
+            .clamp(min, max)
+    } else {
+        current.clamp(min, max)
+    }
+}
+
+/// Limits the bytes of the trie node caches on a background thread, see
+/// [AdaptiveCacheConfig](crate::config::AdaptiveCacheConfig).
+#[derive(Debug)]
+pub(super) struct CacheTuner {
+    max_bytes: Option<Arc<AtomicUsize>>,
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CacheTuner {
+    /// A tuner that leaves the caches at the limits they are configured with.
+    pub(super) const fn fixed() -> Self {
+        Self {
+            max_bytes: None,
+            stop: None,
+            thread: None,
+        }
+    }
+
+    /// Starts sampling the memory pressure. The cache of the latest committed revision is
+    /// limited in place; revisions and proposals created later pick up the current
+    /// [CacheTuner::max_bytes].
+    pub(super) fn spawn(
+        cfg: AdaptiveCacheConfig,
+        revisions: Arc<Mutex<DbRevInner<StoreRevShared>>>,
+    ) -> Self {
+        let probe = MemoryProbe::system();
+        let max_bytes = Arc::new(AtomicUsize::new(cfg.max_cache_bytes));
+        revisions
+            .lock()
+            .base_revision
+            .merkle
+            .set_cache_max_bytes(Some(cfg.max_cache_bytes));
+        let (stop, stopped) = mpsc::channel();
+
+        let thread = std::thread::Builder::new()
+            .name("CacheTuner".to_string())
+            .spawn({
+                let max_bytes = max_bytes.clone();
+                move || {
+                    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(cfg.interval) {
+                        let Some(pressure) = probe.sample() else {
+                            continue;
+                        };
+
+                        let current = max_bytes.load(Ordering::Relaxed);
+                        let next = next_max_bytes(current, pressure, &cfg);
+                        if next != current {
+                            debug!("limiting the trie node caches from {current} to {next} bytes");
+                            max_bytes.store(next, Ordering::Relaxed);
+                            revisions
+                                .lock()
+                                .base_revision
+                                .merkle
+                                .set_cache_max_bytes(Some(next));
+                        }
+                    }
+                }
+            })
+            .expect("thread spawn should succeed");
+
+        Self {
+            max_bytes: Some(max_bytes),
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// The estimated bytes of trie objects new caches should hold at most, if the tuner adapts
+    /// them.
+    pub(super) fn max_bytes(&self) -> Option<usize> {
+        self.max_bytes
+            .as_ref()
+            .map(|max_bytes| max_bytes.load(Ordering::Relaxed))
+    }
+
+    /// Stops sampling and waits for the background thread to exit.
+    pub(super) fn stop(&mut self) {
+        self.stop.take();
+        self.thread.take().map(JoinHandle::join);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn get_tmp_path(name: &str) -> PathBuf {
+        let dir = option_env!("CARGO_TARGET_TMPDIR")
+            .map(Into::into)
+            .unwrap_or(std::env::temp_dir())
+            .join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: PathBuf, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    const MEMINFO: &str =
+        "MemTotal:       16000000 kB\nMemFree:         1000000 kB\nMemAvailable:    4000000 kB\n";
+
+    #[test]
+    fn sample() {
+        let root = get_tmp_path("adaptive_cache_sample");
+        let probe = MemoryProbe {
+            cgroup_root: root.join("cgroup"),
+            proc_root: root.join("proc"),
+        };
+        write(root.join("proc/meminfo"), MEMINFO);
+
+        // no cgroup, so the whole system counts
+        let pressure = probe.sample().unwrap();
+        assert!((pressure.usage - 0.75).abs() < 1e-9);
+        assert_eq!(pressure.stall, None);
+
+        // a v2 cgroup with a limit, some inactive page cache and stalls
+        write(root.join("proc/self/cgroup"), "0::/db.slice\n");
+        let cgroup = root.join("cgroup/db.slice");
+        write(cgroup.join("memory.current"), "800\n");
+        write(cgroup.join("memory.max"), "1000\n");
+        write(cgroup.join("memory.stat"), "anon 500\ninactive_file 200\n");
+        write(
+            cgroup.join("memory.pressure"),
+            "some avg10=12.50 avg60=3.00 avg300=1.00 total=100\nfull avg10=1.00 avg60=0.00 avg300=0.00 total=10\n",
+        );
+        let pressure = probe.sample().unwrap();
+        assert!((pressure.usage - 0.6).abs() < 1e-9);
+        assert_eq!(pressure.stall, Some(12.5));
+
+        // no limit
+        write(cgroup.join("memory.max"), "max\n");
+        let pressure = probe.sample().unwrap();
+        assert!((pressure.usage - 0.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sample_v1() {
+        let root = get_tmp_path("adaptive_cache_sample_v1");
+        let probe = MemoryProbe {
+            cgroup_root: root.join("cgroup"),
+            proc_root: root.join("proc"),
+        };
+        write(root.join("proc/meminfo"), MEMINFO);
+        // the cgroup path isn't visible inside the container, only its root is
+        write(
+            root.join("proc/self/cgroup"),
+            "4:memory:/docker/abc\n0::/\n",
+        );
+        let cgroup = root.join("cgroup/memory");
+        write(cgroup.join("memory.usage_in_bytes"), "950\n");
+        write(cgroup.join("memory.limit_in_bytes"), "1000\n");
+        write(cgroup.join("memory.stat"), "total_inactive_file 50\n");
+
+        let pressure = probe.sample().unwrap();
+        assert!((pressure.usage - 0.9).abs() < 1e-9);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let cfg = AdaptiveCacheConfig::builder()
+            .enabled(true)
+            .min_cache_bytes(100)
+            .max_cache_bytes(1000)
+            .build();
+        let at = |usage| MemoryPressure { usage, stall: None };
+
+        // shrink under pressure, but not below the minimum
+        assert_eq!(next_max_bytes(1000, at(0.95), &cfg), 500);
+        assert_eq!(next_max_bytes(150, at(0.95), &cfg), 100);
+
+        // stalls shrink the cache even with low usage
+        let stalled = MemoryPressure {
+            usage: 0.5,
+            stall: Some(20.0),
+        };
+        assert_eq!(next_max_bytes(1000, stalled, &cfg), 500);
+
+        // keep the size between the watermarks
+        assert_eq!(next_max_bytes(500, at(0.8), &cfg), 500);
+
+        // grow back slowly, up to the maximum
+        assert_eq!(next_max_bytes(500, at(0.5), &cfg), 626);
+        assert_eq!(next_max_bytes(900, at(0.5), &cfg), 1000);
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use super::DbError;
+use crate::v2::api::{Batch, BatchOp, KeyType, ValueType};
+use parking_lot::Mutex;
+use std::{fmt, sync::Arc};
+
+/// A callback that checks a batch before it is applied to a proposal, see
+/// [Db::register_batch_validator](super::Db::register_batch_validator). Returning an error
+/// rejects the whole batch with [DbError::BatchRejected].
+pub type BatchValidator = Box<dyn FnMut(&[BatchOp<&[u8], &[u8]>]) -> Result<(), String> + Send>;
+
+/// The batch validators registered on a [Db](super::Db), shared with all of its proposals.
+#[derive(Clone, Default)]
+pub(super) struct BatchValidators(Arc<Mutex<Vec<BatchValidator>>>);
+
+impl fmt::Debug for BatchValidators {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BatchValidators")
+            .field("len", &self.0.lock().len())
+            .finish()
+    }
+}
+
+impl BatchValidators {
+    pub(super) fn register(&self, validator: BatchValidator) {
+        self.0.lock().push(validator);
+    }
+
+    /// Runs every validator in registration order, stopping at the first one that rejects the
+    /// batch.
+    pub(super) fn validate<K: KeyType, V: ValueType>(
+        &self,
+        batch: &Batch<K, V>,
+    ) -> Result<(), DbError> {
+        let mut validators = self.0.lock();
+        if validators.is_empty() {
+            return Ok(());
+        }
+
+        let ops: Vec<_> = batch
+            .iter()
+            .map(|op| match op {
+                BatchOp::Put { key, value } => BatchOp::Put {
+                    key: key.as_ref(),
+                    value: value.as_ref(),
+                },
+                BatchOp::Delete { key } => BatchOp::Delete { key: key.as_ref() },
+                BatchOp::Move {
+                    key,
+                    new_key,
+                    overwrite,
+                } => BatchOp::Move {
+                    key: key.as_ref(),
+                    new_key: new_key.as_ref(),
+                    overwrite: *overwrite,
+                },
+                BatchOp::DeletePrefix { prefix } => BatchOp::DeletePrefix {
+                    prefix: prefix.as_ref(),
+                },
+            })
+            .collect();
+
+        for validator in validators.iter_mut() {
+            validator(&ops).map_err(DbError::BatchRejected)?;
+        }
+
+        Ok(())
+    }
+}
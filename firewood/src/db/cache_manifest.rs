@@ -0,0 +1,163 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use super::{io_scheduler::IoScheduler, DbRev};
+use crate::{
+    merkle::{TrieHash, TRIE_HASH_LEN},
+    shale::{disk_address::DiskAddress, Storable},
+    storage::StoreRevShared,
+};
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+/// Name of the cache manifest file in the DB directory.
+pub(super) const CACHE_MANIFEST_FILE: &str = "cache_manifest";
+
+const ADDR_LEN: usize = DiskAddress::SERIALIZED_LEN as usize;
+
+/// Writes the cache manifest: the root hash of the revision the addresses belong to, followed by
+/// the node addresses, hottest first. The manifest is written to a temporary file which is then
+/// renamed, so a partially written manifest is never picked up.
+pub(super) fn save(path: &Path, root_hash: &TrieHash, addrs: &[DiskAddress]) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(TRIE_HASH_LEN + addrs.len() * ADDR_LEN);
+    bytes.extend_from_slice(&root_hash.0);
+    for addr in addrs {
+        bytes.extend_from_slice(&addr.to_le_bytes());
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(tmp_path, path)
+}
+
+/// Reads and removes the cache manifest. A manifest is only good for the revision it was written
+/// for, so it is consumed here; the next one is written when the DB is closed again. Returns
+/// `None` if there is no manifest.
+pub(super) fn take(path: &Path) -> io::Result<Option<(TrieHash, Vec<DiskAddress>)>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    fs::remove_file(path)?;
+
+    let malformed = || io::Error::new(ErrorKind::InvalidData, "malformed cache manifest");
+
+    if bytes.len() < TRIE_HASH_LEN {
+        return Err(malformed());
+    }
+    let (root_hash, addrs) = bytes.split_at(TRIE_HASH_LEN);
+    let addrs = addrs.chunks_exact(ADDR_LEN);
+    if !addrs.remainder().is_empty() {
+        return Err(malformed());
+    }
+
+    #[allow(clippy::unwrap_used)]
+    let root_hash = TrieHash(root_hash.try_into().unwrap());
+    #[allow(clippy::unwrap_used)]
+    let addrs = addrs
+        .map(|addr| DiskAddress::try_from(addr).unwrap())
+        .collect();
+
+    Ok(Some((root_hash, addrs)))
+}
+
+/// Pre-loads the nodes listed in a cache manifest on a background thread.
+#[derive(Debug, Default)]
+pub(super) struct CachePrimer {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CachePrimer {
+    /// Starts loading `addrs` (hottest first) into the caches of `rev`, as fast as `io` lets it.
+    /// The coldest nodes are loaded first so the hottest ones end up as the most recently used.
+    pub(super) fn spawn(
+        rev: Arc<DbRev<StoreRevShared>>,
+        addrs: Vec<DiskAddress>,
+        io: IoScheduler,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = std::thread::Builder::new()
+            .name("CachePrimer".to_string())
+            .spawn({
+                let stop = stop.clone();
+                move || {
+                    for addr in addrs.into_iter().rev() {
+                        if stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        // a node that can't be read will simply be loaded on demand
+                        if let Ok(node) = rev.merkle.get_node(addr) {
+                            let len = node.serialized_len();
+                            drop(node);
+                            io.throttle(1, len);
+                        }
+                    }
+                }
+            })
+            .expect("thread spawn should succeed");
+
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stops pre-loading and waits for the background thread to exit. This must happen before
+    /// the disk buffer is shut down, since the thread may still be reading pages through it.
+    pub(super) fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.take().map(JoinHandle::join);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn get_tmp_path(name: &str) -> PathBuf {
+        let dir = option_env!("CARGO_TARGET_TMPDIR")
+            .map(Into::into)
+            .unwrap_or(std::env::temp_dir());
+        let _ = fs::create_dir_all(&dir);
+        dir.join(name)
+    }
+
+    #[test]
+    fn save_and_take() {
+        let path = get_tmp_path("cache_manifest_save_and_take");
+
+        assert!(take(&path).unwrap().is_none());
+
+        let root_hash = TrieHash([0xaa; TRIE_HASH_LEN]);
+        let addrs = vec![DiskAddress::from(0x1000), DiskAddress::from(0x2040)];
+        save(&path, &root_hash, &addrs).unwrap();
+
+        assert_eq!(take(&path).unwrap(), Some((root_hash, addrs)));
+        // the manifest is consumed
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn take_malformed() {
+        let path = get_tmp_path("cache_manifest_take_malformed");
+
+        fs::write(&path, [0; TRIE_HASH_LEN + 3]).unwrap();
+
+        let err = take(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The bloom filters of the keys each commit changes, see
+//! [Db::changed_since](super::Db::changed_since).
+//!
+//! A commit records the filter of the keys its batch wrote or removed in the entry of its
+//! revision, see [revision_index](super::revision_index), so whether a key changed since a
+//! revision is answered from the filters of the commits after it, without diffing the tries. A
+//! filter may answer that a key changed when it didn't, for about 1% of the keys, but never
+//! that it didn't when it did.
+//!
+//! A [BatchOp::DeletePrefix](crate::v2::api::BatchOp::DeletePrefix) removes keys it doesn't
+//! read, so the filter holds the prefix instead, along with its length, and a key is answered
+//! as changed if one of its prefixes of those lengths is.
+
+use sha3::{Digest, Keccak256};
+use std::collections::BTreeSet;
+
+/// Bits of filter per key, which with [HASHES] hashes answers about 1% of the keys it doesn't
+/// hold as changed.
+const BITS_PER_KEY: usize = 10;
+const HASHES: u8 = 7;
+/// The filter of a batch that changes more keys is capped to this many words, answering more
+/// of the keys it doesn't hold as changed.
+const MAX_WORDS: usize = 8192;
+/// A batch dropping prefixes of more lengths records the empty prefix instead, answering every
+/// key as changed.
+const MAX_PREFIX_LENS: usize = 256;
+/// Sets the hashes of a prefix apart from those of a key of the same bytes.
+const PREFIX_SALT: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// The keys changed by a batch, as the hashes they are set in a filter with.
+#[derive(Debug, Default)]
+pub(super) struct ChangedKeys {
+    hashes: Vec<(u64, u64)>,
+    /// The lengths of the prefixes dropped by the batch, whose hashes are in `hashes`.
+    prefix_lens: BTreeSet<usize>,
+}
+
+impl ChangedKeys {
+    pub(super) fn insert(&mut self, key: &[u8]) {
+        self.hashes.push(hashes(key));
+    }
+
+    /// Adds every key starting with `prefix`, without reading them.
+    pub(super) fn insert_prefix(&mut self, prefix: &[u8]) {
+        self.hashes.push(prefix_hashes(prefix));
+        self.prefix_lens.insert(prefix.len());
+    }
+
+    /// Adds the keys changed by a later batch of the same commit.
+    pub(super) fn extend(&mut self, other: Self) {
+        self.hashes.extend(other.hashes);
+        self.prefix_lens.extend(other.prefix_lens);
+    }
+
+    pub(super) fn to_filter(&self) -> ChangeFilter {
+        let words = (self.hashes.len() * BITS_PER_KEY)
+            .div_ceil(64)
+            .clamp(1, MAX_WORDS);
+        let mut filter = ChangeFilter {
+            hashes: HASHES,
+            bits: vec![0; words],
+            prefix_lens: self.prefix_lens.iter().map(|&len| len as u32).collect(),
+        };
+        let mut hashes = self.hashes.clone();
+        if filter.prefix_lens.len() > MAX_PREFIX_LENS {
+            filter.prefix_lens = vec![0];
+            hashes.push(prefix_hashes(&[]));
+        }
+        for (h1, h2) in hashes {
+            for bit in filter.bits_of(h1, h2) {
+                #[allow(clippy::indexing_slicing)]
+                (filter.bits[bit / 64] |= 1 << (bit % 64));
+            }
+        }
+        filter
+    }
+}
+
+/// A bloom filter of the keys changed by a commit, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct ChangeFilter {
+    hashes: u8,
+    bits: Vec<u64>,
+    /// The lengths of the dropped prefixes, in ascending order.
+    prefix_lens: Vec<u32>,
+}
+
+impl ChangeFilter {
+    /// Whether `key` may have been changed, which it wasn't if not.
+    pub(super) fn may_contain(&self, key: &[u8]) -> bool {
+        self.holds(hashes(key))
+            || self
+                .prefix_lens
+                .iter()
+                .map_while(|&len| key.get(..len as usize))
+                .any(|prefix| self.holds(prefix_hashes(prefix)))
+    }
+
+    fn holds(&self, (h1, h2): (u64, u64)) -> bool {
+        self.bits_of(h1, h2).all(|bit| {
+            self.bits
+                .get(bit / 64)
+                .is_some_and(|word| word & (1 << (bit % 64)) != 0)
+        })
+    }
+
+    /// The bits of the key of hashes `h1` and `h2`, by double hashing.
+    fn bits_of(&self, h1: u64, h2: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64).map(move |n| (h1.wrapping_add(n.wrapping_mul(h2)) % len) as usize)
+    }
+
+    /// The length of the encoding of the filter.
+    pub(super) const fn encoded_len(&self) -> usize {
+        1 + size_of::<u32>()
+            + self.bits.len() * size_of::<u64>()
+            + size_of::<u32>()
+            + self.prefix_lens.len() * size_of::<u32>()
+    }
+
+    /// Appends the number of hashes, the number of words and the words of the filter to `out`,
+    /// then the number of lengths of the dropped prefixes and the lengths.
+    pub(super) fn encode_to(&self, out: &mut Vec<u8>) {
+        out.push(self.hashes);
+        out.extend_from_slice(&(self.bits.len() as u32).to_le_bytes());
+        for word in &self.bits {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+        out.extend_from_slice(&(self.prefix_lens.len() as u32).to_le_bytes());
+        for len in &self.prefix_lens {
+            out.extend_from_slice(&len.to_le_bytes());
+        }
+    }
+
+    /// Reads a filter written by [ChangeFilter::encode_to] from `read`, which returns the bytes
+    /// from an offset of the encoding, or `None` if they aren't there.
+    pub(super) fn decode(read: impl Fn(usize, usize) -> Option<Vec<u8>>) -> Option<Self> {
+        let header = read(0, 1 + size_of::<u32>())?;
+        let (&hashes, words) = header.split_first()?;
+        let words = u32::from_le_bytes(words.try_into().ok()?) as usize;
+        if hashes == 0 || words == 0 || words > MAX_WORDS {
+            return None;
+        }
+        let bits_len = words * size_of::<u64>();
+        let bits = read(header.len(), bits_len)?
+            .chunks_exact(size_of::<u64>())
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap_or_default()))
+            .collect();
+
+        let offset = header.len() + bits_len;
+        let lens = read(offset, size_of::<u32>())?;
+        let lens = u32::from_le_bytes(lens.as_slice().try_into().ok()?) as usize;
+        if lens > MAX_PREFIX_LENS {
+            return None;
+        }
+        let prefix_lens = read(offset + size_of::<u32>(), lens * size_of::<u32>())?
+            .chunks_exact(size_of::<u32>())
+            .map(|len| u32::from_le_bytes(len.try_into().unwrap_or_default()))
+            .collect();
+        Some(Self {
+            hashes,
+            bits,
+            prefix_lens,
+        })
+    }
+}
+
+fn prefix_hashes(prefix: &[u8]) -> (u64, u64) {
+    let (h1, h2) = hashes(prefix);
+    (h1 ^ PREFIX_SALT, h2)
+}
+
+fn hashes(key: &[u8]) -> (u64, u64) {
+    let hash = Keccak256::digest(key);
+    let (h1, rest) = hash.split_at(8);
+    #[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+    (
+        u64::from_le_bytes(h1.try_into().unwrap()),
+        // odd, so that the bits of a key are distinct whatever the length of the filter
+        u64::from_le_bytes(rest[..8].try_into().unwrap()) | 1,
+    )
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_holds_the_changed_keys() {
+        let mut changed = ChangedKeys::default();
+        let keys: Vec<_> = (0..1000u32).map(|n| n.to_be_bytes()).collect();
+        for key in &keys {
+            changed.insert(key);
+        }
+        let filter = changed.to_filter();
+        assert!(keys.iter().all(|key| filter.may_contain(key)));
+
+        let false_positives = (1000..11000u32)
+            .filter(|n| filter.may_contain(&n.to_be_bytes()))
+            .count();
+        assert!(false_positives < 300, "{false_positives}");
+
+        let mut encoded = Vec::new();
+        filter.encode_to(&mut encoded);
+        assert_eq!(encoded.len(), filter.encoded_len());
+        let reader = |bytes: &[u8]| {
+            let bytes = bytes.to_vec();
+            move |offset: usize, len: usize| bytes.get(offset..offset + len).map(<[u8]>::to_vec)
+        };
+        assert_eq!(ChangeFilter::decode(reader(&encoded)), Some(filter));
+        // a filter cut short by the end of the store isn't read
+        assert_eq!(ChangeFilter::decode(reader(&encoded[..10])), None);
+    }
+
+    #[test]
+    fn filter_holds_the_dropped_prefixes() {
+        let mut changed = ChangedKeys::default();
+        changed.insert(b"key");
+        changed.insert_prefix(b"ab");
+        changed.insert_prefix(b"xyz");
+        let filter = changed.to_filter();
+        for key in [&b"key"[..], b"ab", b"abc", b"xyz", b"xyz0"] {
+            assert!(filter.may_contain(key), "{key:?}");
+        }
+        // a prefix only holds the keys starting with it
+        assert!(!filter.may_contain(b"a"));
+        assert!(!filter.may_contain(b"xy"));
+
+        let mut encoded = Vec::new();
+        filter.encode_to(&mut encoded);
+        assert_eq!(encoded.len(), filter.encoded_len());
+        let decoded = ChangeFilter::decode(|offset, len| {
+            encoded.get(offset..offset + len).map(<[u8]>::to_vec)
+        });
+        assert_eq!(decoded, Some(filter));
+
+        // too many lengths of prefixes hold every key
+        let mut changed = ChangedKeys::default();
+        for len in 1..=MAX_PREFIX_LENS + 1 {
+            changed.insert_prefix(&vec![1; len]);
+        }
+        assert!(changed.to_filter().may_contain(b"anything"));
+    }
+
+    #[test]
+    fn empty_batch_changes_nothing() {
+        let filter = ChangedKeys::default().to_filter();
+        assert!(!filter.may_contain(b"key"));
+    }
+}
@@ -0,0 +1,225 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Commits many tiny batches together, for callers committing a lot of them every second.
+//!
+//! Every commit writes a Wal record and at least a page of each store, however few keys its
+//! batch changes, so committing tiny batches one by one spends most of the time on the commits
+//! themselves. A [CommitBatcher] queues the batches it is given, and once
+//! [CommitBatchingConfig::interval] passed since the first of them was queued, or
+//! [CommitBatchingConfig::max_batches] are queued, applies them one after the other to a single
+//! proposal, which it commits. Each caller is still answered with the root hash the trie had
+//! right after its own batch.
+//!
+//! Only the revision of the last batch of a commit is kept by the DB: the root hashes of the
+//! other batches can't be opened with [Db::get_revision]. If the merged batches can't be
+//! proposed or committed together, for instance because a validator rejects one of them, they
+//! are committed one by one, so that only the callers of the failing batches get an error.
+
+use super::{
+    write_batch::{to_owned, OwnedBatch},
+    Db, DbError,
+};
+use crate::{
+    config::CommitBatchingConfig,
+    merkle::TrieHash,
+    v2::api::{self, Batch, BatchOp, KeyType, ValueType},
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Instant,
+};
+use tokio::sync::oneshot;
+
+/// What a [CommitBatcher] has committed since it was started.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CommitBatcherStats {
+    /// Number of batches committed, or that failed to be.
+    pub batches: u64,
+    /// Number of commits made to the DB.
+    pub commits: u64,
+}
+
+struct Queued {
+    batch: OwnedBatch,
+    at: Instant,
+    waiter: oneshot::Sender<Result<TrieHash, DbError>>,
+}
+
+struct Shared {
+    db: Arc<Db>,
+    cfg: CommitBatchingConfig,
+    batches: AtomicU64,
+    commits: AtomicU64,
+}
+
+impl Shared {
+    fn work(&self, queue: &mpsc::Receiver<Queued>) {
+        // the queue is disconnected once the batcher is dropped and every batch is committed
+        while let Ok(first) = queue.recv() {
+            let deadline = first.at + self.cfg.interval;
+            let mut group = vec![first];
+            while group.len() < self.cfg.max_batches {
+                match queue.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(queued) => group.push(queued),
+                    Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            self.commit(group);
+        }
+    }
+
+    /// Commits `group` as a single proposal, or one batch at a time if it can't be.
+    fn commit(&self, group: Vec<Queued>) {
+        self.batches
+            .fetch_add(group.len() as u64, Ordering::Relaxed);
+        let merged = self
+            .db
+            .new_merged_proposal(group.iter().map(|queued| borrowed(&queued.batch)).collect())
+            .and_then(|(proposal, root_hashes)| {
+                proposal.commit_sync()?;
+                Ok(root_hashes)
+            });
+
+        match merged {
+            Ok(root_hashes) => {
+                self.commits.fetch_add(1, Ordering::Relaxed);
+                for (queued, root_hash) in group.into_iter().zip(root_hashes) {
+                    // the caller may have stopped waiting, which is fine
+                    let _ = queued.waiter.send(Ok(root_hash));
+                }
+            }
+            Err(e) if group.len() == 1 => {
+                if let Some(queued) = group.into_iter().next() {
+                    let _ = queued.waiter.send(Err(e));
+                }
+            }
+            Err(_) => {
+                for queued in group {
+                    let committed = self.db.new_proposal(queued.batch).and_then(|proposal| {
+                        let root_hash = proposal.root_hash;
+                        proposal.commit_sync()?;
+                        self.commits.fetch_add(1, Ordering::Relaxed);
+                        Ok(root_hash)
+                    });
+                    let _ = queued.waiter.send(committed);
+                }
+            }
+        }
+    }
+}
+
+/// Commits the batches it is given to a [Db] together, see the [module documentation](self).
+pub struct CommitBatcher {
+    shared: Arc<Shared>,
+    queue: Option<mpsc::Sender<Queued>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for CommitBatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CommitBatcher")
+            .field("cfg", &self.shared.cfg)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl CommitBatcher {
+    /// Starts the worker committing the batches given to `db`.
+    pub fn new(db: Arc<Db>, cfg: CommitBatchingConfig) -> Self {
+        let shared = Arc::new(Shared {
+            db,
+            cfg,
+            batches: AtomicU64::new(0),
+            commits: AtomicU64::new(0),
+        });
+
+        let (queue, jobs) = mpsc::channel();
+        let worker = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("CommitBatcher".to_string())
+                .spawn(move || shared.work(&jobs))
+                .expect("thread spawn should succeed")
+        };
+
+        Self {
+            shared,
+            queue: Some(queue),
+            worker: Some(worker),
+        }
+    }
+
+    /// Commits `data` with the batches queued around the same time, and returns the root hash
+    /// the trie had right after it. Like a proposal of `data`, fails if `data` can't be applied
+    /// or is rejected by a validator, in which case none of it is committed.
+    pub async fn commit<K: KeyType, V: ValueType>(
+        &self,
+        data: Batch<K, V>,
+    ) -> Result<api::HashKey, api::Error> {
+        let (waiter, rx) = oneshot::channel();
+        #[allow(clippy::unwrap_used)]
+        self.queue
+            .as_ref()
+            .unwrap()
+            .send(Queued {
+                batch: to_owned(data),
+                at: Instant::now(),
+                waiter,
+            })
+            .map_err(|_| stopped())?;
+
+        match rx.await {
+            Ok(root_hash) => root_hash.map(|root_hash| root_hash.0).map_err(Into::into),
+            Err(_) => Err(stopped()),
+        }
+    }
+
+    /// What the batcher has committed so far.
+    pub fn stats(&self) -> CommitBatcherStats {
+        CommitBatcherStats {
+            batches: self.shared.batches.load(Ordering::Relaxed),
+            commits: self.shared.commits.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for CommitBatcher {
+    /// Commits the batches still queued, then stops the worker.
+    fn drop(&mut self) {
+        self.queue.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn borrowed(batch: &OwnedBatch) -> Batch<&Vec<u8>, &Vec<u8>> {
+    batch
+        .iter()
+        .map(|op| match op {
+            BatchOp::Put { key, value } => BatchOp::Put { key, value },
+            BatchOp::Delete { key } => BatchOp::Delete { key },
+            BatchOp::Move {
+                key,
+                new_key,
+                overwrite,
+            } => BatchOp::Move {
+                key,
+                new_key,
+                overwrite: *overwrite,
+            },
+            BatchOp::DeletePrefix { prefix } => BatchOp::DeletePrefix { prefix },
+        })
+        .collect()
+}
+
+fn stopped() -> api::Error {
+    api::Error::IO(std::io::Error::other("the commit batcher stopped"))
+}
@@ -0,0 +1,48 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use super::DbError;
+use async_trait::async_trait;
+use parking_lot::RwLock;
+use std::{error::Error, fmt::Debug, sync::Arc};
+
+/// A hook invoked for every commit after its Wal record has been appended, but before the commit
+/// returns to the caller. This can be used to ship the Wal to a remote location or to implement
+/// synchronous replication: the commit is only acknowledged once every hook has returned.
+///
+/// Commits are serialized while the hooks run, so hooks see the records in Wal order. A slow hook
+/// delays every commit after it, and a hook must not call back into the [Db](super::Db).
+#[async_trait]
+pub trait CommitHook: Debug + Send + Sync {
+    /// Called with the serialized Wal record of a commit. Returning an error fails the commit
+    /// from the caller's point of view, but note that by the time the hook runs, the changes have
+    /// already been applied and are durable in the local Wal.
+    async fn on_wal_append(&self, record: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+/// The commit hooks registered on a [Db](super::Db), shared with all of its proposals.
+#[derive(Clone, Debug, Default)]
+pub(super) struct CommitHooks(Arc<RwLock<Vec<Arc<dyn CommitHook>>>>);
+
+impl CommitHooks {
+    pub(super) fn register(&self, hook: Arc<dyn CommitHook>) {
+        self.0.write().push(hook);
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.0.read().is_empty()
+    }
+
+    /// Runs every hook in registration order, stopping at the first one that fails.
+    pub(super) async fn run(&self, record: &[u8]) -> Result<(), DbError> {
+        let hooks = self.0.read().clone();
+
+        for hook in hooks {
+            hook.on_wal_append(record)
+                .await
+                .map_err(DbError::CommitHook)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Checks that a commit has the disk space it takes before it changes anything, see
+//! [DbConfig::min_free_space](super::DbConfig::min_free_space).
+//!
+//! The Wal record and the pages of a commit are written by the disk buffer in the background,
+//! once the commit has changed the stores and handed out its revision, so running out of space
+//! then can't fail the commit anymore. The space of a commit is estimated instead, as its Wal
+//! record and its pages, and compared to the space left on the file system beforehand. With
+//! [DbConfig::preallocate](super::DbConfig::preallocate), the store files the pages are written
+//! to are allocated then too, so that a new file that doesn't fit fails the commit as well.
+
+use super::DbError;
+use crate::{
+    file,
+    storage::{Ash, CachedStore, StoreError, PAGE_SIZE},
+};
+use std::path::PathBuf;
+
+/// Serialization overhead of a Wal record, per write, on top of its bytes: the offset and the
+/// lengths of the undo and redo data.
+const WAL_BYTES_PER_WRITE: u64 = 32;
+
+#[derive(Debug)]
+pub(super) struct DiskSpace {
+    path: PathBuf,
+    reserve: u64,
+}
+
+impl DiskSpace {
+    /// Checks the space of the file system of `path`, leaving `reserve` bytes free.
+    pub(super) const fn new(path: PathBuf, reserve: u64) -> Self {
+        Self { path, reserve }
+    }
+
+    /// Fails with [DbError::NoSpace] unless the pages `pids` of the deltas, for their stores,
+    /// and the Wal records of `ashes` fit on the file system, on top of the reserve. Opens the
+    /// store files the pages are written to once they do.
+    pub(super) fn check(
+        &self,
+        pids: &[(&CachedStore, &[u64])],
+        ashes: &[&Ash],
+    ) -> Result<(), DbError> {
+        let pages: usize = pids.iter().map(|(_, pids)| pids.len()).sum();
+        let wal: u64 = ashes
+            .iter()
+            .flat_map(|ash| ash.undo.iter().chain(&ash.redo))
+            .map(|write| write.len() as u64 + WAL_BYTES_PER_WRITE)
+            .sum();
+        let needed = pages as u64 * PAGE_SIZE + wal + self.reserve;
+
+        let available = file::available_space(&self.path)?;
+        if available < needed {
+            return Err(DbError::NoSpace { needed, available });
+        }
+
+        for (store, pids) in pids {
+            store.open_files(pids).map_err(|e| match e {
+                StoreError::Io(e) if e.raw_os_error() == Some(nix::libc::ENOSPC) => {
+                    DbError::NoSpace {
+                        needed,
+                        available: file::available_space(&self.path).unwrap_or(0),
+                    }
+                }
+                StoreError::Io(e) => DbError::IO(*e),
+                e => DbError::IO(std::io::Error::other(e.to_string())),
+            })?;
+        }
+        Ok(())
+    }
+}
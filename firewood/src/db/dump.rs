@@ -0,0 +1,80 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Streams the key-value pairs under a prefix of a revision, such as the storage of an account,
+//! in pages, see [DbRev::dump_prefix](super::DbRev::dump_prefix).
+//!
+//! Unlike [DbRev::kv_dump](super::DbRev::kv_dump), nothing is written to a blocking writer: the
+//! pages are yielded as a [Stream], one at a time, so they can be sent over an async connection
+//! as they are read. A page can come with the proof of its range, which the receiver checks
+//! against the root hash of the revision.
+
+use super::{replicate, stream_error, DbError, DbRev};
+use crate::{merkle::Proof, shale::LinearStore};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+
+/// A page of the key-value pairs streamed by [DbRev::dump_prefix](super::DbRev::dump_prefix),
+/// in ascending key order.
+#[derive(Debug)]
+pub struct DumpPage {
+    pub pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The proofs of the first and last keys of the page, which together prove that the pairs
+    /// are every pair of the revision between them, if the proofs were asked for.
+    pub proof: Option<Proof<Vec<u8>>>,
+}
+
+/// Reads the page of at most `len` pairs under `prefix` starting at `start`, and the proof of
+/// its range if `prove`. An empty page is the end of the pairs under the prefix.
+async fn page<T: LinearStore>(
+    rev: &DbRev<T>,
+    prefix: &[u8],
+    start: &[u8],
+    len: usize,
+    prove: bool,
+) -> Result<DumpPage, DbError> {
+    let pairs = rev
+        .stream_from(start.into())
+        .take_while(|pair| {
+            let under_prefix = pair
+                .as_ref()
+                .map_or(true, |(key, _)| key.starts_with(prefix));
+            async move { under_prefix }
+        })
+        .take(len)
+        .map_ok(|(key, value)| (key.into_vec(), value))
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(stream_error)?;
+
+    let proof = match (prove, pairs.first(), pairs.last()) {
+        (true, Some((first, _)), Some((last, _))) => {
+            let mut proof = rev.prove(first).map_err(DbError::Merkle)?;
+            proof.extend(rev.prove(last).map_err(DbError::Merkle)?);
+            Some(proof)
+        }
+        _ => None,
+    };
+    Ok(DumpPage { pairs, proof })
+}
+
+/// Streams the pages of the pairs of `rev` under `prefix`, see the [module documentation](self).
+pub(super) fn pages<'a, T: LinearStore>(
+    rev: &'a DbRev<T>,
+    prefix: &'a [u8],
+    page_len: usize,
+    prove: bool,
+) -> impl Stream<Item = Result<DumpPage, DbError>> + 'a {
+    // the key the next page starts at, or None once the last page was read
+    stream::try_unfold(Some(prefix.to_vec()), move |start| async move {
+        let Some(start) = start else {
+            return Ok(None);
+        };
+        let page = page(rev, prefix, &start, page_len, prove).await?;
+        let next = match page.pairs.last() {
+            Some((last, _)) if page.pairs.len() == page_len => Some(replicate::successor(last)),
+            Some(_) => None,
+            None => return Ok(None),
+        };
+        Ok(Some((page, next)))
+    })
+}
@@ -0,0 +1,47 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! A fence around the commits of a DB, for the tools that snapshot the file system it's on, see
+//! [Db::freeze_writes](super::Db::freeze_writes).
+//!
+//! Every commit holds the fence shared for as long as it writes, and a freeze holds it
+//! exclusively. A freeze waits for the commits in flight, and the commits that come after it
+//! wait for the freeze to be thawed, rather than slipping past it while it waits.
+
+use parking_lot::{ArcRwLockWriteGuard, RawRwLock, RwLock, RwLockReadGuard};
+use std::{fmt, sync::Arc, time::Instant};
+
+#[derive(Debug, Clone, Default)]
+pub(super) struct WriteFence(Arc<RwLock<()>>);
+
+impl WriteFence {
+    /// Held by a commit while it writes, which waits while the writes are frozen.
+    pub(super) fn enter(&self) -> RwLockReadGuard<'_, ()> {
+        self.0.read()
+    }
+
+    /// Freezes the writes once the commits in flight are done, or gives up at `deadline`.
+    pub(super) fn freeze_until(&self, deadline: Instant) -> Option<WriteFreeze> {
+        self.0
+            .try_write_arc_until(deadline)
+            .map(|frozen| WriteFreeze { _frozen: frozen })
+    }
+}
+
+/// Holds off the commits of a DB until it's thawed or dropped, see
+/// [Db::freeze_writes](super::Db::freeze_writes).
+#[must_use = "the writes are thawed as soon as the freeze is dropped"]
+pub struct WriteFreeze {
+    _frozen: ArcRwLockWriteGuard<RawRwLock, ()>,
+}
+
+impl WriteFreeze {
+    /// Lets the commits go on, starting with the ones that waited for the freeze.
+    pub fn thaw(self) {}
+}
+
+impl fmt::Debug for WriteFreeze {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteFreeze").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Sampling profiler of the key prefixes a [Db](super::Db) reads and writes, see
+//! [Db::hot_keys](super::Db::hot_keys).
+//!
+//! The window is split into periods, each counting the samples taken during it. The periods
+//! that ended more than a window ago are dropped as new samples come in, rather than on a timer,
+//! so a profiler that isn't used costs nothing.
+
+use crate::config::HotKeyConfig;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Number of periods the window is split into.
+const PERIODS: u32 = 6;
+
+/// The estimated accesses to the keys starting with a prefix, see
+/// [Db::hot_keys](super::Db::hot_keys).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HotPrefix {
+    /// The first [HotKeyConfig::prefix_len] bytes of the keys, or the whole key if shorter.
+    pub prefix: Vec<u8>,
+    /// Estimated number of reads of those keys over the window.
+    pub reads: u64,
+    /// Estimated number of writes of those keys over the window.
+    pub writes: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    reads: u64,
+    writes: u64,
+}
+
+#[derive(Debug)]
+struct Period {
+    start: Instant,
+    counts: HashMap<Vec<u8>, Counts>,
+}
+
+#[derive(Debug)]
+struct Profiler {
+    sample_rate: u64,
+    prefix_len: usize,
+    window: Duration,
+    period: Duration,
+    accesses: AtomicU64,
+    periods: Mutex<VecDeque<Period>>,
+}
+
+impl Profiler {
+    fn record(&self, key: &[u8], access: Access, now: Instant) {
+        if !self
+            .accesses
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.sample_rate)
+        {
+            return;
+        }
+
+        let mut periods = self.periods.lock();
+        self.expire(&mut periods, now);
+        if periods
+            .back()
+            .is_none_or(|period| now.duration_since(period.start) >= self.period)
+        {
+            periods.push_back(Period {
+                start: now,
+                counts: HashMap::new(),
+            });
+        }
+
+        #[allow(clippy::unwrap_used)]
+        let period = periods.back_mut().unwrap();
+        #[allow(clippy::indexing_slicing)]
+        let prefix = &key[..key.len().min(self.prefix_len)];
+        let counts = match period.counts.get_mut(prefix) {
+            Some(counts) => counts,
+            None => period.counts.entry(prefix.to_vec()).or_default(),
+        };
+        match access {
+            Access::Read => counts.reads += 1,
+            Access::Write => counts.writes += 1,
+        }
+    }
+
+    fn expire(&self, periods: &mut VecDeque<Period>, now: Instant) {
+        while periods
+            .front()
+            .is_some_and(|period| now.duration_since(period.start) >= self.window + self.period)
+        {
+            periods.pop_front();
+        }
+    }
+
+    fn top(&self, top_n: usize, now: Instant) -> Vec<HotPrefix> {
+        let mut totals = HashMap::<Vec<u8>, Counts>::new();
+        {
+            let mut periods = self.periods.lock();
+            self.expire(&mut periods, now);
+            for period in periods.iter() {
+                for (prefix, counts) in &period.counts {
+                    let total = totals.entry(prefix.clone()).or_default();
+                    total.reads += counts.reads;
+                    total.writes += counts.writes;
+                }
+            }
+        }
+
+        let mut top: Vec<_> = totals
+            .into_iter()
+            .map(|(prefix, counts)| HotPrefix {
+                prefix,
+                reads: counts.reads * self.sample_rate,
+                writes: counts.writes * self.sample_rate,
+            })
+            .collect();
+        top.sort_unstable_by(|a, b| {
+            (b.reads + b.writes)
+                .cmp(&(a.reads + a.writes))
+                .then_with(|| a.prefix.cmp(&b.prefix))
+        });
+        top.truncate(top_n);
+        top
+    }
+}
+
+/// A handle on the profiler of a DB, shared by its revisions and proposals. Records nothing if
+/// profiling is disabled.
+#[derive(Debug, Clone, Default)]
+pub(super) struct HotKeys(Option<Arc<Profiler>>);
+
+impl HotKeys {
+    pub(super) fn new(config: &HotKeyConfig) -> Self {
+        if !config.enabled {
+            return Self::default();
+        }
+        Self(Some(Arc::new(Profiler {
+            sample_rate: config.sample_rate.max(1),
+            prefix_len: config.prefix_len,
+            window: config.window,
+            period: config.window / PERIODS,
+            accesses: AtomicU64::new(0),
+            periods: Mutex::new(VecDeque::new()),
+        })))
+    }
+
+    pub(super) fn record(&self, key: &[u8], access: Access) {
+        if let Some(profiler) = &self.0 {
+            profiler.record(key, access, Instant::now());
+        }
+    }
+
+    pub(super) fn top(&self, top_n: usize) -> Vec<HotPrefix> {
+        self.0
+            .as_ref()
+            .map_or_else(Vec::new, |profiler| profiler.top(top_n, Instant::now()))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolling_window() {
+        let config = HotKeyConfig::builder()
+            .enabled(true)
+            .sample_rate(2)
+            .prefix_len(2)
+            .window(Duration::from_secs(60))
+            .build();
+        let hot_keys = HotKeys::new(&config);
+        let profiler = hot_keys.0.as_ref().unwrap();
+        let start = Instant::now();
+
+        // every other access is sampled, and counts twice
+        for _ in 0..4 {
+            profiler.record(b"aa1", Access::Read, start);
+            profiler.record(b"aa2", Access::Write, start);
+        }
+        for _ in 0..2 {
+            profiler.record(b"b", Access::Read, start + Duration::from_secs(30));
+        }
+        assert_eq!(
+            profiler.top(10, start + Duration::from_secs(30)),
+            vec![
+                HotPrefix {
+                    prefix: b"aa".to_vec(),
+                    reads: 8,
+                    writes: 0,
+                },
+                HotPrefix {
+                    prefix: b"b".to_vec(),
+                    reads: 2,
+                    writes: 0,
+                },
+            ]
+        );
+        assert_eq!(profiler.top(1, start).len(), 1);
+
+        // the first period leaves the window, the one 30s in doesn't yet
+        let top = profiler.top(10, start + Duration::from_secs(75));
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].prefix, b"b");
+
+        assert!(HotKeys::new(&HotKeyConfig::builder().build())
+            .top(10)
+            .is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The rate limits of the disk reads a [Db](super::Db) makes in the background, see
+//! [BackgroundIoConfig].
+//!
+//! The work the DB does on its own, such as pre-loading the cache manifest, goes through the
+//! [IoScheduler] before each read, which holds it back once it goes over its share of
+//! operations or bytes per second. The reads and commits made on behalf of the user never go
+//! through it, so they are never held back. Each limit is a token bucket that is allowed to go
+//! into debt: a read is let through at once, and the reader then sleeps for as long as it takes
+//! the bucket to pay it back, which keeps the waits short and the rate exact.
+
+use crate::config::BackgroundIoConfig;
+use parking_lot::Mutex;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+struct Bucket {
+    /// Tokens added per second.
+    rate: f64,
+    /// Most tokens the bucket holds.
+    capacity: f64,
+    tokens: f64,
+}
+
+impl Bucket {
+    fn new(rate: u64, burst: Duration) -> Option<Self> {
+        (rate > 0).then(|| {
+            let rate = rate as f64;
+            let capacity = rate * burst.as_secs_f64();
+            Self {
+                rate,
+                capacity,
+                tokens: capacity,
+            }
+        })
+    }
+
+    /// Takes `n` tokens, returning how long until the bucket is out of debt.
+    fn take(&mut self, n: u64, elapsed: Duration) -> Duration {
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    ops: Option<Bucket>,
+    bytes: Option<Bucket>,
+    last: Instant,
+}
+
+impl Buckets {
+    fn take(&mut self, ops: u64, bytes: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last);
+        self.last = now;
+        let ops = self.ops.as_mut().map(|bucket| bucket.take(ops, elapsed));
+        let bytes = self
+            .bytes
+            .as_mut()
+            .map(|bucket| bucket.take(bytes, elapsed));
+        ops.max(bytes).unwrap_or_default()
+    }
+}
+
+/// A handle on the rate limits of the background reads of a DB, shared by its background
+/// threads. Lets everything through if there are no limits.
+#[derive(Debug, Clone, Default)]
+pub(super) struct IoScheduler(Option<Arc<Mutex<Buckets>>>);
+
+impl IoScheduler {
+    pub(super) fn new(config: &BackgroundIoConfig) -> Self {
+        let ops = Bucket::new(config.iops, config.burst);
+        let bytes = Bucket::new(config.bytes_per_sec, config.burst);
+        if ops.is_none() && bytes.is_none() {
+            return Self::default();
+        }
+        Self(Some(Arc::new(Mutex::new(Buckets {
+            ops,
+            bytes,
+            last: Instant::now(),
+        }))))
+    }
+
+    /// Accounts for a background read of `bytes` in `ops` operations, sleeping as long as the
+    /// background work is over its share.
+    pub(super) fn throttle(&self, ops: u64, bytes: u64) {
+        let Some(buckets) = &self.0 else {
+            return;
+        };
+        let wait = buckets.lock().take(ops, bytes, Instant::now());
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_buckets() {
+        let config = BackgroundIoConfig::builder()
+            .iops(100)
+            .bytes_per_sec(1 << 20)
+            .burst(Duration::from_millis(100))
+            .build();
+        let scheduler = IoScheduler::new(&config);
+        let mut buckets = scheduler.0.as_ref().unwrap().lock();
+        let start = buckets.last;
+
+        let about = |wait: Duration, millis| {
+            assert!(wait.abs_diff(Duration::from_millis(millis)) < Duration::from_micros(1))
+        };
+
+        // a burst of 10 operations goes through at once
+        for _ in 0..10 {
+            assert_eq!(buckets.take(1, 0, start), Duration::ZERO);
+        }
+        // then an operation every 10ms
+        about(buckets.take(1, 0, start), 10);
+        let later = start + Duration::from_millis(20);
+        assert_eq!(buckets.take(1, 0, later), Duration::ZERO);
+        about(buckets.take(1, 0, later), 10);
+
+        // the bytes are limited in the same way, and the longer wait wins
+        about(
+            buckets.take(1, 1 << 20, later + Duration::from_secs(1)),
+            900,
+        );
+
+        assert!(IoScheduler::new(&BackgroundIoConfig::builder().build())
+            .0
+            .is_none());
+    }
+}
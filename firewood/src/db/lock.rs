@@ -0,0 +1,93 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Advisory locks on the DB directory, so that two processes never write to the same DB.
+//!
+//! A writer holds an exclusive lock on [LOCK_FILE], which also records its pid. Read-only handles
+//! don't take that lock, so any number of them can attach next to a single writer; they hold a
+//! shared lock on [READERS_LOCK_FILE] instead, which only keeps a writer from truncating the DB
+//! from under them. The locks are `flock` locks on Unix and `LockFileEx` locks on Windows, and
+//! are released by the OS when the process exits, so a crashed process never leaves a stale lock
+//! behind.
+
+use super::DbError;
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    path::Path,
+};
+
+/// Name of the file locked by the writer.
+pub(super) const LOCK_FILE: &str = "LOCK";
+/// Name of the file locked by the read-only handles.
+pub(super) const READERS_LOCK_FILE: &str = "LOCK.readers";
+
+/// A lock on the DB directory, held until it is dropped.
+#[derive(Debug)]
+pub(super) struct DbLock {
+    _file: File,
+}
+
+impl DbLock {
+    /// Takes the writer lock, failing with [DbError::AlreadyOpen] if another handle, in this
+    /// process or another one, holds it.
+    pub(super) fn writer(db_path: &Path) -> Result<Self, DbError> {
+        let mut file = open(db_path, LOCK_FILE)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(DbError::AlreadyOpen {
+                    pid: read_pid(&mut file),
+                })
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        file.set_len(0)?;
+        file.write_all(std::process::id().to_string().as_bytes())?;
+
+        Ok(Self { _file: file })
+    }
+
+    /// Takes a shared lock for a read-only handle.
+    pub(super) fn reader(db_path: &Path) -> Result<Self, DbError> {
+        let file = open(db_path, READERS_LOCK_FILE)?;
+
+        match file.try_lock_shared() {
+            Ok(()) => Ok(Self { _file: file }),
+            // a writer is truncating the DB
+            Err(TryLockError::WouldBlock) => Err(DbError::AlreadyOpen { pid: None }),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+
+    /// Makes sure no read-only handle is attached, for as long as the returned lock is held.
+    pub(super) fn no_readers(db_path: &Path) -> Result<Self, DbError> {
+        let file = open(db_path, READERS_LOCK_FILE)?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(DbError::AlreadyOpen { pid: None }),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
+
+fn open(db_path: &Path, name: &str) -> Result<File, DbError> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(db_path.join(name))
+        .map_err(Into::into)
+}
+
+/// The pid recorded by the writer holding the lock, if it could be read.
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut pid = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut pid).ok()?;
+    pid.trim().parse().ok()
+}
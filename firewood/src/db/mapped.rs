@@ -0,0 +1,82 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The latest revision of a closed DB, read from its memory-mapped store files, see
+//! [Db::open_mapped].
+
+use super::{
+    lock::DbLock, shutdown, Db, DbConfig, DbError, DbHeader, DbRev, NodeEncoding,
+    HOT_SEGMENT_DIR_FILE, MERKLE_META_STORE_ID, MERKLE_PAYLOAD_STORE_ID, RESERVED_STORE_ID,
+};
+use crate::{file, memory_budget::MemoryBudget, shale::mmap::MmapStore};
+use std::{ops::Deref, os::fd::AsFd, path::Path};
+
+/// The latest revision of a closed DB, see [Db::open_mapped]. It derefs to the [DbRev] to read,
+/// and keeps a writer from opening the DB until it is dropped.
+#[derive(Debug)]
+pub struct MappedRev {
+    rev: DbRev<MmapStore>,
+    _lock: DbLock,
+}
+
+impl Deref for MappedRev {
+    type Target = DbRev<MmapStore>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rev
+    }
+}
+
+pub(super) fn open(db_path: &Path, cfg: &DbConfig) -> Result<MappedRev, DbError> {
+    let lock = DbLock::writer(db_path)?;
+    // the store files hold every change only if the DB was closed since it was last written to
+    let closed = shutdown::read(db_path)?.map_or(Ok(false), |marker| marker.is_current(db_path))?;
+    // the files of a hot segment in a directory of its own aren't mapped
+    if !closed || db_path.join(HOT_SEGMENT_DIR_FILE).exists() {
+        return Err(DbError::InvalidParams);
+    }
+
+    let meta_path = db_path.join("merkle").join("meta");
+    let payload_path = db_path.join("merkle").join("compact");
+    let meta_file = file::File::new(0, RESERVED_STORE_ID, &meta_path)?;
+    let params = Db::read_params(meta_file.as_fd())?;
+    drop(meta_file);
+
+    // SAFETY: no writer can open the DB while the lock is held, see MappedRev
+    let (meta, payload) = unsafe {
+        (
+            MmapStore::new(meta_path, params.meta_file_nbit, MERKLE_META_STORE_ID)?,
+            MmapStore::new(
+                payload_path,
+                params.payload_file_nbit,
+                MERKLE_PAYLOAD_STORE_ID,
+            )?,
+        )
+    };
+
+    let db_header_ref = Db::get_db_header_ref(&meta)?;
+    // the sentinel of a DB is created with its first revision, and can't be in a read-only store
+    if db_header_ref.sentinel_addr.is_null() {
+        return Err(DbError::InvalidParams);
+    }
+    let merkle_payload_header_ref =
+        Db::get_payload_header_ref(&meta, Db::PARAM_SIZE + DbHeader::MSIZE)?;
+
+    let rev = Db::new_revision::<MmapStore, _>(
+        (db_header_ref, merkle_payload_header_ref),
+        (meta, payload),
+        params.payload_regn_nbit,
+        params.payload_align_nbit,
+        cfg.payload_max_walk,
+        &cfg.rev,
+        cfg.verify_hashes_on_read,
+        params.inline_value_threshold as usize,
+        NodeEncoding::from_u64(params.node_encoding).ok_or(DbError::InvalidParams)?,
+        cfg.delayed_allocation,
+        params.hot_levels as usize,
+        &MemoryBudget::new(cfg.memory_budget),
+        &cfg.payload_allocator,
+    )?;
+
+    Ok(MappedRev { rev, _lock: lock })
+}
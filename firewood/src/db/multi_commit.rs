@@ -0,0 +1,255 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Commits batches on several DBs atomically, for state sharded across several firewood DBs.
+//!
+//! A [MultiCommit] goes through two phases. [MultiCommit::prepare] proposes every batch on its
+//! DB, and writes a journal holding the batches with the root hashes of the DBs before and after
+//! them. Once the journal is written, the batches are bound to be committed:
+//! [PreparedCommit::commit] commits the proposals one after the other and removes the journal.
+//! If the process dies in between, [MultiCommit::recover] reads the journal back when the DBs are
+//! opened again, and proposes and commits the batches of the DBs still at their old root hash.
+
+use super::{
+    proposal::Proposal,
+    write_batch::{decode_batch, encode_batch, to_owned, OwnedBatch, Reader},
+    Db, DbError,
+};
+use crate::{
+    merkle::{TrieHash, TRIE_HASH_LEN},
+    v2::api::{Batch, KeyType, ValueType},
+};
+use std::{
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+/// The batch of one DB in the journal, with the root hashes of the DB before and after it.
+#[derive(Debug, PartialEq)]
+struct Participant {
+    parent: TrieHash,
+    expected: TrieHash,
+    batch: OwnedBatch,
+}
+
+/// A coordinator committing batches on several DBs atomically, through the prepare/commit
+/// protocol of this module, with its journal at `journal`.
+///
+/// The DBs must not be written otherwise while a commit is prepared, and must be given in the
+/// same order to [MultiCommit::prepare] and [MultiCommit::recover].
+#[derive(Debug, Clone)]
+pub struct MultiCommit {
+    journal: PathBuf,
+}
+
+/// The batches proposed by [MultiCommit::prepare], bound to be committed.
+pub struct PreparedCommit<'a> {
+    journal: PathBuf,
+    proposals: Vec<(&'a Db, Proposal)>,
+}
+
+impl MultiCommit {
+    pub fn new(journal: impl Into<PathBuf>) -> Self {
+        Self {
+            journal: journal.into(),
+        }
+    }
+
+    /// Propose every batch on its DB, then write the journal. Nothing is committed, and the
+    /// journal isn't written, if any of the batches can't be proposed. Fails with an
+    /// [ErrorKind::AlreadyExists] error if the journal of an earlier commit is still there, see
+    /// [MultiCommit::recover].
+    pub fn prepare<'a, K: KeyType, V: ValueType>(
+        &self,
+        batches: Vec<(&'a Db, Batch<K, V>)>,
+    ) -> Result<PreparedCommit<'a>, DbError> {
+        if self.journal.exists() {
+            return Err(DbError::IO(io::Error::new(
+                ErrorKind::AlreadyExists,
+                "a multi-DB commit is still pending recovery",
+            )));
+        }
+
+        let mut participants = Vec::with_capacity(batches.len());
+        let mut proposals = Vec::with_capacity(batches.len());
+        for (db, batch) in batches {
+            let batch = to_owned(batch);
+            let parent = db.kv_root_hash()?;
+            let proposal = db.new_proposal(batch.clone())?;
+            participants.push(Participant {
+                parent,
+                expected: proposal.root_hash,
+                batch,
+            });
+            proposals.push((db, proposal));
+        }
+
+        write_journal(&self.journal, &participants)?;
+        Ok(PreparedCommit {
+            journal: self.journal.clone(),
+            proposals,
+        })
+    }
+
+    /// Finish the commit whose journal is left, if any, on `dbs`: the batch of every DB still at
+    /// the root hash it had before its batch is proposed and committed again, and the journal is
+    /// removed once the commits are in the Wal. Returns whether there was a commit to finish.
+    ///
+    /// Fails with [DbError::InvalidParams] if there isn't one DB for each batch of the journal,
+    /// and with [DbError::RootMismatch] if a DB is neither before nor after its batch. The
+    /// journal is kept when recovering fails.
+    pub fn recover(&self, dbs: &[&Db]) -> Result<bool, DbError> {
+        let journal = match fs::read(&self.journal) {
+            Ok(journal) => journal,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(DbError::IO(e)),
+        };
+        let participants = decode_journal(&journal)?;
+        if participants.len() != dbs.len() {
+            return Err(DbError::InvalidParams);
+        }
+
+        for (db, participant) in dbs.iter().zip(participants) {
+            let root_hash = db.kv_root_hash()?;
+            if root_hash == participant.expected {
+                continue;
+            }
+            if root_hash != participant.parent {
+                return Err(DbError::RootMismatch {
+                    expected: participant.expected,
+                    computed: root_hash,
+                    dump: None,
+                });
+            }
+            db.new_proposal(participant.batch)?
+                .commit_with_expected_root(participant.expected)?;
+        }
+        // the journal goes once the commits it holds can't be lost
+        for db in dbs {
+            db.flush_writes()?;
+        }
+
+        fs::remove_file(&self.journal).map_err(DbError::IO)?;
+        Ok(true)
+    }
+}
+
+impl PreparedCommit<'_> {
+    /// The root hashes the DBs will have once the batches are committed, in the order of the
+    /// DBs.
+    pub fn root_hashes(&self) -> Vec<TrieHash> {
+        self.proposals
+            .iter()
+            .map(|(_, proposal)| proposal.root_hash)
+            .collect()
+    }
+
+    /// Commit the proposals, and remove the journal once they all are in the Wal of their DB. If
+    /// a commit fails, the journal is kept, so that [MultiCommit::recover] finishes the commit.
+    pub fn commit(self) -> Result<(), DbError> {
+        let mut dbs = Vec::with_capacity(self.proposals.len());
+        for (db, proposal) in self.proposals {
+            proposal.commit_sync()?;
+            dbs.push(db);
+        }
+        // a commit is only queued for the Wal when `commit_sync` returns
+        for db in dbs {
+            db.flush_writes()?;
+        }
+        fs::remove_file(&self.journal).map_err(DbError::IO)
+    }
+
+    /// Give up on the commit: the proposals are dropped, and the journal is removed. Unlike an
+    /// aborted one, a [PreparedCommit] dropped without being committed is committed by
+    /// [MultiCommit::recover].
+    pub fn abort(self) -> Result<(), DbError> {
+        fs::remove_file(&self.journal).map_err(DbError::IO)
+    }
+}
+
+/// Writes the journal, made durable before it is renamed into place, so that a partially
+/// written journal is never picked up. For every participant, the journal holds the root hashes
+/// of its DB before and after its batch, the number of operations of the batch, then the
+/// operations: a tag followed by their keys and values, each prefixed with its length.
+fn write_journal(path: &Path, participants: &[Participant]) -> Result<(), DbError> {
+    let mut bytes = Vec::new();
+    for participant in participants {
+        bytes.extend_from_slice(&participant.parent.0);
+        bytes.extend_from_slice(&participant.expected.0);
+        encode_batch(&mut bytes, &participant.batch);
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path).map_err(DbError::IO)?;
+    file.write_all(&bytes).map_err(DbError::IO)?;
+    file.sync_all().map_err(DbError::IO)?;
+    fs::rename(tmp_path, path).map_err(DbError::IO)
+}
+
+fn decode_journal(bytes: &[u8]) -> Result<Vec<Participant>, DbError> {
+    let mut journal = Reader(bytes);
+    let mut participants = Vec::new();
+    while !journal.0.is_empty() {
+        let parent = hash(&mut journal)?;
+        let expected = hash(&mut journal)?;
+        let batch = decode_batch(&mut journal)?;
+        participants.push(Participant {
+            parent,
+            expected,
+            batch,
+        });
+    }
+    Ok(participants)
+}
+
+fn hash(journal: &mut Reader) -> Result<TrieHash, DbError> {
+    #[allow(clippy::unwrap_used)]
+    journal
+        .take(TRIE_HASH_LEN)
+        .map(|hash| TrieHash(hash.try_into().unwrap()))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod tests {
+    use super::*;
+    use crate::v2::api::BatchOp;
+
+    #[test]
+    fn journal() {
+        let participants = vec![
+            Participant {
+                parent: TrieHash([1; TRIE_HASH_LEN]),
+                expected: TrieHash([2; TRIE_HASH_LEN]),
+                batch: vec![
+                    BatchOp::Put {
+                        key: b"k".to_vec(),
+                        value: b"v".to_vec(),
+                    },
+                    BatchOp::Delete { key: b"d".to_vec() },
+                    BatchOp::Move {
+                        key: b"from".to_vec(),
+                        new_key: b"to".to_vec(),
+                        overwrite: true,
+                    },
+                    BatchOp::DeletePrefix { prefix: Vec::new() },
+                ],
+            },
+            Participant {
+                parent: TrieHash([3; TRIE_HASH_LEN]),
+                expected: TrieHash([3; TRIE_HASH_LEN]),
+                batch: Vec::new(),
+            },
+        ];
+
+        let path = std::env::temp_dir().join("multi_commit_journal");
+        write_journal(&path, &participants).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(decode_journal(&bytes).unwrap(), participants);
+
+        // a journal cut short is never taken for a shorter one
+        assert!(decode_journal(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! A cache of the keys found missing from the latest revision of a [Db](super::Db), so that
+//! looking them up again answers without descending the trie, see
+//! [Db::negative_cache_stats](super::Db::negative_cache_stats).
+//!
+//! The keys are cached by their Keccak-256 hash, which is as unlikely to collide as the hashes
+//! of the trie itself, so that a key is never answered as missing because of another. A
+//! revision never changes, so each one has a cache of its own, rather than one cache entries
+//! are dropped from as keys are written: a commit starts the next revision with an empty cache,
+//! and the readers of a past revision keep the cache they had.
+
+use crate::config::NegativeCacheConfig;
+use lru::LruCache;
+use parking_lot::Mutex;
+use sha3::{Digest, Keccak256};
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// The lookups answered by the negative cache, see
+/// [Db::negative_cache_stats](super::Db::negative_cache_stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NegativeCacheStats {
+    /// Lookups of missing keys answered by the cache.
+    pub hits: u64,
+    /// Lookups of missing keys that descended the trie, after which the keys were cached.
+    pub misses: u64,
+    /// Keys cached for the latest revision.
+    pub entries: usize,
+}
+
+impl NegativeCacheStats {
+    /// Fraction of the lookups of missing keys answered by the cache, from 0 to 1, 0 if there
+    /// were none.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    capacity: NonZeroUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// The negative cache of a DB, handing a cache to each of its latest revisions in turn. Caches
+/// nothing if it's disabled.
+#[derive(Debug, Clone, Default)]
+pub(super) struct NegativeCache(Option<Arc<Shared>>);
+
+impl NegativeCache {
+    pub(super) fn new(config: &NegativeCacheConfig) -> Self {
+        let capacity = match NonZeroUsize::new(config.capacity) {
+            Some(capacity) if config.enabled => capacity,
+            _ => return Self::default(),
+        };
+        Self(Some(Arc::new(Shared {
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })))
+    }
+
+    /// An empty cache, for a revision that just became the latest one.
+    pub(super) fn for_revision(&self) -> MissingKeys {
+        MissingKeys(self.0.as_ref().map(|shared| {
+            (
+                shared.clone(),
+                Arc::new(Mutex::new(LruCache::new(shared.capacity))),
+            )
+        }))
+    }
+
+    /// The stats of the DB, with the entries of the cache of its `latest` revision.
+    pub(super) fn stats(&self, latest: &MissingKeys) -> NegativeCacheStats {
+        let Some(shared) = &self.0 else {
+            return NegativeCacheStats::default();
+        };
+        NegativeCacheStats {
+            hits: shared.hits.load(Ordering::Relaxed),
+            misses: shared.misses.load(Ordering::Relaxed),
+            entries: latest.0.as_ref().map_or(0, |(_, keys)| keys.lock().len()),
+        }
+    }
+}
+
+/// The keys found missing from a revision, shared by its views, see the
+/// [module documentation](self).
+#[derive(Debug, Clone, Default)]
+pub(super) struct MissingKeys(Option<(Arc<Shared>, Arc<Mutex<KeyHashes>>)>);
+
+type KeyHashes = LruCache<[u8; 32], ()>;
+
+impl MissingKeys {
+    /// Whether `key` was found missing already, counted as a hit if it was.
+    pub(super) fn contains(&self, key: &[u8]) -> bool {
+        let Some((shared, keys)) = &self.0 else {
+            return false;
+        };
+        let found = keys.lock().get(&hash(key)).is_some();
+        if found {
+            shared.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        found
+    }
+
+    /// Records that `key` was found missing by descending the trie, counted as a miss.
+    pub(super) fn insert(&self, key: &[u8]) {
+        if let Some((shared, keys)) = &self.0 {
+            shared.misses.fetch_add(1, Ordering::Relaxed);
+            keys.lock().put(hash(key), ());
+        }
+    }
+}
+
+fn hash(key: &[u8]) -> [u8; 32] {
+    Keccak256::digest(key).into()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recent_keys_are_evicted() {
+        let config = NegativeCacheConfig::builder()
+            .enabled(true)
+            .capacity(2)
+            .build();
+        let cache = NegativeCache::new(&config);
+        let keys = cache.for_revision();
+
+        assert!(!keys.contains(b"a"));
+        keys.insert(b"a");
+        keys.insert(b"b");
+        assert!(keys.contains(b"a"));
+        // "b" is the least recently used now
+        keys.insert(b"c");
+        assert!(!keys.contains(b"b"));
+        assert!(keys.contains(b"c"));
+
+        let stats = cache.stats(&keys);
+        assert_eq!(
+            stats,
+            NegativeCacheStats {
+                hits: 2,
+                misses: 3,
+                entries: 2,
+            }
+        );
+        assert_eq!(stats.hit_rate(), 0.4);
+
+        // the next revision starts empty, with the same counts
+        let next = cache.for_revision();
+        assert!(!next.contains(b"a"));
+        assert_eq!(cache.stats(&next).entries, 0);
+        assert_eq!(cache.stats(&next).hits, 2);
+    }
+
+    #[test]
+    fn disabled_caches_nothing() {
+        let cache = NegativeCache::new(&NegativeCacheConfig::builder().build());
+        let keys = cache.for_revision();
+        keys.insert(b"a");
+        assert!(!keys.contains(b"a"));
+        assert_eq!(cache.stats(&keys), NegativeCacheStats::default());
+    }
+}
@@ -0,0 +1,303 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Latency of the operations of a [Db](super::Db), by type of operation, see
+//! [Db::op_stats](super::Db::op_stats).
+//!
+//! Every operation is counted, and its latency added to the total and the maximum of its type.
+//! The latencies the percentiles are taken from are a uniform sample of all of them, kept in a
+//! reservoir of bounded size: the n-th operation replaces a random entry of the reservoir with a
+//! probability of its size over n. Once the reservoir is full, the lock on it is only taken for
+//! the operations that are kept, so that recording costs a few atomic additions for most of them.
+
+use crate::config::OpStatsConfig;
+use futures::{stream::FusedStream, Stream};
+use parking_lot::Mutex;
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// A type of operation whose latency is recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// A read of the value of a key.
+    Get,
+    /// A write of the value of a key, as part of a batch.
+    Insert,
+    /// A commit of a proposal.
+    Commit,
+    /// A proof of a key.
+    Prove,
+    /// A step of an iteration over the keys of a revision.
+    IterateNext,
+}
+
+/// The latencies of one type of operation, see [Db::op_stats](super::Db::op_stats).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpLatency {
+    /// Number of operations.
+    pub count: u64,
+    /// Total latency of the operations.
+    pub total: Duration,
+    /// Highest latency of an operation.
+    pub max: Duration,
+    /// A uniform sample of the latencies of the operations, in ascending order.
+    pub samples: Vec<Duration>,
+}
+
+impl OpLatency {
+    /// Mean latency of the operations, zero if there were none.
+    pub const fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos((self.total.as_nanos() / self.count as u128) as u64)
+    }
+
+    /// The latency below which the fraction `q` of the sampled operations are, from 0 to 1, or
+    /// `None` if no operation was sampled.
+    pub fn percentile(&self, q: f64) -> Option<Duration> {
+        let last = self.samples.len().checked_sub(1)?;
+        let rank = (q.clamp(0.0, 1.0) * last as f64).round() as usize;
+        self.samples.get(rank).copied()
+    }
+}
+
+/// The latencies of the operations of a DB, by type, see [Db::op_stats](super::Db::op_stats).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpStats {
+    pub get: OpLatency,
+    pub insert: OpLatency,
+    pub commit: OpLatency,
+    pub prove: OpLatency,
+    pub iterate_next: OpLatency,
+}
+
+/// A step of the splitmix64 generator, used to draw the entry of the reservoir to replace
+/// without a source of randomness shared by the threads.
+const fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[derive(Debug)]
+struct Histogram {
+    count: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+    reservoir: Mutex<Vec<u64>>,
+}
+
+impl Histogram {
+    fn new(reservoir_size: usize) -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            total_nanos: AtomicU64::new(0),
+            max_nanos: AtomicU64::new(0),
+            reservoir: Mutex::new(Vec::with_capacity(reservoir_size)),
+        }
+    }
+
+    fn record(&self, latency: Duration, reservoir_size: usize) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        let seen = self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+
+        let slot = if seen < reservoir_size as u64 {
+            seen
+        } else {
+            splitmix64(seen) % (seen + 1)
+        };
+        if slot >= reservoir_size as u64 {
+            return;
+        }
+
+        let mut reservoir = self.reservoir.lock();
+        // the operations counted before this one may still be on their way to the reservoir
+        match reservoir.get_mut(slot as usize) {
+            Some(sample) => *sample = nanos,
+            None => reservoir.push(nanos),
+        }
+    }
+
+    fn latency(&self) -> OpLatency {
+        let mut samples: Vec<_> = self
+            .reservoir
+            .lock()
+            .iter()
+            .map(|&nanos| Duration::from_nanos(nanos))
+            .collect();
+        samples.sort_unstable();
+        OpLatency {
+            count: self.count.load(Ordering::Relaxed),
+            total: Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed)),
+            max: Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed)),
+            samples,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Histograms {
+    reservoir_size: usize,
+    get: Histogram,
+    insert: Histogram,
+    commit: Histogram,
+    prove: Histogram,
+    iterate_next: Histogram,
+}
+
+impl Histograms {
+    const fn get(&self, op: Op) -> &Histogram {
+        match op {
+            Op::Get => &self.get,
+            Op::Insert => &self.insert,
+            Op::Commit => &self.commit,
+            Op::Prove => &self.prove,
+            Op::IterateNext => &self.iterate_next,
+        }
+    }
+}
+
+/// A handle on the latency histograms of a DB, shared by its revisions and proposals. Records
+/// nothing if the stats are disabled.
+#[derive(Debug, Clone, Default)]
+pub(super) struct OpRecorder(Option<Arc<Histograms>>);
+
+impl OpRecorder {
+    pub(super) fn new(config: &OpStatsConfig) -> Self {
+        if !config.enabled {
+            return Self::default();
+        }
+        let size = config.reservoir_size.max(1);
+        Self(Some(Arc::new(Histograms {
+            reservoir_size: size,
+            get: Histogram::new(size),
+            insert: Histogram::new(size),
+            commit: Histogram::new(size),
+            prove: Histogram::new(size),
+            iterate_next: Histogram::new(size),
+        })))
+    }
+
+    pub(super) fn record(&self, op: Op, latency: Duration) {
+        if let Some(histograms) = &self.0 {
+            histograms
+                .get(op)
+                .record(latency, histograms.reservoir_size);
+        }
+    }
+
+    /// Times `op` until the returned timer is dropped.
+    pub(super) fn start(&self, op: Op) -> OpTimer<'_> {
+        OpTimer {
+            recorder: self,
+            op,
+            start: self.0.as_ref().map(|_| Instant::now()),
+        }
+    }
+
+    pub(super) fn stats(&self) -> OpStats {
+        self.0
+            .as_ref()
+            .map_or_else(OpStats::default, |histograms| OpStats {
+                get: histograms.get.latency(),
+                insert: histograms.insert.latency(),
+                commit: histograms.commit.latency(),
+                prove: histograms.prove.latency(),
+                iterate_next: histograms.iterate_next.latency(),
+            })
+    }
+}
+
+/// Records the latency of an operation when dropped, see [OpRecorder::start].
+pub(super) struct OpTimer<'a> {
+    recorder: &'a OpRecorder,
+    op: Op,
+    start: Option<Instant>,
+}
+
+impl Drop for OpTimer<'_> {
+    fn drop(&mut self) {
+        if let Some(start) = self.start {
+            self.recorder.record(self.op, start.elapsed());
+        }
+    }
+}
+
+/// A stream over the keys of a revision that records the latency of each of its steps as an
+/// [Op::IterateNext].
+#[derive(Debug)]
+pub struct TimedStream<S> {
+    stream: S,
+    recorder: OpRecorder,
+}
+
+impl<S> TimedStream<S> {
+    pub(super) const fn new(stream: S, recorder: OpRecorder) -> Self {
+        Self { stream, recorder }
+    }
+}
+
+impl<S: Stream + Unpin> Stream for TimedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let _timer = this.recorder.start(Op::IterateNext);
+        Pin::new(&mut this.stream).poll_next(cx)
+    }
+}
+
+impl<S: FusedStream + Unpin> FusedStream for TimedStream<S> {
+    fn is_terminated(&self) -> bool {
+        self.stream.is_terminated()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservoir() {
+        let config = OpStatsConfig::builder()
+            .enabled(true)
+            .reservoir_size(100)
+            .build();
+        let recorder = OpRecorder::new(&config);
+        for micros in 1..=1000 {
+            recorder.record(Op::Get, Duration::from_micros(micros));
+        }
+        recorder.record(Op::Commit, Duration::from_millis(3));
+
+        let stats = recorder.stats();
+        assert_eq!(stats.get.count, 1000);
+        assert_eq!(stats.get.max, Duration::from_micros(1000));
+        assert_eq!(stats.get.mean(), Duration::from_nanos(500_500));
+        assert_eq!(stats.get.samples.len(), 100);
+        // a uniform sample of 1..=1000 has its median well inside the middle half
+        let median = stats.get.percentile(0.5).unwrap();
+        assert!(median > Duration::from_micros(250) && median < Duration::from_micros(750));
+        assert_eq!(
+            stats.commit.percentile(0.99),
+            Some(Duration::from_millis(3))
+        );
+        assert_eq!(stats.prove, OpLatency::default());
+        assert_eq!(stats.prove.percentile(0.5), None);
+
+        let disabled = OpRecorder::new(&OpStatsConfig::builder().build());
+        drop(disabled.start(Op::Insert));
+        assert_eq!(disabled.stats(), OpStats::default());
+    }
+}
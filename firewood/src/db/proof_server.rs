@@ -0,0 +1,224 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Serves single-key proofs from a bounded pool of threads.
+//!
+//! Requests are queued, and a request for the same key of the same revision as one that is still
+//! queued joins it instead of traversing the trie again. Workers hold each request for at least
+//! [ProofServerConfig::coalesce_window] after it was queued, so that a burst of identical requests
+//! is answered by a single traversal. The revisions proofs are served from are kept open between
+//! requests, so that their node caches stay warm.
+
+use super::{Db, DbRev};
+use crate::{
+    config::ProofServerConfig,
+    merkle::{Proof, TrieHash},
+    storage::StoreRevShared,
+    v2::api::{self, HashKey, KeyType},
+};
+use lru::LruCache;
+use parking_lot::Mutex;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    thread::JoinHandle,
+    time::Instant,
+};
+use tokio::sync::oneshot;
+
+/// A proof requested for a key of the revision with a root hash.
+type Request = (HashKey, Vec<u8>);
+
+/// Why a proof couldn't be served. Unlike [api::Error], this can be sent to every waiter of a
+/// coalesced request.
+#[derive(Clone, Debug)]
+enum ProveError {
+    HashNotFound,
+    Failed(String),
+}
+
+type ProveResult = Result<Proof<Vec<u8>>, ProveError>;
+
+/// What a [ProofServer] has served since it was started.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProofServerStats {
+    /// Number of proofs requested.
+    pub requests: u64,
+    /// Number of requests answered by the traversal of an identical request.
+    pub coalesced: u64,
+}
+
+struct Queued {
+    request: Request,
+    at: Instant,
+}
+
+struct Shared {
+    db: Arc<Db>,
+    cfg: ProofServerConfig,
+    /// The waiters of the requests that are queued, and not yet picked up by a worker.
+    pending: Mutex<HashMap<Request, Vec<oneshot::Sender<ProveResult>>>>,
+    revisions: Mutex<LruCache<HashKey, Arc<DbRev<StoreRevShared>>>>,
+    requests: AtomicU64,
+    coalesced: AtomicU64,
+}
+
+impl Shared {
+    fn revision(&self, root_hash: HashKey) -> Option<Arc<DbRev<StoreRevShared>>> {
+        if let Some(rev) = self.revisions.lock().get(&root_hash) {
+            return Some(rev.clone());
+        }
+
+        let rev = Arc::new(self.db.get_revision(&TrieHash(root_hash))?);
+        self.revisions.lock().put(root_hash, rev.clone());
+        Some(rev)
+    }
+
+    fn prove(&self, (root_hash, key): &Request) -> ProveResult {
+        let rev = self.revision(*root_hash).ok_or(ProveError::HashNotFound)?;
+        rev.prove(key)
+            .map_err(|e| ProveError::Failed(e.to_string()))
+    }
+
+    fn work(&self, queue: &Mutex<mpsc::Receiver<Queued>>) {
+        loop {
+            // the queue is disconnected once the server is dropped and every request is served
+            let Ok(Queued { request, at }) = queue.lock().recv() else {
+                return;
+            };
+
+            if let Some(wait) = self.cfg.coalesce_window.checked_sub(at.elapsed()) {
+                std::thread::sleep(wait);
+            }
+
+            let waiters = self.pending.lock().remove(&request).unwrap_or_default();
+            let result = self.prove(&request);
+            for waiter in waiters {
+                // the requester may have stopped waiting, which is fine
+                let _ = waiter.send(result.clone());
+            }
+        }
+    }
+}
+
+/// Serves proofs of the revisions of a [Db] to concurrent requesters, see the
+/// [module documentation](self).
+pub struct ProofServer {
+    shared: Arc<Shared>,
+    queue: Option<mpsc::Sender<Queued>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for ProofServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProofServer")
+            .field("cfg", &self.shared.cfg)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl ProofServer {
+    /// Starts the workers of a server for the revisions of `db`.
+    pub fn new(db: Arc<Db>, cfg: ProofServerConfig) -> Self {
+        let ncached_revisions =
+            NonZeroUsize::new(cfg.ncached_revisions).unwrap_or(NonZeroUsize::MIN);
+        let nthreads = cfg.threads.max(1);
+        let shared = Arc::new(Shared {
+            db,
+            cfg,
+            pending: Mutex::new(HashMap::new()),
+            revisions: Mutex::new(LruCache::new(ncached_revisions)),
+            requests: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+        });
+
+        let (queue, jobs) = mpsc::channel();
+        let jobs = Arc::new(Mutex::new(jobs));
+        let workers = (0..nthreads)
+            .map(|i| {
+                let shared = shared.clone();
+                let jobs = jobs.clone();
+                std::thread::Builder::new()
+                    .name(format!("ProofServer-{i}"))
+                    .spawn(move || shared.work(&jobs))
+                    .expect("thread spawn should succeed")
+            })
+            .collect();
+
+        Self {
+            shared,
+            queue: Some(queue),
+            workers,
+        }
+    }
+
+    /// Proves `key` in the revision with `root_hash`, failing with [api::Error::HashNotFound] if
+    /// the DB no longer has that revision.
+    pub async fn prove<K: KeyType>(
+        &self,
+        root_hash: HashKey,
+        key: K,
+    ) -> Result<Proof<Vec<u8>>, api::Error> {
+        let (tx, rx) = oneshot::channel();
+        self.shared.requests.fetch_add(1, Ordering::Relaxed);
+
+        {
+            let mut pending = self.shared.pending.lock();
+            match pending.entry((root_hash, key.as_ref().to_vec())) {
+                Entry::Occupied(mut e) => {
+                    e.get_mut().push(tx);
+                    self.shared.coalesced.fetch_add(1, Ordering::Relaxed);
+                }
+                Entry::Vacant(e) => {
+                    let request = e.key().clone();
+                    e.insert(vec![tx]);
+                    #[allow(clippy::unwrap_used)]
+                    self.queue
+                        .as_ref()
+                        .unwrap()
+                        .send(Queued {
+                            request,
+                            at: Instant::now(),
+                        })
+                        .map_err(|_| stopped())?;
+                }
+            }
+        }
+
+        match rx.await {
+            Ok(Ok(proof)) => Ok(proof),
+            Ok(Err(ProveError::HashNotFound)) => Err(api::Error::HashNotFound {
+                provided: root_hash,
+            }),
+            Ok(Err(ProveError::Failed(e))) => Err(api::Error::IO(std::io::Error::other(e))),
+            Err(_) => Err(stopped()),
+        }
+    }
+
+    /// What the server has served so far.
+    pub fn stats(&self) -> ProofServerStats {
+        ProofServerStats {
+            requests: self.shared.requests.load(Ordering::Relaxed),
+            coalesced: self.shared.coalesced.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for ProofServer {
+    /// Serves the requests still queued, then stops the workers.
+    fn drop(&mut self) {
+        self.queue.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn stopped() -> api::Error {
+    api::Error::IO(std::io::Error::other("the proof server stopped"))
+}
@@ -2,8 +2,11 @@
 // See the file LICENSE.md for licensing terms.
 
 use super::{
-    get_sub_universe_from_deltas, Db, DbConfig, DbError, DbHeader, DbInner, DbRev, DbRevInner,
-    Universe, MERKLE_META_STORE_ID, MERKLE_PAYLOAD_STORE_ID, ROOT_HASH_STORE_ID,
+    batch_validator::BatchValidators, change_filter::ChangeFilter, commit_hook::CommitHooks,
+    get_sub_universe_from_deltas, get_sub_universe_from_empty_delta, pin_hot_segment,
+    revision_index, secondary_index::SecondaryIndexes, sequence::Sequences, Db, DbConfig, DbError,
+    DbHeader, DbInner, DbRev, DbRevInner, DryRun, MemoryBudget, Op, TimedStream, Universe,
+    MERKLE_META_STORE_ID, MERKLE_PAYLOAD_STORE_ID, ROOT_HASH_STORE_ID,
 };
 use crate::merkle::{Bincode, MerkleKeyValueStream, Proof};
 use crate::shale::LinearStore;
@@ -11,13 +14,26 @@ use crate::storage::StoreRevShared;
 use crate::{
     merkle::{TrieHash, TRIE_HASH_LEN},
     storage::{buffer::BufferWrite, AshRecord, StoreRevMut},
-    v2::api::{self, Batch, BatchOp, KeyType, ValueType},
+    v2::api::{self, Batch, KeyType, ValueType},
 };
 use async_trait::async_trait;
+use growthring::wal::Record;
 use parking_lot::{Mutex, RwLock};
-use std::{io::ErrorKind, sync::Arc};
+use std::{
+    fmt::Write as _,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
 use tokio::task::block_in_place;
 
+/// Maximum number of nodes written to the dump of a root hash mismatch.
+const MAX_DIVERGING_NODES: usize = 1 << 16;
+/// Number of pages of the payload read back at a time when a proposal is committed, so that the
+/// pages it spilled aren't all read back before they are applied.
+const COMMIT_CHUNK_PAGES: usize = 1024;
+
 /// An atomic batch of changes proposed against the latest committed revision,
 /// or any existing [Proposal]. Multiple proposals can be created against the
 /// latest committed revision at the same time. [Proposal] is immutable meaning
@@ -28,12 +44,21 @@ pub struct Proposal {
     pub(super) m: Arc<RwLock<DbInner>>,
     pub(super) r: Arc<Mutex<DbRevInner<StoreRevShared>>>,
     pub(super) cfg: DbConfig,
+    pub(super) hooks: CommitHooks,
+    pub(super) validators: BatchValidators,
+    pub(super) indexes: SecondaryIndexes,
+    pub(super) sequences: Sequences,
+    pub(super) budget: MemoryBudget,
+    pub(super) diagnostics: PathBuf,
 
     // State of the proposal
     pub(super) rev: DbRev<StoreRevMut>,
     pub(super) store: Universe<StoreRevMut>,
     pub(super) committed: Arc<Mutex<bool>>,
     pub(super) root_hash: TrieHash,
+    pub(super) annotation: String,
+    /// The filter of the keys the batch of the proposal changed.
+    pub(super) changes: ChangeFilter,
 
     pub(super) parent: ProposalBase,
 }
@@ -68,11 +93,23 @@ impl Proposal {
         self: Arc<Self>,
         data: Batch<K, V>,
     ) -> Result<Proposal, DbError> {
+        if self.m.read().closed {
+            return Err(DbError::Closed);
+        }
+        self.validators.validate(&data)?;
+
         let store = self.store.new_from_other();
+        store.reserve_for_batch(&data);
 
         let m = Arc::clone(&self.m);
         let r = Arc::clone(&self.r);
         let cfg = self.cfg.clone();
+        let hooks = self.hooks.clone();
+        let validators = self.validators.clone();
+        let indexes = self.indexes.clone();
+        let sequences = self.sequences.clone();
+        let budget = self.budget.clone();
+        let diagnostics = self.diagnostics.clone();
 
         let db_header_ref = Db::get_db_header_ref(&store.merkle.meta)?;
 
@@ -85,27 +122,23 @@ impl Proposal {
             header_refs,
             (store.merkle.meta.clone(), store.merkle.payload.clone()),
             cfg.payload_regn_nbit,
+            cfg.payload_align_nbit,
             cfg.payload_max_walk,
             &cfg.rev,
-        )?;
-        data.into_iter().try_for_each(|op| -> Result<(), DbError> {
-            match op {
-                BatchOp::Put { key, value } => {
-                    let (header, merkle) = rev.borrow_split();
-                    merkle
-                        .insert(key, value.as_ref().to_vec(), header.sentinel_addr)
-                        .map_err(DbError::Merkle)?;
-                    Ok(())
-                }
-                BatchOp::Delete { key } => {
-                    let (header, merkle) = rev.borrow_split();
-                    merkle
-                        .remove(key, header.sentinel_addr)
-                        .map_err(DbError::Merkle)?;
-                    Ok(())
-                }
-            }
-        })?;
+            cfg.verify_hashes_on_read,
+            cfg.inline_value_threshold,
+            cfg.node_encoding,
+            cfg.delayed_allocation,
+            cfg.hot_levels,
+            &budget,
+            &cfg.payload_allocator,
+        )?
+        .with_hot_keys(self.rev.hot_keys.clone())
+        .with_op_stats(self.rev.op_stats.clone())
+        .with_quarantine(self.rev.merkle.quarantine())
+        .with_free_index(self.rev.merkle.free_index());
+        let changes = rev.apply_batch(data, &indexes)?.to_filter();
+        rev.write_sequences(&sequences)?;
 
         // Calculated the root hash before flushing so it can be persisted.
         let hash = rev.kv_root_hash()?;
@@ -118,14 +151,73 @@ impl Proposal {
             m,
             r,
             cfg,
+            hooks,
+            validators,
+            indexes,
+            sequences,
+            budget,
+            diagnostics,
             rev,
             store,
             committed: Arc::new(Mutex::new(false)),
             root_hash: hash,
+            annotation: String::new(),
+            changes,
             parent,
         })
     }
 
+    /// Like [Proposal::commit_sync], but only commits if the root hash of the proposal is
+    /// `expected`. Otherwise, the nodes where the proposal diverges from its parent are written
+    /// to the `diagnostics` directory of the DB, with their paths, hashes and keys, and
+    /// [DbError::RootMismatch] is returned. Writing the dump is best effort: when it fails, the
+    /// error is returned without it.
+    pub fn commit_with_expected_root(self, expected: TrieHash) -> Result<(), DbError> {
+        if self.root_hash == expected {
+            return self.commit_sync();
+        }
+
+        let dump = self.dump_divergence(expected).ok();
+        Err(DbError::RootMismatch {
+            expected,
+            computed: self.root_hash,
+            dump,
+        })
+    }
+
+    fn dump_divergence(&self, expected: TrieHash) -> Result<PathBuf, DbError> {
+        let diverging = match &self.parent {
+            ProposalBase::View(p) => self.rev.diverging_nodes(&**p, MAX_DIVERGING_NODES),
+            ProposalBase::Proposal(p) => self.rev.diverging_nodes(&p.rev, MAX_DIVERGING_NODES),
+        }?;
+        let parent_root_hash = match &self.parent {
+            ProposalBase::View(p) => p.kv_root_hash(),
+            ProposalBase::Proposal(p) => Ok(p.root_hash),
+        }?;
+
+        let mut dump = format!(
+            "expected root {expected:?}\ncomputed root {:?}\nparent root {parent_root_hash:?}\n\
+             path hash parent_hash key\n",
+            self.root_hash
+        );
+        for node in diverging {
+            let other_hash = node
+                .other_hash
+                .map_or_else(|| "-".to_string(), |hash| format!("{hash:?}"));
+            let key = node.key.map_or_else(|| "-".to_string(), hex::encode);
+            #[allow(clippy::unwrap_used)]
+            writeln!(
+                dump,
+                "{} {:?} {other_hash} {key}",
+                path_to_hex(&node.path),
+                node.hash
+            )
+            .unwrap();
+        }
+
+        write_dump(&self.diagnostics, &self.root_hash, &dump)
+    }
+
     /// Persist all changes to the DB. The atomicity of the [Proposal] guarantees all changes are
     /// either retained on disk or lost together during a crash.
     pub fn commit_sync(self) -> Result<(), DbError> {
@@ -133,10 +225,18 @@ impl Proposal {
             m,
             r,
             cfg: _,
+            hooks,
+            validators: _,
+            indexes: _,
+            sequences: _,
+            budget: _,
+            diagnostics: _,
             rev,
             store,
             committed,
             root_hash: hash,
+            annotation,
+            changes,
             parent,
         } = self;
 
@@ -144,6 +244,15 @@ impl Proposal {
         if *committed {
             return Ok(());
         }
+        // held until the commit is done writing, see `Db::freeze_writes`
+        let fence = m.read().write_fence.clone();
+        let _writing = fence.enter();
+        let op_stats = rev.op_stats.clone();
+        let _timer = op_stats.start(Op::Commit);
+
+        // a header broken by the proposal is never written to the Wal, and so never reaches the
+        // store files at a checkpoint
+        rev.merkle.check_store_header().map_err(DbError::Merkle)?;
 
         if let ProposalBase::Proposal(_p) = parent {
             // p.commit_sync()?;
@@ -171,24 +280,44 @@ impl Proposal {
             }
         };
 
-        // clear the staging layer and apply changes to the CachedStore
-        let (merkle_payload_redo, merkle_payload_wal) = store.merkle.payload.delta();
-        let (merkle_meta_redo, merkle_meta_wal) = store.merkle.meta.delta();
+        // clear the staging layer and apply changes to the CachedStore, the pages of the payload
+        // being read when they are applied, as they may be spilled
+        let merkle_payload_pids = store.merkle.payload.delta_pids();
+        let merkle_payload_wal = store.merkle.payload.delta_writes()?;
+        let merkle_meta_pids = store.merkle.meta.delta_pids();
+        let (merkle_meta_redo, merkle_meta_wal) = store.merkle.meta.delta()?;
 
         let mut rev_inner = m.write();
+        if rev_inner.closed {
+            return Err(DbError::Closed);
+        }
+        // nothing is changed yet, so a commit that doesn't fit on disk leaves the DB as it was
+        rev_inner.disk_space.check(
+            &[
+                (
+                    rev_inner.cached_store.merkle.payload.as_ref(),
+                    merkle_payload_pids.as_slice(),
+                ),
+                (
+                    rev_inner.cached_store.merkle.meta.as_ref(),
+                    merkle_meta_pids.as_slice(),
+                ),
+            ],
+            &[&merkle_payload_wal, &merkle_meta_wal],
+        )?;
         #[allow(clippy::unwrap_used)]
         let merkle_meta_undo = rev_inner
             .cached_store
             .merkle
             .meta
-            .update(&merkle_meta_redo)
+            .undo_delta(&merkle_meta_pids)
             .unwrap();
         #[allow(clippy::unwrap_used)]
         let merkle_payload_undo = rev_inner
             .cached_store
             .merkle
             .payload
-            .update(&merkle_payload_redo)
+            .undo_delta(&merkle_payload_pids)
             .unwrap();
 
         // update the rolling window of past revisions
@@ -200,21 +329,87 @@ impl Proposal {
             ),
         };
 
+        // Pin every view of the revisions read through the CachedStore, including the ones still
+        // being iterated, at their current state before the CachedStore is changed under them.
+        // The latest past revision reads the same as the CachedStore until then.
         let max_revisions = revisions.max_revisions;
         if let Some(rev) = revisions.inner.front_mut() {
-            rev.merkle
-                .meta
-                .set_base_store(latest_past.merkle.meta.inner().clone());
-            rev.merkle
-                .payload
-                .set_base_store(latest_past.merkle.payload.inner().clone());
+            rev.set_base_store(&latest_past);
         }
+        revisions.base.set_base_store(&latest_past);
+        revisions
+            .base_revision
+            .universe()
+            .set_base_store(&latest_past);
         revisions.inner.push_front(latest_past);
+
+        // apply the changes to the CachedStore
+        let mut page_batch = Vec::new();
+        let mut payload_restores = Vec::new();
+        for pids in merkle_payload_pids.chunks(COMMIT_CHUNK_PAGES) {
+            let merkle_payload_redo = match store.merkle.payload.delta_pages_of(pids) {
+                Ok(redo) => redo,
+                Err(e) => {
+                    // put back the pages applied so far, which the views pinned above read as
+                    // they were already
+                    for restore in payload_restores.iter().rev() {
+                        #[allow(clippy::unwrap_used)]
+                        rev_inner
+                            .cached_store
+                            .merkle
+                            .payload
+                            .update(restore)
+                            .unwrap();
+                    }
+                    revisions.inner.pop_front();
+                    return Err(e.into());
+                }
+            };
+            #[allow(clippy::unwrap_used)]
+            payload_restores.push(
+                rev_inner
+                    .cached_store
+                    .merkle
+                    .payload
+                    .update(&merkle_payload_redo)
+                    .unwrap(),
+            );
+            page_batch.push(BufferWrite {
+                store_id: store.merkle.payload.id(),
+                delta: merkle_payload_redo,
+            });
+        }
+        drop(payload_restores);
+        #[allow(clippy::unwrap_used)]
+        rev_inner
+            .cached_store
+            .merkle
+            .meta
+            .update(&merkle_meta_redo)
+            .unwrap();
         while revisions.inner.len() > max_revisions {
             revisions.inner.pop_back();
         }
+        // the committed revision reads its pages from the CachedStore now, so they, and the ones
+        // it spilled above all, aren't read back into it
+        store.merkle.payload.reset_deltas();
 
-        revisions.base_revision = Arc::new(rev.into());
+        revisions.base = Universe {
+            merkle: get_sub_universe_from_empty_delta(&rev_inner.cached_store.merkle),
+        };
+        // the changes are applied already, so the pages of the segment that can't be read now
+        // are pinned by the next commit instead
+        if let Ok(pinned) = pin_hot_segment(
+            &revisions.base.merkle.payload,
+            rev.merkle.hot_segment(),
+            rev_inner.hot_pinned,
+        ) {
+            rev_inner.hot_pinned = pinned;
+        }
+        let counts = rev.counts();
+        let missing_keys = revisions.negative_cache.for_revision();
+        revisions.base_revision =
+            Arc::new(DbRev::from(rev.with_revision_hash(hash)).with_missing_keys(missing_keys));
 
         // update the rolling window of root hashes
         revisions.root_hashes.push_front(hash);
@@ -224,36 +419,48 @@ impl Proposal {
                 .resize(max_revisions, TrieHash([0; TRIE_HASH_LEN]));
         }
 
-        rev_inner.root_hash_staging.write(0, &hash.0)?;
-        let (root_hash_redo, root_hash_wal) = rev_inner.root_hash_staging.delta();
+        let entry = revision_index::encode(&hash, SystemTime::now(), counts, &annotation, &changes);
+        rev_inner.root_hash_staging.write(0, &entry)?;
+        let (root_hash_redo, root_hash_wal) = rev_inner.root_hash_staging.delta()?;
+
+        page_batch.extend([
+            BufferWrite {
+                store_id: store.merkle.meta.id(),
+                delta: merkle_meta_redo,
+            },
+            BufferWrite {
+                store_id: rev_inner.root_hash_staging.id(),
+                delta: root_hash_redo,
+            },
+        ]);
+        let page_batch = page_batch.into_boxed_slice();
+        let write_batch = AshRecord(
+            [
+                (MERKLE_META_STORE_ID, merkle_meta_wal),
+                (MERKLE_PAYLOAD_STORE_ID, merkle_payload_wal),
+                (ROOT_HASH_STORE_ID, root_hash_wal),
+            ]
+            .into(),
+        );
 
         // schedule writes to the disk
-        rev_inner.disk_requester.write(
-            Box::new([
-                BufferWrite {
-                    store_id: store.merkle.payload.id(),
-                    delta: merkle_payload_redo,
-                },
-                BufferWrite {
-                    store_id: store.merkle.meta.id(),
-                    delta: merkle_meta_redo,
-                },
-                BufferWrite {
-                    store_id: rev_inner.root_hash_staging.id(),
-                    delta: root_hash_redo,
-                },
-            ]),
-            AshRecord(
-                [
-                    (MERKLE_META_STORE_ID, merkle_meta_wal),
-                    (MERKLE_PAYLOAD_STORE_ID, merkle_payload_wal),
-                    (ROOT_HASH_STORE_ID, root_hash_wal),
-                ]
-                .into(),
-            ),
-        );
+        if hooks.is_empty() {
+            rev_inner.disk_requester.write(page_batch, write_batch);
+            *committed = true;
+            return Ok(());
+        }
+
+        // With hooks registered, the commit is acknowledged only once the record is in the Wal
+        // and every hook has accepted it. The locks stay held until then so the hooks see the
+        // records in the same order as the Wal.
+        let record = write_batch.serialize();
         *committed = true;
-        Ok(())
+        rev_inner
+            .disk_requester
+            .write_logged(page_batch, write_batch)
+            .map_err(|e| DbError::IO(std::io::Error::other(e)))?;
+
+        block_in_place(|| futures::executor::block_on(hooks.run(&record)))
     }
 }
 
@@ -261,11 +468,49 @@ impl Proposal {
     pub const fn get_revision(&self) -> &DbRev<StoreRevMut> {
         &self.rev
     }
+
+    /// Annotates the revision the proposal commits, for instance with the block it applies. The
+    /// annotation is listed with the revision by [Db::revision_index], truncated to
+    /// [MAX_ANNOTATION_LEN](super::MAX_ANNOTATION_LEN) bytes.
+    pub fn with_annotation(mut self, annotation: impl Into<String>) -> Self {
+        self.annotation = revision_index::truncate_annotation(annotation.into());
+        self
+    }
+
+    /// Compute what committing `data` on top of this proposal would result in, without creating a
+    /// proposal, see [Db::dry_run].
+    pub fn dry_run<K: KeyType, V: ValueType>(&self, data: Batch<K, V>) -> Result<DryRun, DbError> {
+        self.validators.validate(&data)?;
+
+        let store = self.store.new_from_other();
+        store.reserve_for_batch(&data);
+        let db_header_ref = Db::get_db_header_ref(&store.merkle.meta)?;
+        let merkle_payload_header_ref =
+            Db::get_payload_header_ref(&store.merkle.meta, Db::PARAM_SIZE + DbHeader::MSIZE)?;
+
+        let rev = Db::new_revision(
+            (db_header_ref, merkle_payload_header_ref),
+            (store.merkle.meta.clone(), store.merkle.payload.clone()),
+            self.cfg.payload_regn_nbit,
+            self.cfg.payload_align_nbit,
+            self.cfg.payload_max_walk,
+            &self.cfg.rev,
+            self.cfg.verify_hashes_on_read,
+            self.cfg.inline_value_threshold,
+            self.cfg.node_encoding,
+            self.cfg.delayed_allocation,
+            self.cfg.hot_levels,
+            &self.budget,
+            &self.cfg.payload_allocator,
+        )?;
+
+        DryRun::new(&store, rev, data, &self.indexes)
+    }
 }
 
 #[async_trait]
 impl api::DbView for Proposal {
-    type Stream<'a> = MerkleKeyValueStream<'a, StoreRevMut, Bincode>;
+    type Stream<'a> = TimedStream<MerkleKeyValueStream<'a, StoreRevMut, Bincode>>;
 
     async fn root_hash(&self) -> Result<api::HashKey, api::Error> {
         self.get_revision()
@@ -281,6 +526,16 @@ impl api::DbView for Proposal {
         Ok(self.get_revision().kv_get(key))
     }
 
+    async fn val_many<K>(&self, keys: Vec<K>) -> Vec<Result<Option<Vec<u8>>, api::Error>>
+    where
+        K: api::KeyType,
+    {
+        block_in_place(|| self.get_revision().read_many(&keys))
+            .into_iter()
+            .map(|value| value.map_err(|e| api::Error::InternalError(Box::new(e))))
+            .collect()
+    }
+
     async fn single_key_proof<K>(&self, key: K) -> Result<Option<Proof<Vec<u8>>>, api::Error>
     where
         K: api::KeyType,
@@ -303,6 +558,21 @@ impl api::DbView for Proposal {
         todo!();
     }
 
+    async fn range_proof_rev<K, V>(
+        &self,
+        first_key: Option<K>,
+        last_key: Option<K>,
+        limit: Option<usize>,
+    ) -> Result<Option<api::RangeProof<Vec<u8>, Vec<u8>>>, api::Error>
+    where
+        K: api::KeyType,
+    {
+        let rev = self.get_revision();
+        rev.merkle
+            .range_proof_rev(rev.header.sentinel_addr, first_key, last_key, limit)
+            .await
+    }
+
     fn iter_option<K: KeyType>(
         &self,
         first_key: Option<K>,
@@ -312,6 +582,29 @@ impl api::DbView for Proposal {
             None => rev.stream(),
             Some(first_key) => rev.stream_from(first_key.as_ref().into()),
         };
-        Ok(iter)
+        Ok(rev.timed(iter))
     }
+
+    fn iter_rev_option<K: KeyType>(
+        &self,
+        last_key: Option<K>,
+    ) -> Result<Self::Stream<'_>, api::Error> {
+        let rev = self.get_revision();
+        Ok(rev.timed(rev.stream_rev(last_key.map(|key| key.as_ref().into()))))
+    }
+}
+
+/// The nibbles of a path, one hex digit each, or `-` for the root.
+fn path_to_hex(path: &[u8]) -> String {
+    if path.is_empty() {
+        return "-".to_string();
+    }
+    path.iter().map(|nibble| format!("{nibble:x}")).collect()
+}
+
+fn write_dump(dir: &Path, computed: &TrieHash, dump: &str) -> Result<PathBuf, DbError> {
+    std::fs::create_dir_all(dir).map_err(DbError::IO)?;
+    let path = dir.join(format!("root-mismatch-{computed:?}.txt"));
+    std::fs::write(&path, dump).map_err(DbError::IO)?;
+    Ok(path)
 }
@@ -0,0 +1,77 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Plans how [DbRev::kv_get_many](super::DbRev::kv_get_many) reads a set of keys.
+//!
+//! A key can be read by traversing the trie from the root down to it, which reads about as many
+//! nodes as the trie is deep, or by streaming the keys of the trie from an earlier key until it
+//! is reached, which reads about one node per key streamed past. Keys close enough together are
+//! cheaper to read in a single stream, so the sorted keys are split into runs, each read with
+//! one stream, wherever the next key is expected to be further away than a traversal costs.
+//!
+//! How far apart two keys are is estimated from the trie stats alone, assuming the keys of the
+//! trie are spread evenly, as hashed keys are: the number of keys between two keys is their
+//! distance, taking their first bytes as a fraction of the key space, times the number of keys.
+
+use std::ops::Range;
+
+/// The number of bytes of a key its position in the key space is taken from.
+const POSITION_BYTES: usize = 8;
+
+/// The position of `key` in the key space, from 0 to 1.
+fn position(key: &[u8]) -> f64 {
+    let mut bytes = [0; POSITION_BYTES];
+    for (byte, key_byte) in bytes.iter_mut().zip(key) {
+        *byte = *key_byte;
+    }
+    u64::from_be_bytes(bytes) as f64 / (u64::MAX as f64 + 1.0)
+}
+
+/// The number of nodes read by a traversal of a trie of `key_count` keys: one for each nibble
+/// needed to tell the keys apart, and the root.
+fn traversal_cost(key_count: u64) -> f64 {
+    (key_count.max(1) as f64).log(16.0).ceil() + 1.0
+}
+
+/// Splits `keys`, which are sorted, into the runs to read with a single stream each, for a trie
+/// with `key_count` keys. A run of one key is read with a traversal.
+pub(super) fn plan<K: AsRef<[u8]>>(keys: &[K], key_count: u64) -> Vec<Range<usize>> {
+    let cost = traversal_cost(key_count);
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for (i, pair) in keys.windows(2).enumerate() {
+        let [previous, next] = pair else {
+            continue;
+        };
+        let between = (position(next.as_ref()) - position(previous.as_ref())) * key_count as f64;
+        if between >= cost {
+            runs.push(start..i + 1);
+            start = i + 1;
+        }
+    }
+    if start < keys.len() {
+        runs.push(start..keys.len());
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs() {
+        assert!(plan::<&[u8]>(&[], 1000).is_empty());
+
+        // a traversal of a million keys reads 6 nodes, so keys further apart are traversed to
+        let close = 1 << 40;
+        let apart = 1 << 50;
+        let keys =
+            [0u64, close, 2 * close, apart, 2 * apart, 2 * apart + close].map(u64::to_be_bytes);
+        assert_eq!(plan(&keys, 1_000_000), [0..3, 3..4, 4..6]);
+
+        // in a small trie, the same keys are all close together
+        let all = 0..keys.len();
+        assert_eq!(plan(&keys, 16), [all]);
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The nodes of the subtrees dropped by
+//! [BatchOp::DeletePrefix](crate::v2::api::BatchOp::DeletePrefix), which are freed by the
+//! batches that follow rather than by the one dropping them.
+//!
+//! Dropping a prefix only restructures the path to its subtree, see
+//! [Merkle::drop_prefix](crate::merkle::Merkle::drop_prefix), without reading the subtree,
+//! unless a secondary index needs its keys, nor writing the free space of every node of it.
+//! Each batch then frees up to [NODES_PER_BATCH] nodes of the subtrees dropped by it and the
+//! ones before it, taking the keys of those nodes off the counts of the header.
+//! The roots of the rest are kept in the trie of the secondary indexes, under a key no index
+//! uses, so that they are committed along with the batch, and the batches after a reopen carry
+//! on where it left off.
+
+use super::DbError;
+use crate::shale::disk_address::DiskAddress;
+use std::io::ErrorKind;
+
+/// Nodes of the dropped subtrees each batch frees at most.
+pub(super) const NODES_PER_BATCH: usize = 1024;
+
+/// The key of the roots left to free in the index trie: that of an entry of an index with a
+/// name of 255 bytes, cut short, which no entry has.
+pub(super) const KEY: [u8; 1] = [u8::MAX];
+
+pub(super) fn decode(roots: &[u8]) -> Result<Vec<DiskAddress>, DbError> {
+    roots
+        .chunks(DiskAddress::SERIALIZED_LEN as usize)
+        .map(|root| {
+            DiskAddress::try_from(root).map_err(|_| DbError::IO(ErrorKind::InvalidData.into()))
+        })
+        .collect()
+}
+
+pub(super) fn encode(roots: &[DiskAddress]) -> Vec<u8> {
+    roots.iter().flat_map(DiskAddress::to_le_bytes).collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let roots = [DiskAddress::from(8), DiskAddress::from(4096)];
+        assert_eq!(decode(&encode(&roots)).unwrap(), roots);
+        assert!(decode(&encode(&roots)[1..]).is_err());
+    }
+}
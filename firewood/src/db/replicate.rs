@@ -0,0 +1,139 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Streams a revision of a [Db] into a [BatchSink], see [Db::replicate_to].
+//!
+//! The revision is read in ranges of consecutive keys, and every range is checked against a
+//! proof of the revision before it is handed to the sink, so that a corrupted read is not
+//! replicated. The sink is asked where a previous run stopped, so a replication that failed
+//! half-way resumes after the last range the sink wrote instead of starting over.
+
+use super::{Db, DbError, DbRev};
+use crate::{
+    merkle::{Proof, TrieHash},
+    shale::LinearStore,
+    v2::api::{BatchOp, HashKey},
+};
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use std::error::Error;
+use tokio::task::block_in_place;
+
+/// Where [Db::replicate_to] streams the key-value pairs of a revision: a second DB, in this
+/// process or behind a network connection.
+///
+/// The ranges are written in key order, so a sink that starts empty holds a prefix of the
+/// revision at any time, and the last key it holds is where the replication resumes.
+#[async_trait]
+pub trait BatchSink: Send + Sync {
+    /// The last key written by an earlier replication of the same revision, after which this
+    /// one resumes, or `None` to start from the first key.
+    async fn resume_after(&self) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>>;
+
+    /// Writes a range of key-value pairs, in ascending key order, after every key written before.
+    /// A range is only resumed after once this has returned.
+    async fn write_range(
+        &self,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Called once every range has been written, with the root hash of the revision replicated.
+    async fn finish(&self, root_hash: HashKey) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+/// A [Db] as a sink commits every range as a proposal, and checks its root hash against that of
+/// the revision replicated once every range has been written. It must start empty.
+#[async_trait]
+impl BatchSink for Db {
+    async fn resume_after(&self) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        let latest = self.latest_revision();
+        // an api::Error can't be shared between threads, its message can
+        let last = latest
+            .stream_rev(None)
+            .next()
+            .await
+            .transpose()
+            .map_err(|e| e.to_string())?;
+        Ok(last.map(|(key, _)| key.into_vec()))
+    }
+
+    async fn write_range(
+        &self,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let batch = pairs
+            .into_iter()
+            .map(|(key, value)| BatchOp::Put { key, value })
+            .collect();
+        block_in_place(|| self.new_proposal(batch)?.commit_sync())?;
+        Ok(())
+    }
+
+    async fn finish(&self, root_hash: HashKey) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let computed = self.kv_root_hash()?;
+        if computed.0 != root_hash {
+            return Err(Box::new(DbError::RootMismatch {
+                expected: TrieHash(root_hash),
+                computed,
+                dump: None,
+            }));
+        }
+        Ok(())
+    }
+}
+
+/// Reads the range of at most `len` pairs starting at `start`, or at the first key, and checks
+/// every pair against a proof of the range in `rev` with `root_hash`. An empty range is the end
+/// of the revision.
+///
+/// The proof is the union of the proofs of the keys of the range, which share the nodes they
+/// have in common. It proves that every pair is in the revision, but not that the range has no
+/// gaps: a pair missed is caught when the root hash of the replica is checked, see
+/// [BatchSink::finish].
+pub(super) async fn verified_range<T: LinearStore>(
+    rev: &DbRev<T>,
+    root_hash: HashKey,
+    start: Option<&[u8]>,
+    len: usize,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>, DbError> {
+    let sentinel_addr = rev.header.sentinel_addr;
+    let stream = match start {
+        Some(start) => rev.stream_from(start.into()),
+        None => rev.stream(),
+    };
+    let pairs = stream
+        .take(len)
+        .map(|pair| pair.map(|(key, value)| (key.into_vec(), value)))
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|e| DbError::Replication(e.to_string().into()))?;
+
+    let mut proof = Proof(Default::default());
+    for (key, _) in &pairs {
+        proof.extend(
+            rev.merkle
+                .prove(key, sentinel_addr)
+                .map_err(DbError::Merkle)?,
+        );
+    }
+    for (key, value) in &pairs {
+        let proven = proof
+            .verify(key, root_hash)
+            .map_err(|e| DbError::Replication(Box::new(e)))?;
+        if proven.as_ref() != Some(value) {
+            return Err(DbError::Replication(
+                format!("the value of {key:?} doesn't match its proof").into(),
+            ));
+        }
+    }
+
+    Ok(pairs)
+}
+
+/// The smallest key greater than `key`.
+pub(super) fn successor(key: &[u8]) -> Vec<u8> {
+    let mut successor = Vec::with_capacity(key.len() + 1);
+    successor.extend_from_slice(key);
+    successor.push(0);
+    successor
+}
@@ -0,0 +1,193 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The index of the revisions a [Db](super::Db) retains, see
+//! [Db::revision_index](super::Db::revision_index).
+//!
+//! Every commit writes an entry for the revision it creates to the root hash store, right after
+//! the root hash: when the revision was committed, its counts, and the annotation of its
+//! proposal, followed by the filter of the keys the commit changed, see
+//! [change_filter](super::change_filter). The entry is part of the Wal record of the commit, so
+//! the entries of all the revisions still in the Wal are read back from there. The revisions
+//! committed before entries were written only have their root hash.
+
+use super::{change_filter::ChangeFilter, TrieCounts};
+use crate::{
+    merkle::{TrieHash, TRIE_HASH_LEN},
+    shale::LinearStore,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Annotations are truncated to this many bytes.
+pub const MAX_ANNOTATION_LEN: usize = 1024;
+
+/// The root hash, the commit time in milliseconds, the counts and the length of the annotation.
+const ENTRY_HEADER_LEN: usize = TRIE_HASH_LEN + 3 * size_of::<u64>() + size_of::<u16>();
+/// Set in the length of the annotation of the entries followed by a change filter, which the
+/// entries written before the filters were recorded aren't.
+const HAS_CHANGES: u16 = 0x8000;
+
+/// A revision retained by a [Db](super::Db).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevisionEntry {
+    /// How many commits ago the revision was the latest one, 0 being the latest revision.
+    pub index: usize,
+    pub root_hash: TrieHash,
+    /// When the revision was committed, if recorded.
+    pub committed_at: Option<SystemTime>,
+    /// The annotation of the proposal the revision was committed from, see
+    /// [Proposal::with_annotation](super::Proposal::with_annotation).
+    pub annotation: Option<String>,
+    /// The counts of the revision, if recorded.
+    pub counts: Option<TrieCounts>,
+    /// How many bytes of values the revision added, or removed if negative, to the revision
+    /// before it, if both counts are recorded.
+    pub size_delta: Option<i64>,
+}
+
+/// Truncates an annotation to [MAX_ANNOTATION_LEN], on a character boundary.
+pub(super) fn truncate_annotation(mut annotation: String) -> String {
+    if annotation.len() > MAX_ANNOTATION_LEN {
+        let len = (0..=MAX_ANNOTATION_LEN)
+            .rev()
+            .find(|&len| annotation.is_char_boundary(len))
+            .unwrap_or_default();
+        annotation.truncate(len);
+    }
+    annotation
+}
+
+pub(super) fn encode(
+    root_hash: &TrieHash,
+    committed_at: SystemTime,
+    counts: TrieCounts,
+    annotation: &str,
+    changes: &ChangeFilter,
+) -> Vec<u8> {
+    // a time before the epoch is recorded as 1ms, as 0 means there is no entry
+    let millis = committed_at
+        .duration_since(UNIX_EPOCH)
+        .map_or(1, |since| since.as_millis().max(1) as u64);
+
+    let mut entry = Vec::with_capacity(ENTRY_HEADER_LEN + annotation.len() + changes.encoded_len());
+    entry.extend_from_slice(&root_hash.0);
+    entry.extend_from_slice(&millis.to_le_bytes());
+    entry.extend_from_slice(&counts.keys.to_le_bytes());
+    entry.extend_from_slice(&counts.value_bytes.to_le_bytes());
+    entry.extend_from_slice(&(annotation.len() as u16 | HAS_CHANGES).to_le_bytes());
+    entry.extend_from_slice(annotation.as_bytes());
+    changes.encode_to(&mut entry);
+    entry
+}
+
+/// Reads the entry of the revision the root hash store is at, which only has a root hash if the
+/// commit didn't record an entry.
+pub(super) fn decode<S: LinearStore + ?Sized>(store: &S, index: usize) -> Option<RevisionEntry> {
+    let header = store.get_view(0, ENTRY_HEADER_LEN as u64)?.as_deref();
+    let (root_hash, rest) = header.split_first_chunk::<TRIE_HASH_LEN>()?;
+    let (millis, rest) = rest.split_first_chunk::<8>()?;
+    let (keys, rest) = rest.split_first_chunk::<8>()?;
+    let (value_bytes, rest) = rest.split_first_chunk::<8>()?;
+    let (annotation_len, _) = rest.split_first_chunk::<2>()?;
+
+    let mut entry = RevisionEntry {
+        index,
+        root_hash: TrieHash(*root_hash),
+        committed_at: None,
+        annotation: None,
+        counts: None,
+        size_delta: None,
+    };
+    let millis = u64::from_le_bytes(*millis);
+    if millis == 0 {
+        return Some(entry);
+    }
+
+    entry.committed_at = Some(UNIX_EPOCH + Duration::from_millis(millis));
+    entry.counts = Some(TrieCounts {
+        keys: u64::from_le_bytes(*keys),
+        value_bytes: u64::from_le_bytes(*value_bytes),
+    });
+    let annotation_len = (u16::from_le_bytes(*annotation_len) & !HAS_CHANGES) as u64;
+    if annotation_len > 0 {
+        let annotation = store.get_view(ENTRY_HEADER_LEN, annotation_len)?.as_deref();
+        entry.annotation = Some(String::from_utf8_lossy(&annotation).into_owned());
+    }
+    Some(entry)
+}
+
+/// Reads the filter of the keys changed by the commit of the revision the root hash store is
+/// at, if it recorded one.
+pub(super) fn decode_changes<S: LinearStore + ?Sized>(store: &S) -> Option<ChangeFilter> {
+    let header = store.get_view(0, ENTRY_HEADER_LEN as u64)?.as_deref();
+    let (millis, rest) = header.get(TRIE_HASH_LEN..)?.split_first_chunk::<8>()?;
+    let (_, annotation_len) = rest.split_last_chunk::<2>()?;
+    let annotation_len = u16::from_le_bytes(*annotation_len);
+    if u64::from_le_bytes(*millis) == 0 || annotation_len & HAS_CHANGES == 0 {
+        return None;
+    }
+    let start = ENTRY_HEADER_LEN + (annotation_len & !HAS_CHANGES) as usize;
+    ChangeFilter::decode(|offset, len| Some(store.get_view(start + offset, len as u64)?.as_deref()))
+}
+
+/// Fills in the size deltas of entries ordered from the latest revision.
+pub(super) fn set_size_deltas(entries: &mut [RevisionEntry]) {
+    let mut older = None;
+    for entry in entries.iter_mut().rev() {
+        entry.size_delta =
+            entry
+                .counts
+                .zip(older)
+                .map(|(counts, older): (TrieCounts, TrieCounts)| {
+                    counts.value_bytes as i64 - older.value_bytes as i64
+                });
+        older = entry.counts;
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{db::change_filter::ChangedKeys, shale::in_mem::InMemLinearStore};
+
+    #[test]
+    fn entry() {
+        let root_hash = TrieHash([7; TRIE_HASH_LEN]);
+        let committed_at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        let counts = TrieCounts {
+            keys: 3,
+            value_bytes: 12,
+        };
+
+        let mut changed = ChangedKeys::default();
+        changed.insert(b"key");
+        let changes = changed.to_filter();
+
+        let mut store = InMemLinearStore::new(0x1000, 0);
+        store
+            .write(
+                0,
+                &encode(&root_hash, committed_at, counts, "nightly", &changes),
+            )
+            .unwrap();
+        let entry = decode(&store, 2).unwrap();
+        assert_eq!(entry.index, 2);
+        assert_eq!(entry.root_hash, root_hash);
+        assert_eq!(entry.committed_at, Some(committed_at));
+        assert_eq!(entry.counts, Some(counts));
+        assert_eq!(entry.annotation.as_deref(), Some("nightly"));
+        assert_eq!(decode_changes(&store), Some(changes));
+
+        // a commit that didn't record an entry only wrote its root hash
+        let mut store = InMemLinearStore::new(0x1000, 0);
+        store.write(0, &root_hash.0).unwrap();
+        let entry = decode(&store, 0).unwrap();
+        assert_eq!((entry.committed_at, entry.counts), (None, None));
+        assert_eq!(decode_changes(&store), None);
+
+        // the annotation is truncated before the character that crosses the limit
+        let annotation = truncate_annotation(format!("a{}", "é".repeat(MAX_ANNOTATION_LEN)));
+        assert_eq!(annotation.len(), MAX_ANNOTATION_LEN - 1);
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Secondary indexes over the values of a [Db](super::Db), for reverse lookups.
+//!
+//! An index maps the index key its [IndexKeyExtractor] extracts from a value to the keys holding
+//! such a value. The entries of every index are kept in a trie of their own, next to the
+//! key-value trie, and are updated with each batch as part of the same proposal, so that the
+//! indexes of a revision always match its values. The root hash only covers the key-value trie.
+//!
+//! The extractors are code, so they are not persisted: the indexes of a DB have to be registered
+//! again every time it is opened, before anything is written. Values written while an index is
+//! not registered are missing from it.
+
+use super::DbError;
+use parking_lot::{RwLock, RwLockReadGuard};
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    sync::Arc,
+};
+
+/// Extracts the index key of a value, or `None` if the value isn't indexed, see
+/// [Db::register_index](super::Db::register_index).
+pub type IndexKeyExtractor = Box<dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+pub(super) struct SecondaryIndex {
+    name: String,
+    extract: IndexKeyExtractor,
+}
+
+/// The secondary indexes registered on a [Db](super::Db), shared with all of its proposals.
+#[derive(Clone, Default)]
+pub(super) struct SecondaryIndexes(Arc<RwLock<Vec<SecondaryIndex>>>);
+
+impl fmt::Debug for SecondaryIndexes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let indexes = self.0.read();
+        f.debug_list()
+            .entries(indexes.iter().map(|index| &index.name))
+            .finish()
+    }
+}
+
+impl SecondaryIndexes {
+    /// Registers an index, failing with [DbError::InvalidParams] if there already is one with the
+    /// same name, or if the name is empty or longer than 255 bytes. The empty name is left to
+    /// the [sequences](super::sequence).
+    pub(super) fn register(&self, name: String, extract: IndexKeyExtractor) -> Result<(), DbError> {
+        let mut indexes = self.0.write();
+        if name.is_empty()
+            || name.len() > u8::MAX as usize
+            || indexes.iter().any(|index| index.name == name)
+        {
+            return Err(DbError::InvalidParams);
+        }
+        indexes.push(SecondaryIndex { name, extract });
+        Ok(())
+    }
+
+    pub(super) fn contains(&self, name: &str) -> bool {
+        self.0.read().iter().any(|index| index.name == name)
+    }
+
+    /// Locks the indexes for the duration of a batch, so that none is registered half way.
+    pub(super) fn read(&self) -> RwLockReadGuard<'_, Vec<SecondaryIndex>> {
+        self.0.read()
+    }
+}
+
+/// A change to the entries of an index, for a key whose value changed from `old` to `new`.
+pub(super) struct IndexUpdate {
+    /// The entry the key is removed from.
+    pub(super) remove: Option<Vec<u8>>,
+    /// The entry the key is added to.
+    pub(super) add: Option<Vec<u8>>,
+}
+
+impl SecondaryIndex {
+    /// The entries of the index that change when a value changes from `old` to `new`, `None` if
+    /// the index key stays the same.
+    pub(super) fn update(&self, old: Option<&[u8]>, new: Option<&[u8]>) -> Option<IndexUpdate> {
+        let old = old.and_then(|value| (self.extract)(value));
+        let new = new.and_then(|value| (self.extract)(value));
+        (old != new).then(|| IndexUpdate {
+            remove: old.map(|index_key| entry_key(&self.name, &index_key)),
+            add: new.map(|index_key| entry_key(&self.name, &index_key)),
+        })
+    }
+}
+
+/// The key of the entry for `index_key` in the index trie: the length of the name of the index,
+/// the name, then the index key. Each entry holds the sorted keys with that index key.
+pub(super) fn entry_key(name: &str, index_key: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(1 + name.len() + index_key.len());
+    key.push(name.len() as u8);
+    key.extend_from_slice(name.as_bytes());
+    key.extend_from_slice(index_key);
+    key
+}
+
+pub(super) fn decode_entry(entry: &[u8]) -> Result<Vec<Vec<u8>>, DbError> {
+    bincode::deserialize(entry).map_err(|e| DbError::IO(io::Error::new(ErrorKind::InvalidData, e)))
+}
+
+pub(super) fn encode_entry(keys: &[Vec<u8>]) -> Vec<u8> {
+    #[allow(clippy::unwrap_used)]
+    bincode::serialize(keys).unwrap()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update() {
+        let index = SecondaryIndex {
+            name: "owner".to_string(),
+            extract: Box::new(|value| value.split(|b| *b == b':').next().map(<[u8]>::to_vec)),
+        };
+        let entry = |owner: &[u8]| entry_key("owner", owner);
+
+        let update = index.update(None, Some(b"alice:1")).unwrap();
+        assert_eq!((update.remove, update.add), (None, Some(entry(b"alice"))));
+
+        // same owner, the entry doesn't change
+        assert!(index.update(Some(b"alice:1"), Some(b"alice:2")).is_none());
+
+        let update = index.update(Some(b"alice:2"), Some(b"bob:2")).unwrap();
+        assert_eq!(
+            (update.remove, update.add),
+            (Some(entry(b"alice")), Some(entry(b"bob")))
+        );
+
+        let update = index.update(Some(b"bob:2"), None).unwrap();
+        assert_eq!((update.remove, update.add), (Some(entry(b"bob")), None));
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Named sequences of numbers handed out by [Db::next_sequence](super::Db::next_sequence).
+//!
+//! The last number handed out of each sequence is kept in memory, and every proposal writes the
+//! ones that changed to the trie of the secondary indexes, under keys no index uses, so that
+//! they are committed along with its batch. The last numbers committed are read back the first
+//! time a sequence is used after the DB is opened. A number is durable once a proposal created
+//! after it was handed out is committed: once the DB is reopened, the numbers handed out since
+//! are handed out again, just as the batches they were handed out for were never committed.
+
+use super::{secondary_index, DbError};
+use parking_lot::Mutex;
+use std::{collections::HashMap, sync::Arc};
+
+/// The sequences of a [Db](super::Db), shared with all of its proposals.
+#[derive(Clone, Debug, Default)]
+pub(super) struct Sequences(Arc<Mutex<HashMap<String, u64>>>);
+
+impl Sequences {
+    /// Hands out the next number of the sequence `name`. The first time, the sequence resumes
+    /// from the last number `committed` returns.
+    pub(super) fn next(
+        &self,
+        name: &str,
+        committed: impl FnOnce() -> Result<u64, DbError>,
+    ) -> Result<u64, DbError> {
+        let mut sequences = self.0.lock();
+        let last = match sequences.get_mut(name) {
+            Some(last) => last,
+            None => sequences.entry(name.to_string()).or_insert(committed()?),
+        };
+        *last = last.checked_add(1).ok_or(DbError::InvalidParams)?;
+        Ok(*last)
+    }
+
+    /// The last number handed out of every sequence used since the DB was opened.
+    pub(super) fn last_numbers(&self) -> Vec<(String, u64)> {
+        let sequences = self.0.lock();
+        sequences
+            .iter()
+            .map(|(name, last)| (name.clone(), *last))
+            .collect()
+    }
+}
+
+/// The key of the last number of the sequence `name` in the index trie: that of an entry of an
+/// index with an empty name, which can't be registered.
+pub(super) fn key(name: &str) -> Vec<u8> {
+    secondary_index::entry_key("", name.as_bytes())
+}
+
+pub(super) fn decode(last: &[u8]) -> Result<u64, DbError> {
+    last.try_into()
+        .map(u64::from_le_bytes)
+        .map_err(|_| DbError::IO(std::io::ErrorKind::InvalidData.into()))
+}
+
+pub(super) const fn encode(last: u64) -> [u8; 8] {
+    last.to_le_bytes()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next() {
+        let sequences = Sequences::default();
+        assert_eq!(sequences.next("orders", || Ok(41)).unwrap(), 42);
+        // the committed number is only read the first time
+        assert_eq!(sequences.next("orders", || unreachable!()).unwrap(), 43);
+        assert_eq!(sequences.next("invoices", || Ok(0)).unwrap(), 1);
+
+        let mut last_numbers = sequences.last_numbers();
+        last_numbers.sort();
+        assert_eq!(
+            last_numbers,
+            [("invoices".to_string(), 1), ("orders".to_string(), 43)]
+        );
+
+        assert!(matches!(
+            sequences.next("full", || Ok(u64::MAX)),
+            Err(DbError::InvalidParams)
+        ));
+        assert_eq!(decode(&encode(43)).unwrap(), 43);
+        assert!(decode(b"short").is_err());
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The marker [Db::close](super::Db::close) leaves behind once everything it drained is on disk.
+//!
+//! The marker records the root hash of the latest revision and the name, length and
+//! modification time of every Wal file. When the DB is opened again, the marker is current if
+//! the root hash store still starts with that root hash and the Wal files are unchanged, in
+//! which case the store files already hold every record in the Wal and replaying it is skipped.
+//! Anything else, such as a marker written before the DB was restored from a backup, falls back
+//! to the full recovery.
+
+use crate::{
+    file,
+    merkle::{TrieHash, TRIE_HASH_LEN},
+};
+use std::{
+    fs,
+    io::{self, ErrorKind, Write},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+/// Name of the clean-shutdown marker in the DB directory.
+const CLEAN_SHUTDOWN_FILE: &str = "clean_shutdown";
+
+const ROOT_HASH_DIR: &str = "root_hash";
+const WAL_DIR: &str = "wal";
+
+/// What the marker of a DB records.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct Marker {
+    root_hash: TrieHash,
+    wal_files: Vec<u8>,
+}
+
+impl Marker {
+    /// Whether the DB in `db_path` is still the one the marker was written for.
+    pub(super) fn is_current(&self, db_path: &Path) -> io::Result<bool> {
+        let root_hash_file = file::File::new(0, 0, db_path.join(ROOT_HASH_DIR))?;
+        let mut root_hash = [0; TRIE_HASH_LEN];
+        let read = nix::sys::uio::pread(&*root_hash_file, &mut root_hash, 0)?;
+        Ok(read == TRIE_HASH_LEN
+            && root_hash == self.root_hash.0
+            && wal_files(db_path)? == self.wal_files)
+    }
+}
+
+/// The name, length and modification time of the Wal files, by name.
+fn wal_files(db_path: &Path) -> io::Result<Vec<u8>> {
+    let mut entries = fs::read_dir(db_path.join(WAL_DIR))?
+        .map(|entry| {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let modified = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64;
+            Ok((entry.file_name(), metadata.len(), modified))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort_unstable();
+
+    let mut bytes = Vec::new();
+    for (name, len, modified) in entries {
+        let name = name.as_encoded_bytes();
+        bytes.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(name);
+        bytes.extend_from_slice(&len.to_le_bytes());
+        bytes.extend_from_slice(&modified.to_le_bytes());
+    }
+    Ok(bytes)
+}
+
+/// Writes the marker of the DB in `db_path`, closed at `root_hash`. Like the cache manifest, it
+/// is written to a temporary file which is then renamed, and both the file and the rename are
+/// synced, so the marker never claims more than what is on disk.
+pub(super) fn save(db_path: &Path, root_hash: &TrieHash) -> io::Result<()> {
+    let path = db_path.join(CLEAN_SHUTDOWN_FILE);
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(&root_hash.0)?;
+    file.write_all(&wal_files(db_path)?)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    fs::File::open(db_path)?.sync_all()
+}
+
+/// Reads and removes the marker of the DB in `db_path`, so that a crash after the DB is opened
+/// again is never taken for a clean shutdown. Returns `None` if there is no marker.
+pub(super) fn take(db_path: &Path) -> io::Result<Option<Marker>> {
+    let marker = read(db_path);
+    // a malformed marker is removed as well
+    if !matches!(marker, Ok(None)) {
+        fs::remove_file(db_path.join(CLEAN_SHUTDOWN_FILE))?;
+    }
+    marker
+}
+
+/// Reads the marker of the DB in `db_path`, leaving it in place, for a handle that doesn't
+/// write the DB. Returns `None` if there is no marker.
+pub(super) fn read(db_path: &Path) -> io::Result<Option<Marker>> {
+    let bytes = match fs::read(db_path.join(CLEAN_SHUTDOWN_FILE)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let (root_hash, wal_files) = bytes
+        .split_first_chunk::<TRIE_HASH_LEN>()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed clean-shutdown marker"))?;
+    Ok(Some(Marker {
+        root_hash: TrieHash(*root_hash),
+        wal_files: wal_files.to_vec(),
+    }))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_marker() {
+        let db_path = std::env::temp_dir().join("firewood_test_stale_marker");
+        let _ = fs::remove_dir_all(&db_path);
+        fs::create_dir_all(db_path.join(WAL_DIR)).unwrap();
+        fs::create_dir_all(db_path.join(ROOT_HASH_DIR)).unwrap();
+        let root_hash = TrieHash([7; TRIE_HASH_LEN]);
+        let root_hash_file = file::File::new(0, 0, db_path.join(ROOT_HASH_DIR)).unwrap();
+        nix::sys::uio::pwrite(&*root_hash_file, &root_hash.0, 0).unwrap();
+        fs::write(db_path.join(WAL_DIR).join("00000000.log"), b"records").unwrap();
+
+        save(&db_path, &root_hash).unwrap();
+        assert!(read(&db_path).unwrap().is_some());
+        let marker = take(&db_path).unwrap().unwrap();
+        assert!(marker.is_current(&db_path).unwrap());
+        assert_eq!(take(&db_path).unwrap(), None);
+
+        // records appended to the Wal since
+        fs::write(db_path.join(WAL_DIR).join("00000000.log"), b"more records").unwrap();
+        assert!(!marker.is_current(&db_path).unwrap());
+
+        // another revision at the start of the root hash store
+        save(&db_path, &root_hash).unwrap();
+        let marker = take(&db_path).unwrap().unwrap();
+        nix::sys::uio::pwrite(&*root_hash_file, &[0; TRIE_HASH_LEN], 0).unwrap();
+        assert!(!marker.is_current(&db_path).unwrap());
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! A snapshot of the counters of a DB, see [Db::stats_snapshot](super::Db::stats_snapshot).
+//!
+//! A [StatsSnapshot] holds the counters of the caches, the reads of the stores, the space of
+//! the allocator and the writes to the Wal at the time it was taken. It is serializable, so that
+//! benchmark harnesses and dashboards can record it, and [StatsSnapshot::diff] subtracts an
+//! earlier snapshot from it, giving what a phase of a workload did. Most fields count events
+//! since the DB was opened; the sizes, such as the bytes held by a cache, are their values at
+//! the time of the snapshot, and their difference is how much they grew or shrank.
+
+use serde::{Deserialize, Serialize};
+
+/// Declares a section of a [StatsSnapshot], whose fields are of type `N`, with the difference
+/// of two of them.
+macro_rules! section {
+    ($(#[$doc:meta])* $name:ident { $($(#[$field_doc:meta])* $field:ident,)* }) => {
+        $(#[$doc])*
+        #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+        pub struct $name<N = u64> {
+            $($(#[$field_doc])* pub $field: N,)*
+        }
+
+        impl $name {
+            /// The change of every field since `before`.
+            pub const fn diff(&self, before: &Self) -> $name<i64> {
+                $name {
+                    $($field: self.$field.wrapping_sub(before.$field) as i64,)*
+                }
+            }
+        }
+    };
+}
+
+section! {
+    /// The caches of a DB.
+    CacheStats {
+        /// Maximum estimated bytes of the trie nodes cached by each revision, or 0 if they aren't
+        /// limited, see [Db::cache_max_bytes](super::Db::cache_max_bytes).
+        obj_cache_max_bytes,
+        /// Bytes of the trie nodes cached by the revisions and proposals.
+        obj_cache_bytes,
+        /// Bytes of the pages cached by the stores.
+        page_cache_bytes,
+        /// Pages found in the page caches of the stores.
+        page_cache_hits,
+        /// Lookups of missing keys answered by the negative cache.
+        negative_cache_hits,
+        /// Lookups of missing keys that descended the trie.
+        negative_cache_misses,
+        /// Bytes of the buffers of the dropped nodes kept by the threads of the process to
+        /// read the next nodes into.
+        node_pool_bytes,
+    }
+}
+
+section! {
+    /// The reads of the stores of a DB that missed their page caches.
+    IoStats {
+        /// Pages found in the disk buffer, not written to the store files yet.
+        buffer_hits,
+        /// Pages read from the store files.
+        file_reads,
+    }
+}
+
+section! {
+    /// The space of the payload store of the latest revision.
+    AllocatorStats {
+        /// Bytes up to the end of the chunks of the store, free chunks included.
+        payload_bytes,
+        /// Number of free chunks, zero unless
+        /// [DbConfig::free_list_index](super::DbConfig::free_list_index) is set.
+        free_chunks,
+        /// Bytes of the free chunks, zero unless
+        /// [DbConfig::free_list_index](super::DbConfig::free_list_index) is set.
+        free_bytes,
+    }
+}
+
+section! {
+    /// The writes of the commits of a DB to the Wal and the store files.
+    WalStats {
+        /// Wal records written, one per commit.
+        records,
+        /// Bytes of the writes of the records.
+        record_bytes,
+        /// Store pages written.
+        pages_written,
+        /// Bytes of the records and pages waiting to be written.
+        buffered_bytes,
+    }
+}
+
+/// The counters of a DB at some point, see the [module documentation](self). The difference of
+/// two snapshots is a [StatsDelta].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsSnapshot<N = u64> {
+    pub cache: CacheStats<N>,
+    pub io: IoStats<N>,
+    pub allocator: AllocatorStats<N>,
+    pub wal: WalStats<N>,
+}
+
+/// What happened between two [StatsSnapshot]s, see [StatsSnapshot::diff].
+pub type StatsDelta = StatsSnapshot<i64>;
+
+impl StatsSnapshot {
+    /// The change of every counter since the `before` snapshot was taken.
+    pub const fn diff(&self, before: &Self) -> StatsDelta {
+        StatsSnapshot {
+            cache: self.cache.diff(&before.cache),
+            io: self.io.diff(&before.io),
+            allocator: self.allocator.diff(&before.allocator),
+            wal: self.wal.diff(&before.wal),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff() {
+        let before = StatsSnapshot {
+            io: IoStats {
+                buffer_hits: 1,
+                file_reads: 10,
+            },
+            allocator: AllocatorStats {
+                payload_bytes: 4096,
+                free_chunks: 3,
+                free_bytes: 300,
+            },
+            ..Default::default()
+        };
+        let mut after = before;
+        after.io.file_reads = 15;
+        after.allocator.free_chunks = 1;
+        after.allocator.free_bytes = 100;
+
+        let delta = after.diff(&before);
+        assert_eq!(
+            delta.io,
+            IoStats {
+                buffer_hits: 0,
+                file_reads: 5,
+            }
+        );
+        assert_eq!(
+            delta.allocator,
+            AllocatorStats {
+                payload_bytes: 0,
+                free_chunks: -2,
+                free_bytes: -200,
+            }
+        );
+        assert_eq!(delta.cache, CacheStats::default());
+
+        let bytes = bincode::serialize(&after).unwrap();
+        assert_eq!(
+            bincode::deserialize::<StatsSnapshot>(&bytes).unwrap(),
+            after
+        );
+    }
+}
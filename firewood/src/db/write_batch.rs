@@ -0,0 +1,294 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Stages the operations of a batch in a [WriteBatch], which is serialized into a compact,
+//! portable encoding, so that a batch can be built on a machine, or kept in a queue, and
+//! committed on another one holding the DB.
+//!
+//! The encoding starts with [MAGIC] and the version of the encoding, then the number of
+//! operations, then the operations: a tag followed by their keys and values, each prefixed with
+//! its length. Numbers are little-endian `u64`s, whatever the machine. The operations are
+//! encoded the same way in the journal of a [MultiCommit](super::MultiCommit).
+
+use super::{Db, DbError};
+use crate::v2::api::{Batch, BatchOp, KeyType, ValueType};
+use std::io::{self, ErrorKind};
+
+pub(super) type OwnedBatch = Batch<Vec<u8>, Vec<u8>>;
+
+/// The first bytes of a serialized [WriteBatch].
+const MAGIC: &[u8; 4] = b"fwwb";
+/// The version of the encoding of a serialized [WriteBatch], bumped on incompatible changes.
+const VERSION: u8 = 1;
+
+const PUT: u8 = 0;
+const DELETE: u8 = 1;
+const MOVE: u8 = 2;
+const MOVE_OVERWRITE: u8 = 3;
+const DELETE_PREFIX: u8 = 4;
+
+/// The operations of a batch staged to be proposed on a [Db], see the
+/// [module documentation](self).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteBatch {
+    ops: OwnedBatch,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: impl KeyType, value: impl ValueType) -> &mut Self {
+        self.ops.push(BatchOp::Put {
+            key: key.as_ref().to_vec(),
+            value: value.as_ref().to_vec(),
+        });
+        self
+    }
+
+    pub fn delete(&mut self, key: impl KeyType) -> &mut Self {
+        self.ops.push(BatchOp::Delete {
+            key: key.as_ref().to_vec(),
+        });
+        self
+    }
+
+    /// Stage a [BatchOp::Move] of the value of `key` to `new_key`.
+    pub fn move_key(
+        &mut self,
+        key: impl KeyType,
+        new_key: impl KeyType,
+        overwrite: bool,
+    ) -> &mut Self {
+        self.ops.push(BatchOp::Move {
+            key: key.as_ref().to_vec(),
+            new_key: new_key.as_ref().to_vec(),
+            overwrite,
+        });
+        self
+    }
+
+    pub fn delete_prefix(&mut self, prefix: impl KeyType) -> &mut Self {
+        self.ops.push(BatchOp::DeletePrefix {
+            prefix: prefix.as_ref().to_vec(),
+        });
+        self
+    }
+
+    pub const fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[BatchOp<Vec<u8>, Vec<u8>>] {
+        &self.ops
+    }
+
+    /// The staged operations, to be proposed with [Db::propose](crate::v2::api::Db::propose).
+    pub fn into_batch(self) -> OwnedBatch {
+        self.ops
+    }
+
+    /// Proposes the staged operations on `db` and commits them. Like any commit, see
+    /// [Proposal::commit_sync](super::Proposal::commit_sync), the changes are appended to the
+    /// Wal of `db` as one record before the store files are written, so that a commit cut short
+    /// by a crash is either replayed whole from the Wal when the DB is opened again, or lost
+    /// whole if its record wasn't written.
+    pub fn commit(self, db: &Db) -> Result<(), DbError> {
+        db.new_proposal(self.ops)?.commit_sync()
+    }
+
+    /// Encode the staged operations, see the [module documentation](self).
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 1 + 8);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        encode_batch(&mut bytes, &self.ops);
+        bytes
+    }
+
+    /// Decode a batch [WriteBatch::serialize]d, possibly on another machine, to be proposed on
+    /// `db`. The operations are checked by the validators registered on `db` right away, see
+    /// [Db::register_batch_validator], so that a batch the DB would reject fails where it is
+    /// received rather than when it is proposed.
+    ///
+    /// Fails with an [ErrorKind::InvalidData] error if `bytes` aren't a serialized batch, or
+    /// were serialized with another version of the encoding, and with
+    /// [DbError::BatchRejected] if a validator rejects the batch.
+    pub fn deserialize(db: &Db, bytes: &[u8]) -> Result<Self, DbError> {
+        let mut reader = Reader(bytes);
+        if reader.take(MAGIC.len())? != MAGIC || reader.take(1)? != [VERSION] {
+            return Err(malformed());
+        }
+        let ops = decode_batch(&mut reader)?;
+        if !reader.0.is_empty() {
+            return Err(malformed());
+        }
+        db.batch_validators.validate(&ops)?;
+        Ok(Self { ops })
+    }
+}
+
+impl<K: KeyType, V: ValueType> From<Batch<K, V>> for WriteBatch {
+    fn from(batch: Batch<K, V>) -> Self {
+        Self {
+            ops: to_owned(batch),
+        }
+    }
+}
+
+pub(super) fn to_owned<K: KeyType, V: ValueType>(batch: Batch<K, V>) -> OwnedBatch {
+    batch
+        .into_iter()
+        .map(|op| match op {
+            BatchOp::Put { key, value } => BatchOp::Put {
+                key: key.as_ref().to_vec(),
+                value: value.as_ref().to_vec(),
+            },
+            BatchOp::Delete { key } => BatchOp::Delete {
+                key: key.as_ref().to_vec(),
+            },
+            BatchOp::Move {
+                key,
+                new_key,
+                overwrite,
+            } => BatchOp::Move {
+                key: key.as_ref().to_vec(),
+                new_key: new_key.as_ref().to_vec(),
+                overwrite,
+            },
+            BatchOp::DeletePrefix { prefix } => BatchOp::DeletePrefix {
+                prefix: prefix.as_ref().to_vec(),
+            },
+        })
+        .collect()
+}
+
+/// Appends the number of operations of `batch` to `bytes`, then the operations: a tag followed
+/// by their keys and values, each prefixed with its length.
+pub(super) fn encode_batch(bytes: &mut Vec<u8>, batch: &OwnedBatch) {
+    bytes.extend_from_slice(&(batch.len() as u64).to_le_bytes());
+    for op in batch {
+        let (tag, fields): (_, &[&Vec<u8>]) = match op {
+            BatchOp::Put { key, value } => (PUT, &[key, value]),
+            BatchOp::Delete { key } => (DELETE, &[key]),
+            BatchOp::Move {
+                key,
+                new_key,
+                overwrite,
+            } => (
+                if *overwrite { MOVE_OVERWRITE } else { MOVE },
+                &[key, new_key],
+            ),
+            BatchOp::DeletePrefix { prefix } => (DELETE_PREFIX, &[prefix]),
+        };
+        bytes.push(tag);
+        for field in fields {
+            bytes.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(field);
+        }
+    }
+}
+
+/// Decodes a batch [encode_batch] encoded.
+pub(super) fn decode_batch(reader: &mut Reader) -> Result<OwnedBatch, DbError> {
+    let len = reader.u64()?;
+    let mut batch = Vec::new();
+    for _ in 0..len {
+        let tag = reader.take(1)?;
+        batch.push(match tag {
+            [PUT] => BatchOp::Put {
+                key: reader.field()?,
+                value: reader.field()?,
+            },
+            [DELETE] => BatchOp::Delete {
+                key: reader.field()?,
+            },
+            [tag @ (MOVE | MOVE_OVERWRITE)] => BatchOp::Move {
+                key: reader.field()?,
+                new_key: reader.field()?,
+                overwrite: *tag == MOVE_OVERWRITE,
+            },
+            [DELETE_PREFIX] => BatchOp::DeletePrefix {
+                prefix: reader.field()?,
+            },
+            _ => return Err(malformed()),
+        });
+    }
+    Ok(batch)
+}
+
+pub(super) fn malformed() -> DbError {
+    DbError::IO(io::Error::new(
+        ErrorKind::InvalidData,
+        "malformed batch encoding",
+    ))
+}
+
+/// The part of an encoding not decoded yet.
+pub(super) struct Reader<'a>(pub(super) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(super) fn take(&mut self, len: usize) -> Result<&'a [u8], DbError> {
+        if self.0.len() < len {
+            return Err(malformed());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    pub(super) fn u64(&mut self) -> Result<u64, DbError> {
+        #[allow(clippy::unwrap_used)]
+        self.take(8)
+            .map(|n| u64::from_le_bytes(n.try_into().unwrap()))
+    }
+
+    fn field(&mut self) -> Result<Vec<u8>, DbError> {
+        let len = usize::try_from(self.u64()?).map_err(|_| malformed())?;
+        self.take(len).map(<[u8]>::to_vec)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod tests {
+    use super::*;
+
+    fn batch() -> WriteBatch {
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"k", b"v")
+            .delete(b"d")
+            .move_key(b"from", b"to", true)
+            .move_key(b"a", b"b", false)
+            .delete_prefix(b"");
+        batch
+    }
+
+    #[test]
+    fn round_trip() {
+        let batch = batch();
+        let mut reader = Reader(&batch.serialize()[MAGIC.len() + 1..]);
+        assert_eq!(decode_batch(&mut reader).unwrap(), batch.ops);
+        assert!(reader.0.is_empty());
+    }
+
+    #[test]
+    fn malformed_encoding() {
+        let bytes = batch().serialize();
+        for len in 0..bytes.len() {
+            let mut reader = Reader(&bytes[MAGIC.len() + 1..len.max(MAGIC.len() + 1)]);
+            assert!(decode_batch(&mut reader).is_err());
+        }
+
+        let mut bytes = bytes;
+        bytes[MAGIC.len() + 1 + 8] = 6;
+        let mut reader = Reader(&bytes[MAGIC.len() + 1..]);
+        assert!(decode_batch(&mut reader).is_err());
+    }
+}
@@ -78,6 +78,13 @@ impl File {
         };
         Ok(File { fd })
     }
+
+    /// Allocates the first `len` bytes of the file on disk, growing it if it's shorter, so that
+    /// writing them can't run out of space.
+    pub fn preallocate(&self, len: u64) -> Result<(), std::io::Error> {
+        nix::fcntl::posix_fallocate(self.fd.as_raw_fd(), 0, len as nix::libc::off_t)
+            .map_err(std::io::Error::from)
+    }
 }
 
 impl Deref for File {
@@ -116,3 +123,41 @@ pub(crate) fn open_dir<P: AsRef<Path>>(
         Ok(_) => Ok((path.as_ref().to_path_buf(), true)),
     }
 }
+
+/// Removes everything in the directory at `path`, except for the entries named in `keep`.
+pub(crate) fn clear_dir(path: &Path, keep: &[&str]) -> Result<(), std::io::Error> {
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if keep.iter().any(|name| entry.file_name() == *name) {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            remove_dir_all(entry.path())?;
+        } else {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The bytes available to unprivileged users on the file system of `path`.
+pub(crate) fn available_space(path: &Path) -> Result<u64, std::io::Error> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    Ok(stat.blocks_available() * stat.fragment_size())
+}
+
+/// Flushes every file under the directory at `path`, and the directories themselves, to disk.
+pub(crate) fn sync_dir(path: &Path) -> Result<(), std::io::Error> {
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            sync_dir(&entry.path())?;
+        } else {
+            std::fs::File::open(entry.path())?.sync_all()?;
+        }
+    }
+
+    std::fs::File::open(path)?.sync_all()
+}
@@ -0,0 +1,256 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Order-preserving encodings of integers and composite keys.
+//!
+//! The trie orders its keys byte by byte, so numbers written in their native little-endian form
+//! don't come out of a range scan in numeric order. The encodings here do: the bytes of two
+//! encoded keys compare as the keys themselves do.
+//!
+//! - Unsigned integers are written big-endian, on their full width.
+//! - Signed integers are written big-endian with the sign bit flipped, so that the negative
+//!   ones come before the positive ones.
+//! - Byte strings are prefixed with their length, as a big-endian `u32`, so that a composite
+//!   key can be split back into its parts. This orders them by length first, then by their
+//!   bytes.
+//! - Tuples are the concatenation of their parts, ordered by their first part, then by the next.
+//!
+//! ```
+//! use firewood::keys::{self, U256};
+//!
+//! let key = keys::encode(&(7u64, -1i64, b"account".to_vec()));
+//! assert!(keys::encode(&(7u64, -2i64, b"account".to_vec())) < key);
+//! assert_eq!(keys::decode::<(u64, i64, Vec<u8>)>(&key).unwrap().1, -1);
+//!
+//! assert!(keys::encode(&U256::from(255u64)) < keys::encode(&U256::from(256u64)));
+//! ```
+
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum KeyError {
+    /// The key ends before the part being decoded does.
+    #[error("key truncated: {needed} more bytes needed, {remaining} left")]
+    Truncated { needed: usize, remaining: usize },
+    /// The key goes on after its last part.
+    #[error("key has {0} trailing bytes")]
+    TrailingBytes(usize),
+}
+
+/// A part of a key with an order-preserving encoding, see the [module documentation](self).
+pub trait KeyPart: Sized {
+    /// Appends the encoding of this part to `out`.
+    fn encode_to(&self, out: &mut Vec<u8>);
+
+    /// Decodes a part from the start of `bytes`, returning it with the bytes after it.
+    fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), KeyError>;
+}
+
+/// Encodes `key`, see the [module documentation](self).
+pub fn encode<K: KeyPart>(key: &K) -> Vec<u8> {
+    let mut out = Vec::new();
+    key.encode_to(&mut out);
+    out
+}
+
+/// Decodes a key encoded by [encode], failing if `bytes` go on after it.
+pub fn decode<K: KeyPart>(bytes: &[u8]) -> Result<K, KeyError> {
+    match K::decode_from(bytes)? {
+        (key, []) => Ok(key),
+        (_, rest) => Err(KeyError::TrailingBytes(rest.len())),
+    }
+}
+
+/// Splits the first `N` bytes off `bytes`.
+fn split<const N: usize>(bytes: &[u8]) -> Result<([u8; N], &[u8]), KeyError> {
+    match bytes.split_first_chunk::<N>() {
+        Some((head, rest)) => Ok((*head, rest)),
+        None => Err(KeyError::Truncated {
+            needed: N - bytes.len(),
+            remaining: bytes.len(),
+        }),
+    }
+}
+
+impl KeyPart for u64 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_be_bytes());
+    }
+
+    fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), KeyError> {
+        let (head, rest) = split(bytes)?;
+        Ok((u64::from_be_bytes(head), rest))
+    }
+}
+
+impl KeyPart for i64 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&((*self as u64) ^ (1 << 63)).to_be_bytes());
+    }
+
+    fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), KeyError> {
+        let (head, rest) = split(bytes)?;
+        Ok(((u64::from_be_bytes(head) ^ (1 << 63)) as i64, rest))
+    }
+}
+
+/// An unsigned 256-bit integer, such as an EVM storage slot or balance, held as its 32
+/// big-endian bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct U256([u8; 32]);
+
+impl U256 {
+    pub const fn from_be_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub const fn to_be_bytes(self) -> [u8; 32] {
+        self.0
+    }
+}
+
+impl From<u64> for U256 {
+    fn from(value: u64) -> Self {
+        Self::from(u128::from(value))
+    }
+}
+
+impl From<u128> for U256 {
+    fn from(value: u128) -> Self {
+        let mut bytes = [0; 32];
+        #[allow(clippy::indexing_slicing)]
+        bytes[16..].copy_from_slice(&value.to_be_bytes());
+        Self(bytes)
+    }
+}
+
+impl KeyPart for U256 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0);
+    }
+
+    fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), KeyError> {
+        let (head, rest) = split(bytes)?;
+        Ok((U256(head), rest))
+    }
+}
+
+impl KeyPart for Vec<u8> {
+    /// # Panics
+    ///
+    /// Panics if the bytes don't fit a `u32` length.
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        #[allow(clippy::unwrap_used)]
+        let len = u32::try_from(self.len()).unwrap();
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(self);
+    }
+
+    fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), KeyError> {
+        let (len, rest) = split::<4>(bytes)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if rest.len() < len {
+            return Err(KeyError::Truncated {
+                needed: len - rest.len(),
+                remaining: rest.len(),
+            });
+        }
+        let (part, rest) = rest.split_at(len);
+        Ok((part.to_vec(), rest))
+    }
+}
+
+macro_rules! tuple_key_part {
+    ($($part:ident),+) => {
+        impl<$($part: KeyPart),+> KeyPart for ($($part,)+) {
+            #[allow(non_snake_case)]
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                let ($($part,)+) = self;
+                $($part.encode_to(out);)+
+            }
+
+            #[allow(non_snake_case)]
+            fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), KeyError> {
+                let rest = bytes;
+                $(let ($part, rest) = $part::decode_from(rest)?;)+
+                Ok((($($part,)+), rest))
+            }
+        }
+    };
+}
+
+tuple_key_part!(A, B);
+tuple_key_part!(A, B, C);
+tuple_key_part!(A, B, C, D);
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod tests {
+    use super::*;
+
+    /// Checks that the encodings of `keys`, in ascending order, are in ascending order too, and
+    /// decode back to them.
+    fn assert_ordered<K: KeyPart + Clone + PartialEq + std::fmt::Debug>(keys: &[K]) {
+        let encoded: Vec<_> = keys.iter().map(encode).collect();
+        for pair in encoded.windows(2) {
+            assert!(pair[0] < pair[1], "{pair:?}");
+        }
+        for (key, bytes) in keys.iter().zip(&encoded) {
+            assert_eq!(&decode::<K>(bytes).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn integers_keep_their_order() {
+        assert_ordered(&[0u64, 1, 255, 256, u64::MAX - 1, u64::MAX]);
+        assert_ordered(&[i64::MIN, -256, -1, 0, 1, 255, i64::MAX]);
+        assert_ordered(&[
+            U256::default(),
+            U256::from(1u64),
+            U256::from(u64::MAX),
+            U256::from(u128::MAX),
+            U256::from_be_bytes([0xff; 32]),
+        ]);
+    }
+
+    #[test]
+    fn composite_keys_keep_their_order() {
+        assert_ordered(&[
+            (1u64, Vec::new()),
+            (1, b"a".to_vec()),
+            (1, b"b".to_vec()),
+            // byte strings are ordered by length first
+            (1, b"aa".to_vec()),
+            (2, Vec::new()),
+        ]);
+        assert_ordered(&[(-1i64, 0u64, U256::from(9u64)), (-1, 1, U256::default())]);
+        assert_ordered(&[(b"x".to_vec(), 1u64, -1i64, 0u64), (b"x".to_vec(), 1, 0, 0)]);
+    }
+
+    #[test]
+    fn malformed_keys() {
+        assert_eq!(
+            decode::<u64>(&[0; 5]),
+            Err(KeyError::Truncated {
+                needed: 3,
+                remaining: 5
+            })
+        );
+        assert_eq!(decode::<u64>(&[0; 9]), Err(KeyError::TrailingBytes(1)));
+        assert_eq!(
+            decode::<Vec<u8>>(&[0, 0, 0, 4, 1, 2]),
+            Err(KeyError::Truncated {
+                needed: 2,
+                remaining: 2
+            })
+        );
+        let key = encode(&(1u64, b"ab".to_vec()));
+        assert_eq!(
+            decode::<(u64, Vec<u8>)>(&key[..key.len() - 1]),
+            Err(KeyError::Truncated {
+                needed: 1,
+                remaining: 1
+            })
+        );
+    }
+}
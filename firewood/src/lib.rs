@@ -181,16 +181,33 @@
 //! No change is required for other historical ghost store instances. Finally, we can phase out
 //! some very old ghost store to keep the size of the rolling window invariant.
 //!
+//! ## On-disk Format
+//!
+//! Every integer Firewood writes to its files is little-endian, whatever the host it runs on:
+//! the parameters and headers at the start of the meta store, the metadata and lengths of the
+//! trie nodes, and the record headers of the Wal. They are converted explicitly when written and
+//! read, never transmuted from their in-memory representation, so a database written on one
+//! architecture opens on another. Disk addresses are stored on 8 bytes, which leaves 64-bit hosts
+//! as the only ones supported.
+//!
+pub mod bench;
 pub mod db;
 pub(crate) mod file;
+pub mod keys;
 pub mod merkle;
 pub mod merkle_util;
 pub mod storage;
 
 pub mod config;
+pub mod memory_budget;
 pub mod nibbles;
-// TODO: shale should not be pub, but there are integration test dependencies :(
+pub mod reference;
+// shale is public so that a standalone [merkle::standalone::Trie] can be built on a custom
+// [shale::LinearStore]
 pub mod shale;
 
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
+
 pub mod logger;
 pub mod v2;
@@ -0,0 +1,295 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! A single ceiling on the memory held by the caches and buffers of a [Db](crate::db::Db).
+//!
+//! Every consumer charges the bytes it holds to a [MemoryBudget]. When the total goes above the
+//! limit, the budget reclaims memory from the consumers that can give it back, in the order of
+//! [MemoryConsumer::EVICTION_ORDER]: decoded trie nodes are dropped first since they are rebuilt
+//! from the page cache cheaply, then the page cache itself. Staged proposals, the disk buffer and
+//! the scratch buffers can't be evicted; they are only accounted, and push the caches out instead.
+
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+};
+
+/// What a [MemoryBudget] accounts memory to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MemoryConsumer {
+    /// Decoded trie nodes held by the [ObjCache](crate::shale::ObjCache)s of the revisions and
+    /// proposals.
+    ObjCache,
+    /// Pages read from the store files, held by the page caches of the stores.
+    PageCache,
+    /// Pages changed by proposals that are not committed yet.
+    Overlay,
+    /// Pages and Wal records of committed revisions that the disk buffer has not written yet.
+    WalBuffer,
+    /// Buffers objects are serialized into before they are written. These are shared by every
+    /// [Db](crate::db::Db) of the process.
+    Scratch,
+}
+
+impl MemoryConsumer {
+    const ALL: [MemoryConsumer; 5] = [
+        MemoryConsumer::ObjCache,
+        MemoryConsumer::PageCache,
+        MemoryConsumer::Overlay,
+        MemoryConsumer::WalBuffer,
+        MemoryConsumer::Scratch,
+    ];
+
+    /// The consumers memory is reclaimed from, most expendable first.
+    pub const EVICTION_ORDER: [MemoryConsumer; 2] =
+        [MemoryConsumer::ObjCache, MemoryConsumer::PageCache];
+
+    const fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// A cache that gives memory back to its [MemoryBudget] when asked to.
+pub(crate) trait Reclaim: Send + Sync {
+    /// Evicts entries worth about `bytes` bytes, and releases them from the budget. Caches that
+    /// are locked are left alone rather than waited for.
+    fn reclaim(&self, bytes: usize);
+}
+
+struct BudgetInner {
+    limit: usize,
+    /// Whether the process-wide scratch buffers count, which they always do outside of tests.
+    scratch: bool,
+    used: [AtomicUsize; MemoryConsumer::ALL.len()],
+    reclaimers: Mutex<Vec<(MemoryConsumer, Weak<dyn Reclaim>)>>,
+}
+
+/// Accounts the memory of the caches and buffers of a [Db](crate::db::Db) against a single
+/// limit, see the [module documentation](self). Clones share the same budget.
+#[derive(Clone)]
+pub struct MemoryBudget(Arc<BudgetInner>);
+
+impl fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("MemoryBudget");
+        s.field("limit", &self.0.limit);
+        for consumer in MemoryConsumer::ALL {
+            s.field(&format!("{consumer:?}"), &self.used(consumer));
+        }
+        s.finish()
+    }
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl MemoryBudget {
+    /// A budget of `limit` bytes. A limit of zero only accounts the memory, without evicting
+    /// anything.
+    pub fn new(limit: usize) -> Self {
+        Self(Arc::new(BudgetInner {
+            limit,
+            scratch: true,
+            used: Default::default(),
+            reclaimers: Mutex::new(Vec::new()),
+        }))
+    }
+
+    /// The limit in bytes, zero if there is none.
+    pub fn limit(&self) -> usize {
+        self.0.limit
+    }
+
+    /// The bytes currently accounted to `consumer`.
+    pub fn used(&self, consumer: MemoryConsumer) -> usize {
+        if consumer == MemoryConsumer::Scratch {
+            return if self.0.scratch {
+                crate::shale::scratch::allocated()
+            } else {
+                0
+            };
+        }
+        #[allow(clippy::indexing_slicing)]
+        self.0.used[consumer.index()].load(Ordering::Relaxed)
+    }
+
+    /// The bytes currently accounted to all the consumers.
+    pub fn total(&self) -> usize {
+        MemoryConsumer::ALL
+            .into_iter()
+            .map(|consumer| self.used(consumer))
+            .sum()
+    }
+
+    /// How many bytes the total is above the limit.
+    fn excess(&self) -> usize {
+        match self.0.limit {
+            0 => 0,
+            limit => self.total().saturating_sub(limit),
+        }
+    }
+
+    pub(crate) fn over_limit(&self) -> bool {
+        self.excess() > 0
+    }
+
+    /// Accounts `bytes` more to `consumer`. This never evicts anything, see
+    /// [MemoryBudget::enforce].
+    pub(crate) fn charge(&self, consumer: MemoryConsumer, bytes: usize) {
+        #[allow(clippy::indexing_slicing)]
+        self.0.used[consumer.index()].fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Accounts `bytes` less to `consumer`.
+    pub(crate) fn release(&self, consumer: MemoryConsumer, bytes: usize) {
+        #[allow(clippy::indexing_slicing)]
+        self.0.used[consumer.index()].fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Accounts `bytes` to `consumer` until the returned [Reservation] is dropped, and brings the
+    /// total back under the limit.
+    pub(crate) fn reserve(&self, consumer: MemoryConsumer, bytes: usize) -> Reservation {
+        self.charge(consumer, bytes);
+        self.enforce();
+        Reservation {
+            budget: self.clone(),
+            consumer,
+            bytes,
+        }
+    }
+
+    /// Registers a cache to reclaim memory from. The cache stays registered until it is dropped.
+    pub(crate) fn register(&self, consumer: MemoryConsumer, reclaimer: Weak<dyn Reclaim>) {
+        let mut reclaimers = self.0.reclaimers.lock();
+        reclaimers.retain(|(_, reclaimer)| reclaimer.strong_count() > 0);
+        reclaimers.push((consumer, reclaimer));
+    }
+
+    /// Reclaims memory from the registered caches, in [MemoryConsumer::EVICTION_ORDER], until the
+    /// total is under the limit or nothing more can be evicted. Caches in use by another thread
+    /// are skipped. Callers must not hold the lock of a registered cache.
+    pub(crate) fn enforce(&self) {
+        let mut excess = self.excess();
+        if excess == 0 {
+            return;
+        }
+
+        let reclaimers: Vec<_> = self.0.reclaimers.lock().clone();
+        for consumer in MemoryConsumer::EVICTION_ORDER {
+            for (_, reclaimer) in reclaimers.iter().filter(|(c, _)| *c == consumer) {
+                let Some(reclaimer) = reclaimer.upgrade() else {
+                    continue;
+                };
+                reclaimer.reclaim(excess);
+
+                excess = self.excess();
+                if excess == 0 {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Memory accounted to a [MemoryBudget] until this is dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: MemoryBudget,
+    consumer: MemoryConsumer,
+    bytes: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.consumer, self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A budget that doesn't depend on the scratch buffers of the other tests.
+    fn budget(limit: usize) -> MemoryBudget {
+        MemoryBudget(Arc::new(BudgetInner {
+            limit,
+            scratch: false,
+            used: Default::default(),
+            reclaimers: Mutex::new(Vec::new()),
+        }))
+    }
+
+    struct Cache {
+        budget: MemoryBudget,
+        consumer: MemoryConsumer,
+        bytes: Mutex<usize>,
+    }
+
+    impl Cache {
+        fn new(budget: &MemoryBudget, consumer: MemoryConsumer, bytes: usize) -> Arc<Self> {
+            budget.charge(consumer, bytes);
+            let cache = Arc::new(Self {
+                budget: budget.clone(),
+                consumer,
+                bytes: Mutex::new(bytes),
+            });
+            let reclaimer: Arc<dyn Reclaim> = cache.clone();
+            budget.register(consumer, Arc::downgrade(&reclaimer));
+            cache
+        }
+    }
+
+    impl Reclaim for Cache {
+        fn reclaim(&self, bytes: usize) {
+            let mut held = self.bytes.lock();
+            let freed = bytes.min(*held);
+            *held -= freed;
+            self.budget.release(self.consumer, freed);
+        }
+    }
+
+    #[test]
+    fn evicts_in_order() {
+        let budget = budget(1000);
+        let pages = Cache::new(&budget, MemoryConsumer::PageCache, 500);
+        let nodes = Cache::new(&budget, MemoryConsumer::ObjCache, 400);
+
+        // under the limit, nothing is evicted
+        let overlay = budget.reserve(MemoryConsumer::Overlay, 100);
+        assert_eq!(*nodes.bytes.lock(), 400);
+
+        // the nodes go first
+        let wal = budget.reserve(MemoryConsumer::WalBuffer, 300);
+        assert_eq!(*nodes.bytes.lock(), 100);
+        assert_eq!(*pages.bytes.lock(), 500);
+
+        // and the pages once the nodes are all gone
+        let more = budget.reserve(MemoryConsumer::Overlay, 200);
+        assert_eq!(*nodes.bytes.lock(), 0);
+        assert_eq!(*pages.bytes.lock(), 400);
+        assert_eq!(budget.total(), 1000);
+
+        drop((overlay, wal, more));
+        assert_eq!(budget.used(MemoryConsumer::Overlay), 0);
+        assert_eq!(budget.used(MemoryConsumer::WalBuffer), 0);
+    }
+
+    #[test]
+    fn unlimited() {
+        let budget = budget(0);
+        let nodes = Cache::new(&budget, MemoryConsumer::ObjCache, 400);
+        let _overlay = budget.reserve(MemoryConsumer::Overlay, usize::MAX / 2);
+        assert_eq!(*nodes.bytes.lock(), 400);
+
+        // dropped caches are skipped
+        drop(nodes);
+        budget.enforce();
+    }
+}
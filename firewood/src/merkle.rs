@@ -2,27 +2,41 @@
 // See the file LICENSE.md for licensing terms.
 use crate::nibbles::Nibbles;
 use crate::shale::compact::Store;
+use crate::shale::free_index::FreeIndex;
 use crate::shale::LinearStore;
-use crate::shale::{self, disk_address::DiskAddress, ObjWriteSizeError, ShaleError};
+use crate::shale::{self, disk_address::DiskAddress, ObjWriteError, ShaleError};
 use crate::storage::{StoreRevMut, StoreRevShared};
 use crate::v2::api;
 use futures::{StreamExt, TryStreamExt};
 use sha3::Digest;
-use std::{
-    collections::HashMap, future::ready, io::Write, iter::once, marker::PhantomData, sync::OnceLock,
-};
+use std::{future::ready, io::Write, iter::once, marker::PhantomData, ops::Deref};
 use thiserror::Error;
 
+mod audit;
+mod forensics;
+mod hot_levels;
 mod node;
 pub mod proof;
+mod quarantine;
+pub mod standalone;
 mod stream;
 mod trie_hash;
+mod walk;
 
-pub use node::{BinarySerde, Bincode, BranchNode, EncodedNode, LeafNode, Node, NodeType, Path};
+pub use audit::{subtree_prefixes, Degeneracy, DegenerateNode};
+pub use forensics::DivergingNode;
+pub use node::{
+    BinarySerde, Bincode, BincodeEncoder, BranchNode, CborEncoder, Child, EncodedNode, LeafNode,
+    NativeEncoder, Node, NodeEncoder, NodeEncoding, NodeType, Path, StoredNode,
+};
 pub use proof::{Proof, ProofError};
+pub(crate) use quarantine::Quarantine;
+pub use quarantine::{CorruptionReport, Health};
 pub use stream::MerkleKeyValueStream;
-pub use trie_hash::{TrieHash, TRIE_HASH_LEN};
+pub use trie_hash::{Keccak256, Keccak384, TrieHash, TrieHasher, EMPTY_ROOT_HASH, TRIE_HASH_LEN};
+pub use walk::{NodeContext, TrieVisitor, Visit};
 
+use self::quarantine::Failure;
 use self::stream::PathIterator;
 
 type NodeObjRef<'a> = shale::ObjRef<'a, Node>;
@@ -47,9 +61,19 @@ pub enum MerkleError {
     #[error("removing internal node references failed")]
     UnsetInternal,
     #[error("error updating nodes: {0}")]
-    WriteError(#[from] ObjWriteSizeError),
+    WriteError(#[from] ObjWriteError),
     #[error("merkle serde error: {0}")]
     BinarySerdeError(String),
+    #[error("hash mismatch for node at {ptr:?}: expected {expected:?}, computed {computed:?}")]
+    HashMismatch {
+        ptr: DiskAddress,
+        expected: TrieHash,
+        computed: TrieHash,
+    },
+    /// The dirty nodes can't be flushed while some nodes are referenced, see
+    /// [Merkle::flush_dirty].
+    #[error("nodes are still referenced")]
+    NodesInUse,
 }
 
 macro_rules! write_node {
@@ -65,9 +89,33 @@ macro_rules! write_node {
     };
 }
 
+/// A node of the trie: either one stored on its own, or a leaf stored inline in its parent
+/// branch.
+#[derive(Debug)]
+pub enum NodeRef<'a> {
+    Stored(NodeObjRef<'a>),
+    Inline(Box<Node>),
+}
+
+impl Deref for NodeRef<'_> {
+    type Target = Node;
+
+    fn deref(&self) -> &Node {
+        match self {
+            NodeRef::Stored(node) => node,
+            NodeRef::Inline(node) => node,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Merkle<S, T> {
     store: Store<Node, S>,
+    verify_hashes_on_read: bool,
+    inline_value_threshold: usize,
+    node_encoding: NodeEncoding,
+    quarantine: Option<Quarantine>,
+    hot_levels: usize,
     phantom: PhantomData<T>,
 }
 
@@ -76,6 +124,11 @@ impl<T> From<Merkle<StoreRevMut, T>> for Merkle<StoreRevShared, T> {
         let store = value.store.into();
         Merkle {
             store,
+            verify_hashes_on_read: value.verify_hashes_on_read,
+            inline_value_threshold: value.inline_value_threshold,
+            node_encoding: value.node_encoding,
+            quarantine: value.quarantine,
+            hot_levels: value.hot_levels,
             phantom: PhantomData,
         }
     }
@@ -83,10 +136,103 @@ impl<T> From<Merkle<StoreRevMut, T>> for Merkle<StoreRevShared, T> {
 
 impl<S: LinearStore, T> Merkle<S, T> {
     pub fn get_node(&self, ptr: DiskAddress) -> Result<NodeObjRef, MerkleError> {
-        self.store.get_item(ptr).map_err(Into::into)
+        self.read_node(ptr, Store::get_item)
+    }
+
+    /// Reads a node like [Merkle::get_node], without caching it if it isn't cached already, see
+    /// [Store::scan_item].
+    pub(crate) fn scan_node(&self, ptr: DiskAddress) -> Result<NodeObjRef<'_>, MerkleError> {
+        self.read_node(ptr, Store::scan_item)
     }
 
-    pub fn put_node(&self, node: Node) -> Result<NodeObjRef, MerkleError> {
+    fn read_node<'a>(
+        &'a self,
+        ptr: DiskAddress,
+        read: impl FnOnce(&'a Store<Node, S>, DiskAddress) -> Result<NodeObjRef<'a>, ShaleError>,
+    ) -> Result<NodeObjRef<'a>, MerkleError> {
+        let node = read(&self.store, ptr)
+            .inspect_err(|e| self.report_corruption(ptr, Failure::Decode(e)))?;
+
+        if self.verify_hashes_on_read {
+            self.verify_node_hash(&node)?;
+        }
+
+        Ok(node)
+    }
+
+    /// Reads a child of a branch node. An inline child comes with its parent, so it is only
+    /// checked by [Merkle::verify_node_hash] as part of the parent.
+    pub fn get_child(&self, child: Child) -> Result<NodeRef<'_>, MerkleError> {
+        match child {
+            Child::Node(ptr) => self.get_node(ptr).map(NodeRef::Stored),
+            Child::Inline(leaf) => Ok(NodeRef::Inline(Box::new(Node::from_leaf(*leaf)))),
+        }
+    }
+
+    /// Reads a child like [Merkle::get_child], without caching it, see [Merkle::scan_node].
+    pub(crate) fn scan_child(&self, child: Child) -> Result<NodeRef<'_>, MerkleError> {
+        match child {
+            Child::Node(ptr) => self.scan_node(ptr).map(NodeRef::Stored),
+            Child::Inline(leaf) => Ok(NodeRef::Inline(Box::new(Node::from_leaf(*leaf)))),
+        }
+    }
+
+    /// Rehashes `node` and compares the result against the hash recorded for it. The recorded
+    /// hash is the one its parent's encoding commits to, so checking every node on the way down
+    /// from the root detects any corrupted node or child pointer. Nodes without a recorded hash
+    /// (i.e. ones that were modified and not yet hashed) are not checked.
+    fn verify_node_hash(&self, node: &NodeObjRef) -> Result<(), MerkleError> {
+        let Some(expected) = node.root_hash.get() else {
+            return Ok(());
+        };
+
+        let computed = TrieHash(sha3::Keccak256::digest(node.inner.encode(&self.store)).into());
+
+        if computed != *expected {
+            self.report_corruption(
+                node.as_addr(),
+                Failure::HashMismatch {
+                    expected: *expected,
+                    computed,
+                },
+            );
+            return Err(MerkleError::HashMismatch {
+                ptr: node.as_addr(),
+                expected: *expected,
+                computed,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Reports the node at `ptr` to the quarantine of the trie, if it has one and the node is
+    /// corrupted.
+    fn report_corruption(&self, ptr: DiskAddress, failure: Failure) {
+        let Some(quarantine) = &self.quarantine else {
+            return;
+        };
+        if !failure.is_corruption() {
+            return;
+        }
+        let (start, length) = Quarantine::dump_range(ptr.get());
+        let (store_id, bytes) = self.store.data_bytes(start, length);
+        quarantine.report(store_id, ptr, failure, bytes.map(|bytes| (start, bytes)));
+    }
+
+    /// Reports the nodes that fail to read to `quarantine` from now on, see
+    /// [Db::health](crate::db::Db::health).
+    pub(crate) fn set_quarantine(&mut self, quarantine: Quarantine) {
+        self.quarantine = Some(quarantine);
+    }
+
+    pub(crate) const fn quarantine(&self) -> Option<&Quarantine> {
+        self.quarantine.as_ref()
+    }
+
+    /// Puts a new node into the store, in the [NodeEncoding] of the trie.
+    pub fn put_node(&self, mut node: Node) -> Result<NodeObjRef, MerkleError> {
+        node.encoding = self.node_encoding;
         self.store.put_item(node, 0).map_err(Into::into)
     }
 
@@ -104,10 +250,76 @@ where
     pub const fn new(store: Store<Node, S>) -> Self {
         Self {
             store,
+            verify_hashes_on_read: false,
+            inline_value_threshold: 0,
+            node_encoding: NodeEncoding::Native,
+            quarantine: None,
+            hot_levels: 0,
             phantom: PhantomData,
         }
     }
 
+    /// Enables or disables rehashing every node as it is read. See
+    /// [DbConfig::verify_hashes_on_read](crate::db::DbConfig::verify_hashes_on_read).
+    pub const fn with_hash_verification(mut self, verify_hashes_on_read: bool) -> Self {
+        self.verify_hashes_on_read = verify_hashes_on_read;
+        self
+    }
+
+    /// Stores the leaves with a value of at most `inline_value_threshold` bytes inline in their
+    /// parent branch, which saves reading and writing a node of their own. Zero disables it. See
+    /// [DbConfig::inline_value_threshold](crate::db::DbConfig::inline_value_threshold).
+    pub const fn with_inline_value_threshold(mut self, inline_value_threshold: usize) -> Self {
+        self.inline_value_threshold = inline_value_threshold;
+        self
+    }
+
+    /// Writes the nodes put into the store from now on in `node_encoding`. The nodes already
+    /// written keep their encoding. See
+    /// [DbConfig::node_encoding](crate::db::DbConfig::node_encoding).
+    pub const fn with_node_encoding(mut self, node_encoding: NodeEncoding) -> Self {
+        self.node_encoding = node_encoding;
+        self
+    }
+
+    /// Defers allocating the nodes put into the store until they are flushed, see
+    /// [DbConfig::delayed_allocation](crate::db::DbConfig::delayed_allocation).
+    pub fn with_delayed_allocation(mut self, enabled: bool) -> Self {
+        self.store = self.store.with_delayed_allocation(enabled);
+        self
+    }
+
+    /// Keeps the free list of the store indexed in memory, starting from `index`, see
+    /// [DbConfig::free_list_index](crate::db::DbConfig::free_list_index).
+    pub fn with_free_index(mut self, index: Option<FreeIndex>) -> Self {
+        self.store = self.store.with_free_index(index);
+        self
+    }
+
+    pub fn free_index(&self) -> Option<FreeIndex> {
+        self.store.free_index()
+    }
+
+    /// The number of free chunks of the store and their total size, see [Store::free_space].
+    pub fn free_space(&self) -> Option<(usize, u64)> {
+        self.store.free_space()
+    }
+
+    /// The end of the chunks of the store, see [Store::data_tail].
+    pub fn data_tail(&self) -> usize {
+        self.store.data_tail()
+    }
+
+    /// Checks the invariants of the header of the store, see [Store::check_header].
+    pub fn check_store_header(&self) -> Result<(), MerkleError> {
+        self.store.check_header().map_err(MerkleError::Shale)
+    }
+
+    /// Indexes the free list of the store, see [Store::build_free_index].
+    pub fn build_free_index(&self) -> Result<FreeIndex, MerkleError> {
+        self.store.build_free_index().map_err(MerkleError::Shale)
+    }
+
     // TODO: use `encode` / `decode` instead of `node.encode` / `node.decode` after extention node removal.
     #[allow(dead_code)]
     fn encode(&self, node: &NodeType) -> Result<Vec<u8>, MerkleError> {
@@ -172,6 +384,8 @@ where
                 children: [None; BranchNode::MAX_CHILDREN],
                 value: encoded.value,
                 children_encoded: encoded.children,
+                inline_children: Default::default(),
+                inline_encoded: Default::default(),
             }
             .into(),
         ))
@@ -181,13 +395,16 @@ where
 impl<S: LinearStore, T> Merkle<S, T> {
     /// Creates the sentinel node, puts it into the store, and returns its address.
     pub fn init_sentinel(&self) -> Result<DiskAddress, MerkleError> {
+        // the address of the sentinel is recorded outside the store, so it can't be deferred
         self.store
-            .put_item(
+            .put_item_allocated(
                 Node::from_branch(BranchNode {
                     partial_path: vec![].into(),
                     children: [None; BranchNode::MAX_CHILDREN],
                     value: None,
                     children_encoded: Default::default(),
+                    inline_children: Default::default(),
+                    inline_encoded: Default::default(),
                 }),
                 Node::max_branch_node_size(),
             )
@@ -195,17 +412,19 @@ impl<S: LinearStore, T> Merkle<S, T> {
             .map(|node| node.as_addr())
     }
 
-    pub fn empty_root() -> &'static TrieHash {
-        static V: OnceLock<TrieHash> = OnceLock::new();
-        #[allow(clippy::unwrap_used)]
-        V.get_or_init(|| {
-            TrieHash(
-                hex::decode("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421")
-                    .unwrap()
-                    .try_into()
-                    .unwrap(),
-            )
-        })
+    /// The root hash of the empty trie, see [EMPTY_ROOT_HASH].
+    pub const fn empty_root() -> &'static TrieHash {
+        &EMPTY_ROOT_HASH
+    }
+
+    /// Whether the trie of `sentinel_addr` has no keys.
+    pub fn is_empty(&self, sentinel_addr: DiskAddress) -> Result<bool, MerkleError> {
+        let sentinel = self.get_node(sentinel_addr)?;
+        let sentinel = sentinel
+            .inner
+            .as_branch()
+            .ok_or(MerkleError::NotBranchNode)?;
+        Ok(sentinel.children[0].is_none())
     }
 
     pub fn root_hash(&self, sentinel_addr: DiskAddress) -> Result<TrieHash, MerkleError> {
@@ -262,13 +481,15 @@ impl<S: LinearStore, T> Merkle<S, T> {
         Ok(())
     }
 
+    /// Inserts `val` at `key`, and returns the length of the value it replaces, if any.
     pub fn insert<K: AsRef<[u8]>>(
         &mut self,
         key: K,
         val: Vec<u8>,
         sentinel_addr: DiskAddress,
-    ) -> Result<(), MerkleError> {
-        let (parents, deleted) = self.insert_and_return_updates(key, val, sentinel_addr)?;
+    ) -> Result<Option<usize>, MerkleError> {
+        let (parents, deleted, old_len) =
+            self.insert_and_return_updates(key, val, sentinel_addr)?;
 
         for mut r in parents {
             r.write(|u| u.rehash())?;
@@ -278,18 +499,28 @@ impl<S: LinearStore, T> Merkle<S, T> {
             self.free_node(ptr)?
         }
 
-        Ok(())
+        Ok(old_len)
     }
 
+    #[allow(clippy::type_complexity)]
     fn insert_and_return_updates<K: AsRef<[u8]>>(
         &self,
         key: K,
         val: Vec<u8>,
         sentinel_addr: DiskAddress,
-    ) -> Result<(impl Iterator<Item = NodeObjRef>, Vec<DiskAddress>), MerkleError> {
+    ) -> Result<
+        (
+            impl Iterator<Item = NodeObjRef>,
+            Vec<DiskAddress>,
+            Option<usize>,
+        ),
+        MerkleError,
+    > {
         // as we split a node, we need to track deleted nodes and parents
         let mut deleted = Vec::new();
         let mut parents = Vec::new();
+        // the length of the value overwritten, if the key has one
+        let mut old_len = None;
 
         // we use Nibbles::<1> so that 1 zero nibble is at the front
         // this is for the sentinel node, which avoids moving the root
@@ -321,6 +552,7 @@ impl<S: LinearStore, T> Merkle<S, T> {
                     match (overlap.unique_a.len(), overlap.unique_b.len()) {
                         // same node, overwrite the value
                         (0, 0) => {
+                            old_len = Some(n.value.len());
                             self.update_value_and_move_node_if_larger(
                                 (&mut parents, &mut deleted),
                                 node,
@@ -335,20 +567,20 @@ impl<S: LinearStore, T> Merkle<S, T> {
                                 (index[0], path.to_vec())
                             };
 
-                            let new_leaf = Node::from_leaf(LeafNode::new(Path(new_leaf_path), val));
-
-                            let new_leaf = self.put_node(new_leaf)?.as_addr();
+                            let new_leaf =
+                                self.new_child(LeafNode::new(Path(new_leaf_path), val), true)?;
 
-                            let mut children = [None; BranchNode::MAX_CHILDREN];
-                            children[new_leaf_index as usize] = Some(new_leaf);
-
-                            let new_branch = BranchNode {
+                            let mut new_branch = BranchNode {
                                 partial_path: Path(overlap.shared.to_vec()),
-                                children,
+                                children: [None; BranchNode::MAX_CHILDREN],
                                 value: n.value.clone().into(),
                                 children_encoded: Default::default(),
+                                inline_children: Default::default(),
+                                inline_encoded: Default::default(),
                             };
 
+                            new_branch.set_child(new_leaf_index, Some(new_leaf));
+
                             let new_branch = Node::from_branch(new_branch);
 
                             let new_branch = self.put_node(new_branch)?.as_addr();
@@ -380,6 +612,8 @@ impl<S: LinearStore, T> Merkle<S, T> {
                                 children: [None; BranchNode::MAX_CHILDREN],
                                 value: Some(val),
                                 children_encoded: Default::default(),
+                                inline_children: Default::default(),
+                                inline_encoded: Default::default(),
                             };
 
                             new_branch.children[old_leaf_index as usize] = Some(old_leaf);
@@ -412,19 +646,20 @@ impl<S: LinearStore, T> Merkle<S, T> {
                                 )?
                                 .as_addr();
 
-                            let new_leaf = Node::from_leaf(LeafNode::new(Path(new_leaf_path), val));
-
-                            let new_leaf = self.put_node(new_leaf)?.as_addr();
+                            let new_leaf =
+                                self.new_child(LeafNode::new(Path(new_leaf_path), val), true)?;
 
                             let mut new_branch = BranchNode {
                                 partial_path: Path(new_branch_path),
                                 children: [None; BranchNode::MAX_CHILDREN],
                                 value: None,
                                 children_encoded: Default::default(),
+                                inline_children: Default::default(),
+                                inline_encoded: Default::default(),
                             };
 
                             new_branch.children[old_leaf_index as usize] = Some(old_leaf);
-                            new_branch.children[new_leaf_index as usize] = Some(new_leaf);
+                            new_branch.set_child(new_leaf_index, Some(new_leaf));
 
                             let node = Node::from_branch(new_branch);
                             let node = self.put_node(node)?.as_addr();
@@ -437,26 +672,37 @@ impl<S: LinearStore, T> Merkle<S, T> {
                 }
 
                 NodeType::Branch(n) if n.partial_path.len() == 0 => {
-                    #[allow(clippy::indexing_slicing)]
-                    match n.children[next_nibble as usize] {
-                        Some(c) => (node, c),
-                        None => {
-                            // insert the leaf to the empty slot
-                            // create a new leaf
-                            let leaf_ptr = self
-                                .put_node(Node::from_leaf(LeafNode::new(
-                                    Path(key_nibbles.collect()),
-                                    val,
-                                )))?
-                                .as_addr();
+                    match n.child(next_nibble) {
+                        Some(Child::Node(c)) => (node, c),
+                        // the inline leaf is at another key, so it is split like a stored one
+                        Some(Child::Inline(leaf))
+                            if !key_nibbles.clone().eq(leaf.partial_path.iter().copied()) =>
+                        {
+                            let c = self.materialize_inline_child(&mut node, next_nibble, *leaf)?;
+                            (node, c)
+                        }
+                        child => {
+                            // insert the leaf to the empty slot, or overwrite the inline one
+                            // (the sentinel node never holds an inline leaf)
+                            if let Some(Child::Inline(leaf)) = child {
+                                old_len = Some(leaf.value.len());
+                            }
+                            let leaf = self.new_child(
+                                LeafNode::new(Path(key_nibbles.collect()), val),
+                                !parents.is_empty(),
+                            )?;
 
                             // set the current child to point to this leaf
-                            #[allow(clippy::indexing_slicing)]
-                            node.write(|node| {
-                                node.as_branch_mut().children[next_nibble as usize] =
-                                    Some(leaf_ptr);
+                            let write_result = node.write(|node| {
+                                node.as_branch_mut().set_child(next_nibble, Some(leaf));
                                 node.rehash();
-                            })?;
+                            });
+
+                            self.move_node_if_write_failed(
+                                (&mut parents, &mut deleted),
+                                node,
+                                write_result,
+                            )?;
 
                             break None;
                         }
@@ -475,6 +721,7 @@ impl<S: LinearStore, T> Merkle<S, T> {
                     match (overlap.unique_a.len(), overlap.unique_b.len()) {
                         // same node, overwrite the value
                         (0, 0) => {
+                            old_len = n.value.as_ref().map(Vec::len);
                             self.update_value_and_move_node_if_larger(
                                 (&mut parents, &mut deleted),
                                 node,
@@ -496,22 +743,37 @@ impl<S: LinearStore, T> Merkle<S, T> {
 
                             next_nibble = new_leaf_index;
 
-                            match n.children[next_nibble as usize] {
-                                Some(ptr) => (node, ptr),
-                                None => {
-                                    let new_leaf = Node::from_leaf(LeafNode::new(
-                                        Path(new_leaf_path.to_vec()),
-                                        val,
-                                    ));
-
-                                    let new_leaf = self.put_node(new_leaf)?.as_addr();
-
-                                    #[allow(clippy::indexing_slicing)]
-                                    node.write(|node| {
-                                        node.as_branch_mut().children[next_nibble as usize] =
-                                            Some(new_leaf);
+                            match n.child(next_nibble) {
+                                Some(Child::Node(ptr)) => (node, ptr),
+                                Some(Child::Inline(leaf))
+                                    if leaf.partial_path.0 != new_leaf_path =>
+                                {
+                                    let ptr = self.materialize_inline_child(
+                                        &mut node,
+                                        next_nibble,
+                                        *leaf,
+                                    )?;
+                                    (node, ptr)
+                                }
+                                child => {
+                                    if let Some(Child::Inline(leaf)) = child {
+                                        old_len = Some(leaf.value.len());
+                                    }
+                                    let new_leaf = self.new_child(
+                                        LeafNode::new(Path(new_leaf_path.to_vec()), val),
+                                        true,
+                                    )?;
+
+                                    let write_result = node.write(|node| {
+                                        node.as_branch_mut().set_child(next_nibble, Some(new_leaf));
                                         node.rehash();
-                                    })?;
+                                    });
+
+                                    self.move_node_if_write_failed(
+                                        (&mut parents, &mut deleted),
+                                        node,
+                                        write_result,
+                                    )?;
 
                                     break None;
                                 }
@@ -540,6 +802,8 @@ impl<S: LinearStore, T> Merkle<S, T> {
                                 children: [None; BranchNode::MAX_CHILDREN],
                                 value: Some(val),
                                 children_encoded: Default::default(),
+                                inline_children: Default::default(),
+                                inline_encoded: Default::default(),
                             };
 
                             new_branch.children[old_branch_index as usize] = Some(old_branch);
@@ -574,19 +838,20 @@ impl<S: LinearStore, T> Merkle<S, T> {
                                 )?
                                 .as_addr();
 
-                            let new_leaf = Node::from_leaf(LeafNode::new(Path(new_leaf_path), val));
-
-                            let new_leaf = self.put_node(new_leaf)?.as_addr();
+                            let new_leaf =
+                                self.new_child(LeafNode::new(Path(new_leaf_path), val), true)?;
 
                             let mut new_branch = BranchNode {
                                 partial_path: Path(new_branch_path),
                                 children: [None; BranchNode::MAX_CHILDREN],
                                 value: None,
                                 children_encoded: Default::default(),
+                                inline_children: Default::default(),
+                                inline_encoded: Default::default(),
                             };
 
                             new_branch.children[old_branch_index as usize] = Some(old_branch);
-                            new_branch.children[new_leaf_index as usize] = Some(new_leaf);
+                            new_branch.set_child(new_leaf_index, Some(new_leaf));
 
                             let node = Node::from_branch(new_branch);
                             let node = self.put_node(node)?.as_addr();
@@ -614,13 +879,23 @@ impl<S: LinearStore, T> Merkle<S, T> {
                     node,
                     |u| {
                         info = match &mut u.inner {
-                            NodeType::Branch(n) => {
-                                n.value = Some(val);
+                            NodeType::Branch(n) if n.partial_path.is_empty() => {
+                                old_len = n.value.replace(val).as_ref().map(Vec::len);
                                 None
                             }
+                            // the key ends within the path of the branch, which becomes a
+                            // child of a new branch holding the value
+                            NodeType::Branch(n) => {
+                                #[allow(clippy::indexing_slicing)]
+                                let idx = n.partial_path[0];
+                                #[allow(clippy::indexing_slicing)]
+                                (n.partial_path = Path(n.partial_path[1..].to_vec()));
+
+                                Some((idx, true, None, val))
+                            }
                             NodeType::Leaf(n) => {
                                 if n.partial_path.len() == 0 {
-                                    n.value = val;
+                                    old_len = Some(std::mem::replace(&mut n.value, val).len());
 
                                     None
                                 } else {
@@ -664,6 +939,8 @@ impl<S: LinearStore, T> Merkle<S, T> {
                         children: chd,
                         value: Some(val),
                         children_encoded: Default::default(),
+                        inline_children: Default::default(),
+                        inline_encoded: Default::default(),
                     }))?
                     .as_addr();
 
@@ -671,7 +948,11 @@ impl<S: LinearStore, T> Merkle<S, T> {
             }
         }
 
-        Ok((parents.into_iter().rev().map(|(node, _)| node), deleted))
+        Ok((
+            parents.into_iter().rev().map(|(node, _)| node),
+            deleted,
+            old_len,
+        ))
     }
 
     pub fn remove<K: AsRef<[u8]>>(
@@ -689,145 +970,88 @@ impl<S: LinearStore, T> Merkle<S, T> {
             let (node, mut parents) =
                 self.get_node_and_parents_by_key(self.get_node(sentinel_addr)?, key)?;
 
-            let Some(mut node) = node else {
+            let Some(node) = node else {
                 return Ok(None);
             };
 
-            let value = match &node.inner {
-                NodeType::Branch(branch) => {
-                    let value = branch.value.clone();
-                    if value.is_none() {
-                        return Ok(None);
-                    }
-
-                    let children: Vec<_> = branch
-                        .children
-                        .iter()
-                        .enumerate()
-                        .filter_map(|(i, child)| child.map(|child| (i, child)))
-                        .collect();
-
-                    // don't change the sentinel node
-                    if children.len() == 1 && !parents.is_empty() {
-                        let branch_path = &branch.partial_path.0;
+            let value = match node {
+                NodeRef::Stored(mut node) => match &node.inner {
+                    NodeType::Branch(branch) => {
+                        let value = branch.value.clone();
+                        if value.is_none() {
+                            return Ok(None);
+                        }
 
-                        #[allow(clippy::indexing_slicing)]
-                        let (child_index, child) = children[0];
-                        let mut child = self.get_node(child)?;
+                        let children: Vec<_> = branch.children_iter().collect();
 
-                        child.write(|child| {
-                            let child_path = child.inner.path_mut();
-                            let path = branch_path
+                        // don't change the sentinel node
+                        if children.len() == 1 && !parents.is_empty() {
+                            #[allow(clippy::indexing_slicing)]
+                            let (child_index, child) = children[0].clone();
+                            let path_prefix = branch
+                                .partial_path
+                                .0
                                 .iter()
                                 .copied()
-                                .chain(once(child_index as u8))
-                                .chain(child_path.0.iter().copied())
-                                .collect();
-                            *child_path = Path(path);
-
-                            child.rehash();
-                        })?;
-
-                        set_parent(child.as_addr(), &mut parents);
-
-                        deleted.push(node.as_addr());
-                    } else {
-                        node.write(|node| {
-                            node.as_branch_mut().value = None;
-                            node.rehash();
-                        })?
-                    }
-
-                    value
-                }
-
-                NodeType::Leaf(n) => {
-                    let value = Some(n.value.clone());
-
-                    // TODO: handle unwrap better
-                    deleted.push(node.as_addr());
-
-                    let (mut parent, child_index) = parents.pop().expect("parents is never empty");
-
-                    #[allow(clippy::indexing_slicing)]
-                    parent.write(|parent| {
-                        parent.as_branch_mut().children[child_index as usize] = None;
-                    })?;
-
-                    let branch = parent
-                        .inner
-                        .as_branch()
-                        .expect("parents are always branch nodes");
-
-                    let children: Vec<_> = branch
-                        .children
-                        .iter()
-                        .enumerate()
-                        .filter_map(|(i, child)| child.map(|child| (i, child)))
-                        .collect();
-
-                    match (children.len(), &branch.value, !parents.is_empty()) {
-                        // node is invalid, all single-child nodes should have a value
-                        (1, None, true) => {
-                            let parent_path = &branch.partial_path.0;
-
-                            #[allow(clippy::indexing_slicing)]
-                            let (child_index, child) = children[0];
-                            let child = self.get_node(child)?;
-
-                            // TODO:
-                            // there's an optimization here for when the paths are the same length
-                            // and that clone isn't great but ObjRef causes problems
-                            // we can't write directly to the child because we could be changing its size
-                            let new_child = match child.inner.clone() {
-                                NodeType::Branch(mut child) => {
-                                    let path = parent_path
-                                        .iter()
-                                        .copied()
-                                        .chain(once(child_index as u8))
-                                        .chain(child.partial_path.0.iter().copied())
-                                        .collect();
-
-                                    child.partial_path = Path(path);
-
-                                    Node::from_branch(child)
+                                .chain(once(child_index));
+
+                            let child = match child {
+                                Child::Node(child) => {
+                                    let child = self.get_node(child)?;
+                                    let path = match child.inner() {
+                                        NodeType::Branch(child) => &child.partial_path,
+                                        NodeType::Leaf(child) => &child.partial_path,
+                                    };
+                                    let path =
+                                        Path(path_prefix.chain(path.iter().copied()).collect());
+
+                                    // the longer path may not fit where the child is
+                                    self.update_path_and_move_node_if_larger(
+                                        (&mut parents, &mut deleted),
+                                        child,
+                                        path,
+                                    )?
+                                    .as_addr()
                                 }
-                                NodeType::Leaf(mut child) => {
-                                    let path = parent_path
-                                        .iter()
-                                        .copied()
-                                        .chain(once(child_index as u8))
+                                // the merged leaf is stored on its own, whatever its value
+                                Child::Inline(mut child) => {
+                                    let path = path_prefix
                                         .chain(child.partial_path.0.iter().copied())
                                         .collect();
-
                                     child.partial_path = Path(path);
 
-                                    Node::from_leaf(child)
+                                    self.put_node(Node::from_leaf(*child))?.as_addr()
                                 }
                             };
 
-                            let child = self.put_node(new_child)?.as_addr();
-
                             set_parent(child, &mut parents);
 
-                            deleted.push(parent.as_addr());
+                            deleted.push(node.as_addr());
+                        } else {
+                            node.write(|node| {
+                                node.as_branch_mut().value = None;
+                                node.rehash();
+                            })?
                         }
 
-                        // branch nodes shouldn't have no children
-                        (0, Some(value), true) => {
-                            let leaf = Node::from_leaf(LeafNode::new(
-                                Path(branch.partial_path.0.clone()),
-                                value.clone(),
-                            ));
+                        value
+                    }
 
-                            let leaf = self.put_node(leaf)?.as_addr();
-                            set_parent(leaf, &mut parents);
+                    NodeType::Leaf(n) => {
+                        let value = Some(n.value.clone());
 
-                            deleted.push(parent.as_addr());
-                        }
+                        deleted.push(node.as_addr());
+
+                        self.remove_child(&mut parents, &mut deleted)?;
 
-                        _ => parent.write(|parent| parent.rehash())?,
+                        value
                     }
+                },
+
+                NodeRef::Inline(node) => {
+                    let value = node.inner.as_leaf().map(|leaf| leaf.value.clone());
+
+                    self.remove_child(&mut parents, &mut deleted)?;
 
                     value
                 }
@@ -847,6 +1071,96 @@ impl<S: LinearStore, T> Merkle<S, T> {
         Ok(value)
     }
 
+    /// Removes the child at the position recorded with the last of `parents` from that parent,
+    /// which is merged into its own parent if it's no longer a valid branch.
+    fn remove_child<'a>(
+        &'a self,
+        parents: &mut ParentRefs<'a>,
+        deleted: &mut Vec<DiskAddress>,
+    ) -> Result<(), MerkleError> {
+        // TODO: handle unwrap better
+        let (mut parent, child_index) = parents.pop().expect("parents is never empty");
+
+        parent.write(|parent| {
+            parent.as_branch_mut().set_child(child_index, None);
+        })?;
+
+        let branch = parent
+            .inner
+            .as_branch()
+            .expect("parents are always branch nodes");
+
+        let children: Vec<_> = branch.children_iter().collect();
+
+        match (children.len(), &branch.value, !parents.is_empty()) {
+            // node is invalid, all single-child nodes should have a value
+            (1, None, true) => {
+                let parent_path = &branch.partial_path.0;
+
+                #[allow(clippy::indexing_slicing)]
+                let (child_index, child) = children[0].clone();
+
+                // TODO:
+                // there's an optimization here for when the paths are the same length
+                // and that clone isn't great but ObjRef causes problems
+                // we can't write directly to the child because we could be changing its size
+                let child = match child {
+                    Child::Node(child) => self.get_node(child)?.inner.clone(),
+                    Child::Inline(child) => NodeType::Leaf(*child),
+                };
+
+                let new_child = match child {
+                    NodeType::Branch(mut child) => {
+                        let path = parent_path
+                            .iter()
+                            .copied()
+                            .chain(once(child_index))
+                            .chain(child.partial_path.0.iter().copied())
+                            .collect();
+
+                        child.partial_path = Path(path);
+
+                        Node::from_branch(child)
+                    }
+                    NodeType::Leaf(mut child) => {
+                        let path = parent_path
+                            .iter()
+                            .copied()
+                            .chain(once(child_index))
+                            .chain(child.partial_path.0.iter().copied())
+                            .collect();
+
+                        child.partial_path = Path(path);
+
+                        Node::from_leaf(child)
+                    }
+                };
+
+                let child = self.put_node(new_child)?.as_addr();
+
+                set_parent(child, parents);
+
+                deleted.push(parent.as_addr());
+            }
+
+            // branch nodes shouldn't have no children
+            (0, Some(value), true) => {
+                let leaf = Node::from_leaf(LeafNode::new(
+                    Path(branch.partial_path.0.clone()),
+                    value.clone(),
+                ));
+
+                let leaf = self.put_node(leaf)?.as_addr();
+                set_parent(leaf, parents);
+
+                deleted.push(parent.as_addr());
+            }
+
+            _ => parent.write(|parent| parent.rehash())?,
+        }
+
+        Ok(())
+    }
     fn remove_tree_(
         &self,
         u: DiskAddress,
@@ -877,11 +1191,227 @@ impl<S: LinearStore, T> Merkle<S, T> {
         Ok(())
     }
 
+    /// Removes every key starting with `prefix`, calling `removed` with each key and value. The
+    /// subtree holding those keys is detached as [Merkle::drop_prefix] detaches it, then walked
+    /// to call `removed`, which reads every node of it: only use this over
+    /// [Merkle::drop_prefix] when the keys are needed, to keep indexes of them. The nodes of the
+    /// subtree aren't freed either, see [Merkle::reclaim].
+    pub fn remove_prefix<K: AsRef<[u8]>>(
+        &mut self,
+        prefix: K,
+        sentinel_addr: DiskAddress,
+        mut removed: impl FnMut(&[u8], &[u8]),
+    ) -> Result<Option<Child>, MerkleError> {
+        let Some((mut path, subtree)) = self.detach_prefix(prefix.as_ref(), sentinel_addr)? else {
+            return Ok(None);
+        };
+        match &subtree {
+            Child::Node(ptr) => self.walk_subtree(*ptr, &mut path, &mut removed)?,
+            Child::Inline(leaf) => {
+                path.extend_from_slice(&leaf.partial_path.0);
+                removed(
+                    &nibbles_to_bytes_iter(&path).collect::<Vec<_>>(),
+                    &leaf.value,
+                );
+            }
+        }
+        Ok(Some(subtree))
+    }
+
+    /// Removes every key starting with `prefix` by detaching the subtree holding them where the
+    /// prefix ends, which only restructures the path to it, as removing a single key would: the
+    /// work is proportional to the depth of the prefix, however many keys the subtree holds.
+    /// Returns the detached subtree, if any. The nodes of a subtree stored on its own aren't
+    /// freed but left to the caller, who passes its root to [Merkle::reclaim]; a leaf inline in
+    /// its parent is gone along with it.
+    pub fn drop_prefix<K: AsRef<[u8]>>(
+        &mut self,
+        prefix: K,
+        sentinel_addr: DiskAddress,
+    ) -> Result<Option<Child>, MerkleError> {
+        Ok(self
+            .detach_prefix(prefix.as_ref(), sentinel_addr)?
+            .map(|(_, subtree)| subtree))
+    }
+
+    /// Detaches the subtree of the keys starting with `prefix`, returning it along with the
+    /// nibbles of its keys up to it.
+    fn detach_prefix(
+        &mut self,
+        prefix: &[u8],
+        sentinel_addr: DiskAddress,
+    ) -> Result<Option<(Vec<u8>, Child)>, MerkleError> {
+        if sentinel_addr.is_null() {
+            return Ok(None);
+        }
+
+        // the first nibble picks the root under the sentinel
+        let prefix: Vec<u8> = Nibbles::<1>::new(prefix).into_iter().collect();
+        let mut deleted = Vec::new();
+
+        let detached = {
+            let mut parents = Vec::new();
+            let mut node = self.get_node(sentinel_addr)?;
+            // the nibbles of the prefix on the path to `node`
+            let mut depth = 0;
+
+            // the root of the subtree holding the keys
+            let subtree = loop {
+                let (path, branch) = match &node.inner {
+                    NodeType::Branch(branch) => (&branch.partial_path.0, Some(branch)),
+                    NodeType::Leaf(leaf) => (&leaf.partial_path.0, None),
+                };
+                #[allow(clippy::indexing_slicing)]
+                let rest = &prefix[depth..];
+                if rest.len() <= path.len() {
+                    if !path.starts_with(rest) {
+                        return Ok(None);
+                    }
+                    let ptr = node.as_addr();
+                    drop(node);
+                    break Child::Node(ptr);
+                }
+                let Some(branch) = branch.filter(|_| rest.starts_with(path)) else {
+                    return Ok(None);
+                };
+
+                #[allow(clippy::indexing_slicing)]
+                let index = rest[path.len()];
+                depth += path.len() + 1;
+                match branch.child(index) {
+                    None => return Ok(None),
+                    Some(Child::Node(child)) => {
+                        parents.push((node, index));
+                        node = self.get_node(child)?;
+                    }
+                    Some(Child::Inline(leaf)) => {
+                        #[allow(clippy::indexing_slicing)]
+                        if !leaf.partial_path.0.starts_with(&prefix[depth..]) {
+                            return Ok(None);
+                        }
+                        parents.push((node, index));
+                        break Child::Inline(leaf);
+                    }
+                }
+            };
+
+            self.remove_child(&mut parents, &mut deleted)?;
+
+            for (mut parent, _) in parents {
+                parent.write(|u| u.rehash())?;
+            }
+
+            #[allow(clippy::indexing_slicing)]
+            (prefix[1..depth].to_vec(), subtree)
+        };
+
+        for ptr in deleted.into_iter() {
+            self.free_node(ptr)?;
+        }
+
+        Ok(Some(detached))
+    }
+
+    /// Calls `removed` with each of the keys and values of the subtree at `ptr`. `path` holds the
+    /// nibbles of the keys up to the subtree.
+    fn walk_subtree(
+        &self,
+        ptr: DiskAddress,
+        path: &mut Vec<u8>,
+        removed: &mut dyn FnMut(&[u8], &[u8]),
+    ) -> Result<(), MerkleError> {
+        let node = self.get_node(ptr)?;
+        let len = path.len();
+        match &node.inner {
+            NodeType::Leaf(leaf) => {
+                path.extend_from_slice(&leaf.partial_path.0);
+                removed(
+                    &nibbles_to_bytes_iter(path).collect::<Vec<_>>(),
+                    &leaf.value,
+                );
+            }
+            NodeType::Branch(branch) => {
+                path.extend_from_slice(&branch.partial_path.0);
+                if let Some(value) = &branch.value {
+                    removed(&nibbles_to_bytes_iter(path).collect::<Vec<_>>(), value);
+                }
+                let branch_len = path.len();
+                for (index, child) in branch.children_iter() {
+                    path.push(index);
+                    match child {
+                        Child::Node(child) => self.walk_subtree(child, path, removed)?,
+                        Child::Inline(leaf) => {
+                            path.extend_from_slice(&leaf.partial_path.0);
+                            removed(
+                                &nibbles_to_bytes_iter(path).collect::<Vec<_>>(),
+                                &leaf.value,
+                            );
+                        }
+                    }
+                    path.truncate(branch_len);
+                }
+            }
+        }
+        path.truncate(len);
+        Ok(())
+    }
+
+    /// Frees up to `budget` nodes of the detached subtrees whose roots are in `roots`, see
+    /// [Merkle::drop_prefix]. The children of a freed branch take its place in `roots`, so that
+    /// the subtrees are freed a bit at a time, by successive calls. The nodes not allocated yet
+    /// are freed whatever the budget, as their addresses change once they are. Calls `freed` with
+    /// the value of each key of the freed nodes, and returns the number of nodes freed.
+    pub fn reclaim(
+        &mut self,
+        roots: &mut Vec<DiskAddress>,
+        budget: usize,
+        mut freed: impl FnMut(&[u8]),
+    ) -> Result<usize, MerkleError> {
+        let mut deferred: Vec<_> = roots
+            .iter()
+            .copied()
+            .filter(DiskAddress::is_deferred)
+            .collect();
+        roots.retain(|ptr| !ptr.is_deferred());
+
+        let mut count = 0;
+        loop {
+            let ptr = match deferred.pop() {
+                Some(ptr) => ptr,
+                None if count < budget => match roots.pop() {
+                    Some(ptr) => ptr,
+                    None => break,
+                },
+                None => break,
+            };
+            let node = self.get_node(ptr)?;
+            match &node.inner {
+                NodeType::Leaf(leaf) => freed(&leaf.value),
+                NodeType::Branch(branch) => {
+                    if let Some(value) = &branch.value {
+                        freed(value);
+                    }
+                    for (_, child) in branch.children_iter() {
+                        match child {
+                            Child::Node(child) if child.is_deferred() => deferred.push(child),
+                            Child::Node(child) => roots.push(child),
+                            Child::Inline(leaf) => freed(&leaf.value),
+                        }
+                    }
+                }
+            }
+            drop(node);
+            self.free_node(ptr)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
     fn get_node_by_key<'a, K: AsRef<[u8]>>(
         &'a self,
         node_ref: NodeObjRef<'a>,
         key: K,
-    ) -> Result<Option<NodeObjRef<'a>>, MerkleError> {
+    ) -> Result<Option<NodeRef<'a>>, MerkleError> {
         let key = key.as_ref();
         let path_iter = self.path_iter(node_ref, key);
 
@@ -890,7 +1420,12 @@ impl<S: LinearStore, T> Merkle<S, T> {
             Some(Err(e)) => Err(e),
             Some(Ok((node_key, node))) => {
                 let key_nibbles = Nibbles::<0>::new(key).into_iter();
-                if key_nibbles.eq(node_key.iter().copied()) {
+                // a branch without a value only splits the keys below it
+                let has_value = match &node.inner {
+                    NodeType::Branch(branch) => branch.value.is_some(),
+                    NodeType::Leaf(_) => true,
+                };
+                if has_value && key_nibbles.eq(node_key.iter().copied()) {
                     Ok(Some(node))
                 } else {
                     Ok(None)
@@ -903,7 +1438,7 @@ impl<S: LinearStore, T> Merkle<S, T> {
         &'a self,
         node_ref: NodeObjRef<'a>,
         key: K,
-    ) -> Result<(Option<NodeObjRef<'a>>, ParentRefs<'a>), MerkleError> {
+    ) -> Result<(Option<NodeRef<'a>>, ParentRefs<'a>), MerkleError> {
         let mut parents = Vec::new();
         let node_ref = self.get_node_by_key_with_callbacks(
             node_ref,
@@ -921,7 +1456,7 @@ impl<S: LinearStore, T> Merkle<S, T> {
         &'a self,
         node_ref: NodeObjRef<'a>,
         key: K,
-    ) -> Result<(Option<NodeObjRef<'a>>, ParentAddresses), MerkleError> {
+    ) -> Result<(Option<NodeRef<'a>>, ParentAddresses), MerkleError> {
         let mut parents = Vec::new();
         let node_ref = self.get_node_by_key_with_callbacks(
             node_ref,
@@ -941,7 +1476,7 @@ impl<S: LinearStore, T> Merkle<S, T> {
         key: K,
         mut start_loop_callback: impl FnMut(DiskAddress, u8),
         mut end_loop_callback: impl FnMut(NodeObjRef<'a>, u8),
-    ) -> Result<Option<NodeObjRef<'a>>, MerkleError> {
+    ) -> Result<Option<NodeRef<'a>>, MerkleError> {
         let mut key_nibbles = Nibbles::<1>::new(key.as_ref()).into_iter();
 
         loop {
@@ -951,14 +1486,11 @@ impl<S: LinearStore, T> Merkle<S, T> {
 
             start_loop_callback(node_ref.as_addr(), nib);
 
-            let next_ptr = match &node_ref.inner {
-                #[allow(clippy::indexing_slicing)]
-                NodeType::Branch(n) if n.partial_path.len() == 0 => {
-                    match n.children[nib as usize] {
-                        Some(c) => c,
-                        None => return Ok(None),
-                    }
-                }
+            let next = match &node_ref.inner {
+                NodeType::Branch(n) if n.partial_path.is_empty() => match n.child(nib) {
+                    Some(c) => c,
+                    None => return Ok(None),
+                },
                 NodeType::Branch(n) => {
                     let mut n_path_iter = n.partial_path.iter().copied();
 
@@ -978,14 +1510,13 @@ impl<S: LinearStore, T> Merkle<S, T> {
                         nib
                     } else {
                         return Ok(if n.value.is_some() {
-                            Some(node_ref)
+                            Some(NodeRef::Stored(node_ref))
                         } else {
                             None
                         });
                     };
 
-                    #[allow(clippy::indexing_slicing)]
-                    match n.children[nib as usize] {
+                    match n.child(nib) {
                         Some(c) => c,
                         None => return Ok(None),
                     }
@@ -995,7 +1526,7 @@ impl<S: LinearStore, T> Merkle<S, T> {
                         .chain(key_nibbles)
                         .eq(n.partial_path.iter().copied())
                     {
-                        Some(node_ref)
+                        Some(NodeRef::Stored(node_ref))
                     } else {
                         None
                     };
@@ -1004,6 +1535,20 @@ impl<S: LinearStore, T> Merkle<S, T> {
                 }
             };
 
+            let next_ptr = match next {
+                Child::Node(ptr) => ptr,
+                Child::Inline(leaf) => {
+                    // the branch is the parent of the inline leaf, as it would be of a stored one
+                    if !key_nibbles.eq(leaf.partial_path.iter().copied()) {
+                        return Ok(None);
+                    }
+
+                    end_loop_callback(node_ref, nib);
+
+                    return Ok(Some(NodeRef::Inline(Box::new(Node::from_leaf(*leaf)))));
+                }
+            };
+
             end_loop_callback(node_ref, nib);
 
             node_ref = self.get_node(next_ptr)?;
@@ -1012,9 +1557,9 @@ impl<S: LinearStore, T> Merkle<S, T> {
         // when we're done iterating over nibbles, check if the node we're at has a value
         let node_ref = match &node_ref.inner {
             NodeType::Branch(n) if n.value.as_ref().is_some() && n.partial_path.is_empty() => {
-                Some(node_ref)
+                Some(NodeRef::Stored(node_ref))
             }
-            NodeType::Leaf(n) if n.partial_path.len() == 0 => Some(node_ref),
+            NodeType::Leaf(n) if n.partial_path.is_empty() => Some(NodeRef::Stored(node_ref)),
             _ => None,
         };
 
@@ -1034,7 +1579,21 @@ impl<S: LinearStore, T> Merkle<S, T> {
             let root_node = self.get_node(sentinel_addr)?;
             let (node_ref, parents) = self.get_node_and_parent_addresses_by_key(root_node, key)?;
 
-            (node_ref.map(|n| n.into_ptr()), parents)
+            let ptr = match node_ref {
+                None => None,
+                Some(NodeRef::Stored(node)) => Some(node.into_ptr()),
+                // a RefMut needs a node of its own to write to
+                Some(NodeRef::Inline(node)) => {
+                    let NodeType::Leaf(leaf) = node.inner else {
+                        unreachable!("inline nodes are always leaves")
+                    };
+                    let (parent, index) = parents.last().expect("parents is never empty");
+                    let mut parent = self.get_node(*parent)?;
+                    Some(self.materialize_inline_child(&mut parent, *index, leaf)?)
+                }
+            };
+
+            (ptr, parents)
         };
 
         Ok(ptr.map(|ptr| RefMut::new(ptr, parents, self)))
@@ -1055,26 +1614,38 @@ impl<S: LinearStore, T> Merkle<S, T> {
     where
         K: AsRef<[u8]>,
     {
-        let mut proofs = HashMap::new();
+        // Get the hashes of the nodes.
+        let proofs = self
+            .prove_path(key, sentinel_addr)?
+            .into_iter()
+            .map(|encoded| {
+                let hash: [u8; TRIE_HASH_LEN] = sha3::Keccak256::digest(&encoded).into();
+                (hash, encoded)
+            })
+            .collect();
+
+        Ok(Proof(proofs))
+    }
+
+    /// Like [Merkle::prove], but returns the encoded nodes in order, starting from the root.
+    /// This is the order expected by [Proof::verify_streaming].
+    pub fn prove_path<K>(
+        &self,
+        key: K,
+        sentinel_addr: DiskAddress,
+    ) -> Result<Vec<Vec<u8>>, MerkleError>
+    where
+        K: AsRef<[u8]>,
+    {
         if sentinel_addr.is_null() {
-            return Ok(Proof(proofs));
+            return Ok(Vec::new());
         }
 
         let sentinel_node = self.get_node(sentinel_addr)?;
 
-        let path_iter = self.path_iter(sentinel_node, key.as_ref());
-
-        let nodes = path_iter
-            .map(|result| result.map(|(_, node)| node))
-            .collect::<Result<Vec<NodeObjRef>, MerkleError>>()?;
-
-        // Get the hashes of the nodes.
-        for node in nodes.into_iter() {
-            let encoded = node.get_encoded(&self.store);
-            let hash: [u8; TRIE_HASH_LEN] = sha3::Keccak256::digest(encoded).into();
-            proofs.insert(hash, encoded.to_vec());
-        }
-        Ok(Proof(proofs))
+        self.path_iter(sentinel_node, key.as_ref())
+            .map(|result| result.map(|(_, node)| node.get_encoded(&self.store).to_vec()))
+            .collect()
     }
 
     pub fn get<K: AsRef<[u8]>>(
@@ -1092,8 +1663,36 @@ impl<S: LinearStore, T> Merkle<S, T> {
         Ok(node_ref.map(Ref))
     }
 
-    pub fn flush_dirty(&self) -> Option<()> {
-        self.store.flush_dirty()
+    /// Allocates the deferred nodes, then writes the dirty ones to the stores. Fails with
+    /// [MerkleError::NodesInUse] if some nodes are still referenced, and none is written.
+    pub fn flush_dirty(&self) -> Result<(), MerkleError> {
+        self.store.allocate_deferred()?;
+        self.store.flush_dirty().ok_or(MerkleError::NodesInUse)
+    }
+
+    /// Changes the maximum estimated bytes of the cached nodes, see
+    /// [ObjCache::set_max_bytes](shale::ObjCache::set_max_bytes).
+    pub(crate) fn set_cache_max_bytes(&self, max_bytes: Option<usize>) {
+        self.store.set_cache_max_bytes(max_bytes)
+    }
+
+    /// Drops the cached nodes that aren't modified, see
+    /// [ObjCache::clear_clean](shale::ObjCache::clear_clean).
+    pub(crate) fn clear_clean_cache(&self) -> usize {
+        self.store.clear_clean_cache()
+    }
+
+    /// Returns the addresses of up to `limit` cached nodes, hottest first.
+    pub(crate) fn cached_node_addresses(&self, limit: usize) -> Vec<DiskAddress> {
+        self.store.cached_addresses(limit)
+    }
+
+    /// Returns the meta and data stores the trie is read from.
+    pub(crate) fn linear_stores(&self) -> (S, S)
+    where
+        S: Clone,
+    {
+        self.store.linear_stores()
     }
 
     pub fn path_iter<'a, 'b>(
@@ -1119,6 +1718,16 @@ impl<S: LinearStore, T> Merkle<S, T> {
         MerkleKeyValueStream::from_key(self, sentinel_addr, key)
     }
 
+    /// Iterates over the key-value pairs in descending key order, starting at `key` (or at the
+    /// last key before it), or at the last key of the trie if there is no `key`.
+    pub(crate) const fn key_value_iter_rev(
+        &self,
+        sentinel_addr: DiskAddress,
+        key: Option<Key>,
+    ) -> MerkleKeyValueStream<'_, S, T> {
+        MerkleKeyValueStream::rev_from_key(self, sentinel_addr, key)
+    }
+
     pub(super) async fn range_proof<K: api::KeyType + Send + Sync>(
         &self,
         sentinel_addr: DiskAddress,
@@ -1153,8 +1762,9 @@ impl<S: LinearStore, T> Merkle<S, T> {
         // transpose the Option<Result<T, E>> to Result<Option<T>, E>
         // If this is an error, the ? operator will return it
         let Some((first_key, first_value)) = first_result.transpose()? else {
-            // nothing returned, either the trie is empty or the key wasn't found
-            return Ok(None);
+            // nothing returned, either the trie is empty, which needs no proof, or the key
+            // wasn't found
+            return Ok(self.is_empty(sentinel_addr)?.then(api::RangeProof::empty));
         };
 
         let first_key_proof = self
@@ -1210,6 +1820,72 @@ impl<S: LinearStore, T> Merkle<S, T> {
         }))
     }
 
+    /// Like [Merkle::range_proof], but the keys are collected from `last_key` down, so with a
+    /// `limit` the proof covers the highest keys of the range instead of the lowest ones. As in
+    /// any [api::RangeProof], the keys in `middle` are in ascending order.
+    pub(super) async fn range_proof_rev<K: api::KeyType + Send + Sync>(
+        &self,
+        sentinel_addr: DiskAddress,
+        first_key: Option<K>,
+        last_key: Option<K>,
+        limit: Option<usize>,
+    ) -> Result<Option<api::RangeProof<Vec<u8>, Vec<u8>>>, api::Error> {
+        if let (Some(k1), Some(k2)) = (&first_key, &last_key) {
+            if k1.as_ref() > k2.as_ref() {
+                return Err(api::Error::InvalidRange {
+                    first_key: k1.as_ref().to_vec(),
+                    last_key: k2.as_ref().to_vec(),
+                });
+            }
+        }
+
+        // limit of 0 is always an empty RangeProof
+        if limit == Some(0) {
+            return Ok(None);
+        }
+
+        let stream = self.key_value_iter_rev(
+            sentinel_addr,
+            last_key.map(|key| key.as_ref().to_vec().into_boxed_slice()),
+        );
+
+        // we stop streaming if either we hit the limit or the key returned was smaller
+        // than the smallest key requested
+        let mut middle = stream
+            .take(limit.unwrap_or(usize::MAX))
+            .take_while(|kv_result| {
+                let (Some(first_key), Ok(kv)) = (first_key.as_ref(), kv_result) else {
+                    // no first key asked for, or an error to return
+                    return ready(true);
+                };
+
+                ready(&*kv.0 >= first_key.as_ref())
+            })
+            .map(|kv_result| kv_result.map(|(k, v)| (k.into_vec(), v)))
+            .try_collect::<Vec<(Vec<u8>, Vec<u8>)>>()
+            .await?;
+        middle.reverse();
+
+        let (Some((first_key, _)), Some((last_key, _))) = (middle.first(), middle.last()) else {
+            // nothing returned, either the trie is empty, which needs no proof, or there is no
+            // key in the range
+            return Ok(self.is_empty(sentinel_addr)?.then(api::RangeProof::empty));
+        };
+
+        let first_key_proof = self
+            .prove(first_key, sentinel_addr)
+            .map_err(|e| api::Error::InternalError(Box::new(e)))?;
+        let last_key_proof = self
+            .prove(last_key, sentinel_addr)
+            .map_err(|e| api::Error::InternalError(Box::new(e)))?;
+
+        Ok(Some(api::RangeProof {
+            first_key_proof,
+            middle,
+            last_key_proof,
+        }))
+    }
+
     /// Try to update the [NodeObjRef]'s path in-place. If the update fails because the node can no longer fit at its old address,
     /// then the old address is marked for deletion and the [Node] (with its update) is inserted at a new address.
     fn update_path_and_move_node_if_larger<'a>(
@@ -1242,23 +1918,62 @@ impl<S: LinearStore, T> Merkle<S, T> {
         self.move_node_if_write_failed((parents, to_delete), node, write_result)
     }
 
-    /// Checks if the `write_result` is an [ObjWriteSizeError]. If it is, then the `node` is moved to a new address and the old address is marked for deletion.
+    /// Checks if the `write_result` is an [ObjWriteError::Size]. If it is, then the `node` is moved to a new address and the old address is marked for deletion.
     fn move_node_if_write_failed<'a>(
         &'a self,
         (parents, deleted): (&mut [(NodeObjRef, u8)], &mut Vec<DiskAddress>),
         mut node: NodeObjRef<'a>,
-        write_result: Result<(), ObjWriteSizeError>,
+        write_result: Result<(), ObjWriteError>,
     ) -> Result<NodeObjRef<'a>, MerkleError> {
-        if let Err(ObjWriteSizeError) = write_result {
-            let old_node_address = node.as_addr();
-            node = self.put_node(node.into_inner())?;
-            deleted.push(old_node_address);
-
-            set_parent(node.as_addr(), parents);
+        match write_result {
+            Ok(()) => {}
+            Err(ObjWriteError::Size(_)) => {
+                let old_node_address = node.as_addr();
+                node = self.put_node(node.into_inner())?;
+                deleted.push(old_node_address);
+
+                set_parent(node.as_addr(), parents);
+            }
+            // the node of an immutable store can't be moved either
+            Err(ObjWriteError::ReadOnly) => return Err(MerkleError::ReadOnly),
         }
 
         Ok(node)
     }
+
+    /// Whether a leaf with `value` is stored inline in its parent branch.
+    const fn is_inlined(&self, value: &[u8]) -> bool {
+        self.inline_value_threshold > 0 && value.len() <= self.inline_value_threshold
+    }
+
+    /// Returns the [Child] to store in a branch for a new `leaf`, which is stored inline if it is
+    /// `inline_allowed` and its value is short enough, and is put into the store otherwise.
+    fn new_child(&self, leaf: LeafNode, inline_allowed: bool) -> Result<Child, MerkleError> {
+        if inline_allowed && self.is_inlined(&leaf.value) {
+            return Ok(Child::Inline(Box::new(leaf)));
+        }
+
+        Ok(Child::Node(self.put_node(Node::from_leaf(leaf))?.as_addr()))
+    }
+
+    /// Moves the inline `leaf` at position `index` of the branch `node` into a node of its own,
+    /// and returns the address of that node. The branch only gets smaller, and its encoding
+    /// doesn't change, so it is neither moved nor rehashed.
+    fn materialize_inline_child(
+        &self,
+        node: &mut NodeObjRef,
+        index: u8,
+        leaf: LeafNode,
+    ) -> Result<DiskAddress, MerkleError> {
+        let ptr = self.put_node(Node::from_leaf(leaf))?.as_addr();
+
+        node.write(|node| {
+            node.as_branch_mut()
+                .set_child(index, Some(Child::Node(ptr)))
+        })?;
+
+        Ok(ptr)
+    }
 }
 
 fn set_parent(new_chd: DiskAddress, parents: &mut [(NodeObjRef, u8)]) {
@@ -1277,7 +1992,7 @@ fn set_parent(new_chd: DiskAddress, parents: &mut [(NodeObjRef, u8)]) {
         .unwrap();
 }
 
-pub struct Ref<'a>(NodeObjRef<'a>);
+pub struct Ref<'a>(NodeRef<'a>);
 
 pub struct RefMut<'a, S, T> {
     ptr: DiskAddress,
@@ -1309,7 +2024,7 @@ impl<'a, S, T> RefMut<'a, S, T> {
 impl<'a, S: LinearStore, T> RefMut<'a, S, T> {
     #[allow(clippy::unwrap_used)]
     pub fn get(&self) -> Ref {
-        Ref(self.merkle.get_node(self.ptr).unwrap())
+        Ref(NodeRef::Stored(self.merkle.get_node(self.ptr).unwrap()))
     }
 
     pub fn write(&mut self, modify: impl FnOnce(&mut Vec<u8>)) -> Result<(), MerkleError> {
@@ -1413,7 +2128,9 @@ mod tests {
         assert_eq!(n, nibbles);
     }
 
-    fn create_generic_test_merkle<'de, T>() -> Merkle<InMemLinearStore, T>
+    pub(super) fn create_generic_test_merkle<'de, T>(
+        cache_size: usize,
+    ) -> Merkle<InMemLinearStore, T>
     where
         T: BinarySerde,
         EncodedNode<T>: serde::Serialize + serde::Deserialize<'de>,
@@ -1440,7 +2157,7 @@ mod tests {
         let mem_meta = dm;
         let mem_payload = InMemLinearStore::new(0x10000, 0x1);
 
-        let cache = shale::ObjCache::new(1);
+        let cache = shale::ObjCache::new(cache_size);
         let store =
             shale::compact::Store::new(mem_meta, mem_payload, compact_header, cache, 10, 16)
                 .expect("Store init fail");
@@ -1449,7 +2166,7 @@ mod tests {
     }
 
     pub(super) fn create_test_merkle() -> Merkle<InMemLinearStore, Bincode> {
-        create_generic_test_merkle::<Bincode>()
+        create_generic_test_merkle::<Bincode>(1)
     }
 
     fn branch(path: &[u8], value: &[u8], encoded_child: Option<Vec<u8>>) -> Node {
@@ -1470,6 +2187,8 @@ mod tests {
             children,
             value,
             children_encoded,
+            inline_children: Default::default(),
+            inline_encoded: Default::default(),
         })
     }
 
@@ -1492,6 +2211,8 @@ mod tests {
             children,
             value,
             children_encoded,
+            inline_children: Default::default(),
+            inline_encoded: Default::default(),
         })
     }
 
@@ -1534,7 +2255,7 @@ mod tests {
         T: BinarySerde,
         for<'de> EncodedNode<T>: serde::Serialize + serde::Deserialize<'de>,
     {
-        let merkle = create_generic_test_merkle::<T>();
+        let merkle = create_generic_test_merkle::<T>(1);
         let node_ref = merkle.put_node(node.clone()).unwrap();
 
         let encoded = merkle.encode(node_ref.inner()).unwrap();
@@ -1587,6 +2308,129 @@ mod tests {
         }
     }
 
+    #[test]
+    fn insert_returns_old_len() {
+        // inline leaves, leaves, and branches holding values, split by the keys after them
+        let keys: [&[u8]; 6] = [
+            &[1, 2, 3],
+            &[1, 2],
+            &[1, 2, 4],
+            &[1],
+            &[1, 2, 3, 4],
+            &[0x12],
+        ];
+        for threshold in [0, 8] {
+            let mut merkle = create_test_merkle().with_inline_value_threshold(threshold);
+            let sentinel_addr = merkle.init_sentinel().unwrap();
+
+            for key in keys {
+                assert_eq!(merkle.insert(key, vec![0; 3], sentinel_addr).unwrap(), None);
+            }
+            for key in keys {
+                let old_len = merkle.insert(key, vec![1; 5], sentinel_addr).unwrap();
+                assert_eq!(old_len, Some(3));
+                // too large for their nodes, which are moved
+                let old_len = merkle.insert(key, vec![2; 40], sentinel_addr).unwrap();
+                assert_eq!(old_len, Some(5));
+            }
+            for key in keys {
+                assert_eq!(
+                    merkle.get(key, sentinel_addr).unwrap().as_deref(),
+                    Some([2; 40].as_slice())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn flush_referenced_nodes() {
+        let mut merkle = create_test_merkle();
+        let sentinel_addr = merkle.init_sentinel().unwrap();
+        merkle.insert([1], vec![1], sentinel_addr).unwrap();
+
+        let value = merkle.get([1], sentinel_addr).unwrap();
+        assert!(matches!(merkle.flush_dirty(), Err(MerkleError::NodesInUse)));
+        drop(value);
+        merkle.flush_dirty().unwrap();
+    }
+
+    #[test]
+    fn delayed_allocation() {
+        let mut eager = create_test_merkle();
+        let eager_sentinel = eager.init_sentinel().unwrap();
+        let mut delayed = create_test_merkle().with_delayed_allocation(true);
+        let sentinel = delayed.init_sentinel().unwrap();
+        assert!(!sentinel.is_deferred());
+
+        // overwritten with larger values, some nodes are moved, and the others are deleted
+        for (merkle, sentinel) in [(&mut eager, eager_sentinel), (&mut delayed, sentinel)] {
+            for i in 0..40u8 {
+                merkle.insert([i, i], vec![i; 40], sentinel).unwrap();
+            }
+            for i in (0..40u8).step_by(3) {
+                merkle.remove([i, i], sentinel).unwrap();
+            }
+            for i in (0..40u8).step_by(5) {
+                merkle.insert([i, i], vec![i; 80], sentinel).unwrap();
+            }
+        }
+
+        let root_hash = eager.root_hash(eager_sentinel).unwrap();
+        assert_eq!(delayed.root_hash(sentinel).unwrap(), root_hash);
+        delayed.flush_dirty().unwrap();
+        assert_eq!(delayed.root_hash(sentinel).unwrap(), root_hash);
+        assert_eq!(
+            delayed.get([10, 10], sentinel).unwrap().as_deref(),
+            Some(&[10; 80][..])
+        );
+        assert!(delayed.get([3, 3], sentinel).unwrap().is_none());
+
+        // the nodes that are left are allocated next to each other, depth first from the root
+        let mut addrs = Vec::new();
+        let mut stack = vec![sentinel];
+        while let Some(addr) = stack.pop() {
+            let node = delayed.get_node(addr).unwrap();
+            if addr != sentinel {
+                addrs.push((addr, shale::Storable::serialized_len(&**node)));
+            }
+            if let NodeType::Branch(n) = node.inner() {
+                stack.extend(n.chd().iter().rev().flatten());
+            }
+        }
+        assert!(addrs.len() > 20);
+        for pair in addrs.windows(2) {
+            let ((addr, size), (next, _)) = (pair[0], pair[1]);
+            assert_eq!(
+                next.get() as u64,
+                addr.get() as u64 + size + shale::compact::CHUNK_OVERHEAD
+            );
+        }
+    }
+
+    #[test]
+    fn insert_prefix_of_branch_path() {
+        let mut merkle = create_test_merkle();
+        let sentinel_addr = merkle.init_sentinel().unwrap();
+
+        // the root branch has the path 1, and its child 1 is a branch with the path 000
+        let keys: [&[u8]; 3] = [&[0x10, 0x00], &[0x11, 0x00, 0x00], &[0x11, 0x00, 0x01]];
+        for key in keys {
+            merkle.insert(key, key.to_vec(), sentinel_addr).unwrap();
+        }
+
+        // the key ends where the path of the child branch starts, which is split
+        merkle.insert([0x11], vec![0x11], sentinel_addr).unwrap();
+        for key in keys.into_iter().chain([&[0x11][..]]) {
+            let fetched_val = merkle.get(key, sentinel_addr).unwrap();
+            assert_eq!(fetched_val.as_deref(), Some(key));
+        }
+
+        // a branch without a value doesn't hold a key
+        merkle.remove([0x11], sentinel_addr).unwrap();
+        assert!(merkle.get([0x11], sentinel_addr).unwrap().is_none());
+        assert!(merkle.get([0x11, 0x00], sentinel_addr).unwrap().is_none());
+    }
+
     #[test]
     fn long_insert_and_retrieve_multiple() {
         let key_val: Vec<(&'static [u8], _)> = vec![
@@ -1697,6 +2541,101 @@ mod tests {
         }
     }
 
+    #[test]
+    fn remove_prefix() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut items: Vec<(Vec<u8>, Vec<u8>)> = (0..256)
+            .map(|_| {
+                let key = (0..rng.gen_range(0..4))
+                    .map(|_| rng.gen_range(0..4))
+                    .collect();
+                let value = (0..rng.gen_range(1..16)).map(|_| rng.gen()).collect();
+                (key, value)
+            })
+            .collect();
+        items.sort();
+        items.dedup_by(|(a, _), (b, _)| a == b);
+
+        for prefix in [&[][..], &[1], &[2, 3], &[0, 1, 2], &[0, 1, 2, 3, 0], &[9]] {
+            let mut merkle = create_test_merkle().with_inline_value_threshold(8);
+            let sentinel_addr = merkle.init_sentinel().unwrap();
+            for (key, value) in &items {
+                merkle.insert(key, value.clone(), sentinel_addr).unwrap();
+            }
+
+            let mut dropped = create_test_merkle().with_inline_value_threshold(8);
+            let dropped_sentinel = dropped.init_sentinel().unwrap();
+            for (key, value) in &items {
+                dropped
+                    .insert(key, value.clone(), dropped_sentinel)
+                    .unwrap();
+            }
+
+            let mut removed = Vec::new();
+            let subtree = merkle
+                .remove_prefix(prefix, sentinel_addr, |key, value| {
+                    removed.push((key.to_vec(), value.to_vec()))
+                })
+                .unwrap();
+            removed.sort();
+            let mut roots: Vec<_> = match subtree {
+                Some(Child::Node(root)) => vec![root],
+                _ => Vec::new(),
+            };
+            // the subtree is freed a node at a time
+            while !roots.is_empty() {
+                assert_eq!(merkle.reclaim(&mut roots, 1, |_| ()).unwrap(), 1);
+            }
+
+            // the values of the dropped keys are those of the inline leaf, or of the nodes freed
+            let mut freed = Vec::new();
+            let mut roots = match dropped.drop_prefix(prefix, dropped_sentinel).unwrap() {
+                Some(Child::Node(root)) => vec![root],
+                Some(Child::Inline(leaf)) => {
+                    freed.push(leaf.value.to_vec());
+                    Vec::new()
+                }
+                None => Vec::new(),
+            };
+            dropped
+                .reclaim(&mut roots, usize::MAX, |value| freed.push(value.to_vec()))
+                .unwrap();
+            assert!(roots.is_empty());
+            assert_eq!(
+                dropped.root_hash(dropped_sentinel).unwrap(),
+                merkle.root_hash(sentinel_addr).unwrap()
+            );
+
+            let (expected, kept): (Vec<_>, Vec<_>) = items
+                .iter()
+                .cloned()
+                .partition(|(key, _)| key.starts_with(prefix));
+            assert_eq!(removed, expected);
+            freed.sort();
+            let mut expected_values: Vec<_> =
+                expected.into_iter().map(|(_, value)| value).collect();
+            expected_values.sort();
+            assert_eq!(freed, expected_values);
+
+            // the trie is the same as one that never had the keys
+            let mut other = create_test_merkle();
+            let other_sentinel = other.init_sentinel().unwrap();
+            for (key, value) in &kept {
+                other.insert(key, value.clone(), other_sentinel).unwrap();
+            }
+            assert_eq!(
+                merkle.root_hash(sentinel_addr).unwrap(),
+                other.root_hash(other_sentinel).unwrap()
+            );
+            for (key, value) in &kept {
+                let fetched = merkle.get(key, sentinel_addr).unwrap();
+                assert_eq!(fetched.as_deref(), Some(value.as_slice()));
+            }
+        }
+    }
+
     #[test]
     fn get_empty_proof() {
         let merkle = create_test_merkle();
@@ -1712,11 +2651,19 @@ mod tests {
         let merkle = create_test_merkle();
         let sentinel_addr = merkle.init_sentinel().unwrap();
 
-        assert!(merkle
-            .range_proof::<&[u8]>(sentinel_addr, None, None, None)
-            .await
-            .unwrap()
-            .is_none());
+        // the empty trie is proven by the empty range proof, whatever the range
+        for (first_key, last_key) in [(None, None), (Some(&[0x01][..]), Some(&[0x02][..]))] {
+            let proof = merkle
+                .range_proof::<&[u8]>(sentinel_addr, first_key, last_key, None)
+                .await
+                .unwrap();
+            assert_eq!(proof, Some(api::RangeProof::empty()));
+            let proof = merkle
+                .range_proof_rev::<&[u8]>(sentinel_addr, first_key, last_key, None)
+                .await
+                .unwrap();
+            assert_eq!(proof, Some(api::RangeProof::empty()));
+        }
     }
 
     #[tokio::test]
@@ -1750,7 +2697,7 @@ mod tests {
 
             merkle.insert(key, val.to_vec(), sentinel_addr).unwrap();
         }
-        merkle.flush_dirty();
+        merkle.flush_dirty().unwrap();
 
         let rangeproof = merkle
             .range_proof::<&[u8]>(sentinel_addr, None, None, None)
@@ -1778,7 +2725,7 @@ mod tests {
 
             merkle.insert(key, val.to_vec(), sentinel_addr).unwrap();
         }
-        merkle.flush_dirty();
+        merkle.flush_dirty().unwrap();
 
         let rangeproof = merkle
             .range_proof(sentinel_addr, Some([RANDOM_KEY]), None, Some(1))
@@ -1789,6 +2736,97 @@ mod tests {
         assert_eq!(rangeproof.middle.len(), 1);
     }
 
+    #[tokio::test]
+    async fn reverse_range_proof() {
+        let mut merkle = create_test_merkle();
+        let sentinel_addr = merkle.init_sentinel().unwrap();
+        // insert values
+        for key_val in (u8::MIN..=u8::MAX).step_by(2) {
+            merkle
+                .insert([key_val], vec![key_val], sentinel_addr)
+                .unwrap();
+        }
+        merkle.flush_dirty().unwrap();
+
+        // the limit keeps the highest keys of the range, in ascending order
+        let rangeproof = merkle
+            .range_proof_rev(sentinel_addr, Some([10]), Some([201]), Some(3))
+            .await
+            .unwrap()
+            .unwrap();
+        let expected: Vec<_> = [196u8, 198, 200]
+            .into_iter()
+            .map(|key_val| (vec![key_val], vec![key_val]))
+            .collect();
+        assert_eq!(rangeproof.middle, expected);
+        let left_proof = merkle.prove([196], sentinel_addr).unwrap();
+        let right_proof = merkle.prove([200], sentinel_addr).unwrap();
+        assert_eq!(rangeproof.first_key_proof.0, left_proof.0);
+        assert_eq!(rangeproof.last_key_proof.0, right_proof.0);
+
+        // without a limit, the proof stops at the first key
+        let rangeproof = merkle
+            .range_proof_rev(sentinel_addr, Some([9]), None, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rangeproof.middle.len(), 123);
+        assert_eq!(rangeproof.middle.first().unwrap().0, [10]);
+        assert_eq!(rangeproof.middle.last().unwrap().0, [254]);
+
+        // nothing in the range
+        let rangeproof = merkle
+            .range_proof_rev(sentinel_addr, Some([11]), Some([11]), None)
+            .await
+            .unwrap();
+        assert!(rangeproof.is_none());
+    }
+
+    #[tokio::test]
+    async fn verify_range_proof() {
+        let mut merkle = create_test_merkle();
+        let sentinel_addr = merkle.init_sentinel().unwrap();
+
+        // the empty trie is proven by the empty range proof
+        let empty = api::RangeProof::<Vec<u8>, Vec<u8>>::empty();
+        assert!(!empty.verify(EMPTY_ROOT_HASH.0).unwrap());
+
+        for key_val in (u8::MIN..=u8::MAX).step_by(2) {
+            merkle
+                .insert([key_val; 4], vec![key_val; 20], sentinel_addr)
+                .unwrap();
+        }
+        merkle.flush_dirty().unwrap();
+        let root_hash = merkle.root_hash(sentinel_addr).unwrap().0;
+
+        // a range proof is verified against the root hash alone
+        let mut rangeproof = merkle
+            .range_proof(sentinel_addr, Some([10; 4]), Some([20; 4]), None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(rangeproof.verify(root_hash).unwrap());
+        let last = merkle
+            .range_proof(sentinel_addr, Some([250; 4]), None, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!last.verify(root_hash).unwrap());
+
+        // a pair left out of the range, or changed, doesn't rebuild the root hash
+        let (key, _) = rangeproof.middle.remove(1);
+        assert!(matches!(
+            rangeproof.verify(root_hash),
+            Err(ProofError::RangeMismatch)
+        ));
+        rangeproof.middle.insert(1, (key, vec![0; 20]));
+        assert!(matches!(
+            rangeproof.verify(root_hash),
+            Err(ProofError::RangeMismatch)
+        ));
+        assert!(empty.verify(root_hash).is_err());
+    }
+
     #[test]
     fn shared_path_proof() {
         let mut merkle = create_test_merkle();
@@ -2135,6 +3173,8 @@ mod tests {
             children: Default::default(),
             value: Some(value.clone()),
             children_encoded: Default::default(),
+            inline_children: Default::default(),
+            inline_encoded: Default::default(),
         });
 
         check_node_update(node, double_path, value)
@@ -2156,6 +3196,8 @@ mod tests {
             children: Default::default(),
             value: Some(value),
             children_encoded: Default::default(),
+            inline_children: Default::default(),
+            inline_encoded: Default::default(),
         });
 
         check_node_update(node, path, double_value)
@@ -2180,7 +3222,7 @@ mod tests {
             node.rehash();
         });
 
-        assert!(matches!(write_result, Err(ObjWriteSizeError)));
+        assert!(matches!(write_result, Err(ObjWriteError::Size(_))));
 
         let mut to_delete = vec![];
         // could be any branch node, convenient to use the root.
@@ -2205,4 +3247,264 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn verified_reads_of_intact_trie() {
+        let mut merkle = create_test_merkle().with_hash_verification(true);
+        let sentinel_addr = merkle.init_sentinel().unwrap();
+
+        for key_val in u8::MIN..=u8::MAX {
+            let key = vec![key_val, key_val];
+            merkle.insert(&key, key.clone(), sentinel_addr).unwrap();
+            // compute the hashes so that later reads have something to verify
+            merkle.root_hash(sentinel_addr).unwrap();
+        }
+
+        for key_val in (u8::MIN..=u8::MAX).step_by(2) {
+            let key = vec![key_val, key_val];
+            assert_eq!(merkle.remove(&key, sentinel_addr).unwrap(), Some(key));
+            merkle.root_hash(sentinel_addr).unwrap();
+        }
+
+        for key_val in u8::MIN..=u8::MAX {
+            let key = vec![key_val, key_val];
+            let fetched_val = merkle.get(&key, sentinel_addr).unwrap();
+            let expected = (key_val % 2 == 1).then_some(key.as_slice());
+            assert_eq!(fetched_val.as_deref(), expected);
+        }
+    }
+
+    #[test]
+    fn verified_read_of_corrupted_node() {
+        let merkle = create_test_merkle().with_hash_verification(true);
+
+        let bogus_hash = TrieHash([0xab; TRIE_HASH_LEN]);
+        let inner = NodeType::Leaf(LeafNode::new(Path(vec![0x1, 0x2]), vec![0x3]));
+        let node = Node::new_from_hash(Some(bogus_hash), None, None, inner);
+        let addr = merkle.put_node(node).unwrap().as_addr();
+
+        let err = merkle.get_node(addr).unwrap_err();
+        assert!(matches!(
+            err,
+            MerkleError::HashMismatch { ptr, expected, .. } if ptr == addr && expected == bogus_hash
+        ));
+
+        // without verification, the corrupted node is returned as is
+        let merkle = Merkle::<_, Bincode>::new(merkle.store);
+        assert!(merkle.get_node(addr).is_ok());
+    }
+
+    #[test]
+    #[allow(clippy::indexing_slicing)]
+    fn corrupted_node_is_quarantined() {
+        let dir = std::env::temp_dir().join("corrupted_node_is_quarantined");
+        let _ = std::fs::remove_dir_all(&dir);
+        let quarantine = Quarantine::new(dir.clone());
+        let revision = TrieHash([0xcd; TRIE_HASH_LEN]);
+        let mut merkle = create_test_merkle().with_hash_verification(true);
+        merkle.set_quarantine(quarantine.for_revision(Some(revision)));
+
+        let bogus_hash = TrieHash([0xab; TRIE_HASH_LEN]);
+        let inner = NodeType::Leaf(LeafNode::new(Path(vec![0x1, 0x2]), b"value".to_vec()));
+        let node = Node::new_from_hash(Some(bogus_hash), None, None, inner);
+        let addr = merkle.put_node(node).unwrap().as_addr();
+        merkle.flush_dirty().unwrap();
+        assert!(quarantine.health().is_healthy());
+
+        // a node is reported once, however many times it's read
+        merkle.get_node(addr).unwrap_err();
+        merkle.get_node(addr).unwrap_err();
+        let health = quarantine.health();
+        assert_eq!(health.corruptions.len(), 1);
+        assert_eq!(health.suspect_revisions, vec![revision]);
+        assert_eq!(health.unwritten_reports, 0);
+
+        let report = &health.corruptions[0];
+        assert_eq!(report.offset, addr.get());
+        assert_eq!(report.expected, Some(bogus_hash));
+        assert!(report.actual.is_some_and(|actual| actual != bogus_hash));
+        assert!(report.dump_offset < report.offset);
+        assert!(report.bytes.windows(5).any(|bytes| bytes == b"value"));
+
+        let written = std::fs::read_to_string(dir.join(format!(
+            "corruption-{}-{:x}.txt",
+            report.store_id, report.offset
+        )))
+        .unwrap();
+        assert_eq!(written, report.to_string());
+        assert!(written.contains(&format!("revision {revision:?}")));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[allow(clippy::indexing_slicing)]
+    fn inline_children_are_encoded_once() {
+        // a cache large enough for the branch to stay in it
+        let mut merkle =
+            create_generic_test_merkle::<Bincode>(1 << 20).with_inline_value_threshold(8);
+        let sentinel_addr = merkle.init_sentinel().unwrap();
+        for key in [0x10, 0x20, 0x30] {
+            merkle.insert([key], vec![key; 4], sentinel_addr).unwrap();
+        }
+        merkle.root_hash(sentinel_addr).unwrap();
+
+        let cached = |merkle: &Merkle<_, _>| {
+            let sentinel = merkle.get_node(sentinel_addr).unwrap();
+            let root = sentinel.inner().as_branch().unwrap().chd()[0].unwrap();
+            let root = merkle.get_node(root).unwrap();
+            let branch = root.inner().as_branch().unwrap();
+            let positions = |filter: &dyn Fn(usize) -> bool| {
+                (0..BranchNode::MAX_CHILDREN)
+                    .filter(|&i| filter(i))
+                    .collect::<Vec<_>>()
+            };
+            (
+                positions(&|i| branch.inline_children[i].is_some()),
+                positions(&|i| branch.inline_encoded.is_cached(i)),
+            )
+        };
+        // the first leaf was split off the root before the branch was made, it's stored apart
+        assert_eq!(cached(&merkle), (vec![2, 3], vec![2, 3]));
+
+        // the branch is rehashed when its stored child changes, without encoding the others
+        merkle.insert([0x10], vec![1; 4], sentinel_addr).unwrap();
+        assert_eq!(cached(&merkle), (vec![2, 3], vec![2, 3]));
+        let root_hash = merkle.root_hash(sentinel_addr).unwrap();
+
+        // and an inline child that changes is encoded again
+        merkle.insert([0x20], vec![2; 4], sentinel_addr).unwrap();
+        assert_eq!(cached(&merkle), (vec![2, 3], vec![3]));
+        assert_ne!(merkle.root_hash(sentinel_addr).unwrap(), root_hash);
+        assert_eq!(cached(&merkle), (vec![2, 3], vec![2, 3]));
+
+        let mut other = create_test_merkle();
+        let other_sentinel = other.init_sentinel().unwrap();
+        for (key, value) in [(0x10, 1), (0x20, 2), (0x30, 0x30)] {
+            other.insert([key], vec![value; 4], other_sentinel).unwrap();
+        }
+        assert_eq!(
+            merkle.root_hash(sentinel_addr).unwrap(),
+            other.root_hash(other_sentinel).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn inline_values() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        fn check(
+            (stored, stored_sentinel): (&Merkle<InMemLinearStore, Bincode>, DiskAddress),
+            (inline, inline_sentinel): (&Merkle<InMemLinearStore, Bincode>, DiskAddress),
+            items: &[(Vec<u8>, Option<Vec<u8>>)],
+        ) {
+            // inlining doesn't change the trie, only where its leaves are stored
+            assert_eq!(
+                stored.root_hash(stored_sentinel).unwrap(),
+                inline.root_hash(inline_sentinel).unwrap()
+            );
+
+            for (key, value) in items {
+                let fetched = inline.get(key, inline_sentinel).unwrap();
+                assert_eq!(fetched.as_deref(), value.as_deref());
+            }
+        }
+
+        let mut stored = create_test_merkle();
+        let stored_sentinel = stored.init_sentinel().unwrap();
+        let mut inline = create_test_merkle().with_inline_value_threshold(8);
+        let inline_sentinel = inline.init_sentinel().unwrap();
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut items: Vec<(Vec<u8>, Option<Vec<u8>>)> = (0..128)
+            .map(|_| {
+                let key = (0..rng.gen_range(1..4)).map(|_| rng.gen()).collect();
+                let value = (0..rng.gen_range(1..16)).map(|_| rng.gen()).collect();
+                (key, Some(value))
+            })
+            .collect();
+        items.sort();
+        items.dedup_by(|(a, _), (b, _)| a == b);
+
+        for (key, value) in &items {
+            let value = value.clone().unwrap();
+            stored.insert(key, value.clone(), stored_sentinel).unwrap();
+            inline.insert(key, value, inline_sentinel).unwrap();
+        }
+        check(
+            (&stored, stored_sentinel),
+            (&inline, inline_sentinel),
+            &items,
+        );
+
+        let ninline = inline
+            .node_iter(inline_sentinel)
+            .filter(|node| ready(matches!(node, Ok((_, NodeRef::Inline(_))))))
+            .count()
+            .await;
+        assert!(ninline > 0);
+
+        let kvs: Vec<_> = inline
+            .key_value_iter(inline_sentinel)
+            .map(|kv| kv.map(|(key, value)| (key.into_vec(), Some(value))))
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(kvs, items);
+
+        let root_hash = inline.root_hash(inline_sentinel).unwrap();
+        for (key, value) in &items {
+            let proof = inline.prove(key, inline_sentinel).unwrap();
+            assert_eq!(&proof.verify(key, *root_hash).unwrap(), value);
+        }
+
+        // short values become long ones and the other way around
+        for (key, value) in &mut items {
+            let long = value.as_ref().unwrap().len() <= 8;
+            let new_value = vec![key[0]; if long { 12 } else { 4 }];
+            stored
+                .insert(&key, new_value.clone(), stored_sentinel)
+                .unwrap();
+            inline
+                .insert(&key, new_value.clone(), inline_sentinel)
+                .unwrap();
+            *value = Some(new_value);
+        }
+        check(
+            (&stored, stored_sentinel),
+            (&inline, inline_sentinel),
+            &items,
+        );
+
+        for (key, value) in items.iter_mut().step_by(3) {
+            inline
+                .get_mut(&key, inline_sentinel)
+                .unwrap()
+                .unwrap()
+                .write(|value| value.push(0))
+                .unwrap();
+            stored
+                .get_mut(&key, stored_sentinel)
+                .unwrap()
+                .unwrap()
+                .write(|value| value.push(0))
+                .unwrap();
+            value.as_mut().unwrap().push(0);
+        }
+        check(
+            (&stored, stored_sentinel),
+            (&inline, inline_sentinel),
+            &items,
+        );
+
+        for (key, value) in items.iter_mut().step_by(2) {
+            assert_eq!(&stored.remove(&key, stored_sentinel).unwrap(), value);
+            assert_eq!(&inline.remove(&key, inline_sentinel).unwrap(), value);
+            *value = None;
+        }
+        check(
+            (&stored, stored_sentinel),
+            (&inline, inline_sentinel),
+            &items,
+        );
+    }
 }
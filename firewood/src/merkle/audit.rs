@@ -0,0 +1,215 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Finds the nodes of a trie whose shape the trie operations never leave behind, such as the ones
+//! written by old bugs or partial writes.
+//!
+//! Inserting and removing keep every branch below the sentinel with either a value or at least
+//! two children, and with at least one child. A branch without a value and a single child
+//! should have been merged with its child into one node with the longer partial path; a branch
+//! with a value and no children should have been a leaf. Such nodes still hold the right keys
+//! and values, but not the same hashes as the normalized trie with those keys and values.
+//!
+//! Repairing a trie rebuilds the smallest subtrees that hold the degenerate nodes and are rooted
+//! at a byte boundary of the keys, see [subtree_prefixes], by removing their keys and inserting
+//! them again.
+
+use super::{nibbles_to_bytes_iter, Child, Merkle, MerkleError, NodeType};
+use crate::shale::{disk_address::DiskAddress, LinearStore};
+
+/// How a degenerate node differs from the shape the trie operations leave behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Degeneracy {
+    /// A branch without a value and with a single child, which should be merged into its child.
+    SingleChild,
+    /// A branch with a value and no children, which should be a leaf.
+    Childless,
+    /// A branch with neither a value nor children, which should not be there at all.
+    Empty,
+}
+
+/// A node of a trie with a degenerate shape, see [Merkle::audit].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DegenerateNode {
+    /// The nibbles from the root to the node, not counting its partial path.
+    pub path: Vec<u8>,
+    pub degeneracy: Degeneracy,
+}
+
+impl<S: LinearStore, T> Merkle<S, T> {
+    /// Returns the degenerate nodes of the trie, in key order. Every node of the trie is read.
+    pub fn audit(&self, sentinel_addr: DiskAddress) -> Result<Vec<DegenerateNode>, MerkleError> {
+        let mut degenerate = Vec::new();
+        let sentinel = self.get_node(sentinel_addr)?;
+        let Some(root) = sentinel
+            .inner
+            .as_branch()
+            .ok_or(MerkleError::NotBranchNode)?
+            .children[0]
+        else {
+            return Ok(degenerate);
+        };
+        drop(sentinel);
+
+        let mut stack = vec![(Child::Node(root), Vec::new())];
+        while let Some((child, path)) = stack.pop() {
+            let node = self.get_child(child)?;
+            let NodeType::Branch(branch) = &node.inner else {
+                continue;
+            };
+
+            let children = branch.children_iter().count();
+            let degeneracy = match (children, &branch.value) {
+                (0, None) => Some(Degeneracy::Empty),
+                (0, Some(_)) => Some(Degeneracy::Childless),
+                (1, None) => Some(Degeneracy::SingleChild),
+                _ => None,
+            };
+
+            let mut full_path = path.clone();
+            full_path.extend(branch.partial_path.iter());
+            // in reverse, so that the children are walked in key order
+            for (index, child) in branch.children_iter().rev() {
+                let mut child_path = full_path.clone();
+                child_path.push(index);
+                stack.push((child, child_path));
+            }
+
+            if let Some(degeneracy) = degeneracy {
+                degenerate.push(DegenerateNode { path, degeneracy });
+            }
+        }
+
+        Ok(degenerate)
+    }
+}
+
+/// The key prefixes whose keys are to be removed and inserted again to normalize the trie with
+/// `degenerate` nodes: the whole bytes of the path to each node, leaving out the ones under
+/// another prefix.
+pub fn subtree_prefixes(degenerate: &[DegenerateNode]) -> Vec<Vec<u8>> {
+    let mut prefixes: Vec<Vec<u8>> = degenerate
+        .iter()
+        .map(|node| {
+            let whole_bytes = node.path.len() / 2 * 2;
+            #[allow(clippy::indexing_slicing)]
+            nibbles_to_bytes_iter(&node.path[..whole_bytes]).collect()
+        })
+        .collect();
+    prefixes.sort();
+    // once sorted, a prefix comes right before the ones it covers
+    prefixes.dedup_by(|prefix, covering| prefix.starts_with(covering));
+    prefixes
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod tests {
+    use super::super::tests::create_test_merkle;
+    use super::*;
+
+    /// Removes the keys under each prefix of `degenerate` and inserts them again.
+    fn repair<S: LinearStore>(
+        merkle: &mut Merkle<S, crate::merkle::Bincode>,
+        sentinel_addr: DiskAddress,
+        degenerate: &[DegenerateNode],
+    ) {
+        for prefix in subtree_prefixes(degenerate) {
+            let mut removed = Vec::new();
+            let subtree = merkle
+                .remove_prefix(&prefix, sentinel_addr, |key, value| {
+                    removed.push((key.to_vec(), value.to_vec()))
+                })
+                .unwrap();
+            if let Some(Child::Node(root)) = subtree {
+                merkle.reclaim(&mut vec![root], usize::MAX, |_| ()).unwrap();
+            }
+            for (key, value) in removed {
+                merkle.insert(key, value, sentinel_addr).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn audit_and_repair() {
+        let keys: [&[u8]; 4] = [&[0x12], &[0x12, 0x34], &[0x56, 0x78], &[0x56, 0x79]];
+        let mut merkle = create_test_merkle();
+        let sentinel_addr = merkle.init_sentinel().unwrap();
+        for key in keys {
+            merkle.insert(key, key.to_vec(), sentinel_addr).unwrap();
+        }
+        assert!(merkle.audit(sentinel_addr).unwrap().is_empty());
+
+        // detach the first child of the branches at 0x12 and 0x567, under the root at nibbles 1
+        // and 5, as an old bug could have
+        let sentinel = merkle.get_node(sentinel_addr).unwrap();
+        let root = sentinel.inner.as_branch().unwrap().children[0].unwrap();
+        let root = merkle.get_node(root).unwrap();
+        let branches = [1, 5].map(|index| root.inner.as_branch().unwrap().children[index]);
+        drop((sentinel, root));
+        for branch in branches {
+            let mut node = merkle.get_node(branch.unwrap()).unwrap();
+            node.write(|node| {
+                let branch = node.inner_mut().as_branch_mut().unwrap();
+                let index = branch.children_iter().next().unwrap().0;
+                branch.set_child(index, None);
+                node.rehash();
+            })
+            .unwrap();
+        }
+
+        let degenerate = merkle.audit(sentinel_addr).unwrap();
+        assert_eq!(
+            degenerate,
+            [
+                DegenerateNode {
+                    path: vec![1],
+                    degeneracy: Degeneracy::Childless,
+                },
+                DegenerateNode {
+                    path: vec![5],
+                    degeneracy: Degeneracy::SingleChild,
+                },
+            ]
+        );
+        assert_eq!(subtree_prefixes(&degenerate), [vec![]]);
+
+        repair(&mut merkle, sentinel_addr, &degenerate);
+        assert!(merkle.audit(sentinel_addr).unwrap().is_empty());
+
+        // the same keys and values, and the hash of a trie built from them
+        let mut expected = create_test_merkle();
+        let expected_sentinel_addr = expected.init_sentinel().unwrap();
+        for key in [&[0x12][..], &[0x56, 0x79]] {
+            assert_eq!(
+                merkle.get(key, sentinel_addr).unwrap().as_deref(),
+                Some(key)
+            );
+            expected
+                .insert(key, key.to_vec(), expected_sentinel_addr)
+                .unwrap();
+        }
+        assert_eq!(
+            merkle.root_hash(sentinel_addr).unwrap(),
+            expected.root_hash(expected_sentinel_addr).unwrap()
+        );
+    }
+
+    #[test]
+    fn prefixes() {
+        let node = |path: Vec<u8>| DegenerateNode {
+            path,
+            degeneracy: Degeneracy::SingleChild,
+        };
+        let degenerate = [
+            node(vec![1, 2, 3]),
+            node(vec![1, 2, 3, 4, 5]),
+            node(vec![6, 7, 8, 9]),
+            node(vec![1, 3]),
+        ];
+        assert_eq!(
+            subtree_prefixes(&degenerate),
+            [vec![0x12], vec![0x13], vec![0x67, 0x89]]
+        );
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Locates where two tries diverge, to find out why a root hash isn't the expected one.
+//!
+//! Both tries are walked together from the root, following the nodes at the same nibble path.
+//! A subtree whose root has the same hash as the node at its path in the other trie is the same
+//! in both, and is skipped, so the walk is proportional to the size of the difference rather
+//! than to the size of the tries.
+//!
+//! The same walk tells whether a trie is a subset of another: a subtree that is the same in
+//! both holds nothing the other trie is missing, and the values of the other nodes are looked up
+//! in the other trie, stopping at the first one it doesn't have.
+
+use super::{nibbles_to_bytes_iter, Child, Merkle, MerkleError, NodeRef, NodeType, TrieHash};
+use crate::shale::{disk_address::DiskAddress, LinearStore};
+
+/// A node whose hash differs from the one of the node at the same path of another trie, or that
+/// the other trie doesn't have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DivergingNode {
+    /// The nibbles from the root to the node, not counting its partial path.
+    pub path: Vec<u8>,
+    /// The hash of the node.
+    pub hash: TrieHash,
+    /// The hash of the node at the same path of the other trie, if it has one.
+    pub other_hash: Option<TrieHash>,
+    /// The key of the value the node holds, if it holds one.
+    pub key: Option<Vec<u8>>,
+}
+
+/// A node of the other trie whose path is a prefix of the path being walked.
+#[derive(Clone)]
+struct Candidate {
+    child: Child,
+    path: Vec<u8>,
+}
+
+impl<S: LinearStore, T> Merkle<S, T> {
+    /// Returns the nodes of this trie that differ from `other`, in key order, along with all
+    /// their ancestors, which differ as well. At most `limit` nodes are returned.
+    pub(crate) fn diverging_nodes<S2: LinearStore, T2>(
+        &self,
+        sentinel_addr: DiskAddress,
+        other: &Merkle<S2, T2>,
+        other_sentinel_addr: DiskAddress,
+        limit: usize,
+    ) -> Result<Vec<DivergingNode>, MerkleError> {
+        let mut diverging = Vec::new();
+        let Some(root) = self.root_child(sentinel_addr)? else {
+            return Ok(diverging);
+        };
+        let other_root = other
+            .root_child(other_sentinel_addr)?
+            .map(|child| Candidate {
+                child,
+                path: Vec::new(),
+            });
+
+        let mut stack = vec![(root, Vec::new(), other_root)];
+        while let Some((child, path, candidate)) = stack.pop() {
+            if diverging.len() >= limit {
+                break;
+            }
+
+            let node = self.get_child(child)?;
+            let hash = *node.get_root_hash(&self.store);
+
+            let (other_node, candidate) = match candidate {
+                Some(candidate) => other.node_at(candidate, &path)?,
+                None => (None, None),
+            };
+            let other_hash = other_node.map(|node| *node.get_root_hash(&other.store));
+            if other_hash == Some(hash) {
+                continue;
+            }
+
+            let (partial_path, value, branch) = match &node.inner {
+                NodeType::Leaf(leaf) => (&leaf.partial_path, Some(&leaf.value), None),
+                NodeType::Branch(branch) => {
+                    (&branch.partial_path, branch.value.as_ref(), Some(branch))
+                }
+            };
+            let full_path: Vec<u8> = path.iter().chain(partial_path.iter()).copied().collect();
+            let key = value
+                .filter(|_| full_path.len().is_multiple_of(2))
+                .map(|_| nibbles_to_bytes_iter(&full_path).collect());
+
+            diverging.push(DivergingNode {
+                path,
+                hash,
+                other_hash,
+                key,
+            });
+
+            // in reverse, so that the children are walked in key order
+            if let Some(branch) = branch {
+                for (index, child) in branch.children_iter().rev() {
+                    let mut child_path = full_path.clone();
+                    child_path.push(index);
+                    stack.push((child, child_path, candidate.clone()));
+                }
+            }
+        }
+
+        Ok(diverging)
+    }
+
+    /// Whether this trie holds the same keys and values as `other`, which only takes comparing
+    /// their root hashes, since the root hash commits to the whole trie.
+    pub fn equal<S2: LinearStore>(
+        &self,
+        sentinel_addr: DiskAddress,
+        other: &Merkle<S2, T>,
+        other_sentinel_addr: DiskAddress,
+    ) -> Result<bool, MerkleError> {
+        Ok(self.root_hash(sentinel_addr)? == other.root_hash(other_sentinel_addr)?)
+    }
+
+    /// Whether every key of this trie is in `other` with the same value, such as a trie
+    /// reconstructed from part of `other`. Stops at the first key that isn't.
+    pub fn is_subset<S2: LinearStore>(
+        &self,
+        sentinel_addr: DiskAddress,
+        other: &Merkle<S2, T>,
+        other_sentinel_addr: DiskAddress,
+    ) -> Result<bool, MerkleError> {
+        let Some(root) = self.root_child(sentinel_addr)? else {
+            return Ok(true);
+        };
+        let other_root = other
+            .root_child(other_sentinel_addr)?
+            .map(|child| Candidate {
+                child,
+                path: Vec::new(),
+            });
+
+        let mut stack = vec![(root, Vec::new(), other_root)];
+        while let Some((child, path, candidate)) = stack.pop() {
+            let node = self.get_child(child)?;
+
+            let (other_node, candidate) = match candidate {
+                Some(candidate) => other.node_at(candidate, &path)?,
+                None => (None, None),
+            };
+            if let Some(other_node) = other_node {
+                if other_node.get_root_hash(&other.store) == node.get_root_hash(&self.store) {
+                    continue;
+                }
+            }
+
+            let (partial_path, value, branch) = match &node.inner {
+                NodeType::Leaf(leaf) => (&leaf.partial_path, Some(&leaf.value), None),
+                NodeType::Branch(branch) => {
+                    (&branch.partial_path, branch.value.as_ref(), Some(branch))
+                }
+            };
+            let full_path: Vec<u8> = path.iter().chain(partial_path.iter()).copied().collect();
+            if let Some(value) = value {
+                let key: Vec<u8> = nibbles_to_bytes_iter(&full_path).collect();
+                let other_value = other.get(key, other_sentinel_addr)?;
+                if other_value.as_deref() != Some(value.as_slice()) {
+                    return Ok(false);
+                }
+            }
+
+            if let Some(branch) = branch {
+                for (index, child) in branch.children_iter() {
+                    let mut child_path = full_path.clone();
+                    child_path.push(index);
+                    stack.push((child, child_path, candidate.clone()));
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    pub(super) fn root_child(
+        &self,
+        sentinel_addr: DiskAddress,
+    ) -> Result<Option<Child>, MerkleError> {
+        let sentinel = self.get_node(sentinel_addr)?;
+        let root = sentinel
+            .inner
+            .as_branch()
+            .ok_or(MerkleError::NotBranchNode)?
+            .children[0];
+        Ok(root.map(Child::Node))
+    }
+
+    /// Descends from `candidate` to the node at `path`. Returns that node if there is one, and
+    /// the deepest node on the way, which is where the descendants of `path` are looked up from.
+    fn node_at(
+        &self,
+        mut candidate: Candidate,
+        path: &[u8],
+    ) -> Result<(Option<NodeRef<'_>>, Option<Candidate>), MerkleError> {
+        loop {
+            let node = self.get_child(candidate.child.clone())?;
+            if candidate.path == path {
+                return Ok((Some(node), Some(candidate)));
+            }
+
+            let next = match &node.inner {
+                NodeType::Branch(branch) => {
+                    let mut full_path = candidate.path.clone();
+                    full_path.extend(branch.partial_path.iter());
+                    path.strip_prefix(full_path.as_slice())
+                        .and_then(|rest| rest.first())
+                        .and_then(|&index| {
+                            full_path.push(index);
+                            branch.child(index).map(|child| Candidate {
+                                child,
+                                path: full_path,
+                            })
+                        })
+                }
+                NodeType::Leaf(_) => None,
+            };
+
+            match next {
+                Some(next) => candidate = next,
+                None => return Ok((None, Some(candidate))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::super::tests::create_test_merkle;
+
+    #[test]
+    fn subset_and_equal() {
+        let mut big = create_test_merkle();
+        let big_sentinel = big.init_sentinel().unwrap();
+        let mut small = create_test_merkle();
+        let small_sentinel = small.init_sentinel().unwrap();
+        let empty = create_test_merkle();
+        let empty_sentinel = empty.init_sentinel().unwrap();
+
+        for i in 0..50u8 {
+            big.insert([i, i * 3], vec![i; 8], big_sentinel).unwrap();
+            if i % 4 == 0 {
+                small
+                    .insert([i, i * 3], vec![i; 8], small_sentinel)
+                    .unwrap();
+            }
+        }
+        // a key that is a prefix of a key only the big trie has
+        big.insert([7], vec![7], big_sentinel).unwrap();
+        small.insert([7], vec![7], small_sentinel).unwrap();
+
+        assert!(small.is_subset(small_sentinel, &big, big_sentinel).unwrap());
+        assert!(!big.is_subset(big_sentinel, &small, small_sentinel).unwrap());
+        assert!(!small.equal(small_sentinel, &big, big_sentinel).unwrap());
+        assert!(empty.is_subset(empty_sentinel, &big, big_sentinel).unwrap());
+        assert!(!big.is_subset(big_sentinel, &empty, empty_sentinel).unwrap());
+
+        // the same keys inserted in another order make the same trie, which is its own subset
+        let mut copy = create_test_merkle();
+        let copy_sentinel = copy.init_sentinel().unwrap();
+        copy.insert([7], vec![7], copy_sentinel).unwrap();
+        for i in (0..50u8).rev() {
+            copy.insert([i, i * 3], vec![i; 8], copy_sentinel).unwrap();
+        }
+        assert!(copy.equal(copy_sentinel, &big, big_sentinel).unwrap());
+        assert!(copy.is_subset(copy_sentinel, &big, big_sentinel).unwrap());
+        assert!(big.is_subset(big_sentinel, &copy, copy_sentinel).unwrap());
+
+        // a key with another value is missing
+        small.insert([8, 24], vec![0; 8], small_sentinel).unwrap();
+        assert!(!small.is_subset(small_sentinel, &big, big_sentinel).unwrap());
+    }
+}
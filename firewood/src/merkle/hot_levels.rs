@@ -0,0 +1,81 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Moves the nodes of the upper levels of a trie to the hot segment of its store, see
+//! [DbConfig::hot_levels](crate::db::DbConfig::hot_levels).
+//!
+//! Every lookup reads the nodes near the root, while the nodes further down are each read by a
+//! few keys only. Keeping the upper levels together in a segment of their own, away from the
+//! leaves, lets them be kept in memory as a whole, and their files be put on faster media.
+
+use super::{Merkle, MerkleError, NodeType};
+use crate::shale::{compact::StoreHeader, disk_address::DiskAddress, LinearStore, Obj};
+use std::{collections::HashMap, ops::Range};
+
+impl<S: LinearStore, T> Merkle<S, T> {
+    /// Keeps the nodes of the top `levels` levels of the trie in the hot segment of the store,
+    /// of header `header`, see [Merkle::place_hot_levels].
+    pub fn with_hot_levels(
+        mut self,
+        header: Obj<StoreHeader>,
+        levels: usize,
+    ) -> Result<Self, MerkleError> {
+        self.store = self.store.with_hot_segment(header)?;
+        self.hot_levels = levels;
+        Ok(self)
+    }
+
+    /// The range of the payload store the hot segment is in so far, if the trie has one.
+    pub fn hot_segment(&self) -> Option<Range<usize>> {
+        self.store.hot_segment()
+    }
+
+    /// Moves the nodes of the hot levels of the trie of `sentinel_addr` that aren't in the hot
+    /// segment of the store yet into it, and the nodes of the level below them that went down
+    /// out of it, and points their parents at where they moved. The nodes not allocated yet,
+    /// see [DbConfig::delayed_allocation](crate::db::DbConfig::delayed_allocation), are
+    /// allocated first. Returns the number of nodes moved.
+    pub fn place_hot_levels(&mut self, sentinel_addr: DiskAddress) -> Result<usize, MerkleError> {
+        if self.hot_levels == 0 {
+            return Ok(0);
+        }
+        self.store.allocate_deferred()?;
+        let root = match self.get_node(sentinel_addr)?.inner() {
+            NodeType::Branch(sentinel) => sentinel.children[0],
+            NodeType::Leaf(_) => None,
+        };
+        // the nodes of a level, with their parents
+        let mut level: Vec<_> = root.map(|root| (sentinel_addr, root)).into_iter().collect();
+        let mut moved = 0;
+
+        for depth in 0..=self.hot_levels {
+            let hot = depth < self.hot_levels;
+            let mut next = Vec::new();
+            for (parent, addr) in level {
+                let addr = if addr.is_deferred() || self.store.is_hot(addr) == hot {
+                    addr
+                } else {
+                    let new_addr = if hot {
+                        self.store.relocate_hot(addr)?
+                    } else {
+                        self.store.relocate(addr)?
+                    };
+                    self.get_node(parent)?.write(|parent| {
+                        parent.relocate_children(&HashMap::from([(addr, new_addr)]))
+                    })?;
+                    moved += 1;
+                    new_addr
+                };
+                if !hot {
+                    continue;
+                }
+                if let NodeType::Branch(branch) = self.get_node(addr)?.inner() {
+                    next.extend(branch.children.iter().flatten().map(|&child| (addr, child)));
+                }
+            }
+            level = next;
+        }
+
+        Ok(moved)
+    }
+}
@@ -4,7 +4,7 @@
 use crate::{
     logger::trace,
     merkle::nibbles_to_bytes_iter,
-    shale::{compact::Store, disk_address::DiskAddress, LinearStore, ShaleError, Storable},
+    shale::{compact::Store, disk_address::DiskAddress, pool, LinearStore, ShaleError, Storable},
 };
 use bincode::{Error, Options};
 use bitflags::bitflags;
@@ -14,8 +14,8 @@ use serde::{
     ser::{SerializeSeq, SerializeTuple},
     Deserialize, Serialize,
 };
-use sha3::{Digest, Keccak256};
 use std::{
+    collections::HashMap,
     fmt::Debug,
     io::{Cursor, Write},
     marker::PhantomData,
@@ -28,10 +28,15 @@ use std::{
 };
 
 mod branch;
+mod encoding;
 mod leaf;
 mod path;
+mod version;
 
-pub use branch::BranchNode;
+pub use branch::{BranchNode, Child};
+pub use encoding::{
+    BincodeEncoder, CborEncoder, NativeEncoder, NodeEncoder, NodeEncoding, StoredNode,
+};
 pub use leaf::{LeafNode, SIZE as LEAF_NODE_SIZE};
 pub use path::Path;
 
@@ -119,6 +124,8 @@ pub struct Node {
     // Therefore, we can always use Relaxed ordering. It's atomic
     // just to ensure Sync + Send.
     lazy_dirty: AtomicBool,
+    /// The format the node is written to the store in, see [NodeEncoding].
+    pub(super) encoding: NodeEncoding,
     pub(super) inner: NodeType,
 }
 
@@ -133,6 +140,7 @@ impl PartialEq for Node {
             encoded,
             is_encoded_longer_than_hash_len: _,
             lazy_dirty: _,
+            encoding: _,
             inner,
         } = self;
         *root_hash == other.root_hash
@@ -149,6 +157,7 @@ impl Clone for Node {
             is_encoded_longer_than_hash_len: self.is_encoded_longer_than_hash_len.clone(),
             encoded: self.encoded.clone(),
             lazy_dirty: AtomicBool::new(self.is_dirty()),
+            encoding: self.encoding,
             inner: self.inner.clone(),
         }
     }
@@ -162,6 +171,7 @@ impl From<NodeType> for Node {
             is_encoded_longer_than_hash_len: OnceLock::new(),
             inner,
             lazy_dirty: AtomicBool::new(false),
+            encoding: NodeEncoding::default(),
         };
         s.rehash();
         s
@@ -175,6 +185,11 @@ bitflags! {
         const HAS_ROOT_HASH           = 0b001;
         const ENCODED_LENGTH_IS_KNOWN = 0b010;
         const ENCODED_IS_LONG         = 0b110;
+        // the node is written in a standard format, see [NodeEncoding]
+        const ENCODING_BINCODE        = 0b01000;
+        const ENCODING_CBOR           = 0b10000;
+        // the metadata is followed by a version tag, see [version]
+        const VERSIONED               = 0b100000;
     }
 }
 
@@ -192,10 +207,13 @@ impl Node {
                         children: [Some(DiskAddress::null()); BranchNode::MAX_CHILDREN],
                         value: Some(Vec::new()),
                         children_encoded: Default::default(),
+                        inline_children: Default::default(),
+                        inline_encoded: Default::default(),
                     }
                     .into(),
                 ),
                 lazy_dirty: AtomicBool::new(false),
+                encoding: NodeEncoding::default(),
             }
             .serialized_len()
         })
@@ -208,7 +226,7 @@ impl Node {
     pub(super) fn get_root_hash<S: LinearStore>(&self, store: &Store<Node, S>) -> &TrieHash {
         self.root_hash.get_or_init(|| {
             self.set_dirty(true);
-            TrieHash(Keccak256::digest(self.get_encoded(store)).into())
+            TrieHash::of(self.get_encoded(store))
         })
     }
 
@@ -232,6 +250,31 @@ impl Node {
         Self::from(NodeType::Leaf(leaf))
     }
 
+    /// The addresses of the children of this node that aren't allocated yet, see
+    /// [Store::with_delayed_allocation].
+    pub(crate) fn deferred_children(&self) -> impl Iterator<Item = DiskAddress> + '_ {
+        let children: &[Option<DiskAddress>] = match &self.inner {
+            NodeType::Branch(n) => &n.children,
+            NodeType::Leaf(_) => &[],
+        };
+        children
+            .iter()
+            .flatten()
+            .copied()
+            .filter(DiskAddress::is_deferred)
+    }
+
+    /// Points the children of this node that were allocated at their new address.
+    pub(crate) fn relocate_children(&mut self, relocated: &HashMap<DiskAddress, DiskAddress>) {
+        if let NodeType::Branch(n) = &mut self.inner {
+            for child in n.children.iter_mut().flatten() {
+                if let Some(addr) = relocated.get(child) {
+                    *child = *addr;
+                }
+            }
+        }
+    }
+
     pub const fn inner(&self) -> &NodeType {
         &self.inner
     }
@@ -261,6 +304,7 @@ impl Node {
             },
             inner,
             lazy_dirty: AtomicBool::new(false),
+            encoding: NodeEncoding::default(),
         }
     }
 
@@ -284,7 +328,8 @@ impl Node {
 struct Meta {
     root_hash: [u8; TRIE_HASH_LEN],
     attrs: NodeAttributes,
-    encoded_len: u64,
+    /// A little-endian u64, kept as bytes so that the layout doesn't depend on the host.
+    encoded_len: [u8; 8],
     encoded: [u8; TRIE_HASH_LEN],
     type_id: NodeTypeId,
 }
@@ -332,7 +377,7 @@ impl Storable for Node {
                     offset,
                     size: Meta::SIZE as u64,
                 })?;
-        let meta_raw = meta_raw.as_deref();
+        let meta_raw = meta_raw.bytes();
 
         let meta = bytemuck::checked::try_from_bytes::<Meta>(&meta_raw)
             .map_err(|_| ShaleError::InvalidNodeMeta)?;
@@ -344,6 +389,7 @@ impl Storable for Node {
             encoded,
             type_id,
         } = *meta;
+        let encoded_len = u64::from_le_bytes(encoded_len);
 
         trace!("[{mem:p}] Deserializing node at {offset}");
 
@@ -356,7 +402,10 @@ impl Storable for Node {
         };
 
         let encoded = if encoded_len > 0 {
-            Some(encoded.iter().take(encoded_len as usize).copied().collect())
+            let len = encoded.len().min(encoded_len as usize);
+            let mut buf = pool::take(len);
+            buf.extend(encoded.iter().take(len));
+            Some(buf)
         } else {
             None
         };
@@ -368,34 +417,29 @@ impl Storable for Node {
                 None
             };
 
-        match type_id {
-            NodeTypeId::Branch => {
-                let inner = NodeType::Branch(Box::new(BranchNode::deserialize(offset, mem)?));
-
-                Ok(Self::new_from_hash(
-                    root_hash,
-                    encoded,
-                    is_encoded_longer_than_hash_len,
-                    inner,
-                ))
-            }
-
-            NodeTypeId::Leaf => {
-                let inner = NodeType::Leaf(LeafNode::deserialize(offset, mem)?);
-                let node =
-                    Self::new_from_hash(root_hash, encoded, is_encoded_longer_than_hash_len, inner);
+        let encoding = if attrs.contains(NodeAttributes::ENCODING_CBOR) {
+            NodeEncoding::Cbor
+        } else if attrs.contains(NodeAttributes::ENCODING_BINCODE) {
+            NodeEncoding::Bincode
+        } else {
+            NodeEncoding::Native
+        };
+        let offset = if attrs.contains(NodeAttributes::VERSIONED) {
+            version::skip(offset, mem)?
+        } else {
+            offset
+        };
+        let branch = matches!(type_id, NodeTypeId::Branch);
+        let inner = encoding.decode(branch, offset, mem)?;
 
-                Ok(node)
-            }
-        }
+        let mut node =
+            Self::new_from_hash(root_hash, encoded, is_encoded_longer_than_hash_len, inner);
+        node.encoding = encoding;
+        Ok(node)
     }
 
     fn serialized_len(&self) -> u64 {
-        Meta::SIZE as u64
-            + match &self.inner {
-                NodeType::Branch(n) => n.serialized_len(),
-                NodeType::Leaf(n) => n.serialized_len(),
-            }
+        (Meta::SIZE + version::TAG_SIZE) as u64 + self.encoding.encoded_len(&self.inner)
     }
 
     fn serialize(&self, to: &mut [u8]) -> Result<(), ShaleError> {
@@ -406,6 +450,7 @@ impl Storable for Node {
             Some(hash) => (NodeAttributes::HAS_ROOT_HASH, hash.0),
             None => Default::default(),
         };
+        attrs.insert(NodeAttributes::VERSIONED);
 
         let encoded = self
             .encoded
@@ -422,6 +467,12 @@ impl Storable for Node {
             });
         }
 
+        match self.encoding {
+            NodeEncoding::Native => {}
+            NodeEncoding::Bincode => attrs.insert(NodeAttributes::ENCODING_BINCODE),
+            NodeEncoding::Cbor => attrs.insert(NodeAttributes::ENCODING_CBOR),
+        }
+
         let encoded = std::array::from_fn({
             let mut encoded = encoded.into_iter().flatten().copied();
             move |_| encoded.next().unwrap_or(0)
@@ -432,27 +483,27 @@ impl Storable for Node {
         let meta = Meta {
             root_hash,
             attrs,
-            encoded_len,
+            encoded_len: encoded_len.to_le_bytes(),
             encoded,
             type_id,
         };
 
         cursor.write_all(bytemuck::bytes_of(&meta))?;
+        cursor.write_all(&version::encode(version::NODE_VERSION, &[]))?;
 
-        match &self.inner {
-            NodeType::Branch(n) => {
-                let pos = cursor.position() as usize;
-
-                #[allow(clippy::indexing_slicing)]
-                n.serialize(&mut cursor.get_mut()[pos..])
-            }
-
-            NodeType::Leaf(n) => {
-                let pos = cursor.position() as usize;
+        let pos = cursor.position() as usize;
+        #[allow(clippy::indexing_slicing)]
+        self.encoding
+            .encode(&self.inner, &mut cursor.get_mut()[pos..])
+    }
 
-                #[allow(clippy::indexing_slicing)]
-                n.serialize(&mut cursor.get_mut()[pos..])
-            }
+    fn recycle(&mut self) {
+        if let Some(encoded) = self.encoded.take() {
+            pool::recycle(encoded);
+        }
+        match &mut self.inner {
+            NodeType::Branch(branch) => branch.recycle(),
+            NodeType::Leaf(leaf) => leaf.recycle(),
         }
     }
 }
@@ -497,7 +548,7 @@ impl Serialize for EncodedNode<PlainCodec> {
             .filter_map(|(i, c)| c.as_ref().map(|c| (i as u64, c)))
             .map(|(i, c)| {
                 if c.len() >= TRIE_HASH_LEN {
-                    (i, Keccak256::digest(c).to_vec())
+                    (i, TrieHash::of(c).to_vec())
                 } else {
                     (i, c.to_vec())
                 }
@@ -559,7 +610,7 @@ impl Serialize for EncodedNode<Bincode> {
         #[allow(clippy::indexing_slicing)]
         for (i, child) in children {
             if child.len() >= TRIE_HASH_LEN {
-                let serialized_hash = Keccak256::digest(child).to_vec();
+                let serialized_hash = TrieHash::of(child).to_vec();
                 list[i] = serialized_hash;
             } else {
                 list[i] = child.to_vec();
@@ -753,6 +804,8 @@ mod tests {
             children: [Some(DiskAddress::from(1)); BranchNode::MAX_CHILDREN],
             value: Some(vec![1, 2, 3]),
             children_encoded: std::array::from_fn(|_| Some(vec![1])),
+            inline_children: Default::default(),
+            inline_encoded: Default::default(),
         }));
 
         let root_hash = root_hash.into().map(TrieHash);
@@ -855,11 +908,104 @@ mod tests {
             children,
             value,
             children_encoded,
+            inline_children: Default::default(),
+            inline_encoded: Default::default(),
         });
 
         check_node_encoding(node);
     }
 
+    #[test_case(&[0]; "first child")]
+    #[test_case(&[15]; "last child")]
+    #[test_case(&[1, 7, 8, 14]; "several children")]
+    fn branch_with_inline_children(positions: &[u8]) {
+        let mut branch = BranchNode {
+            partial_path: Path(vec![0x1, 0x2]),
+            children: [None; BranchNode::MAX_CHILDREN],
+            value: Some(vec![1, 2, 3]),
+            children_encoded: Default::default(),
+            inline_children: Default::default(),
+            inline_encoded: Default::default(),
+        };
+
+        // a stored child next to the inline ones
+        branch.set_child(3, Some(Child::Node(DiskAddress::from(0x1000))));
+
+        for &pos in positions {
+            let leaf = LeafNode::new(Path(vec![pos]), vec![pos; pos as usize]);
+            branch.set_child(pos, Some(Child::Inline(Box::new(leaf))));
+        }
+
+        check_node_encoding(Node::from_branch(branch));
+    }
+
+    #[test]
+    #[allow(clippy::indexing_slicing)]
+    fn integers_are_little_endian() {
+        let value = vec![9; 0x0102];
+        let leaf = NodeType::Leaf(LeafNode::new(Path(vec![1, 2]), value.clone()));
+        let node = Node::new_from_hash(None, Some(vec![7; 3]), Some(false), leaf);
+
+        let mut bytes = vec![0; node.serialized_len() as usize];
+        node.serialize(&mut bytes).expect("node should serialize");
+
+        // the encoded length follows the root hash and the attributes
+        let encoded_len = TRIE_HASH_LEN + 1;
+        assert_eq!(
+            bytes[encoded_len..encoded_len + 8],
+            [3, 0, 0, 0, 0, 0, 0, 0]
+        );
+        // the leaf follows the meta and the version tag of the node, its value length follows its
+        // path length
+        let value_len = Meta::SIZE + version::TAG_SIZE + 1;
+        assert_eq!(bytes[value_len..value_len + 4], [0x02, 0x01, 0, 0]);
+
+        // the same node written by a host that swaps the bytes of its integers is read back
+        // with other lengths
+        let mut swapped = bytes.clone();
+        swapped[encoded_len..encoded_len + 8].reverse();
+        let mut mem = InMemLinearStore::new(swapped.len() as u64, 0);
+        mem.write(0, &swapped).expect("write should succeed");
+        assert!(Node::deserialize(0, &mem).map_or(true, |read| read != node));
+
+        check_node_encoding(node);
+    }
+
+    #[test]
+    #[allow(clippy::indexing_slicing)]
+    fn version_tags() {
+        let leaf = NodeType::Leaf(LeafNode::new(Path(vec![1, 2]), vec![3; 4]));
+        let node = Node::new_from_hash(None, None, None, leaf);
+        let mut bytes = vec![0; node.serialized_len() as usize];
+        node.serialize(&mut bytes).expect("node should serialize");
+        let (meta, rest) = bytes.split_at(Meta::SIZE);
+        let body = &rest[version::TAG_SIZE..];
+
+        let read = |tag: Option<Vec<u8>>| {
+            let mut meta = meta.to_vec();
+            if tag.is_none() {
+                meta[TRIE_HASH_LEN] &= !NodeAttributes::VERSIONED.bits();
+            }
+            let bytes = [meta, tag.unwrap_or_default(), body.to_vec()].concat();
+            let mut mem = InMemLinearStore::new(bytes.len() as u64, 0);
+            mem.write(0, &bytes).expect("write should succeed");
+            Node::deserialize(0, &mem)
+        };
+
+        // the nodes written before the version tag are read without one
+        assert_eq!(read(None).expect("node should deserialize"), node);
+        // the extensions a node of a later version adds are skipped
+        let tag = version::encode(version::NODE_VERSION + 1, &[(1, &[7; 3]), (2, &[])]);
+        assert_eq!(read(Some(tag)).expect("node should deserialize"), node);
+        // unless they are required to read the node
+        let tag = version::encode(version::NODE_VERSION + 1, &[(version::REQUIRED | 1, &[7])]);
+        assert!(read(Some(tag)).is_err());
+        // or are cut short
+        let mut tag = version::encode(version::NODE_VERSION, &[(1, &[7; 3])]);
+        tag[1] -= 1;
+        assert!(read(Some(tag)).is_err());
+    }
+
     fn check_node_encoding(node: Node) {
         let serialized_len = node.serialized_len();
 
@@ -1,11 +1,11 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
-use super::Node;
+use super::{LeafNode, Node};
 use crate::{
-    merkle::{nibbles_to_bytes_iter, to_nibble_array, Path},
+    merkle::{nibbles_to_bytes_iter, to_nibble_array, Path, TrieHash, TRIE_HASH_LEN},
     nibbles::Nibbles,
-    shale::{compact::Store, DiskAddress, LinearStore, ShaleError, Storable},
+    shale::{compact::Store, pool, DiskAddress, LinearStore, ShaleError, Storable},
 };
 use bincode::{Error, Options};
 use serde::de::Error as DeError;
@@ -13,11 +13,14 @@ use std::{
     fmt::{Debug, Error as FmtError, Formatter},
     io::{Cursor, Read, Write},
     mem::size_of,
+    sync::OnceLock,
 };
 
 type PathLen = u8;
 pub type ValueLen = u32;
 pub type EncodedChildLen = u8;
+/// Bitmap of the positions of the inline children of a branch node.
+type InlineChildren = u16;
 
 const MAX_CHILDREN: usize = 16;
 
@@ -27,6 +30,64 @@ pub struct BranchNode {
     pub(crate) children: [Option<DiskAddress>; MAX_CHILDREN],
     pub(crate) value: Option<Vec<u8>>,
     pub(crate) children_encoded: [Option<Vec<u8>>; MAX_CHILDREN],
+    /// Leaves with a short value stored in the branch itself instead of in a node of their own,
+    /// see [Merkle::with_inline_value_threshold](crate::merkle::Merkle::with_inline_value_threshold).
+    /// A position never holds both a child in `children` and an inline child.
+    pub(crate) inline_children: [Option<Box<LeafNode>>; MAX_CHILDREN],
+    /// What the inline children are encoded as in the branch, see [InlineEncoded]. A position
+    /// is cleared whenever [BranchNode::set_child] changes it, which is the only way an inline
+    /// child changes once the branch is built.
+    pub(crate) inline_encoded: InlineEncoded,
+}
+
+/// What each inline child of a branch is encoded as in the encoding of the branch: the leaf
+/// encoded, or its hash if that's longer than a hash. It's computed the first time the branch
+/// is hashed, and kept in the branch in the node cache, so that rehashing a wide branch after
+/// one of its other children changed doesn't encode and hash its unchanged inline children
+/// again. It's never stored, and doesn't take part in the comparison of branches.
+#[derive(Clone, Default)]
+pub(crate) struct InlineEncoded([OnceLock<Vec<u8>>; MAX_CHILDREN]);
+
+impl InlineEncoded {
+    #[allow(clippy::indexing_slicing)]
+    fn get_or_init(&self, index: usize, leaf: &LeafNode) -> &[u8] {
+        self.0[index].get_or_init(|| {
+            let encoded = leaf.encode();
+            if encoded.len() >= TRIE_HASH_LEN {
+                TrieHash::of(&encoded).to_vec()
+            } else {
+                encoded
+            }
+        })
+    }
+
+    #[allow(clippy::indexing_slicing)]
+    fn clear(&mut self, index: usize) {
+        self.0[index] = OnceLock::new();
+    }
+
+    #[cfg(test)]
+    #[allow(clippy::indexing_slicing)]
+    pub(crate) fn is_cached(&self, index: usize) -> bool {
+        self.0[index].get().is_some()
+    }
+}
+
+impl PartialEq for InlineEncoded {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for InlineEncoded {}
+
+/// What a [BranchNode] holds at one of its child positions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Child {
+    /// A node of its own.
+    Node(DiskAddress),
+    /// A leaf stored inline in the branch.
+    Inline(Box<LeafNode>),
 }
 
 impl Debug for BranchNode {
@@ -46,6 +107,12 @@ impl Debug for BranchNode {
             }
         }
 
+        for (i, c) in self.inline_children.iter().enumerate() {
+            if let Some(c) = c {
+                write!(f, " ({i:x} {c:?})")?;
+            }
+        }
+
         write!(
             f,
             " v={}]",
@@ -61,6 +128,10 @@ impl BranchNode {
     pub const MAX_CHILDREN: usize = MAX_CHILDREN;
     pub const MSIZE: usize = Self::MAX_CHILDREN + 2;
 
+    pub const fn path(&self) -> &Path {
+        &self.partial_path
+    }
+
     pub const fn value(&self) -> &Option<Vec<u8>> {
         &self.value
     }
@@ -81,6 +152,40 @@ impl BranchNode {
         &mut self.children_encoded
     }
 
+    /// The child at `index`, whether it is a node of its own or stored inline.
+    pub fn child(&self, index: u8) -> Option<Child> {
+        #[allow(clippy::indexing_slicing)]
+        match (
+            self.children[index as usize],
+            &self.inline_children[index as usize],
+        ) {
+            (Some(addr), _) => Some(Child::Node(addr)),
+            (None, Some(leaf)) => Some(Child::Inline(leaf.clone())),
+            (None, None) => None,
+        }
+    }
+
+    /// The children of this node and their positions, in ascending order.
+    pub fn children_iter(&self) -> impl DoubleEndedIterator<Item = (u8, Child)> + '_ {
+        (0..MAX_CHILDREN as u8).filter_map(|index| self.child(index).map(|child| (index, child)))
+    }
+
+    /// Stores `child` at `index`, replacing whatever was there.
+    pub(crate) fn set_child(&mut self, index: u8, child: Option<Child>) {
+        let (addr, inline) = match child {
+            Some(Child::Node(addr)) => (Some(addr), None),
+            Some(Child::Inline(leaf)) => (None, Some(leaf)),
+            None => (None, None),
+        };
+
+        #[allow(clippy::indexing_slicing)]
+        {
+            self.children[index as usize] = addr;
+            self.inline_children[index as usize] = inline;
+        }
+        self.inline_encoded.clear(index as usize);
+    }
+
     pub(super) fn decode(buf: &[u8]) -> Result<Self, Error> {
         let mut items: Vec<Vec<u8>> = bincode::DefaultOptions::new().deserialize(buf)?;
 
@@ -108,6 +213,8 @@ impl BranchNode {
             children: [None; Self::MAX_CHILDREN],
             value,
             children_encoded: chd_encoded,
+            inline_children: Default::default(),
+            inline_encoded: Default::default(),
         })
     }
 
@@ -145,6 +252,14 @@ impl BranchNode {
                 // TODO:
                 // change the data-structure children: [(Option<DiskAddress>, Option<Vec<u8>>); Self::MAX_CHILDREN]
                 None => {
+                    // An inline child is encoded like the leaf node it would otherwise be.
+                    #[allow(clippy::indexing_slicing)]
+                    if let Some(leaf) = &self.inline_children[i] {
+                        #[allow(clippy::indexing_slicing)]
+                        (list[i] = self.inline_encoded.get_or_init(i, leaf).to_vec());
+                        continue;
+                    }
+
                     // Check if there is already a calculated encoded value for the child, which
                     // can happen when manually constructing a trie from proof.
                     #[allow(clippy::indexing_slicing)]
@@ -181,8 +296,20 @@ impl Storable for BranchNode {
         });
         let path_len_size = size_of::<PathLen>() as u64;
         let path_len = self.partial_path.serialized_len();
-
-        children_len + value_len + children_encoded_len + path_len_size + path_len
+        let inline_children_len = self
+            .inline_children
+            .iter()
+            .flatten()
+            .fold(size_of::<InlineChildren>() as u64, |len, leaf| {
+                len + leaf.serialized_len()
+            });
+
+        children_len
+            + value_len
+            + children_encoded_len
+            + path_len_size
+            + path_len
+            + inline_children_len
     }
 
     fn serialize(&self, to: &mut [u8]) -> Result<(), crate::shale::ShaleError> {
@@ -216,6 +343,22 @@ impl Storable for BranchNode {
             cursor.write_all(child)?;
         }
 
+        let inline_children = self
+            .inline_children
+            .iter()
+            .enumerate()
+            .filter(|(_, leaf)| leaf.is_some())
+            .fold(0 as InlineChildren, |bits, (i, _)| bits | 1 << i);
+        cursor.write_all(&inline_children.to_le_bytes())?;
+
+        for leaf in self.inline_children.iter().flatten() {
+            let pos = cursor.position() as usize;
+            let len = leaf.serialized_len();
+            #[allow(clippy::indexing_slicing)]
+            leaf.serialize(&mut cursor.get_mut()[pos..pos + len as usize])?;
+            cursor.set_position((pos as u64) + len);
+        }
+
         Ok(())
     }
 
@@ -233,14 +376,14 @@ impl Storable for BranchNode {
             .ok_or(ShaleError::InvalidCacheView {
                 offset: addr,
                 size: PATH_LEN_SIZE,
-            })?
-            .as_deref();
+            })?;
+        let path_len = path_len.bytes();
 
         addr += PATH_LEN_SIZE as usize;
 
         let path_len = {
             let mut buf = [0u8; PATH_LEN_SIZE as usize];
-            let mut cursor = Cursor::new(path_len);
+            let mut cursor = Cursor::new(&*path_len);
             cursor.read_exact(buf.as_mut())?;
 
             PathLen::from_le_bytes(buf) as u64
@@ -251,13 +394,15 @@ impl Storable for BranchNode {
             .ok_or(ShaleError::InvalidCacheView {
                 offset: addr,
                 size: path_len,
-            })?
-            .as_deref();
+            })?;
+        let path = path.bytes();
 
         addr += path_len as usize;
 
-        let path: Vec<u8> = path.into_iter().flat_map(to_nibble_array).collect();
-        let path = Path::decode(&path);
+        let path = Path::from_iter_in(
+            path.iter().copied().flat_map(to_nibble_array),
+            pool::take(2 * path.len()),
+        );
 
         let node_raw =
             mem.get_view(addr, BRANCH_HEADER_SIZE)
@@ -268,7 +413,8 @@ impl Storable for BranchNode {
 
         addr += BRANCH_HEADER_SIZE as usize;
 
-        let mut cursor = Cursor::new(node_raw.as_deref());
+        let node_raw = node_raw.bytes();
+        let mut cursor = Cursor::new(&*node_raw);
         let mut children = [None; BranchNode::MAX_CHILDREN];
         let mut buf = [0u8; DiskAddress::SERIALIZED_LEN as usize];
 
@@ -289,16 +435,17 @@ impl Storable for BranchNode {
 
         let value = match raw_len {
             Some(len) => {
-                let value = mem
-                    .get_view(addr, len)
+                let mut value = pool::take(len as usize);
+                mem.get_view(addr, len)
                     .ok_or(ShaleError::InvalidCacheView {
                         offset: addr,
                         size: len,
-                    })?;
+                    })?
+                    .extend_into(&mut value);
 
                 addr += len as usize;
 
-                Some(value.as_deref())
+                Some(value)
             }
             None => None,
         };
@@ -308,15 +455,15 @@ impl Storable for BranchNode {
         for child in &mut children_encoded {
             const ENCODED_CHILD_LEN_SIZE: u64 = size_of::<EncodedChildLen>() as u64;
 
-            let len_raw = mem
-                .get_view(addr, ENCODED_CHILD_LEN_SIZE)
-                .ok_or(ShaleError::InvalidCacheView {
-                    offset: addr,
-                    size: ENCODED_CHILD_LEN_SIZE,
-                })?
-                .as_deref();
+            let len_raw =
+                mem.get_view(addr, ENCODED_CHILD_LEN_SIZE)
+                    .ok_or(ShaleError::InvalidCacheView {
+                        offset: addr,
+                        size: ENCODED_CHILD_LEN_SIZE,
+                    })?;
+            let len_raw = len_raw.bytes();
 
-            let mut cursor = Cursor::new(len_raw);
+            let mut cursor = Cursor::new(&*len_raw);
 
             let len = {
                 let mut buf = [0; ENCODED_CHILD_LEN_SIZE as usize];
@@ -330,28 +477,75 @@ impl Storable for BranchNode {
                 continue;
             }
 
-            let encoded = mem
-                .get_view(addr, len)
+            let mut encoded = pool::take(len as usize);
+            mem.get_view(addr, len)
                 .ok_or(ShaleError::InvalidCacheView {
                     offset: addr,
                     size: len,
                 })?
-                .as_deref();
+                .extend_into(&mut encoded);
 
             addr += len as usize;
 
             *child = Some(encoded);
         }
 
+        const INLINE_CHILDREN_SIZE: u64 = size_of::<InlineChildren>() as u64;
+
+        let inline_children_raw =
+            mem.get_view(addr, INLINE_CHILDREN_SIZE)
+                .ok_or(ShaleError::InvalidCacheView {
+                    offset: addr,
+                    size: INLINE_CHILDREN_SIZE,
+                })?;
+        let inline_children_raw = inline_children_raw.bytes();
+
+        addr += INLINE_CHILDREN_SIZE as usize;
+
+        let inline_children_bits = {
+            let mut buf = [0; INLINE_CHILDREN_SIZE as usize];
+            Cursor::new(&*inline_children_raw).read_exact(buf.as_mut())?;
+            InlineChildren::from_le_bytes(buf)
+        };
+
+        let mut inline_children: [Option<Box<LeafNode>>; BranchNode::MAX_CHILDREN] =
+            Default::default();
+
+        for (i, child) in inline_children.iter_mut().enumerate() {
+            if inline_children_bits & (1 << i) == 0 {
+                continue;
+            }
+
+            let leaf = LeafNode::deserialize(addr, mem)?;
+            addr += leaf.serialized_len() as usize;
+
+            *child = Some(Box::new(leaf));
+        }
+
         let node = BranchNode {
             partial_path: path,
             children,
             value,
             children_encoded,
+            inline_children,
+            inline_encoded: Default::default(),
         };
 
         Ok(node)
     }
+
+    fn recycle(&mut self) {
+        pool::recycle(std::mem::take(&mut self.partial_path.0));
+        if let Some(value) = self.value.take() {
+            pool::recycle(value);
+        }
+        for encoded in self.children_encoded.iter_mut().filter_map(Option::take) {
+            pool::recycle(encoded);
+        }
+        for leaf in self.inline_children.iter_mut().flatten() {
+            leaf.recycle();
+        }
+    }
 }
 
 fn optional_value_len<Len, T: AsRef<[u8]>>(value: Option<T>) -> u64 {
@@ -0,0 +1,375 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The formats the trie nodes are written to the store in, see [NodeEncoding].
+//!
+//! This is only the layout of a node in the store, after the metadata every node starts with:
+//! the encoding a node is hashed in, and so the root hash and the proofs, are the same in every
+//! format. Each node records the format it was written in with its metadata, so a store can
+//! mix formats, and a node keeps its format when it is modified. The native format is the
+//! compact layout of [BranchNode] and [LeafNode]. The standard formats write the node as a
+//! [StoredNode], prefixed with its length as a 32-bit little-endian integer, so that it can be
+//! read by external tooling.
+
+use super::{BranchNode, LeafNode, NodeType, Path};
+use crate::shale::{DiskAddress, LinearStore, ShaleError, Storable};
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::mem::size_of;
+
+type StoredLen = u32;
+
+/// The format the trie nodes are written to the store in, see
+/// [DbConfig::node_encoding](crate::db::DbConfig::node_encoding).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NodeEncoding {
+    /// The compact layout of firewood, see [NativeEncoder].
+    #[default]
+    Native,
+    /// Bincode, see [BincodeEncoder].
+    Bincode,
+    /// CBOR, see [CborEncoder].
+    Cbor,
+}
+
+impl NodeEncoding {
+    pub(crate) const fn to_u64(self) -> u64 {
+        match self {
+            NodeEncoding::Native => 0,
+            NodeEncoding::Bincode => 1,
+            NodeEncoding::Cbor => 2,
+        }
+    }
+
+    /// The encoding recorded as `id`, `None` if there is no such encoding.
+    pub(crate) const fn from_u64(id: u64) -> Option<Self> {
+        match id {
+            0 => Some(NodeEncoding::Native),
+            1 => Some(NodeEncoding::Bincode),
+            2 => Some(NodeEncoding::Cbor),
+            _ => None,
+        }
+    }
+
+    pub(super) fn encoded_len(self, node: &NodeType) -> u64 {
+        match self {
+            NodeEncoding::Native => NativeEncoder::encoded_len(node),
+            NodeEncoding::Bincode => BincodeEncoder::encoded_len(node),
+            NodeEncoding::Cbor => CborEncoder::encoded_len(node),
+        }
+    }
+
+    pub(super) fn encode(self, node: &NodeType, to: &mut [u8]) -> Result<(), ShaleError> {
+        match self {
+            NodeEncoding::Native => NativeEncoder::encode(node, to),
+            NodeEncoding::Bincode => BincodeEncoder::encode(node, to),
+            NodeEncoding::Cbor => CborEncoder::encode(node, to),
+        }
+    }
+
+    pub(super) fn decode<T: LinearStore>(
+        self,
+        branch: bool,
+        offset: usize,
+        mem: &T,
+    ) -> Result<NodeType, ShaleError> {
+        match self {
+            NodeEncoding::Native => NativeEncoder::decode(branch, offset, mem),
+            NodeEncoding::Bincode => BincodeEncoder::decode(branch, offset, mem),
+            NodeEncoding::Cbor => CborEncoder::decode(branch, offset, mem),
+        }
+    }
+}
+
+/// A format trie nodes can be written to the store in.
+pub trait NodeEncoder {
+    /// The number of bytes `node` takes.
+    fn encoded_len(node: &NodeType) -> u64;
+
+    /// Writes `node` to `to`, which is at least [NodeEncoder::encoded_len] bytes long.
+    fn encode(node: &NodeType, to: &mut [u8]) -> Result<(), ShaleError>;
+
+    /// Reads the node at `offset` of `mem`, which is a branch if `branch` is set, and a leaf
+    /// otherwise, as recorded in its metadata.
+    fn decode<T: LinearStore>(branch: bool, offset: usize, mem: &T)
+        -> Result<NodeType, ShaleError>;
+}
+
+/// The compact layout of firewood, which every DB created before the encoding could be chosen
+/// uses.
+#[derive(Debug)]
+pub struct NativeEncoder;
+
+impl NodeEncoder for NativeEncoder {
+    fn encoded_len(node: &NodeType) -> u64 {
+        match node {
+            NodeType::Branch(n) => n.serialized_len(),
+            NodeType::Leaf(n) => n.serialized_len(),
+        }
+    }
+
+    fn encode(node: &NodeType, to: &mut [u8]) -> Result<(), ShaleError> {
+        match node {
+            NodeType::Branch(n) => n.serialize(to),
+            NodeType::Leaf(n) => n.serialize(to),
+        }
+    }
+
+    fn decode<T: LinearStore>(
+        branch: bool,
+        offset: usize,
+        mem: &T,
+    ) -> Result<NodeType, ShaleError> {
+        Ok(if branch {
+            NodeType::Branch(Box::new(BranchNode::deserialize(offset, mem)?))
+        } else {
+            NodeType::Leaf(LeafNode::deserialize(offset, mem)?)
+        })
+    }
+}
+
+/// A trie node as the standard formats write it. Paths are lists of nibbles, children are
+/// listed with their position in the branch, and the addresses of the children are offsets in
+/// the payload store.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StoredNode {
+    Branch {
+        path: Vec<u8>,
+        value: Option<Vec<u8>>,
+        children: Vec<(u8, u64)>,
+        /// The encodings of the children too short to be hashed.
+        children_encoded: Vec<(u8, Vec<u8>)>,
+        /// The leaves stored in the branch, as their position, path and value.
+        inline_children: Vec<(u8, Vec<u8>, Vec<u8>)>,
+    },
+    Leaf {
+        path: Vec<u8>,
+        value: Vec<u8>,
+    },
+}
+
+impl From<&NodeType> for StoredNode {
+    fn from(node: &NodeType) -> Self {
+        match node {
+            NodeType::Branch(n) => {
+                let positions = (0u8..).zip(n.children.iter());
+                let children = positions
+                    .filter_map(|(i, child)| child.map(|addr| (i, addr.get() as u64)))
+                    .collect();
+                let positions = (0u8..).zip(n.children_encoded.iter());
+                let children_encoded = positions
+                    .filter_map(|(i, child)| child.clone().map(|child| (i, child)))
+                    .collect();
+                let positions = (0u8..).zip(n.inline_children.iter());
+                let inline_children = positions
+                    .filter_map(|(i, leaf)| {
+                        leaf.as_ref()
+                            .map(|leaf| (i, leaf.partial_path.0.clone(), leaf.value.clone()))
+                    })
+                    .collect();
+                StoredNode::Branch {
+                    path: n.partial_path.0.clone(),
+                    value: n.value.clone(),
+                    children,
+                    children_encoded,
+                    inline_children,
+                }
+            }
+            NodeType::Leaf(n) => StoredNode::Leaf {
+                path: n.partial_path.0.clone(),
+                value: n.value.clone(),
+            },
+        }
+    }
+}
+
+impl StoredNode {
+    fn into_node_type(self, branch: bool, offset: usize) -> Result<NodeType, ShaleError> {
+        let invalid = |error| ShaleError::InvalidObj {
+            addr: offset,
+            obj_type: "Node",
+            error,
+        };
+        let position = |i: u8| {
+            let i = i as usize;
+            (i < BranchNode::MAX_CHILDREN)
+                .then_some(i)
+                .ok_or(invalid("child position out of range"))
+        };
+
+        match self {
+            StoredNode::Branch {
+                path,
+                value,
+                children,
+                children_encoded,
+                inline_children,
+            } if branch => {
+                let mut node = BranchNode {
+                    partial_path: Path(path),
+                    children: [None; BranchNode::MAX_CHILDREN],
+                    value,
+                    children_encoded: Default::default(),
+                    inline_children: Default::default(),
+                    inline_encoded: Default::default(),
+                };
+                #[allow(clippy::indexing_slicing)]
+                for (i, addr) in children {
+                    node.children[position(i)?] = Some(DiskAddress::from(addr as usize));
+                }
+                #[allow(clippy::indexing_slicing)]
+                for (i, child) in children_encoded {
+                    node.children_encoded[position(i)?] = Some(child);
+                }
+                #[allow(clippy::indexing_slicing)]
+                for (i, path, value) in inline_children {
+                    node.inline_children[position(i)?] = Some(Box::new(LeafNode::new(path, value)));
+                }
+                Ok(NodeType::Branch(Box::new(node)))
+            }
+            StoredNode::Leaf { path, value } if !branch => {
+                Ok(NodeType::Leaf(LeafNode::new(Path(path), value)))
+            }
+            _ => Err(invalid("node type doesn't match its metadata")),
+        }
+    }
+}
+
+/// Writes the [StoredNode] of a node with a serde format.
+trait SerdeFormat {
+    fn to_bytes(node: &StoredNode) -> Vec<u8>;
+    fn from_bytes(bytes: &[u8]) -> Option<StoredNode>;
+}
+
+impl<F: SerdeFormat> NodeEncoder for F {
+    fn encoded_len(node: &NodeType) -> u64 {
+        (size_of::<StoredLen>() + F::to_bytes(&node.into()).len()) as u64
+    }
+
+    fn encode(node: &NodeType, to: &mut [u8]) -> Result<(), ShaleError> {
+        let bytes = F::to_bytes(&node.into());
+        let len = (bytes.len() as StoredLen).to_le_bytes();
+        let to = to
+            .get_mut(..len.len() + bytes.len())
+            .ok_or(ShaleError::InvalidObj {
+                addr: 0,
+                obj_type: "Node",
+                error: "buffer too short for the node",
+            })?;
+        let (len_to, bytes_to) = to.split_at_mut(len.len());
+        len_to.copy_from_slice(&len);
+        bytes_to.copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    fn decode<T: LinearStore>(
+        branch: bool,
+        offset: usize,
+        mem: &T,
+    ) -> Result<NodeType, ShaleError> {
+        const LEN_SIZE: u64 = size_of::<StoredLen>() as u64;
+        let view = |offset, size| {
+            mem.get_view(offset, size)
+                .ok_or(ShaleError::InvalidCacheView { offset, size })
+        };
+
+        let len = view(offset, LEN_SIZE)?;
+        #[allow(clippy::unwrap_used)]
+        let len = StoredLen::from_le_bytes((*len.bytes()).try_into().unwrap());
+        let bytes = view(offset + LEN_SIZE as usize, len as u64)?;
+        F::from_bytes(&bytes.bytes())
+            .ok_or(ShaleError::InvalidObj {
+                addr: offset,
+                obj_type: "Node",
+                error: "malformed node",
+            })?
+            .into_node_type(branch, offset)
+    }
+}
+
+/// [Bincode](https://github.com/bincode-org/bincode), with its default options.
+#[derive(Debug)]
+pub struct BincodeEncoder;
+
+impl SerdeFormat for BincodeEncoder {
+    fn to_bytes(node: &StoredNode) -> Vec<u8> {
+        #[allow(clippy::unwrap_used)]
+        bincode::DefaultOptions::new().serialize(node).unwrap()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<StoredNode> {
+        bincode::DefaultOptions::new().deserialize(bytes).ok()
+    }
+}
+
+/// [CBOR](https://www.rfc-editor.org/rfc/rfc8949).
+#[derive(Debug)]
+pub struct CborEncoder;
+
+impl SerdeFormat for CborEncoder {
+    fn to_bytes(node: &StoredNode) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        #[allow(clippy::unwrap_used)]
+        ciborium::into_writer(node, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<StoredNode> {
+        ciborium::from_reader(bytes).ok()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod tests {
+    use super::*;
+    use crate::shale::in_mem::InMemLinearStore;
+
+    #[test]
+    fn round_trips() {
+        let mut branch = BranchNode {
+            partial_path: Path(vec![1, 2, 3]),
+            children: [None; BranchNode::MAX_CHILDREN],
+            value: Some(b"value".to_vec()),
+            children_encoded: Default::default(),
+            inline_children: Default::default(),
+            inline_encoded: Default::default(),
+        };
+        branch.children[0] = Some(DiskAddress::from(4096));
+        branch.children_encoded[3] = Some(vec![7; 8]);
+        branch.inline_children[15] = Some(Box::new(LeafNode::new(Path(vec![4]), b"inline")));
+        let nodes = [
+            NodeType::Branch(Box::new(branch)),
+            NodeType::Leaf(LeafNode::new(Path(vec![5, 6]), b"leaf")),
+        ];
+
+        for encoding in [
+            NodeEncoding::Native,
+            NodeEncoding::Bincode,
+            NodeEncoding::Cbor,
+        ] {
+            assert_eq!(NodeEncoding::from_u64(encoding.to_u64()), Some(encoding));
+            for node in &nodes {
+                let len = encoding.encoded_len(node);
+                let mut bytes = vec![0; len as usize];
+                encoding.encode(node, &mut bytes).unwrap();
+                let mut mem = InMemLinearStore::new(len, 0);
+                mem.write(0, &bytes).unwrap();
+
+                let branch = matches!(node, NodeType::Branch(_));
+                assert_eq!(&encoding.decode(branch, 0, &mem).unwrap(), node);
+                // the standard formats check the type recorded in the metadata
+                if encoding != NodeEncoding::Native {
+                    assert!(encoding.decode(!branch, 0, &mem).is_err());
+                }
+            }
+        }
+
+        // the standard formats can be read without firewood
+        let node = NodeType::Leaf(LeafNode::new(Path(vec![5, 6]), b"leaf"));
+        let mut bytes = vec![0; CborEncoder::encoded_len(&node) as usize];
+        CborEncoder::encode(&node, &mut bytes).unwrap();
+        let value: ciborium::Value = ciborium::from_reader(&bytes[4..]).unwrap();
+        assert!(value.as_map().is_some());
+    }
+}
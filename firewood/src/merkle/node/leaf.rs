@@ -4,14 +4,14 @@
 use crate::{
     merkle::{nibbles_to_bytes_iter, Path},
     nibbles::Nibbles,
-    shale::{ShaleError::InvalidCacheView, Storable},
+    shale::{pool, ShaleError::InvalidCacheView, Storable},
 };
 use bincode::Options;
 use bytemuck::{Pod, Zeroable};
 use std::{
     fmt::{Debug, Error as FmtError, Formatter},
     io::{Cursor, Write},
-    mem::size_of,
+    mem::{size_of, take},
 };
 
 pub const SIZE: usize = 2;
@@ -70,7 +70,8 @@ impl LeafNode {
 #[repr(C, packed)]
 struct Meta {
     path_len: PathLen,
-    value_len: ValueLen,
+    /// A little-endian [ValueLen], kept as bytes so that the layout doesn't depend on the host.
+    value_len: [u8; size_of::<ValueLen>()],
 }
 
 impl Meta {
@@ -98,7 +99,7 @@ impl Storable for LeafNode {
 
         let meta = Meta {
             path_len,
-            value_len,
+            value_len: value_len.to_le_bytes(),
         };
 
         cursor.write_all(bytemuck::bytes_of(&meta))?;
@@ -124,32 +125,39 @@ impl Storable for LeafNode {
             .ok_or(InvalidCacheView {
                 offset,
                 size: Meta::SIZE as u64,
-            })?
-            .as_deref();
+            })?;
+        let node_header_raw = node_header_raw.bytes();
 
         let offset = offset + Meta::SIZE;
         let Meta {
             path_len,
             value_len,
         } = *bytemuck::from_bytes(&node_header_raw);
+        let value_len = ValueLen::from_le_bytes(value_len);
         let size = path_len as u64 + value_len as u64;
 
-        let remainder = mem
-            .get_view(offset, size)
+        let mut remainder = pool::take(size as usize);
+        mem.get_view(offset, size)
             .ok_or(InvalidCacheView { offset, size })?
-            .as_deref();
+            .extend_into(&mut remainder);
 
         let (path, value) = remainder.split_at(path_len as usize);
 
         let path = {
             let nibbles = Nibbles::<0>::new(path).into_iter();
-            Path::from_nibbles(nibbles).0
+            Path::from_iter_in(nibbles, pool::take(2 * path.len()))
         };
 
-        let value = value.to_vec();
+        let value = pool::to_vec(value);
+        pool::recycle(remainder);
 
         Ok(Self::new(path, value))
     }
+
+    fn recycle(&mut self) {
+        pool::recycle(take(&mut self.partial_path.0));
+        pool::recycle(take(&mut self.value));
+    }
 }
 
 #[cfg(test)]
@@ -74,14 +74,24 @@ impl Path {
     }
 
     /// Assumes all bytes are nibbles, prefer to use `from_nibbles` instead.
-    fn from_iter<Iter: Iterator<Item = u8>>(mut iter: Iter) -> Self {
+    fn from_iter<Iter: Iterator<Item = u8>>(iter: Iter) -> Self {
+        Self::from_iter_in(iter, Vec::new())
+    }
+
+    /// Decodes the nibbles of `iter` into `buf`, such as a buffer of the
+    /// [pool](crate::shale::pool).
+    pub(super) fn from_iter_in<Iter: Iterator<Item = u8>>(
+        mut iter: Iter,
+        mut buf: Vec<u8>,
+    ) -> Self {
         let flags = Flags::from_bits_retain(iter.next().unwrap_or_default());
 
         if !flags.contains(Flags::ODD_LEN) {
             let _ = iter.next();
         }
 
-        Self(iter.collect())
+        buf.extend(iter);
+        Self(buf)
     }
 
     pub(super) fn serialized_len(&self) -> u64 {
@@ -0,0 +1,81 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The version tag written after the metadata of a node, followed by its extensions, so that
+//! fields can be added to the nodes, such as counts or timestamps, without migrating the store.
+//!
+//! A tagged node is flagged in its metadata, and the nodes written before the tag was added are
+//! still read without one. The tag is the [NODE_VERSION] the node was written with, then the
+//! length of its extensions as a 16-bit little-endian integer. Each extension is a tag byte,
+//! the length of its bytes as a 16-bit little-endian integer, then its bytes.
+//!
+//! A decoder skips the extensions it doesn't know, unless their tag has the [REQUIRED] bit set,
+//! in which case the node can't be read without them. Skipped extensions are dropped when the
+//! node is written again, so an extension that can be skipped must only hold what can be
+//! recomputed, or done without.
+
+use crate::shale::{LinearStore, ShaleError};
+use std::mem::size_of;
+
+/// The version of the layout of the nodes written by this version of firewood. Later versions
+/// only add extensions, so nodes of any version are read.
+pub(super) const NODE_VERSION: u8 = 1;
+
+/// The bit of the tag of an extension a decoder must know to read the node.
+pub(super) const REQUIRED: u8 = 0x80;
+
+type ExtLen = u16;
+
+/// Size of the tag of a node with no extension.
+pub(super) const TAG_SIZE: usize = 1 + size_of::<ExtLen>();
+
+/// The bytes of the tag of a node, followed by its `extensions`, as their tag and bytes.
+pub(super) fn encode(version: u8, extensions: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut bytes = vec![version, 0, 0];
+    for (tag, ext) in extensions {
+        bytes.push(*tag);
+        bytes.extend_from_slice(&(ext.len() as ExtLen).to_le_bytes());
+        bytes.extend_from_slice(ext);
+    }
+    let len = ((bytes.len() - TAG_SIZE) as ExtLen).to_le_bytes();
+    #[allow(clippy::indexing_slicing)]
+    bytes[1..TAG_SIZE].copy_from_slice(&len);
+    bytes
+}
+
+/// Reads the tag of the node at `offset` of `mem`, skipping its extensions, and returns the
+/// offset of the rest of the node. Fails if the node has an extension that is
+/// [REQUIRED] to read it.
+pub(super) fn skip<T: LinearStore>(offset: usize, mem: &T) -> Result<usize, ShaleError> {
+    let view = |offset, size| {
+        mem.get_view(offset, size)
+            .ok_or(ShaleError::InvalidCacheView { offset, size })
+    };
+    let invalid = |error| ShaleError::InvalidObj {
+        addr: offset,
+        obj_type: "Node",
+        error,
+    };
+
+    let tag = view(offset, TAG_SIZE as u64)?;
+    let tag = tag.bytes();
+    #[allow(clippy::indexing_slicing, clippy::unwrap_used)]
+    let len = ExtLen::from_le_bytes(tag[1..].try_into().unwrap()) as usize;
+    let extensions = view(offset + TAG_SIZE, len as u64)?;
+
+    let extensions = extensions.bytes();
+    let mut rest = &*extensions;
+    while let [tag, len_0, len_1, more @ ..] = rest {
+        let len = ExtLen::from_le_bytes([*len_0, *len_1]) as usize;
+        if tag & REQUIRED != 0 {
+            // no extension is known yet
+            return Err(invalid("unknown required node extension"));
+        }
+        rest = more.get(len..).ok_or(invalid("truncated node extension"))?;
+    }
+    if !rest.is_empty() {
+        return Err(invalid("truncated node extension"));
+    }
+
+    Ok(offset + TAG_SIZE + len)
+}
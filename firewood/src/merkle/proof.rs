@@ -2,12 +2,13 @@
 // See the file LICENSE.md for licensing terms.
 
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::shale::{disk_address::DiskAddress, ShaleError};
-use crate::shale::{LinearStore, ObjWriteSizeError};
+use crate::shale::{LinearStore, ObjWriteError};
 use crate::v2::api::HashKey;
 use aiofut::AioError;
+use futures::{Stream, StreamExt};
 use nix::errno::Errno;
 use sha3::Digest;
 use thiserror::Error;
@@ -16,7 +17,7 @@ use crate::nibbles::Nibbles;
 use crate::nibbles::NibblesIterator;
 use crate::{
     db::DbError,
-    merkle::{to_nibble_array, Merkle, MerkleError, Node, NodeType},
+    merkle::{to_nibble_array, Merkle, MerkleError, Node, NodeType, EMPTY_ROOT_HASH},
     merkle_util::{DataStoreError, InMemoryMerkle},
 };
 
@@ -32,6 +33,10 @@ pub enum ProofError {
     NoSuchNode,
     #[error("proof node missing")]
     ProofNodeMissing,
+    #[error("proof node does not match its hash")]
+    NodeHashMismatch,
+    #[error("proof stream error: {0}")]
+    StreamError(Box<dyn std::error::Error + Send + Sync>),
     #[error("inconsistent proof data")]
     InconsistentProofData,
     #[error("non-monotonic range increase")]
@@ -42,6 +47,15 @@ pub enum ProofError {
     InvalidProof,
     #[error("invalid edge keys")]
     InvalidEdgeKeys,
+    /// A key of a range that isn't in the trie
+    #[error("extra key in range: {0:?}")]
+    ExtraKey(Vec<u8>),
+    /// An edge key of a range that is in the trie, but not in the range
+    #[error("missing key in range: {0:?}")]
+    MissingKey(Vec<u8>),
+    /// The pairs of a range don't rebuild the root hash of the trie
+    #[error("range doesn't match the root hash")]
+    RangeMismatch,
     #[error("node insertion error")]
     NodesInsertionError,
     #[error("node not in trie")]
@@ -61,7 +75,7 @@ pub enum ProofError {
     #[error("invalid root hash")]
     InvalidRootHash,
     #[error("{0}")]
-    WriteError(#[from] ObjWriteSizeError),
+    WriteError(#[from] ObjWriteError),
 }
 
 impl From<DataStoreError> for ProofError {
@@ -89,7 +103,20 @@ impl From<DbError> for ProofError {
                 ProofError::SystemError(nix::errno::Errno::from_raw(e.raw_os_error().unwrap()))
             }
             DbError::Shale(e) => ProofError::Shale(e),
-            DbError::InvalidProposal => ProofError::InvalidProof,
+            DbError::InvalidProposal
+            | DbError::CommitHook(_)
+            | DbError::KeyExists(_)
+            | DbError::BatchRejected(_)
+            | DbError::AlreadyOpen { .. }
+            | DbError::ReadOnly
+            | DbError::UnknownIndex(_)
+            | DbError::RootMismatch { .. }
+            | DbError::Closed
+            | DbError::CloseTimeout
+            | DbError::FreezeTimeout
+            | DbError::NoSpace { .. }
+            | DbError::Replication(_)
+            | DbError::RevisionNotFound(_) => ProofError::InvalidProof,
         }
     }
 }
@@ -97,7 +124,7 @@ impl From<DbError> for ProofError {
 /// A proof that a single key is present
 ///
 /// The generic N represents the storage for the node
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Proof<N>(pub HashMap<HashKey, N>);
 
 /// `SubProof` contains the value or the hash of a node that maps
@@ -113,7 +140,8 @@ enum SubProof {
 impl<N: AsRef<[u8]> + Send> Proof<N> {
     /// verify_proof checks merkle proofs. The given proof must contain the value for
     /// key in a trie with the given root hash. VerifyProof returns an error if the
-    /// proof contains invalid trie nodes or the wrong value.
+    /// proof contains invalid trie nodes or the wrong value. No key is in the empty trie,
+    /// of [EMPTY_ROOT_HASH], whatever the proof.
     ///
     /// The generic N represents the storage for the node
     pub fn verify<K: AsRef<[u8]>>(
@@ -121,7 +149,22 @@ impl<N: AsRef<[u8]> + Send> Proof<N> {
         key: K,
         root_hash: HashKey,
     ) -> Result<Option<Vec<u8>>, ProofError> {
-        let mut key_nibbles = Nibbles::<0>::new(key.as_ref()).into_iter();
+        self.walk(key.as_ref(), root_hash, |_| ())
+    }
+
+    /// Verifies the proof of `key` like [Proof::verify], calling `visit` with the hash of each
+    /// node of the proof on the path to the key.
+    fn walk(
+        &self,
+        key: &[u8],
+        root_hash: HashKey,
+        mut visit: impl FnMut(HashKey),
+    ) -> Result<Option<Vec<u8>>, ProofError> {
+        // the empty trie has no nodes, and no keys
+        if root_hash == EMPTY_ROOT_HASH.0 {
+            return Ok(None);
+        }
+        let mut key_nibbles = Nibbles::<0>::new(key).into_iter();
 
         let mut cur_hash = root_hash;
         let proofs_map = &self.0;
@@ -130,6 +173,7 @@ impl<N: AsRef<[u8]> + Send> Proof<N> {
             let cur_proof = proofs_map
                 .get(&cur_hash)
                 .ok_or(ProofError::ProofNodeMissing)?;
+            visit(cur_hash);
 
             let node = NodeType::decode(cur_proof.as_ref())?;
             // TODO: I think this will currently fail if the key is &[];
@@ -146,10 +190,101 @@ impl<N: AsRef<[u8]> + Send> Proof<N> {
         }
     }
 
+    /// Like [Proof::verify], but for a proof whose nodes arrive one at a time, for instance from a
+    /// network peer. The nodes must be in path order, starting from the root (see
+    /// [Merkle::prove_path]). Every node is checked against the hash its parent committed to as
+    /// soon as it arrives, so an invalid proof is rejected at the first bad node without ever
+    /// holding the whole proof in memory. Nodes after the one proving the key are not read.
+    pub async fn verify_streaming<K, S, E>(
+        key: K,
+        root_hash: HashKey,
+        nodes: S,
+    ) -> Result<Option<Vec<u8>>, ProofError>
+    where
+        K: AsRef<[u8]>,
+        S: Stream<Item = Result<N, E>>,
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        // the empty trie has no nodes, and no keys
+        if root_hash == EMPTY_ROOT_HASH.0 {
+            return Ok(None);
+        }
+        let mut key_nibbles = Nibbles::<0>::new(key.as_ref()).into_iter();
+        let mut nodes = std::pin::pin!(nodes);
+
+        let mut cur_hash = root_hash;
+
+        loop {
+            let cur_proof = nodes
+                .next()
+                .await
+                .ok_or(ProofError::ProofNodeMissing)?
+                .map_err(|e| ProofError::StreamError(e.into()))?;
+            let cur_proof = cur_proof.as_ref();
+
+            let hash: HashKey = sha3::Keccak256::digest(cur_proof).into();
+            if hash != cur_hash {
+                return Err(ProofError::NodeHashMismatch);
+            }
+
+            let node = NodeType::decode(cur_proof)?;
+            let (sub_proof, traversed_nibbles) = locate_subproof(key_nibbles, node)?;
+            key_nibbles = traversed_nibbles;
+
+            cur_hash = match sub_proof {
+                // Return when reaching the end of the key.
+                Some(SubProof::Value(value)) if key_nibbles.is_empty() => return Ok(Some(value)),
+                // The trie doesn't contain the key.
+                Some(SubProof::Hash(hash)) => hash,
+                _ => return Ok(None),
+            };
+        }
+    }
+
     pub fn extend(&mut self, other: Proof<N>) {
         self.0.extend(other.0)
     }
 
+    /// Strips the nodes not needed to verify `keys` against `root_hash`, such as the ones of the
+    /// other keys of proofs combined with [Proof::extend], leaving the smallest proof of the
+    /// keys. Fails if the proof of one of the keys is missing a node, or if a key verifies to
+    /// another value once the proof is minimized.
+    pub fn minimize<K: AsRef<[u8]>>(
+        mut self,
+        keys: &[K],
+        root_hash: HashKey,
+    ) -> Result<Self, ProofError> {
+        let mut needed = HashSet::new();
+        let values = keys
+            .iter()
+            .map(|key| {
+                self.walk(key.as_ref(), root_hash, |hash| {
+                    needed.insert(hash);
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.0.retain(|hash, _| needed.contains(hash));
+
+        for (key, value) in keys.iter().zip(values) {
+            if self.verify(key, root_hash)? != value {
+                return Err(ProofError::InconsistentProofData);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Verifies that `keys` and `vals` are all the pairs of the trie of `root_hash` from
+    /// `first_key` to `last_key`, given the proofs of both edge keys, which may prove that an
+    /// edge key is absent. An empty proof proves that `keys` are all the keys of the trie.
+    /// Nothing but the proof and `root_hash` is needed, see
+    /// [RangeProof::verify](crate::v2::api::RangeProof::verify).
+    ///
+    /// Returns whether the trie has keys after `last_key`, to be fetched with the next range.
+    /// Fails with [ProofError::ExtraKey] for a key of the range the trie doesn't have, with
+    /// [ProofError::MissingKey] for an edge key the trie has but the range doesn't, and with
+    /// [ProofError::RangeMismatch] if the pairs don't rebuild `root_hash`, which is how a gap
+    /// in the middle of the range is detected.
     pub fn verify_range_proof<K, V, T>(
         &self,
         root_hash: HashKey,
@@ -180,9 +315,8 @@ impl<N: AsRef<[u8]> + Send> Proof<N> {
         // Special case, there is no edge proof at all. The given range is expected
         // to be the whole leaf-set in the trie.
         if self.0.is_empty() {
-            for (index, k) in keys.iter().enumerate() {
-                #[allow(clippy::indexing_slicing)]
-                in_mem_merkle.insert(k, vals[index].as_ref().to_vec())?;
+            for (key, val) in keys.iter().zip(vals.iter()) {
+                in_mem_merkle.insert(key, val.as_ref().to_vec())?;
             }
 
             let merkle_root = &*in_mem_merkle.root_hash()?;
@@ -190,61 +324,42 @@ impl<N: AsRef<[u8]> + Send> Proof<N> {
             return if merkle_root == &root_hash {
                 Ok(false)
             } else {
-                Err(ProofError::InvalidProof)
-            };
-        }
-
-        // Special case when there is a provided edge proof but zero key/value pairs,
-        // ensure there are no more accounts / slots in the trie.
-        if keys.is_empty() {
-            let proof_to_path =
-                self.proof_to_path(first_key, root_hash, &mut in_mem_merkle, true)?;
-            return match proof_to_path {
-                Some(_) => Err(ProofError::InvalidData),
-                None => Ok(false),
+                Err(ProofError::RangeMismatch)
             };
         }
 
-        // Special case, there is only one element and two edge keys are same.
-        // In this case, we can't construct two edge paths. So handle it here.
-        if keys.len() == 1 && first_key.as_ref() == last_key.as_ref() {
-            let value =
-                self.proof_to_path(first_key.as_ref(), root_hash, &mut in_mem_merkle, false)?;
-
-            #[allow(clippy::indexing_slicing)]
-            return if first_key.as_ref() != keys[0].as_ref() {
-                // correct proof but invalid key
-                Err(ProofError::InvalidEdgeKeys)
-            } else {
-                match value {
-                    #[allow(clippy::indexing_slicing)]
-                    Some(val) if val == vals[0].as_ref() => Ok(true),
-                    None => Ok(false),
-                    _ => Err(ProofError::InvalidData),
-                }
-            };
-        }
-
-        // Ok, in all other cases, we require two edge paths available.
-        // First check the validity of edge keys.
-        if first_key.as_ref() >= last_key.as_ref() {
+        let (first_key, last_key) = (first_key.as_ref(), last_key.as_ref());
+        if first_key > last_key {
             return Err(ProofError::InvalidEdgeKeys);
         }
+        if let Some(key) = keys
+            .iter()
+            .map(AsRef::as_ref)
+            .find(|&key| key < first_key || key > last_key)
+        {
+            return Err(ProofError::ExtraKey(key.to_vec()));
+        }
 
         // Convert the edge proofs to edge trie paths. Then we can
         // have the same tree architecture with the original one.
-        // For the first edge proof, non-existent proof is allowed.
-        self.proof_to_path(first_key.as_ref(), root_hash, &mut in_mem_merkle, true)?;
+        // Non-existent proofs are allowed for both edges.
+        let first_value = self.proof_to_path(first_key, root_hash, &mut in_mem_merkle, true)?;
+        check_edge(first_key, first_value, keys.first().zip(vals.first()))?;
+
+        // Special case, the two edge keys are the same, so there is nothing between
+        // the two edge paths to rebuild.
+        if first_key == last_key {
+            return self.has_right_element(last_key, root_hash);
+        }
 
         // Pass the root node here, the second path will be merged
-        // with the first one. For the last edge proof, non-existent
-        // proof is also allowed.
-        self.proof_to_path(last_key.as_ref(), root_hash, &mut in_mem_merkle, true)?;
+        // with the first one.
+        let last_value = self.proof_to_path(last_key, root_hash, &mut in_mem_merkle, true)?;
+        check_edge(last_key, last_value, keys.last().zip(vals.last()))?;
 
         // Remove all internal caculated values. All the removed parts should
         // be re-filled(or re-constructed) by the given leaves range.
-        let fork_at_root =
-            unset_internal(&mut in_mem_merkle, first_key.as_ref(), last_key.as_ref())?;
+        let fork_at_root = unset_internal(&mut in_mem_merkle, first_key, last_key)?;
 
         // If the fork point is the root, the trie should be empty, start with a new one.
         if fork_at_root {
@@ -259,9 +374,53 @@ impl<N: AsRef<[u8]> + Send> Proof<N> {
         let merkle_root = &*in_mem_merkle.root_hash()?;
 
         if merkle_root == &root_hash {
-            Ok(true)
+            self.has_right_element(last_key, root_hash)
         } else {
-            Err(ProofError::InvalidProof)
+            Err(ProofError::RangeMismatch)
+        }
+    }
+
+    /// Whether the trie of `root_hash` has keys after `key`, that is whether a node on the path
+    /// of `key` in the proof has a child, or a path, to its right.
+    fn has_right_element(&self, key: &[u8], root_hash: HashKey) -> Result<bool, ProofError> {
+        if root_hash == EMPTY_ROOT_HASH.0 {
+            return Ok(false);
+        }
+        let mut key_nibbles = Nibbles::<0>::new(key).into_iter();
+        let mut cur_hash = root_hash;
+
+        loop {
+            let cur_proof = self.0.get(&cur_hash).ok_or(ProofError::ProofNodeMissing)?;
+            let node = NodeType::decode(cur_proof.as_ref())?;
+
+            let path = match &node {
+                NodeType::Leaf(n) => &n.path().0,
+                NodeType::Branch(n) => &n.partial_path.0,
+            };
+            // a path diverging from the key leads to keys after it if it is greater, and so
+            // does a path the key is a prefix of
+            for nibble in path {
+                match key_nibbles.next() {
+                    None => return Ok(true),
+                    Some(key_nibble) if key_nibble != *nibble => return Ok(*nibble > key_nibble),
+                    Some(_) => (),
+                }
+            }
+
+            let NodeType::Branch(n) = node else {
+                return Ok(false);
+            };
+            let children = n.chd_encode();
+            let Some(index) = key_nibbles.next().map(usize::from) else {
+                return Ok(children.iter().any(Option::is_some));
+            };
+            if children.iter().skip(index + 1).any(Option::is_some) {
+                return Ok(true);
+            }
+            cur_hash = match children.get(index) {
+                Some(Some(encoded)) => generate_subproof_hash(encoded)?,
+                _ => return Ok(false),
+            };
         }
     }
 
@@ -391,6 +550,22 @@ impl<N: AsRef<[u8]> + Send> Proof<N> {
     }
 }
 
+/// Checks the value of an edge key proven by its proof, `None` if it is absent, against the
+/// first or last pair of the range.
+fn check_edge<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+    edge_key: &[u8],
+    proven: Option<Vec<u8>>,
+    pair: Option<(&K, &V)>,
+) -> Result<(), ProofError> {
+    let pair = pair.filter(|(key, _)| key.as_ref() == edge_key);
+    match (proven, pair) {
+        (Some(proven), Some((_, val))) if proven != val.as_ref() => Err(ProofError::InvalidData),
+        (Some(_), None) => Err(ProofError::MissingKey(edge_key.to_vec())),
+        (None, Some(_)) => Err(ProofError::ExtraKey(edge_key.to_vec())),
+        _ => Ok(()),
+    }
+}
+
 fn locate_subproof(
     mut key_nibbles: NibblesIterator<'_, 0>,
     node: NodeType,
@@ -0,0 +1,231 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Reports of the nodes that fail to decode, or whose hash isn't the one recorded for them.
+//!
+//! Besides failing the read, a corrupted node is reported once, to a file of the quarantine
+//! directory of its DB: where it is, what was expected of it and what was found, and a hexdump
+//! of the bytes around it, for a post-mortem once the DB has been replaced. The revision the
+//! node was read from is marked as suspect, see [Db::health](crate::db::Db::health).
+
+use super::TrieHash;
+use crate::{
+    logger::error,
+    shale::{disk_address::DiskAddress, ShaleError, StoreId},
+};
+use parking_lot::Mutex;
+use std::{
+    collections::HashSet,
+    fmt::{self, Write as _},
+    fs,
+    path::PathBuf,
+    sync::Arc,
+};
+
+/// How many bytes before a corrupted node its report holds.
+const DUMP_BEFORE: usize = 64;
+/// How many bytes from a corrupted node its report holds.
+const DUMP_AFTER: usize = 192;
+
+/// What is known of a node that failed to read, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptionReport {
+    /// The store the node is in.
+    pub store_id: StoreId,
+    /// The offset of the node in its store.
+    pub offset: usize,
+    /// The hash recorded for the node, if it decoded but its hash doesn't match.
+    pub expected: Option<TrieHash>,
+    /// The hash of the node as it decoded, if it did.
+    pub actual: Option<TrieHash>,
+    /// What failed.
+    pub error: String,
+    /// The root hash of the revision the node was read from, if it is known.
+    pub revision: Option<TrieHash>,
+    /// The offset of the first byte of `bytes` in the store.
+    pub dump_offset: usize,
+    /// The bytes around the node, if they could be read.
+    pub bytes: Vec<u8>,
+}
+
+impl CorruptionReport {
+    /// A hexdump of the bytes around the node, 16 of them per line, each line starting with
+    /// the offset of its first byte in the store.
+    pub fn hexdump(&self) -> String {
+        let mut dump = String::new();
+        for (n, line) in self.bytes.chunks(16).enumerate() {
+            let hex: Vec<_> = line.iter().map(|byte| format!("{byte:02x}")).collect();
+            let ascii: String = line
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            #[allow(clippy::unwrap_used)]
+            writeln!(
+                dump,
+                "{:08x}  {:<47}  {ascii}",
+                self.dump_offset + n * 16,
+                hex.join(" ")
+            )
+            .unwrap();
+        }
+        dump
+    }
+}
+
+impl fmt::Display for CorruptionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hash =
+            |hash: &Option<TrieHash>| hash.map_or_else(|| "-".to_string(), |h| format!("{h:?}"));
+        writeln!(f, "store {}", self.store_id)?;
+        writeln!(f, "offset {:#x}", self.offset)?;
+        writeln!(f, "expected {}", hash(&self.expected))?;
+        writeln!(f, "actual {}", hash(&self.actual))?;
+        writeln!(f, "error {}", self.error)?;
+        writeln!(f, "revision {}", hash(&self.revision))?;
+        write!(f, "{}", self.hexdump())
+    }
+}
+
+/// The corruptions found in a DB since it was opened, see [Db::health](crate::db::Db::health).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Health {
+    /// The reports of the corrupted nodes, in the order they were found.
+    pub corruptions: Vec<CorruptionReport>,
+    /// The root hashes of the revisions a corrupted node was read from.
+    pub suspect_revisions: Vec<TrieHash>,
+    /// The reports that couldn't be written to the quarantine directory.
+    pub unwritten_reports: usize,
+}
+
+impl Health {
+    /// Whether no corrupted node was found.
+    pub const fn is_healthy(&self) -> bool {
+        self.corruptions.is_empty()
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    dir: PathBuf,
+    health: Mutex<Health>,
+    /// The nodes reported already, by store and offset.
+    reported: Mutex<HashSet<(StoreId, usize)>>,
+}
+
+/// Where the corrupted nodes of the tries of a DB are reported, shared by all of its revisions,
+/// each of which tells the ones found in it apart by its root hash.
+#[derive(Debug, Clone)]
+pub(crate) struct Quarantine {
+    shared: Arc<Shared>,
+    revision: Option<TrieHash>,
+}
+
+impl Quarantine {
+    /// Reports to files of `dir`, created with the first one.
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                dir,
+                health: Default::default(),
+                reported: Default::default(),
+            }),
+            revision: None,
+        }
+    }
+
+    /// The same quarantine, for the nodes of the revision of root hash `revision`.
+    pub(crate) fn for_revision(&self, revision: Option<TrieHash>) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            revision,
+        }
+    }
+
+    pub(crate) fn health(&self) -> Health {
+        self.shared.health.lock().clone()
+    }
+
+    /// Reports the node at `ptr` of `store_id`, unless it was reported already. `bytes` are the
+    /// stored bytes from `dump_offset`.
+    pub(crate) fn report(
+        &self,
+        store_id: StoreId,
+        ptr: DiskAddress,
+        failure: Failure,
+        bytes: Option<(usize, Vec<u8>)>,
+    ) {
+        let offset = ptr.get();
+        let (dump_offset, bytes) = bytes.unwrap_or((offset, Vec::new()));
+        let (expected, actual, error) = match failure {
+            Failure::Decode(e) => (None, None, e.to_string()),
+            Failure::HashMismatch { expected, computed } => {
+                (Some(expected), Some(computed), "hash mismatch".to_string())
+            }
+        };
+        let report = CorruptionReport {
+            store_id,
+            offset,
+            expected,
+            actual,
+            error,
+            revision: self.revision,
+            dump_offset,
+            bytes,
+        };
+
+        let mut health = self.shared.health.lock();
+        if let Some(revision) = self.revision {
+            if !health.suspect_revisions.contains(&revision) {
+                health.suspect_revisions.push(revision);
+            }
+        }
+        if !self.shared.reported.lock().insert((store_id, offset)) {
+            return;
+        }
+        error!("corrupted node in store {store_id} at {offset:#x}");
+
+        let path = self
+            .shared
+            .dir
+            .join(format!("corruption-{store_id}-{offset:x}.txt"));
+        if fs::create_dir_all(&self.shared.dir)
+            .and_then(|_| fs::write(path, report.to_string()))
+            .is_err()
+        {
+            health.unwritten_reports += 1;
+        }
+        health.corruptions.push(report);
+    }
+
+    /// The range of bytes reported around a node at `offset`.
+    pub(crate) const fn dump_range(offset: usize) -> (usize, u64) {
+        let start = offset.saturating_sub(DUMP_BEFORE);
+        (start, (offset - start + DUMP_AFTER) as u64)
+    }
+}
+
+/// Why a node failed to read.
+pub(crate) enum Failure<'a> {
+    Decode(&'a ShaleError),
+    HashMismatch {
+        expected: TrieHash,
+        computed: TrieHash,
+    },
+}
+
+impl Failure<'_> {
+    /// Whether the node failed to read because of what is stored, rather than of how it was
+    /// read.
+    pub(crate) const fn is_corruption(&self) -> bool {
+        match self {
+            Failure::Decode(ShaleError::Io(_) | ShaleError::ImmutableWrite) => false,
+            Failure::Decode(_) | Failure::HashMismatch { .. } => true,
+        }
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! A merkle trie on top of user-provided storage, without a [Db](crate::db::Db).
+//!
+//! The [Db](crate::db::Db) owns its files, its Wal and its revisions. Embedders with a storage
+//! engine of their own can instead use a [Trie], which only needs two [LinearStore]s: a meta
+//! store for the allocator's bookkeeping and a data store for the trie nodes. Every change is
+//! written through [LinearStore::write], so persisting the trie is up to the stores; call
+//! [Trie::flush_dirty] first to write out the nodes still held in the cache.
+//!
+//! ```
+//! use firewood::merkle::{standalone::{Trie, TrieConfig}, Bincode};
+//! use firewood::shale::in_mem::InMemLinearStore;
+//!
+//! let meta = InMemLinearStore::new(0x10000, 0);
+//! let data = InMemLinearStore::new(0x10000, 1);
+//! let mut trie = Trie::<_, Bincode>::create(meta, data, &TrieConfig::builder().build()).unwrap();
+//!
+//! trie.insert(b"horse", b"stallion".to_vec()).unwrap();
+//! let root_hash = trie.root_hash().unwrap();
+//!
+//! let proof = trie.prove(b"horse").unwrap();
+//! assert_eq!(proof.verify(b"horse", *root_hash).unwrap(), Some(b"stallion".to_vec()));
+//! ```
+
+use super::{BinarySerde, EncodedNode, Merkle, MerkleError, Proof, Ref, TrieHash};
+use crate::shale::{
+    self,
+    compact::{ChunkHeader, Store, StoreHeader},
+    disk_address::DiskAddress,
+    LinearStore, ObjCache, StoredView,
+};
+use std::num::NonZeroUsize;
+use typed_builder::TypedBuilder;
+
+/// Bytes at the start of both stores that are never allocated. The [StoreHeader] lives at the
+/// start of the meta store.
+const RESERVED: usize = 0x1000;
+
+/// Configuration of a [Trie].
+#[derive(Clone, TypedBuilder, Debug)]
+pub struct TrieConfig {
+    /// Maximum cached trie nodes.
+    #[builder(default = 1 << 20)]
+    pub ncached_objs: usize,
+    /// Maximum steps of walk to recycle a freed node.
+    #[builder(default = 10)]
+    pub max_walk: u64,
+    /// Region size in bits. Allocations never cross a region boundary.
+    #[builder(default = 22)]
+    pub regn_nbit: u64,
+    /// Rehash every node as it is read, see
+    /// [DbConfig::verify_hashes_on_read](crate::db::DbConfig::verify_hashes_on_read).
+    #[builder(default = false)]
+    pub verify_hashes_on_read: bool,
+}
+
+/// A merkle trie stored in a pair of user-provided [LinearStore]s. See the
+/// [module documentation](self).
+#[derive(Debug)]
+pub struct Trie<S, T> {
+    merkle: Merkle<S, T>,
+    sentinel_addr: DiskAddress,
+}
+
+impl<S, T> Trie<S, T>
+where
+    S: LinearStore,
+    T: BinarySerde,
+    EncodedNode<T>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    /// Creates an empty trie, overwriting whatever `meta_store` and `data_store` contain.
+    pub fn create(mut meta_store: S, data_store: S, cfg: &TrieConfig) -> Result<Self, MerkleError> {
+        #[allow(clippy::unwrap_used)]
+        let reserved = NonZeroUsize::new(RESERVED).unwrap();
+        meta_store.write(
+            DiskAddress::null().into(),
+            &shale::to_dehydrated(&StoreHeader::new(reserved, reserved))?,
+        )?;
+
+        let merkle = Self::new_merkle(meta_store, data_store, cfg)?;
+        let sentinel_addr = merkle.init_sentinel()?;
+
+        Ok(Self {
+            merkle,
+            sentinel_addr,
+        })
+    }
+
+    /// Opens a trie previously created in these stores with [Trie::create]. `sentinel_addr` is
+    /// the [Trie::sentinel_addr] of the created trie, which the caller has to keep track of.
+    pub fn open(
+        meta_store: S,
+        data_store: S,
+        sentinel_addr: DiskAddress,
+        cfg: &TrieConfig,
+    ) -> Result<Self, MerkleError> {
+        let merkle = Self::new_merkle(meta_store, data_store, cfg)?;
+
+        // make sure the address actually points at a trie
+        merkle.root_hash(sentinel_addr)?;
+
+        Ok(Self {
+            merkle,
+            sentinel_addr,
+        })
+    }
+
+    fn new_merkle(
+        meta_store: S,
+        data_store: S,
+        cfg: &TrieConfig,
+    ) -> Result<Merkle<S, T>, MerkleError> {
+        let header = StoredView::addr_to_obj(
+            &meta_store,
+            DiskAddress::null(),
+            ChunkHeader::SERIALIZED_LEN,
+        )?;
+
+        let store = Store::new(
+            meta_store,
+            data_store,
+            header,
+            ObjCache::new(cfg.ncached_objs),
+            cfg.max_walk,
+            cfg.regn_nbit,
+        )?;
+
+        Ok(Merkle::new(store).with_hash_verification(cfg.verify_hashes_on_read))
+    }
+
+    /// The address of the sentinel node, needed to [open](Trie::open) the trie again.
+    pub const fn sentinel_addr(&self) -> DiskAddress {
+        self.sentinel_addr
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Ref<'_>>, MerkleError> {
+        self.merkle.get(key, self.sentinel_addr)
+    }
+
+    pub fn insert<K: AsRef<[u8]>>(&mut self, key: K, value: Vec<u8>) -> Result<(), MerkleError> {
+        self.merkle
+            .insert(key, value, self.sentinel_addr)
+            .map(|_| ())
+    }
+
+    /// Removes `key` from the trie, returning its value.
+    pub fn remove<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>, MerkleError> {
+        self.merkle.remove(key, self.sentinel_addr)
+    }
+
+    pub fn root_hash(&self) -> Result<TrieHash, MerkleError> {
+        self.merkle.root_hash(self.sentinel_addr)
+    }
+
+    /// See [Merkle::prove].
+    pub fn prove<K: AsRef<[u8]>>(&self, key: K) -> Result<Proof<Vec<u8>>, MerkleError> {
+        self.merkle.prove(key, self.sentinel_addr)
+    }
+
+    /// See [Merkle::prove_path].
+    pub fn prove_path<K: AsRef<[u8]>>(&self, key: K) -> Result<Vec<Vec<u8>>, MerkleError> {
+        self.merkle.prove_path(key, self.sentinel_addr)
+    }
+
+    /// Writes the dirty nodes in the cache, and the allocator state, to the stores.
+    pub fn flush_dirty(&self) -> Result<(), MerkleError> {
+        self.merkle.flush_dirty()
+    }
+
+    /// The underlying [Merkle], for everything not covered by [Trie] itself.
+    pub const fn merkle(&self) -> &Merkle<S, T> {
+        &self.merkle
+    }
+}
@@ -1,7 +1,7 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
-use super::{BranchNode, Key, Merkle, MerkleError, NodeObjRef, NodeType, Value};
+use super::{BranchNode, Child, Key, Merkle, MerkleError, NodeObjRef, NodeRef, NodeType, Value};
 use crate::{
     nibbles::{Nibbles, NibblesIterator},
     shale::{DiskAddress, LinearStore},
@@ -17,7 +17,7 @@ enum IterationNode<'a> {
     Unvisited {
         /// The key (as nibbles) of this node.
         key: Key,
-        node: NodeObjRef<'a>,
+        node: NodeRef<'a>,
     },
     /// This node has been returned. Track which child to visit next.
     Visited {
@@ -25,7 +25,7 @@ enum IterationNode<'a> {
         key: Key,
         /// Returns the non-empty children of this node and their positions
         /// in the node's children array.
-        children_iter: Box<dyn Iterator<Item = (u8, DiskAddress)> + Send>,
+        children_iter: Box<dyn Iterator<Item = (u8, Child)> + Send>,
     },
 }
 
@@ -45,6 +45,43 @@ impl<'a> std::fmt::Debug for IterationNode<'a> {
     }
 }
 
+/// Represents an ongoing iteration, in descending key order, over a node and its children.
+/// A node's key is a prefix of its descendants' keys, so it is returned after all of them.
+enum ReverseIterationNode<'a> {
+    /// None of this node's children have been visited yet.
+    Unvisited {
+        /// The key (as nibbles) of this node.
+        key: Key,
+        node: NodeRef<'a>,
+    },
+    /// Some of this node's children may have been visited. Track which child to visit next.
+    Visited {
+        /// The key (as nibbles) of this node.
+        key: Key,
+        node: NodeRef<'a>,
+        /// Returns the non-empty children of this node left to visit, last child first, and
+        /// their positions in the node's children array.
+        children_iter: Box<dyn Iterator<Item = (u8, Child)> + Send>,
+    },
+}
+
+impl<'a> std::fmt::Debug for ReverseIterationNode<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unvisited { key, node } => f
+                .debug_struct("Unvisited")
+                .field("key", key)
+                .field("node", node)
+                .finish(),
+            Self::Visited { key, node, .. } => f
+                .debug_struct("Visited")
+                .field("key", key)
+                .field("node", node)
+                .finish(),
+        }
+    }
+}
+
 #[derive(Debug)]
 enum NodeStreamState<'a> {
     /// The iterator state is lazily initialized when poll_next is called
@@ -56,8 +93,22 @@ enum NodeStreamState<'a> {
         /// On each call to poll_next we pop the next element.
         /// If it's unvisited, we visit it.
         /// If it's visited, we push its next child onto this stack.
+        ///
+        /// The stack is the path from the root to the next node, with the children left to
+        /// visit of each node on it, so a node is read once however many of its descendants
+        /// follow. The nodes are read with [Merkle::scan_child], so the iteration doesn't depend
+        /// on, nor evict, the nodes in the cache of the trie, and reads each node it returns
+        /// once whatever the pressure on that cache.
         iter_stack: Vec<IterationNode<'a>>,
     },
+    /// Like [NodeStreamState::StartFromKey], for an iterator in descending key order
+    /// which starts at the given key, or at the last key if there is none.
+    StartFromKeyRev(Option<Key>),
+    /// Like [NodeStreamState::Iterating], for an iterator in descending key order. The nodes on
+    /// the path are kept on the stack until they are returned, after their descendants.
+    IteratingRev {
+        iter_stack: Vec<ReverseIterationNode<'a>>,
+    },
 }
 
 impl NodeStreamState<'_> {
@@ -77,7 +128,11 @@ impl<'a, S: LinearStore, T> FusedStream for MerkleNodeStream<'a, S, T> {
     fn is_terminated(&self) -> bool {
         // The top of `iter_stack` is the next node to return.
         // If `iter_stack` is empty, there are no more nodes to visit.
-        matches!(&self.state, NodeStreamState::Iterating { iter_stack } if iter_stack.is_empty())
+        match &self.state {
+            NodeStreamState::Iterating { iter_stack } => iter_stack.is_empty(),
+            NodeStreamState::IteratingRev { iter_stack } => iter_stack.is_empty(),
+            NodeStreamState::StartFromKey(_) | NodeStreamState::StartFromKeyRev(_) => false,
+        }
     }
 }
 
@@ -91,10 +146,24 @@ impl<'a, S, T> MerkleNodeStream<'a, S, T> {
             merkle,
         }
     }
+
+    /// Returns a new iterator that will iterate, in descending key order, over all the nodes in
+    /// `merkle` with keys less than or equal to `key`, or over all the nodes if there is no `key`.
+    pub(super) const fn new_rev(
+        merkle: &'a Merkle<S, T>,
+        sentinel_addr: DiskAddress,
+        key: Option<Key>,
+    ) -> Self {
+        Self {
+            state: NodeStreamState::StartFromKeyRev(key),
+            sentinel_addr,
+            merkle,
+        }
+    }
 }
 
 impl<'a, S: LinearStore, T> Stream for MerkleNodeStream<'a, S, T> {
-    type Item = Result<(Key, NodeObjRef<'a>), api::Error>;
+    type Item = Result<(Key, NodeRef<'a>), api::Error>;
 
     fn poll_next(
         mut self: std::pin::Pin<&mut Self>,
@@ -138,26 +207,13 @@ impl<'a, S: LinearStore, T> Stream for MerkleNodeStream<'a, S, T> {
                             ref mut children_iter,
                         } => {
                             // We returned `node` already. Visit its next child.
-                            let Some((pos, child_addr)) = children_iter.next() else {
+                            let Some((pos, child)) = children_iter.next() else {
                                 // We visited all this node's descendants. Go back to its parent.
                                 continue;
                             };
 
-                            let child = merkle.get_node(child_addr)?;
-
-                            let partial_path = match child.inner() {
-                                NodeType::Branch(branch) => branch.partial_path.iter().copied(),
-                                NodeType::Leaf(leaf) => leaf.partial_path.iter().copied(),
-                            };
-
-                            // The child's key is its parent's key, followed by the child's index,
-                            // followed by the child's partial path (if any).
-                            let child_key: Key = key
-                                .iter()
-                                .copied()
-                                .chain(once(pos))
-                                .chain(partial_path)
-                                .collect();
+                            let child = merkle.scan_child(child)?;
+                            let child_key = child_key(key, pos, &child);
 
                             // There may be more children of this node to visit.
                             iter_stack.push(iter_node);
@@ -172,10 +228,83 @@ impl<'a, S: LinearStore, T> Stream for MerkleNodeStream<'a, S, T> {
                 }
                 Poll::Ready(None)
             }
+            NodeStreamState::StartFromKeyRev(key) => {
+                self.state = match key {
+                    Some(key) => get_reverse_iterator_initial_state(merkle, *sentinel_addr, key)?,
+                    None => NodeStreamState::IteratingRev {
+                        iter_stack: vec![ReverseIterationNode::Unvisited {
+                            key: Box::new([]),
+                            node: NodeRef::Stored(merkle.get_node(*sentinel_addr)?),
+                        }],
+                    },
+                };
+                self.poll_next(_cx)
+            }
+            NodeStreamState::IteratingRev { iter_stack } => {
+                while let Some(iter_node) = iter_stack.pop() {
+                    let (key, node, mut children_iter) = match iter_node {
+                        ReverseIterationNode::Unvisited { key, node } => match node.inner() {
+                            NodeType::Branch(branch) => {
+                                // Visit `node`'s children, last one first, before `node` itself.
+                                let children_iter: Box<dyn Iterator<Item = _> + Send> =
+                                    Box::new(as_enumerated_children_iter(branch).rev());
+                                (key, node, children_iter)
+                            }
+                            NodeType::Leaf(_) => {
+                                let key = key_from_nibble_iter(key.iter().copied().skip(1));
+                                return Poll::Ready(Some(Ok((key, node))));
+                            }
+                        },
+                        ReverseIterationNode::Visited {
+                            key,
+                            node,
+                            children_iter,
+                        } => (key, node, children_iter),
+                    };
+
+                    let Some((pos, child)) = children_iter.next() else {
+                        // We visited all this node's descendants, so it's this node's turn.
+                        let key = key_from_nibble_iter(key.iter().copied().skip(1));
+                        return Poll::Ready(Some(Ok((key, node))));
+                    };
+
+                    let child = merkle.scan_child(child)?;
+                    let child_key = child_key(&key, pos, &child);
+
+                    // There may be more children of this node to visit.
+                    iter_stack.push(ReverseIterationNode::Visited {
+                        key,
+                        node,
+                        children_iter,
+                    });
+
+                    iter_stack.push(ReverseIterationNode::Unvisited {
+                        key: child_key,
+                        node: child,
+                    });
+                }
+                Poll::Ready(None)
+            }
         }
     }
 }
 
+/// The child's key is its parent's key, followed by the child's index, followed by the child's
+/// partial path (if any).
+fn child_key(parent_key: &[u8], pos: u8, child: &NodeRef) -> Key {
+    let partial_path = match child.inner() {
+        NodeType::Branch(branch) => branch.partial_path.iter().copied(),
+        NodeType::Leaf(leaf) => leaf.partial_path.iter().copied(),
+    };
+
+    parent_key
+        .iter()
+        .copied()
+        .chain(once(pos))
+        .chain(partial_path)
+        .collect()
+}
+
 /// Returns the initial state for an iterator over the given `merkle` which starts at `key`.
 fn get_iterator_intial_state<'a, S: LinearStore, T>(
     merkle: &'a Merkle<S, T>,
@@ -183,7 +312,7 @@ fn get_iterator_intial_state<'a, S: LinearStore, T>(
     key: &[u8],
 ) -> Result<NodeStreamState<'a>, api::Error> {
     // Invariant: `node`'s key is a prefix of `key`.
-    let mut node = merkle.get_node(sentinel_addr)?;
+    let mut node = NodeRef::Stored(merkle.get_node(sentinel_addr)?);
 
     // Invariant: `matched_key_nibbles` is the key of `node` at the start
     // of each loop iteration.
@@ -226,8 +355,7 @@ fn get_iterator_intial_state<'a, S: LinearStore, T>(
 
                 // Figure out if the child at `next_unmatched_key_nibble` is a prefix of `key`.
                 // (i.e. if we should run this loop body again)
-                #[allow(clippy::indexing_slicing)]
-                let Some(child_addr) = branch.children[next_unmatched_key_nibble as usize] else {
+                let Some(child) = branch.child(next_unmatched_key_nibble) else {
                     // There is no child at `next_unmatched_key_nibble`.
                     // We'll visit `node`'s first child at index > `next_unmatched_key_nibble`
                     // first (if it exists).
@@ -236,7 +364,7 @@ fn get_iterator_intial_state<'a, S: LinearStore, T>(
 
                 matched_key_nibbles.push(next_unmatched_key_nibble);
 
-                let child = merkle.get_node(child_addr)?;
+                let child = merkle.get_child(child)?;
 
                 let partial_key = match child.inner() {
                     NodeType::Branch(branch) => &branch.partial_path,
@@ -270,29 +398,128 @@ fn get_iterator_intial_state<'a, S: LinearStore, T>(
                     }
                 }
             }
-            NodeType::Leaf(leaf) => {
-                if compare_partial_path(leaf.partial_path.iter(), unmatched_key_nibbles).0
-                    == Ordering::Greater
-                {
-                    // `child` is after `key`.
-                    let key = matched_key_nibbles
-                        .iter()
-                        .chain(leaf.partial_path.iter())
-                        .copied()
-                        .collect();
-                    iter_stack.push(IterationNode::Unvisited { key, node });
-                }
+            NodeType::Leaf(_) => {
+                // The partial path of `node` was matched when it was reached, so its key is a
+                // strict prefix of `key`, and `node` is before `key`.
                 return Ok(NodeStreamState::Iterating { iter_stack });
             }
         };
     }
 }
 
+/// Returns the initial state for an iterator over the given `merkle`, in descending key order,
+/// which starts at `key`.
+fn get_reverse_iterator_initial_state<'a, S: LinearStore, T>(
+    merkle: &'a Merkle<S, T>,
+    sentinel_addr: DiskAddress,
+    key: &[u8],
+) -> Result<NodeStreamState<'a>, api::Error> {
+    // Invariant: `node`'s key is a prefix of `key`.
+    let mut node = NodeRef::Stored(merkle.get_node(sentinel_addr)?);
+
+    // Invariant: `matched_key_nibbles` is the key of `node` at the start
+    // of each loop iteration.
+    let mut matched_key_nibbles = vec![];
+
+    let mut unmatched_key_nibbles = Nibbles::<1>::new(key).into_iter();
+
+    let mut iter_stack: Vec<ReverseIterationNode> = vec![];
+
+    loop {
+        let node_key: Key = matched_key_nibbles.iter().copied().collect();
+
+        // `next_unmatched_key_nibble` is the first nibble after `matched_key_nibbles`.
+        let Some(next_unmatched_key_nibble) = unmatched_key_nibbles.next() else {
+            // `node` is at `key`, so all of its descendants are after `key`.
+            // Only `node` itself is left to return.
+            iter_stack.push(ReverseIterationNode::Visited {
+                key: node_key,
+                node,
+                children_iter: Box::new(std::iter::empty()),
+            });
+
+            return Ok(NodeStreamState::IteratingRev { iter_stack });
+        };
+
+        let NodeType::Branch(branch) = &node.inner else {
+            // `node` is a leaf whose key is a strict prefix of `key`, so it is before `key`.
+            iter_stack.push(ReverseIterationNode::Unvisited {
+                key: node_key,
+                node,
+            });
+
+            return Ok(NodeStreamState::IteratingRev { iter_stack });
+        };
+
+        // The children of `node` with a position < `next_unmatched_key_nibble` are before `key`,
+        // and so is `node` itself, which is returned once they have been visited.
+        let children_iter = Box::new(
+            as_enumerated_children_iter(branch)
+                .rev()
+                .filter(move |(pos, _)| *pos < next_unmatched_key_nibble),
+        );
+
+        // Figure out if the child at `next_unmatched_key_nibble` is a prefix of `key`.
+        // (i.e. if we should run this loop body again)
+        let child = branch.child(next_unmatched_key_nibble);
+
+        iter_stack.push(ReverseIterationNode::Visited {
+            key: node_key,
+            node,
+            children_iter,
+        });
+
+        let Some(child) = child else {
+            // There is no child at `next_unmatched_key_nibble`.
+            return Ok(NodeStreamState::IteratingRev { iter_stack });
+        };
+
+        matched_key_nibbles.push(next_unmatched_key_nibble);
+
+        let child = merkle.get_child(child)?;
+
+        let partial_key = match child.inner() {
+            NodeType::Branch(branch) => &branch.partial_path,
+            NodeType::Leaf(leaf) => &leaf.partial_path,
+        };
+
+        let (comparison, new_unmatched_key_nibbles) =
+            compare_partial_path(partial_key.iter(), unmatched_key_nibbles);
+        unmatched_key_nibbles = new_unmatched_key_nibbles;
+
+        match comparison {
+            Ordering::Less => {
+                // `child` and all of its descendants are before `key`.
+                let key = matched_key_nibbles
+                    .iter()
+                    .chain(partial_key.iter())
+                    .copied()
+                    .collect();
+                iter_stack.push(ReverseIterationNode::Unvisited { key, node: child });
+
+                return Ok(NodeStreamState::IteratingRev { iter_stack });
+            }
+            Ordering::Equal => {
+                // `child` is a prefix of `key`.
+                matched_key_nibbles.extend(partial_key.iter().copied());
+                node = child;
+            }
+            Ordering::Greater => {
+                // `child` is after `key`.
+                return Ok(NodeStreamState::IteratingRev { iter_stack });
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 enum MerkleKeyValueStreamState<'a, S, T> {
     /// The iterator state is lazily initialized when poll_next is called
     /// for the first time. The iteration start key is stored here.
     Uninitialized(Key),
+    /// Like [MerkleKeyValueStreamState::Uninitialized], for an iterator in descending key order.
+    /// The iteration starts at the given key, or at the last key if there is none.
+    UninitializedRev(Option<Key>),
     /// The iterator works by iterating over the nodes in the merkle trie
     /// and returning the key-value pairs for nodes that have values.
     Initialized {
@@ -342,6 +569,20 @@ impl<'a, S, T> MerkleKeyValueStream<'a, S, T> {
             merkle,
         }
     }
+
+    /// Returns a new iterator over the key-value pairs in `merkle`, in descending key order,
+    /// starting at `key` if there is one, and at the last key otherwise.
+    pub(super) const fn rev_from_key(
+        merkle: &'a Merkle<S, T>,
+        sentinel_addr: DiskAddress,
+        key: Option<Key>,
+    ) -> Self {
+        Self {
+            state: MerkleKeyValueStreamState::UninitializedRev(key),
+            sentinel_addr,
+            merkle,
+        }
+    }
 }
 
 impl<'a, S: LinearStore, T> Stream for MerkleKeyValueStream<'a, S, T> {
@@ -365,6 +606,11 @@ impl<'a, S: LinearStore, T> Stream for MerkleKeyValueStream<'a, S, T> {
                 self.state = MerkleKeyValueStreamState::Initialized { node_iter: iter };
                 self.poll_next(_cx)
             }
+            MerkleKeyValueStreamState::UninitializedRev(key) => {
+                let iter = MerkleNodeStream::new_rev(merkle, *sentinel_addr, key.take());
+                self.state = MerkleKeyValueStreamState::Initialized { node_iter: iter };
+                self.poll_next(_cx)
+            }
             MerkleKeyValueStreamState::Initialized { node_iter: iter } => {
                 match iter.poll_next_unpin(_cx) {
                     Poll::Ready(node) => match node {
@@ -396,15 +642,15 @@ impl<'a, S: LinearStore, T> Stream for MerkleKeyValueStream<'a, S, T> {
 
 enum PathIteratorState<'a> {
     Iterating {
-        /// The key, as nibbles, of the node at `address`, without the
+        /// The key, as nibbles, of the node `child`, without the
         /// node's partial path (if any) at the end.
         /// Invariant: If this node has a parent, the parent's key is a
         /// prefix of the key we're traversing to.
-        /// Note the node at `address` may not have a key which is a
+        /// Note the node `child` may not have a key which is a
         /// prefix of the key we're traversing to.
         matched_key: Vec<u8>,
         unmatched_key: NibblesIterator<'a, 0>,
-        address: DiskAddress,
+        child: Child,
     },
     Exhausted,
 }
@@ -431,9 +677,9 @@ impl<'a, 'b, S: LinearStore, T> PathIterator<'a, 'b, S, T> {
         sentinel_node: NodeObjRef<'a>,
         key: &'b [u8],
     ) -> Self {
-        let sentinel_addr = match sentinel_node.inner() {
-            NodeType::Branch(branch) => match branch.children[0] {
-                Some(sentinel_addr) => sentinel_addr,
+        let root = match sentinel_node.inner() {
+            NodeType::Branch(branch) => match branch.child(0) {
+                Some(root) => root,
                 None => {
                     return Self {
                         state: PathIteratorState::Exhausted,
@@ -449,14 +695,14 @@ impl<'a, 'b, S: LinearStore, T> PathIterator<'a, 'b, S, T> {
             state: PathIteratorState::Iterating {
                 matched_key: vec![],
                 unmatched_key: Nibbles::new(key).into_iter(),
-                address: sentinel_addr,
+                child: root,
             },
         }
     }
 }
 
 impl<'a, 'b, S: LinearStore, T> Iterator for PathIterator<'a, 'b, S, T> {
-    type Item = Result<(Key, NodeObjRef<'a>), MerkleError>;
+    type Item = Result<(Key, NodeRef<'a>), MerkleError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         // destructuring is necessary here because we need mutable access to `state`
@@ -468,9 +714,9 @@ impl<'a, 'b, S: LinearStore, T> Iterator for PathIterator<'a, 'b, S, T> {
             PathIteratorState::Iterating {
                 matched_key,
                 unmatched_key,
-                address,
+                child,
             } => {
-                let node = match merkle.get_node(*address) {
+                let node = match merkle.get_child(child.clone()) {
                     Ok(node) => node,
                     Err(e) => return Some(Err(e)),
                 };
@@ -503,8 +749,7 @@ impl<'a, 'b, S: LinearStore, T> Iterator for PathIterator<'a, 'b, S, T> {
                                 return Some(Ok((node_key, node)));
                             };
 
-                            #[allow(clippy::indexing_slicing)]
-                            let Some(child) = branch.children[next_unmatched_key_nibble as usize] else {
+                            let Some(next_child) = branch.child(next_unmatched_key_nibble) else {
                                 // There's no child at the index of the next nibble in the key.
                                 // The node we're traversing to isn't in the trie.
                                 self.state = PathIteratorState::Exhausted;
@@ -513,7 +758,7 @@ impl<'a, 'b, S: LinearStore, T> Iterator for PathIterator<'a, 'b, S, T> {
 
                             matched_key.push(next_unmatched_key_nibble);
 
-                            *address = child;
+                            *child = next_child;
 
                             Some(Ok((node_key, node)))
                         }
@@ -555,14 +800,12 @@ where
     (Ordering::Equal, unmatched_key_nibbles_iter)
 }
 
-/// Returns an iterator that returns (`pos`,`child_addr`) for each non-empty child of `branch`,
+/// Returns an iterator that returns (`pos`,`child`) for each non-empty child of `branch`,
 /// where `pos` is the position of the child in `branch`'s children array.
-fn as_enumerated_children_iter(branch: &BranchNode) -> impl Iterator<Item = (u8, DiskAddress)> {
-    branch
-        .children
-        .into_iter()
-        .enumerate()
-        .filter_map(|(pos, child_addr)| child_addr.map(|child_addr| (pos as u8, child_addr)))
+fn as_enumerated_children_iter(
+    branch: &BranchNode,
+) -> impl DoubleEndedIterator<Item = (u8, Child)> {
+    branch.children_iter().collect::<Vec<_>>().into_iter()
 }
 
 fn key_from_nibble_iter<Iter: Iterator<Item = u8>>(mut nibbles: Iter) -> Key {
@@ -576,7 +819,7 @@ fn key_from_nibble_iter<Iter: Iterator<Item = u8>>(mut nibbles: Iter) -> Key {
 }
 
 #[cfg(test)]
-use super::tests::create_test_merkle;
+use super::tests::{create_generic_test_merkle, create_test_merkle};
 
 #[cfg(test)]
 #[allow(clippy::indexing_slicing, clippy::unwrap_used)]
@@ -1150,6 +1393,25 @@ mod tests {
         check_stream_is_done(stream).await;
     }
 
+    #[tokio::test]
+    async fn key_value_start_past_leaf_key() {
+        let mut merkle = create_test_merkle();
+        let sentinel_addr = merkle.init_sentinel().unwrap();
+
+        for key in [vec![0x00, 0xFF], vec![0x00, 0x0F], vec![0x01]] {
+            merkle.insert(&key, key.clone(), sentinel_addr).unwrap();
+        }
+
+        // the leaf at 0x00FF is before its extensions
+        let mut stream = merkle
+            .key_value_iter_from_key(sentinel_addr, vec![0x00, 0xFF, 0x00].into_boxed_slice());
+
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(&*first.0, &[0x01]);
+
+        check_stream_is_done(stream).await;
+    }
+
     #[tokio::test]
     async fn key_value_start_at_key_on_branch_with_no_value() {
         let sibling_path = 0x00;
@@ -1407,6 +1669,118 @@ mod tests {
         check_stream_is_done(stream).await;
     }
 
+    #[test_case(None; "No start specified")]
+    #[test_case(Some(&[]); "Starting at empty key")]
+    #[test_case(Some(&[0x12]); "Starting on branch with value")]
+    #[test_case(Some(&[0x12, 0x30]); "Starting on leaf")]
+    #[test_case(Some(&[0x12, 0x31]); "Starting between siblings")]
+    #[test_case(Some(&[0x12, 0x34, 0x56]); "Starting below a leaf")]
+    #[test_case(Some(&[0x20]); "Starting on branch without value")]
+    #[test_case(Some(&[0x20, 0x00, 0x01]); "Starting overlapping with extension")]
+    #[test_case(Some(&[0xff, 0xff]); "Starting after last key")]
+    #[test_case(Some(&[0x00]); "Starting before first key")]
+    #[tokio::test]
+    async fn key_value_iterate_rev(start: Option<&[u8]>) {
+        let mut merkle = create_test_merkle();
+        let sentinel_addr = merkle.init_sentinel().unwrap();
+
+        let keys: Vec<&[u8]> = vec![
+            &[0x01],
+            &[0x12],
+            &[0x12, 0x30],
+            &[0x12, 0x34],
+            &[0x12, 0x3f, 0xff],
+            &[0x20, 0x00, 0x00, 0x10],
+            &[0x20, 0x00, 0x00, 0x20],
+            &[0x21],
+            &[0xf0, 0x0f],
+        ];
+        for key in &keys {
+            merkle.insert(key, key.to_vec(), sentinel_addr).unwrap();
+        }
+
+        let mut stream =
+            merkle.key_value_iter_rev(sentinel_addr, start.map(|key| key.to_vec().into()));
+
+        let expected = keys
+            .iter()
+            .rev()
+            .filter(|key| start.is_none_or(|start| **key <= start));
+        for key in expected {
+            assert_eq!(
+                stream.next().await.unwrap().unwrap(),
+                (key.to_vec().into_boxed_slice(), key.to_vec())
+            );
+        }
+
+        check_stream_is_done(stream).await;
+    }
+
+    #[tokio::test]
+    async fn key_value_rev_table_test() {
+        let mut merkle = create_test_merkle();
+        let sentinel_addr = merkle.init_sentinel().unwrap();
+
+        for i in 0..=u8::MAX {
+            for j in 0..=u8::MAX {
+                merkle.insert([i, j], vec![i, j], sentinel_addr).unwrap();
+            }
+        }
+
+        check_stream_is_done(merkle.key_value_iter_rev(sentinel_addr, Some(Box::new([])))).await;
+
+        // start in the middle of each group of keys
+        for i in (0..=u8::MAX).step_by(17) {
+            let mut stream = merkle.key_value_iter_rev(sentinel_addr, Some(Box::new([i, 128])));
+            for j in (0..=128).rev() {
+                assert_eq!(
+                    stream.next().await.unwrap().unwrap(),
+                    (Box::from([i, j]), vec![i, j]),
+                );
+            }
+            match i.checked_sub(1) {
+                Some(prev) => assert_eq!(
+                    stream.next().await.unwrap().unwrap(),
+                    (Box::from([prev, u8::MAX]), vec![prev, u8::MAX]),
+                ),
+                None => check_stream_is_done(stream).await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn key_value_fused_empty_rev() {
+        let merkle = create_test_merkle();
+        let sentinel_addr = merkle.init_sentinel().unwrap();
+        check_stream_is_done(merkle.key_value_iter_rev(sentinel_addr, None)).await;
+    }
+
+    #[test_case(false; "forward")]
+    #[test_case(true; "reverse")]
+    #[tokio::test]
+    async fn key_value_iterate_keeps_cache(rev: bool) {
+        let mut merkle = create_generic_test_merkle::<Bincode>(16);
+        let sentinel_addr = merkle.init_sentinel().unwrap();
+        for i in 0..=u8::MAX {
+            merkle.insert([i; 4], vec![i; 20], sentinel_addr).unwrap();
+        }
+        merkle.flush_dirty().unwrap();
+        let mut cached = merkle.cached_node_addresses(usize::MAX);
+        cached.sort();
+
+        let stream = if rev {
+            merkle.key_value_iter_rev(sentinel_addr, None)
+        } else {
+            merkle.key_value_iter(sentinel_addr)
+        };
+        assert_eq!(stream.count().await, 256);
+
+        // the iteration read every node, and the cache only holds the nodes it held before
+        let mut after = merkle.cached_node_addresses(usize::MAX);
+        after.sort();
+        assert_eq!(after, cached);
+    }
+
     async fn check_stream_is_done<S>(mut stream: S)
     where
         S: FusedStream + Unpin,
@@ -2,38 +2,56 @@
 // See the file LICENSE.md for licensing terms.
 
 use crate::shale::{LinearStore, ShaleError, Storable};
+use sha3::Digest;
 use std::{
     fmt::{self, Debug},
     io::Write,
 };
 
+/// Length of the hashes of the trie, whose nodes are hashed with [Keccak256].
 pub const TRIE_HASH_LEN: usize = 32;
-const U64_TRIE_HASH_LEN: u64 = TRIE_HASH_LEN as u64;
 
+/// The root hash of the empty trie, the hash of the encoding of an empty node, as in Ethereum.
+/// A DB nothing was written to, or everything was removed from, has this root hash, and
+/// proves the absence of every key and the emptiness of every range against it without any
+/// node.
+pub const EMPTY_ROOT_HASH: TrieHash = TrieHash([
+    0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6, 0xff, 0x83, 0x45, 0xe6, 0x92, 0xc0, 0xf8, 0x6e,
+    0x5b, 0x48, 0xe0, 0x1b, 0x99, 0x6c, 0xad, 0xc0, 0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21,
+]);
+
+/// A hash of `N` bytes, 32 by default, which is the length of the hashes of the trie.
 #[derive(PartialEq, Eq, Clone, Copy)]
-pub struct TrieHash(pub [u8; TRIE_HASH_LEN]);
+pub struct TrieHash<const N: usize = TRIE_HASH_LEN>(pub [u8; N]);
+
+impl TrieHash {
+    /// Hashes `data` the way the nodes of the trie are hashed.
+    pub fn of(data: &[u8]) -> Self {
+        Keccak256::digest(data)
+    }
+}
 
-impl std::ops::Deref for TrieHash {
-    type Target = [u8; TRIE_HASH_LEN];
-    fn deref(&self) -> &[u8; TRIE_HASH_LEN] {
+impl<const N: usize> std::ops::Deref for TrieHash<N> {
+    type Target = [u8; N];
+    fn deref(&self) -> &[u8; N] {
         &self.0
     }
 }
 
-impl Storable for TrieHash {
+impl<const N: usize> Storable for TrieHash<N> {
     fn deserialize<T: LinearStore>(addr: usize, mem: &T) -> Result<Self, ShaleError> {
         let raw = mem
-            .get_view(addr, U64_TRIE_HASH_LEN)
+            .get_view(addr, N as u64)
             .ok_or(ShaleError::InvalidCacheView {
                 offset: addr,
-                size: U64_TRIE_HASH_LEN,
+                size: N as u64,
             })?;
         #[allow(clippy::indexing_slicing, clippy::unwrap_used)]
-        Ok(Self(raw.as_deref()[..TRIE_HASH_LEN].try_into().unwrap()))
+        Ok(Self(raw.bytes()[..N].try_into().unwrap()))
     }
 
     fn serialized_len(&self) -> u64 {
-        U64_TRIE_HASH_LEN
+        N as u64
     }
 
     fn serialize(&self, mut to: &mut [u8]) -> Result<(), ShaleError> {
@@ -41,12 +59,45 @@ impl Storable for TrieHash {
     }
 }
 
-impl Debug for TrieHash {
+impl<const N: usize> Debug for TrieHash<N> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         write!(f, "{}", hex::encode(self.0))
     }
 }
 
+/// A hash function with an output of `N` bytes, for integrators that commit to the trie with
+/// shorter or longer digests than the 32 bytes of its own hashes.
+pub trait TrieHasher<const N: usize> {
+    fn digest(data: &[u8]) -> TrieHash<N>;
+}
+
+/// Keccak-256, which the nodes of the trie are hashed with. An output shorter than 32 bytes is
+/// the truncated digest, such as the 20 bytes of an address.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Keccak256;
+
+impl<const N: usize> TrieHasher<N> for Keccak256 {
+    fn digest(data: &[u8]) -> TrieHash<N> {
+        const { assert!(N <= 32, "Keccak-256 has a 32 byte output") };
+        let digest = sha3::Keccak256::digest(data);
+        #[allow(clippy::indexing_slicing, clippy::unwrap_used)]
+        TrieHash(digest[..N].try_into().unwrap())
+    }
+}
+
+/// Keccak-384, for 48 byte digests, or shorter truncated ones.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Keccak384;
+
+impl<const N: usize> TrieHasher<N> for Keccak384 {
+    fn digest(data: &[u8]) -> TrieHash<N> {
+        const { assert!(N <= 48, "Keccak-384 has a 48 byte output") };
+        let digest = sha3::Keccak384::digest(data);
+        #[allow(clippy::indexing_slicing, clippy::unwrap_used)]
+        TrieHash(digest[..N].try_into().unwrap())
+    }
+}
+
 #[cfg(test)]
 #[allow(clippy::indexing_slicing)]
 mod tests {
@@ -62,4 +113,21 @@ mod tests {
 
         assert_eq!(&to, &zero_hash.0);
     }
+
+    #[test]
+    fn hash_lengths() {
+        let trie: TrieHash = Keccak256::digest(b"firewood");
+        let address: TrieHash<20> = Keccak256::digest(b"firewood");
+        assert_eq!(address.0, trie.0[..20]);
+
+        let long: TrieHash<48> = Keccak384::digest(b"firewood");
+        let short: TrieHash<32> = Keccak384::digest(b"firewood");
+        assert_eq!(short.0, long.0[..32]);
+
+        let mut to = [0; 20];
+        #[allow(clippy::unwrap_used)]
+        address.serialize(&mut to).unwrap();
+        assert_eq!(address.serialized_len(), 20);
+        assert_eq!(to, address.0);
+    }
 }
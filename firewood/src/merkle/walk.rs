@@ -0,0 +1,227 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! A depth-first walk of a trie, telling a [TrieVisitor] about every node it enters and exits,
+//! so that the tools analyzing a trie don't have to walk it themselves.
+//!
+//! The nodes are entered in key order, a branch before its children and exited after them. The
+//! visitor can skip the subtree of the node it just entered, or stop the walk. The trie has no
+//! extension nodes: the nibbles an extension would hold are the partial path of the branch
+//! below it, see [BranchNode::path].
+
+use super::{BranchNode, Child, LeafNode, Merkle, MerkleError, NodeRef, NodeType};
+use crate::shale::{disk_address::DiskAddress, LinearStore};
+
+/// What a walk does after entering a node, see [TrieVisitor].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visit {
+    /// Goes on with the children of the node.
+    Continue,
+    /// Exits the node without entering its children.
+    SkipSubtree,
+    /// Ends the walk, without exiting the nodes entered so far.
+    Stop,
+}
+
+/// Where a node being visited is in its trie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeContext<'a> {
+    /// The number of nodes above this one, 0 for the root.
+    pub depth: usize,
+    /// The nibbles from the root to the node, not counting its partial path.
+    pub path: &'a [u8],
+    /// Where the node is stored, `None` for a leaf stored inline in its parent branch.
+    pub addr: Option<DiskAddress>,
+}
+
+/// Told about the nodes of a trie as [Merkle::walk] enters and exits them. Every callback does
+/// nothing by default, and the entries go on with the children.
+pub trait TrieVisitor {
+    fn enter_branch(&mut self, _context: &NodeContext<'_>, _branch: &BranchNode) -> Visit {
+        Visit::Continue
+    }
+
+    fn exit_branch(&mut self, _context: &NodeContext<'_>, _branch: &BranchNode) {}
+
+    /// A leaf has no children, so skipping its subtree only matters to the walk as a
+    /// [Visit::Stop].
+    fn enter_leaf(&mut self, _context: &NodeContext<'_>, _leaf: &LeafNode) -> Visit {
+        Visit::Continue
+    }
+
+    fn exit_leaf(&mut self, _context: &NodeContext<'_>, _leaf: &LeafNode) {}
+}
+
+enum Step<'a> {
+    Enter(Child, Vec<u8>, usize),
+    Exit(NodeRef<'a>, Vec<u8>, usize),
+}
+
+impl<S: LinearStore, T> Merkle<S, T> {
+    /// Walks the trie under `sentinel_addr`, see the [module documentation](self). Returns
+    /// whether the walk went through, rather than being stopped by `visitor`.
+    pub fn walk<V: TrieVisitor>(
+        &self,
+        sentinel_addr: DiskAddress,
+        visitor: &mut V,
+    ) -> Result<bool, MerkleError> {
+        let Some(root) = self.root_child(sentinel_addr)? else {
+            return Ok(true);
+        };
+
+        let mut steps = vec![Step::Enter(root, Vec::new(), 0)];
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Enter(child, path, depth) => {
+                    let addr = match &child {
+                        Child::Node(addr) => Some(*addr),
+                        Child::Inline(_) => None,
+                    };
+                    let node = self.get_child(child)?;
+                    let context = NodeContext {
+                        depth,
+                        path: &path,
+                        addr,
+                    };
+                    let visit = match &node.inner {
+                        NodeType::Branch(branch) => visitor.enter_branch(&context, branch),
+                        NodeType::Leaf(leaf) => visitor.enter_leaf(&context, leaf),
+                    };
+                    match visit {
+                        Visit::Stop => return Ok(false),
+                        Visit::SkipSubtree => {
+                            steps.push(Step::Exit(node, path, depth));
+                        }
+                        Visit::Continue => {
+                            let children: Vec<_> = match &node.inner {
+                                NodeType::Branch(branch) => branch.children_iter().collect(),
+                                NodeType::Leaf(_) => Vec::new(),
+                            };
+                            let mut full_path = path.clone();
+                            if let NodeType::Branch(branch) = &node.inner {
+                                full_path.extend(branch.partial_path.iter());
+                            }
+                            steps.push(Step::Exit(node, path, depth));
+                            // in reverse, so that the children are entered in key order
+                            for (index, child) in children.into_iter().rev() {
+                                let mut child_path = full_path.clone();
+                                child_path.push(index);
+                                steps.push(Step::Enter(child, child_path, depth + 1));
+                            }
+                        }
+                    }
+                }
+                Step::Exit(node, path, depth) => {
+                    let context = NodeContext {
+                        depth,
+                        path: &path,
+                        addr: match &node {
+                            NodeRef::Stored(node) => Some(node.as_addr()),
+                            NodeRef::Inline(_) => None,
+                        },
+                    };
+                    match &node.inner {
+                        NodeType::Branch(branch) => visitor.exit_branch(&context, branch),
+                        NodeType::Leaf(leaf) => visitor.exit_leaf(&context, leaf),
+                    }
+                }
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod tests {
+    use super::super::tests::create_test_merkle;
+    use super::*;
+
+    /// Records the callbacks, as the kind of node, whether it's entered, its depth and path.
+    #[derive(Default)]
+    struct Recorder {
+        calls: Vec<(&'static str, usize, Vec<u8>)>,
+        skip: Option<Vec<u8>>,
+        stop_at: Option<usize>,
+    }
+
+    impl Recorder {
+        fn visit(&mut self, call: &'static str, context: &NodeContext<'_>) -> Visit {
+            self.calls
+                .push((call, context.depth, context.path.to_vec()));
+            if self.stop_at == Some(self.calls.len()) {
+                Visit::Stop
+            } else if self.skip.as_deref() == Some(context.path) {
+                Visit::SkipSubtree
+            } else {
+                Visit::Continue
+            }
+        }
+    }
+
+    impl TrieVisitor for Recorder {
+        fn enter_branch(&mut self, context: &NodeContext<'_>, _: &BranchNode) -> Visit {
+            self.visit("enter branch", context)
+        }
+
+        fn exit_branch(&mut self, context: &NodeContext<'_>, _: &BranchNode) {
+            self.visit("exit branch", context);
+        }
+
+        fn enter_leaf(&mut self, context: &NodeContext<'_>, _: &LeafNode) -> Visit {
+            self.visit("enter leaf", context)
+        }
+
+        fn exit_leaf(&mut self, context: &NodeContext<'_>, _: &LeafNode) {
+            self.visit("exit leaf", context);
+        }
+    }
+
+    #[test]
+    fn walk_in_key_order() {
+        let mut merkle = create_test_merkle();
+        let sentinel_addr = merkle.init_sentinel().unwrap();
+        assert!(merkle
+            .walk(sentinel_addr, &mut Recorder::default())
+            .unwrap());
+
+        for key in [&[0x12, 0x34][..], &[0x12, 0x56], &[0x78]] {
+            merkle.insert(key, key.to_vec(), sentinel_addr).unwrap();
+        }
+
+        let mut recorder = Recorder::default();
+        assert!(merkle.walk(sentinel_addr, &mut recorder).unwrap());
+        assert_eq!(
+            recorder.calls,
+            vec![
+                ("enter branch", 0, vec![]),
+                ("enter branch", 1, vec![1]),
+                ("enter leaf", 2, vec![1, 2, 3]),
+                ("exit leaf", 2, vec![1, 2, 3]),
+                ("enter leaf", 2, vec![1, 2, 5]),
+                ("exit leaf", 2, vec![1, 2, 5]),
+                ("exit branch", 1, vec![1]),
+                ("enter leaf", 1, vec![7]),
+                ("exit leaf", 1, vec![7]),
+                ("exit branch", 0, vec![]),
+            ]
+        );
+
+        // a skipped subtree is exited right away
+        let mut recorder = Recorder {
+            skip: Some(vec![1]),
+            ..Default::default()
+        };
+        assert!(merkle.walk(sentinel_addr, &mut recorder).unwrap());
+        assert_eq!(recorder.calls.len(), 6);
+        assert_eq!(recorder.calls[2], ("exit branch", 1, vec![1]));
+
+        let mut recorder = Recorder {
+            stop_at: Some(3),
+            ..Default::default()
+        };
+        assert!(!merkle.walk(sentinel_addr, &mut recorder).unwrap());
+        assert_eq!(recorder.calls.len(), 3);
+    }
+}
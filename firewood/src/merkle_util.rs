@@ -88,6 +88,7 @@ where
     pub fn insert<K: AsRef<[u8]>>(&mut self, key: K, val: Vec<u8>) -> Result<(), DataStoreError> {
         self.merkle
             .insert(key, val, self.sentinel_addr)
+            .map(|_| ())
             .map_err(|_err| DataStoreError::InsertionError)
     }
 
@@ -140,6 +141,12 @@ where
             .map_err(|_err| DataStoreError::ProofError)
     }
 
+    pub fn prove_path<K: AsRef<[u8]>>(&self, key: K) -> Result<Vec<Vec<u8>>, DataStoreError> {
+        self.merkle
+            .prove_path(key, self.sentinel_addr)
+            .map_err(|_err| DataStoreError::ProofError)
+    }
+
     pub fn verify_proof<N: AsRef<[u8]> + Send, K: AsRef<[u8]>>(
         &self,
         key: K,
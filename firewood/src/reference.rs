@@ -0,0 +1,622 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! A reference merkle patricia trie, and a harness that checks a [Db] against it.
+//!
+//! The [ReferenceTrie] is as simple as a trie can be: a sorted map of its keys and values, with
+//! the root hash recomputed from scratch every time it is asked for. It shares no code with the
+//! [Merkle](crate::merkle::Merkle) trie, only its encoding of the nodes, so a bug in the way the
+//! trie is restructured by an insert or a remove shows up as a different root hash.
+//!
+//! [check_against_reference] applies the same batches to a [Db] and to a [ReferenceTrie], and
+//! compares the outcome of every batch, the root hash and the value of every key after each
+//! commit. [RandomBatches] generates the batches from a seed, over a small key space so that
+//! the keys share prefixes and the batches overwrite, delete and move existing keys, and delete
+//! their prefixes. Forks that change the trie can run the same checks against their own [Db]:
+//!
+//! ```
+//! # use firewood::{db::{Db, DbConfig}, reference::{check_against_reference, RandomBatches, ReferenceTrie}};
+//! # #[tokio::main(flavor = "multi_thread")]
+//! # async fn main() {
+//! # let path = std::env::temp_dir().join("firewood-reference-doctest");
+//! let db = Db::new(&path, &DbConfig::builder().truncate(true).build()).await.unwrap();
+//! let mut reference = ReferenceTrie::default();
+//! tokio::task::block_in_place(|| {
+//!     check_against_reference(&db, &mut reference, RandomBatches::new(42).take(20)).unwrap()
+//! });
+//! # std::fs::remove_dir_all(&path).unwrap();
+//! # }
+//! ```
+//!
+//! [check_order_independence] checks that the shape of the trie only depends on its keys: the
+//! inserts and deletes of an [UnorderedOps] are applied to fresh tries in several orders, which
+//! must all end with the root hash and the values of the reference. The ops are generated from
+//! a seed, or decoded from arbitrary bytes for a fuzzer to drive.
+
+use crate::{
+    db::{Db, DbError},
+    merkle::{Bincode, TrieHash, TRIE_HASH_LEN},
+    merkle_util::{DataStoreError, InMemoryMerkle},
+    v2::api::{Batch, BatchOp, KeyType, ValueType},
+};
+use bincode::Options;
+use std::collections::{BTreeMap, BTreeSet};
+use thiserror::Error;
+
+/// Nibbles of a key, in the order they are walked from the root.
+type NibblePath = Vec<u8>;
+
+/// An in-memory merkle patricia trie, hashed the way [Merkle](crate::merkle::Merkle) is.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReferenceTrie {
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// A difference between a [Db] and a [ReferenceTrie], or an error reading the [Db].
+#[derive(Debug, Error)]
+pub enum ReferenceError {
+    #[error(transparent)]
+    Db(#[from] DbError),
+    #[error("batch {batch} failed on the db but not on the reference: {error}")]
+    DbFailed { batch: usize, error: DbError },
+    #[error("batch {batch} failed on the reference but not on the db")]
+    ReferenceFailed { batch: usize },
+    #[error("root hash after batch {batch} is {actual:?}, the reference has {expected:?}")]
+    RootMismatch {
+        batch: usize,
+        expected: TrieHash,
+        actual: TrieHash,
+    },
+    #[error("value of {key:?} after batch {batch} is {actual:?}, the reference has {expected:?}")]
+    ValueMismatch {
+        batch: usize,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        actual: Option<Vec<u8>>,
+    },
+    #[error("db has {actual} keys after batch {batch}, the reference has {expected}")]
+    CountMismatch {
+        batch: usize,
+        expected: u64,
+        actual: u64,
+    },
+}
+
+impl ReferenceTrie {
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.entries.get(key).map(Vec::as_slice)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> Option<Vec<u8>> {
+        self.entries.insert(key, value)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.entries.remove(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.entries.keys().map(Vec::as_slice)
+    }
+
+    /// Applies a batch the way a [Db] does, returning `None` without changing anything if the
+    /// batch fails.
+    pub fn apply<K: KeyType, V: ValueType>(&mut self, batch: &[BatchOp<K, V>]) -> Option<()> {
+        let mut entries = self.entries.clone();
+        for op in batch {
+            match op {
+                BatchOp::Put { key, value } => {
+                    entries.insert(key.as_ref().to_vec(), value.as_ref().to_vec());
+                }
+                BatchOp::Delete { key } => {
+                    entries.remove(key.as_ref());
+                }
+                BatchOp::Move {
+                    key,
+                    new_key,
+                    overwrite,
+                } => {
+                    let (key, new_key) = (key.as_ref(), new_key.as_ref());
+                    if key == new_key {
+                        entries.get(key)?;
+                        continue;
+                    }
+                    if entries.contains_key(new_key) && !overwrite {
+                        return None;
+                    }
+                    let value = entries.remove(key)?;
+                    entries.insert(new_key.to_vec(), value);
+                }
+                BatchOp::DeletePrefix { prefix } => {
+                    entries.retain(|key, _| !key.starts_with(prefix.as_ref()));
+                }
+            }
+        }
+        self.entries = entries;
+        Some(())
+    }
+
+    /// The root hash of the trie, recomputed from all of its entries.
+    pub fn root_hash(&self) -> TrieHash {
+        let entries: Vec<(NibblePath, &[u8])> = self
+            .entries
+            .iter()
+            .map(|(key, value)| {
+                let nibbles = key.iter().flat_map(|b| [b >> 4, b & 0xf]).collect();
+                (nibbles, value.as_slice())
+            })
+            .collect();
+        if entries.is_empty() {
+            // the hash of the RLP encoding of an empty string, as in Ethereum
+            TrieHash::of(&[0x80])
+        } else {
+            TrieHash::of(&encode_node(&entries, 0))
+        }
+    }
+}
+
+/// Encodes the node holding `entries`, which are sorted and all share their first `depth`
+/// nibbles. A single entry is a leaf. Otherwise the node is a branch on the longest prefix all
+/// the entries share, holding the value of the entry that ends there, if any.
+#[allow(clippy::indexing_slicing)]
+fn encode_node(entries: &[(NibblePath, &[u8])], depth: usize) -> Vec<u8> {
+    if let [(key, value)] = entries {
+        return serialize(&[encode_path(&key[depth..]), value.to_vec()]);
+    }
+
+    // the entries are sorted, so the first and the last share the shortest prefix
+    let (first, last) = (&entries[0].0, &entries[entries.len() - 1].0);
+    let end = depth
+        + first[depth..]
+            .iter()
+            .zip(&last[depth..])
+            .take_while(|(a, b)| a == b)
+            .count();
+
+    // 16 children, the value, then the partial path
+    let mut list = vec![Vec::new(); 18];
+    let mut children = entries;
+    if let Some(((key, value), rest)) = entries.split_first() {
+        if key.len() == end {
+            list[16] = value.to_vec();
+            children = rest;
+        }
+    }
+    for child in children.chunk_by(|a, b| a.0[end] == b.0[end]) {
+        let encoded = encode_node(child, end + 1);
+        list[child[0].0[end] as usize] = if encoded.len() >= TRIE_HASH_LEN {
+            TrieHash::of(&encoded).to_vec()
+        } else {
+            encoded
+        };
+    }
+    list[17] = encode_path(&first[depth..end]);
+
+    serialize(&list)
+}
+
+/// Packs a partial path into bytes, after a flag nibble that is set if the path has an odd number
+/// of nibbles, and padded to a whole byte otherwise.
+fn encode_path(nibbles: &[u8]) -> Vec<u8> {
+    let mut padded = if !nibbles.len().is_multiple_of(2) {
+        vec![1]
+    } else {
+        vec![0, 0]
+    };
+    padded.extend_from_slice(nibbles);
+    padded
+        .chunks_exact(2)
+        .map(|pair| pair.iter().fold(0, |byte, nibble| (byte << 4) | nibble))
+        .collect()
+}
+
+fn serialize(list: &[Vec<u8>]) -> Vec<u8> {
+    bincode::DefaultOptions::new()
+        .serialize(list)
+        .expect("serializing a list of bytes to always succeed")
+}
+
+/// Proposes and commits every batch on `db`, applying it to `reference` as well, and checks that
+/// they agree: a batch fails on both or on neither, and after each commit the root hash, the
+/// number of keys and the value of every key in either, or in the batch, are the same.
+///
+/// `reference` has to hold the entries of `db` when called, so starts out empty for an empty DB.
+/// The checks stop at the first difference, which is returned.
+pub fn check_against_reference<I>(
+    db: &Db,
+    reference: &mut ReferenceTrie,
+    batches: I,
+) -> Result<(), ReferenceError>
+where
+    I: IntoIterator<Item = Batch<Vec<u8>, Vec<u8>>>,
+{
+    for (batch, ops) in batches.into_iter().enumerate() {
+        let mut keys: BTreeSet<Vec<u8>> = ops
+            .iter()
+            .flat_map(|op| match op {
+                BatchOp::Put { key, .. } | BatchOp::Delete { key } => vec![key.clone()],
+                BatchOp::Move { key, new_key, .. } => vec![key.clone(), new_key.clone()],
+                BatchOp::DeletePrefix { prefix } => reference
+                    .keys()
+                    .filter(|key| key.starts_with(prefix))
+                    .map(<[u8]>::to_vec)
+                    .collect(),
+            })
+            .collect();
+
+        let expected = reference.apply(&ops);
+        let actual = db
+            .new_proposal(ops)
+            .and_then(|proposal| proposal.commit_sync());
+        match (expected, actual) {
+            (Some(()), Ok(())) => {}
+            (None, Err(_)) => continue,
+            (Some(()), Err(error)) => return Err(ReferenceError::DbFailed { batch, error }),
+            (None, Ok(())) => return Err(ReferenceError::ReferenceFailed { batch }),
+        }
+
+        let revision = db.latest_revision();
+        let (expected, actual) = (reference.root_hash(), revision.kv_root_hash()?);
+        if expected != actual {
+            return Err(ReferenceError::RootMismatch {
+                batch,
+                expected,
+                actual,
+            });
+        }
+
+        let (expected, actual) = (reference.len() as u64, revision.counts().keys);
+        if expected != actual {
+            return Err(ReferenceError::CountMismatch {
+                batch,
+                expected,
+                actual,
+            });
+        }
+
+        keys.extend(reference.keys().map(<[u8]>::to_vec));
+        for key in keys {
+            let expected = reference.get(&key).map(<[u8]>::to_vec);
+            let actual = revision.kv_get(&key);
+            if expected != actual {
+                return Err(ReferenceError::ValueMismatch {
+                    batch,
+                    key,
+                    expected,
+                    actual,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The bytes keys are made of, picked so that keys share whole bytes as well as single nibbles.
+const KEY_BYTES: [u8; 5] = [0x00, 0x01, 0x10, 0xab, 0xff];
+/// Keys are at most this long, which keeps the key space small enough for the batches to keep
+/// hitting existing keys.
+const MAX_KEY_LEN: usize = 3;
+/// Values are at most this long, so that nodes are both inlined in their parent and hashed.
+const MAX_VALUE_LEN: usize = 40;
+
+/// An endless, reproducible stream of random batches of puts, deletes, moves and prefix deletes.
+#[derive(Clone, Debug)]
+pub struct RandomBatches {
+    state: u64,
+    max_batch_len: usize,
+}
+
+impl RandomBatches {
+    /// Batches of up to 8 operations; the same seed always generates the same batches.
+    pub const fn new(seed: u64) -> Self {
+        Self {
+            state: seed,
+            max_batch_len: 8,
+        }
+    }
+
+    pub const fn with_max_batch_len(mut self, max_batch_len: usize) -> Self {
+        self.max_batch_len = max_batch_len;
+        self
+    }
+
+    /// splitmix64, which is good enough for this and doesn't need a dependency
+    const fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `1..=max`.
+    fn up_to(&mut self, max: usize) -> usize {
+        (self.next_u64() % max.max(1) as u64) as usize + 1
+    }
+
+    fn key(&mut self) -> Vec<u8> {
+        let len = self.up_to(MAX_KEY_LEN);
+        #[allow(clippy::indexing_slicing)]
+        (0..len)
+            .map(|_| KEY_BYTES[(self.next_u64() % KEY_BYTES.len() as u64) as usize])
+            .collect()
+    }
+
+    fn value(&mut self) -> Vec<u8> {
+        let len = self.up_to(MAX_VALUE_LEN);
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+}
+
+impl Iterator for RandomBatches {
+    type Item = Batch<Vec<u8>, Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = self.up_to(self.max_batch_len);
+        let batch = (0..len)
+            .map(|_| match self.next_u64() % 12 {
+                0..=5 => BatchOp::Put {
+                    key: self.key(),
+                    value: self.value(),
+                },
+                6..=8 => BatchOp::Delete { key: self.key() },
+                9 | 10 => BatchOp::Move {
+                    key: self.key(),
+                    new_key: self.key(),
+                    overwrite: self.next_u64().is_multiple_of(2),
+                },
+                _ => BatchOp::DeletePrefix { prefix: self.key() },
+            })
+            .collect();
+        Some(batch)
+    }
+}
+
+/// A difference between the tries built by applying the same [UnorderedOps] in two orders, or
+/// an error building one of them.
+#[derive(Debug, Error)]
+pub enum OrderError {
+    #[error(transparent)]
+    Merkle(#[from] DataStoreError),
+    #[error(
+        "root hash after the ops in order {order:?} is {actual:?}, the reference has {expected:?}"
+    )]
+    RootMismatch {
+        order: Vec<usize>,
+        expected: TrieHash,
+        actual: TrieHash,
+    },
+    #[error("value of {key:?} after the ops in order {order:?} is {actual:?}, the reference has {expected:?}")]
+    ValueMismatch {
+        order: Vec<usize>,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        actual: Option<Vec<u8>>,
+    },
+}
+
+/// Inserts and deletes of distinct keys, applied on top of a base of entries, so the trie they
+/// end with is the same whatever the order they are applied in. The deletes are of keys of the
+/// base, or of keys that aren't there.
+#[derive(Clone, Debug, Default)]
+pub struct UnorderedOps {
+    pub base: Vec<(Vec<u8>, Vec<u8>)>,
+    pub ops: Vec<BatchOp<Vec<u8>, Vec<u8>>>,
+}
+
+impl UnorderedOps {
+    /// Up to `max_len` entries and as many ops over the key space of [RandomBatches]; the same
+    /// seed always generates the same ops.
+    pub fn random(seed: u64, max_len: usize) -> Self {
+        let mut random = RandomBatches::new(seed);
+        let base: BTreeMap<_, _> = (0..random.up_to(max_len))
+            .map(|_| (random.key(), random.value()))
+            .collect();
+        let mut keys = BTreeSet::new();
+        let mut ops = Vec::new();
+        for _ in 0..random.up_to(max_len) {
+            let key = random.key();
+            if !keys.insert(key.clone()) {
+                continue;
+            }
+            ops.push(match random.next_u64() % 3 {
+                0 => BatchOp::Delete { key },
+                _ => BatchOp::Put {
+                    key,
+                    value: random.value(),
+                },
+            });
+        }
+        Self {
+            base: base.into_iter().collect(),
+            ops,
+        }
+    }
+
+    /// Decodes arbitrary bytes into ops, so a fuzzer explores them: every op takes a tag byte,
+    /// whose low bits pick between a base entry, a put and a delete, and whose high bits give
+    /// the length of the key that follows. A value is the key reversed. The ops after the first
+    /// of a key, and the base entries after an op, are skipped.
+    pub fn from_bytes(mut data: &[u8]) -> Self {
+        let mut unordered = Self::default();
+        let mut keys = BTreeSet::new();
+        while let Some((&tag, rest)) = data.split_first() {
+            let len = ((tag >> 2) as usize).min(rest.len());
+            let (key, rest) = rest.split_at(len);
+            data = rest;
+            if !keys.insert(key.to_vec()) {
+                continue;
+            }
+            let value: Vec<u8> = key.iter().rev().copied().collect();
+            match tag & 0b11 {
+                0 if unordered.ops.is_empty() => unordered.base.push((key.to_vec(), value)),
+                0 => {}
+                1 => unordered.ops.push(BatchOp::Delete { key: key.to_vec() }),
+                _ => unordered.ops.push(BatchOp::Put {
+                    key: key.to_vec(),
+                    value,
+                }),
+            }
+        }
+        unordered
+    }
+
+    /// The trie of the base with the ops applied in `order`, which is a permutation of their
+    /// indexes.
+    fn build(&self, order: &[usize]) -> Result<InMemoryMerkle<Bincode>, DataStoreError> {
+        let mut merkle = InMemoryMerkle::new(0x10000, 0x10000);
+        for (key, value) in &self.base {
+            merkle.insert(key, value.clone())?;
+        }
+        for op in order.iter().filter_map(|&index| self.ops.get(index)) {
+            match op {
+                BatchOp::Put { key, value } => merkle.insert(key, value.clone())?,
+                BatchOp::Delete { key } => {
+                    merkle.remove(key)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(merkle)
+    }
+}
+
+/// Applies `unordered` to fresh tries in the order the ops are listed, in the reverse order,
+/// and in `shuffles` random orders picked from `seed`, and checks that every trie ends with the
+/// root hash of the reference, and that every key of the base and of the ops has the value it
+/// has in the reference.
+///
+/// The checks stop at the first difference, which is returned with the order that caused it.
+pub fn check_order_independence(
+    unordered: &UnorderedOps,
+    shuffles: usize,
+    seed: u64,
+) -> Result<(), OrderError> {
+    let mut reference = ReferenceTrie::default();
+    for (key, value) in &unordered.base {
+        reference.insert(key.clone(), value.clone());
+    }
+    #[allow(clippy::unwrap_used)]
+    reference.apply(&unordered.ops).unwrap();
+    let expected = reference.root_hash();
+    let keys: BTreeSet<&[u8]> = unordered
+        .base
+        .iter()
+        .map(|(key, _)| key.as_slice())
+        .chain(unordered.ops.iter().filter_map(|op| match op {
+            BatchOp::Put { key, .. } | BatchOp::Delete { key } => Some(key.as_slice()),
+            _ => None,
+        }))
+        .collect();
+
+    let mut random = RandomBatches::new(seed);
+    let in_order: Vec<usize> = (0..unordered.ops.len()).collect();
+    let orders = [in_order.clone(), in_order.iter().rev().copied().collect()]
+        .into_iter()
+        .chain((0..shuffles).map(|_| {
+            // Fisher-Yates
+            let mut order = in_order.clone();
+            for i in (1..order.len()).rev() {
+                order.swap(i, random.up_to(i + 1) - 1);
+            }
+            order
+        }));
+
+    for order in orders {
+        let merkle = unordered.build(&order)?;
+        let actual = merkle.root_hash()?;
+        if actual != expected {
+            return Err(OrderError::RootMismatch {
+                order,
+                expected,
+                actual,
+            });
+        }
+        for &key in &keys {
+            let expected = reference.get(key).map(<[u8]>::to_vec);
+            let actual = merkle.get(key)?.map(|value| value.to_vec());
+            if expected != actual {
+                return Err(OrderError::ValueMismatch {
+                    order,
+                    key: key.to_vec(),
+                    expected,
+                    actual,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::merkle::{Bincode, Merkle};
+    use crate::shale::in_mem::InMemLinearStore;
+
+    #[test]
+    fn empty_root() {
+        let mut trie = ReferenceTrie::default();
+        assert_eq!(
+            trie.root_hash(),
+            *Merkle::<InMemLinearStore, Bincode>::empty_root()
+        );
+
+        trie.insert(b"horse".to_vec(), b"stallion".to_vec());
+        assert_ne!(
+            trie.root_hash(),
+            *Merkle::<InMemLinearStore, Bincode>::empty_root()
+        );
+        trie.remove(b"horse");
+        assert_eq!(
+            trie.root_hash(),
+            *Merkle::<InMemLinearStore, Bincode>::empty_root()
+        );
+    }
+
+    #[test]
+    fn failed_batch() {
+        let mut trie = ReferenceTrie::default();
+        trie.insert(b"a".to_vec(), b"1".to_vec());
+        let before = trie.clone();
+
+        // the put is undone when the move fails
+        let batch = [
+            BatchOp::Put {
+                key: b"b".to_vec(),
+                value: b"2".to_vec(),
+            },
+            BatchOp::Move {
+                key: b"a".to_vec(),
+                new_key: b"b".to_vec(),
+                overwrite: false,
+            },
+        ];
+        assert_eq!(trie.apply(&batch), None);
+        assert_eq!(trie, before);
+    }
+
+    #[test]
+    fn ops_from_bytes() {
+        let unordered = UnorderedOps::from_bytes(&[
+            0b1000, b'a', b'b', // base entry "ab"
+            0b0110, b'c', // put "c"
+            0b1000, b'd', b'e', // base entry after an op, skipped
+            0b0101, b'a', // delete "a"
+            0b0110, b'c', // second op of "c", skipped
+            0b1010, b'f', // put "f", cut short
+        ]);
+        assert_eq!(unordered.base, [(b"ab".to_vec(), b"ba".to_vec())]);
+        assert_eq!(unordered.ops.len(), 3);
+        check_order_independence(&unordered, 4, 0).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Strategies for picking the free chunk a [compact store](super::compact::Store) allocates from.
+//!
+//! Freed chunks are recorded in a free list, which every strategy shares, so the strategy of a
+//! store can be changed between opens. An [Allocator] only picks a chunk out of the free list;
+//! the store splits the chunk and updates the list. When no chunk is picked, the object is
+//! appended at the end of the store instead, always within a single region.
+
+use super::{disk_address::DiskAddress, ShaleError};
+use std::fmt::Debug;
+
+/// A chunk in the free list of a compact store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FreeChunk {
+    /// Where the chunk is recorded in the free list.
+    pub desc_addr: DiskAddress,
+    /// Size of the chunk, not counting its header and footer.
+    pub size: u64,
+}
+
+impl FreeChunk {
+    /// Whether an object of `length` bytes can be allocated from this chunk: either it is
+    /// exactly that size, or what is left after splitting it off can hold a chunk of its own.
+    pub const fn fits(&self, length: u64) -> bool {
+        self.size == length || self.size > length + super::compact::CHUNK_OVERHEAD
+    }
+}
+
+/// Picks the free chunk an object is allocated from.
+pub trait Allocator: Debug + Send + Sync {
+    /// Whether the search starts where the previous allocation left off, rather than at the start
+    /// of the free list.
+    fn resume(&self) -> bool {
+        false
+    }
+
+    /// Picks a chunk that [fits](FreeChunk::fits) `length` bytes out of `chunks`, or `None` to
+    /// append the object at the end of the store. `chunks` walks the free list in order from
+    /// where the search starts, and is bounded by the maximum walk of the store.
+    fn pick(
+        &self,
+        length: u64,
+        chunks: &mut dyn Iterator<Item = Result<FreeChunk, ShaleError>>,
+    ) -> Result<Option<FreeChunk>, ShaleError>;
+}
+
+/// Picks the first chunk that fits, resuming the search where the previous allocation left off.
+/// This is the default, and spreads allocations over the whole free list.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NextFit;
+
+impl Allocator for NextFit {
+    fn resume(&self) -> bool {
+        true
+    }
+
+    fn pick(
+        &self,
+        length: u64,
+        chunks: &mut dyn Iterator<Item = Result<FreeChunk, ShaleError>>,
+    ) -> Result<Option<FreeChunk>, ShaleError> {
+        FirstFit.pick(length, chunks)
+    }
+}
+
+/// Picks the first chunk that fits, searching from the start of the free list.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FirstFit;
+
+impl Allocator for FirstFit {
+    fn pick(
+        &self,
+        length: u64,
+        chunks: &mut dyn Iterator<Item = Result<FreeChunk, ShaleError>>,
+    ) -> Result<Option<FreeChunk>, ShaleError> {
+        for chunk in chunks {
+            let chunk = chunk?;
+            if chunk.fits(length) {
+                return Ok(Some(chunk));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Picks the smallest chunk that fits, which leaves the larger chunks for larger objects at the
+/// cost of walking the whole free list unless an exact match is found.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BestFit;
+
+impl Allocator for BestFit {
+    fn pick(
+        &self,
+        length: u64,
+        chunks: &mut dyn Iterator<Item = Result<FreeChunk, ShaleError>>,
+    ) -> Result<Option<FreeChunk>, ShaleError> {
+        let mut best: Option<FreeChunk> = None;
+        for chunk in chunks {
+            let chunk = chunk?;
+            if chunk.size == length {
+                return Ok(Some(chunk));
+            }
+            if chunk.fits(length) && best.is_none_or(|best| chunk.size < best.size) {
+                best = Some(chunk);
+            }
+        }
+        Ok(best)
+    }
+}
+
+/// Picks the first chunk of the same power-of-two size class as the object, falling back to the
+/// first chunk that fits. When objects come in a few sizes, this keeps each size reusing the
+/// chunks freed by objects of the same size, without splitting large chunks.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SegregatedFit;
+
+impl SegregatedFit {
+    const fn class(size: u64) -> u32 {
+        u64::BITS - size.leading_zeros()
+    }
+}
+
+impl Allocator for SegregatedFit {
+    fn pick(
+        &self,
+        length: u64,
+        chunks: &mut dyn Iterator<Item = Result<FreeChunk, ShaleError>>,
+    ) -> Result<Option<FreeChunk>, ShaleError> {
+        let mut first_fit = None;
+        for chunk in chunks {
+            let chunk = chunk?;
+            if !chunk.fits(length) {
+                continue;
+            }
+            if Self::class(chunk.size) == Self::class(length) {
+                return Ok(Some(chunk));
+            }
+            first_fit = first_fit.or(Some(chunk));
+        }
+        Ok(first_fit)
+    }
+}
+
+/// Never reuses freed chunks, and always appends objects at the end of the store. This is the
+/// fastest strategy, for workloads that rarely free anything, at the cost of letting the store
+/// grow by whatever it frees.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bump;
+
+impl Allocator for Bump {
+    fn pick(
+        &self,
+        _length: u64,
+        _chunks: &mut dyn Iterator<Item = Result<FreeChunk, ShaleError>>,
+    ) -> Result<Option<FreeChunk>, ShaleError> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::shale::compact::CHUNK_OVERHEAD;
+
+    fn pick(allocator: &dyn Allocator, length: u64, sizes: &[u64]) -> Option<u64> {
+        let mut chunks = sizes.iter().enumerate().map(|(i, &size)| {
+            Ok(FreeChunk {
+                desc_addr: DiskAddress::from(i + 1),
+                size,
+            })
+        });
+        allocator
+            .pick(length, &mut chunks)
+            .unwrap()
+            .map(|chunk| chunk.size)
+    }
+
+    #[test]
+    fn strategies() {
+        let large = 1000;
+        // a chunk of 32 + CHUNK_OVERHEAD bytes can't hold 32 bytes, there would be nothing left
+        let sizes = [10, large, 32 + CHUNK_OVERHEAD, 33 + CHUNK_OVERHEAD, 32];
+
+        assert_eq!(pick(&FirstFit, 32, &sizes), Some(large));
+        assert_eq!(pick(&NextFit, 32, &sizes), Some(large));
+        assert_eq!(pick(&BestFit, 32, &sizes), Some(32));
+        assert_eq!(pick(&BestFit, 20, &sizes), Some(32 + CHUNK_OVERHEAD));
+        assert_eq!(pick(&SegregatedFit, 32, &sizes), Some(33 + CHUNK_OVERHEAD));
+        assert_eq!(pick(&SegregatedFit, 2000, &sizes), None);
+        assert_eq!(pick(&Bump, 32, &sizes), None);
+    }
+}
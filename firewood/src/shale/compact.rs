@@ -6,13 +6,21 @@ use crate::merkle::Node;
 use crate::shale::ObjCache;
 use crate::storage::{StoreRevMut, StoreRevShared};
 
-use super::disk_address::DiskAddress;
-use super::{LinearStore, Obj, ObjRef, ShaleError, Storable, StoredView};
+use super::allocator::{Allocator, FreeChunk, NextFit};
+use super::disk_address::{DiskAddress, DEFERRED_BIT};
+use super::free_index::FreeIndex;
+use super::{LinearStore, Obj, ObjRef, ShaleError, Storable, StoreId, StoredView};
 use bytemuck::{Pod, Zeroable};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::io::{Cursor, Write};
 use std::num::NonZeroUsize;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
+
+/// Where the hot segment of a store starts, in both its linear stores, see
+/// [Store::with_hot_segment]. Past every offset the rest of the store reaches, so that the
+/// segment is in files of its own.
+pub const HOT_SEGMENT_BASE: usize = 1 << 40;
 
 /// Marks the start of a linear chunk of the store.
 /// The chunk may be freed or in use.
@@ -112,6 +120,9 @@ impl Storable for ChunkFooter {
     }
 }
 
+/// The bytes a chunk takes in the store on top of the object it holds.
+pub(crate) const CHUNK_OVERHEAD: u64 = ChunkHeader::SERIALIZED_LEN + ChunkFooter::SERIALIZED_LEN;
+
 #[derive(Clone, Copy, Debug)]
 struct ChunkDescriptor {
     chunk_size: u64,
@@ -285,6 +296,18 @@ struct StoreInner<M> {
     header: StoreHeaderObjs,
     alloc_max_walk: u64,
     regn_nbit: u64,
+    /// Chunks start at, and are padded up to, a multiple of `1 << align_nbit` bytes, see
+    /// [Store::with_alignment].
+    align_nbit: u64,
+    allocator: Arc<dyn Allocator>,
+    /// The number of objects deferred so far, if allocation is deferred, see
+    /// [Store::with_delayed_allocation].
+    deferred: Option<usize>,
+    /// The free list indexed in memory, if it is, see [Store::with_free_index].
+    free_index: Option<FreeIndex>,
+    /// The header of the pool of chunks of the hot segment, if the store has one, see
+    /// [Store::with_hot_segment].
+    hot: Option<StoreHeaderObjs>,
 }
 
 impl From<StoreInner<StoreRevMut>> for StoreInner<StoreRevShared> {
@@ -295,11 +318,55 @@ impl From<StoreInner<StoreRevMut>> for StoreInner<StoreRevShared> {
             header: value.header,
             alloc_max_walk: value.alloc_max_walk,
             regn_nbit: value.regn_nbit,
+            align_nbit: value.align_nbit,
+            allocator: value.allocator,
+            deferred: None,
+            free_index: value.free_index,
+            hot: value.hot,
         }
     }
 }
 
+/// The error of a header field that broke an invariant, see [Store::check_header].
+const fn corrupt(field: &'static str, value: DiskAddress, error: &'static str) -> ShaleError {
+    ShaleError::CorruptHeader {
+        field,
+        value: match value.0 {
+            Some(value) => value.get(),
+            None => 0,
+        },
+        error,
+    }
+}
+
 impl<M: LinearStore> StoreInner<M> {
+    /// See [Store::check_header].
+    fn check_header(&self) -> Result<(), ShaleError> {
+        const DESCRIPTOR_SIZE: usize = ChunkDescriptor::SERIALIZED_LEN as usize;
+
+        let base = *self.header.base_addr;
+        let descriptors = |field, addr: DiskAddress| {
+            if addr < base {
+                return Err(corrupt(field, addr, "below the base of the free list"));
+            }
+            if !(addr.get() - base.get()).is_multiple_of(DESCRIPTOR_SIZE) {
+                return Err(corrupt(field, addr, "not at a descriptor of the free list"));
+            }
+            Ok(())
+        };
+        if base.is_null() || base.is_deferred() {
+            return Err(corrupt("base_addr", base, "not a store offset"));
+        }
+        descriptors("meta_store_tail", *self.header.meta_store_tail)?;
+        descriptors("alloc_addr", *self.header.alloc_addr)?;
+
+        let data_tail = *self.header.data_store_tail;
+        if data_tail.is_null() || data_tail.is_deferred() {
+            return Err(corrupt("data_store_tail", data_tail, "not a store offset"));
+        }
+        Ok(())
+    }
+
     fn get_data_ref<U: Storable + 'static>(
         &self,
         addr: DiskAddress,
@@ -324,14 +391,30 @@ impl<M: LinearStore> StoreInner<M> {
         // TODO: subtracting two disk addresses is only used here, probably can rewrite this
         // debug_assert!((desc_addr.0 - self.header.base_addr.value.into()) % ChunkDescriptor::SERIALIZED_LEN == 0);
         // Move the last descriptor to the position of the deleted descriptor
+        let tail = *self.header.meta_store_tail;
+        let last_addr = tail
+            .checked_sub(ChunkDescriptor::SERIALIZED_LEN as usize)
+            .filter(|&last_addr| last_addr >= *self.header.base_addr)
+            .ok_or(corrupt(
+                "meta_store_tail",
+                tail,
+                "the free list is empty, no descriptor to delete",
+            ))?;
         #[allow(clippy::unwrap_used)]
         self.header
             .meta_store_tail
-            .modify(|r| *r -= ChunkDescriptor::SERIALIZED_LEN as usize)
+            .modify(|r| *r = last_addr)
             .unwrap();
 
-        if desc_addr != DiskAddress(**self.header.meta_store_tail) {
-            let last_desc = self.get_descriptor(*self.header.meta_store_tail.value)?;
+        if let Some(index) = &mut self.free_index {
+            index.remove(desc_addr);
+        }
+        if desc_addr != last_addr {
+            let last_desc = self.get_descriptor(last_addr)?;
+            if let Some(index) = &mut self.free_index {
+                index.remove(last_addr);
+                index.insert(desc_addr, last_desc.chunk_size);
+            }
 
             let mut desc = self.get_descriptor(desc_addr)?;
             #[allow(clippy::unwrap_used)]
@@ -347,20 +430,49 @@ impl<M: LinearStore> StoreInner<M> {
     }
 
     fn new_descriptor_address(&mut self) -> Result<DiskAddress, ShaleError> {
-        let addr = **self.header.meta_store_tail;
+        let addr = *self.header.meta_store_tail;
+        let tail = addr
+            .checked_add(ChunkDescriptor::SERIALIZED_LEN as usize)
+            .ok_or(corrupt("meta_store_tail", addr, "overflows"))?;
         #[allow(clippy::unwrap_used)]
-        self.header
-            .meta_store_tail
-            .modify(|r| *r += ChunkDescriptor::SERIALIZED_LEN as usize)
-            .unwrap();
+        self.header.meta_store_tail.modify(|r| *r = tail).unwrap();
 
-        Ok(DiskAddress(addr))
+        Ok(addr)
+    }
+
+    /// Runs `f` on the pool of chunks of the hot segment instead of the main one, by swapping
+    /// the header of the hot segment in, or on the main pool if there is no hot segment. The
+    /// free list of the hot segment isn't indexed.
+    fn in_hot_segment<R>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<R, ShaleError>,
+    ) -> Result<R, ShaleError> {
+        let Some(mut hot) = self.hot.take() else {
+            return f(self);
+        };
+        std::mem::swap(&mut self.header, &mut hot);
+        let free_index = self.free_index.take();
+        let result = f(self);
+        std::mem::swap(&mut self.header, &mut hot);
+        self.free_index = free_index;
+        self.hot = Some(hot);
+        result
     }
 
     fn free(&mut self, freed_addr: u64) -> Result<(), ShaleError> {
+        if self.hot.is_some() && freed_addr >= HOT_SEGMENT_BASE as u64 {
+            return self.in_hot_segment(|inner| inner.free(freed_addr));
+        }
         let region_size = 1 << self.regn_nbit;
 
-        let mut freed_header_offset = freed_addr - ChunkHeader::SERIALIZED_LEN;
+        let mut freed_header_offset =
+            freed_addr
+                .checked_sub(ChunkHeader::SERIALIZED_LEN)
+                .ok_or(ShaleError::InvalidObj {
+                    addr: freed_addr as usize,
+                    obj_type: "ChunkHeader",
+                    error: "before the start of the store",
+                })?;
         let mut freed_chunk_size = {
             let header = self.get_header(DiskAddress::from(freed_header_offset as usize))?;
             assert!(!header.is_freed);
@@ -374,8 +486,15 @@ impl<M: LinearStore> StoreInner<M> {
             let prev_footer_offset = freed_header_offset - ChunkFooter::SERIALIZED_LEN;
             let prev_footer = self.get_footer(DiskAddress::from(prev_footer_offset as usize))?;
 
-            let prev_header_offset =
-                prev_footer_offset - prev_footer.chunk_size - ChunkHeader::SERIALIZED_LEN;
+            let prev_header_offset = prev_footer
+                .chunk_size
+                .checked_add(ChunkHeader::SERIALIZED_LEN)
+                .and_then(|len| prev_footer_offset.checked_sub(len))
+                .ok_or(ShaleError::InvalidObj {
+                    addr: prev_footer_offset as usize,
+                    obj_type: "ChunkFooter",
+                    error: "chunk size runs past the start of the store",
+                })?;
             let prev_header = self.get_header(DiskAddress::from(prev_header_offset as usize))?;
 
             if prev_header.is_freed {
@@ -424,6 +543,9 @@ impl<M: LinearStore> StoreInner<M> {
             })
             .unwrap();
         }
+        if let Some(index) = &mut self.free_index {
+            index.insert(desc_addr, freed_chunk_size);
+        }
         let mut freed_header = self.get_header(DiskAddress::from(freed_header_offset as usize))?;
         #[allow(clippy::unwrap_used)]
         freed_header
@@ -443,141 +565,201 @@ impl<M: LinearStore> StoreInner<M> {
         Ok(())
     }
 
+    /// Walks the free list from `start`, wrapping around at its end, for at most
+    /// `alloc_max_walk` chunks.
+    fn free_chunks(
+        &self,
+        start: DiskAddress,
+    ) -> impl Iterator<Item = Result<FreeChunk, ShaleError>> + '_ {
+        const DESCRIPTOR_SIZE: usize = ChunkDescriptor::SERIALIZED_LEN as usize;
+
+        let base = *self.header.base_addr;
+        let tail = *self.header.meta_store_tail;
+        std::iter::successors(Some(start), move |&addr| {
+            let mut next = addr + DESCRIPTOR_SIZE;
+            if next >= tail {
+                next = base;
+            }
+            (next != start).then_some(next)
+        })
+        .take(self.alloc_max_walk as usize)
+        .map(|addr| {
+            self.get_descriptor(addr).map(|desc| FreeChunk {
+                desc_addr: addr,
+                size: desc.chunk_size,
+            })
+        })
+    }
+
     fn alloc_from_freed(&mut self, length: u64) -> Result<Option<u64>, ShaleError> {
         const HEADER_SIZE: usize = ChunkHeader::SERIALIZED_LEN as usize;
         const FOOTER_SIZE: usize = ChunkFooter::SERIALIZED_LEN as usize;
-        const DESCRIPTOR_SIZE: usize = ChunkDescriptor::SERIALIZED_LEN as usize;
 
         let tail = *self.header.meta_store_tail;
         if tail == *self.header.base_addr {
             return Ok(None);
         }
 
-        let mut old_alloc_addr = *self.header.alloc_addr;
+        let mut start = *self.header.base_addr;
+        if self.allocator.resume() && *self.header.alloc_addr < tail {
+            start = *self.header.alloc_addr;
+        }
+
+        let allocator = self.allocator.clone();
+        let picked = match &self.free_index {
+            // the index is walked from the smallest chunk that fits
+            Some(index) => allocator.pick(
+                length,
+                &mut index
+                    .fitting(length)
+                    .take(self.alloc_max_walk as usize)
+                    .map(Ok),
+            )?,
+            None => allocator.pick(length, &mut self.free_chunks(start))?,
+        };
+        let Some(FreeChunk {
+            desc_addr: addr, ..
+        }) = picked
+        else {
+            return Ok(None);
+        };
 
-        if old_alloc_addr >= tail {
-            old_alloc_addr = *self.header.base_addr;
+        let (chunk_size, desc_haddr) = {
+            let desc = self.get_descriptor(addr)?;
+            (desc.chunk_size as usize, desc.haddr)
+        };
+        let chunk = FreeChunk {
+            desc_addr: addr,
+            size: chunk_size as u64,
+        };
+        // a chunk picked by an allocator that doesn't fit is ignored
+        if !chunk.fits(length) {
+            return Ok(None);
         }
+        if chunk_size == length as usize {
+            // perfect match
+            {
+                let mut header = self.get_header(DiskAddress::from(desc_haddr))?;
+                assert_eq!(header.chunk_size as usize, chunk_size);
+                assert!(header.is_freed);
+                #[allow(clippy::unwrap_used)]
+                header.modify(|h| h.is_freed = false).unwrap();
+            }
+            self.delete_descriptor(addr)?;
+        } else {
+            // able to split
+            {
+                let mut lheader = self.get_header(DiskAddress::from(desc_haddr))?;
+                assert_eq!(lheader.chunk_size as usize, chunk_size);
+                assert!(lheader.is_freed);
+                #[allow(clippy::unwrap_used)]
+                lheader
+                    .modify(|h| {
+                        h.is_freed = false;
+                        h.chunk_size = length;
+                    })
+                    .unwrap();
+            }
+            {
+                let mut lfooter = self.get_footer(DiskAddress::from(
+                    desc_haddr + HEADER_SIZE + length as usize,
+                ))?;
+                //assert!(lfooter.chunk_size == chunk_size);
+                #[allow(clippy::unwrap_used)]
+                lfooter.modify(|f| f.chunk_size = length).unwrap();
+            }
 
-        let mut addr = old_alloc_addr;
-        let mut res: Option<u64> = None;
-        for _ in 0..self.alloc_max_walk {
-            assert!(addr < tail);
-            let (chunk_size, desc_haddr) = {
-                let desc = self.get_descriptor(addr)?;
-                (desc.chunk_size as usize, desc.haddr)
-            };
-            let exit = if chunk_size == length as usize {
-                // perfect match
-                {
-                    let mut header = self.get_header(DiskAddress::from(desc_haddr))?;
-                    assert_eq!(header.chunk_size as usize, chunk_size);
-                    assert!(header.is_freed);
-                    #[allow(clippy::unwrap_used)]
-                    header.modify(|h| h.is_freed = false).unwrap();
-                }
-                self.delete_descriptor(addr)?;
-                true
-            } else if chunk_size > length as usize + HEADER_SIZE + FOOTER_SIZE {
-                // able to split
-                {
-                    let mut lheader = self.get_header(DiskAddress::from(desc_haddr))?;
-                    assert_eq!(lheader.chunk_size as usize, chunk_size);
-                    assert!(lheader.is_freed);
-                    #[allow(clippy::unwrap_used)]
-                    lheader
-                        .modify(|h| {
-                            h.is_freed = false;
-                            h.chunk_size = length;
-                        })
-                        .unwrap();
-                }
-                {
-                    let mut lfooter = self.get_footer(DiskAddress::from(
-                        desc_haddr + HEADER_SIZE + length as usize,
-                    ))?;
-                    //assert!(lfooter.chunk_size == chunk_size);
-                    #[allow(clippy::unwrap_used)]
-                    lfooter.modify(|f| f.chunk_size = length).unwrap();
-                }
+            let offset = desc_haddr + HEADER_SIZE + length as usize + FOOTER_SIZE;
+            let rchunk_size = chunk_size - length as usize - FOOTER_SIZE - HEADER_SIZE;
+            let rdesc_addr = self.new_descriptor_address()?;
+            {
+                let mut rdesc = self.get_descriptor(rdesc_addr)?;
+                #[allow(clippy::unwrap_used)]
+                rdesc
+                    .modify(|rd| {
+                        rd.chunk_size = rchunk_size as u64;
+                        rd.haddr = offset;
+                    })
+                    .unwrap();
+            }
+            if let Some(index) = &mut self.free_index {
+                index.insert(rdesc_addr, rchunk_size as u64);
+            }
+            {
+                let mut rheader = self.get_header(DiskAddress::from(offset))?;
+                #[allow(clippy::unwrap_used)]
+                rheader
+                    .modify(|rh| {
+                        rh.is_freed = true;
+                        rh.chunk_size = rchunk_size as u64;
+                        rh.desc_addr = rdesc_addr;
+                    })
+                    .unwrap();
+            }
+            {
+                let mut rfooter =
+                    self.get_footer(DiskAddress::from(offset + HEADER_SIZE + rchunk_size))?;
+                #[allow(clippy::unwrap_used)]
+                rfooter
+                    .modify(|f| f.chunk_size = rchunk_size as u64)
+                    .unwrap();
+            }
+            self.delete_descriptor(addr)?;
+        }
 
-                let offset = desc_haddr + HEADER_SIZE + length as usize + FOOTER_SIZE;
-                let rchunk_size = chunk_size - length as usize - FOOTER_SIZE - HEADER_SIZE;
-                let rdesc_addr = self.new_descriptor_address()?;
-                {
-                    let mut rdesc = self.get_descriptor(rdesc_addr)?;
-                    #[allow(clippy::unwrap_used)]
-                    rdesc
-                        .modify(|rd| {
-                            rd.chunk_size = rchunk_size as u64;
-                            rd.haddr = offset;
-                        })
-                        .unwrap();
-                }
-                {
-                    let mut rheader = self.get_header(DiskAddress::from(offset))?;
-                    #[allow(clippy::unwrap_used)]
-                    rheader
-                        .modify(|rh| {
-                            rh.is_freed = true;
-                            rh.chunk_size = rchunk_size as u64;
-                            rh.desc_addr = rdesc_addr;
-                        })
-                        .unwrap();
-                }
-                {
-                    let mut rfooter =
-                        self.get_footer(DiskAddress::from(offset + HEADER_SIZE + rchunk_size))?;
-                    #[allow(clippy::unwrap_used)]
-                    rfooter
-                        .modify(|f| f.chunk_size = rchunk_size as u64)
-                        .unwrap();
-                }
-                self.delete_descriptor(addr)?;
-                true
-            } else {
-                false
-            };
+        #[allow(clippy::unwrap_used)]
+        self.header.alloc_addr.modify(|r| *r = addr).unwrap();
+        Ok(Some((desc_haddr + HEADER_SIZE) as u64))
+    }
+
+    fn alloc_new(&mut self, alloc_size: u64) -> Result<u64, ShaleError> {
+        // the chunks are only aligned from the first aligned offset after the base of the store,
+        // the gap up to it is filled with a chunk that is never freed
+        let align = 1 << self.align_nbit;
+        loop {
             #[allow(clippy::unwrap_used)]
-            if exit {
-                self.header.alloc_addr.modify(|r| *r = addr).unwrap();
-                res = Some((desc_haddr + HEADER_SIZE) as u64);
+            let misalignment = self.header.data_store_tail.unwrap().get() as u64 & (align - 1);
+            if misalignment == 0 {
                 break;
             }
-            addr += DESCRIPTOR_SIZE;
-            if addr >= tail {
-                addr = *self.header.base_addr;
-            }
-            if addr == old_alloc_addr {
-                break;
+            let mut gap = align - misalignment;
+            if gap < CHUNK_OVERHEAD {
+                gap += align;
             }
+            self.append(gap - CHUNK_OVERHEAD)?;
         }
-        Ok(res)
+        self.append(alloc_size)
     }
 
-    fn alloc_new(&mut self, alloc_size: u64) -> Result<u64, ShaleError> {
+    /// Allocates a chunk of `alloc_size` bytes at the end of the store.
+    fn append(&mut self, alloc_size: u64) -> Result<u64, ShaleError> {
         let region_size = 1 << self.regn_nbit;
         let new_chunk_size = ChunkHeader::SERIALIZED_LEN + alloc_size + ChunkFooter::SERIALIZED_LEN;
-        let mut free_chunk_header_offset = *self.header.data_store_tail;
+        let tail = *self.header.data_store_tail;
+        let overflow = || corrupt("data_store_tail", tail, "overflows");
+
+        // an item is always fully in one region
+        // TODO danlaine: we should document the above better. Where is this guaranteed?
+        let mut free_chunk_header_offset = tail;
+        let remaining_region_size = region_size - (tail & (region_size - 1)).get();
+        if remaining_region_size < new_chunk_size as usize {
+            // There is not enough space in the current region for this alloc.
+            // Move to the next region.
+            free_chunk_header_offset = tail
+                .checked_add(remaining_region_size)
+                .ok_or_else(overflow)?;
+        }
+        // the addresses from the deferred bit up are the provisional ones, never store offsets
+        let new_tail = usize::try_from(new_chunk_size)
+            .ok()
+            .and_then(|size| free_chunk_header_offset.checked_add(size))
+            .filter(|new_tail| !new_tail.is_deferred())
+            .ok_or_else(overflow)?;
 
         #[allow(clippy::unwrap_used)]
         self.header
             .data_store_tail
-            .modify(|data_store_tail| {
-                // an item is always fully in one region
-                // TODO danlaine: we should document the above better. Where is this guaranteed?
-                let remaining_region_size =
-                    region_size - (free_chunk_header_offset & (region_size - 1)).get();
-
-                if remaining_region_size < new_chunk_size as usize {
-                    // There is not enough space in the current region for this alloc.
-                    // Move to the next region.
-                    free_chunk_header_offset += remaining_region_size;
-                    *data_store_tail += remaining_region_size;
-                }
-
-                *data_store_tail += new_chunk_size as usize
-            })
+            .modify(|data_store_tail| *data_store_tail = new_tail)
             .unwrap();
 
         let mut free_chunk_header = self.get_header(free_chunk_header_offset)?;
@@ -609,7 +791,14 @@ impl<M: LinearStore> StoreInner<M> {
         )
     }
 
+    /// The size of the chunk allocated for an object of `length` bytes: the smallest that ends
+    /// at the alignment of the store, so that the next chunk starts there.
+    const fn aligned_len(&self, length: u64) -> u64 {
+        (length + CHUNK_OVERHEAD).next_multiple_of(1 << self.align_nbit) - CHUNK_OVERHEAD
+    }
+
     fn alloc(&mut self, length: u64) -> Result<u64, ShaleError> {
+        let length = self.aligned_len(length);
         self.alloc_from_freed(length).and_then(|addr| {
             if let Some(addr) = addr {
                 Ok(addr)
@@ -642,17 +831,153 @@ impl<T: Storable, M: LinearStore> Store<T, M> {
                 header: StoreHeader::into_fields(header)?,
                 alloc_max_walk,
                 regn_nbit,
+                align_nbit: 0,
+                allocator: Arc::new(NextFit),
+                deferred: None,
+                free_index: None,
+                hot: None,
             }),
             obj_cache,
         };
         Ok(cs)
     }
+
+    /// Picks the free chunks objects are allocated from with `allocator` instead of [NextFit].
+    #[allow(clippy::unwrap_used)]
+    pub fn with_allocator(self, allocator: Arc<dyn Allocator>) -> Self {
+        self.inner.write().unwrap().allocator = allocator;
+        self
+    }
+
+    /// Defers allocating the objects put into the store until they are flushed. Until then, they
+    /// have a provisional address and are held by the cache, so an object freed before the
+    /// flush never takes any space, and the objects put together are allocated together. Only
+    /// stores of [Node]s can be flushed with deferred objects, see [Store::allocate_deferred].
+    #[allow(clippy::unwrap_used)]
+    pub fn with_delayed_allocation(self, enabled: bool) -> Self {
+        self.inner.write().unwrap().deferred = enabled.then_some(0);
+        self
+    }
+
+    /// Starts every chunk at, and pads it up to, a multiple of `1 << align_nbit` bytes, from the
+    /// first such offset after the base of the store. The alignment must not be greater than the
+    /// regions of the store, and the chunks only stay aligned if the store is always written
+    /// with the same alignment.
+    #[allow(clippy::unwrap_used)]
+    pub fn with_alignment(self, align_nbit: u64) -> Self {
+        self.inner.write().unwrap().align_nbit = align_nbit;
+        self
+    }
+
+    /// Keeps the free list indexed in memory, starting from `index`, which must index the free
+    /// list the store starts with, see [Store::build_free_index]. The chunks an object fits in
+    /// are then picked from the index, the smallest first, rather than by walking the free list.
+    /// `None` walks the free list again.
+    #[allow(clippy::unwrap_used)]
+    pub fn with_free_index(self, index: Option<FreeIndex>) -> Self {
+        self.inner.write().unwrap().free_index = index;
+        self
+    }
+
+    /// Allocates the objects moved by [Store::relocate_hot] from a second pool of chunks, of
+    /// header `header`, whose chunks start at [HOT_SEGMENT_BASE] in the data store and whose
+    /// free list starts there in the meta store. The objects of the hot segment are read like
+    /// any other, and their chunks are freed back to the segment.
+    #[allow(clippy::unwrap_used)]
+    pub fn with_hot_segment(self, header: Obj<StoreHeader>) -> Result<Self, ShaleError> {
+        self.inner.write().unwrap().hot = Some(StoreHeader::into_fields(header)?);
+        Ok(self)
+    }
+
+    /// Whether `addr` is in the hot segment of the store.
+    #[allow(clippy::unwrap_used)]
+    pub fn is_hot(&self, addr: DiskAddress) -> bool {
+        self.inner.read().unwrap().hot.is_some()
+            && !addr.is_deferred()
+            && addr >= DiskAddress::from(HOT_SEGMENT_BASE)
+    }
+
+    /// The range of the data store the chunks of the hot segment are in so far, if the store
+    /// has a hot segment.
+    #[allow(clippy::unwrap_used)]
+    pub fn hot_segment(&self) -> Option<std::ops::Range<usize>> {
+        let inner = self.inner.read().unwrap();
+        let tail = inner.hot.as_ref()?.data_store_tail.get();
+        Some(HOT_SEGMENT_BASE..tail)
+    }
+
+    /// The index of the free list, if it is indexed, as of the objects allocated and freed so far.
+    #[allow(clippy::unwrap_used)]
+    pub fn free_index(&self) -> Option<FreeIndex> {
+        self.inner.read().unwrap().free_index.clone()
+    }
+
+    /// The number of free chunks and their total size, if the free list is indexed, without
+    /// copying the index.
+    #[allow(clippy::unwrap_used)]
+    pub fn free_space(&self) -> Option<(usize, u64)> {
+        let inner = self.inner.read().unwrap();
+        let index = inner.free_index.as_ref()?;
+        Some((index.len(), index.total_size()))
+    }
+
+    /// The end of the chunks of the data store outside of the hot segment, free chunks
+    /// included.
+    #[allow(clippy::unwrap_used)]
+    pub fn data_tail(&self) -> usize {
+        self.inner.read().unwrap().header.data_store_tail.get()
+    }
+
+    /// Checks the invariants of the header of the store: the tail of the free list and the
+    /// descriptor the allocations resume from are whole descriptors past its base, and the tails
+    /// of both linear stores are store offsets. Fails with [ShaleError::CorruptHeader] on the
+    /// first field that isn't.
+    #[allow(clippy::unwrap_used)]
+    pub fn check_header(&self) -> Result<(), ShaleError> {
+        self.inner.read().unwrap().check_header()
+    }
+
+    /// Indexes the free list, reading every descriptor of it, and checks that each points at a
+    /// freed chunk whose header and footer record the same size and point back at it. Fails
+    /// with [ShaleError::InvalidObj] on the first descriptor that doesn't.
+    #[allow(clippy::unwrap_used)]
+    pub fn build_free_index(&self) -> Result<FreeIndex, ShaleError> {
+        const DESCRIPTOR_SIZE: usize = ChunkDescriptor::SERIALIZED_LEN as usize;
+
+        let inner = self.inner.read().unwrap();
+        let invalid = |addr: DiskAddress, error| ShaleError::InvalidObj {
+            addr: addr.get(),
+            obj_type: "ChunkDescriptor",
+            error,
+        };
+
+        let mut index = FreeIndex::default();
+        let mut desc_addr = *inner.header.base_addr;
+        while desc_addr < *inner.header.meta_store_tail {
+            let desc = inner.get_descriptor(desc_addr)?;
+            let header = inner.get_header(DiskAddress::from(desc.haddr))?;
+            if !header.is_freed || header.desc_addr != desc_addr {
+                return Err(invalid(desc_addr, "chunk not freed by this descriptor"));
+            }
+            let footer = inner.get_footer(DiskAddress::from(
+                desc.haddr + ChunkHeader::SERIALIZED_LEN as usize + desc.chunk_size as usize,
+            ))?;
+            if header.chunk_size != desc.chunk_size || footer.chunk_size != desc.chunk_size {
+                return Err(invalid(desc_addr, "chunk size differs from the descriptor"));
+            }
+            index.insert(desc_addr, desc.chunk_size);
+            desc_addr += DESCRIPTOR_SIZE;
+        }
+        Ok(index)
+    }
 }
 
 impl From<Store<Node, StoreRevMut>> for Store<Node, StoreRevShared> {
     #[allow(clippy::unwrap_used)]
     fn from(value: Store<Node, StoreRevMut>) -> Self {
         let inner = value.inner.into_inner().unwrap();
+        // the nodes are committed and can't change anymore, so their readers can share them
+        value.obj_cache.freeze();
         Store {
             inner: RwLock::new(inner.into()),
             obj_cache: value.obj_cache,
@@ -661,10 +986,35 @@ impl From<Store<Node, StoreRevMut>> for Store<Node, StoreRevShared> {
 }
 
 impl<T: Storable + Debug + 'static, M: LinearStore> Store<T, M> {
+    /// Puts an item into the store, with room for it to grow by `extra` bytes. Its allocation is
+    /// deferred if the store defers allocations.
     pub(crate) fn put_item(&self, item: T, extra: u64) -> Result<ObjRef<'_, T>, ShaleError> {
-        let size = item.serialized_len() + extra;
-        #[allow(clippy::unwrap_used)]
-        let addr = self.inner.write().unwrap().alloc(size)?;
+        self.put(item, extra, true)
+    }
+
+    /// Puts an item into the store, allocating it right away even if the store defers
+    /// allocations, for items whose address is recorded outside of the store.
+    pub(crate) fn put_item_allocated(
+        &self,
+        item: T,
+        extra: u64,
+    ) -> Result<ObjRef<'_, T>, ShaleError> {
+        self.put(item, extra, false)
+    }
+
+    fn put(&self, item: T, extra: u64, defer: bool) -> Result<ObjRef<'_, T>, ShaleError> {
+        let (addr, size) = {
+            #[allow(clippy::unwrap_used)]
+            let mut inner = self.inner.write().unwrap();
+            if let Some(deferred) = inner.deferred.as_mut().filter(|_| defer) {
+                // a deferred item can grow as much as it wants until it is allocated
+                *deferred += 1;
+                ((DEFERRED_BIT | *deferred) as u64, u64::MAX)
+            } else {
+                let size = item.serialized_len() + extra;
+                (inner.alloc(size)?, size)
+            }
+        };
 
         trace!("{self:p} put_item at {addr} size {size}");
 
@@ -693,21 +1043,104 @@ impl<T: Storable + Debug + 'static, M: LinearStore> Store<T, M> {
     pub(crate) fn free_item(&mut self, addr: DiskAddress) -> Result<(), ShaleError> {
         let mut inner = self.inner.write().unwrap();
         self.obj_cache.pop(addr);
+        if addr.is_deferred() {
+            return Ok(());
+        }
         #[allow(clippy::unwrap_used)]
         inner.free(addr.unwrap().get() as u64)
     }
 
+    /// Moves the item at `addr` to a newly allocated chunk of the same size, frees the chunk it
+    /// was in, and returns its new address. The item is moved as it is in memory, modified or
+    /// not, but the items pointing at it still point at `addr`, which is for the caller to fix.
+    /// Fails if the item isn't allocated yet or is in use, or if the store is immutable.
+    pub fn relocate(&mut self, addr: DiskAddress) -> Result<DiskAddress, ShaleError> {
+        self.relocate_to(addr, false)
+    }
+
+    /// Moves the item at `addr` to the hot segment of the store, see [Store::relocate] and
+    /// [Store::with_hot_segment]. Without a hot segment, the item is moved like any other.
+    pub fn relocate_hot(&mut self, addr: DiskAddress) -> Result<DiskAddress, ShaleError> {
+        self.relocate_to(addr, true)
+    }
+
+    #[allow(clippy::unwrap_used)]
+    fn relocate_to(&mut self, addr: DiskAddress, hot: bool) -> Result<DiskAddress, ShaleError> {
+        let invalid = |error| ShaleError::InvalidObj {
+            addr: addr.get(),
+            obj_type: std::any::type_name::<T>(),
+            error,
+        };
+        if addr.is_deferred() {
+            return Err(invalid("not allocated yet"));
+        }
+        if self.obj_cache.lock().pinned.contains_key(&addr) {
+            return Err(invalid("in use"));
+        }
+        if !self.inner.read().unwrap().data_store.is_writeable() {
+            return Err(invalid("immutable store"));
+        }
+
+        let bytes = {
+            let item = self.get_item(addr)?;
+            let mut bytes = vec![0; item.serialized_len() as usize];
+            item.serialize(&mut bytes)?;
+            bytes
+        };
+        // the item is written where it moves, so what the cache holds of it is stale
+        self.obj_cache.pop(addr);
+
+        let mut inner = self.inner.write().unwrap();
+        let chunk_size = inner
+            .get_header(addr - ChunkHeader::SERIALIZED_LEN as usize)?
+            .chunk_size;
+        let new_addr = if hot {
+            inner.in_hot_segment(|inner| inner.alloc(chunk_size))?
+        } else {
+            inner.alloc(chunk_size)?
+        };
+        inner.data_store.write(new_addr as usize, &bytes)?;
+        inner.free(addr.get() as u64)?;
+        Ok(DiskAddress::from(new_addr as usize))
+    }
+
     pub(crate) fn get_item(&self, addr: DiskAddress) -> Result<ObjRef<'_, T>, ShaleError> {
-        let obj = self.obj_cache.get(addr)?;
+        self.read_item(addr, true)
+    }
 
+    /// Reads the item at `addr` like [Store::get_item], except that an item that isn't cached
+    /// stays out of the cache, so that a scan over many items doesn't evict the hot ones.
+    pub(crate) fn scan_item(&self, addr: DiskAddress) -> Result<ObjRef<'_, T>, ShaleError> {
+        self.read_item(addr, false)
+    }
+
+    fn read_item(
+        &self,
+        addr: DiskAddress,
+        cache_misses: bool,
+    ) -> Result<ObjRef<'_, T>, ShaleError> {
         #[allow(clippy::unwrap_used)]
         let inner = self.inner.read().unwrap();
         let cache = &self.obj_cache;
 
-        if let Some(obj) = obj {
+        // the items of an immutable store are never modified, so all their readers share them
+        let immutable = !inner.data_store.is_writeable();
+        if immutable {
+            if let Some(obj) = cache.get_shared(addr) {
+                return Ok(ObjRef::shared(obj, cache));
+            }
+        } else if let Some(obj) = cache.get(addr)? {
             return Ok(ObjRef::new(obj, cache));
         }
 
+        if addr.is_deferred() {
+            return Err(ShaleError::InvalidObj {
+                addr: addr.get(),
+                obj_type: std::any::type_name::<T>(),
+                error: "freed before it was allocated",
+            });
+        }
+
         #[allow(clippy::unwrap_used)]
         if addr < DiskAddress::from(StoreHeader::SERIALIZED_LEN as usize) {
             return Err(ShaleError::InvalidAddressLength {
@@ -719,27 +1152,176 @@ impl<T: Storable + Debug + 'static, M: LinearStore> Store<T, M> {
         let chunk_size = inner
             .get_header(addr - ChunkHeader::SERIALIZED_LEN as usize)?
             .chunk_size;
-        let obj = self.obj_cache.put(inner.get_data_ref(addr, chunk_size)?);
-        let cache = &self.obj_cache;
+        let obj = inner.get_data_ref(addr, chunk_size)?;
+
+        if !cache_misses {
+            return Ok(ObjRef::private(obj, cache));
+        }
+        if immutable {
+            return Ok(ObjRef::shared(cache.put_shared(obj), cache));
+        }
+        Ok(ObjRef::new(cache.put(obj), cache))
+    }
+
+    /// Returns the addresses of up to `limit` cached items, starting with the
+    /// most recently used one.
+    pub(crate) fn cached_addresses(&self, limit: usize) -> Vec<DiskAddress> {
+        self.obj_cache.addresses(limit)
+    }
+
+    pub(crate) fn set_cache_max_bytes(&self, max_bytes: Option<usize>) {
+        self.obj_cache.set_max_bytes(max_bytes)
+    }
+
+    pub(crate) fn clear_clean_cache(&self) -> usize {
+        self.obj_cache.clear_clean()
+    }
+
+    /// Returns the id of the data store, and `length` of its bytes from `offset` if they can be
+    /// read, for the reports of the items that fail to read.
+    #[allow(clippy::unwrap_used)]
+    pub(crate) fn data_bytes(&self, offset: usize, length: u64) -> (StoreId, Option<Vec<u8>>) {
+        let inner = self.inner.read().unwrap();
+        let bytes = inner
+            .data_store
+            .get_view(offset, length)
+            .map(|view| view.as_deref());
+        (inner.data_store.id(), bytes)
+    }
 
-        Ok(ObjRef::new(obj, cache))
+    /// Returns the meta and data stores the items are read from.
+    #[allow(clippy::unwrap_used)]
+    pub(crate) fn linear_stores(&self) -> (M, M)
+    where
+        M: Clone,
+    {
+        let inner = self.inner.read().unwrap();
+        (inner.meta_store.clone(), inner.data_store.clone())
     }
 
     #[allow(clippy::unwrap_used)]
     pub(crate) fn flush_dirty(&self) -> Option<()> {
         let mut inner = self.inner.write().unwrap();
         inner.header.flush_dirty();
+        if let Some(hot) = &mut inner.hot {
+            hot.flush_dirty();
+        }
         // hold the write lock to ensure that both cache and header are flushed in-sync
         self.obj_cache.flush_dirty()
     }
 }
 
+impl<M: LinearStore> Store<Node, M> {
+    /// Allocates the deferred nodes, see [Store::with_delayed_allocation], and points their
+    /// parents at where they were allocated. The nodes are laid out depth first from the
+    /// topmost ones, so the nodes along a path are next to each other whenever they are
+    /// allocated from the end of the store, as the [Bump](super::allocator::Bump) allocator
+    /// always does. Nothing is allocated while some nodes are in use.
+    pub(crate) fn allocate_deferred(&self) -> Result<(), ShaleError> {
+        let deferred = match self.obj_cache.take_deferred() {
+            Some(deferred) if !deferred.is_empty() => deferred,
+            _ => return Ok(()),
+        };
+
+        let index: HashMap<_, _> = deferred
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (node.as_addr(), i))
+            .collect();
+        let referenced: HashSet<_> = deferred
+            .iter()
+            .flat_map(|node| node.deferred_children())
+            .collect();
+        let mut roots: Vec<_> = deferred
+            .iter()
+            .map(|node| node.as_addr())
+            .filter(|addr| !referenced.contains(addr))
+            .collect();
+        roots.sort_unstable();
+
+        let mut order = Vec::with_capacity(deferred.len());
+        let mut visited = vec![false; deferred.len()];
+        let mut stack: Vec<_> = roots
+            .iter()
+            .rev()
+            .filter_map(|addr| index.get(addr))
+            .copied()
+            .collect();
+        while let Some(i) = stack.pop() {
+            #[allow(clippy::indexing_slicing)]
+            if std::mem::replace(&mut visited[i], true) {
+                continue;
+            }
+            order.push(i);
+            #[allow(clippy::indexing_slicing)]
+            let children: Vec<_> = deferred[i].deferred_children().collect();
+            stack.extend(
+                children
+                    .iter()
+                    .rev()
+                    .filter_map(|addr| index.get(addr))
+                    .copied(),
+            );
+        }
+
+        let mut relocated = HashMap::with_capacity(order.len());
+        let mut sizes = Vec::with_capacity(order.len());
+        {
+            #[allow(clippy::unwrap_used)]
+            let mut inner = self.inner.write().unwrap();
+            for &i in &order {
+                #[allow(clippy::indexing_slicing)]
+                let node = &deferred[i];
+                let size = node.serialized_len();
+                let addr = DiskAddress::from(inner.alloc(size)? as usize);
+                relocated.insert(node.as_addr(), addr);
+                sizes.push((addr, size));
+            }
+        }
+
+        let mut nodes: Vec<_> = deferred.into_iter().map(Some).collect();
+        let nodes = order
+            .iter()
+            .zip(sizes)
+            .filter_map(|(&i, (addr, size))| {
+                #[allow(clippy::indexing_slicing)]
+                let mut node = nodes[i].take()?;
+                // the node was put as dirty, and stays so until it is written where it belongs
+                #[allow(clippy::unwrap_used)]
+                node.modify(|node| node.relocate_children(&relocated))
+                    .unwrap();
+                node.relocate(addr, size);
+                Some(node)
+            })
+            .collect();
+        self.obj_cache.insert_relocated(nodes);
+
+        // only the nodes modified since the last flush can point at a node that was deferred
+        for addr in self.obj_cache.dirty_addresses() {
+            let mut node = self.get_item(addr)?;
+            if node.deferred_children().next().is_some() {
+                node.write(|node| node.relocate_children(&relocated))
+                    .map_err(|_| ShaleError::InvalidObj {
+                        addr: addr.get(),
+                        obj_type: std::any::type_name::<Node>(),
+                        error: "relocated children don't fit",
+                    })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 #[allow(clippy::indexing_slicing, clippy::unwrap_used)]
 mod tests {
     use sha3::Digest;
 
-    use crate::shale::{self, in_mem::InMemLinearStore};
+    use crate::shale::{
+        self, in_mem::InMemLinearStore, LinearStoreView, ObjWriteError, SendSyncDerefMut, StoreId,
+        StoreStats,
+    };
 
     use super::*;
 
@@ -786,11 +1368,14 @@ mod tests {
         }
     }
 
-    #[test]
-    fn test_store_item() {
+    fn new_store() -> Store<Hash, InMemLinearStore> {
+        let reserved = NonZeroUsize::new(0x1000).unwrap();
+        new_store_with(StoreHeader::new(reserved, reserved))
+    }
+
+    fn new_store_with(header: StoreHeader) -> Store<Hash, InMemLinearStore> {
         let meta_size: NonZeroUsize = NonZeroUsize::new(0x10000).unwrap();
         let compact_size: NonZeroUsize = NonZeroUsize::new(0x10000).unwrap();
-        let reserved: DiskAddress = 0x1000.into();
 
         let mut dm = InMemLinearStore::new(meta_size.get() as u64, 0x0);
 
@@ -798,8 +1383,7 @@ mod tests {
         let compact_header = DiskAddress::from(0x1);
         dm.write(
             compact_header.unwrap().get(),
-            &shale::to_dehydrated(&StoreHeader::new(reserved.0.unwrap(), reserved.0.unwrap()))
-                .unwrap(),
+            &shale::to_dehydrated(&header).unwrap(),
         )
         .unwrap();
         let compact_header =
@@ -808,7 +1392,12 @@ mod tests {
         let mem_payload = InMemLinearStore::new(compact_size.get() as u64, 0x1);
 
         let cache: ObjCache<Hash> = ObjCache::new(1);
-        let store = Store::new(mem_meta, mem_payload, compact_header, cache, 10, 16).unwrap();
+        Store::new(mem_meta, mem_payload, compact_header, cache, 10, 16).unwrap()
+    }
+
+    #[test]
+    fn test_store_item() {
+        let store = new_store();
 
         // initial write
         let data = b"hello world";
@@ -845,4 +1434,288 @@ mod tests {
             hash
         );
     }
+
+    #[test]
+    fn freeze() {
+        let store = new_store();
+        let addr = store.put_item(Hash([1; HASH_SIZE]), 0).unwrap().as_addr();
+        store.flush_dirty().unwrap();
+        let bytes = store.obj_cache.bytes();
+
+        store.obj_cache.freeze();
+        assert!(store.obj_cache.lock().cached.is_empty());
+        assert_eq!(store.obj_cache.bytes(), bytes);
+        assert_eq!(store.obj_cache.get_shared(addr).unwrap().0, [1; HASH_SIZE]);
+    }
+
+    #[test]
+    fn max_bytes() {
+        let store = new_store();
+        store.obj_cache.resize(16);
+        let addrs: Vec<_> = (0..4)
+            .map(|i| store.put_item(Hash([i; HASH_SIZE]), 0).unwrap().as_addr())
+            .collect();
+        let size = store.obj_cache.bytes() / addrs.len();
+
+        // dirty objects aren't evicted before they are written back
+        store.obj_cache.set_max_bytes(Some(2 * size));
+        assert_eq!(store.obj_cache.lock().cached.len(), 4);
+
+        store.flush_dirty().unwrap();
+        store.obj_cache.set_max_bytes(Some(2 * size));
+        assert_eq!(store.obj_cache.bytes(), 2 * size);
+        assert_eq!(store.obj_cache.addresses(4), [addrs[3], addrs[2]]);
+
+        // reading an evicted object caches it again, in place of the least recently used one
+        assert_eq!(store.get_item(addrs[0]).unwrap().0, [0; HASH_SIZE]);
+        assert_eq!(store.obj_cache.bytes(), 2 * size);
+        assert_eq!(store.obj_cache.addresses(4), [addrs[0], addrs[3]]);
+    }
+
+    #[test]
+    fn relocate() {
+        let mut store = new_store();
+        let flushed = store.put_item(Hash([1; HASH_SIZE]), 0).unwrap().as_addr();
+        store.flush_dirty().unwrap();
+        let mut modified = store.put_item(Hash([2; HASH_SIZE]), 0).unwrap();
+        modified.write(|hash| hash.0 = [3; HASH_SIZE]).unwrap();
+        let modified = modified.into_ptr();
+
+        for (addr, hash) in [(flushed, [1; HASH_SIZE]), (modified, [3; HASH_SIZE])] {
+            let new_addr = store.relocate(addr).unwrap();
+            assert_ne!(new_addr, addr);
+            assert_eq!(store.get_item(new_addr).unwrap().0, hash);
+        }
+
+        // the freed chunks are reused
+        let reused = store.put_item(Hash([4; HASH_SIZE]), 0).unwrap().as_addr();
+        assert!([flushed, modified].contains(&reused));
+
+        // an item checked out of the cache is in use
+        let _item = store.obj_cache.get(reused).unwrap();
+        assert!(matches!(
+            store.relocate(reused),
+            Err(ShaleError::InvalidObj {
+                error: "in use",
+                ..
+            })
+        ));
+    }
+
+    /// A read-only view of another store.
+    struct ReadOnly(Box<dyn SendSyncDerefMut<Target = dyn LinearStore>>);
+
+    impl Debug for ReadOnly {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("ReadOnly")
+        }
+    }
+
+    impl LinearStore for ReadOnly {
+        fn get_view(
+            &self,
+            offset: usize,
+            length: u64,
+        ) -> Option<Box<dyn LinearStoreView<DerefReturn = Vec<u8>>>> {
+            self.0.get_view(offset, length)
+        }
+
+        fn get_shared(&self) -> Box<dyn SendSyncDerefMut<Target = dyn LinearStore>> {
+            self.0.get_shared()
+        }
+
+        fn write(&mut self, _offset: usize, _change: &[u8]) -> Result<(), ShaleError> {
+            Err(ShaleError::ImmutableWrite)
+        }
+
+        fn id(&self) -> StoreId {
+            self.0.id()
+        }
+
+        fn is_writeable(&self) -> bool {
+            false
+        }
+
+        fn stats(&self) -> StoreStats {
+            self.0.stats()
+        }
+    }
+
+    #[test]
+    fn shared_reads() {
+        let store = new_store();
+        let addr = store.put_item(Hash([1; HASH_SIZE]), 0).unwrap().as_addr();
+        store.flush_dirty().unwrap();
+
+        let (meta, data) = {
+            let inner = store.inner.read().unwrap();
+            (
+                ReadOnly(inner.meta_store.get_shared()),
+                ReadOnly(inner.data_store.get_shared()),
+            )
+        };
+        let header =
+            StoredView::addr_to_obj(&meta, DiskAddress::from(0x1), ChunkHeader::SERIALIZED_LEN)
+                .unwrap();
+        let store: Store<Hash, _> =
+            Store::new(meta, data, header, ObjCache::new(1), 10, 16).unwrap();
+
+        // every reader gets the same object, which stays in the cache
+        let mut first = store.get_item(addr).unwrap();
+        let second = store.get_item(addr).unwrap();
+        assert!(std::ptr::eq(&*first, &*second));
+        assert_eq!(second.as_ref(), [1; HASH_SIZE]);
+        assert!(store.obj_cache.lock().shared.contains(&addr));
+        assert!(matches!(first.write(|_| {}), Err(ObjWriteError::ReadOnly)));
+
+        drop((first, second));
+        assert!(std::ptr::eq(
+            &*store.get_item(addr).unwrap(),
+            &*store.obj_cache.get_shared(addr).unwrap()
+        ));
+    }
+
+    #[test]
+    fn allocators() {
+        use crate::shale::allocator::{BestFit, Bump, FirstFit, SegregatedFit};
+
+        // whether the freed chunk is reused
+        let allocators: [(Arc<dyn Allocator>, bool); 5] = [
+            (Arc::new(NextFit), true),
+            (Arc::new(FirstFit), true),
+            (Arc::new(BestFit), true),
+            (Arc::new(SegregatedFit), true),
+            (Arc::new(Bump), false),
+        ];
+        for (allocator, reuses) in allocators {
+            let mut store = new_store().with_allocator(allocator);
+
+            let addrs: Vec<_> = (0..3u8)
+                .map(|i| store.put_item(Hash([i; HASH_SIZE]), 0).unwrap().as_addr())
+                .collect();
+            store.free_item(addrs[0]).unwrap();
+
+            let addr = store.put_item(Hash([3; HASH_SIZE]), 0).unwrap().as_addr();
+            if reuses {
+                assert_eq!(addr, addrs[0]);
+            } else {
+                assert!(addr > addrs[2]);
+            }
+            assert_eq!(store.get_item(addrs[1]).unwrap().as_ref(), [1; HASH_SIZE]);
+        }
+    }
+
+    #[test]
+    fn alignment() {
+        // aligned past the base of the store, at 0x1000
+        let align = 1 << 13;
+        let mut store = new_store().with_alignment(13);
+        let chunk = |addr: DiskAddress| addr.get() as u64 - ChunkHeader::SERIALIZED_LEN;
+
+        let addrs: Vec<_> = (0..3u8)
+            .map(|i| store.put_item(Hash([i; HASH_SIZE]), 0).unwrap().as_addr())
+            .collect();
+        assert_eq!(chunk(addrs[0]), align);
+        assert_eq!(chunk(addrs[1]), 2 * align);
+        assert_eq!(chunk(addrs[2]), 3 * align);
+
+        store.free_item(addrs[1]).unwrap();
+        let addr = store.put_item(Hash([3; HASH_SIZE]), 0).unwrap().as_addr();
+        assert_eq!(addr, addrs[1]);
+        assert_eq!(store.get_item(addrs[2]).unwrap().as_ref(), [2; HASH_SIZE]);
+    }
+
+    #[test]
+    fn free_index() {
+        for indexed in [false, true] {
+            let mut store = new_store();
+            let index = indexed.then(|| store.build_free_index().unwrap());
+            store = store.with_free_index(index);
+            let check = |store: &Store<Hash, InMemLinearStore>| {
+                if indexed {
+                    assert_eq!(store.free_index(), Some(store.build_free_index().unwrap()));
+                }
+            };
+
+            // the first item leaves a large chunk when freed
+            let addrs: Vec<_> = [100, 0, 0, 0, 0, 0]
+                .into_iter()
+                .enumerate()
+                .map(|(i, extra)| {
+                    let hash = Hash([i as u8; HASH_SIZE]);
+                    store.put_item(hash, extra).unwrap().as_addr()
+                })
+                .collect();
+            store.free_item(addrs[0]).unwrap();
+            store.free_item(addrs[4]).unwrap();
+            check(&store);
+            assert_eq!(store.build_free_index().unwrap().len(), 2);
+
+            // walking the free list finds the large chunk first, the index the smallest that fits
+            let addr = store.put_item(Hash([6; HASH_SIZE]), 0).unwrap().as_addr();
+            assert_eq!(addr, if indexed { addrs[4] } else { addrs[0] });
+            check(&store);
+
+            // merging freed chunks moves descriptors around
+            store.free_item(addrs[1]).unwrap();
+            store.free_item(addrs[3]).unwrap();
+            store.free_item(addrs[2]).unwrap();
+            check(&store);
+            assert_eq!(store.get_item(addrs[5]).unwrap().as_ref(), [5; HASH_SIZE]);
+        }
+    }
+
+    #[test]
+    fn header_invariants() {
+        let mut store = new_store();
+        store.check_header().unwrap();
+        let addrs: Vec<_> = (0..3)
+            .map(|i| store.put_item(Hash([i; HASH_SIZE]), 0).unwrap().as_addr())
+            .collect();
+        store.free_item(addrs[0]).unwrap();
+        store.free_item(addrs[1]).unwrap();
+        store.check_header().unwrap();
+
+        let header = |meta_store_tail: usize, data_store_tail: usize| StoreHeader {
+            meta_store_tail: meta_store_tail.into(),
+            data_store_tail: data_store_tail.into(),
+            base_addr: 0x1000.into(),
+            alloc_addr: 0x1000.into(),
+        };
+        let corrupt_field = |store: Store<Hash, InMemLinearStore>| match store.check_header() {
+            Err(ShaleError::CorruptHeader { field, .. }) => field,
+            result => panic!("the header should be corrupt, got {result:?}"),
+        };
+        assert_eq!(
+            corrupt_field(new_store_with(header(0x800, 0x1000))),
+            "meta_store_tail"
+        );
+        assert_eq!(
+            corrupt_field(new_store_with(header(0x1001, 0x1000))),
+            "meta_store_tail"
+        );
+        assert_eq!(
+            corrupt_field(new_store_with(header(0x1000, 0))),
+            "data_store_tail"
+        );
+
+        // updates that would wrap fail instead
+        let store = new_store_with(header(0x1000, 0x1000));
+        let Err(ShaleError::CorruptHeader { field, .. }) = store
+            .inner
+            .write()
+            .unwrap()
+            .delete_descriptor(0x1000.into())
+        else {
+            panic!("an empty free list has no descriptor to delete");
+        };
+        assert_eq!(field, "meta_store_tail");
+        let store = new_store_with(header(0x1000, DEFERRED_BIT - 0x10));
+        store.check_header().unwrap();
+        let Err(ShaleError::CorruptHeader { field, .. }) = store.put_item(Hash([0; HASH_SIZE]), 0)
+        else {
+            panic!("the data store tail should not wrap around");
+        };
+        assert_eq!(field, "data_store_tail");
+    }
 }
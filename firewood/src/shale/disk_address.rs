@@ -10,6 +10,10 @@ use bytemuck::{Pod, Zeroable};
 
 use crate::shale::{LinearStore, ShaleError, Storable};
 
+/// Set in the provisional addresses of the objects whose allocation is deferred, which are never
+/// valid store offsets.
+pub(crate) const DEFERRED_BIT: usize = 1 << (usize::BITS - 1);
+
 /// The virtual disk address of an object
 #[repr(transparent)]
 #[derive(Debug, Copy, Clone, Eq, Hash, Ord, PartialOrd, PartialEq, Pod, Zeroable)]
@@ -56,6 +60,25 @@ impl DiskAddress {
     pub fn get(&self) -> usize {
         self.0.map(|v| v.get()).unwrap_or_default()
     }
+
+    /// The address `rhs` bytes after this one, or `None` if it overflows.
+    pub fn checked_add(self, rhs: usize) -> Option<Self> {
+        self.get().checked_add(rhs).map(Self::from)
+    }
+
+    /// The address `rhs` bytes before this one, or `None` if it underflows.
+    pub fn checked_sub(self, rhs: usize) -> Option<Self> {
+        self.get().checked_sub(rhs).map(Self::from)
+    }
+
+    /// Whether this is the provisional address of an object that isn't allocated yet, see
+    /// [Store::with_delayed_allocation](super::compact::Store::with_delayed_allocation).
+    pub const fn is_deferred(&self) -> bool {
+        match self.0 {
+            Some(addr) => addr.get() & DEFERRED_BIT != 0,
+            None => false,
+        }
+    }
 }
 
 /// Convert from a usize to a DiskAddress
@@ -0,0 +1,92 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! An index of the free list of a [compact store](super::compact::Store) held in memory, see
+//! [Store::with_free_index](super::compact::Store::with_free_index).
+//!
+//! Without the index, an allocation walks the descriptors of the free list in the meta store, up
+//! to the maximum walk of the store, and gives up on the chunks further down the list. With it,
+//! every free chunk is known by its size, so the chunks an object fits in are found without
+//! reading the free list at all.
+//!
+//! The free list in the meta store stays the record of the free chunks: the store keeps the
+//! index in step with every change it makes to the list, and the changes to the list are
+//! journaled through the Wal with the rest of the meta store. The index is only rebuilt from the
+//! list when the store is opened, after the Wal is replayed, and the list is checked against the
+//! chunk headers of the data store on the way, see
+//! [Store::build_free_index](super::compact::Store::build_free_index).
+
+use super::{allocator::FreeChunk, disk_address::DiskAddress};
+use std::collections::{BTreeSet, HashMap};
+
+/// The free chunks of a compact store, by size and by the address of their descriptor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FreeIndex {
+    by_size: BTreeSet<(u64, DiskAddress)>,
+    by_desc_addr: HashMap<DiskAddress, u64>,
+}
+
+impl FreeIndex {
+    pub fn len(&self) -> usize {
+        self.by_desc_addr.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_desc_addr.is_empty()
+    }
+
+    /// The total size of the free chunks, in bytes.
+    pub fn total_size(&self) -> u64 {
+        self.by_desc_addr.values().sum()
+    }
+
+    /// Records a free chunk of `size` bytes, described at `desc_addr`, in place of the chunk
+    /// described there before, if any.
+    pub(super) fn insert(&mut self, desc_addr: DiskAddress, size: u64) {
+        self.remove(desc_addr);
+        self.by_desc_addr.insert(desc_addr, size);
+        self.by_size.insert((size, desc_addr));
+    }
+
+    /// Forgets the free chunk described at `desc_addr`, returning its size.
+    pub(super) fn remove(&mut self, desc_addr: DiskAddress) -> Option<u64> {
+        let size = self.by_desc_addr.remove(&desc_addr)?;
+        self.by_size.remove(&(size, desc_addr));
+        Some(size)
+    }
+
+    /// The free chunks of at least `length` bytes, from the smallest.
+    pub fn fitting(&self, length: u64) -> impl Iterator<Item = FreeChunk> + '_ {
+        self.by_size
+            .range((length, DiskAddress::null())..)
+            .map(|&(size, desc_addr)| FreeChunk { desc_addr, size })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fitting() {
+        let mut index = FreeIndex::default();
+        for (addr, size) in [(8, 100), (16, 20), (24, 50), (32, 20)] {
+            index.insert(DiskAddress::from(addr), size);
+        }
+        index.insert(DiskAddress::from(8), 10);
+        assert_eq!(index.remove(DiskAddress::from(24)), Some(50));
+        assert_eq!(index.remove(DiskAddress::from(24)), None);
+
+        let sizes = |length| {
+            index
+                .fitting(length)
+                .map(|chunk| (chunk.desc_addr.get(), chunk.size))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sizes(15), [(16, 20), (32, 20)]);
+        assert_eq!(sizes(0), [(8, 10), (16, 20), (32, 20)]);
+        assert!(sizes(21).is_empty());
+        assert_eq!(index.len(), 3);
+    }
+}
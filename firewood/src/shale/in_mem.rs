@@ -1,7 +1,9 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
-use crate::shale::{LinearStore, LinearStoreView, SendSyncDerefMut, StoreId};
+use crate::shale::{
+    LinearStore, LinearStoreView, SendSyncDerefMut, StoreCounters, StoreId, StoreStats,
+};
 use std::{
     fmt::Debug,
     ops::{Deref, DerefMut},
@@ -16,12 +18,26 @@ use super::ShaleError;
 pub struct InMemLinearStore {
     store: Arc<RwLock<Vec<u8>>>,
     id: StoreId,
+    counters: Arc<StoreCounters>,
 }
 
 impl InMemLinearStore {
     pub fn new(size: u64, id: StoreId) -> Self {
         let store = Arc::new(RwLock::new(vec![0; size as usize]));
-        Self { store, id }
+        Self {
+            store,
+            id,
+            counters: Default::default(),
+        }
+    }
+
+    /// Another handle to the same store.
+    fn handle(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            id: self.id,
+            counters: self.counters.clone(),
+        }
     }
 }
 
@@ -41,21 +57,16 @@ impl LinearStore for InMemLinearStore {
             store.resize(size, 0);
         }
 
+        self.counters.read(length as u64);
         Some(Box::new(InMemLinearStoreView {
             offset,
             length,
-            mem: Self {
-                store: self.store.clone(),
-                id: self.id,
-            },
+            mem: self.handle(),
         }))
     }
 
     fn get_shared(&self) -> Box<dyn SendSyncDerefMut<Target = dyn LinearStore>> {
-        Box::new(InMemLinearStoreShared(Self {
-            store: self.store.clone(),
-            id: self.id,
-        }))
+        Box::new(InMemLinearStoreShared(self.handle()))
     }
 
     fn write(&mut self, offset: usize, change: &[u8]) -> Result<(), ShaleError> {
@@ -71,6 +82,7 @@ impl LinearStore for InMemLinearStore {
         }
         #[allow(clippy::indexing_slicing)]
         store[offset..offset + length].copy_from_slice(change);
+        self.counters.written(length as u64);
 
         Ok(())
     }
@@ -82,6 +94,10 @@ impl LinearStore for InMemLinearStore {
     fn is_writeable(&self) -> bool {
         true
     }
+
+    fn stats(&self) -> StoreStats {
+        self.counters.stats()
+    }
 }
 
 /// A range within an in-memory linear byte store.
@@ -102,6 +118,13 @@ impl LinearStoreView for InMemLinearStoreView {
         #[allow(clippy::indexing_slicing, clippy::unwrap_used)]
         self.mem.store.read().unwrap()[self.offset..self.offset + self.length].to_vec()
     }
+
+    fn extend_into(&self, buf: &mut Vec<u8>) {
+        #[allow(clippy::indexing_slicing, clippy::unwrap_used)]
+        buf.extend_from_slice(
+            &self.mem.store.read().unwrap()[self.offset..self.offset + self.length],
+        )
+    }
 }
 
 struct InMemLinearStoreShared(InMemLinearStore);
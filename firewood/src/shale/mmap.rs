@@ -0,0 +1,258 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use crate::shale::{
+    LinearStore, LinearStoreView, SendSyncDerefMut, ShaleError, StoreCounters, StoreId, StoreStats,
+};
+use memmap2::Mmap;
+use std::{
+    fmt::{self, Debug, Formatter},
+    fs,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// A read-only [LinearStore] over the files of a store, which are memory-mapped instead of
+/// read into pages: a view borrows its bytes straight from the mappings, see
+/// [LinearStoreView::as_slice], and the kernel caches the pages that are read.
+///
+/// The files are laid out as those of a [CachedStore](crate::storage::CachedStore), the
+/// `2^file_nbit` bytes at `fid << file_nbit` being in the file `{fid:08x}.fw` of `rootdir`, and
+/// they are all mapped when the store is created, see [MmapStore::new].
+#[derive(Clone)]
+pub struct MmapStore {
+    files: Arc<MmapFiles>,
+    id: StoreId,
+    counters: Arc<StoreCounters>,
+}
+
+struct MmapFiles {
+    rootdir: PathBuf,
+    file_nbit: u64,
+    /// The mapping of each file, by its `fid`.
+    maps: Vec<Option<Mmap>>,
+}
+
+impl Debug for MmapStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MmapStore")
+            .field("rootdir", &self.files.rootdir)
+            .field("file_nbit", &self.files.file_nbit)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl MmapStore {
+    /// Maps the files of the store in `rootdir`.
+    ///
+    /// # Safety
+    ///
+    /// The files must not be written, nor truncated, for as long as the store, or any view of
+    /// it, is alive, e.g. they are those of a DB that is closed and not opened by another
+    /// process meanwhile, or a copy of them. A write would change the bytes of the views under
+    /// them, which is undefined behavior, and a truncation would make reading them fail with a
+    /// `SIGBUS`.
+    pub unsafe fn new<P: AsRef<Path>>(
+        rootdir: P,
+        file_nbit: u64,
+        id: StoreId,
+    ) -> Result<Self, ShaleError> {
+        let rootdir = rootdir.as_ref().to_path_buf();
+        let counters = Arc::new(StoreCounters::default());
+        let mut maps = Vec::new();
+        for entry in fs::read_dir(&rootdir)? {
+            let path = entry?.path();
+            let Some(fid) = path
+                .file_name()
+                .and_then(|name| name.to_str()?.strip_suffix(".fw"))
+                .and_then(|fid| u64::from_str_radix(fid, 16).ok())
+            else {
+                continue;
+            };
+            let file = fs::File::open(&path)?;
+            // SAFETY: the files are not written while they are mapped, see the caller
+            let map = unsafe { Mmap::map(&file)? };
+            counters.syscall();
+            let fid = fid as usize;
+            if maps.len() <= fid {
+                maps.resize_with(fid + 1, || None);
+            }
+            #[allow(clippy::indexing_slicing)]
+            {
+                maps[fid] = Some(map);
+            }
+        }
+
+        Ok(Self {
+            files: Arc::new(MmapFiles {
+                rootdir,
+                file_nbit,
+                maps,
+            }),
+            id,
+            counters,
+        })
+    }
+}
+
+impl MmapFiles {
+    /// The bytes from `start` to `end`, or the ones up to the end of the file `start` is in if
+    /// `end` is past it, or `None` if they are past the end of the mapped files.
+    fn segment(&self, start: usize, end: usize) -> Option<&[u8]> {
+        let file_size = 1 << self.file_nbit;
+        let map = self.maps.get(start >> self.file_nbit)?.as_ref()?;
+        let file_start = start & (file_size - 1);
+        let file_end = (file_start + end - start).min(file_size);
+        map.get(file_start..file_end)
+    }
+
+    /// Calls `f` with the bytes of each file the `length` bytes from `offset` are in, and
+    /// returns whether they are all mapped.
+    fn for_each_segment(&self, offset: usize, length: usize, mut f: impl FnMut(&[u8])) -> bool {
+        let Some(end) = offset.checked_add(length) else {
+            return false;
+        };
+        let mut start = offset;
+        while start < end {
+            let Some(segment) = self.segment(start, end) else {
+                return false;
+            };
+            f(segment);
+            start += segment.len();
+        }
+        true
+    }
+}
+
+impl LinearStore for MmapStore {
+    fn get_view(
+        &self,
+        offset: usize,
+        length: u64,
+    ) -> Option<Box<dyn LinearStoreView<DerefReturn = Vec<u8>>>> {
+        let length = length as usize;
+        if !self.files.for_each_segment(offset, length, |_| ()) {
+            return None;
+        }
+        self.counters.read(length as u64);
+        Some(Box::new(MmapStoreView {
+            files: self.files.clone(),
+            offset,
+            length,
+        }))
+    }
+
+    fn get_shared(&self) -> Box<dyn SendSyncDerefMut<Target = dyn LinearStore>> {
+        Box::new(MmapStoreShared(self.clone()))
+    }
+
+    fn write(&mut self, _offset: usize, _change: &[u8]) -> Result<(), ShaleError> {
+        Err(ShaleError::ImmutableWrite)
+    }
+
+    fn id(&self) -> StoreId {
+        self.id
+    }
+
+    fn is_writeable(&self) -> bool {
+        false
+    }
+
+    fn stats(&self) -> StoreStats {
+        self.counters.stats()
+    }
+}
+
+/// A range of an [MmapStore], which is known to be mapped.
+struct MmapStoreView {
+    files: Arc<MmapFiles>,
+    offset: usize,
+    length: usize,
+}
+
+impl LinearStoreView for MmapStoreView {
+    type DerefReturn = Vec<u8>;
+
+    fn as_deref(&self) -> Self::DerefReturn {
+        let mut buf = Vec::with_capacity(self.length);
+        self.extend_into(&mut buf);
+        buf
+    }
+
+    fn extend_into(&self, buf: &mut Vec<u8>) {
+        self.files
+            .for_each_segment(self.offset, self.length, |segment| {
+                buf.extend_from_slice(segment)
+            });
+    }
+
+    /// The bytes of the view, unless they span two files.
+    fn as_slice(&self) -> Option<&[u8]> {
+        self.files
+            .segment(self.offset, self.offset + self.length)
+            .filter(|segment| segment.len() == self.length)
+    }
+}
+
+struct MmapStoreShared(MmapStore);
+
+impl Deref for MmapStoreShared {
+    type Target = dyn LinearStore;
+
+    fn deref(&self) -> &(dyn LinearStore + 'static) {
+        &self.0
+    }
+}
+
+impl DerefMut for MmapStoreShared {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    #[test]
+    fn views_across_files() {
+        let dir = std::env::temp_dir().join("firewood_mmap_store");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // two files of 16 bytes, each byte being its offset in the store
+        for fid in 0..2u8 {
+            let bytes: Vec<u8> = (fid * 16..(fid + 1) * 16).collect();
+            fs::write(dir.join(format!("{fid:08x}.fw")), bytes).unwrap();
+        }
+
+        // SAFETY: the files are not written while they are mapped
+        let mut store = unsafe { MmapStore::new(&dir, 4, 1) }.unwrap();
+        let view = store.get_view(2, 4).unwrap();
+        assert_eq!(view.as_slice(), Some([2, 3, 4, 5].as_slice()));
+        assert!(matches!(view.bytes(), Cow::Borrowed([2, 3, 4, 5])));
+        let view = store.get_view(12, 8).unwrap();
+        assert_eq!(view.as_slice(), None);
+        assert_eq!(view.as_deref(), (12..20).collect::<Vec<u8>>());
+        assert_eq!(*view.bytes(), *(12..20).collect::<Vec<u8>>());
+        let mut buf = vec![0xff];
+        store.get_view(30, 2).unwrap().extend_into(&mut buf);
+        assert_eq!(buf, [0xff, 30, 31]);
+
+        // the store ends with its last file
+        assert!(store.get_view(30, 3).is_none());
+        assert!(store.get_view(40, 1).is_none());
+        assert!(matches!(
+            store.write(0, &[1]),
+            Err(ShaleError::ImmutableWrite)
+        ));
+
+        let stats = store.get_shared().stats();
+        assert_eq!(stats.bytes_read, 14);
+        // each file was mapped when the store was created
+        assert_eq!(stats.syscalls, 2);
+    }
+}
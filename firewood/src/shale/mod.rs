@@ -3,20 +3,28 @@
 
 pub(crate) use disk_address::DiskAddress;
 use std::any::type_name;
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 use std::fmt::{self, Debug, Formatter};
 use std::mem::ManuallyDrop;
 use std::num::NonZeroUsize;
 use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock, RwLockWriteGuard};
 
 use thiserror::Error;
 
+use crate::memory_budget::{MemoryBudget, MemoryConsumer, Reclaim};
 use crate::merkle::{LeafNode, Node, Path};
 
+pub mod allocator;
 pub mod compact;
 pub mod disk_address;
+pub mod free_index;
 pub mod in_mem;
+pub mod mmap;
+pub(crate) mod pool;
+pub(crate) mod scratch;
 
 #[derive(Debug, Error)]
 #[non_exhaustive]
@@ -39,6 +47,14 @@ pub enum ShaleError {
     Io(#[from] std::io::Error),
     #[error("Write on immutable cache")]
     ImmutableWrite,
+    /// A field of the header of a [compact store](compact::Store) broke an invariant, or would
+    /// have overflowed, see [Store::check_header](compact::Store::check_header).
+    #[error("corrupt store header: {field}: {value:#x}: {error}")]
+    CorruptHeader {
+        field: &'static str,
+        value: usize,
+        error: &'static str,
+    },
 }
 
 // TODO:
@@ -48,13 +64,99 @@ pub enum ShaleError {
 #[error("object cannot be written in the store provided")]
 pub struct ObjWriteSizeError;
 
+/// Why an [ObjRef] can't be written.
+#[derive(Debug, Error)]
+pub enum ObjWriteError {
+    /// The object no longer fits where it is stored, and has to be moved.
+    #[error(transparent)]
+    Size(#[from] ObjWriteSizeError),
+    /// The object is shared with the readers of an immutable store, or isn't cached.
+    #[error("object is read-only")]
+    ReadOnly,
+}
+
 pub type StoreId = u8;
 pub const INVALID_STORE_ID: StoreId = 0xff;
 
+/// The I/O of a store since it was opened, see [LinearStore::stats].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Bytes of the views read from the store.
+    pub bytes_read: u64,
+    /// Bytes written to the store by the revisions being built.
+    pub bytes_written: u64,
+    /// Pages of the views found in the page cache of the store, which a store held in memory
+    /// has none of.
+    pub view_cache_hits: u64,
+    /// System calls made to read pages from the store files.
+    pub syscalls: u64,
+}
+
+/// The counters behind the [StoreStats] of a store, shared by all its revisions, so that they
+/// add up per [StoreId].
+#[derive(Debug, Default)]
+pub struct StoreCounters {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    view_cache_hits: AtomicU64,
+    syscalls: AtomicU64,
+}
+
+impl StoreCounters {
+    pub(crate) fn read(&self, bytes: u64) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn written(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn view_cache_hit(&self) {
+        self.view_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn syscall(&self) {
+        self.syscalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counts so far.
+    pub fn stats(&self) -> StoreStats {
+        StoreStats {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            view_cache_hits: self.view_cache_hits.load(Ordering::Relaxed),
+            syscalls: self.syscalls.load(Ordering::Relaxed),
+        }
+    }
+}
+
 /// A handle that pins and provides a readable access to a portion of a [LinearStore].
 pub trait LinearStoreView {
     type DerefReturn: Deref<Target = [u8]>;
     fn as_deref(&self) -> Self::DerefReturn;
+
+    /// Appends the bytes of the view to `buf`, such as a buffer of the [pool].
+    fn extend_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.as_deref())
+    }
+
+    /// The bytes of the view, if they are in memory as they are, e.g. in a mapping of the
+    /// store, so that they can be read without copying them, see
+    /// [bytes](dyn LinearStoreView::bytes).
+    fn as_slice(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+impl dyn LinearStoreView<DerefReturn = Vec<u8>> {
+    /// The bytes of the view, borrowed from the store if it can, see
+    /// [as_slice](LinearStoreView::as_slice), or copied otherwise.
+    pub fn bytes(&self) -> Cow<'_, [u8]> {
+        match self.as_slice() {
+            Some(bytes) => Cow::Borrowed(bytes),
+            None => Cow::Owned(self.as_deref()),
+        }
+    }
 }
 
 pub trait SendSyncDerefMut: DerefMut + Send + Sync {}
@@ -86,6 +188,19 @@ pub trait LinearStore: Debug + Send + Sync {
 
     /// Returns whether or not this store is writable
     fn is_writeable(&self) -> bool;
+
+    /// Keeps the `length` bytes starting from `offset` resident for as long as the store is
+    /// open, exempt from the eviction of its cache, for the metadata every operation reads.
+    /// Stores held in memory are always resident.
+    fn pin_region(&self, _offset: usize, _length: u64) -> Result<(), ShaleError> {
+        Ok(())
+    }
+
+    /// The I/O of this store so far, which includes that of the other revisions of the same
+    /// store. Stores that don't count their I/O report none.
+    fn stats(&self) -> StoreStats {
+        StoreStats::default()
+    }
 }
 
 /// A wrapper of `StoredView` to enable writes. The direct construction (by [Obj::from_stored_view]
@@ -121,31 +236,44 @@ impl<T: Storable> Obj<T> {
         Ok(())
     }
 
+    /// Moves a deferred object to the address it was allocated, where it is written when
+    /// flushed.
+    fn relocate(&mut self, addr: DiskAddress, len_limit: u64) {
+        self.value.offset = addr.get();
+        self.value.len_limit = len_limit;
+    }
+
     #[inline(always)]
     pub const fn from_stored_view(value: StoredView<T>) -> Self {
         Obj { value, dirty: None }
     }
 
     pub fn flush_dirty(&mut self) {
-        // faster than calling `self.dirty.take()` on a `None`
-        if self.dirty.is_none() {
+        // faster than calling `self.dirty.take()` on a `None`, and an object that isn't
+        // allocated yet has nowhere to be written
+        if self.dirty.is_none() || self.as_addr().is_deferred() {
             return;
         }
 
         if let Some(new_value_len) = self.dirty.take() {
-            let mut new_value = vec![0; new_value_len as usize];
-            // TODO: log error
-            #[allow(clippy::unwrap_used)]
-            self.value.serialize(&mut new_value).unwrap();
-            let offset = self.value.get_offset();
-            let bx: &mut dyn LinearStore = self.value.get_mut_mem_store();
-            bx.write(offset, &new_value).expect("write should succeed");
+            scratch::with_scratch(new_value_len as usize, |new_value| {
+                // TODO: log error
+                #[allow(clippy::unwrap_used)]
+                self.value.serialize(new_value).unwrap();
+                let offset = self.value.get_offset();
+                let bx: &mut dyn LinearStore = self.value.get_mut_mem_store();
+                bx.write(offset, new_value).expect("write should succeed");
+            });
         }
     }
 }
 
 impl Obj<Node> {
     pub fn into_inner(mut self) -> Node {
+        self.take()
+    }
+
+    fn take(&mut self) -> Node {
         let empty_node = LeafNode {
             partial_path: Path(Vec::new()),
             value: Vec::new(),
@@ -157,7 +285,8 @@ impl Obj<Node> {
 
 impl<T: Storable> Drop for Obj<T> {
     fn drop(&mut self) {
-        self.flush_dirty()
+        self.flush_dirty();
+        self.value.item.recycle();
     }
 }
 
@@ -168,26 +297,56 @@ impl<T: Storable> Deref for Obj<T> {
     }
 }
 
+/// How an [ObjRef] holds its object.
+#[derive(Debug)]
+enum Checkout<T: Storable> {
+    /// Taken out of the cache, which gets it back once the [ObjRef] is dropped.
+    Owned(ManuallyDrop<Obj<T>>),
+    /// Shared with the cache and the other readers of an immutable store.
+    Shared(Arc<Obj<T>>),
+    /// Read for a single reader, and never cached, see [compact::Store::scan_item].
+    Private(Obj<T>),
+}
+
 /// User handle that offers read & write access to the stored items.
 #[derive(Debug)]
 pub struct ObjRef<'a, T: Storable> {
-    inner: ManuallyDrop<Obj<T>>,
+    inner: Checkout<T>,
     cache: &'a ObjCache<T>,
 }
 
 impl<'a, T: Storable + Debug> ObjRef<'a, T> {
     const fn new(inner: Obj<T>, cache: &'a ObjCache<T>) -> Self {
         Self {
-            inner: ManuallyDrop::new(inner),
+            inner: Checkout::Owned(ManuallyDrop::new(inner)),
             cache,
         }
     }
 
+    const fn shared(inner: Arc<Obj<T>>, cache: &'a ObjCache<T>) -> Self {
+        Self {
+            inner: Checkout::Shared(inner),
+            cache,
+        }
+    }
+
+    const fn private(inner: Obj<T>, cache: &'a ObjCache<T>) -> Self {
+        Self {
+            inner: Checkout::Private(inner),
+            cache,
+        }
+    }
+
+    /// Modifies the object, which can't be done to the shared object of an immutable store, nor
+    /// to an object that isn't cached.
     #[inline]
-    pub fn write(&mut self, modify: impl FnOnce(&mut T)) -> Result<(), ObjWriteSizeError> {
-        self.inner.modify(modify)?;
+    pub fn write(&mut self, modify: impl FnOnce(&mut T)) -> Result<(), ObjWriteError> {
+        let Checkout::Owned(inner) = &mut self.inner else {
+            return Err(ObjWriteError::ReadOnly);
+        };
+        inner.modify(modify)?;
 
-        self.cache.lock().dirty.insert(self.inner.as_addr());
+        self.cache.lock().dirty.insert(inner.as_addr());
 
         Ok(())
     }
@@ -198,9 +357,21 @@ impl<'a, T: Storable + Debug> ObjRef<'a, T> {
 }
 
 impl<'a> ObjRef<'a, Node> {
+    /// Takes the node out of the cache, e.g. to store it elsewhere. The node of an immutable
+    /// store is copied instead.
     pub fn into_inner(mut self) -> Node {
+        let inner = match &mut self.inner {
+            Checkout::Owned(inner) => inner,
+            // the object stays shared with the other readers of the store, which never change it
+            Checkout::Shared(inner) => return Node::clone(inner),
+            Checkout::Private(inner) => return inner.take(),
+        };
+
+        // the object leaves the cache for good, so it must not stay pinned
+        self.cache.lock().pinned.remove(&inner.as_addr());
+
         // Safety: okay because we'll never be touching "self.inner" again
-        let b = unsafe { ManuallyDrop::take(&mut self.inner) };
+        let b = unsafe { ManuallyDrop::take(inner) };
 
         // Safety: safe because self.cache:
         //  - is valid for both reads and writes.
@@ -221,27 +392,48 @@ impl<'a> ObjRef<'a, Node> {
 impl<'a, T: Storable + Debug> Deref for ObjRef<'a, T> {
     type Target = Obj<T>;
     fn deref(&self) -> &Obj<T> {
-        &self.inner
+        match &self.inner {
+            Checkout::Owned(inner) => inner,
+            Checkout::Shared(inner) => inner,
+            Checkout::Private(inner) => inner,
+        }
     }
 }
 
 impl<'a, T: Storable> Drop for ObjRef<'a, T> {
     fn drop(&mut self) {
-        let ptr = self.inner.as_addr();
+        // a shared object stays in the cache as it is, and a private one was never in it
+        let Checkout::Owned(inner) = &mut self.inner else {
+            return;
+        };
+
+        let ptr = inner.as_addr();
         let mut cache = self.cache.lock();
         match cache.pinned.remove(&ptr) {
             Some(true) => {
-                self.inner.dirty = None;
+                inner.dirty = None;
                 // SAFETY: self.inner will have completed it's destructor
                 // so it must not be referenced after this line, and it isn't
-                unsafe { ManuallyDrop::drop(&mut self.inner) };
+                unsafe { ManuallyDrop::drop(inner) };
             }
             _ => {
                 // SAFETY: safe because self.inner is not referenced after this line
-                let b = unsafe { ManuallyDrop::take(&mut self.inner) };
-                cache.cached.put(ptr, b);
+                let b = unsafe { ManuallyDrop::take(inner) };
+                cache.insert(ptr, b);
             }
         }
+        self.cache.trim(&mut cache);
+
+        // the budget can only evict from this cache once it is unlocked
+        let budget = cache
+            .budget
+            .as_ref()
+            .filter(|budget| budget.over_limit())
+            .cloned();
+        drop(cache);
+        if let Some(budget) = budget {
+            budget.enforce();
+        }
     }
 }
 
@@ -254,6 +446,9 @@ pub trait Storable {
     fn deserialize<T: LinearStore>(addr: usize, mem: &T) -> Result<Self, ShaleError>
     where
         Self: Sized;
+
+    /// Gives the buffers of the object to the [pool] of this thread as the object is dropped.
+    fn recycle(&mut self) {}
 }
 
 pub fn to_dehydrated(item: &dyn Storable) -> Result<Vec<u8>, ShaleError> {
@@ -422,34 +617,244 @@ impl<T: Storable> StoredView<T> {
 #[derive(Debug)]
 pub struct ObjCacheInner<T: Storable> {
     cached: lru::LruCache<DiskAddress, Obj<T>>,
+    /// The objects whose allocation is deferred, kept apart from `cached` since they can't be
+    /// evicted: they have nowhere to be written back until they are allocated.
+    deferred: HashMap<DiskAddress, Obj<T>>,
     pinned: HashMap<DiskAddress, bool>,
     dirty: HashSet<DiskAddress>,
+    /// The addresses of the shared objects of an immutable store, see [ObjCache::get_shared],
+    /// with the estimated size of each.
+    shared: lru::LruCache<DiskAddress, usize>,
+    /// Estimated size of the objects in `cached` and `shared`, see [ObjCacheInner::entry_size].
+    bytes: usize,
+    /// See [ObjCache::set_max_bytes].
+    max_bytes: Option<usize>,
+    budget: Option<MemoryBudget>,
+}
+
+impl<T: Storable> ObjCacheInner<T> {
+    /// An estimate of the memory held by a cached object: its serialized size, which is about
+    /// the size of its contents, and the fixed size of the object.
+    fn entry_size(obj: &Obj<T>) -> usize {
+        size_of::<Obj<T>>() + Storable::serialized_len(&**obj) as usize
+    }
+
+    fn charge(&mut self, bytes: usize) {
+        self.bytes += bytes;
+        if let Some(budget) = &self.budget {
+            budget.charge(MemoryConsumer::ObjCache, bytes);
+        }
+    }
+
+    fn release(&mut self, bytes: usize) {
+        self.bytes -= bytes;
+        if let Some(budget) = &self.budget {
+            budget.release(MemoryConsumer::ObjCache, bytes);
+        }
+    }
+
+    /// Caches `obj`, which may evict the least recently used object.
+    fn insert(&mut self, ptr: DiskAddress, obj: Obj<T>) {
+        self.charge(Self::entry_size(&obj));
+        let replaced = if ptr.is_deferred() {
+            self.deferred.insert(ptr, obj)
+        } else {
+            self.cached.push(ptr, obj).map(|(_, replaced)| replaced)
+        };
+        if let Some(replaced) = replaced {
+            self.release(Self::entry_size(&replaced));
+        }
+    }
+
+    /// The estimated bytes held above [ObjCacheInner::max_bytes].
+    fn excess(&self) -> usize {
+        self.max_bytes
+            .map_or(0, |max_bytes| self.bytes.saturating_sub(max_bytes))
+    }
+
+    fn remove(&mut self, ptr: &DiskAddress) -> Option<Obj<T>> {
+        let obj = if ptr.is_deferred() {
+            self.deferred.remove(ptr)?
+        } else {
+            self.cached.pop(ptr)?
+        };
+        self.release(Self::entry_size(&obj));
+        Some(obj)
+    }
+}
+
+impl<T: Storable> Drop for ObjCacheInner<T> {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(MemoryConsumer::ObjCache, self.bytes);
+        }
+    }
+}
+
+impl<T: Storable + Send + Sync> Reclaim for ObjCacheState<T> {
+    fn reclaim(&self, bytes: usize) {
+        if let Ok(mut inner) = self.inner.try_write() {
+            self.evict(&mut inner, bytes);
+        }
+    }
+}
+
+/// Number of maps the shared objects of an immutable store are split over.
+const SHARDS: usize = 16;
+
+/// The shared objects of an immutable store, by address.
+type SharedObjs<T> = parking_lot::RwLock<HashMap<DiskAddress, Arc<Obj<T>>>>;
+
+#[derive(Debug)]
+struct ObjCacheState<T: Storable> {
+    inner: RwLock<ObjCacheInner<T>>,
+    /// Kept out of `inner` so that reading a shared object only takes the read lock of the
+    /// shard it is in.
+    shared: [SharedObjs<T>; SHARDS],
+}
+
+impl<T: Storable> ObjCacheState<T> {
+    fn shard(&self, ptr: DiskAddress) -> &SharedObjs<T> {
+        #[allow(clippy::indexing_slicing)]
+        &self.shared[ptr.get() % SHARDS]
+    }
+
+    /// Evicts the least recently used objects that aren't dirty until `bytes` are freed, since
+    /// writing those back could need the lock of a store that is held by whoever went over the
+    /// limit.
+    fn evict(&self, inner: &mut ObjCacheInner<T>, bytes: usize) {
+        let mut freed = 0;
+        let evicted: Vec<_> = inner
+            .cached
+            .iter()
+            .rev()
+            .filter(|(_, obj)| obj.dirty.is_none())
+            .take_while(|(_, obj)| {
+                let enough = freed >= bytes;
+                freed += ObjCacheInner::entry_size(obj);
+                !enough
+            })
+            .map(|(ptr, _)| *ptr)
+            .collect();
+
+        for ptr in evicted {
+            inner.remove(&ptr);
+        }
+
+        while freed < bytes {
+            let Some((ptr, size)) = inner.shared.pop_lru() else {
+                break;
+            };
+            self.shard(ptr).write().remove(&ptr);
+            inner.release(size);
+            freed += size;
+        }
+    }
 }
 
 /// [ObjRef] pool that is used by [compact::Store] to construct [ObjRef]s.
+///
+/// Objects of a writable store are taken out of the cache while they are in use, and put back
+/// when their [ObjRef] is dropped. Objects of an immutable store, such as a committed revision,
+/// can never be modified, so they stay in the cache and are shared by all their readers
+/// instead, see [ObjCache::get_shared].
 #[derive(Debug)]
-pub struct ObjCache<T: Storable>(Arc<RwLock<ObjCacheInner<T>>>);
+pub struct ObjCache<T: Storable>(Arc<ObjCacheState<T>>);
 
 impl<T: Storable> ObjCache<T> {
     pub fn new(capacity: usize) -> Self {
-        Self(Arc::new(RwLock::new(ObjCacheInner {
-            cached: lru::LruCache::new(NonZeroUsize::new(capacity).expect("non-zero cache size")),
-            pinned: HashMap::new(),
-            dirty: HashSet::new(),
-        })))
+        let capacity = NonZeroUsize::new(capacity).expect("non-zero cache size");
+        Self(Arc::new(ObjCacheState {
+            inner: RwLock::new(ObjCacheInner {
+                cached: lru::LruCache::new(capacity),
+                deferred: HashMap::new(),
+                pinned: HashMap::new(),
+                dirty: HashSet::new(),
+                shared: lru::LruCache::new(capacity),
+                bytes: 0,
+                max_bytes: None,
+                budget: None,
+            }),
+            shared: std::array::from_fn(|_| Default::default()),
+        }))
     }
 
     fn lock(&self) -> RwLockWriteGuard<ObjCacheInner<T>> {
         #[allow(clippy::unwrap_used)]
-        self.0.write().unwrap()
+        self.0.inner.write().unwrap()
+    }
+
+    /// Reads a shared object of an immutable store, without taking the lock of the cache. The
+    /// order objects are evicted in is only updated if the cache isn't locked by someone else.
+    fn get_shared(&self, ptr: DiskAddress) -> Option<Arc<Obj<T>>> {
+        let obj = self.0.shard(ptr).read().get(&ptr).cloned()?;
+        if let Ok(mut inner) = self.0.inner.try_write() {
+            inner.shared.promote(&ptr);
+        }
+        Some(obj)
+    }
+
+    /// Shares `obj`, which was read from an immutable store, with its next readers. If another
+    /// reader shared the same object first, that one is returned instead.
+    fn put_shared(&self, obj: Obj<T>) -> Arc<Obj<T>> {
+        let ptr = obj.as_addr();
+        let mut inner = self.lock();
+        let obj = match self.0.shard(ptr).write().entry(ptr) {
+            Entry::Occupied(e) => return e.get().clone(),
+            Entry::Vacant(e) => e.insert(Arc::new(obj)).clone(),
+        };
+
+        let size = ObjCacheInner::entry_size(&obj);
+        inner.charge(size);
+        if let Some((evicted, size)) = inner.shared.push(ptr, size) {
+            if evicted != ptr {
+                self.0.shard(evicted).write().remove(&evicted);
+            }
+            inner.release(size);
+        }
+        self.trim(&mut inner);
+
+        // the budget can only evict from this cache once it is unlocked
+        let budget = inner
+            .budget
+            .as_ref()
+            .filter(|budget| budget.over_limit())
+            .cloned();
+        drop(inner);
+        if let Some(budget) = budget {
+            budget.enforce();
+        }
+
+        obj
+    }
+
+    /// Shares the cached objects with all their readers from now on, once the store they were
+    /// read from has become immutable, e.g. when a proposal is committed. The objects are moved
+    /// as they are, so a revision starts with the objects its proposal already had in memory.
+    pub(crate) fn freeze(&self) {
+        let mut inner = self.lock();
+        // the store is gone, and its pins with it
+        inner.pinned.clear();
+        inner.dirty.clear();
+
+        // least recently used first, so that the order is the same once they are all shared
+        while let Some((ptr, obj)) = inner.cached.pop_lru() {
+            let size = ObjCacheInner::entry_size(&obj);
+            self.0.shard(ptr).write().insert(ptr, Arc::new(obj));
+            if let Some((evicted, size)) = inner.shared.push(ptr, size) {
+                if evicted != ptr {
+                    self.0.shard(evicted).write().remove(&evicted);
+                }
+                inner.release(size);
+            }
+        }
     }
 
     #[inline(always)]
     fn get(&self, ptr: DiskAddress) -> Result<Option<Obj<T>>, ShaleError> {
-        #[allow(clippy::unwrap_used)]
-        let mut inner = self.0.write().unwrap();
+        let mut inner = self.lock();
 
-        let obj_ref = inner.cached.pop(&ptr).map(|r| {
+        let obj_ref = inner.remove(&ptr).map(|r| {
             // insert and set to `false` if you can
             // When using `get` in parallel, one should not `write` to the same address
             inner
@@ -494,12 +899,144 @@ impl<T: Storable> ObjCache<T> {
         if let Some(f) = inner.pinned.get_mut(&ptr) {
             *f = true
         }
-        if let Some(mut r) = inner.cached.pop(&ptr) {
+        if let Some(mut r) = inner.remove(&ptr) {
             r.dirty = None
         }
         inner.dirty.remove(&ptr);
     }
 
+    /// Returns the addresses of up to `limit` cached objects, starting with
+    /// the most recently used one.
+    pub fn addresses(&self, limit: usize) -> Vec<DiskAddress> {
+        #[allow(clippy::unwrap_used)]
+        let inner = self.0.inner.read().unwrap();
+        inner
+            .cached
+            .iter()
+            .map(|(ptr, _)| *ptr)
+            .chain(inner.shared.iter().map(|(ptr, _)| *ptr))
+            .take(limit)
+            .collect()
+    }
+
+    pub fn capacity(&self) -> usize {
+        #[allow(clippy::unwrap_used)]
+        self.0.inner.read().unwrap().cached.cap().get()
+    }
+
+    /// Changes the maximum number of cached objects, evicting the least recently used ones if
+    /// there are too many. Evicted dirty objects are written back.
+    pub fn resize(&self, capacity: usize) {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        let mut inner = self.lock();
+        while inner.cached.len() > capacity.get() {
+            if let Some((_, obj)) = inner.cached.pop_lru() {
+                inner.release(ObjCacheInner::entry_size(&obj));
+            }
+        }
+        inner.cached.resize(capacity);
+        while inner.shared.len() > capacity.get() {
+            if let Some((ptr, size)) = inner.shared.pop_lru() {
+                self.0.shard(ptr).write().remove(&ptr);
+                inner.release(size);
+            }
+        }
+        inner.shared.resize(capacity);
+    }
+
+    /// Changes the maximum estimated bytes of the cached objects, see [ObjCacheInner::entry_size],
+    /// evicting the least recently used ones that aren't dirty if they hold more. The objects are
+    /// evicted when either this or [ObjCache::capacity] is reached, and `None` leaves only the
+    /// latter. Dirty objects can't be evicted before they are written back, so a cache may hold
+    /// more bytes until they are.
+    pub fn set_max_bytes(&self, max_bytes: Option<usize>) {
+        let mut inner = self.lock();
+        inner.max_bytes = max_bytes;
+        self.trim(&mut inner);
+    }
+
+    /// Limits the estimated bytes of the cached objects, see [ObjCache::set_max_bytes].
+    pub fn with_max_bytes(self, max_bytes: Option<usize>) -> Self {
+        self.set_max_bytes(max_bytes);
+        self
+    }
+
+    pub fn max_bytes(&self) -> Option<usize> {
+        #[allow(clippy::unwrap_used)]
+        self.0.inner.read().unwrap().max_bytes
+    }
+
+    /// Evicts objects until the cache is back within [ObjCache::set_max_bytes], if it can.
+    fn trim(&self, inner: &mut ObjCacheInner<T>) {
+        let excess = inner.excess();
+        if excess > 0 {
+            self.0.evict(inner, excess);
+        }
+    }
+
+    /// Evicts every cached object that isn't dirty, and stops sharing the objects of an
+    /// immutable store, returning the estimated bytes released. The objects in use aren't cached
+    /// until they are put back, and the deferred ones have nowhere to be written back yet, so
+    /// they stay.
+    pub fn clear_clean(&self) -> usize {
+        let mut inner = self.lock();
+        let bytes = inner.bytes;
+
+        let clean: Vec<_> = inner
+            .cached
+            .iter()
+            .filter(|(_, obj)| obj.dirty.is_none())
+            .map(|(ptr, _)| *ptr)
+            .collect();
+        for ptr in clean {
+            inner.remove(&ptr);
+        }
+
+        while let Some((ptr, size)) = inner.shared.pop_lru() {
+            self.0.shard(ptr).write().remove(&ptr);
+            inner.release(size);
+        }
+
+        bytes - inner.bytes
+    }
+
+    /// The estimated bytes held by the cached objects.
+    pub fn bytes(&self) -> usize {
+        #[allow(clippy::unwrap_used)]
+        self.0.inner.read().unwrap().bytes
+    }
+
+    /// Takes out the deferred objects, unless some objects are in use.
+    pub(crate) fn take_deferred(&self) -> Option<Vec<Obj<T>>> {
+        let mut inner = self.lock();
+        if !inner.pinned.is_empty() {
+            return None;
+        }
+        let deferred: Vec<_> = std::mem::take(&mut inner.deferred).into_values().collect();
+        inner.dirty.retain(|ptr| !ptr.is_deferred());
+        for obj in &deferred {
+            inner.release(ObjCacheInner::entry_size(obj));
+        }
+        Some(deferred)
+    }
+
+    /// Caches deferred objects once they are allocated, see [Obj::relocate], to be written with
+    /// the other dirty objects.
+    pub(crate) fn insert_relocated(&self, objs: Vec<Obj<T>>) {
+        let mut inner = self.lock();
+        for obj in objs {
+            let ptr = obj.as_addr();
+            inner.dirty.insert(ptr);
+            inner.insert(ptr, obj);
+        }
+        self.trim(&mut inner);
+    }
+
+    /// The addresses of the objects modified since the last flush.
+    pub(crate) fn dirty_addresses(&self) -> Vec<DiskAddress> {
+        self.lock().dirty.iter().copied().collect()
+    }
+
     pub fn flush_dirty(&self) -> Option<()> {
         let mut inner = self.lock();
         if !inner.pinned.is_empty() {
@@ -513,3 +1050,18 @@ impl<T: Storable> ObjCache<T> {
         Some(())
     }
 }
+
+impl<T: Storable + Send + Sync + 'static> ObjCache<T> {
+    /// Accounts the cached objects to `budget`, which evicts them when it goes over its limit.
+    pub fn with_memory_budget(self, budget: &MemoryBudget) -> Self {
+        {
+            let mut inner = self.lock();
+            budget.charge(MemoryConsumer::ObjCache, inner.bytes);
+            inner.budget = Some(budget.clone());
+        }
+
+        let reclaimer: Arc<dyn Reclaim> = self.0.clone();
+        budget.register(MemoryConsumer::ObjCache, Arc::downgrade(&reclaimer));
+        self
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Recycled buffers for the contents of the objects read from a store.
+//!
+//! Reading a node allocates a buffer for its partial path, its value and each of its encoded
+//! children, and a read-heavy workload frees about as many of them, as the nodes it read are
+//! evicted from the caches. Instead, the buffers of the objects dropped by a thread are kept by
+//! that thread, by size class, and reused for the next objects it reads, see
+//! [Storable::recycle](super::Storable::recycle).
+//!
+//! Like the free lists of the allocator, the pools aren't accounted to the
+//! [MemoryBudget](crate::memory_budget::MemoryBudget), as each of them is bounded, see
+//! [pooled].
+
+use std::{
+    cell::RefCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The smallest buffers pooled are `1 << MIN_CLASS` bytes, smaller ones are too cheap to be
+/// worth it.
+const MIN_CLASS: u32 = 4;
+/// The largest buffers pooled are `1 << MAX_CLASS` bytes, larger ones are rare enough to be
+/// allocated every time.
+const MAX_CLASS: u32 = 12;
+const CLASSES: usize = (MAX_CLASS - MIN_CLASS + 1) as usize;
+
+/// Bytes of buffers a thread keeps at most, beyond which recycled buffers are freed.
+const MAX_POOLED_BYTES: usize = 256 << 10;
+
+/// Bytes held by the pools of all the threads, see [pooled].
+static POOLED: AtomicUsize = AtomicUsize::new(0);
+
+/// The buffers of a thread, by size class: each buffer of `classes[i]` has a capacity of at
+/// least `1 << (MIN_CLASS + i)` bytes.
+#[derive(Default)]
+struct Pool {
+    classes: [Vec<Vec<u8>>; CLASSES],
+    bytes: usize,
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        POOLED.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+thread_local! {
+    static POOL: RefCell<Pool> = RefCell::default();
+}
+
+/// Returns an empty buffer with room for at least `len` bytes, reusing a buffer this thread
+/// recycled when possible.
+pub(crate) fn take(len: usize) -> Vec<u8> {
+    if len == 0 {
+        return Vec::new();
+    }
+    let class = len.next_power_of_two().trailing_zeros().max(MIN_CLASS);
+    if class > MAX_CLASS {
+        return Vec::with_capacity(len);
+    }
+
+    let index = (class - MIN_CLASS) as usize;
+    let reused = POOL
+        .try_with(|pool| {
+            let mut pool = pool.try_borrow_mut().ok()?;
+            #[allow(clippy::indexing_slicing)]
+            let buf = pool.classes[index].pop()?;
+            pool.bytes -= buf.capacity();
+            POOLED.fetch_sub(buf.capacity(), Ordering::Relaxed);
+            Some(buf)
+        })
+        .ok()
+        .flatten();
+
+    reused.unwrap_or_else(|| Vec::with_capacity(1 << class))
+}
+
+/// Returns a buffer of this thread holding a copy of `bytes`.
+pub(crate) fn to_vec(bytes: &[u8]) -> Vec<u8> {
+    let mut buf = take(bytes.len());
+    buf.extend_from_slice(bytes);
+    buf
+}
+
+/// Keeps `buf` to be reused by the next [take] of this thread, unless it's too small or too
+/// large to be pooled, or the pool of this thread is full.
+pub(crate) fn recycle(mut buf: Vec<u8>) {
+    let capacity = buf.capacity();
+    if !(1 << MIN_CLASS..=1 << MAX_CLASS).contains(&capacity) {
+        return;
+    }
+
+    let index = (capacity.ilog2() - MIN_CLASS) as usize;
+    // the pool of this thread may already be gone if it is exiting
+    let _ = POOL.try_with(|pool| {
+        let Ok(mut pool) = pool.try_borrow_mut() else {
+            return;
+        };
+        if pool.bytes + capacity > MAX_POOLED_BYTES {
+            return;
+        }
+        buf.clear();
+        pool.bytes += capacity;
+        POOLED.fetch_add(capacity, Ordering::Relaxed);
+        #[allow(clippy::indexing_slicing)]
+        pool.classes[index].push(buf);
+    });
+}
+
+/// The bytes currently held by the pools of all the threads of the process.
+pub(crate) fn pooled() -> usize {
+    POOLED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thread_bytes() -> usize {
+        POOL.with(|pool| pool.borrow().bytes)
+    }
+
+    #[test]
+    fn reuses_recycled_buffers() {
+        let buf = to_vec(&[1; 100]);
+        assert_eq!(buf.capacity(), 128);
+        let ptr = buf.as_ptr();
+        recycle(buf);
+        assert_eq!(thread_bytes(), 128);
+
+        // a buffer of the same class is reused, and doesn't hold what it held before
+        let buf = take(65);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(buf.is_empty());
+        assert_eq!(thread_bytes(), 0);
+
+        // a buffer of another class isn't
+        recycle(buf);
+        let other = take(200);
+        assert_ne!(other.as_ptr(), ptr);
+        assert_eq!(thread_bytes(), 128);
+    }
+
+    #[test]
+    fn bounded() {
+        assert_eq!(take(0).capacity(), 0);
+        recycle(Vec::with_capacity(1 << (MAX_CLASS + 1)));
+        recycle(Vec::with_capacity(1));
+        assert_eq!(thread_bytes(), 0);
+
+        for _ in 0..(2 * MAX_POOLED_BYTES) >> MAX_CLASS {
+            recycle(Vec::with_capacity(1 << MAX_CLASS));
+        }
+        assert_eq!(thread_bytes(), MAX_POOLED_BYTES);
+        assert!(pooled() >= MAX_POOLED_BYTES);
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Reusable scratch space for serializing objects before they are written to a store.
+//!
+//! A large commit flushes a lot of dirty objects, and allocating a fresh buffer for each of them
+//! puts a lot of pressure on the allocator. Instead, every thread keeps one buffer around and
+//! serializes into it.
+
+use std::{
+    cell::RefCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Objects larger than this get a buffer of their own, so a single huge object doesn't keep a
+/// huge allocation alive for the rest of the thread's life.
+const MAX_SCRATCH_LEN: usize = 1 << 20;
+
+/// Bytes allocated by the scratch buffers of all the threads, see [allocated].
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// A buffer whose allocation is counted in [ALLOCATED].
+#[derive(Default)]
+struct Scratch(Vec<u8>);
+
+impl Scratch {
+    fn with_len<R>(&mut self, len: usize, f: impl FnOnce(&mut [u8]) -> R) -> R {
+        let capacity = self.0.capacity();
+        self.0.clear();
+        self.0.resize(len, 0);
+        ALLOCATED.fetch_add(self.0.capacity() - capacity, Ordering::Relaxed);
+        f(&mut self.0)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        ALLOCATED.fetch_sub(self.0.capacity(), Ordering::Relaxed);
+    }
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Scratch> = RefCell::default();
+}
+
+/// Calls `f` with a zeroed buffer of `len` bytes, reusing the allocation of this thread's scratch
+/// buffer when possible.
+pub(crate) fn with_scratch<R>(len: usize, f: impl FnOnce(&mut [u8]) -> R) -> R {
+    SCRATCH.with(|scratch| match scratch.try_borrow_mut() {
+        Ok(mut buf) if len <= MAX_SCRATCH_LEN => buf.with_len(len, f),
+        // either too large or already in use further up the stack
+        _ => Scratch::default().with_len(len, f),
+    })
+}
+
+/// The bytes currently allocated for scratch buffers by all the threads of the process.
+pub(crate) fn allocated() -> usize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_allocation() {
+        let first = with_scratch(64, |buf| {
+            buf.fill(0xff);
+            buf.as_ptr()
+        });
+        let second = with_scratch(32, |buf| {
+            // the previous contents never leak into the next use
+            assert!(buf.iter().all(|&byte| byte == 0));
+            buf.as_ptr()
+        });
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn nested_and_oversized() {
+        with_scratch(8, |outer| {
+            with_scratch(8, |inner| {
+                assert_ne!(outer.as_ptr(), inner.as_ptr());
+            });
+        });
+
+        let len = MAX_SCRATCH_LEN + 1;
+        with_scratch(len, |buf| {
+            assert_eq!(buf.len(), len);
+            assert!(allocated() >= len);
+        });
+        SCRATCH.with(|scratch| assert!(scratch.borrow().0.capacity() <= MAX_SCRATCH_LEN));
+    }
+}
@@ -7,16 +7,23 @@ use std::ops::IndexMut;
 use std::os::fd::{AsFd, AsRawFd};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::{cell::RefCell, collections::HashMap};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+};
 
-use super::{AshRecord, FilePool, Page, StoreDelta, StoreError, WalConfig, PAGE_SIZE_NBIT};
+use super::{
+    AshRecord, FilePool, Page, StoreDelta, StoreError, WalConfig, PAGE_SIZE, PAGE_SIZE_NBIT,
+};
+use crate::memory_budget::{MemoryBudget, MemoryConsumer, Reservation};
 use crate::shale::StoreId;
 use crate::storage::DeltaPage;
 use aiofut::{AioBuilder, AioError, AioManager};
 use futures::future::join_all;
 use growthring::{
-    wal::{RecoverPolicy, WalLoader, WalWriter},
+    wal::{RecoverPolicy, WalLoadReport, WalLoader, WalWriter},
     walerror::WalError,
     WalFileImpl, WalStoreImpl,
 };
@@ -32,18 +39,37 @@ use tokio::{
 use typed_builder::TypedBuilder;
 
 type BufferWrites = Box<[BufferWrite]>;
+/// Notified once the Wal record of a write batch has been appended.
+type WalAck = oneshot::Sender<()>;
+/// What the Wal queue is asked to do, in the order it's asked.
+enum WalQueueItem {
+    /// A write batch to append to the Wal, with the memory it holds until its pages are written.
+    Write(BufferWrites, AshRecord, Option<WalAck>, Option<Reservation>),
+    /// Notified once the pages of every write batch queued before are written.
+    Flush(oneshot::Sender<()>),
+}
 
 #[derive(Debug)]
 pub enum BufferCmd {
-    /// Initialize the Wal.
-    InitWal(PathBuf, String),
-    /// Process a write batch against the underlying store.
-    WriteBatch(BufferWrites, AshRecord),
+    /// Initialize the Wal, replaying it unless the store files are known to be up to date with
+    /// it, optionally sending back what was found while replaying it.
+    InitWal(
+        PathBuf,
+        String,
+        bool,
+        Option<oneshot::Sender<(WalLoadReport, ReplayStats)>>,
+    ),
+    /// Process a write batch against the underlying store, optionally notifying the sender once
+    /// the batch is in the Wal. The reservation is released once the pages are written.
+    WriteBatch(BufferWrites, AshRecord, Option<WalAck>, Option<Reservation>),
     /// Get a page from the disk buffer.
     GetPage((StoreId, u64), oneshot::Sender<Option<Page>>),
     CollectAsh(usize, oneshot::Sender<Vec<AshRecord>>),
     /// Register a new store and add the files to a memory mapped pool.
     RegCachedStore(StoreId, Arc<FilePool>),
+    /// Notify the sender once every write batch sent before is in the Wal and its pages are
+    /// written to the store files.
+    Flush(oneshot::Sender<()>),
     /// Returns false if the
     Shutdown,
 }
@@ -154,6 +180,8 @@ impl DiskBuffer {
             batch: cfg.wal_max_batch,
             revisions: wal_cfg.max_revisions,
             pending: cfg.max_pending,
+            checkpoint_bytes: wal_cfg.checkpoint_bytes,
+            checkpoint_records: wal_cfg.checkpoint_records,
         };
 
         let (wal_in, writes) = mpsc::channel(cfg.wal_max_buffered);
@@ -208,6 +236,56 @@ struct WalQueueMax {
     batch: usize,
     revisions: u32,
     pending: usize,
+    checkpoint_bytes: u64,
+    checkpoint_records: usize,
+}
+
+/// The Wal records appended whose pages are not all written yet. These are what recovery
+/// replays on top of the last `max_revisions` records.
+#[derive(Default)]
+struct Unflushed {
+    records: Cell<usize>,
+    bytes: Cell<u64>,
+    /// Notified whenever records are flushed.
+    flushed: Notify,
+}
+
+impl Unflushed {
+    fn add(&self, records: usize, bytes: u64) {
+        self.records.set(self.records.get() + records);
+        self.bytes.set(self.bytes.get() + bytes);
+    }
+
+    fn remove(&self, records: usize, bytes: u64) {
+        self.records.set(self.records.get() - records);
+        self.bytes.set(self.bytes.get() - bytes);
+        self.flushed.notify_one();
+    }
+
+    const fn over(&self, max: &WalQueueMax) -> bool {
+        (max.checkpoint_records > 0 && self.records.get() >= max.checkpoint_records)
+            || (max.checkpoint_bytes > 0 && self.bytes.get() >= max.checkpoint_bytes)
+    }
+
+    /// Waits until every record has been flushed, which leaves the Wal truncated to the last
+    /// `max_revisions` records.
+    async fn checkpoint(&self) {
+        while self.records.get() > 0 {
+            self.flushed.notified().await;
+        }
+    }
+
+    /// Notifies `flushes` once every record has been flushed, if there are any.
+    async fn flush(&self, flushes: Vec<oneshot::Sender<()>>) {
+        if flushes.is_empty() {
+            return;
+        }
+        self.checkpoint().await;
+        for flush in flushes {
+            // the requester may have stopped waiting, which is fine
+            let _ = flush.send(());
+        }
+    }
 }
 
 /// Add an pending pages to aio manager for processing by the local pool.
@@ -285,6 +363,39 @@ fn schedule_write(
     task::spawn_local(task);
 }
 
+/// What replaying the Wal found in the store files. Every record holds both the image of the
+/// bytes it writes before and after the write, so a write the store files already hold is told
+/// apart from one that was lost, and skipped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// Writes made to the store files.
+    pub applied: usize,
+    /// Writes the store files already held, which weren't made again.
+    pub skipped: usize,
+    /// Writes made over bytes that held neither their image before nor the one after the write,
+    /// most likely because a later record was flushed to the store files first, which replaying
+    /// that record makes right again.
+    pub diverged: usize,
+}
+
+/// The write batches sent to a [DiskBuffer] since its [DiskBufferRequester] was created.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WalWrites {
+    /// Wal records sent, one per batch.
+    pub records: u64,
+    /// Bytes of the writes of the records, both their images before and after the writes.
+    pub record_bytes: u64,
+    /// Store pages sent to be written.
+    pub pages: u64,
+}
+
+#[derive(Debug, Default)]
+struct WalWriteCounters {
+    records: AtomicU64,
+    record_bytes: AtomicU64,
+    pages: AtomicU64,
+}
+
 /// Initialize the Wal subsystem if it does not exists and attempts to replay the Wal if exists.
 async fn init_wal(
     file_pools: &Rc<RefCell<[Option<Arc<FilePool>>; 255]>>,
@@ -292,9 +403,17 @@ async fn init_wal(
     loader: WalLoader,
     max_revisions: u32,
     final_path: &Path,
-) -> Result<Rc<Mutex<WalWriter<WalFileImpl, WalStoreImpl>>>, WalError> {
-    let wal = loader
-        .load(
+) -> Result<
+    (
+        Rc<Mutex<WalWriter<WalFileImpl, WalStoreImpl>>>,
+        WalLoadReport,
+        ReplayStats,
+    ),
+    WalError,
+> {
+    let stats = RefCell::new(ReplayStats::default());
+    let (wal, report) = loader
+        .load_with_report(
             store,
             |raw, _| {
                 let batch = AshRecord::deserialize(raw);
@@ -308,26 +427,42 @@ async fn init_wal(
                         let file_nbit = file_pool.get_file_nbit();
                         let file_mask = (1 << file_nbit) - 1;
                         let fid = offset >> file_nbit;
-
-                        nix::sys::uio::pwrite(
-                            file_pool
-                                .get_file(fid)
-                                .map_err(|e| {
-                                    WalError::Other(format!(
-                                        "file pool error: {:?} - final path {:?}",
-                                        e, final_path
-                                    ))
-                                })?
-                                .as_fd(),
-                            &redo.data,
-                            (offset & file_mask) as nix::libc::off_t,
-                        )
-                        .map_err(|e| {
+                        let file = file_pool.get_file(fid).map_err(|e| {
                             WalError::Other(format!(
-                                "wal loader error: {:?} - final path {:?}",
+                                "file pool error: {:?} - final path {:?}",
                                 e, final_path
                             ))
                         })?;
+                        let file_offset = (offset & file_mask) as nix::libc::off_t;
+
+                        // the bytes past the end of the file read as the zeros they would be
+                        let mut current = vec![0; redo.data.len()];
+                        nix::sys::uio::pread(file.as_fd(), &mut current, file_offset).map_err(
+                            |e| {
+                                WalError::Other(format!(
+                                    "wal loader error: {:?} - final path {:?}",
+                                    e, final_path
+                                ))
+                            },
+                        )?;
+                        let mut stats = stats.borrow_mut();
+                        if *current == *redo.data {
+                            stats.skipped += 1;
+                            continue;
+                        }
+                        if *current != *undo.data {
+                            stats.diverged += 1;
+                        }
+                        stats.applied += 1;
+
+                        nix::sys::uio::pwrite(file.as_fd(), &redo.data, file_offset).map_err(
+                            |e| {
+                                WalError::Other(format!(
+                                    "wal loader error: {:?} - final path {:?}",
+                                    e, final_path
+                                ))
+                            },
+                        )?;
                     }
                 }
 
@@ -337,7 +472,7 @@ async fn init_wal(
         )
         .await?;
 
-    Ok(Rc::new(Mutex::new(wal)))
+    Ok((Rc::new(Mutex::new(wal)), report, stats.into_inner()))
 }
 
 async fn run_wal_queue(
@@ -345,33 +480,64 @@ async fn run_wal_queue(
     wal: Rc<Mutex<WalWriter<WalFileImpl, WalStoreImpl>>>,
     pending: Rc<RefCell<HashMap<(StoreId, u64), PendingPage>>>,
     file_pools: Rc<RefCell<[Option<Arc<FilePool>>; 255]>>,
-    mut writes: mpsc::Receiver<(BufferWrites, AshRecord)>,
+    mut writes: mpsc::Receiver<WalQueueItem>,
     fc_notifier: Rc<Notify>,
     aiomgr: Rc<AioManager>,
 ) {
     use std::collections::hash_map::Entry::*;
 
+    let unflushed = Rc::new(Unflushed::default());
+
     loop {
+        // stop taking batches while checkpointing, so that commits wait for it through the
+        // bounded queue and their Wal acks
+        if unflushed.over(&max) {
+            unflushed.checkpoint().await;
+        }
+
         let mut bwrites = Vec::new();
         let mut records = Vec::new();
+        let mut acks = Vec::new();
+        let mut reservations = Vec::new();
+        let mut flushes = Vec::new();
         let wal = wal.clone();
 
-        if let Some((bw, ac)) = writes.recv().await {
-            records.push(ac);
-            bwrites.extend(bw.into_vec());
-        } else {
+        let Some(mut item) = writes.recv().await else {
             break;
-        }
-
-        while let Ok((bw, ac)) = writes.try_recv() {
-            records.push(ac);
-            bwrites.extend(bw.into_vec());
+        };
+        loop {
+            match item {
+                WalQueueItem::Write(bw, ac, ack, reservation) => {
+                    records.push(ac);
+                    bwrites.extend(bw.into_vec());
+                    acks.extend(ack);
+                    reservations.extend(reservation);
+                }
+                WalQueueItem::Flush(flush) => flushes.push(flush),
+            }
 
-            if records.len() >= max.batch {
+            // a flush waits for the batches before it only, so it ends the batch
+            if records.len() >= max.batch || !flushes.is_empty() {
                 break;
             }
+            match writes.try_recv() {
+                Ok(next) => item = next,
+                Err(_) => break,
+            }
         }
 
+        if records.is_empty() {
+            unflushed.flush(flushes).await;
+            continue;
+        }
+
+        let nrecords = records.len();
+        let nbytes = records
+            .iter()
+            .map(|record| bincode::serialized_size(record).unwrap_or_default())
+            .sum();
+        unflushed.add(nrecords, nbytes);
+
         // first write to Wal
         #[allow(clippy::unwrap_used)]
         let ring_ids = join_all(wal.clone().lock().await.grow(records))
@@ -379,6 +545,12 @@ async fn run_wal_queue(
             .into_iter()
             .map(|ring| ring.map_err(|_| "Wal Error while writing").unwrap().1)
             .collect::<Vec<_>>();
+
+        for ack in acks {
+            // the requester may have stopped waiting, which is fine
+            let _ = ack.send(());
+        }
+
         let sem = Rc::new(tokio::sync::Semaphore::new(0));
         let mut npermit = 0;
 
@@ -432,6 +604,7 @@ async fn run_wal_queue(
             }
         }
 
+        let task_unflushed = unflushed.clone();
         let task = async move {
             #[allow(clippy::unwrap_used)]
             let _ = sem.acquire_many(npermit).await.unwrap();
@@ -442,10 +615,16 @@ async fn run_wal_queue(
                 .peel(ring_ids, max.revisions)
                 .await
                 .map_err(|_| "Wal errored while pruning")
-                .unwrap()
+                .unwrap();
+            task_unflushed.remove(nrecords, nbytes);
+
+            // the pages are written, so the batches no longer hold memory
+            drop(reservations);
         };
 
         task::spawn_local(task);
+
+        unflushed.flush(flushes).await;
     }
 
     // if this function breaks for any reason, make sure there is no one waiting for staged writes.
@@ -469,24 +648,26 @@ async fn process(
     wal_cfg: &WalConfig,
     req: BufferCmd,
     max: WalQueueMax,
-    wal_in: mpsc::Sender<(BufferWrites, AshRecord)>,
-    writes: &mut Option<mpsc::Receiver<(BufferWrites, AshRecord)>>,
+    wal_in: mpsc::Sender<WalQueueItem>,
+    writes: &mut Option<mpsc::Receiver<WalQueueItem>>,
 ) -> bool {
     match req {
         BufferCmd::Shutdown => return false,
-        BufferCmd::InitWal(rootpath, waldir) => {
+        BufferCmd::InitWal(rootpath, waldir, replay, report_tx) => {
             let final_path = rootpath.join(&waldir);
 
             let store = WalStoreImpl::new(final_path.clone(), false)
-                .unwrap_or_else(panic_on_intialization_failure_with(&rootpath, &waldir));
+                .unwrap_or_else(panic_on_intialization_failure_with(&rootpath, &waldir))
+                .with_preallocation(wal_cfg.preallocate);
 
             let mut loader = WalLoader::new();
             loader
                 .file_nbit(wal_cfg.file_nbit)
                 .block_nbit(wal_cfg.block_nbit)
-                .recover_policy(RecoverPolicy::Strict);
+                .recover_policy(RecoverPolicy::Strict)
+                .skip_replay(!replay);
 
-            let initialized_wal = init_wal(
+            let (initialized_wal, report, stats) = init_wal(
                 &file_pools,
                 store,
                 loader,
@@ -498,6 +679,11 @@ async fn process(
 
             wal.replace(initialized_wal.clone());
 
+            if let Some(tx) = report_tx {
+                // the requester may have stopped waiting
+                tx.send((report, stats)).ok();
+            }
+
             #[allow(clippy::unwrap_used)]
             let writes = writes.take().unwrap();
 
@@ -523,9 +709,16 @@ async fn process(
                     .map(|e| e.staging_data.clone()),
             )
             .unwrap(),
-        BufferCmd::WriteBatch(writes, wal_writes) => {
+        BufferCmd::WriteBatch(writes, wal_writes, ack, reservation) => {
             #[allow(clippy::unwrap_used)]
-            wal_in.send((writes, wal_writes)).await.unwrap();
+            wal_in
+                .send(WalQueueItem::Write(writes, wal_writes, ack, reservation))
+                .await
+                .unwrap();
+        }
+        BufferCmd::Flush(flush) => {
+            #[allow(clippy::unwrap_used)]
+            wal_in.send(WalQueueItem::Flush(flush)).await.unwrap();
         }
         BufferCmd::CollectAsh(nrecords, tx) => {
             // wait to ensure writes are paused for Wal
@@ -572,36 +765,117 @@ async fn process(
 #[derive(Clone, Debug)]
 pub struct DiskBufferRequester {
     sender: mpsc::UnboundedSender<BufferCmd>,
+    budget: Option<MemoryBudget>,
+    /// Shared by the clones of the requester.
+    writes: Arc<WalWriteCounters>,
 }
 
 impl DiskBufferRequester {
     /// Create a new requester.
-    pub const fn new(sender: mpsc::UnboundedSender<BufferCmd>) -> Self {
-        Self { sender }
+    pub fn new(sender: mpsc::UnboundedSender<BufferCmd>) -> Self {
+        Self {
+            sender,
+            budget: None,
+            writes: Default::default(),
+        }
+    }
+
+    /// Accounts the batches waiting to be written to `budget`, see [MemoryConsumer::WalBuffer].
+    pub fn with_memory_budget(mut self, budget: &MemoryBudget) -> Self {
+        self.budget = Some(budget.clone());
+        self
+    }
+
+    /// The write batches sent so far by this requester and its clones.
+    pub fn wal_writes(&self) -> WalWrites {
+        WalWrites {
+            records: self.writes.records.load(Ordering::Relaxed),
+            record_bytes: self.writes.record_bytes.load(Ordering::Relaxed),
+            pages: self.writes.pages.load(Ordering::Relaxed),
+        }
     }
 
-    /// Get a page from the buffer.
+    /// Counts a batch, and reserves the memory it holds until its pages are written.
+    fn reserve(&self, page_batch: &BufferWrites, write_batch: &AshRecord) -> Option<Reservation> {
+        let pages: usize = page_batch.iter().map(|write| write.delta.0.len()).sum();
+        let records: usize = write_batch
+            .0
+            .values()
+            .flat_map(|ash| ash.undo.iter().chain(ash.redo.iter()))
+            .map(|write| write.data.len())
+            .sum();
+        self.writes.records.fetch_add(1, Ordering::Relaxed);
+        self.writes
+            .record_bytes
+            .fetch_add(records as u64, Ordering::Relaxed);
+        self.writes.pages.fetch_add(pages as u64, Ordering::Relaxed);
+
+        let budget = self.budget.as_ref()?;
+        Some(budget.reserve(
+            MemoryConsumer::WalBuffer,
+            pages * PAGE_SIZE as usize + records,
+        ))
+    }
+
+    /// Get a page from the buffer. A buffer that has shut down has no pages left to write.
     pub fn get_page(&self, store_id: StoreId, page_id: u64) -> Option<Page> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.sender
             .send(BufferCmd::GetPage((store_id, page_id), resp_tx))
             .map_err(StoreError::Send)
             .ok();
-        #[allow(clippy::unwrap_used)]
-        block_in_place(move || resp_rx.blocking_recv().unwrap())
+        block_in_place(move || resp_rx.blocking_recv().ok().flatten())
     }
 
     /// Sends a batch of writes to the buffer.
     pub fn write(&self, page_batch: BufferWrites, write_batch: AshRecord) {
+        let reservation = self.reserve(&page_batch, &write_batch);
+        self.sender
+            .send(BufferCmd::WriteBatch(
+                page_batch,
+                write_batch,
+                None,
+                reservation,
+            ))
+            .map_err(StoreError::Send)
+            .ok();
+    }
+
+    /// Sends a batch of writes to the buffer and waits until its Wal record has been appended.
+    pub fn write_logged(
+        &self,
+        page_batch: BufferWrites,
+        write_batch: AshRecord,
+    ) -> Result<(), StoreError<RecvError>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let reservation = self.reserve(&page_batch, &write_batch);
         self.sender
-            .send(BufferCmd::WriteBatch(page_batch, write_batch))
+            .send(BufferCmd::WriteBatch(
+                page_batch,
+                write_batch,
+                Some(resp_tx),
+                reservation,
+            ))
+            .map_err(StoreError::Send)
+            .ok();
+        block_in_place(|| resp_rx.blocking_recv().map_err(StoreError::Receive))
+    }
+
+    /// Waits until every write batch sent so far is in the Wal and its pages are written to the
+    /// store files.
+    pub fn flush(&self) -> Result<(), StoreError<RecvError>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.sender
+            .send(BufferCmd::Flush(resp_tx))
             .map_err(StoreError::Send)
             .ok();
+        block_in_place(|| resp_rx.blocking_recv().map_err(StoreError::Receive))
     }
 
+    /// Stops the buffer once it has written the pages queued so far. Does nothing if it has
+    /// stopped already.
     pub fn shutdown(&self) {
-        #[allow(clippy::unwrap_used)]
-        self.sender.send(BufferCmd::Shutdown).ok().unwrap()
+        self.sender.send(BufferCmd::Shutdown).ok();
     }
 
     /// Initialize the Wal.
@@ -610,11 +884,35 @@ impl DiskBufferRequester {
             .send(BufferCmd::InitWal(
                 rootpath.to_path_buf(),
                 waldir.to_string(),
+                true,
+                None,
             ))
             .map_err(StoreError::Send)
             .ok();
     }
 
+    /// Initialize the Wal and wait until it has been replayed, returning what was found in it
+    /// and in the store files. Unless `replay` is set, the Wal is only scanned, as the store files
+    /// already hold everything in it.
+    pub fn recover_wal(
+        &self,
+        waldir: &str,
+        rootpath: &Path,
+        replay: bool,
+    ) -> Result<(WalLoadReport, ReplayStats), StoreError<RecvError>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.sender
+            .send(BufferCmd::InitWal(
+                rootpath.to_path_buf(),
+                waldir.to_string(),
+                replay,
+                Some(resp_tx),
+            ))
+            .map_err(StoreError::Send)
+            .ok();
+        block_in_place(|| resp_rx.blocking_recv().map_err(StoreError::Receive))
+    }
+
     /// Collect the last N records from the Wal.
     pub fn collect_ash(&self, nrecords: usize) -> Result<Vec<AshRecord>, StoreError<RecvError>> {
         let (resp_tx, resp_rx) = oneshot::channel();
@@ -643,10 +941,7 @@ mod tests {
     use crate::shale::LinearStore;
     use crate::{
         file,
-        storage::{
-            Ash, CachedStore, MemStoreR, StoreConfig, StoreRevMut, StoreRevMutDelta,
-            StoreRevShared, ZeroStore,
-        },
+        storage::{CachedStore, MemStoreR, StoreConfig, StoreRevMut, StoreRevShared, ZeroStore},
     };
 
     const STATE_STORE_ID: StoreId = 0x0;
@@ -799,7 +1094,7 @@ mod tests {
 
         // Commit the change. Take the delta from cached store,
         // then apply changes to the CachedStore.
-        let (redo_delta, wal) = mut_store.delta();
+        let (redo_delta, wal) = mut_store.delta().unwrap();
         state_cache.update(&redo_delta).unwrap();
 
         // create a mutation request to the disk buffer by passing the page and write batch.
@@ -907,11 +1202,11 @@ mod tests {
         assert_eq!(view.as_deref(), hash);
 
         // Commit the change. Take the delta from both stores.
-        let (redo_delta, wal) = store.delta();
+        let (redo_delta, wal) = store.delta().unwrap();
         assert_eq!(1, redo_delta.0.len());
         assert_eq!(1, wal.undo.len());
 
-        let (another_redo_delta, another_wal) = another_store.delta();
+        let (another_redo_delta, another_wal) = another_store.delta().unwrap();
         assert_eq!(1, another_redo_delta.0.len());
         assert_eq!(2, another_wal.undo.len());
 
@@ -929,6 +1224,105 @@ mod tests {
         block_in_place(|| disk_requester.shutdown());
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_checkpoint() {
+        let buf_cfg = DiskBufferConfig::builder().build();
+        let wal_cfg = WalConfig::builder().checkpoint_records(1).build();
+        let disk_requester = init_buffer(buf_cfg, wal_cfg);
+
+        let tmp_dir = get_tmp_dir();
+        let path = get_file_path(tmp_dir.as_path(), file!(), line!());
+        std::fs::create_dir_all(&path).unwrap();
+        let (root_db_path, reset) = file::open_dir(path, file::Options::Truncate).unwrap();
+        let state_path = file::touch_dir("state", &root_db_path).unwrap();
+        assert!(reset);
+        disk_requester.init_wal("wal", &root_db_path);
+
+        let state_cache: Arc<CachedStore> = CachedStore::new(
+            &StoreConfig::builder()
+                .ncached_pages(1)
+                .ncached_files(1)
+                .store_id(STATE_STORE_ID)
+                .file_nbit(22)
+                .rootdir(state_path)
+                .build(),
+            disk_requester.clone(),
+        )
+        .unwrap()
+        .into();
+        disk_requester.reg_cached_store(state_cache.id(), state_cache.clone_files());
+
+        // every batch is past the threshold, so each one is only taken once the pages of the
+        // previous ones are written
+        for page_id in 0..8u64 {
+            let mut store = StoreRevMut::new(state_cache.clone());
+            block_in_place(|| {
+                store.write((page_id << PAGE_SIZE_NBIT) as usize, &page_id.to_le_bytes())
+            })
+            .unwrap();
+            let (page_batch, write_batch) = create_batches(&store);
+            block_in_place(|| disk_requester.write_logged(page_batch, write_batch)).unwrap();
+
+            if page_id > 0 {
+                let previous = page_id - 1;
+                assert!(
+                    block_in_place(|| disk_requester.get_page(STATE_STORE_ID, previous)).is_none()
+                );
+            }
+        }
+
+        // the Wal still holds the records of the past revisions
+        assert_eq!(
+            block_in_place(|| disk_requester.collect_ash(8))
+                .unwrap()
+                .len(),
+            8
+        );
+        block_in_place(|| disk_requester.shutdown());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pinned_region() {
+        let buf_cfg = DiskBufferConfig::builder().build();
+        let wal_cfg = WalConfig::builder().build();
+        let disk_requester = init_buffer(buf_cfg, wal_cfg);
+
+        let tmp_dir = get_tmp_dir();
+        let path = get_file_path(tmp_dir.as_path(), file!(), line!());
+        std::fs::create_dir_all(&path).unwrap();
+        let (root_db_path, _) = file::open_dir(path, file::Options::Truncate).unwrap();
+        let state_path = file::touch_dir("state", &root_db_path).unwrap();
+
+        let state_cache: Arc<CachedStore> = CachedStore::new(
+            &StoreConfig::builder()
+                .ncached_pages(1)
+                .ncached_files(1)
+                .store_id(STATE_STORE_ID)
+                .rootdir(state_path)
+                .build(),
+            disk_requester.clone(),
+        )
+        .unwrap()
+        .into();
+        disk_requester.reg_cached_store(state_cache.id(), state_cache.clone_files());
+
+        // the two bytes span the first two pages
+        let store = StoreRevMut::new(state_cache.clone());
+        block_in_place(|| store.pin_region(PAGE_SIZE as usize - 1, 2)).unwrap();
+
+        // reading other pages through a single cached page doesn't evict the pinned ones
+        for page_id in 2..6u64 {
+            block_in_place(|| store.get_view((page_id << PAGE_SIZE_NBIT) as usize, 8)).unwrap();
+        }
+        {
+            let inner = state_cache.inner.read();
+            assert!(inner.pinned_pages.contains_key(&0) && inner.pinned_pages.contains_key(&1));
+            assert_eq!(inner.pinned_pages.len(), 2);
+            assert_eq!(inner.cached_pages.len(), 1);
+        }
+        block_in_place(|| disk_requester.shutdown());
+    }
+
     fn get_file_path(path: &Path, file: &str, line: u32) -> PathBuf {
         path.join(format!("{}_{}", file.replace('/', "-"), line))
     }
@@ -944,27 +1338,16 @@ mod tests {
     }
 
     fn create_batches(rev_mut: &StoreRevMut) -> (BufferWrites, AshRecord) {
-        let deltas = std::mem::replace(
-            &mut *rev_mut.deltas.write(),
-            StoreRevMutDelta {
-                pages: HashMap::new(),
-                plain: Ash::default(),
-            },
-        );
+        let mut deltas = rev_mut.deltas.write();
+        let plain = deltas.writes().unwrap();
 
         // create a list of delta pages from existing in memory data.
-        let mut pages = Vec::new();
-        for (pid, page) in deltas.pages.into_iter() {
-            pages.push(DeltaPage(pid, page));
-        }
-        pages.sort_by_key(|p| p.0);
-
         let page_batch = Box::new([BufferWrite {
             store_id: STATE_STORE_ID,
-            delta: StoreDelta(pages),
+            delta: deltas.take_pages(),
         }]);
 
-        let write_batch = AshRecord([(STATE_STORE_ID, deltas.plain)].into());
+        let write_batch = AshRecord([(STATE_STORE_ID, plain)].into());
         (page_batch, write_batch)
     }
 }
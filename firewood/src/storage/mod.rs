@@ -4,17 +4,23 @@
 // TODO: try to get rid of the use `RefCell` in this file
 use self::buffer::DiskBufferRequester;
 use crate::file::File;
-use crate::shale::{self, LinearStore, LinearStoreView, SendSyncDerefMut, ShaleError, StoreId};
+use crate::memory_budget::{MemoryBudget, MemoryConsumer, Reclaim};
+use crate::shale::{
+    self, LinearStore, LinearStoreView, SendSyncDerefMut, ShaleError, StoreCounters, StoreId,
+    StoreStats,
+};
 use nix::fcntl::{Flock, FlockArg};
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap},
     fmt::{self, Debug},
+    io::ErrorKind,
     num::NonZeroUsize,
     ops::{Deref, DerefMut},
     os::fd::AsFd,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
 };
 use thiserror::Error;
@@ -22,6 +28,9 @@ use tokio::sync::{mpsc::error::SendError, oneshot::error::RecvError};
 use typed_builder::TypedBuilder;
 
 pub mod buffer;
+mod spill;
+
+use self::spill::{Spill, SpillConfig};
 
 pub(crate) const PAGE_SIZE_NBIT: u64 = 12;
 pub(crate) const PAGE_SIZE: u64 = 1 << PAGE_SIZE_NBIT;
@@ -53,6 +62,16 @@ pub trait MemStoreR: Debug + Send + Sync {
     /// Returns a slice of bytes from memory.
     fn get_slice(&self, offset: u64, length: u64) -> Option<Vec<u8>>;
     fn id(&self) -> StoreId;
+    /// Keeps the `length` bytes starting from `offset` in memory, see
+    /// [LinearStore::pin_region]. Returns `None` if they can't be read.
+    fn pin_region(&self, _offset: u64, _length: u64) -> Option<()> {
+        Some(())
+    }
+    /// The counters of the I/O of this store, which the revisions on top of it count into, see
+    /// [LinearStore::stats].
+    fn counters(&self) -> Arc<StoreCounters> {
+        Arc::default()
+    }
 }
 
 // Page should be boxed as to not take up so much stack-space
@@ -73,6 +92,13 @@ pub struct Ash {
     pub redo: Vec<StoreWrite>,
 }
 
+impl StoreWrite {
+    /// The number of bytes written.
+    pub(crate) fn len(&self) -> usize {
+        self.data.len()
+    }
+}
+
 impl Ash {
     fn iter(&self) -> impl Iterator<Item = (&StoreWrite, &StoreWrite)> {
         self.undo.iter().zip(self.redo.iter())
@@ -222,6 +248,7 @@ impl StoreDelta {
 pub struct StoreRev {
     base_store: RwLock<Arc<dyn MemStoreR>>,
     delta: StoreDelta,
+    counters: Arc<StoreCounters>,
 }
 
 impl fmt::Debug for StoreRev {
@@ -309,6 +336,14 @@ impl MemStoreR for StoreRev {
     fn id(&self) -> StoreId {
         self.base_store.read().id()
     }
+
+    fn pin_region(&self, offset: u64, length: u64) -> Option<()> {
+        self.base_store.read().pin_region(offset, length)
+    }
+
+    fn counters(&self) -> Arc<StoreCounters> {
+        self.counters.clone()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -317,13 +352,17 @@ pub struct StoreRevShared(Arc<StoreRev>);
 impl StoreRevShared {
     pub fn from_ash(base_store: Arc<dyn MemStoreR>, writes: &[StoreWrite]) -> Self {
         let delta = StoreDelta::new(base_store.as_ref(), writes);
-        let base_store = RwLock::new(base_store);
-        Self(Arc::new(StoreRev { base_store, delta }))
+        Self::from_delta(base_store, delta)
     }
 
     pub fn from_delta(base_store: Arc<dyn MemStoreR>, delta: StoreDelta) -> Self {
+        let counters = base_store.counters();
         let base_store = RwLock::new(base_store);
-        Self(Arc::new(StoreRev { base_store, delta }))
+        Self(Arc::new(StoreRev {
+            base_store,
+            delta,
+            counters,
+        }))
     }
 
     pub fn set_base_store(&mut self, base_store: Arc<dyn MemStoreR>) {
@@ -342,6 +381,7 @@ impl LinearStore for StoreRevShared {
         length: u64,
     ) -> Option<Box<dyn LinearStoreView<DerefReturn = Vec<u8>>>> {
         let data = self.0.get_slice(offset as u64, length)?;
+        self.0.counters.read(length);
         Some(Box::new(StoreRef { data }))
     }
 
@@ -362,21 +402,29 @@ impl LinearStore for StoreRevShared {
     fn is_writeable(&self) -> bool {
         false
     }
+
+    fn pin_region(&self, offset: usize, length: u64) -> Result<(), ShaleError> {
+        self.0
+            .pin_region(offset as u64, length)
+            .ok_or(ShaleError::InvalidCacheView {
+                offset,
+                size: length,
+            })
+    }
+
+    fn stats(&self) -> StoreStats {
+        self.0.counters.stats()
+    }
 }
 
 impl From<StoreRevMut> for StoreRevShared {
     fn from(value: StoreRevMut) -> Self {
-        let mut pages = Vec::new();
-        let deltas = std::mem::take(&mut *value.deltas.write());
-        for (pid, page) in deltas.pages.into_iter() {
-            pages.push(DeltaPage(pid, page));
-        }
-        pages.sort_by_key(|p| p.0);
-        let delta = StoreDelta(pages);
+        let delta = value.deltas.write().take_pages();
 
         let rev = Arc::new(StoreRev {
             base_store: RwLock::new(value.base_store),
             delta,
+            counters: value.counters,
         });
         StoreRevShared(rev)
     }
@@ -400,6 +448,10 @@ impl LinearStoreView for StoreRef {
     fn as_deref(&self) -> Self::DerefReturn {
         self.deref().to_vec()
     }
+
+    fn extend_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.data)
+    }
 }
 
 struct StoreShared<S: Clone + LinearStore>(S);
@@ -421,6 +473,138 @@ impl<S: Clone + LinearStore + 'static> DerefMut for StoreShared<S> {
 struct StoreRevMutDelta {
     pages: HashMap<u64, Page>,
     plain: Ash,
+    /// Accounts the pages in `pages` as [MemoryConsumer::Overlay].
+    budget: Option<MemoryBudget>,
+    /// Bytes of the writes in `plain`.
+    plain_bytes: usize,
+    spill_cfg: Option<SpillConfig>,
+    /// The pages and writes spilled, which are not in `pages` or `plain`.
+    spill: Option<Spill>,
+}
+
+impl StoreRevMutDelta {
+    fn new(budget: Option<MemoryBudget>, spill_cfg: Option<SpillConfig>) -> Self {
+        Self {
+            pages: HashMap::new(),
+            plain: Ash::default(),
+            budget,
+            plain_bytes: 0,
+            spill_cfg,
+            spill: None,
+        }
+    }
+
+    /// A changed page, whether it is in memory or spilled.
+    fn page(&self, pid: u64) -> std::io::Result<Option<Cow<'_, [u8; PAGE_SIZE as usize]>>> {
+        if let Some(page) = self.pages.get(&pid) {
+            return Ok(Some(Cow::Borrowed(page)));
+        }
+        match &self.spill {
+            Some(spill) => Ok(spill.page(pid)?.map(|page| Cow::Owned(*page))),
+            None => Ok(None),
+        }
+    }
+
+    /// Number of changed pages, whether they are in memory or spilled.
+    fn npages(&self) -> usize {
+        self.pages.len() + self.spill.as_ref().map_or(0, Spill::npages)
+    }
+
+    /// The ids of the changed pages, in page order.
+    fn sorted_pids(&self) -> Vec<u64> {
+        let mut pids: Vec<u64> = self.pages.keys().copied().collect();
+        if let Some(spill) = &self.spill {
+            pids.extend(spill.pids());
+        }
+        pids.sort_unstable();
+        pids
+    }
+
+    /// The changed pages `pids`, the spilled ones being read back.
+    fn pages_of(&self, pids: &[u64]) -> std::io::Result<StoreDelta> {
+        let mut pages = Vec::with_capacity(pids.len());
+        for &pid in pids {
+            let page = self.page(pid)?.ok_or_else(|| {
+                std::io::Error::new(ErrorKind::NotFound, format!("page {pid} isn't changed"))
+            })?;
+            pages.push(DeltaPage(pid, Box::new(page.into_owned())));
+        }
+        Ok(StoreDelta(pages))
+    }
+
+    /// The writes made, in order.
+    fn writes(&self) -> std::io::Result<Ash> {
+        match &self.spill {
+            Some(spill) => {
+                let mut writes = spill.writes()?;
+                writes.undo.extend(self.plain.undo.iter().cloned());
+                writes.redo.extend(self.plain.redo.iter().cloned());
+                Ok(writes)
+            }
+            None => Ok(self.plain.clone()),
+        }
+    }
+
+    /// Takes the pages out, in page order, leaving the delta empty. The pages of a revision are
+    /// only taken once it is committed, after the ones it spilled are applied to its base store
+    /// and dropped, see [StoreRevMut::reset_deltas], so they are all in memory.
+    fn take_pages(&mut self) -> StoreDelta {
+        debug_assert!(self.spill.is_none());
+        self.release_pages();
+        let mut pages: Vec<DeltaPage> = self
+            .pages
+            .drain()
+            .map(|(pid, page)| DeltaPage(pid, page))
+            .collect();
+        pages.sort_by_key(|p| p.0);
+        self.clear();
+        StoreDelta(pages)
+    }
+
+    fn clear(&mut self) {
+        self.release_pages();
+        self.pages.clear();
+        self.plain = Ash::default();
+        self.plain_bytes = 0;
+        self.spill = None;
+    }
+
+    fn release_pages(&self) {
+        if let Some(budget) = &self.budget {
+            budget.release(
+                MemoryConsumer::Overlay,
+                self.pages.len() * PAGE_SIZE as usize,
+            );
+        }
+    }
+
+    /// Spills all the pages and writes held in memory once they are above the threshold.
+    fn spill_if_needed(&mut self) -> std::io::Result<()> {
+        let Some(cfg) = &self.spill_cfg else {
+            return Ok(());
+        };
+        if self.pages.len() * PAGE_SIZE as usize + self.plain_bytes <= cfg.threshold {
+            return Ok(());
+        }
+
+        if self.spill.is_none() {
+            self.spill = Some(Spill::new(&cfg.dir)?);
+        }
+        self.release_pages();
+        #[allow(clippy::unwrap_used)]
+        let spill = self.spill.as_mut().unwrap();
+        spill.spill_pages(self.pages.drain())?;
+        spill.spill_writes(&self.plain)?;
+        self.plain = Ash::default();
+        self.plain_bytes = 0;
+        Ok(())
+    }
+}
+
+impl Drop for StoreRevMutDelta {
+    fn drop(&mut self) {
+        self.release_pages();
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -434,6 +618,7 @@ pub struct StoreRevMut {
     base_store: Arc<dyn MemStoreR>,
     deltas: Arc<RwLock<StoreRevMutDelta>>,
     prev_deltas: Arc<RwLock<StoreRevMutDelta>>,
+    counters: Arc<StoreCounters>,
 }
 
 impl From<StoreRevShared> for StoreRevMut {
@@ -442,6 +627,7 @@ impl From<StoreRevShared> for StoreRevMut {
             base_store: value.0.base_store.read().clone(),
             deltas: Arc::new(RwLock::new(StoreRevMutDelta::default())),
             prev_deltas: Arc::new(RwLock::new(StoreRevMutDelta::default())),
+            counters: value.0.counters.clone(),
         }
     }
 }
@@ -449,6 +635,7 @@ impl From<StoreRevShared> for StoreRevMut {
 impl StoreRevMut {
     pub fn new(base_store: Arc<dyn MemStoreR>) -> Self {
         Self {
+            counters: base_store.counters(),
             base_store,
             deltas: Default::default(),
             prev_deltas: Default::default(),
@@ -456,11 +643,45 @@ impl StoreRevMut {
     }
 
     pub fn new_from_other(other: &StoreRevMut) -> Self {
+        let other_deltas = other.deltas.read();
+        let deltas =
+            StoreRevMutDelta::new(other_deltas.budget.clone(), other_deltas.spill_cfg.clone());
         Self {
             base_store: other.base_store.clone(),
-            deltas: Default::default(),
+            deltas: Arc::new(RwLock::new(deltas)),
             prev_deltas: other.deltas.clone(),
+            counters: other.counters.clone(),
+        }
+    }
+
+    /// Accounts the pages changed by this revision to `budget`, see
+    /// [MemoryConsumer::Overlay]. Revisions created from this one with
+    /// [StoreRevMut::new_from_other] are accounted too.
+    pub fn with_memory_budget(self, budget: &MemoryBudget) -> Self {
+        {
+            let mut deltas = self.deltas.write();
+            deltas.release_pages();
+            deltas.budget = Some(budget.clone());
+            budget.charge(
+                MemoryConsumer::Overlay,
+                deltas.pages.len() * PAGE_SIZE as usize,
+            );
         }
+        self
+    }
+
+    /// Spills the pages changed by this revision and the writes that changed them to temporary
+    /// files in `dir` whenever they take more than `threshold` bytes of memory, so that a batch
+    /// can change more than fits in memory. Spilled pages are read back as they are read or
+    /// written, and a chunk at a time when the commit applies them, see
+    /// [StoreRevMut::delta_pages_of]. Revisions created from this one with
+    /// [StoreRevMut::new_from_other] spill too.
+    pub fn with_spill(self, threshold: usize, dir: &Path) -> Self {
+        self.deltas.write().spill_cfg = Some(SpillConfig {
+            threshold,
+            dir: dir.to_path_buf(),
+        });
+        self
     }
 
     fn get_page_mut<'a>(
@@ -468,40 +689,82 @@ impl StoreRevMut {
         deltas: &'a mut StoreRevMutDelta,
         prev_deltas: &StoreRevMutDelta,
         pid: u64,
-    ) -> &'a mut [u8] {
-        #[allow(clippy::unwrap_used)]
-        let page = deltas
-            .pages
-            .entry(pid)
-            .or_insert_with(|| match prev_deltas.pages.get(&pid) {
-                Some(p) => Box::new(*p.as_ref()),
-                None => Box::new(
-                    self.base_store
-                        .get_slice(pid << PAGE_SIZE_NBIT, PAGE_SIZE)
-                        .unwrap()
-                        .try_into()
-                        .unwrap(),
-                ),
-            });
+    ) -> Result<&'a mut [u8], ShaleError> {
+        let spilled = match &mut deltas.spill {
+            Some(spill) if !deltas.pages.contains_key(&pid) => spill.take_page(pid)?,
+            _ => None,
+        };
+
+        let page = match deltas.pages.entry(pid) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                if let Some(budget) = &deltas.budget {
+                    budget.charge(MemoryConsumer::Overlay, PAGE_SIZE as usize);
+                    budget.enforce();
+                }
 
-        page.as_mut()
+                let page = match spilled {
+                    Some(page) => page,
+                    None => match prev_deltas.page(pid)? {
+                        Some(p) => Box::new(*p),
+                        #[allow(clippy::unwrap_used)]
+                        None => Box::new(
+                            self.base_store
+                                .get_slice(pid << PAGE_SIZE_NBIT, PAGE_SIZE)
+                                .unwrap()
+                                .try_into()
+                                .unwrap(),
+                        ),
+                    },
+                };
+                e.insert(page)
+            }
+        };
+
+        Ok(page.as_mut())
     }
 
-    #[must_use]
-    pub fn delta(&self) -> (StoreDelta, Ash) {
+    /// The pages changed by this revision, in page order, and the writes that changed them.
+    pub fn delta(&self) -> Result<(StoreDelta, Ash), ShaleError> {
         let guard = self.deltas.read();
-        let mut pages: Vec<DeltaPage> = guard
-            .pages
-            .iter()
-            .map(|page| DeltaPage(*page.0, page.1.clone()))
-            .collect();
-        pages.sort_by_key(|p| p.0);
-        (StoreDelta(pages), guard.plain.clone())
+        Ok((guard.pages_of(&guard.sorted_pids())?, guard.writes()?))
     }
+
+    /// The ids of the pages changed by this revision, in page order, which are read a few at a
+    /// time with [StoreRevMut::delta_pages_of] rather than all at once when they may be spilled.
+    pub fn delta_pids(&self) -> Vec<u64> {
+        self.deltas.read().sorted_pids()
+    }
+
+    /// The pages `pids` changed by this revision, see [StoreRevMut::delta_pids].
+    pub fn delta_pages_of(&self, pids: &[u64]) -> Result<StoreDelta, ShaleError> {
+        Ok(self.deltas.read().pages_of(pids)?)
+    }
+
+    /// The writes made by this revision, in order. The ones spilled are read back all at once,
+    /// as they make a single Wal record.
+    pub fn delta_writes(&self) -> Result<Ash, ShaleError> {
+        Ok(self.deltas.read().writes()?)
+    }
+
+    /// The number of pages changed by this revision.
+    pub fn delta_pages(&self) -> usize {
+        self.deltas.read().npages()
+    }
+
+    /// Reserves room for `npages` more changed pages, up to the pages held before spilling, so
+    /// that the pages of a large batch don't grow the overlay step by step.
+    pub fn reserve_pages(&self, npages: usize) {
+        let mut deltas = self.deltas.write();
+        let npages = match &deltas.spill_cfg {
+            Some(spill_cfg) => npages.min(spill_cfg.threshold / PAGE_SIZE as usize),
+            None => npages,
+        };
+        deltas.pages.reserve(npages);
+    }
+
     pub fn reset_deltas(&self) {
-        let mut guard = self.deltas.write();
-        guard.plain = Ash::default();
-        guard.pages = HashMap::new();
+        self.deltas.write().clear();
     }
 }
 
@@ -519,56 +782,47 @@ impl LinearStore for StoreRevMut {
             let s_off = offset & PAGE_MASK as usize;
             let e_pid = (end >> PAGE_SIZE_NBIT) as u64;
             let e_off = end & PAGE_MASK as usize;
-            let deltas = &self.deltas.read().pages;
-            let prev_deltas = &self.prev_deltas.read().pages;
+            let deltas = self.deltas.read();
+            let prev_deltas = self.prev_deltas.read();
+            // `None` if a spilled page can't be read
+            let page = |pid: u64| match deltas.page(pid).ok()? {
+                Some(p) => Some(Some(p)),
+                None => prev_deltas.page(pid).ok(),
+            };
             if s_pid == e_pid {
-                match deltas.get(&s_pid) {
+                match page(s_pid)? {
                     #[allow(clippy::indexing_slicing)]
                     Some(p) => p[s_off..e_off + 1].to_vec(),
-                    None => match prev_deltas.get(&s_pid) {
-                        #[allow(clippy::indexing_slicing)]
-                        Some(p) => p[s_off..e_off + 1].to_vec(),
-                        None => self.base_store.get_slice(offset as u64, length)?,
-                    },
+                    None => self.base_store.get_slice(offset as u64, length)?,
                 }
             } else {
-                let mut data = match deltas.get(&s_pid) {
+                let mut data = match page(s_pid)? {
                     #[allow(clippy::indexing_slicing)]
                     Some(p) => p[s_off..].to_vec(),
-                    None => match prev_deltas.get(&s_pid) {
-                        #[allow(clippy::indexing_slicing)]
-                        Some(p) => p[s_off..].to_vec(),
-                        None => self
-                            .base_store
-                            .get_slice(offset as u64, PAGE_SIZE - s_off as u64)?,
-                    },
+                    None => self
+                        .base_store
+                        .get_slice(offset as u64, PAGE_SIZE - s_off as u64)?,
                 };
                 for p in s_pid + 1..e_pid {
-                    match deltas.get(&p) {
-                        Some(p) => data.extend(**p),
-                        None => match prev_deltas.get(&p) {
-                            Some(p) => data.extend(**p),
-                            None => data.extend(
-                                &self.base_store.get_slice(p << PAGE_SIZE_NBIT, PAGE_SIZE)?,
-                            ),
-                        },
+                    match page(p)? {
+                        Some(p) => data.extend(p.iter()),
+                        None => {
+                            data.extend(&self.base_store.get_slice(p << PAGE_SIZE_NBIT, PAGE_SIZE)?)
+                        }
                     };
                 }
-                match deltas.get(&e_pid) {
+                match page(e_pid)? {
                     #[allow(clippy::indexing_slicing)]
                     Some(p) => data.extend(&p[..e_off + 1]),
-                    None => match prev_deltas.get(&e_pid) {
-                        #[allow(clippy::indexing_slicing)]
-                        Some(p) => data.extend(&p[..e_off + 1]),
-                        None => data.extend(
-                            self.base_store
-                                .get_slice(e_pid << PAGE_SIZE_NBIT, e_off as u64 + 1)?,
-                        ),
-                    },
+                    None => data.extend(
+                        self.base_store
+                            .get_slice(e_pid << PAGE_SIZE_NBIT, e_off as u64 + 1)?,
+                    ),
                 }
                 data
             }
         };
+        self.counters.read(length);
         Some(Box::new(StoreRef { data }))
     }
 
@@ -589,8 +843,9 @@ impl LinearStore for StoreRevMut {
         if s_pid == e_pid {
             let mut deltas = self.deltas.write();
             #[allow(clippy::indexing_slicing)]
-            let slice = &mut self.get_page_mut(&mut deltas, &self.prev_deltas.read(), s_pid as u64)
-                [s_off..e_off + 1];
+            let slice =
+                &mut self.get_page_mut(&mut deltas, &self.prev_deltas.read(), s_pid as u64)?
+                    [s_off..e_off + 1];
             undo.extend(&*slice);
             slice.copy_from_slice(change)
         } else {
@@ -600,7 +855,7 @@ impl LinearStore for StoreRevMut {
                 let mut deltas = self.deltas.write();
                 #[allow(clippy::indexing_slicing)]
                 let slice =
-                    &mut self.get_page_mut(&mut deltas, &self.prev_deltas.read(), s_pid as u64)
+                    &mut self.get_page_mut(&mut deltas, &self.prev_deltas.read(), s_pid as u64)?
                         [s_off..];
                 undo.extend(&*slice);
                 #[allow(clippy::indexing_slicing)]
@@ -612,7 +867,7 @@ impl LinearStore for StoreRevMut {
 
             let mut deltas = self.deltas.write();
             for p in s_pid + 1..e_pid {
-                let slice = self.get_page_mut(&mut deltas, &self.prev_deltas.read(), p as u64);
+                let slice = self.get_page_mut(&mut deltas, &self.prev_deltas.read(), p as u64)?;
                 undo.extend(&*slice);
                 #[allow(clippy::indexing_slicing)]
                 slice.copy_from_slice(&change[..PAGE_SIZE as usize]);
@@ -621,22 +876,26 @@ impl LinearStore for StoreRevMut {
             }
 
             #[allow(clippy::indexing_slicing)]
-            let slice = &mut self.get_page_mut(&mut deltas, &self.prev_deltas.read(), e_pid as u64)
-                [..e_off + 1];
+            let slice =
+                &mut self.get_page_mut(&mut deltas, &self.prev_deltas.read(), e_pid as u64)?
+                    [..e_off + 1];
             undo.extend(&*slice);
             slice.copy_from_slice(change);
         }
 
-        let plain = &mut self.deltas.write().plain;
+        let mut deltas = self.deltas.write();
         assert!(undo.len() == redo.len());
-        plain.undo.push(StoreWrite {
+        deltas.plain_bytes += undo.len() + redo.len();
+        deltas.plain.undo.push(StoreWrite {
             offset: offset as u64,
             data: undo.into(),
         });
-        plain.redo.push(StoreWrite {
+        deltas.plain.redo.push(StoreWrite {
             offset: offset as u64,
             data: redo,
         });
+        deltas.spill_if_needed()?;
+        self.counters.written(length);
 
         Ok(())
     }
@@ -648,6 +907,19 @@ impl LinearStore for StoreRevMut {
     fn is_writeable(&self) -> bool {
         true
     }
+
+    fn pin_region(&self, offset: usize, length: u64) -> Result<(), ShaleError> {
+        self.base_store
+            .pin_region(offset as u64, length)
+            .ok_or(ShaleError::InvalidCacheView {
+                offset,
+                size: length,
+            })
+    }
+
+    fn stats(&self) -> StoreStats {
+        self.counters.stats()
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -711,6 +983,82 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn reserve_pages() {
+        let z: Arc<dyn MemStoreR> = Arc::new(ZeroStore::default());
+        let store = StoreRevMut::new(z.clone());
+        store.reserve_pages(1000);
+        assert!(store.deltas.read().pages.capacity() >= 1000);
+        assert_eq!(store.delta_pages(), 0);
+
+        // no room is reserved past what is spilled
+        let spilled = StoreRevMut::new(z).with_spill(2 * PAGE_SIZE as usize, &std::env::temp_dir());
+        spilled.reserve_pages(1000);
+        assert!(spilled.deltas.read().pages.capacity() < 1000);
+    }
+
+    #[test]
+    fn spill() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(42);
+        let z: Arc<dyn MemStoreR> = Arc::new(ZeroStore::default());
+        let mut in_memory = StoreRevMut::new(z.clone());
+        // spills whenever more than two pages are changed
+        let mut spilled =
+            StoreRevMut::new(z).with_spill(2 * PAGE_SIZE as usize, &std::env::temp_dir());
+
+        let max = 50 * PAGE_SIZE;
+        let write = |stores: [&mut StoreRevMut; 2], rng: &mut StdRng| {
+            let l = rng.gen_range(0..max - 1);
+            let r = rng.gen_range(l + 1..std::cmp::min(l + 3 * PAGE_SIZE, max));
+            let data: Vec<u8> = (l..r).map(|_| rng.gen()).collect();
+            for store in stores {
+                store.write(l as usize, &data).unwrap();
+            }
+        };
+        for _ in 0..100 {
+            write([&mut in_memory, &mut spilled], &mut rng);
+        }
+        assert!(spilled.deltas.read().spill.is_some());
+        assert!(spilled.deltas.read().pages.len() <= 3);
+
+        // a revision on top reads through the spilled pages of its base
+        let mut in_memory = StoreRevMut::new_from_other(&in_memory);
+        let mut spilled = StoreRevMut::new_from_other(&spilled);
+        for _ in 0..100 {
+            write([&mut in_memory, &mut spilled], &mut rng);
+        }
+
+        for _ in 0..100 {
+            let l = rng.gen_range(0..max - 1);
+            let r = rng.gen_range(l + 1..max);
+            let view = |store: &StoreRevMut| store.get_view(l as usize, r - l).unwrap().as_deref();
+            assert_eq!(view(&in_memory), view(&spilled));
+        }
+
+        let (pages, writes) = in_memory.delta().unwrap();
+        let (spilled_pages, spilled_writes) = spilled.delta().unwrap();
+        assert_eq!(spilled.delta_pages(), pages.len());
+        assert!(pages
+            .iter()
+            .zip(spilled_pages.iter())
+            .all(|(a, b)| a.0 == b.0 && a.1 == b.1));
+        assert_eq!(writes.undo, spilled_writes.undo);
+        assert_eq!(writes.redo, spilled_writes.redo);
+
+        // the pages are read a chunk at a time as well
+        let pids = spilled.delta_pids();
+        assert_eq!(pids, pages.iter().map(|page| page.0).collect::<Vec<_>>());
+        let mut expected = pages.iter();
+        for chunk in pids.chunks(7) {
+            for page in spilled.delta_pages_of(chunk).unwrap().iter() {
+                let expected = expected.next().unwrap();
+                assert!(page.0 == expected.0 && page.1 == expected.1);
+            }
+        }
+        assert!(expected.next().is_none());
+    }
 }
 
 #[derive(TypedBuilder)]
@@ -721,6 +1069,25 @@ pub struct StoreConfig {
     file_nbit: u64,
     store_id: StoreId,
     rootdir: PathBuf,
+    /// Whether to allocate the files of the store whole on disk when they're first opened.
+    #[builder(default = false)]
+    preallocate: bool,
+    /// The files of the addresses from the first element on, such as the ones of the hot segment
+    /// of an item stash, are in the directory of the second one instead of `rootdir`. The first
+    /// element is a multiple of the size of a file.
+    #[builder(default)]
+    split_dir: Option<(usize, PathBuf)>,
+}
+
+/// Where the pages a [CachedStore] needed were found, since it was created.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PageReads {
+    /// Pages found in the page cache of the store.
+    pub cache_hits: u64,
+    /// Pages found in the disk buffer, not written to the store files yet.
+    pub buffer_hits: u64,
+    /// Pages read from the store files.
+    pub file_reads: u64,
 }
 
 #[derive(Debug)]
@@ -729,6 +1096,10 @@ struct CachedStoreInner {
     pinned_pages: HashMap<u64, (usize, Page)>,
     files: Arc<FilePool>,
     disk_requester: DiskBufferRequester,
+    /// Accounts the pages in `cached_pages`.
+    budget: Option<MemoryBudget>,
+    reads: PageReads,
+    counters: Arc<StoreCounters>,
 }
 
 #[derive(Clone, Debug)]
@@ -752,15 +1123,73 @@ impl CachedStore {
                 pinned_pages: HashMap::new(),
                 files,
                 disk_requester,
+                budget: None,
+                reads: PageReads::default(),
+                counters: Default::default(),
             })),
             store_id,
         })
     }
 
+    /// Where the pages the store needed were found so far.
+    pub fn page_reads(&self) -> PageReads {
+        self.inner.read().reads
+    }
+
+    /// The I/O of the revisions of the store so far, see [LinearStore::stats].
+    pub fn stats(&self) -> StoreStats {
+        self.inner.read().counters.stats()
+    }
+
+    /// Accounts the cached pages to `budget`, which evicts them when it goes over its limit.
+    pub fn with_memory_budget(self, budget: &MemoryBudget) -> Self {
+        {
+            let mut inner = self.inner.write();
+            budget.charge(
+                MemoryConsumer::PageCache,
+                inner.cached_pages.len() * PAGE_SIZE as usize,
+            );
+            inner.budget = Some(budget.clone());
+        }
+
+        let reclaimer: Arc<dyn Reclaim> = self.inner.clone();
+        budget.register(MemoryConsumer::PageCache, Arc::downgrade(&reclaimer));
+        self
+    }
+
     pub fn clone_files(&self) -> Arc<FilePool> {
         self.inner.read().files.clone()
     }
 
+    /// Opens the files the pages `pids`, in page order, are written to, which allocates them on
+    /// disk if the store preallocates its files.
+    pub fn open_files(&self, pids: &[u64]) -> Result<(), StoreError<std::io::Error>> {
+        let files = self.inner.read().files.clone();
+        let file_nbit = files.get_file_nbit();
+        let mut last_fid = None;
+        // the pages are sorted, so the pages of a file are next to each other
+        for pid in pids {
+            let fid = (pid << PAGE_SIZE_NBIT) >> file_nbit;
+            if last_fid != Some(fid) {
+                files.get_file(fid)?;
+                last_fid = Some(fid);
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the StoreDelta that will undo a delta of the pages `pids` once it is applied, i.e. the
+    /// current content of these pages, without changing the store.
+    pub fn undo_delta(&self, pids: &[u64]) -> Option<StoreDelta> {
+        let mut pages = Vec::new();
+        for pid in pids {
+            let data = PageRef::new(*pid, self)?;
+            #[allow(clippy::unwrap_used)]
+            pages.push(DeltaPage(*pid, Box::new((*data).try_into().unwrap())));
+        }
+        Some(StoreDelta(pages))
+    }
+
     /// Apply `delta` to the store and return the StoreDelta that can undo this change.
     pub fn update(&self, delta: &StoreDelta) -> Option<StoreDelta> {
         let mut pages = Vec::new();
@@ -788,13 +1217,21 @@ impl CachedStoreInner {
                 e.1.as_mut_ptr()
             }
             None => {
-                let page = self
-                    .cached_pages
-                    .pop(&pid)
-                    .or_else(|| self.disk_requester.get_page(store_id, pid));
+                let page = match self.pop_cached_page(pid) {
+                    Some(page) => {
+                        self.reads.cache_hits += 1;
+                        self.counters.view_cache_hit();
+                        Some(page)
+                    }
+                    None => self.disk_requester.get_page(store_id, pid).inspect(|_| {
+                        self.reads.buffer_hits += 1;
+                    }),
+                };
                 let mut page = match page {
                     Some(page) => page,
                     None => {
+                        self.reads.file_reads += 1;
+                        self.counters.syscall();
                         let file_nbit = self.files.get_file_nbit();
                         let file_size = 1 << file_nbit;
                         let poff = pid << PAGE_SIZE_NBIT;
@@ -837,7 +1274,44 @@ impl CachedStoreInner {
             }
             _ => unreachable!(),
         };
-        self.cached_pages.put(pid, page);
+        if let Some(budget) = &self.budget {
+            budget.charge(MemoryConsumer::PageCache, PAGE_SIZE as usize);
+        }
+        if self.cached_pages.push(pid, page).is_some() {
+            self.release_pages(1);
+        }
+    }
+
+    fn pop_cached_page(&mut self, pid: u64) -> Option<Page> {
+        let page = self.cached_pages.pop(&pid)?;
+        self.release_pages(1);
+        Some(page)
+    }
+
+    fn release_pages(&self, npages: usize) {
+        if let Some(budget) = &self.budget {
+            budget.release(MemoryConsumer::PageCache, npages * PAGE_SIZE as usize);
+        }
+    }
+}
+
+impl Drop for CachedStoreInner {
+    fn drop(&mut self) {
+        self.release_pages(self.cached_pages.len());
+    }
+}
+
+impl Reclaim for RwLock<CachedStoreInner> {
+    fn reclaim(&self, bytes: usize) {
+        let Some(mut inner) = self.try_write() else {
+            return;
+        };
+
+        let mut npages = 0;
+        while npages * (PAGE_SIZE as usize) < bytes && inner.cached_pages.pop_lru().is_some() {
+            npages += 1;
+        }
+        inner.release_pages(npages);
     }
 }
 
@@ -872,7 +1346,19 @@ impl PageRef {
 
 impl Drop for PageRef {
     fn drop(&mut self) {
-        self.store.inner.write().unpin_page(self.pid);
+        let budget = {
+            let mut inner = self.store.inner.write();
+            inner.unpin_page(self.pid);
+            inner
+                .budget
+                .as_ref()
+                .filter(|budget| budget.over_limit())
+                .cloned()
+        };
+        // the budget can only evict from this store once it is unlocked
+        if let Some(budget) = budget {
+            budget.enforce();
+        }
     }
 }
 
@@ -906,6 +1392,24 @@ impl MemStoreR for CachedStore {
     fn id(&self) -> StoreId {
         self.store_id
     }
+
+    fn counters(&self) -> Arc<StoreCounters> {
+        self.inner.read().counters.clone()
+    }
+
+    /// The pages of the region are pinned like the ones being read, and never unpinned, so they
+    /// stay out of the cache and of the [MemoryBudget] evicting from it.
+    fn pin_region(&self, offset: u64, length: u64) -> Option<()> {
+        if length == 0 {
+            return Some(());
+        }
+        let end = offset + length - 1;
+        let mut inner = self.inner.write();
+        for pid in offset >> PAGE_SIZE_NBIT..=end >> PAGE_SIZE_NBIT {
+            inner.pin_page(self.store_id, pid).ok()?;
+        }
+        Some(())
+    }
 }
 
 #[derive(Debug)]
@@ -913,18 +1417,33 @@ pub struct FilePool {
     files: parking_lot::Mutex<lru::LruCache<u64, Arc<File>>>,
     file_nbit: u64,
     rootdir: PathBuf,
+    preallocate: bool,
+    /// The first file in the directory of [StoreConfig::split_dir], and that directory.
+    split_dir: Option<(u64, PathBuf)>,
 }
 
 impl FilePool {
     fn new(cfg: &StoreConfig) -> Result<Self, StoreError<std::io::Error>> {
         let rootdir = &cfg.rootdir;
         let file_nbit = cfg.file_nbit;
+        let split_dir = match &cfg.split_dir {
+            Some((base, _)) if base & ((1 << file_nbit) - 1) != 0 => {
+                return Err(StoreError::Init(format!(
+                    "the files of {base:#x} on can't have a directory of their own with files \
+                     of 2^{file_nbit} bytes"
+                )))
+            }
+            Some((base, dir)) => Some(((base >> file_nbit) as u64, dir.clone())),
+            None => None,
+        };
         let s = Self {
             files: parking_lot::Mutex::new(lru::LruCache::new(
                 NonZeroUsize::new(cfg.ncached_files).expect("non-zero file num"),
             )),
             file_nbit,
             rootdir: rootdir.to_path_buf(),
+            preallocate: cfg.preallocate,
+            split_dir,
         };
         let f0 = s.get_file(0)?;
         if let Some(inner) = Arc::<File>::into_inner(f0) {
@@ -942,7 +1461,14 @@ impl FilePool {
             Some(f) => f.clone(),
             None => {
                 let file_size = 1 << self.file_nbit;
-                let file = Arc::new(File::new(fid, file_size, &self.rootdir)?);
+                let dir = match &self.split_dir {
+                    Some((first, dir)) if fid >= *first => dir,
+                    _ => &self.rootdir,
+                };
+                let file = Arc::new(File::new(fid, file_size, dir)?);
+                if self.preallocate {
+                    file.preallocate(file_size)?;
+                }
                 files.put(fid, file.clone());
                 file
             }
@@ -964,4 +1490,19 @@ pub struct WalConfig {
     pub block_nbit: u64,
     #[builder(default = 100)] // preserve a rolling window of 100 past commits
     pub max_revisions: u32,
+    /// Checkpoint once the Wal records whose pages are not all written yet add up to this many
+    /// bytes: new commits wait until every buffered page has been written to the store files and
+    /// the Wal has been truncated to the last `max_revisions` records. This bounds the Wal that
+    /// is replayed after a crash. Zero means no threshold.
+    #[builder(default = 0)]
+    pub checkpoint_bytes: u64,
+    /// Checkpoint once this many Wal records have pages that are not all written yet, see
+    /// `checkpoint_bytes`. Zero means no threshold.
+    #[builder(default = 0)]
+    pub checkpoint_records: usize,
+    /// Whether to allocate the blocks of each Wal record on disk before writing it, so that the
+    /// file system running out of space fails the allocation rather than the write, see
+    /// [DbConfig::preallocate](crate::config::DbConfig::preallocate).
+    #[builder(default = false)]
+    pub preallocate: bool,
 }
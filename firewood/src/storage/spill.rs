@@ -0,0 +1,195 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Temporary files the overlay of a [StoreRevMut](super::StoreRevMut) is spilled to once it
+//! grows past a threshold, see [StoreRevMut::with_spill](super::StoreRevMut::with_spill).
+//!
+//! Pages are spilled to fixed-size slots, indexed by page so that they are read back in order
+//! when the delta is committed, and a page that is written again is loaded back, freeing its
+//! slot. The undo and redo writes are appended to a log of their own, in the order they were
+//! made. The files are removed as soon as they are created, so they go away with the process.
+
+use super::{Ash, Page, PAGE_SIZE};
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, ErrorKind},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// When and where the overlay of a [StoreRevMut](super::StoreRevMut) is spilled.
+#[derive(Clone, Debug)]
+pub(super) struct SpillConfig {
+    /// Bytes of pages and writes held in memory above which they are all spilled.
+    pub(super) threshold: usize,
+    pub(super) dir: PathBuf,
+}
+
+fn temp_file(dir: &Path) -> io::Result<File> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let path = dir.join(format!(
+        "firewood-spill-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
+
+#[derive(Debug)]
+pub(super) struct Spill {
+    pages_file: File,
+    /// The slot of every spilled page.
+    pages: BTreeMap<u64, u64>,
+    /// Slots of the pages loaded back, reused before the file grows.
+    free_slots: Vec<u64>,
+    nslots: u64,
+    writes_file: File,
+    /// Length of the log of writes, made of length-prefixed [Ash] segments.
+    writes_len: u64,
+}
+
+impl Spill {
+    pub(super) fn new(dir: &Path) -> io::Result<Self> {
+        Ok(Self {
+            pages_file: temp_file(dir)?,
+            pages: BTreeMap::new(),
+            free_slots: Vec::new(),
+            nslots: 0,
+            writes_file: temp_file(dir)?,
+            writes_len: 0,
+        })
+    }
+
+    /// Number of pages spilled.
+    pub(super) fn npages(&self) -> usize {
+        self.pages.len()
+    }
+
+    fn read_slot(&self, slot: u64) -> io::Result<Page> {
+        let mut page: Page = Box::new([0; PAGE_SIZE as usize]);
+        self.pages_file
+            .read_exact_at(page.as_mut(), slot * PAGE_SIZE)?;
+        Ok(page)
+    }
+
+    /// Reads a spilled page, leaving it spilled.
+    pub(super) fn page(&self, pid: u64) -> io::Result<Option<Page>> {
+        self.pages
+            .get(&pid)
+            .map(|&slot| self.read_slot(slot))
+            .transpose()
+    }
+
+    /// Loads a spilled page back, so that it can be changed in memory.
+    pub(super) fn take_page(&mut self, pid: u64) -> io::Result<Option<Page>> {
+        let Some(slot) = self.pages.remove(&pid) else {
+            return Ok(None);
+        };
+        self.free_slots.push(slot);
+        self.read_slot(slot).map(Some)
+    }
+
+    pub(super) fn spill_pages(
+        &mut self,
+        pages: impl Iterator<Item = (u64, Page)>,
+    ) -> io::Result<()> {
+        for (pid, page) in pages {
+            let slot = self.free_slots.pop().unwrap_or_else(|| {
+                self.nslots += 1;
+                self.nslots - 1
+            });
+            self.pages_file
+                .write_all_at(page.as_ref(), slot * PAGE_SIZE)?;
+            self.pages.insert(pid, slot);
+        }
+        Ok(())
+    }
+
+    pub(super) fn spill_writes(&mut self, writes: &Ash) -> io::Result<()> {
+        let segment = bincode::serialize(writes).map_err(io::Error::other)?;
+        let len = segment.len() as u64;
+        self.writes_file
+            .write_all_at(&len.to_le_bytes(), self.writes_len)?;
+        self.writes_file
+            .write_all_at(&segment, self.writes_len + size_of::<u64>() as u64)?;
+        self.writes_len += size_of::<u64>() as u64 + len;
+        Ok(())
+    }
+
+    /// The ids of the spilled pages, in page order.
+    pub(super) fn pids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.pages.keys().copied()
+    }
+
+    /// Reads back all the spilled writes, in the order they were made.
+    pub(super) fn writes(&self) -> io::Result<Ash> {
+        let mut writes = Ash::default();
+        let mut offset = 0;
+        while offset < self.writes_len {
+            let mut len = [0; size_of::<u64>()];
+            self.writes_file.read_exact_at(&mut len, offset)?;
+            offset += size_of::<u64>() as u64;
+
+            let len = u64::from_le_bytes(len);
+            let mut segment = vec![0; len as usize];
+            self.writes_file.read_exact_at(&mut segment, offset)?;
+            offset += len;
+
+            let segment: Ash = bincode::deserialize(&segment)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            writes.undo.extend(segment.undo);
+            writes.redo.extend(segment.redo);
+        }
+        Ok(writes)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::storage::StoreWrite;
+
+    #[test]
+    fn spill() {
+        let mut spill = Spill::new(&std::env::temp_dir()).unwrap();
+        let page = |b: u8| -> Page { Box::new([b; PAGE_SIZE as usize]) };
+
+        spill
+            .spill_pages([(7, page(7)), (3, page(3))].into_iter())
+            .unwrap();
+        assert_eq!(spill.page(3).unwrap(), Some(page(3)));
+        assert_eq!(spill.page(5).unwrap(), None);
+
+        // a page loaded back frees its slot for the next one
+        assert_eq!(spill.take_page(7).unwrap(), Some(page(7)));
+        spill.spill_pages([(1, page(1))].into_iter()).unwrap();
+        assert_eq!(spill.nslots, 2);
+        assert_eq!(spill.pids().collect::<Vec<_>>(), [1, 3]);
+        assert_eq!(spill.page(1).unwrap(), Some(page(1)));
+
+        let write = |offset: u64, b: u8| StoreWrite {
+            offset,
+            data: vec![b; 3].into(),
+        };
+        for i in 0..2 {
+            spill
+                .spill_writes(&Ash {
+                    undo: vec![write(i, 0)],
+                    redo: vec![write(i, 1)],
+                })
+                .unwrap();
+        }
+        let writes = spill.writes().unwrap();
+        assert_eq!(writes.undo, [write(0, 0), write(1, 0)]);
+        assert_eq!(writes.redo, [write(0, 1), write(1, 1)]);
+    }
+}
@@ -0,0 +1,678 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Mocks of the storage layer and of the [api::Db] interface, so that crates built on firewood
+//! can unit-test their integration without a disk. Compiled with the `test-utils` feature.
+//!
+//! [MockLinearStore] is a [LinearStore] held in memory, such as for a
+//! [Trie](crate::merkle::standalone::Trie), that records the calls made to it and can be
+//! scripted to fail writes and reads. [MockDb] is an [api::Db] that holds its revisions in
+//! [ReferenceTrie]s and commits its proposals like a [Db](crate::db::Db) does, without the
+//! proofs; it also records the calls made to it, and can be scripted to fail them. There is no
+//! mock of a batch, as a [Batch] is a plain [Vec] of [BatchOp]s.
+//!
+//! The mocks are handles: their clones share the same store or revisions, calls and script, so
+//! a test can keep one to inspect while the code under test owns another.
+
+use crate::{
+    db::DbError,
+    reference::ReferenceTrie,
+    shale::{in_mem::InMemLinearStore, LinearStore, LinearStoreView, SendSyncDerefMut},
+    shale::{ShaleError, StoreId, StoreStats},
+    v2::api::{self, Batch, BatchOp, Error, HashKey, KeyType, Proof, RangeProof, ValueType},
+};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    ops::{Deref, DerefMut, Range},
+    sync::Arc,
+};
+
+/// A call made to a [MockLinearStore].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreCall {
+    GetView { offset: usize, length: u64 },
+    Write { offset: usize, change: Vec<u8> },
+}
+
+#[derive(Debug, Default)]
+struct StoreScript {
+    calls: Vec<StoreCall>,
+    /// How many of the next writes fail.
+    failed_writes: usize,
+    /// The ranges no view is returned into.
+    hidden: Vec<Range<usize>>,
+    read_only: bool,
+}
+
+/// A [LinearStore] that records the calls made to it, and fails them as scripted.
+pub struct MockLinearStore {
+    inner: Box<dyn SendSyncDerefMut<Target = dyn LinearStore>>,
+    script: Arc<Mutex<StoreScript>>,
+}
+
+impl MockLinearStore {
+    /// An empty in-memory store of `size` bytes, which grows as it is written past its end.
+    pub fn new(size: u64, id: StoreId) -> Self {
+        Self::wrap(&InMemLinearStore::new(size, id))
+    }
+
+    /// Records the calls made through the mock to `store`, which the mock shares.
+    pub fn wrap(store: &dyn LinearStore) -> Self {
+        Self {
+            inner: store.get_shared(),
+            script: Default::default(),
+        }
+    }
+
+    /// The calls made to the store and its clones so far, in order.
+    pub fn calls(&self) -> Vec<StoreCall> {
+        self.script.lock().calls.clone()
+    }
+
+    pub fn clear_calls(&self) {
+        self.script.lock().calls.clear();
+    }
+
+    /// Fails the next `n` writes with an IO error, without changing the store.
+    pub fn fail_next_writes(&self, n: usize) {
+        self.script.lock().failed_writes = n;
+    }
+
+    /// Returns no view overlapping `range`, as if those bytes couldn't be read.
+    pub fn hide(&self, range: Range<usize>) {
+        self.script.lock().hidden.push(range);
+    }
+
+    /// Rejects every write while set, and reports the store as not writeable.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.script.lock().read_only = read_only;
+    }
+}
+
+impl Clone for MockLinearStore {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.get_shared(),
+            script: self.script.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for MockLinearStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockLinearStore")
+            .field("id", &self.inner.id())
+            .field("script", &*self.script.lock())
+            .finish()
+    }
+}
+
+impl LinearStore for MockLinearStore {
+    fn get_view(
+        &self,
+        offset: usize,
+        length: u64,
+    ) -> Option<Box<dyn LinearStoreView<DerefReturn = Vec<u8>>>> {
+        {
+            let mut script = self.script.lock();
+            script.calls.push(StoreCall::GetView { offset, length });
+            let end = offset + length as usize;
+            if script
+                .hidden
+                .iter()
+                .any(|range| range.start < end && offset < range.end)
+            {
+                return None;
+            }
+        }
+        self.inner.get_view(offset, length)
+    }
+
+    fn get_shared(&self) -> Box<dyn SendSyncDerefMut<Target = dyn LinearStore>> {
+        Box::new(MockLinearStoreShared(self.clone()))
+    }
+
+    fn write(&mut self, offset: usize, change: &[u8]) -> Result<(), ShaleError> {
+        {
+            let mut script = self.script.lock();
+            script.calls.push(StoreCall::Write {
+                offset,
+                change: change.to_vec(),
+            });
+            if script.read_only {
+                return Err(ShaleError::ImmutableWrite);
+            }
+            if script.failed_writes > 0 {
+                script.failed_writes -= 1;
+                return Err(ShaleError::Io(std::io::Error::other(
+                    "scripted write failure",
+                )));
+            }
+        }
+        self.inner.write(offset, change)
+    }
+
+    fn id(&self) -> StoreId {
+        self.inner.id()
+    }
+
+    fn is_writeable(&self) -> bool {
+        !self.script.lock().read_only && self.inner.is_writeable()
+    }
+
+    fn stats(&self) -> StoreStats {
+        self.inner.stats()
+    }
+
+    fn pin_region(&self, offset: usize, length: u64) -> Result<(), ShaleError> {
+        self.inner.pin_region(offset, length)
+    }
+}
+
+struct MockLinearStoreShared(MockLinearStore);
+
+impl Deref for MockLinearStoreShared {
+    type Target = dyn LinearStore;
+
+    fn deref(&self) -> &(dyn LinearStore + 'static) {
+        &self.0
+    }
+}
+
+impl DerefMut for MockLinearStoreShared {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// A call made to a [MockDb], or to one of its proposals.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DbCall {
+    Revision(HashKey),
+    RootHash,
+    /// A batch proposed on the revision or proposal with root hash `base`.
+    Propose {
+        base: HashKey,
+        batch: Batch<Vec<u8>, Vec<u8>>,
+    },
+    /// The commit of the proposal with that root hash.
+    Commit(HashKey),
+}
+
+/// The kind of a [DbCall], to script failures with [MockDb::fail_next].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbMethod {
+    Revision,
+    RootHash,
+    Propose,
+    Commit,
+}
+
+#[derive(Debug)]
+struct DbState {
+    revisions: HashMap<HashKey, Arc<MockView>>,
+    latest: HashKey,
+    calls: Vec<DbCall>,
+    failures: VecDeque<(DbMethod, Error)>,
+}
+
+impl DbState {
+    /// Records `call`, returning the failure scripted for it, if any.
+    fn call(&mut self, method: DbMethod, call: DbCall) -> Result<(), Error> {
+        self.calls.push(call);
+        let failure = self.failures.iter().position(|(m, _)| *m == method);
+        match failure.and_then(|i| self.failures.remove(i)) {
+            Some((_, error)) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An [api::Db] in memory that records the calls made to it, and fails them as scripted. It
+/// keeps every revision it commits.
+#[derive(Debug, Clone)]
+pub struct MockDb {
+    state: Arc<Mutex<DbState>>,
+}
+
+impl Default for MockDb {
+    fn default() -> Self {
+        let empty = MockView::new(ReferenceTrie::default());
+        let latest = empty.root_hash;
+        Self {
+            state: Arc::new(Mutex::new(DbState {
+                revisions: HashMap::from([(latest, Arc::new(empty))]),
+                latest,
+                calls: Vec::new(),
+                failures: VecDeque::new(),
+            })),
+        }
+    }
+}
+
+impl MockDb {
+    /// The calls made to the DB, its clones and its proposals so far, in order.
+    pub fn calls(&self) -> Vec<DbCall> {
+        self.state.lock().calls.clone()
+    }
+
+    pub fn clear_calls(&self) {
+        self.state.lock().calls.clear();
+    }
+
+    /// Fails the next call of `method` with `error`. The failures scripted for the same method
+    /// are returned in order.
+    pub fn fail_next(&self, method: DbMethod, error: Error) {
+        self.state.lock().failures.push_back((method, error));
+    }
+
+    fn latest(&self) -> Arc<MockView> {
+        let state = self.state.lock();
+        #[allow(clippy::indexing_slicing)]
+        state.revisions[&state.latest].clone()
+    }
+}
+
+#[async_trait]
+impl api::Db for MockDb {
+    type Historical = MockView;
+
+    type Proposal = MockProposal;
+
+    async fn revision(&self, hash: HashKey) -> Result<Arc<MockView>, Error> {
+        let mut state = self.state.lock();
+        state.call(DbMethod::Revision, DbCall::Revision(hash))?;
+        state
+            .revisions
+            .get(&hash)
+            .cloned()
+            .ok_or(Error::HashNotFound { provided: hash })
+    }
+
+    async fn root_hash(&self) -> Result<HashKey, Error> {
+        let mut state = self.state.lock();
+        state.call(DbMethod::RootHash, DbCall::RootHash)?;
+        Ok(state.latest)
+    }
+
+    async fn propose<K: KeyType, V: ValueType>(
+        &self,
+        data: Batch<K, V>,
+    ) -> Result<MockProposal, Error> {
+        let base = self.latest();
+        MockProposal::new(self.clone(), None, &base, data)
+    }
+}
+
+/// A revision of a [MockDb].
+#[derive(Debug)]
+pub struct MockView {
+    trie: ReferenceTrie,
+    root_hash: HashKey,
+}
+
+impl MockView {
+    fn new(trie: ReferenceTrie) -> Self {
+        let root_hash = trie.root_hash().0;
+        Self { trie, root_hash }
+    }
+
+    /// Applies `batch` the way a [Db](crate::db::Db) does.
+    fn apply<K: KeyType, V: ValueType>(&self, batch: &Batch<K, V>) -> Result<Self, Error> {
+        let mut trie = self.trie.clone();
+        for op in batch {
+            match op {
+                BatchOp::Put { key, value } => {
+                    trie.insert(key.as_ref().to_vec(), value.as_ref().to_vec());
+                }
+                BatchOp::Delete { key } => {
+                    trie.remove(key.as_ref());
+                }
+                BatchOp::Move {
+                    key,
+                    new_key,
+                    overwrite,
+                } => {
+                    let (key, new_key) = (key.as_ref(), new_key.as_ref());
+                    trie.get(key).ok_or(DbError::KeyNotFound)?;
+                    if key == new_key {
+                        continue;
+                    }
+                    if trie.get(new_key).is_some() && !overwrite {
+                        return Err(DbError::KeyExists(new_key.to_vec()).into());
+                    }
+                    let value = trie.remove(key).ok_or(DbError::KeyNotFound)?;
+                    trie.insert(new_key.to_vec(), value);
+                }
+                BatchOp::DeletePrefix { prefix } => {
+                    let keys: Vec<_> = trie
+                        .keys()
+                        .filter(|key| key.starts_with(prefix.as_ref()))
+                        .map(<[u8]>::to_vec)
+                        .collect();
+                    for key in keys {
+                        trie.remove(&key);
+                    }
+                }
+            }
+        }
+        Ok(Self::new(trie))
+    }
+}
+
+type MockStream = futures::stream::Iter<std::vec::IntoIter<Result<(Box<[u8]>, Vec<u8>), Error>>>;
+
+#[async_trait]
+impl api::DbView for MockView {
+    type Stream<'a> = MockStream;
+
+    async fn root_hash(&self) -> Result<HashKey, Error> {
+        Ok(self.root_hash)
+    }
+
+    async fn val<K: KeyType>(&self, key: K) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.trie.get(key.as_ref()).map(<[u8]>::to_vec))
+    }
+
+    /// A mock has no proofs.
+    async fn single_key_proof<K: KeyType>(&self, _key: K) -> Result<Option<Proof<Vec<u8>>>, Error> {
+        Ok(None)
+    }
+
+    /// A mock has no proofs.
+    async fn range_proof<K: KeyType, V>(
+        &self,
+        _first_key: Option<K>,
+        _last_key: Option<K>,
+        _limit: Option<usize>,
+    ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, Error> {
+        Ok(None)
+    }
+
+    /// A mock has no proofs.
+    async fn range_proof_rev<K: KeyType, V>(
+        &self,
+        _first_key: Option<K>,
+        _last_key: Option<K>,
+        _limit: Option<usize>,
+    ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, Error> {
+        Ok(None)
+    }
+
+    fn iter_option<K: KeyType>(&self, first_key: Option<K>) -> Result<MockStream, Error> {
+        let first_key = first_key.as_ref().map_or(&[][..], |key| key.as_ref());
+        let entries: Vec<_> = self
+            .trie
+            .keys()
+            .filter(|key| *key >= first_key)
+            .filter_map(|key| Some(Ok((key.into(), self.trie.get(key)?.to_vec()))))
+            .collect();
+        Ok(futures::stream::iter(entries))
+    }
+
+    fn iter_rev_option<K: KeyType>(&self, last_key: Option<K>) -> Result<MockStream, Error> {
+        let last_key = last_key.as_ref().map(|key| key.as_ref());
+        let mut entries: Vec<_> = self
+            .trie
+            .keys()
+            .filter(|key| last_key.is_none_or(|last_key| *key <= last_key))
+            .filter_map(|key| Some(Ok((key.into(), self.trie.get(key)?.to_vec()))))
+            .collect();
+        entries.reverse();
+        Ok(futures::stream::iter(entries))
+    }
+}
+
+/// A proposal of a [MockDb], on its latest revision or on another proposal.
+#[derive(Debug)]
+pub struct MockProposal {
+    db: MockDb,
+    /// The root hash of the revision or proposal this one is based on.
+    base: HashKey,
+    parent: Option<Arc<MockProposal>>,
+    view: Arc<MockView>,
+}
+
+impl MockProposal {
+    fn new<K: KeyType, V: ValueType>(
+        db: MockDb,
+        parent: Option<Arc<MockProposal>>,
+        base: &MockView,
+        data: Batch<K, V>,
+    ) -> Result<Self, Error> {
+        let batch = data
+            .iter()
+            .map(|op| match op {
+                BatchOp::Put { key, value } => BatchOp::Put {
+                    key: key.as_ref().to_vec(),
+                    value: value.as_ref().to_vec(),
+                },
+                BatchOp::Delete { key } => BatchOp::Delete {
+                    key: key.as_ref().to_vec(),
+                },
+                BatchOp::Move {
+                    key,
+                    new_key,
+                    overwrite,
+                } => BatchOp::Move {
+                    key: key.as_ref().to_vec(),
+                    new_key: new_key.as_ref().to_vec(),
+                    overwrite: *overwrite,
+                },
+                BatchOp::DeletePrefix { prefix } => BatchOp::DeletePrefix {
+                    prefix: prefix.as_ref().to_vec(),
+                },
+            })
+            .collect();
+        db.state.lock().call(
+            DbMethod::Propose,
+            DbCall::Propose {
+                base: base.root_hash,
+                batch,
+            },
+        )?;
+
+        let view = Arc::new(base.apply(&data)?);
+        Ok(Self {
+            db,
+            base: base.root_hash,
+            parent,
+            view,
+        })
+    }
+}
+
+#[async_trait]
+impl api::DbView for MockProposal {
+    type Stream<'a> = MockStream;
+
+    async fn root_hash(&self) -> Result<HashKey, Error> {
+        self.view.root_hash().await
+    }
+
+    async fn val<K: KeyType>(&self, key: K) -> Result<Option<Vec<u8>>, Error> {
+        self.view.val(key).await
+    }
+
+    async fn single_key_proof<K: KeyType>(&self, key: K) -> Result<Option<Proof<Vec<u8>>>, Error> {
+        self.view.single_key_proof(key).await
+    }
+
+    async fn range_proof<K: KeyType, V: Send + Sync>(
+        &self,
+        first_key: Option<K>,
+        last_key: Option<K>,
+        limit: Option<usize>,
+    ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, Error> {
+        self.view
+            .range_proof::<K, V>(first_key, last_key, limit)
+            .await
+    }
+
+    async fn range_proof_rev<K: KeyType, V: Send + Sync>(
+        &self,
+        first_key: Option<K>,
+        last_key: Option<K>,
+        limit: Option<usize>,
+    ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, Error> {
+        self.view
+            .range_proof_rev::<K, V>(first_key, last_key, limit)
+            .await
+    }
+
+    fn iter_option<K: KeyType>(&self, first_key: Option<K>) -> Result<MockStream, Error> {
+        self.view.iter_option(first_key)
+    }
+
+    fn iter_rev_option<K: KeyType>(&self, last_key: Option<K>) -> Result<MockStream, Error> {
+        self.view.iter_rev_option(last_key)
+    }
+}
+
+#[async_trait]
+impl api::Proposal for MockProposal {
+    type Proposal = MockProposal;
+
+    /// Commits the proposal, and the proposals it is based on that aren't committed yet. Fails
+    /// with [Error::InvalidProposal] if the proposal isn't based on the latest revision by then.
+    async fn commit(self: Arc<Self>) -> Result<(), Error> {
+        self.db
+            .state
+            .lock()
+            .call(DbMethod::Commit, DbCall::Commit(self.view.root_hash))?;
+
+        if let Some(parent) = &self.parent {
+            let committed = self.db.state.lock().latest == self.base;
+            if !committed {
+                parent.clone().commit().await?;
+            }
+        }
+
+        let mut state = self.db.state.lock();
+        if state.latest != self.base {
+            return Err(Error::InvalidProposal);
+        }
+        state.latest = self.view.root_hash;
+        state
+            .revisions
+            .insert(self.view.root_hash, self.view.clone());
+        Ok(())
+    }
+
+    async fn propose<K: KeyType, V: ValueType>(
+        self: Arc<Self>,
+        data: Batch<K, V>,
+    ) -> Result<MockProposal, Error> {
+        let view = self.view.clone();
+        MockProposal::new(self.db.clone(), Some(self), &view, data)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::merkle::{
+        standalone::{Trie, TrieConfig},
+        Bincode,
+    };
+    use crate::v2::api::{Db, DbView, Proposal};
+    use futures::StreamExt;
+
+    #[test]
+    fn mock_store() {
+        let meta = MockLinearStore::new(0x10000, 0);
+        let data = MockLinearStore::new(0x10000, 1);
+        let mut trie =
+            Trie::<_, Bincode>::create(meta.clone(), data.clone(), &TrieConfig::builder().build())
+                .unwrap();
+        trie.insert(b"horse", b"stallion".to_vec()).unwrap();
+        trie.flush_dirty().unwrap();
+        assert!(data
+            .calls()
+            .iter()
+            .any(|call| matches!(call, StoreCall::Write { .. })));
+
+        // the header is written to the meta store, which fails
+        meta.fail_next_writes(1);
+        assert!(
+            Trie::<_, Bincode>::create(meta.clone(), data, &TrieConfig::builder().build()).is_err()
+        );
+
+        meta.clear_calls();
+        meta.hide(0x100..0x200);
+        assert!(meta.get_view(0xf0, 0x20).is_none());
+        assert!(meta.get_view(0x200, 0x20).is_some());
+        assert_eq!(
+            meta.calls(),
+            [
+                StoreCall::GetView {
+                    offset: 0xf0,
+                    length: 0x20
+                },
+                StoreCall::GetView {
+                    offset: 0x200,
+                    length: 0x20
+                },
+            ]
+        );
+
+        meta.set_read_only(true);
+        assert!(!meta.get_shared().is_writeable());
+    }
+
+    #[tokio::test]
+    async fn mock_db() {
+        let db = MockDb::default();
+        let empty = db.root_hash().await.unwrap();
+
+        let put: Batch<&[u8], &[u8]> = vec![BatchOp::Put {
+            key: b"a",
+            value: b"1",
+        }];
+        let first = Arc::new(db.propose(put).await.unwrap());
+        let moved: Batch<&[u8], &[u8]> = vec![BatchOp::Move {
+            key: b"a",
+            new_key: b"b",
+            overwrite: false,
+        }];
+        let second = Arc::new(first.clone().propose(moved).await.unwrap());
+        let root_hash = second.root_hash().await.unwrap();
+
+        // committing the second proposal commits the first one
+        second.commit().await.unwrap();
+        assert_eq!(db.root_hash().await.unwrap(), root_hash);
+        let revision = db.revision(root_hash).await.unwrap();
+        assert_eq!(revision.val(b"b").await.unwrap(), Some(b"1".to_vec()));
+        let entries: Vec<_> = revision.iter().unwrap().collect().await;
+        assert_eq!(entries.len(), 1);
+        assert!(db
+            .revision(empty)
+            .await
+            .unwrap()
+            .val(b"a")
+            .await
+            .unwrap()
+            .is_none());
+
+        // the first proposal is no longer on the latest revision
+        assert!(matches!(first.commit().await, Err(Error::InvalidProposal)));
+
+        db.clear_calls();
+        db.fail_next(DbMethod::Propose, Error::ReadOnly);
+        let delete: Batch<&[u8], &[u8]> = vec![BatchOp::DeletePrefix { prefix: b"" }];
+        assert!(matches!(
+            db.propose(delete.clone()).await,
+            Err(Error::ReadOnly)
+        ));
+        assert!(db.propose(delete).await.is_ok());
+        let call = DbCall::Propose {
+            base: root_hash,
+            batch: vec![BatchOp::DeletePrefix { prefix: vec![] }],
+        };
+        assert_eq!(db.calls(), [call.clone(), call]);
+    }
+}
@@ -1,11 +1,11 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
-use crate::merkle::MerkleError;
 pub use crate::merkle::Proof;
+use crate::merkle::{Bincode, MerkleError, ProofError};
 use async_trait::async_trait;
-use futures::Stream;
-use std::{fmt::Debug, sync::Arc};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use std::{collections::HashMap, fmt::Debug, pin::Pin, sync::Arc};
 
 /// A `KeyType` is something that can be xcast to a u8 reference,
 /// and can be sent and shared across threads. References with
@@ -20,9 +20,9 @@ impl<T> KeyType for T where T: AsRef<[u8]> + Send + Sync + Debug {}
 /// This also means that the type of all the keys for a single
 /// API call must be the same, as well as the type of all values
 /// must be the same.
-pub trait ValueType: AsRef<[u8]> + Send + Sync + Debug + 'static {}
+pub trait ValueType: AsRef<[u8]> + Send + Sync + Debug {}
 
-impl<T> ValueType for T where T: AsRef<[u8]> + Send + Sync + Debug + 'static {}
+impl<T> ValueType for T where T: AsRef<[u8]> + Send + Sync + Debug {}
 
 /// The type and size of a single hash key
 /// These are 256-bit hashes that are used for a variety of reasons:
@@ -32,12 +32,34 @@ impl<T> ValueType for T where T: AsRef<[u8]> + Send + Sync + Debug + 'static {}
 ///    proof
 pub type HashKey = [u8; 32];
 
-/// A key/value pair operation. Only put (upsert) and delete are
-/// supported
-#[derive(Debug)]
+/// A key/value pair operation. Keys can be put (upserted), deleted,
+/// or moved to a new key
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum BatchOp<K: KeyType, V: ValueType> {
-    Put { key: K, value: V },
-    Delete { key: K },
+    Put {
+        key: K,
+        value: V,
+    },
+    Delete {
+        key: K,
+    },
+    /// Move the value of `key` to `new_key`, like deleting `key` and
+    /// putting its value at `new_key`, but without the caller having to
+    /// read the value first. Fails if `key` doesn't exist, or if `new_key`
+    /// exists and `overwrite` is not set.
+    Move {
+        key: K,
+        new_key: K,
+        overwrite: bool,
+    },
+    /// Delete every key starting with `prefix`. The subtree holding those
+    /// keys is detached at once, rather than deleting its keys one by one,
+    /// and its nodes are freed by the batches that follow. The keys of the
+    /// subtree are only read if a secondary index is registered, and leave
+    /// the counts of the header as its nodes are freed.
+    DeletePrefix {
+        prefix: K,
+    },
 }
 
 /// A list of operations to consist of a batch that
@@ -84,6 +106,26 @@ pub enum Error {
 
     #[error("Range too small")]
     RangeTooSmall,
+
+    /// The destination of a [BatchOp::Move] exists
+    #[error("Key already exists: {key:?}")]
+    KeyExists { key: Vec<u8> },
+
+    /// A batch validator registered on the database rejected the batch
+    #[error("Batch rejected: {reason}")]
+    BatchRejected { reason: String },
+
+    /// The database was opened read-only
+    #[error("Database is read-only")]
+    ReadOnly,
+
+    /// The database was closed
+    #[error("Database is closed")]
+    Closed,
+
+    /// The view doesn't implement the operation
+    #[error("Unsupported operation: {0}")]
+    Unsupported(&'static str),
 }
 
 impl From<MerkleError> for Error {
@@ -95,16 +137,63 @@ impl From<MerkleError> for Error {
 
 /// A range proof, consisting of a proof of the first key and the last key,
 /// and a vector of all key/value pairs
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct RangeProof<K, V> {
     pub first_key_proof: Proof<Vec<u8>>,
     pub last_key_proof: Proof<Vec<u8>>,
     pub middle: Vec<(K, V)>,
 }
 
+impl<K, V> RangeProof<K, V> {
+    /// The range proof of the empty trie: no edge proofs and no keys, which
+    /// [Proof::verify_range_proof] checks against the
+    /// [EMPTY_ROOT_HASH](crate::merkle::EMPTY_ROOT_HASH).
+    pub fn empty() -> Self {
+        Self {
+            first_key_proof: Proof(HashMap::new()),
+            last_key_proof: Proof(HashMap::new()),
+            middle: Vec::new(),
+        }
+    }
+}
+
+impl<K: AsRef<[u8]>, V: AsRef<[u8]>> RangeProof<K, V> {
+    /// Verifies this proof against `root_hash` alone, without a database, see
+    /// [Proof::verify_range_proof]. The edges of the range are its first and last keys, which
+    /// [DbView::range_proof] proves. Returns whether the trie has keys after the range.
+    pub fn verify(&self, root_hash: HashKey) -> Result<bool, ProofError> {
+        let proof = Proof(
+            self.first_key_proof
+                .0
+                .iter()
+                .chain(&self.last_key_proof.0)
+                .map(|(hash, node)| (*hash, node))
+                .collect(),
+        );
+        let (keys, vals): (Vec<_>, Vec<_>) = self
+            .middle
+            .iter()
+            .map(|(key, val)| (key.as_ref(), val.as_ref()))
+            .unzip();
+
+        let edges = keys.first().zip(keys.last());
+        let (first_key, last_key) = match edges {
+            Some((first_key, last_key)) => (*first_key, *last_key),
+            // only the proof of the empty trie has no keys
+            None if proof.0.is_empty() => (&[][..], &[][..]),
+            None => return Err(ProofError::InvalidEdgeKeys),
+        };
+        proof.verify_range_proof::<_, _, Bincode>(root_hash, first_key, last_key, keys, vals)
+    }
+}
+
 /// The database interface, which includes a type for a static view of
 /// the database (the DbView). The most common implementation of the DbView
 /// is the api::DbView trait defined next.
+///
+/// A handle to a [Db] can both read and write. To keep the readers of a
+/// database from sharing the handle of its writer, split it into a
+/// [DbRead] and a [DbWrite] half, see [split](super::handles::split).
 #[async_trait]
 pub trait Db {
     type Historical: DbView;
@@ -128,13 +217,117 @@ pub trait Db {
     ///
     /// # Arguments
     ///
-    /// * `data` - A batch consisting of [BatchOp::Put] and
-    ///            [BatchOp::Delete] operations to apply
+    /// * `data` - A batch consisting of [BatchOp::Put], [BatchOp::Delete],
+    ///            [BatchOp::Move] and [BatchOp::DeletePrefix] operations to apply
     ///
     async fn propose<K: KeyType, V: ValueType>(
         &self,
         data: Batch<K, V>,
     ) -> Result<Self::Proposal, Error>;
+
+    /// Stream the key-value pairs of the most recently committed version in
+    /// lexicographic key order
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - If None, start at the lowest key
+    /// * `end` - If None, continue to the highest key, otherwise stop after
+    ///           `end`, or after the highest key before it
+    ///
+    /// The stream holds the revision it reads, so it reads the same version
+    /// whatever is committed while it is consumed.
+    async fn kv_iter<K: KeyType>(
+        &self,
+        start: Option<K>,
+        end: Option<K>,
+    ) -> Result<KeyValueStream, Error>
+    where
+        Self: Sync,
+        Self::Historical: 'static,
+    {
+        let view = self.revision(self.root_hash().await?).await?;
+        Ok(key_value_stream(
+            view,
+            start.map_or_else(Vec::new, |start| start.as_ref().to_vec()),
+            end.map(|end| end.as_ref().to_vec()),
+        ))
+    }
+}
+
+/// The key-value pairs of a revision in ascending key order, see [Db::kv_iter].
+pub type KeyValueStream = Pin<Box<dyn Stream<Item = Result<(Box<[u8]>, Vec<u8>), Error>>>>;
+
+/// Number of pairs a [KeyValueStream] reads from its revision at a time.
+const KV_ITER_PAGE_LEN: usize = 256;
+
+/// Streams the pairs of `view` from `start` up to `end` a page at a time, each page
+/// read by a stream of the view that is dropped once it is read, so that the
+/// stream owns the view it reads rather than borrowing it.
+fn key_value_stream<V: DbView + 'static>(
+    view: Arc<V>,
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+) -> KeyValueStream {
+    // the key the next page starts at, or None once the last page was read
+    let pages = stream::try_unfold(Some(start), move |start| {
+        let (view, end) = (view.clone(), end.clone());
+        async move {
+            let Some(start) = start else {
+                return Ok(None);
+            };
+            let pairs: Vec<_> = view
+                .iter_from(start)?
+                .take_while(|pair| {
+                    let in_range = match (pair, &end) {
+                        (Ok((key, _)), Some(end)) => **key <= **end,
+                        _ => true,
+                    };
+                    async move { in_range }
+                })
+                .take(KV_ITER_PAGE_LEN)
+                .try_collect()
+                .await?;
+            let next = match pairs.last() {
+                // the smallest key greater than the last one
+                Some((last, _)) if pairs.len() == KV_ITER_PAGE_LEN => {
+                    Some([last, &[0][..]].concat())
+                }
+                Some(_) => None,
+                None => return Ok(None),
+            };
+            Ok::<_, Error>(Some((stream::iter(pairs.into_iter().map(Ok)), next)))
+        }
+    });
+    Box::pin(pages.try_flatten())
+}
+
+/// The reading half of a database handle: it can be cloned and shared between
+/// threads, and reads the revisions of the database without going through
+/// its writer.
+#[async_trait]
+pub trait DbRead: Clone + Send + Sync {
+    type Historical: DbView;
+
+    /// Get a reference to a specific view based on a hash, see [Db::revision]
+    async fn revision(&self, hash: HashKey) -> Result<Arc<Self::Historical>, Error>;
+
+    /// Get the hash of the most recently committed version
+    async fn root_hash(&self) -> Result<HashKey, Error>;
+}
+
+/// The writing half of a database handle: it has a single owner, who needs
+/// exclusive access to it to propose a batch, see [Db::propose]. Proposals
+/// are committed through the [Proposal] trait.
+#[async_trait]
+pub trait DbWrite: Send {
+    type Proposal: DbView + Proposal;
+
+    /// Propose a change to the database via a batch, based off the most
+    /// recently committed transaction
+    async fn propose<K: KeyType, V: ValueType>(
+        &mut self,
+        data: Batch<K, V>,
+    ) -> Result<Self::Proposal, Error>;
 }
 
 /// A view of the database at a specific time. These are wrapped with
@@ -152,13 +345,26 @@ pub trait DbView {
     where
         Self: 'a;
 
-    /// Get the root hash for the current DbView
+    /// Get the root hash for the current DbView, the
+    /// [EMPTY_ROOT_HASH](crate::merkle::EMPTY_ROOT_HASH) if it has no keys
     async fn root_hash(&self) -> Result<HashKey, Error>;
 
     /// Get the value of a specific key
     async fn val<K: KeyType>(&self, key: K) -> Result<Option<Vec<u8>>, Error>;
 
-    /// Obtain a proof for a single key
+    /// Get the values of `keys`, in the order of `keys`, each with the result of its own read.
+    /// Views that can read many keys at once override this, which reads them one at a time.
+    async fn val_many<K: KeyType>(&self, keys: Vec<K>) -> Vec<Result<Option<Vec<u8>>, Error>> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(self.val(key).await);
+        }
+        values
+    }
+
+    /// Obtain a proof for a single key. The proof of any key of an empty view has no nodes,
+    /// and verifies to its absence against the
+    /// [EMPTY_ROOT_HASH](crate::merkle::EMPTY_ROOT_HASH).
     async fn single_key_proof<K: KeyType>(&self, key: K) -> Result<Option<Proof<Vec<u8>>>, Error>;
 
     /// Obtain a range proof over a set of keys
@@ -169,6 +375,7 @@ pub trait DbView {
     /// * `last_key` - If None, continue to the end of the database
     /// * `limit` - The maximum number of keys in the range proof
     ///
+    /// The range proof of an empty view is [RangeProof::empty], whatever the range.
     async fn range_proof<K: KeyType, V: Send + Sync>(
         &self,
         first_key: Option<K>,
@@ -176,6 +383,24 @@ pub trait DbView {
         limit: Option<usize>,
     ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, Error>;
 
+    /// Obtain a range proof over a set of keys, collected from the highest key
+    /// down. With a `limit`, the proof covers the highest keys of the range
+    /// rather than the lowest ones. The keys in `middle` are still in ascending
+    /// order, so the proof is verified like any other range proof.
+    ///
+    /// # Arguments
+    ///
+    /// * `first_key` - If None, continue to the lowest key
+    /// * `last_key` - If None, start at the highest key of the database
+    /// * `limit` - The maximum number of keys in the range proof
+    ///
+    async fn range_proof_rev<K: KeyType, V: Send + Sync>(
+        &self,
+        first_key: Option<K>,
+        last_key: Option<K>,
+        limit: Option<usize>,
+    ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, Error>;
+
     /// Obtain a stream over the keys/values of this view, using an optional starting point
     ///
     /// # Arguments
@@ -198,6 +423,28 @@ pub trait DbView {
     fn iter_from<K: KeyType + 'static>(&self, first_key: K) -> Result<Self::Stream<'_>, Error> {
         self.iter_option(Some(first_key))
     }
+
+    /// Obtain a stream over the keys/values of this view in descending key
+    /// order, using an optional starting point
+    ///
+    /// # Arguments
+    ///
+    /// * `last_key` - If None, start at the highest key. Otherwise, start at
+    ///                `last_key`, or at the highest key before it
+    ///
+    fn iter_rev_option<K: KeyType>(&self, last_key: Option<K>) -> Result<Self::Stream<'_>, Error>;
+
+    /// Obtain a stream over the keys/values of this view in descending key
+    /// order, starting from the end
+    fn iter_rev(&self) -> Result<Self::Stream<'_>, Error> {
+        self.iter_rev_option(Option::<Box<[u8]>>::None)
+    }
+
+    /// Obtain a stream over the key/values in descending key order, starting
+    /// at a specific key
+    fn iter_rev_from<K: KeyType + 'static>(&self, last_key: K) -> Result<Self::Stream<'_>, Error> {
+        self.iter_rev_option(Some(last_key))
+    }
 }
 
 /// A proposal for a new revision of the database.
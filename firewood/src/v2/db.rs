@@ -27,6 +27,19 @@ impl From<DbError> for api::Error {
             DbError::Shale(e) => api::Error::InternalError(Box::new(e)),
             DbError::IO(e) => api::Error::IO(e),
             DbError::InvalidProposal => api::Error::InvalidProposal,
+            DbError::CommitHook(e) => api::Error::InternalError(e),
+            DbError::KeyExists(key) => api::Error::KeyExists { key },
+            DbError::BatchRejected(reason) => api::Error::BatchRejected { reason },
+            DbError::AlreadyOpen { .. } => api::Error::InternalError(Box::new(value)),
+            DbError::ReadOnly => api::Error::ReadOnly,
+            DbError::UnknownIndex(_) => api::Error::InternalError(Box::new(value)),
+            DbError::RootMismatch { .. } => api::Error::InternalError(Box::new(value)),
+            DbError::Closed => api::Error::Closed,
+            DbError::CloseTimeout => api::Error::InternalError(Box::new(value)),
+            DbError::FreezeTimeout => api::Error::InternalError(Box::new(value)),
+            DbError::NoSpace { .. } => api::Error::InternalError(Box::new(value)),
+            DbError::Replication(e) => api::Error::InternalError(e),
+            DbError::RevisionNotFound(hash) => api::Error::HashNotFound { provided: hash.0 },
         }
     }
 }
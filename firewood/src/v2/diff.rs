@@ -0,0 +1,113 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use super::api::{self, DbView};
+use futures::{stream::Fuse, Stream, StreamExt};
+use std::{cmp::Ordering, pin::Pin};
+
+type KeyValue = (Box<[u8]>, Vec<u8>);
+
+/// A single difference between two views of the trie, see [diff].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyDiff {
+    /// The key is only in the new view
+    Added { key: Box<[u8]>, value: Vec<u8> },
+    /// The key is only in the old view
+    Removed { key: Box<[u8]>, value: Vec<u8> },
+    /// The key is in both views, with a different value
+    Changed {
+        key: Box<[u8]>,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+}
+
+impl KeyDiff {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            KeyDiff::Added { key, .. }
+            | KeyDiff::Removed { key, .. }
+            | KeyDiff::Changed { key, .. } => key,
+        }
+    }
+}
+
+/// Compare two views (typically two revisions) of the trie, returning a stream of the keys that
+/// differ between them in key order. Both views are walked in full, so this is proportional to
+/// the size of the trie rather than the size of the difference.
+pub fn diff<'a, O: DbView, N: DbView>(
+    old: &'a O,
+    new: &'a N,
+) -> Result<impl Stream<Item = Result<KeyDiff, api::Error>> + Unpin + 'a, api::Error> {
+    let state = DiffState {
+        old: Box::pin(old.iter()?.fuse()),
+        new: Box::pin(new.iter()?.fuse()),
+        old_head: None,
+        new_head: None,
+        failed: false,
+    };
+
+    Ok(Box::pin(futures::stream::unfold(
+        state,
+        |mut state| async move {
+            if state.failed {
+                return None;
+            }
+
+            let next = state.next_diff().await;
+            state.failed = next.is_err();
+            next.transpose().map(|next| (next, state))
+        },
+    )))
+}
+
+struct DiffState<O, N> {
+    old: Pin<Box<Fuse<O>>>,
+    new: Pin<Box<Fuse<N>>>,
+    old_head: Option<KeyValue>,
+    new_head: Option<KeyValue>,
+    failed: bool,
+}
+
+impl<O, N> DiffState<O, N>
+where
+    O: Stream<Item = Result<KeyValue, api::Error>>,
+    N: Stream<Item = Result<KeyValue, api::Error>>,
+{
+    async fn next_diff(&mut self) -> Result<Option<KeyDiff>, api::Error> {
+        loop {
+            if self.old_head.is_none() {
+                self.old_head = self.old.next().await.transpose()?;
+            }
+            if self.new_head.is_none() {
+                self.new_head = self.new.next().await.transpose()?;
+            }
+
+            let ordering = match (&self.old_head, &self.new_head) {
+                (None, None) => return Ok(None),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((old_key, _)), Some((new_key, _))) => old_key.cmp(new_key),
+            };
+
+            #[allow(clippy::unwrap_used)]
+            match ordering {
+                Ordering::Less => {
+                    let (key, value) = self.old_head.take().unwrap();
+                    return Ok(Some(KeyDiff::Removed { key, value }));
+                }
+                Ordering::Greater => {
+                    let (key, value) = self.new_head.take().unwrap();
+                    return Ok(Some(KeyDiff::Added { key, value }));
+                }
+                Ordering::Equal => {
+                    let (key, old) = self.old_head.take().unwrap();
+                    let (_, new) = self.new_head.take().unwrap();
+                    if old != new {
+                        return Ok(Some(KeyDiff::Changed { key, old, new }));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -5,10 +5,10 @@ use super::{
     api::{Batch, Db, DbView, Error, HashKey, KeyType, RangeProof, ValueType},
     propose::{Proposal, ProposalBase},
 };
-use crate::merkle::Proof;
+use crate::merkle::{Proof, EMPTY_ROOT_HASH};
 use async_trait::async_trait;
 use futures::Stream;
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 /// An EmptyDb is a simple implementation of api::Db
 /// that doesn't store any data. It contains a single
@@ -21,8 +21,8 @@ pub struct EmptyDb;
 #[derive(Debug)]
 pub struct HistoricalImpl;
 
-/// This is the hash of the [EmptyDb] root
-const ROOT_HASH: [u8; 32] = [0; 32];
+/// This is the hash of the [EmptyDb] root, the one of any empty trie
+const ROOT_HASH: [u8; 32] = EMPTY_ROOT_HASH.0;
 
 #[async_trait]
 impl Db for EmptyDb {
@@ -47,10 +47,7 @@ impl Db for EmptyDb {
         K: KeyType,
         V: ValueType,
     {
-        Ok(Proposal::new(
-            ProposalBase::View(HistoricalImpl.into()),
-            data,
-        ))
+        Proposal::new(ProposalBase::View(HistoricalImpl.into()), data).await
     }
 }
 
@@ -67,7 +64,7 @@ impl DbView for HistoricalImpl {
     }
 
     async fn single_key_proof<K: KeyType>(&self, _key: K) -> Result<Option<Proof<Vec<u8>>>, Error> {
-        Ok(None)
+        Ok(Some(Proof(HashMap::new())))
     }
 
     async fn range_proof<K: KeyType, V>(
@@ -76,12 +73,25 @@ impl DbView for HistoricalImpl {
         _last_key: Option<K>,
         _limit: Option<usize>,
     ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, Error> {
-        Ok(None)
+        Ok(Some(RangeProof::empty()))
+    }
+
+    async fn range_proof_rev<K: KeyType, V>(
+        &self,
+        _first_key: Option<K>,
+        _last_key: Option<K>,
+        _limit: Option<usize>,
+    ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, Error> {
+        Ok(Some(RangeProof::empty()))
     }
 
     fn iter_option<K: KeyType>(&self, _first_key: Option<K>) -> Result<EmptyStreamer, Error> {
         Ok(EmptyStreamer {})
     }
+
+    fn iter_rev_option<K: KeyType>(&self, _last_key: Option<K>) -> Result<EmptyStreamer, Error> {
+        Ok(EmptyStreamer {})
+    }
 }
 
 pub struct EmptyStreamer;
@@ -169,6 +179,56 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn move_in_proposal() -> Result<(), Error> {
+        let db = Arc::new(EmptyDb);
+
+        let batch = vec![
+            BatchOp::Put {
+                key: b"a",
+                value: b"1",
+            },
+            BatchOp::Put {
+                key: b"b",
+                value: b"2",
+            },
+            BatchOp::Move {
+                key: b"a",
+                new_key: b"c",
+                overwrite: false,
+            },
+        ];
+        let proposal = Arc::new(db.propose(batch).await?);
+        assert!(proposal.val(b"a").await?.is_none());
+        assert_eq!(proposal.val(b"c").await?.unwrap(), b"1");
+
+        // moves see the keys of the base proposals too
+        let overwrite = |overwrite| {
+            vec![BatchOp::<_, &[u8]>::Move {
+                key: b"b",
+                new_key: b"c",
+                overwrite,
+            }]
+        };
+        assert!(matches!(
+            proposal.clone().propose(overwrite(false)).await,
+            Err(Error::KeyExists { key }) if key == b"c"
+        ));
+        let proposal = proposal.propose(overwrite(true)).await?;
+        assert!(proposal.val(b"b").await?.is_none());
+        assert_eq!(proposal.val(b"c").await?.unwrap(), b"2");
+
+        // the key to move must exist
+        let batch = vec![BatchOp::<_, &[u8]>::Move {
+            key: b"z",
+            new_key: b"y",
+            overwrite: true,
+        }];
+        assert!(db.propose(batch).await.is_err());
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn empty_streamer() -> Result<(), Error> {
         let emptydb = EmptyDb {};
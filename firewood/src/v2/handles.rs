@@ -0,0 +1,117 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Split handles to an [api::Db], so that who reads and who writes the database is checked by
+//! the compiler.
+//!
+//! [split] turns a database into a [DbReader], which can be cloned and handed to any number of
+//! threads, and a single [DbWriter]. Only the writer can propose batches, and it needs exclusive
+//! access to do so, so the batches of a database are proposed by one owner at a time, while the
+//! readers read the revisions of the database without waiting on it.
+
+use super::api::{self, Batch, DbRead, DbWrite, HashKey, KeyType, ValueType};
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Splits `db` into its reading and writing halves. The database is closed once both halves,
+/// and every clone of the reader, are dropped.
+pub fn split<D: api::Db>(db: D) -> (DbReader<D>, DbWriter<D>) {
+    let db = Arc::new(db);
+    (DbReader(db.clone()), DbWriter(db))
+}
+
+/// The reading half of a database split by [split].
+#[derive(Debug)]
+pub struct DbReader<D>(Arc<D>);
+
+impl<D> Clone for DbReader<D> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+#[async_trait]
+impl<D> DbRead for DbReader<D>
+where
+    D: api::Db + Send + Sync,
+    D::Historical: Send + Sync,
+{
+    type Historical = D::Historical;
+
+    async fn revision(&self, hash: HashKey) -> Result<Arc<Self::Historical>, api::Error> {
+        self.0.revision(hash).await
+    }
+
+    async fn root_hash(&self) -> Result<HashKey, api::Error> {
+        self.0.root_hash().await
+    }
+}
+
+/// The writing half of a database split by [split]. There is only one, it can't be cloned.
+#[derive(Debug)]
+pub struct DbWriter<D>(Arc<D>);
+
+impl<D> DbWriter<D> {
+    /// Another reader of the database, for the writer to read the revisions it commits.
+    pub fn reader(&self) -> DbReader<D> {
+        DbReader(self.0.clone())
+    }
+}
+
+#[async_trait]
+impl<D> DbWrite for DbWriter<D>
+where
+    D: api::Db + Send + Sync,
+    D::Proposal: Send,
+{
+    type Proposal = D::Proposal;
+
+    async fn propose<K: KeyType, V: ValueType>(
+        &mut self,
+        data: Batch<K, V>,
+    ) -> Result<Self::Proposal, api::Error> {
+        self.0.propose(data).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::v2::{
+        api::{BatchOp, DbView},
+        emptydb::EmptyDb,
+    };
+
+    #[tokio::test]
+    async fn reader_and_writer() {
+        let (reader, mut writer) = split(EmptyDb);
+        let root_hash = reader.root_hash().await.unwrap();
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let reader = reader.clone();
+                tokio::spawn(async move { reader.revision(root_hash).await.map(|_| ()) })
+            })
+            .collect();
+        for read in readers {
+            read.await.unwrap().unwrap();
+        }
+
+        let proposal = writer
+            .propose(vec![BatchOp::Put {
+                key: b"k",
+                value: b"v",
+            }])
+            .await
+            .unwrap();
+        assert_eq!(
+            proposal.val(b"k").await.unwrap().as_deref(),
+            Some(&b"v"[..])
+        );
+        assert_eq!(
+            writer.reader().root_hash().await.unwrap(),
+            reader.root_hash().await.unwrap()
+        );
+    }
+}
@@ -3,7 +3,12 @@
 
 pub mod api;
 pub mod db;
+pub mod diff;
+pub mod handles;
+pub mod namespace;
 pub mod propose;
+pub mod shadow;
+pub mod standby;
 
 // #[cfg(test)]
 pub mod emptydb;
@@ -0,0 +1,435 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use super::api::{self, Batch, BatchOp, HashKey, KeyType, RangeProof, ValueType};
+use crate::{
+    merkle::{Bincode, Proof},
+    merkle_util::InMemoryMerkle,
+};
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use std::{
+    ops::Deref,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// A [NamespacedDb] wraps a handle to an [api::Db] (such as `&Db` or
+/// `Arc<Db>`) and transparently prefixes every key with a fixed namespace
+/// prefix, so multiple logical datasets can share the same trie without seeing
+/// each other's keys.
+///
+/// Keys passed to and returned from a namespaced view are relative to the
+/// namespace. Hashes and proofs are not: [api::DbView::root_hash] is still the
+/// root hash of the whole trie (so it can be passed to [api::Db::revision]),
+/// and proofs are generated for the full, prefixed keys. Use
+/// [NamespacedView::namespace_root_hash] to get a root hash covering only the
+/// namespace.
+#[derive(Debug)]
+pub struct NamespacedDb<D> {
+    db: D,
+    prefix: Arc<[u8]>,
+}
+
+impl<D> NamespacedDb<D> {
+    pub fn new<P: AsRef<[u8]>>(db: D, prefix: P) -> Self {
+        Self {
+            db,
+            prefix: prefix.as_ref().into(),
+        }
+    }
+
+    /// The prefix prepended to every key of this namespace
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Consume the wrapper, returning the underlying database
+    pub fn into_inner(self) -> D {
+        self.db
+    }
+}
+
+#[async_trait]
+impl<D> api::Db for NamespacedDb<D>
+where
+    D: Deref + Send + Sync,
+    D::Target: api::Db + Send + Sync,
+    <D::Target as api::Db>::Historical: Send + Sync,
+{
+    type Historical = NamespacedView<<D::Target as api::Db>::Historical>;
+
+    type Proposal = NamespacedView<<D::Target as api::Db>::Proposal>;
+
+    async fn revision(&self, hash: HashKey) -> Result<Arc<Self::Historical>, api::Error> {
+        let view = self.db.revision(hash).await?;
+        Ok(Arc::new(NamespacedView::new(view, self.prefix.clone())))
+    }
+
+    async fn root_hash(&self) -> Result<HashKey, api::Error> {
+        self.db.root_hash().await
+    }
+
+    async fn propose<K: KeyType, V: ValueType>(
+        &self,
+        data: Batch<K, V>,
+    ) -> Result<Self::Proposal, api::Error> {
+        let proposal = self.db.propose(prefix_batch(&self.prefix, data)).await?;
+        Ok(NamespacedView::new(Arc::new(proposal), self.prefix.clone()))
+    }
+}
+
+/// A view (either a historical revision or a proposal) restricted to a
+/// single namespace. See [NamespacedDb].
+#[derive(Debug)]
+pub struct NamespacedView<V> {
+    view: Arc<V>,
+    prefix: Arc<[u8]>,
+}
+
+impl<V> NamespacedView<V> {
+    const fn new(view: Arc<V>, prefix: Arc<[u8]>) -> Self {
+        Self { view, prefix }
+    }
+
+    /// The prefix prepended to every key of this namespace
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    fn full_key<K: AsRef<[u8]>>(&self, key: K) -> Vec<u8> {
+        prefixed(&self.prefix, key)
+    }
+}
+
+impl<V: api::DbView + Send + Sync> NamespacedView<V> {
+    /// Compute the root hash of a trie holding only the (namespace-relative)
+    /// keys of this namespace. This is the root hash the namespace would have
+    /// if it were stored in a database of its own.
+    ///
+    /// This walks every key in the namespace, so it is proportional to the
+    /// size of the namespace.
+    pub async fn namespace_root_hash(&self) -> Result<HashKey, api::Error> {
+        let mut merkle = InMemoryMerkle::<Bincode>::new(0x10000, 0x10000);
+        let mut stream = api::DbView::iter(self)?;
+
+        while let Some((key, value)) = stream.next().await.transpose()? {
+            merkle
+                .insert(key, value)
+                .map_err(|e| api::Error::InternalError(Box::new(e)))?;
+        }
+
+        merkle
+            .root_hash()
+            .map(|hash| *hash)
+            .map_err(|e| api::Error::InternalError(Box::new(e)))
+    }
+}
+
+#[async_trait]
+impl<V: api::DbView + Send + Sync> api::DbView for NamespacedView<V> {
+    type Stream<'a>
+        = NamespacedStream<V::Stream<'a>>
+    where
+        Self: 'a;
+
+    async fn root_hash(&self) -> Result<HashKey, api::Error> {
+        self.view.root_hash().await
+    }
+
+    async fn val<K: KeyType>(&self, key: K) -> Result<Option<Vec<u8>>, api::Error> {
+        self.view.val(self.full_key(key)).await
+    }
+
+    async fn single_key_proof<K: KeyType>(
+        &self,
+        key: K,
+    ) -> Result<Option<Proof<Vec<u8>>>, api::Error> {
+        self.view.single_key_proof(self.full_key(key)).await
+    }
+
+    /// The returned range never extends past the namespace. The keys in
+    /// `middle` are relative to the namespace, while the edge proofs are for
+    /// the full, prefixed keys.
+    async fn range_proof<K: KeyType, VT: Send + Sync>(
+        &self,
+        first_key: Option<K>,
+        last_key: Option<K>,
+        limit: Option<usize>,
+    ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, api::Error> {
+        let first_key = match first_key {
+            Some(key) => self.full_key(key),
+            None => self.prefix.to_vec(),
+        };
+        // Without a last key, stop at the first key after the namespace, which
+        // is dropped below. If there is no such key, the namespace runs to the
+        // end of the trie.
+        let last_key = match last_key {
+            Some(key) => Some(self.full_key(key)),
+            None => prefix_successor(&self.prefix),
+        };
+
+        let proof = self
+            .view
+            .range_proof::<Vec<u8>, VT>(Some(first_key), last_key, limit)
+            .await?;
+
+        self.strip_range_proof(proof).await
+    }
+
+    /// Like [NamespacedView::range_proof], collecting the keys from the end
+    /// of the range.
+    async fn range_proof_rev<K: KeyType, VT: Send + Sync>(
+        &self,
+        first_key: Option<K>,
+        last_key: Option<K>,
+        limit: Option<usize>,
+    ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, api::Error> {
+        let first_key = match first_key {
+            Some(key) => self.full_key(key),
+            None => self.prefix.to_vec(),
+        };
+        // Without a last key, start at the first key after the namespace,
+        // which is dropped below.
+        let last_key = match last_key {
+            Some(key) => Some(self.full_key(key)),
+            None => prefix_successor(&self.prefix),
+        };
+
+        let proof = self
+            .view
+            .range_proof_rev::<Vec<u8>, VT>(Some(first_key), last_key, limit)
+            .await?;
+
+        self.strip_range_proof(proof).await
+    }
+
+    fn iter_option<K: KeyType>(
+        &self,
+        first_key: Option<K>,
+    ) -> Result<Self::Stream<'_>, api::Error> {
+        let first_key = match first_key {
+            Some(key) => self.full_key(key),
+            None => self.prefix.to_vec(),
+        };
+        let inner = self.view.iter_option(Some(first_key))?;
+
+        Ok(NamespacedStream {
+            inner: Box::pin(inner),
+            prefix: self.prefix.clone(),
+            reverse: false,
+            done: false,
+        })
+    }
+
+    fn iter_rev_option<K: KeyType>(
+        &self,
+        last_key: Option<K>,
+    ) -> Result<Self::Stream<'_>, api::Error> {
+        // Without a last key, start at the first key after the namespace,
+        // which the stream skips.
+        let last_key = match last_key {
+            Some(key) => Some(self.full_key(key)),
+            None => prefix_successor(&self.prefix),
+        };
+        let inner = self.view.iter_rev_option(last_key)?;
+
+        Ok(NamespacedStream {
+            inner: Box::pin(inner),
+            prefix: self.prefix.clone(),
+            reverse: true,
+            done: false,
+        })
+    }
+}
+
+impl<V: api::DbView + Send + Sync> NamespacedView<V> {
+    /// Drops the key after the namespace that the range proofs are bounded
+    /// by, if it was included, and makes the keys relative to the namespace.
+    async fn strip_range_proof(
+        &self,
+        proof: Option<RangeProof<Vec<u8>, Vec<u8>>>,
+    ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, api::Error> {
+        let Some(mut proof) = proof else {
+            return Ok(None);
+        };
+
+        if proof
+            .middle
+            .last()
+            .is_some_and(|(key, _)| !key.starts_with(&self.prefix))
+        {
+            proof.middle.pop();
+
+            let Some((last_key, _)) = proof.middle.last() else {
+                return Ok(None);
+            };
+
+            proof.last_key_proof = self
+                .view
+                .single_key_proof(last_key.as_slice())
+                .await?
+                .ok_or(api::Error::RangeTooSmall)?;
+        }
+
+        for (key, _) in proof.middle.iter_mut() {
+            key.drain(..self.prefix.len());
+        }
+
+        Ok(Some(proof))
+    }
+}
+
+#[async_trait]
+impl<P: api::Proposal> api::Proposal for NamespacedView<P> {
+    type Proposal = NamespacedView<P::Proposal>;
+
+    async fn commit(self: Arc<Self>) -> Result<(), api::Error> {
+        // The underlying proposal can only be committed through its last handle
+        let this = Arc::into_inner(self).ok_or(api::Error::InvalidProposal)?;
+        this.view.commit().await
+    }
+
+    async fn propose<K: KeyType, V: ValueType>(
+        self: Arc<Self>,
+        data: Batch<K, V>,
+    ) -> Result<Self::Proposal, api::Error> {
+        let proposal = self
+            .view
+            .clone()
+            .propose(prefix_batch(&self.prefix, data))
+            .await?;
+        Ok(NamespacedView::new(Arc::new(proposal), self.prefix.clone()))
+    }
+}
+
+/// A stream over the key/value pairs of a namespace. Keys are returned
+/// without the namespace prefix and the stream ends at the first key outside
+/// of the namespace.
+pub struct NamespacedStream<S> {
+    inner: Pin<Box<S>>,
+    prefix: Arc<[u8]>,
+    // In descending order, a stream starts at or after the end of the
+    // namespace, so keys outside of it come before the namespace too.
+    reverse: bool,
+    done: bool,
+}
+
+impl<S> Stream for NamespacedStream<S>
+where
+    S: Stream<Item = Result<(Box<[u8]>, Vec<u8>), api::Error>>,
+{
+    type Item = Result<(Box<[u8]>, Vec<u8>), api::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+
+        loop {
+            return match self.inner.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok((key, value)))) => match key.strip_prefix(&*self.prefix) {
+                    Some(key) => Poll::Ready(Some(Ok((key.into(), value)))),
+                    // not in the namespace yet
+                    None if self.reverse && *key > *self.prefix => continue,
+                    None => {
+                        // keys are sorted, so nothing after this can be in the namespace
+                        self.done = true;
+                        Poll::Ready(None)
+                    }
+                },
+                Poll::Ready(None) => {
+                    self.done = true;
+                    Poll::Ready(None)
+                }
+                other => other,
+            };
+        }
+    }
+}
+
+fn prefixed<K: AsRef<[u8]>>(prefix: &[u8], key: K) -> Vec<u8> {
+    let mut full_key = Vec::with_capacity(prefix.len() + key.as_ref().len());
+    full_key.extend_from_slice(prefix);
+    full_key.extend_from_slice(key.as_ref());
+    full_key
+}
+
+fn prefix_batch<K: KeyType, V: ValueType>(prefix: &[u8], data: Batch<K, V>) -> Batch<Vec<u8>, V> {
+    data.into_iter()
+        .map(|op| match op {
+            BatchOp::Put { key, value } => BatchOp::Put {
+                key: prefixed(prefix, key),
+                value,
+            },
+            BatchOp::Delete { key } => BatchOp::Delete {
+                key: prefixed(prefix, key),
+            },
+            BatchOp::Move {
+                key,
+                new_key,
+                overwrite,
+            } => BatchOp::Move {
+                key: prefixed(prefix, key),
+                new_key: prefixed(prefix, new_key),
+                overwrite,
+            },
+            BatchOp::DeletePrefix { prefix: key } => BatchOp::DeletePrefix {
+                prefix: prefixed(prefix, key),
+            },
+        })
+        .collect()
+}
+
+/// Returns the smallest key that is greater than every key starting with
+/// `prefix`, or None if there is no such key (the prefix is empty or all 0xff).
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last_incrementable = prefix.iter().rposition(|&byte| byte != u8::MAX)?;
+
+    #[allow(clippy::indexing_slicing)]
+    let mut successor = prefix[..=last_incrementable].to_vec();
+    #[allow(clippy::indexing_slicing)]
+    (successor[last_incrementable] += 1);
+
+    Some(successor)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(b"", None; "empty prefix")]
+    #[test_case(b"\xff\xff", None; "all max bytes")]
+    #[test_case(b"ab", Some(&b"ac"[..]); "last byte incremented")]
+    #[test_case(b"a\xff", Some(&b"b"[..]); "trailing max bytes dropped")]
+    fn successor(prefix: &[u8], expected: Option<&[u8]>) {
+        assert_eq!(prefix_successor(prefix).as_deref(), expected);
+    }
+
+    #[test]
+    fn reverse_stream_skips_keys_after_namespace() {
+        // in descending order, many keys after the namespace come before it
+        let keys = (0..100_000u32)
+            .rev()
+            .map(|i| [b"b/".as_slice(), &i.to_be_bytes()].concat())
+            .chain([b"a/2".to_vec(), b"a/1".to_vec(), b"0".to_vec()]);
+        let inner = futures::stream::iter(keys.map(|key| Ok((key.into_boxed_slice(), Vec::new()))));
+        let stream = NamespacedStream {
+            inner: Box::pin(inner),
+            prefix: b"a/".as_slice().into(),
+            reverse: true,
+            done: false,
+        };
+
+        let keys: Vec<_> = futures::executor::block_on(
+            stream.map(|kv| kv.map(|(key, _)| key)).collect::<Vec<_>>(),
+        )
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .unwrap();
+        assert_eq!(keys, [b"2".as_slice().into(), b"1".as_slice().into()]);
+    }
+}
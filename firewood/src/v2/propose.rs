@@ -6,7 +6,7 @@ use std::{collections::BTreeMap, fmt::Debug, sync::Arc};
 use async_trait::async_trait;
 use futures::stream::Empty;
 
-use crate::{merkle::Proof, v2::api};
+use crate::{db::DbError, merkle::Proof, v2::api};
 
 use super::api::{KeyType, ValueType};
 
@@ -68,6 +68,8 @@ impl<T: api::DbView> Clone for ProposalBase<T> {
 pub struct Proposal<T> {
     pub(crate) base: ProposalBase<T>,
     pub(crate) delta: BTreeMap<Vec<u8>, KeyOp<Vec<u8>>>,
+    /// The prefixes deleted from the base, the keys of `delta` excepted.
+    pub(crate) deleted_prefixes: Vec<Vec<u8>>,
 }
 
 // Implement Clone because T doesn't need to be Clone
@@ -77,33 +79,76 @@ impl<T: api::DbView> Clone for Proposal<T> {
         Self {
             base: self.base.clone(),
             delta: self.delta.clone(),
+            deleted_prefixes: self.deleted_prefixes.clone(),
         }
     }
 }
 
-impl<T> Proposal<T> {
-    pub(crate) fn new<K: KeyType, V: ValueType>(
+impl<T: api::DbView + Send + Sync> Proposal<T> {
+    pub(crate) async fn new<K: KeyType, V: ValueType>(
         base: ProposalBase<T>,
         batch: api::Batch<K, V>,
-    ) -> Self {
-        let delta = batch
-            .into_iter()
-            .map(|op| match op {
+    ) -> Result<Self, api::Error> {
+        let mut proposal = Self {
+            base,
+            delta: BTreeMap::new(),
+            deleted_prefixes: Vec::new(),
+        };
+
+        for op in batch {
+            match op {
                 api::BatchOp::Put { key, value } => {
-                    (key.as_ref().to_vec(), KeyOp::Put(value.as_ref().to_vec()))
+                    proposal
+                        .delta
+                        .insert(key.as_ref().to_vec(), KeyOp::Put(value.as_ref().to_vec()));
+                }
+                api::BatchOp::Delete { key } => {
+                    proposal.delta.insert(key.as_ref().to_vec(), KeyOp::Delete);
+                }
+                api::BatchOp::Move {
+                    key,
+                    new_key,
+                    overwrite,
+                } => {
+                    // moves depend on the keys before them, so they are resolved right away
+                    let value = api::DbView::val(&proposal, key.as_ref())
+                        .await?
+                        .ok_or(DbError::KeyNotFound)?;
+                    if key.as_ref() == new_key.as_ref() {
+                        continue;
+                    }
+                    if !overwrite
+                        && api::DbView::val(&proposal, new_key.as_ref())
+                            .await?
+                            .is_some()
+                    {
+                        return Err(DbError::KeyExists(new_key.as_ref().to_vec()).into());
+                    }
+
+                    proposal.delta.insert(key.as_ref().to_vec(), KeyOp::Delete);
+                    proposal
+                        .delta
+                        .insert(new_key.as_ref().to_vec(), KeyOp::Put(value));
                 }
-                api::BatchOp::Delete { key } => (key.as_ref().to_vec(), KeyOp::Delete),
-            })
-            .collect();
+                api::BatchOp::DeletePrefix { prefix } => {
+                    let prefix = prefix.as_ref();
+                    proposal.delta.retain(|key, _| !key.starts_with(prefix));
+                    proposal.deleted_prefixes.push(prefix.to_vec());
+                }
+            }
+        }
 
-        Self { base, delta }
+        Ok(proposal)
     }
 }
 
 #[async_trait]
 impl<T: api::DbView + Send + Sync> api::DbView for Proposal<T> {
     // TODO: Replace with the correct stream type for an in-memory proposal implementation
-    type Stream<'a> = Empty<Result<(Box<[u8]>, Vec<u8>), api::Error>> where T: 'a;
+    type Stream<'a>
+        = Empty<Result<(Box<[u8]>, Vec<u8>), api::Error>>
+    where
+        T: 'a;
 
     async fn root_hash(&self) -> Result<api::HashKey, api::Error> {
         todo!();
@@ -117,6 +162,13 @@ impl<T: api::DbView + Send + Sync> api::DbView for Proposal<T> {
                 KeyOp::Put(val) => Ok(Some(val.to_owned())),
                 KeyOp::Delete => Ok(None), // key was deleted in this proposal
             },
+            None if self
+                .deleted_prefixes
+                .iter()
+                .any(|prefix| key.as_ref().starts_with(prefix)) =>
+            {
+                Ok(None)
+            }
             None => match &self.base {
                 // key not in this proposal, so delegate to base
                 ProposalBase::Proposal(p) => p.val(key).await,
@@ -141,12 +193,29 @@ impl<T: api::DbView + Send + Sync> api::DbView for Proposal<T> {
         todo!();
     }
 
+    async fn range_proof_rev<KT: KeyType, VT>(
+        &self,
+        _first_key: Option<KT>,
+        _last_key: Option<KT>,
+        _limit: Option<usize>,
+    ) -> Result<Option<api::RangeProof<Vec<u8>, Vec<u8>>>, api::Error> {
+        // an in-memory proposal has no trie to prove its keys with
+        Err(api::Error::Unsupported("range_proof_rev"))
+    }
+
     fn iter_option<K: KeyType>(
         &self,
         _first_key: Option<K>,
     ) -> Result<Self::Stream<'_>, api::Error> {
         todo!();
     }
+
+    fn iter_rev_option<K: KeyType>(
+        &self,
+        _last_key: Option<K>,
+    ) -> Result<Self::Stream<'_>, api::Error> {
+        Err(api::Error::Unsupported("iter_rev_option"))
+    }
 }
 
 #[async_trait]
@@ -158,7 +227,7 @@ impl<T: api::DbView + Send + Sync> api::Proposal for Proposal<T> {
         data: api::Batch<K, V>,
     ) -> Result<Self::Proposal, api::Error> {
         // find the Arc for this base proposal from the parent
-        Ok(Proposal::new(ProposalBase::Proposal(self), data))
+        Proposal::new(ProposalBase::Proposal(self), data).await
     }
 
     async fn commit(self: Arc<Self>) -> Result<(), api::Error> {
@@ -176,12 +245,17 @@ impl<T: api::DbView> std::ops::Add for Proposal<T> {
 
     fn add(self, rhs: Self) -> Self::Output {
         let mut delta = self.delta.clone();
+        delta.retain(|key, _| !rhs.deleted_prefixes.iter().any(|p| key.starts_with(p)));
 
         delta.extend(rhs.delta);
 
+        let mut deleted_prefixes = self.deleted_prefixes;
+        deleted_prefixes.extend(rhs.deleted_prefixes);
+
         let proposal = Proposal {
             base: self.base,
             delta,
+            deleted_prefixes,
         };
 
         Arc::new(proposal)
@@ -193,12 +267,17 @@ impl<T: api::DbView> std::ops::Add for &Proposal<T> {
 
     fn add(self, rhs: Self) -> Self::Output {
         let mut delta = self.delta.clone();
+        delta.retain(|key, _| !rhs.deleted_prefixes.iter().any(|p| key.starts_with(p)));
 
         delta.extend(rhs.delta.clone());
 
+        let mut deleted_prefixes = self.deleted_prefixes.clone();
+        deleted_prefixes.extend(rhs.deleted_prefixes.iter().cloned());
+
         let proposal = Proposal {
             base: self.base.clone(),
             delta,
+            deleted_prefixes,
         };
 
         Arc::new(proposal)
@@ -0,0 +1,546 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! A database that shadows the reads of one [api::Db] on another, to validate a new storage
+//! backend against the one in use with real traffic.
+//!
+//! [ShadowDb] answers every read from its primary database, and sends the same read to the
+//! secondary one. The two answers are compared, and a divergence is logged with the read, its
+//! arguments and both answers, and counted. Batches are proposed and committed on both, so
+//! that the secondary database holds the same revisions as the primary. The secondary one can
+//! only ever make the shadow database slower: its errors, and its answers, are never returned.
+//!
+//! Once the secondary database fails to follow the primary one, by missing a revision or
+//! failing to propose a batch, the views and proposals on top of it are no longer shadowed.
+//! Two errors are taken to agree, whatever they are.
+
+use super::api::{self, Batch, BatchOp, Error, HashKey, KeyType, Proof, RangeProof, ValueType};
+use crate::logger::warn;
+use async_trait::async_trait;
+use futures::Stream;
+use parking_lot::Mutex;
+use std::{
+    fmt::Debug,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+#[derive(Debug, Default)]
+struct Divergences {
+    count: AtomicU64,
+    last: Mutex<Option<String>>,
+}
+
+/// The divergences between the two databases of a [ShadowDb], shared by its views and
+/// proposals.
+#[derive(Debug, Clone, Default)]
+struct Tracker(Arc<Divergences>);
+
+impl Tracker {
+    /// Compares the answers of the two databases to `read`, where both answered.
+    fn check<T: PartialEq + Debug, E: Debug>(
+        &self,
+        read: &str,
+        args: &dyn Debug,
+        primary: &Result<T, E>,
+        secondary: &Result<T, E>,
+    ) -> bool {
+        let agree = match (primary, secondary) {
+            (Ok(primary), Ok(secondary)) => primary == secondary,
+            (Err(_), Err(_)) => true,
+            _ => false,
+        };
+        if !agree {
+            self.diverged(format!(
+                "{read}({args:?}): primary returned {primary:?}, secondary returned {secondary:?}"
+            ));
+        }
+        agree
+    }
+
+    fn diverged(&self, message: String) {
+        warn!("shadow read diverged: {message}");
+        self.0.count.fetch_add(1, Ordering::Relaxed);
+        *self.0.last.lock() = Some(message);
+    }
+}
+
+/// The batch `data`, with owned keys and values, to propose it to both databases.
+fn owned_batch<K: KeyType, V: ValueType>(data: &Batch<K, V>) -> Batch<Box<[u8]>, Vec<u8>> {
+    data.iter()
+        .map(|op| match op {
+            BatchOp::Put { key, value } => BatchOp::Put {
+                key: key.as_ref().into(),
+                value: value.as_ref().to_vec(),
+            },
+            BatchOp::Delete { key } => BatchOp::Delete {
+                key: key.as_ref().into(),
+            },
+            BatchOp::Move {
+                key,
+                new_key,
+                overwrite,
+            } => BatchOp::Move {
+                key: key.as_ref().into(),
+                new_key: new_key.as_ref().into(),
+                overwrite: *overwrite,
+            },
+            BatchOp::DeletePrefix { prefix } => BatchOp::DeletePrefix {
+                prefix: prefix.as_ref().into(),
+            },
+        })
+        .collect()
+}
+
+/// A database answering from `primary`, whose reads are compared with the ones of `secondary`,
+/// see the [module documentation](self).
+#[derive(Debug)]
+pub struct ShadowDb<P, S> {
+    primary: P,
+    secondary: S,
+    tracker: Tracker,
+}
+
+impl<P, S> ShadowDb<P, S> {
+    pub fn new(primary: P, secondary: S) -> Self {
+        Self {
+            primary,
+            secondary,
+            tracker: Tracker::default(),
+        }
+    }
+
+    /// Number of reads the two databases answered differently.
+    pub fn divergences(&self) -> u64 {
+        self.tracker.0.count.load(Ordering::Relaxed)
+    }
+
+    /// The last divergence, as logged.
+    pub fn last_divergence(&self) -> Option<String> {
+        self.tracker.0.last.lock().clone()
+    }
+
+    pub const fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    pub const fn secondary(&self) -> &S {
+        &self.secondary
+    }
+
+    /// Stops shadowing, returning both databases.
+    pub fn into_inner(self) -> (P, S) {
+        (self.primary, self.secondary)
+    }
+}
+
+#[async_trait]
+impl<P, S> api::Db for ShadowDb<P, S>
+where
+    P: api::Db + Send + Sync,
+    S: api::Db + Send + Sync,
+    P::Historical: Send + Sync,
+    S::Historical: Send + Sync,
+    P::Proposal: Send + Sync,
+    S::Proposal: Send + Sync,
+{
+    type Historical = Shadow<P::Historical, S::Historical>;
+
+    type Proposal = Shadow<P::Proposal, S::Proposal>;
+
+    async fn revision(&self, hash: HashKey) -> Result<Arc<Self::Historical>, Error> {
+        let primary = self.primary.revision(hash).await?;
+        let secondary = match self.secondary.revision(hash).await {
+            Ok(secondary) => Some(secondary),
+            Err(e) => {
+                self.tracker.diverged(format!(
+                    "revision({hash:?}): primary found it, secondary returned {e:?}"
+                ));
+                None
+            }
+        };
+        Ok(Arc::new(Shadow {
+            primary,
+            secondary,
+            tracker: self.tracker.clone(),
+        }))
+    }
+
+    async fn root_hash(&self) -> Result<HashKey, Error> {
+        let primary = self.primary.root_hash().await;
+        let secondary = self.secondary.root_hash().await;
+        self.tracker.check("root_hash", &(), &primary, &secondary);
+        primary
+    }
+
+    async fn propose<K: KeyType, V: ValueType>(
+        &self,
+        data: Batch<K, V>,
+    ) -> Result<Self::Proposal, Error> {
+        let data = owned_batch(&data);
+        let secondary = self.secondary.propose(data.clone()).await;
+        let primary = self.primary.propose(data).await?;
+        let secondary = match secondary {
+            Ok(secondary) => Some(Arc::new(secondary)),
+            Err(e) => {
+                self.tracker.diverged(format!(
+                    "propose: primary proposed the batch, secondary returned {e:?}"
+                ));
+                None
+            }
+        };
+        Ok(Shadow {
+            primary: Arc::new(primary),
+            secondary,
+            tracker: self.tracker.clone(),
+        })
+    }
+}
+
+/// A view or a proposal of a [ShadowDb]: a view or proposal of the primary database, and the
+/// one of the secondary database it is shadowed on, if any.
+#[derive(Debug)]
+pub struct Shadow<A, B> {
+    primary: Arc<A>,
+    secondary: Option<Arc<B>>,
+    tracker: Tracker,
+}
+
+#[async_trait]
+impl<A, B> api::DbView for Shadow<A, B>
+where
+    A: api::DbView + Send + Sync,
+    B: api::DbView + Send + Sync,
+{
+    type Stream<'a>
+        = ShadowStream<A::Stream<'a>, B::Stream<'a>>
+    where
+        Self: 'a;
+
+    async fn root_hash(&self) -> Result<HashKey, Error> {
+        let primary = self.primary.root_hash().await;
+        if let Some(secondary) = &self.secondary {
+            let secondary = secondary.root_hash().await;
+            self.tracker.check("root_hash", &(), &primary, &secondary);
+        }
+        primary
+    }
+
+    async fn val<K: KeyType>(&self, key: K) -> Result<Option<Vec<u8>>, Error> {
+        let key = key.as_ref();
+        let primary = self.primary.val(key).await;
+        if let Some(secondary) = &self.secondary {
+            let secondary = secondary.val(key).await;
+            self.tracker.check("val", &key, &primary, &secondary);
+        }
+        primary
+    }
+
+    async fn single_key_proof<K: KeyType>(&self, key: K) -> Result<Option<Proof<Vec<u8>>>, Error> {
+        let key = key.as_ref();
+        let primary = self.primary.single_key_proof(key).await;
+        if let Some(secondary) = &self.secondary {
+            let secondary = secondary.single_key_proof(key).await;
+            self.tracker
+                .check("single_key_proof", &key, &primary, &secondary);
+        }
+        primary
+    }
+
+    async fn range_proof<K: KeyType, V: Send + Sync>(
+        &self,
+        first_key: Option<K>,
+        last_key: Option<K>,
+        limit: Option<usize>,
+    ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, Error> {
+        let first_key = first_key.as_ref().map(AsRef::as_ref);
+        let last_key = last_key.as_ref().map(AsRef::as_ref);
+        let primary = self
+            .primary
+            .range_proof::<_, V>(first_key, last_key, limit)
+            .await;
+        if let Some(secondary) = &self.secondary {
+            let secondary = secondary
+                .range_proof::<_, V>(first_key, last_key, limit)
+                .await;
+            let args = (first_key, last_key, limit);
+            self.tracker
+                .check("range_proof", &args, &primary, &secondary);
+        }
+        primary
+    }
+
+    async fn range_proof_rev<K: KeyType, V: Send + Sync>(
+        &self,
+        first_key: Option<K>,
+        last_key: Option<K>,
+        limit: Option<usize>,
+    ) -> Result<Option<RangeProof<Vec<u8>, Vec<u8>>>, Error> {
+        let first_key = first_key.as_ref().map(AsRef::as_ref);
+        let last_key = last_key.as_ref().map(AsRef::as_ref);
+        let primary = self
+            .primary
+            .range_proof_rev::<_, V>(first_key, last_key, limit)
+            .await;
+        if let Some(secondary) = &self.secondary {
+            let secondary = secondary
+                .range_proof_rev::<_, V>(first_key, last_key, limit)
+                .await;
+            let args = (first_key, last_key, limit);
+            self.tracker
+                .check("range_proof_rev", &args, &primary, &secondary);
+        }
+        primary
+    }
+
+    fn iter_option<K: KeyType>(&self, first_key: Option<K>) -> Result<Self::Stream<'_>, Error> {
+        let first_key = first_key.as_ref().map(AsRef::as_ref);
+        let primary = self.primary.iter_option(first_key)?;
+        let secondary = self.secondary.as_ref().and_then(|secondary| {
+            self.shadow_stream("iter_option", &first_key, secondary.iter_option(first_key))
+        });
+        Ok(ShadowStream::new(
+            "iter_option",
+            primary,
+            secondary,
+            &self.tracker,
+        ))
+    }
+
+    fn iter_rev_option<K: KeyType>(&self, last_key: Option<K>) -> Result<Self::Stream<'_>, Error> {
+        let last_key = last_key.as_ref().map(AsRef::as_ref);
+        let primary = self.primary.iter_rev_option(last_key)?;
+        let secondary = self.secondary.as_ref().and_then(|secondary| {
+            self.shadow_stream(
+                "iter_rev_option",
+                &last_key,
+                secondary.iter_rev_option(last_key),
+            )
+        });
+        Ok(ShadowStream::new(
+            "iter_rev_option",
+            primary,
+            secondary,
+            &self.tracker,
+        ))
+    }
+}
+
+impl<A, B> Shadow<A, B> {
+    /// The stream of the secondary database the stream of the primary one is compared with, if
+    /// it could be opened.
+    fn shadow_stream<T>(
+        &self,
+        read: &str,
+        args: &dyn Debug,
+        stream: Result<T, Error>,
+    ) -> Option<T> {
+        stream
+            .map_err(|e| {
+                self.tracker.diverged(format!(
+                    "{read}({args:?}): primary opened the stream, secondary returned {e:?}"
+                ))
+            })
+            .ok()
+    }
+}
+
+#[async_trait]
+impl<A, B> api::Proposal for Shadow<A, B>
+where
+    A: api::Proposal + Send + Sync,
+    B: api::Proposal + Send + Sync,
+    A::Proposal: Send + Sync,
+    B::Proposal: Send + Sync,
+{
+    type Proposal = Shadow<A::Proposal, B::Proposal>;
+
+    async fn commit(self: Arc<Self>) -> Result<(), Error> {
+        let Self {
+            primary,
+            secondary,
+            tracker,
+        } = Arc::into_inner(self).ok_or(Error::InvalidProposal)?;
+        let primary = primary.commit().await;
+        if let Some(secondary) = secondary {
+            let secondary = secondary.commit().await;
+            tracker.check("commit", &(), &primary.as_ref(), &secondary.as_ref());
+        }
+        primary
+    }
+
+    async fn propose<K: KeyType, V: ValueType>(
+        self: Arc<Self>,
+        data: Batch<K, V>,
+    ) -> Result<Self::Proposal, Error> {
+        let data = owned_batch(&data);
+        let secondary = match &self.secondary {
+            Some(secondary) => match secondary.clone().propose(data.clone()).await {
+                Ok(secondary) => Some(Arc::new(secondary)),
+                Err(e) => {
+                    self.tracker.diverged(format!(
+                        "propose: primary proposed the batch, secondary returned {e:?}"
+                    ));
+                    None
+                }
+            },
+            None => None,
+        };
+        let primary = self.primary.clone().propose(data).await?;
+        Ok(Shadow {
+            primary: Arc::new(primary),
+            secondary,
+            tracker: self.tracker.clone(),
+        })
+    }
+}
+
+type Item = Result<(Box<[u8]>, Vec<u8>), Error>;
+
+/// A stream of a [Shadow] view, returning the items of the stream of the primary database, each
+/// compared with the next item of the stream of the secondary database. The streams are no
+/// longer compared after they first diverge, since every item after a missing one would differ.
+pub struct ShadowStream<A, B> {
+    read: &'static str,
+    primary: Pin<Box<A>>,
+    secondary: Option<Pin<Box<B>>>,
+    /// The item of the primary stream waiting for the one of the secondary stream.
+    pending: Option<Option<Item>>,
+    position: usize,
+    tracker: Tracker,
+}
+
+impl<A, B> ShadowStream<A, B> {
+    fn new(read: &'static str, primary: A, secondary: Option<B>, tracker: &Tracker) -> Self {
+        Self {
+            read,
+            primary: Box::pin(primary),
+            secondary: secondary.map(Box::pin),
+            pending: None,
+            position: 0,
+            tracker: tracker.clone(),
+        }
+    }
+}
+
+impl<A, B> Stream for ShadowStream<A, B>
+where
+    A: Stream<Item = Item>,
+    B: Stream<Item = Item>,
+{
+    type Item = Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Item>> {
+        let this = &mut *self;
+        let primary = match this.pending.take() {
+            Some(primary) => primary,
+            None => match this.primary.as_mut().poll_next(cx) {
+                Poll::Ready(primary) => primary,
+                Poll::Pending => return Poll::Pending,
+            },
+        };
+
+        if let Some(secondary) = &mut this.secondary {
+            let secondary = match secondary.as_mut().poll_next(cx) {
+                Poll::Ready(secondary) => secondary,
+                Poll::Pending => {
+                    this.pending = Some(primary);
+                    return Poll::Pending;
+                }
+            };
+            let args = this.position;
+            let agree = this.tracker.check(
+                this.read,
+                &args,
+                &primary.as_ref().map(Result::as_ref).transpose(),
+                &secondary.as_ref().map(Result::as_ref).transpose(),
+            );
+            if !agree {
+                this.secondary = None;
+            }
+        }
+
+        this.position += 1;
+        Poll::Ready(primary)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::v2::{
+        api::{BatchOp, Db, DbView, Proposal as _},
+        emptydb::EmptyDb,
+    };
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn shadow_reads() {
+        let db = ShadowDb::new(EmptyDb, EmptyDb);
+        let root_hash = db.root_hash().await.unwrap();
+        let rev = db.revision(root_hash).await.unwrap();
+        assert_eq!(rev.val(b"k").await.unwrap(), None);
+        assert!(rev.iter().unwrap().next().await.is_none());
+
+        let proposal = Arc::new(
+            db.propose(vec![BatchOp::Put {
+                key: b"k",
+                value: b"v",
+            }])
+            .await
+            .unwrap(),
+        );
+        assert_eq!(proposal.val(b"k").await.unwrap().unwrap(), b"v");
+        assert_eq!(db.divergences(), 0);
+
+        // the secondary proposal differs from the primary one, as a faulty backend would
+        let secondary = proposal
+            .secondary
+            .clone()
+            .unwrap()
+            .propose(vec![BatchOp::Put {
+                key: b"k",
+                value: b"w",
+            }])
+            .await
+            .unwrap();
+        let diverging = Shadow {
+            primary: proposal.primary.clone(),
+            secondary: Some(Arc::new(secondary)),
+            tracker: db.tracker.clone(),
+        };
+        assert_eq!(diverging.val(b"k").await.unwrap().unwrap(), b"v");
+        assert_eq!(db.divergences(), 1);
+        assert!(db.last_divergence().unwrap().starts_with("val([107])"));
+
+        drop(diverging);
+        proposal.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shadow_stream() {
+        let item = |key: &[u8]| Ok((key.into(), key.to_vec()));
+        let primary = futures::stream::iter([item(b"a"), item(b"b"), item(b"c")]);
+        let secondary = futures::stream::iter([item(b"a"), item(b"c")]);
+        let tracker = Tracker::default();
+        let stream = ShadowStream::new("iter", primary, Some(secondary), &tracker);
+
+        // the primary items are returned, and the streams are compared up to the first
+        // divergence only
+        let keys: Vec<_> = stream.map(|item| item.unwrap().0).collect().await;
+        assert_eq!(keys, [b"a", b"b", b"c"].map(|key| Box::from(&key[..])));
+        assert_eq!(tracker.0.count.load(Ordering::Relaxed), 1);
+        assert!(tracker
+            .0
+            .last
+            .lock()
+            .as_ref()
+            .unwrap()
+            .starts_with("iter(1)"));
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! A warm standby of a database, to fail over to when the primary one is lost.
+//!
+//! A [Standby] follows the primary database by applying the [Delta]s between its revisions, as
+//! they are committed, and answers reads at the last revision it applied. A delta is only
+//! applied on top of the revision it was taken from, and only committed if the root hash of the
+//! result is the one of the primary, so the standby never holds a revision the primary didn't
+//! have. The deltas are applied one at a time, and reads go on at the revision before while one
+//! is applied.
+//!
+//! The standby takes no writes of its own: the database is only handed back, writable, by
+//! [Standby::promote]. It must start out at a revision of the primary, replicated with
+//! [Db::replicate_to](crate::db::Db::replicate_to) for instance.
+
+use super::{
+    api::{self, Batch, BatchOp, DbView, Error, HashKey, Proposal},
+    diff::{diff, KeyDiff},
+};
+use futures::{Stream, StreamExt, TryStreamExt};
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+/// The changes from one revision of a database to another, with the root hashes of both.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delta {
+    /// The root hash of the revision the changes apply to.
+    pub base: HashKey,
+    /// The root hash of the revision the changes lead to.
+    pub root: HashKey,
+    /// The keys that differ between the two revisions, in key order.
+    pub diffs: Vec<KeyDiff>,
+}
+
+impl Delta {
+    /// The delta from `old` to `new`, see [diff].
+    pub async fn between<O: DbView, N: DbView>(old: &O, new: &N) -> Result<Self, Error> {
+        let diffs = diff(old, new)?.try_collect().await?;
+        Ok(Self {
+            base: old.root_hash().await?,
+            root: new.root_hash().await?,
+            diffs,
+        })
+    }
+
+    /// The batch turning the base revision into the new one.
+    fn batch(&self) -> Batch<&[u8], &[u8]> {
+        self.diffs
+            .iter()
+            .map(|diff| match diff {
+                KeyDiff::Added { key, value }
+                | KeyDiff::Changed {
+                    key, new: value, ..
+                } => BatchOp::Put {
+                    key: &key[..],
+                    value: &value[..],
+                },
+                KeyDiff::Removed { key, .. } => BatchOp::Delete { key: &key[..] },
+            })
+            .collect()
+    }
+}
+
+/// A read-only database following a primary one, see the [module documentation](self).
+#[derive(Debug)]
+pub struct Standby<D> {
+    db: D,
+    /// The root hash of the last delta applied, the reads are answered at.
+    root: RwLock<HashKey>,
+    /// Held while a delta is applied.
+    applying: tokio::sync::Mutex<()>,
+}
+
+impl<D: api::Db + Sync> Standby<D> {
+    /// A standby of the primary whose latest revision `db` holds.
+    pub async fn new(db: D) -> Result<Self, Error> {
+        let root = db.root_hash().await?;
+        Ok(Self {
+            db,
+            root: RwLock::new(root),
+            applying: Default::default(),
+        })
+    }
+
+    /// The root hash of the last revision applied.
+    pub fn root_hash(&self) -> HashKey {
+        *self.root.read()
+    }
+
+    /// The last revision applied, to read from.
+    pub async fn latest(&self) -> Result<Arc<D::Historical>, Error> {
+        self.db.revision(self.root_hash()).await
+    }
+
+    /// A revision applied before, see [api::Db::revision].
+    pub async fn revision(&self, hash: HashKey) -> Result<Arc<D::Historical>, Error> {
+        self.db.revision(hash).await
+    }
+
+    /// Applies `delta` on top of the last revision applied.
+    ///
+    /// Fails with [Error::IncorrectRootHash] if `delta` wasn't taken from the last revision
+    /// applied, or if it leads to another root hash than the one it claims, in which case it
+    /// isn't committed.
+    pub async fn apply(&self, delta: &Delta) -> Result<(), Error> {
+        let _applying = self.applying.lock().await;
+
+        let current = self.root_hash();
+        if delta.base != current {
+            return Err(Error::IncorrectRootHash {
+                provided: delta.base,
+                current,
+            });
+        }
+
+        let proposal = self.db.propose(delta.batch()).await?;
+        let computed = proposal.root_hash().await?;
+        if computed != delta.root {
+            return Err(Error::IncorrectRootHash {
+                provided: delta.root,
+                current: computed,
+            });
+        }
+        Arc::new(proposal).commit().await?;
+
+        *self.root.write() = delta.root;
+        Ok(())
+    }
+
+    /// Applies the deltas of `deltas` as they come, until it ends or a delta fails to apply.
+    /// Returns the number of deltas applied.
+    pub async fn follow<S>(&self, deltas: S) -> Result<u64, Error>
+    where
+        S: Stream<Item = Result<Delta, Error>>,
+    {
+        futures::pin_mut!(deltas);
+        let mut applied = 0;
+        while let Some(delta) = deltas.next().await {
+            self.apply(&delta?).await?;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    /// Stops following the primary, handing back the database to take over the writes.
+    pub fn promote(self) -> D {
+        self.db
+    }
+}
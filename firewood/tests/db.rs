@@ -2,12 +2,43 @@
 // See the file LICENSE.md for licensing terms.
 
 use firewood::{
-    db::{DbConfig, WalConfig},
-    v2::api::{self, BatchOp, Db as _, DbView, Proposal},
+    bench::replay::{replay, Recorder, Trace, TraceOp},
+    db::{
+        AdaptiveCacheConfig, BackgroundIoConfig, BatchSink, CommitBatcher, CommitBatcherStats,
+        CommitBatchingConfig, CommitHook, Db, DbConfig, DbError, HotKeyConfig, HotPrefix,
+        MemoryConsumer, MultiCommit, NegativeCacheConfig, NegativeCacheStats, NodeEncoding,
+        OpStatsConfig, ProofServer, ProofServerConfig, ProofServerStats, StatsDelta, TrieCounts,
+        WalConfig, WriteBatch, MERKLE_META_STORE_ID, MERKLE_PAYLOAD_STORE_ID, ROOT_HASH_STORE_ID,
+    },
+    merkle::{
+        Bincode, BranchNode, LeafNode, NodeContext, TrieHash, TrieVisitor, Visit, EMPTY_ROOT_HASH,
+    },
+    reference::{check_against_reference, RandomBatches, ReferenceTrie},
+    shale::{
+        allocator::{Allocator, Bump, NextFit},
+        StoreStats,
+    },
+    v2::{
+        api::{self, BatchOp, Db as _, DbRead, DbView, DbWrite, Proposal},
+        handles::split,
+        shadow::ShadowDb,
+        standby::{Delta, Standby},
+    },
 };
+use futures::{StreamExt, TryStreamExt};
 use tokio::task::block_in_place;
 
-use std::{collections::VecDeque, env::temp_dir, path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    env::temp_dir,
+    error::Error,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 mod common;
 use common::TestDbCreator;
@@ -240,104 +271,3139 @@ async fn create_db_issue_proof() {
     }
 }
 
-macro_rules! assert_val {
-    ($rev: ident, $key:literal, $expected_val:literal) => {
-        let actual = $rev.val($key.as_bytes()).await.unwrap().unwrap();
-        assert_eq!(actual, $expected_val.as_bytes().to_vec());
-    };
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn proof_server() {
+    let mut tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    tmpdir.push("/tmp/test_proof_server");
+    let cfg = DbConfig::builder().truncate(true).build();
+    let db = Arc::new(Db::new(tmpdir, &cfg).await.unwrap());
+
+    let batch: Vec<_> = (0..100u8)
+        .map(|i| BatchOp::Put {
+            key: [i],
+            value: [i; 8],
+        })
+        .collect();
+    Arc::new(db.propose(batch).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+    let root_hash = db.root_hash().await.unwrap();
+
+    // a long window, so that the identical requests all join the first one
+    let cfg = ProofServerConfig::builder()
+        .threads(2)
+        .coalesce_window(Duration::from_millis(200))
+        .build();
+    let server = ProofServer::new(db.clone(), cfg);
+
+    let proofs = futures::future::join_all((0..10).map(|_| server.prove(root_hash, [7]))).await;
+    for proof in proofs {
+        let value = proof.unwrap().verify([7], root_hash).unwrap();
+        assert_eq!(value.as_deref(), Some(&[7; 8][..]));
+    }
+    assert_eq!(
+        server.stats(),
+        ProofServerStats {
+            requests: 10,
+            coalesced: 9,
+        }
+    );
+
+    assert!(matches!(
+        server.prove([0; 32], [7]).await,
+        Err(api::Error::HashNotFound { .. })
+    ));
 }
 
-#[ignore = "ref: https://github.com/ava-labs/firewood/issues/457"]
 #[tokio::test(flavor = "multi_thread")]
 #[allow(clippy::unwrap_used)]
-async fn db_proposal() -> Result<(), api::Error> {
-    let cfg = DbConfig::builder()
-        .wal(WalConfig::builder().max_revisions(10).build())
+async fn commit_batcher() {
+    let mut tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    tmpdir.push("/tmp/test_commit_batcher");
+    std::fs::create_dir_all(&tmpdir).unwrap();
+    let cfg = DbConfig::builder().truncate(true).build();
+    let db = Arc::new(Db::new(tmpdir.join("batched"), &cfg).await.unwrap());
+    let expected = Db::new(tmpdir.join("expected"), &cfg).await.unwrap();
+    for db in [&*db, &expected] {
+        db.register_batch_validator(|batch| match batch {
+            [BatchOp::Put { key: [13], .. }] => Err("13 is reserved".to_string()),
+            _ => Ok(()),
+        });
+    }
+
+    // a long interval, so that all the batches of a round are queued before the first commit
+    let cfg = CommitBatchingConfig::builder()
+        .interval(Duration::from_millis(200))
         .build();
+    let batcher = CommitBatcher::new(db.clone(), cfg);
 
-    let db = TestDbCreator::builder()
-        .cfg(cfg)
-        .test_name("db_proposal")
-        .build()
-        .create()
-        .await;
+    // the batches of the first round are committed together, while the rejected batch of the
+    // second one makes the others fall back to a commit each
+    for (keys, stats) in [
+        (
+            20..40u8,
+            CommitBatcherStats {
+                batches: 20,
+                commits: 1,
+            },
+        ),
+        (
+            0..20,
+            CommitBatcherStats {
+                batches: 40,
+                commits: 20,
+            },
+        ),
+    ] {
+        let batches: Vec<_> = keys
+            .map(|i| {
+                vec![BatchOp::Put {
+                    key: [i],
+                    value: [i; 8],
+                }]
+            })
+            .collect();
+        let root_hashes =
+            futures::future::join_all(batches.iter().cloned().map(|batch| batcher.commit(batch)))
+                .await;
 
-    let batch = vec![
-        BatchOp::Put {
-            key: b"k",
-            value: "v".as_bytes().to_vec(),
-        },
-        BatchOp::Delete { key: b"z" },
-    ];
+        // each batch is answered with the root hash it would have had if committed alone
+        for (batch, root_hash) in batches.into_iter().zip(root_hashes) {
+            match expected.propose(batch).await {
+                Ok(proposal) => {
+                    let expected_root_hash = proposal.root_hash().await.unwrap();
+                    proposal.commit_sync().unwrap();
+                    assert_eq!(root_hash.unwrap(), expected_root_hash);
+                }
+                Err(api::Error::BatchRejected { .. }) => {
+                    assert!(matches!(root_hash, Err(api::Error::BatchRejected { .. })));
+                }
+                Err(e) => panic!("{e:?}"),
+            }
+        }
+        assert_eq!(
+            db.root_hash().await.unwrap(),
+            expected.root_hash().await.unwrap()
+        );
+        assert_eq!(batcher.stats(), stats);
+    }
 
-    let proposal = Arc::new(db.propose(batch).await?);
-    assert_val!(proposal, "k", "v");
+    // a batch committed once the others are is committed alone
+    let delete = vec![BatchOp::<_, Vec<u8>>::Delete { key: [0] }];
+    let root_hash = batcher.commit(delete).await.unwrap();
+    assert_eq!(root_hash, db.root_hash().await.unwrap());
+    assert_eq!(batcher.stats().commits, 21);
+}
 
-    let batch_2 = vec![BatchOp::Put {
-        key: b"k2",
-        value: "v2".as_bytes().to_vec(),
-    }];
-    let proposal_2 = Arc::new(proposal.clone().propose(batch_2).await?);
-    assert_val!(proposal_2, "k", "v");
-    assert_val!(proposal_2, "k2", "v2");
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn cache_manifest_reopen() {
+    let mut tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    tmpdir.push("/tmp/test_cache_manifest_reopen");
+    let manifest = tmpdir.join("cache_manifest");
 
-    proposal.clone().commit().await?;
-    proposal_2.commit().await?;
+    let cfg = DbConfig::builder().wal(WalConfig::builder().max_revisions(10).build());
 
-    let t1 = tokio::spawn({
-        let proposal = proposal.clone();
-        async move {
-            let another_batch = vec![BatchOp::Put {
-                key: b"another_k_t1",
-                value: "another_v_t1".as_bytes().to_vec(),
-            }];
-            let another_proposal = proposal.clone().propose(another_batch).await.unwrap();
-            let rev = another_proposal.get_revision();
-            assert_val!(rev, "k", "v");
-            assert_val!(rev, "another_k_t1", "another_v_t1");
-            // The proposal is invalid and cannot be committed
-            assert!(Arc::new(another_proposal).commit().await.is_err());
-        }
-    });
-    let t2 = tokio::spawn({
-        let proposal = proposal.clone();
-        async move {
-            let another_batch = vec![BatchOp::Put {
-                key: b"another_k_t2",
-                value: "another_v_t2".as_bytes().to_vec(),
-            }];
-            let another_proposal = proposal.clone().propose(another_batch).await.unwrap();
-            let rev = another_proposal.get_revision();
-            assert_val!(rev, "k", "v");
-            assert_val!(rev, "another_k_t2", "another_v_t2");
-            assert!(Arc::new(another_proposal).commit().await.is_err());
-        }
-    });
-    let (first, second) = tokio::join!(t1, t2);
-    first.unwrap();
-    second.unwrap();
+    let db = firewood::db::Db::new(&tmpdir, &cfg.clone().truncate(true).build())
+        .await
+        .unwrap();
 
-    // Recursive commit
+    let batch: Vec<_> = (0..100u32)
+        .map(|i| BatchOp::Put {
+            key: i.to_be_bytes(),
+            value: i.to_le_bytes().to_vec(),
+        })
+        .collect();
+    let proposal = Arc::new(db.propose(batch).await.unwrap());
+    proposal.commit().await.unwrap();
+    let root_hash = db.root_hash().await.unwrap();
+
+    // closing the DB saves the hot node addresses
+    drop(db);
+    assert!(manifest.exists());
+
+    // and reopening it consumes them, pre-loading the nodes in the background at a limited
+    // rate, which the reads don't wait for
+    let background_io = BackgroundIoConfig::builder().iops(1000).build();
+    let db = firewood::db::Db::new(
+        &tmpdir,
+        &cfg.truncate(false).background_io(background_io).build(),
+    )
+    .await
+    .unwrap();
+    assert!(!manifest.exists());
+
+    assert_eq!(db.root_hash().await.unwrap(), root_hash);
+    let rev = db.revision(root_hash).await.unwrap();
+    for i in 0..100u32 {
+        assert_eq!(
+            rev.val(i.to_be_bytes()).await.unwrap(),
+            Some(i.to_le_bytes().to_vec())
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn single_writer_many_readers() {
+    let mut tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    tmpdir.push("/tmp/test_single_writer_many_readers");
+
+    let cfg = DbConfig::builder().wal(WalConfig::builder().max_revisions(10).build());
+    let open = |cfg: DbConfig| {
+        let tmpdir = tmpdir.clone();
+        async move { Db::new(tmpdir, &cfg).await }
+    };
+    let already_open = |result: Result<Db, api::Error>| match result {
+        Err(api::Error::InternalError(e)) => match e.downcast_ref::<DbError>() {
+            Some(DbError::AlreadyOpen { pid }) => *pid,
+            _ => panic!("unexpected error: {e}"),
+        },
+        _ => panic!("expected the open to fail"),
+    };
 
+    let db = open(cfg.clone().truncate(true).build()).await.unwrap();
     let batch = vec![BatchOp::Put {
-        key: b"k3",
-        value: "v3".as_bytes().to_vec(),
+        key: b"k",
+        value: b"v",
     }];
-    let proposal = Arc::new(db.propose(batch).await?);
-    assert_val!(proposal, "k", "v");
-    assert_val!(proposal, "k2", "v2");
-    assert_val!(proposal, "k3", "v3");
+    Arc::new(db.propose(batch).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+    let root_hash = db.root_hash().await.unwrap();
 
-    let batch_2 = vec![BatchOp::Put {
-        key: b"k4",
-        value: "v4".as_bytes().to_vec(),
-    }];
-    let proposal_2 = Arc::new(proposal.clone().propose(batch_2).await?);
-    assert_val!(proposal_2, "k", "v");
-    assert_val!(proposal_2, "k2", "v2");
-    assert_val!(proposal_2, "k3", "v3");
-    assert_val!(proposal_2, "k4", "v4");
+    // a second writer is turned away, and told who holds the DB
+    let pid = already_open(open(cfg.clone().build()).await);
+    assert_eq!(pid, Some(std::process::id()));
+    drop(db);
 
-    proposal_2.commit().await?;
-    Ok(())
+    let readers = [
+        open(cfg.clone().read_only(true).build()).await.unwrap(),
+        open(cfg.clone().read_only(true).build()).await.unwrap(),
+    ];
+    // readers don't keep a writer out
+    let db = open(cfg.clone().build()).await.unwrap();
+
+    for reader in &readers {
+        assert_eq!(reader.root_hash().await.unwrap(), root_hash);
+        let rev = reader.revision(root_hash).await.unwrap();
+        assert_eq!(rev.val(b"k").await.unwrap(), Some(b"v".to_vec()));
+
+        let batch = vec![BatchOp::Delete { key: b"k" }];
+        assert!(matches!(
+            reader.propose::<_, Vec<u8>>(batch).await,
+            Err(api::Error::ReadOnly)
+        ));
+    }
+    drop(db);
+
+    // but the DB can't be truncated from under them
+    let pid = already_open(open(cfg.clone().truncate(true).build()).await);
+    assert_eq!(pid, None);
+    drop(readers);
+
+    let db = open(cfg.truncate(true).build()).await.unwrap();
+    assert_ne!(db.root_hash().await.unwrap(), root_hash);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn recovery_report() {
+    let mut tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    tmpdir.push("/tmp/test_recovery_report");
+
+    let cfg = DbConfig::builder().wal(WalConfig::builder().max_revisions(10).build());
+
+    let db = Db::new(&tmpdir, &cfg.clone().truncate(true).build())
+        .await
+        .unwrap();
+    let report = db.recovery_report().unwrap();
+    assert_eq!(report.records_replayed, 0);
+    assert_eq!((report.writes_skipped, report.writes_diverged), (0, 0));
+    assert!(!report.truncated_tail);
+    assert_eq!(report.root_hash.0, db.root_hash().await.unwrap());
+
+    for i in 0..3u8 {
+        let batch = vec![BatchOp::Put {
+            key: [i],
+            value: [i],
+        }];
+        Arc::new(db.propose(batch).await.unwrap())
+            .commit()
+            .await
+            .unwrap();
+    }
+    let root_hash = db.root_hash().await.unwrap();
+    drop(db);
+
+    let db = Db::new(&tmpdir, &cfg.clone().build()).await.unwrap();
+    let report = db.recovery_report().unwrap();
+    assert_eq!(report.records_replayed, 3);
+    // the store files were written before the DB was dropped
+    assert!(report.writes_skipped > 0);
+    assert!(!report.truncated_tail);
+    assert_eq!(report.root_hash.0, root_hash);
+    drop(db);
+
+    // a read-only handle doesn't replay the Wal
+    let reader = Db::new(&tmpdir, &cfg.read_only(true).build())
+        .await
+        .unwrap();
+    assert!(reader.recovery_report().is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn close() {
+    let mut tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    tmpdir.push("/tmp/test_close");
+
+    let cfg = DbConfig::builder().wal(WalConfig::builder().max_revisions(10).build());
+
+    let db = Db::new(&tmpdir, &cfg.clone().truncate(true).build())
+        .await
+        .unwrap();
+    for i in 0..3u8 {
+        let batch = vec![BatchOp::Put {
+            key: [i],
+            value: [i],
+        }];
+        Arc::new(db.propose(batch).await.unwrap())
+            .commit()
+            .await
+            .unwrap();
+    }
+    let root_hash = db.root_hash().await.unwrap();
+    let pending = Arc::new(
+        db.propose(vec![BatchOp::Put {
+            key: [3],
+            value: [3],
+        }])
+        .await
+        .unwrap(),
+    );
+    db.close(Duration::from_secs(10)).await.unwrap();
+
+    // the proposals still around can neither be committed nor proposed on
+    assert!(matches!(
+        pending
+            .clone()
+            .propose(vec![BatchOp::Put {
+                key: [4],
+                value: [4],
+            }])
+            .await,
+        Err(api::Error::Closed)
+    ));
+    assert!(matches!(pending.commit().await, Err(api::Error::Closed)));
+
+    let db = Db::new(&tmpdir, &cfg.clone().build()).await.unwrap();
+    let report = db.recovery_report().unwrap();
+    assert!(report.clean_shutdown);
+    assert_eq!(report.records_replayed, 0);
+    assert_eq!(report.root_hash.0, root_hash);
+    let rev = db.revision(root_hash).await.unwrap();
+    assert_eq!(rev.val([2]).await.unwrap(), Some(vec![2]));
+    assert_eq!(rev.val([3]).await.unwrap(), None);
+    drop(rev);
+
+    // the marker is consumed by the open, and only `close` writes it
+    drop(db);
+    let db = Db::new(&tmpdir, &cfg.build()).await.unwrap();
+    assert!(!db.recovery_report().unwrap().clean_shutdown);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn write_batch_recovery() {
+    let mut tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    tmpdir.push("/tmp/test_write_batch_recovery");
+
+    let cfg = DbConfig::builder().wal(WalConfig::builder().max_revisions(10).build());
+    let db = Db::new(&tmpdir, &cfg.clone().truncate(true).build())
+        .await
+        .unwrap();
+    for i in 0..3u8 {
+        let mut batch = WriteBatch::new();
+        batch.put([i], [i]).delete([i.wrapping_sub(1)]);
+        batch.commit(&db).unwrap();
+    }
+    let root_hash = db.root_hash().await.unwrap();
+
+    // the DB isn't closed, as if the process crashed, and the commits are recovered from the Wal
+    drop(db);
+    let db = Db::new(&tmpdir, &cfg.build()).await.unwrap();
+    let report = db.recovery_report().unwrap();
+    assert!(!report.clean_shutdown);
+    assert_eq!(report.root_hash.0, root_hash);
+    let rev = db.revision(root_hash).await.unwrap();
+    assert_eq!(rev.val([1]).await.unwrap(), None);
+    assert_eq!(rev.val([2]).await.unwrap(), Some(vec![2]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn open_mapped() {
+    let mut tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    tmpdir.push("/tmp/test_open_mapped");
+
+    let cfg = DbConfig::builder().wal(WalConfig::builder().max_revisions(10).build());
+    let db = Db::new(&tmpdir, &cfg.clone().truncate(true).build())
+        .await
+        .unwrap();
+    let batch: Vec<_> = (0..1000u32)
+        .map(|i| BatchOp::Put {
+            key: i.to_be_bytes(),
+            value: i.to_le_bytes(),
+        })
+        .collect();
+    Arc::new(db.propose(batch).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+    let root_hash = db.root_hash().await.unwrap();
+    assert!(matches!(
+        Db::open_mapped(&tmpdir, &cfg.clone().build()),
+        Err(DbError::AlreadyOpen { .. })
+    ));
+    db.close(Duration::from_secs(10)).await.unwrap();
+
+    let rev = Db::open_mapped(&tmpdir, &cfg.clone().build()).unwrap();
+    assert_eq!(rev.root_hash().await.unwrap(), root_hash);
+    for i in (0..1000u32).step_by(7) {
+        assert_eq!(
+            rev.val(i.to_be_bytes()).await.unwrap(),
+            Some(i.to_le_bytes().to_vec())
+        );
+    }
+    assert_eq!(rev.val(1000u32.to_be_bytes()).await.unwrap(), None);
+    // the store files are read, not written, while they are mapped
+    assert!(Db::new(&tmpdir, &cfg.clone().build()).await.is_err());
+    drop(rev);
+
+    // the marker is left in place
+    let db = Db::new(&tmpdir, &cfg.clone().build()).await.unwrap();
+    assert!(db.recovery_report().unwrap().clean_shutdown);
+
+    // a DB that wasn't closed might have changes only in the Wal
+    drop(db);
+    assert!(matches!(
+        Db::open_mapped(&tmpdir, &cfg.build()),
+        Err(DbError::InvalidParams)
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn hash_len() {
+    use std::os::unix::fs::FileExt;
+
+    let mut tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    tmpdir.push("/tmp/test_hash_len");
+
+    let cfg = DbConfig::builder().truncate(true).build();
+    drop(Db::new(&tmpdir, &cfg).await.unwrap());
+
+    // the length follows the threshold for inline values in the parameters at the start of the
+    // DB
+    let params = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(tmpdir.join("merkle/meta/00000000.fw"))
+        .unwrap();
+    let mut hash_len = [0; 8];
+    params.read_exact_at(&mut hash_len, 72).unwrap();
+    assert_eq!(u64::from_le_bytes(hash_len), 32);
+
+    // a DB built with another length can't be opened
+    params.write_all_at(&20u64.to_le_bytes(), 72).unwrap();
+    let cfg = DbConfig::builder().truncate(false).build();
+    let Err(api::Error::InternalError(_)) = Db::new(&tmpdir, &cfg).await else {
+        panic!("the DB should not open with another hash length");
+    };
+
+    std::fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+async fn params_are_little_endian() {
+    use std::os::unix::fs::FileExt;
+
+    let mut tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    tmpdir.push("/tmp/test_params_are_little_endian");
+
+    let cfg = DbConfig::builder()
+        .truncate(true)
+        .inline_value_threshold(0x0102)
+        .build();
+    drop(Db::new(&tmpdir, &cfg).await.unwrap());
+
+    let params = std::fs::File::open(tmpdir.join("merkle/meta/00000000.fw")).unwrap();
+    let mut bytes = [0; 96];
+    params.read_exact_at(&mut bytes, 0).unwrap();
+    assert_eq!(&bytes[..16], b"firewood v0.2\0\0\0");
+    // the magic string, then the parameters as little-endian u64s, whatever the host
+    let fields: Vec<_> = bytes[16..]
+        .chunks_exact(8)
+        .map(|field| u64::from_le_bytes(field.try_into().unwrap()))
+        .collect();
+    assert_eq!(fields, [22, 22, 22, 22, 15, 22, 0x0102, 32, 0, 0]);
+    assert_eq!(bytes[64..72], [0x02, 0x01, 0, 0, 0, 0, 0, 0]);
+
+    // and read back from the same bytes
+    let cfg = DbConfig::builder().truncate(false).build();
+    drop(Db::new(&tmpdir, &cfg).await.unwrap());
+
+    // a DB of another format can't be opened
+    let params = std::fs::OpenOptions::new()
+        .write(true)
+        .open(tmpdir.join("merkle/meta/00000000.fw"))
+        .unwrap();
+    params.write_all_at(b"firewood v0.1\0\0\0", 0).unwrap();
+    let Err(api::Error::InternalError(_)) = Db::new(&tmpdir, &cfg).await else {
+        panic!("the DB should not open with another format");
+    };
+
+    std::fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn payload_alignment() {
+    use std::os::unix::fs::FileExt;
+
+    let mut tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    tmpdir.push("/tmp/test_payload_alignment");
+
+    // the chunks can't be aligned to more than a region
+    let cfg = DbConfig::builder()
+        .truncate(true)
+        .payload_align_nbit(23)
+        .build();
+    let Err(api::Error::InternalError(_)) = Db::new(&tmpdir, &cfg).await else {
+        panic!("the DB should not be created with an alignment above its region size");
+    };
+
+    let cfg = DbConfig::builder()
+        .truncate(true)
+        .payload_align_nbit(12)
+        .build();
+    let db = Db::new(&tmpdir, &cfg).await.unwrap();
+    let mut reference = ReferenceTrie::default();
+    block_in_place(|| {
+        check_against_reference(&db, &mut reference, RandomBatches::new(15).take(50))
+    })
+    .unwrap();
+    drop(db);
+
+    // the alignment is the last of the parameters at the start of the DB, and the one the DB
+    // was created with is kept
+    let params = std::fs::File::open(tmpdir.join("merkle/meta/00000000.fw")).unwrap();
+    let mut align_nbit = [0; 8];
+    params.read_exact_at(&mut align_nbit, 88).unwrap();
+    assert_eq!(u64::from_le_bytes(align_nbit), 12);
+
+    let cfg = DbConfig::builder().truncate(false).build();
+    let db = Db::new(&tmpdir, &cfg).await.unwrap();
+    block_in_place(|| {
+        check_against_reference(&db, &mut reference, RandomBatches::new(16).take(50))
+    })
+    .unwrap();
+    drop(db);
+
+    std::fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn split_handles() {
+    let mut tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    tmpdir.push("/tmp/test_split_handles");
+
+    let cfg = DbConfig::builder().truncate(true).build();
+    let (reader, mut writer) = split(Db::new(&tmpdir, &cfg).await.unwrap());
+
+    // the readers read the revisions the writer commits from tasks of their own
+    let last = 9u8;
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let reader = reader.clone();
+            tokio::spawn(async move {
+                loop {
+                    let root_hash = reader.root_hash().await.unwrap();
+                    if let Ok(rev) = reader.revision(root_hash).await {
+                        if rev.val(b"n").await.unwrap() == Some(vec![last]) {
+                            break;
+                        }
+                    }
+                    tokio::task::yield_now().await;
+                }
+            })
+        })
+        .collect();
+
+    for n in 0..=last {
+        let batch = vec![BatchOp::Put {
+            key: b"n",
+            value: vec![n],
+        }];
+        Arc::new(writer.propose(batch).await.unwrap())
+            .commit()
+            .await
+            .unwrap();
+    }
+    for reader in readers {
+        reader.await.unwrap();
+    }
+
+    drop((reader, writer));
+    std::fs::remove_dir_all(tmpdir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn shadow_db() {
+    let tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    let dirs = ["primary", "secondary"].map(|name| tmpdir.join(format!("test_shadow_db_{name}")));
+
+    // the values inlined in the secondary DB must read the same as the ones stored apart
+    let cfg = DbConfig::builder().truncate(true);
+    let primary = Db::new(&dirs[0], &cfg.clone().build()).await.unwrap();
+    let secondary = Db::new(&dirs[1], &cfg.inline_value_threshold(8).build())
+        .await
+        .unwrap();
+    let db = ShadowDb::new(primary, secondary);
+
+    let puts = |value: u8| -> Vec<BatchOp<[u8; 1], [u8; 1]>> {
+        (0..16)
+            .map(|i| BatchOp::Put {
+                key: [i],
+                value: [value],
+            })
+            .collect()
+    };
+    Arc::new(db.propose(puts(0)).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+    let root_hash = db.root_hash().await.unwrap();
+    let rev = db.revision(root_hash).await.unwrap();
+    assert_eq!(rev.val([3]).await.unwrap(), Some(vec![0]));
+    assert!(rev.single_key_proof([3]).await.unwrap().is_some());
+    assert_eq!(rev.iter().unwrap().count().await, 16);
+    assert_eq!(db.divergences(), 0);
+
+    // a write the primary DB doesn't see
+    let extra = vec![BatchOp::Put {
+        key: b"extra",
+        value: b"1",
+    }];
+    Arc::new(db.secondary().propose(extra).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+    assert_eq!(db.root_hash().await.unwrap(), root_hash);
+    assert_eq!(db.divergences(), 1);
+    assert!(db.last_divergence().unwrap().starts_with("root_hash"));
+
+    // from then on, the revisions committed through the shadow DB differ, and the reads from
+    // them are served by the primary DB alone
+    Arc::new(db.propose(puts(2)).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    assert_eq!(db.divergences(), 3);
+    assert!(db.last_divergence().unwrap().starts_with("revision"));
+    assert_eq!(rev.val([3]).await.unwrap(), Some(vec![2]));
+    assert_eq!(db.divergences(), 3);
+
+    drop((rev, db));
+    for dir in dirs {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+async fn replay_trace() {
+    let tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    let dirs = ["recorded", "replayed"].map(|name| tmpdir.join(format!("test_replay_{name}")));
+
+    let cfg = DbConfig::builder().truncate(true).build();
+    let batch: Vec<BatchOp<[u8; 1], [u8; 1]>> = (0..16)
+        .map(|i| BatchOp::Put {
+            key: [i],
+            value: [i],
+        })
+        .collect();
+    let mut dbs = Vec::new();
+    for dir in &dirs {
+        let db = Db::new(dir, &cfg).await.unwrap();
+        Arc::new(db.propose(batch.clone()).await.unwrap())
+            .commit()
+            .await
+            .unwrap();
+        dbs.push(db);
+    }
+
+    // the reads served by one DB, saved to a file
+    let recorder = Recorder::new();
+    let root_hash = dbs[0].root_hash().await.unwrap();
+    let rev = recorder.view(dbs[0].revision(root_hash).await.unwrap());
+    assert_eq!(rev.val([3]).await.unwrap(), Some(vec![3]));
+    assert_eq!(rev.val([42]).await.unwrap(), None);
+    assert!(rev.single_key_proof([5]).await.unwrap().is_some());
+    let proof = rev
+        .range_proof::<_, Vec<u8>>(Some([2]), None, Some(4))
+        .await
+        .unwrap();
+    assert_eq!(proof.unwrap().middle.len(), 4);
+    assert_eq!(rev.iter().unwrap().count().await, 16);
+    let trace = recorder.take();
+    assert_eq!(
+        trace.ops,
+        vec![
+            TraceOp::Get(vec![3].into()),
+            TraceOp::Get(vec![42].into()),
+            TraceOp::Prove(vec![5].into()),
+            TraceOp::RangeProof {
+                first_key: Some(vec![2].into()),
+                last_key: None,
+                limit: Some(4),
+                reverse: false,
+            },
+        ]
+    );
+    assert_eq!(recorder.trace(), Trace::default());
+    let file = tmpdir.join("test_replay.trace");
+    trace.save(&file).unwrap();
+
+    // replayed against the other one
+    let trace = Trace::load(&file).unwrap();
+    let rev = dbs[1].revision(root_hash).await.unwrap();
+    let report = replay(&*rev, &trace).await.unwrap();
+    assert_eq!(report.get.count, 2);
+    assert_eq!(report.get.samples.len(), 2);
+    assert_eq!(report.prove.count, 1);
+    assert_eq!(report.range_proof.count, 1);
+    assert_eq!(report.found, 3);
+
+    drop((rev, dbs));
+    std::fs::remove_file(file).unwrap();
+    for dir in dirs {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+async fn standby() {
+    let tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    let dirs = ["primary", "standby"].map(|name| tmpdir.join(format!("test_standby_{name}")));
+
+    // the standby DB starts out at the first revision of the primary one
+    let cfg = DbConfig::builder().truncate(true).build();
+    let primary = Db::new(&dirs[0], &cfg).await.unwrap();
+    let standby = Db::new(&dirs[1], &cfg).await.unwrap();
+    for db in [&primary, &standby] {
+        let seed = vec![BatchOp::Put {
+            key: b"seed",
+            value: b"0",
+        }];
+        Arc::new(db.propose(seed).await.unwrap())
+            .commit()
+            .await
+            .unwrap();
+    }
+    let standby = Standby::new(standby).await.unwrap();
+    assert_eq!(standby.root_hash(), primary.root_hash().await.unwrap());
+
+    // every commit of the primary DB, as a delta from the revision before
+    let mut deltas = Vec::new();
+    let mut base = primary.root_hash().await.unwrap();
+    for round in 0..3u8 {
+        let mut batch: Vec<BatchOp<[u8; 1], [u8; 1]>> = (round..8)
+            .map(|i| BatchOp::Put {
+                key: [i],
+                value: [round],
+            })
+            .collect();
+        batch.push(BatchOp::Delete { key: [round] });
+        Arc::new(primary.propose(batch).await.unwrap())
+            .commit()
+            .await
+            .unwrap();
+        let root = primary.root_hash().await.unwrap();
+        let old = primary.revision(base).await.unwrap();
+        let new = primary.revision(root).await.unwrap();
+        deltas.push(Delta::between(&*old, &*new).await.unwrap());
+        base = root;
+    }
+
+    standby.apply(&deltas[0]).await.unwrap();
+    assert_eq!(
+        standby.latest().await.unwrap().val([5]).await.unwrap(),
+        Some(vec![0])
+    );
+
+    // a delta applies on top of the revision it was taken from only
+    let Err(api::Error::IncorrectRootHash { .. }) = standby.apply(&deltas[0]).await else {
+        panic!("a delta should not apply twice");
+    };
+    let Err(api::Error::IncorrectRootHash { .. }) = standby.apply(&deltas[2]).await else {
+        panic!("a delta should not apply out of order");
+    };
+    // and isn't committed if it doesn't lead to the revision of the primary DB
+    let mut forged = deltas[1].clone();
+    forged.diffs.pop();
+    let Err(api::Error::IncorrectRootHash { .. }) = standby.apply(&forged).await else {
+        panic!("a forged delta should not apply");
+    };
+    assert_eq!(standby.root_hash(), deltas[0].root);
+
+    let rest = futures::stream::iter(deltas[1..].iter().cloned().map(Ok));
+    assert_eq!(standby.follow(rest).await.unwrap(), 2);
+    assert_eq!(standby.root_hash(), primary.root_hash().await.unwrap());
+    let latest = standby.latest().await.unwrap();
+    assert_eq!(latest.val([5]).await.unwrap(), Some(vec![2]));
+    assert_eq!(latest.val([1]).await.unwrap(), None);
+    drop(latest);
+
+    // once promoted, the standby DB takes the writes
+    let promoted = standby.promote();
+    let batch = vec![BatchOp::Put {
+        key: b"after",
+        value: b"failover",
+    }];
+    Arc::new(promoted.propose(batch).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+    let rev = promoted
+        .revision(promoted.root_hash().await.unwrap())
+        .await
+        .unwrap();
+    assert_eq!(rev.val(b"after").await.unwrap(), Some(b"failover".to_vec()));
+
+    drop((rev, promoted, primary));
+    for dir in dirs {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn inline_values() {
+    let mut tmpdir: PathBuf = std::env::var_os("CARGO_TARGET_DIR")
+        .unwrap_or(temp_dir().into())
+        .into();
+    tmpdir.push("/tmp/test_inline_values");
+
+    let stored = TestDbCreator::builder()
+        .test_name("inline_values_stored")
+        .build()
+        .create()
+        .await;
+    let inline = Db::new(
+        &tmpdir,
+        &DbConfig::builder()
+            .truncate(true)
+            .inline_value_threshold(8)
+            .build(),
+    )
+    .await
+    .unwrap();
+
+    let puts = |prefix: u8| -> Vec<BatchOp<[u8; 2], [u8; 1]>> {
+        (0..16)
+            .map(|i| BatchOp::Put {
+                key: [prefix, i],
+                value: [i],
+            })
+            .collect()
+    };
+
+    for db in [&*stored, &inline] {
+        Arc::new(db.propose(puts(0)).await.unwrap())
+            .commit()
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        stored.root_hash().await.unwrap(),
+        inline.root_hash().await.unwrap()
+    );
+    drop(inline);
+
+    // the threshold the DB was created with wins over the one in the config
+    let inline = Db::new(&tmpdir, &DbConfig::builder().build())
+        .await
+        .unwrap();
+
+    let batch = || {
+        let mut batch: Vec<_> = (0..16).map(|i| BatchOp::Delete { key: [0, i] }).collect();
+        batch.extend(puts(1));
+        batch
+    };
+    for db in [&*stored, &inline] {
+        Arc::new(db.propose(batch()).await.unwrap())
+            .commit()
+            .await
+            .unwrap();
+    }
+
+    let root_hash = inline.root_hash().await.unwrap();
+    assert_eq!(stored.root_hash().await.unwrap(), root_hash);
+
+    let rev = inline.revision(root_hash).await.unwrap();
+    for i in 0..16 {
+        assert_eq!(rev.val([0, i]).await.unwrap(), None);
+        assert_eq!(rev.val([1, i]).await.unwrap(), Some(vec![i]));
+    }
+
+    // every node of the dump is on a line of its own, the inline values aren't
+    let stored_nodes = kv_dump!(stored).lines().count();
+    let inline_nodes = kv_dump!(inline).lines().count();
+    assert!(
+        inline_nodes < stored_nodes,
+        "{inline_nodes} >= {stored_nodes}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn key_counts() {
+    let db = TestDbCreator::builder()
+        .test_name("key_counts")
+        .build()
+        .create()
+        .await;
+    assert_eq!(db.counts(), TrieCounts::default());
+
+    let batch: Vec<BatchOp<&[u8], &[u8]>> = vec![
+        BatchOp::Put {
+            key: b"a",
+            value: b"12",
+        },
+        BatchOp::Put {
+            key: b"ab",
+            value: b"345",
+        },
+        // deleting a missing key doesn't change anything
+        BatchOp::Delete { key: b"b" },
+    ];
+    let proposal = db.propose(batch).await.unwrap();
+    let counts = TrieCounts {
+        keys: 2,
+        value_bytes: 5,
+    };
+    assert_eq!(proposal.get_revision().counts(), counts);
+    // the counts only change once the proposal is committed
+    assert_eq!(db.counts(), TrieCounts::default());
+    Arc::new(proposal).commit().await.unwrap();
+    assert_eq!(db.counts(), counts);
+
+    let batch: Vec<BatchOp<&[u8], &[u8]>> = vec![
+        BatchOp::Put {
+            key: b"a",
+            value: b"1",
+        },
+        BatchOp::Delete { key: b"ab" },
+        BatchOp::Put {
+            key: b"c",
+            value: b"6789",
+        },
+    ];
+    Arc::new(db.propose(batch).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+    let counts = TrieCounts {
+        keys: 2,
+        value_bytes: 5,
+    };
+    assert_eq!(db.counts(), counts);
+
+    // the counts are persisted
+    let db = db.reopen().await;
+    assert_eq!(db.counts(), counts);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn kv_move() {
+    let db = TestDbCreator::builder()
+        .test_name("kv_move")
+        .build()
+        .create()
+        .await;
+
+    let batch: Vec<BatchOp<&[u8], &[u8]>> = vec![
+        BatchOp::Put {
+            key: b"a",
+            value: b"12",
+        },
+        BatchOp::Put {
+            key: b"b",
+            value: b"345",
+        },
+        BatchOp::Move {
+            key: b"a",
+            new_key: b"c",
+            overwrite: false,
+        },
+    ];
+    Arc::new(db.propose(batch).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    assert!(rev.val(b"a").await.unwrap().is_none());
+    assert_eq!(rev.val(b"c").await.unwrap().unwrap(), b"12");
+    assert_eq!(
+        db.counts(),
+        TrieCounts {
+            keys: 2,
+            value_bytes: 5
+        }
+    );
+
+    let overwrite = |overwrite| {
+        vec![BatchOp::<&[u8], &[u8]>::Move {
+            key: b"b",
+            new_key: b"c",
+            overwrite,
+        }]
+    };
+    assert!(matches!(
+        db.propose(overwrite(false)).await,
+        Err(api::Error::KeyExists { key }) if key == b"c"
+    ));
+
+    Arc::new(db.propose(overwrite(true)).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    assert!(rev.val(b"b").await.unwrap().is_none());
+    assert_eq!(rev.val(b"c").await.unwrap().unwrap(), b"345");
+    assert_eq!(
+        db.counts(),
+        TrieCounts {
+            keys: 1,
+            value_bytes: 3
+        }
+    );
+
+    // the key to move must exist
+    let batch = vec![BatchOp::<&[u8], &[u8]>::Move {
+        key: b"a",
+        new_key: b"d",
+        overwrite: true,
+    }];
+    assert!(db.propose(batch).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn kv_delete_prefix() {
+    let db = TestDbCreator::builder()
+        .test_name("kv_delete_prefix")
+        .build()
+        .create()
+        .await;
+
+    let batch: Vec<BatchOp<Vec<u8>, Vec<u8>>> = (0..64u8)
+        .map(|i| BatchOp::Put {
+            key: vec![i % 4, i],
+            value: vec![i; 2],
+        })
+        .chain([BatchOp::Put {
+            key: vec![1],
+            value: vec![1],
+        }])
+        .collect();
+    Arc::new(db.propose(batch).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+
+    // the keys put in the same batch after the prefix is deleted are kept
+    let batch: Vec<BatchOp<&[u8], &[u8]>> = vec![
+        BatchOp::DeletePrefix { prefix: &[1] },
+        BatchOp::Put {
+            key: &[1, 0],
+            value: b"new",
+        },
+    ];
+    Arc::new(db.propose(batch).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    assert!(rev.val([1]).await.unwrap().is_none());
+    assert!(rev.val([1, 5]).await.unwrap().is_none());
+    assert_eq!(rev.val([1, 0]).await.unwrap().unwrap(), b"new");
+    assert_eq!(rev.val([2, 6]).await.unwrap().unwrap(), [6; 2]);
+    let counts = TrieCounts {
+        keys: 49,
+        value_bytes: 48 * 2 + 3,
+    };
+    assert_eq!(db.counts(), counts);
+
+    let db = db.reopen().await;
+    assert_eq!(db.counts(), counts);
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    assert!(rev.val([1, 9]).await.unwrap().is_none());
+    assert_eq!(rev.val([3, 7]).await.unwrap().unwrap(), [7; 2]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn kv_delete_prefix_reclaims_later() {
+    let db = TestDbCreator::builder()
+        .test_name("kv_delete_prefix_reclaims_later")
+        .build()
+        .create()
+        .await;
+    let puts = |prefix: u8| {
+        (0..5000u16)
+            .map(|i| BatchOp::Put {
+                key: [&[prefix][..], &i.to_be_bytes()].concat(),
+                value: vec![0; 64],
+            })
+            .collect()
+    };
+    let commit = |db: &Db, batch: Vec<BatchOp<Vec<u8>, Vec<u8>>>| {
+        block_in_place(|| {
+            futures::executor::block_on(db.propose(batch))
+                .unwrap()
+                .commit_sync()
+                .unwrap()
+        })
+    };
+    let payload_bytes = |db: &Db| db.stats_snapshot().allocator.payload_bytes;
+
+    // more nodes than a batch frees
+    commit(&db, puts(1));
+    let before = payload_bytes(&db);
+    commit(&db, vec![BatchOp::DeletePrefix { prefix: vec![1] }]);
+    // the keys of the nodes not freed yet are still counted
+    let left = db.counts();
+    assert!(left.keys > 0 && left.keys < 5000);
+
+    // the batches after a reopen go on freeing the subtree
+    let db = db.reopen().await;
+    for i in 0..10u8 {
+        commit(
+            &db,
+            vec![BatchOp::Put {
+                key: vec![2, i],
+                value: vec![i],
+            }],
+        );
+    }
+    assert_eq!(
+        db.counts(),
+        TrieCounts {
+            keys: 10,
+            value_bytes: 10
+        }
+    );
+    // so that as many keys fit where the dropped ones were
+    commit(&db, puts(3));
+    assert!(payload_bytes(&db) < before + before / 10);
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    assert!(rev.val([1, 0, 7]).await.unwrap().is_none());
+    assert_eq!(rev.val([3, 0, 7]).await.unwrap().unwrap(), [0; 64]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn iterate_during_commits() {
+    let db = TestDbCreator::builder()
+        .test_name("iterate_during_commits")
+        .build()
+        .create()
+        .await;
+
+    let put = |round: usize| -> Vec<BatchOp<Vec<u8>, Vec<u8>>> {
+        (0..200usize)
+            .map(|i| BatchOp::Put {
+                key: format!("key{i:03}").into_bytes(),
+                value: format!("value{i}-{round}").into_bytes(),
+            })
+            .collect()
+    };
+    Arc::new(db.propose(put(0)).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+    let expected: Vec<_> = put(0)
+        .into_iter()
+        .map(|op| match op {
+            BatchOp::Put { key, value } => (key.into_boxed_slice(), value),
+            _ => unreachable!(),
+        })
+        .collect();
+
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    let mut stream = rev.iter_option::<&[u8]>(None).unwrap();
+    let mut scanned = Vec::new();
+    for _ in 0..10 {
+        scanned.push(stream.next().await.unwrap().unwrap());
+    }
+
+    // rewrite every value, and then delete every key, so that the nodes the stream still has
+    // to read are freed and reused
+    for round in 1..4 {
+        Arc::new(db.propose(put(round)).await.unwrap())
+            .commit()
+            .await
+            .unwrap();
+    }
+    let delete: Vec<BatchOp<Vec<u8>, Vec<u8>>> = expected
+        .iter()
+        .map(|(key, _)| BatchOp::Delete { key: key.to_vec() })
+        .collect();
+    Arc::new(db.propose(delete).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+    assert_eq!(db.counts(), TrieCounts::default());
+
+    while let Some(kv) = stream.next().await {
+        scanned.push(kv.unwrap());
+    }
+    assert_eq!(scanned, expected);
+}
+
+#[derive(Debug, Default)]
+struct RecordingHook {
+    records: std::sync::Mutex<Vec<Vec<u8>>>,
+    reject: bool,
+}
+
+#[async_trait::async_trait]
+impl CommitHook for RecordingHook {
+    async fn on_wal_append(
+        &self,
+        record: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        #[allow(clippy::unwrap_used)]
+        self.records.lock().unwrap().push(record.to_vec());
+        if self.reject {
+            return Err("replica unavailable".into());
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn commit_hooks() {
+    let db = TestDbCreator::builder()
+        .test_name("commit_hooks")
+        .build()
+        .create()
+        .await;
+
+    let put = |key: &'static [u8]| {
+        vec![BatchOp::Put {
+            key,
+            value: b"v".to_vec(),
+        }]
+    };
+
+    // commits before the hook is registered are not shipped
+    Arc::new(db.propose(put(b"a")).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+
+    let hook = Arc::new(RecordingHook::default());
+    db.register_commit_hook(hook.clone());
+
+    for key in [b"b", b"c"] {
+        Arc::new(db.propose(put(key)).await.unwrap())
+            .commit()
+            .await
+            .unwrap();
+    }
+    let records = hook.records.lock().unwrap().clone();
+    assert_eq!(records.len(), 2);
+    assert!(records.iter().all(|record| !record.is_empty()));
+
+    // a failing hook fails the commit, after the changes were applied locally
+    let rejecting = Arc::new(RecordingHook {
+        reject: true,
+        ..Default::default()
+    });
+    db.register_commit_hook(rejecting.clone());
+
+    let err = Arc::new(db.propose(put(b"d")).await.unwrap())
+        .commit()
+        .await
+        .unwrap_err();
+    assert!(matches!(err, api::Error::InternalError(_)));
+    assert_eq!(hook.records.lock().unwrap().len(), 3);
+    assert_eq!(rejecting.records.lock().unwrap().len(), 1);
+
+    let root_hash = db.root_hash().await.unwrap();
+    let rev = db.revision(root_hash).await.unwrap();
+    assert_eq!(rev.val(b"d").await.unwrap(), Some(b"v".to_vec()));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn empty_root() {
+    let db = TestDbCreator::builder()
+        .test_name("empty_root")
+        .build()
+        .create()
+        .await;
+
+    let check_empty = |rev: Arc<_>| async move {
+        let rev: Arc<firewood::db::DbRev<_>> = rev;
+        assert_eq!(rev.root_hash().await.unwrap(), EMPTY_ROOT_HASH.0);
+
+        // every key is proven absent
+        let proof = rev.single_key_proof(b"k").await.unwrap().unwrap();
+        assert!(proof.0.is_empty());
+        assert_eq!(proof.verify(b"k", EMPTY_ROOT_HASH.0).unwrap(), None);
+
+        // and every range empty
+        for (first, last) in [(None, None), (Some(b"a"), Some(b"z"))] {
+            let proof = rev
+                .range_proof::<&[u8], Vec<u8>>(first.map(|k| &k[..]), last.map(|k| &k[..]), None)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(proof.middle.is_empty());
+            let has_more = proof
+                .first_key_proof
+                .verify_range_proof::<&[u8], Vec<u8>, Bincode>(
+                    EMPTY_ROOT_HASH.0,
+                    b"a",
+                    b"z",
+                    Vec::new(),
+                    Vec::new(),
+                )
+                .unwrap();
+            assert!(!has_more);
+        }
+    };
+
+    // a new DB is at the empty root, which is a revision like any other
+    assert_eq!(db.root_hash().await.unwrap(), EMPTY_ROOT_HASH.0);
+    check_empty(db.revision(EMPTY_ROOT_HASH.0).await.unwrap()).await;
+
+    // and so is a DB everything was removed from
+    let put = vec![BatchOp::Put {
+        key: b"k".to_vec(),
+        value: b"v".to_vec(),
+    }];
+    db.propose(put).await.unwrap().commit_sync().unwrap();
+    let root_hash = db.root_hash().await.unwrap();
+    assert_ne!(root_hash, EMPTY_ROOT_HASH.0);
+    let proof = db
+        .revision(root_hash)
+        .await
+        .unwrap()
+        .single_key_proof(b"k")
+        .await;
+    assert!(proof
+        .unwrap()
+        .unwrap()
+        .verify(b"k", EMPTY_ROOT_HASH.0)
+        .unwrap()
+        .is_none());
+
+    let delete = vec![BatchOp::<_, Vec<u8>>::Delete { key: b"k".to_vec() }];
+    db.propose(delete).await.unwrap().commit_sync().unwrap();
+    assert_eq!(db.root_hash().await.unwrap(), EMPTY_ROOT_HASH.0);
+    check_empty(db.revision(EMPTY_ROOT_HASH.0).await.unwrap()).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn dry_run() {
+    let db = TestDbCreator::builder()
+        .test_name("dry_run")
+        .build()
+        .create()
+        .await;
+    let empty_root = db.root_hash().await.unwrap();
+
+    let batch = |keys: std::ops::Range<u8>| -> Vec<BatchOp<Vec<u8>, Vec<u8>>> {
+        keys.map(|k| BatchOp::Put {
+            key: vec![k],
+            value: vec![k; 4],
+        })
+        .collect()
+    };
+
+    // a dry run predicts the root of the proposal without changing anything
+    let dry_run = db.dry_run(batch(0..10)).unwrap();
+    assert_eq!(
+        dry_run.counts,
+        TrieCounts {
+            keys: 10,
+            value_bytes: 40,
+        }
+    );
+    assert!(dry_run.changed_pages > 0);
+    assert_eq!(db.root_hash().await.unwrap(), empty_root);
+
+    let proposal = Arc::new(db.propose(batch(0..10)).await.unwrap());
+    assert_eq!(dry_run.root_hash.0, proposal.root_hash().await.unwrap());
+
+    // and on top of a proposal
+    let dry_run = proposal.dry_run(batch(10..20)).unwrap();
+    let child = proposal.clone().propose(batch(10..20)).await.unwrap();
+    assert_eq!(dry_run.root_hash.0, child.root_hash().await.unwrap());
+    drop(child);
+
+    Arc::into_inner(proposal).unwrap().commit_sync().unwrap();
+    let root = db.root_hash().await.unwrap();
+
+    // nothing of the dry runs made it to the Wal
+    let db = db.reopen().await;
+    assert_eq!(db.root_hash().await.unwrap(), root);
+    assert_eq!(db.counts().keys, 10);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn root_mismatch() {
+    let db = TestDbCreator::builder()
+        .test_name("root_mismatch")
+        .build()
+        .create()
+        .await;
+
+    let put = |key: &[u8], value: &[u8]| BatchOp::Put {
+        key: key.to_vec(),
+        value: value.to_vec(),
+    };
+    let batch: Vec<_> = (0..100u8).map(|k| put(&[k, k], b"value")).collect();
+    db.propose(batch).await.unwrap().commit_sync().unwrap();
+    let root = db.root_hash().await.unwrap();
+
+    // the expected root was computed without the change to key 0x2a2a
+    let batch = || vec![put(&[42, 42], b"changed"), put(&[200], b"added")];
+    let proposal = db.propose(batch()).await.unwrap();
+    let computed = proposal.root_hash().await.unwrap();
+    let expected = TrieHash(root);
+    let Err(DbError::RootMismatch {
+        expected: e,
+        computed: c,
+        dump: Some(dump),
+    }) = proposal.commit_with_expected_root(expected)
+    else {
+        panic!("the commit should have failed with a dump");
+    };
+    assert_eq!((e, c.0), (expected, computed));
+    assert_eq!(db.root_hash().await.unwrap(), root);
+
+    let dump = std::fs::read_to_string(dump).unwrap();
+    let keys: Vec<&str> = dump
+        .lines()
+        .filter_map(|line| line.split(' ').nth(3))
+        .filter(|key| *key != "-" && *key != "key")
+        .collect();
+    assert_eq!(keys, ["2a2a", "c8"]);
+
+    // with the right root, the proposal is committed
+    let proposal = db.propose(batch()).await.unwrap();
+    proposal
+        .commit_with_expected_root(TrieHash(computed))
+        .unwrap();
+    assert_eq!(db.root_hash().await.unwrap(), computed);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn secondary_indexes() {
+    // the values are "owner:amount", indexed by owner
+    let owner = |value: &[u8]| value.split(|b| *b == b':').next().map(<[u8]>::to_vec);
+    let db = TestDbCreator::builder()
+        .test_name("secondary_indexes")
+        .build()
+        .create()
+        .await;
+    db.register_index("owner", owner).unwrap();
+    assert!(matches!(
+        db.register_index("owner", owner),
+        Err(DbError::InvalidParams)
+    ));
+    assert!(matches!(
+        db.index_get("amount", b"1"),
+        Err(DbError::UnknownIndex(_))
+    ));
+
+    let put = |key: &str, value: &str| BatchOp::Put {
+        key: key.as_bytes().to_vec(),
+        value: value.as_bytes().to_vec(),
+    };
+    let owned_by = |db: &Db, owner: &str| -> Vec<String> {
+        db.index_get("owner", owner)
+            .unwrap()
+            .into_iter()
+            .map(|key| String::from_utf8(key).unwrap())
+            .collect()
+    };
+
+    let batch = vec![put("b", "alice:1"), put("a", "alice:2"), put("c", "bob:3")];
+    db.propose(batch).await.unwrap().commit_sync().unwrap();
+    let first_root = db.root_hash().await.unwrap();
+    assert_eq!(owned_by(&db, "alice"), ["a", "b"]);
+    assert_eq!(owned_by(&db, "bob"), ["c"]);
+    assert!(owned_by(&db, "carol").is_empty());
+
+    let batch: Vec<BatchOp<Vec<u8>, Vec<u8>>> = vec![
+        // changes owner
+        put("b", "bob:1"),
+        // same owner, nothing changes in the index
+        put("c", "bob:4"),
+        BatchOp::Delete { key: b"a".to_vec() },
+        BatchOp::Move {
+            key: b"c".to_vec(),
+            new_key: b"d".to_vec(),
+            overwrite: false,
+        },
+    ];
+    db.propose(batch).await.unwrap().commit_sync().unwrap();
+    assert!(owned_by(&db, "alice").is_empty());
+    assert_eq!(owned_by(&db, "bob"), ["b", "d"]);
+
+    // the past revisions keep their indexes
+    let first = db.get_revision(&TrieHash(first_root)).unwrap();
+    assert_eq!(first.index_get("owner", "alice").unwrap(), [b"a", b"b"]);
+
+    // the index survives a reopen, once registered again
+    let db = db.reopen().await;
+    db.register_index("owner", owner).unwrap();
+    assert_eq!(owned_by(&db, "bob"), ["b", "d"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn sequences() {
+    let db = TestDbCreator::builder()
+        .test_name("sequences")
+        .build()
+        .create()
+        .await;
+    // the empty name is left to the sequences
+    assert!(matches!(
+        db.register_index("", |_| None),
+        Err(DbError::InvalidParams)
+    ));
+    assert_eq!(db.next_sequence("orders").unwrap(), 1);
+    assert_eq!(db.next_sequence("orders").unwrap(), 2);
+    assert_eq!(db.next_sequence("invoices").unwrap(), 1);
+
+    let batch = vec![BatchOp::Put {
+        key: b"order2".to_vec(),
+        value: b"paid".to_vec(),
+    }];
+    let proposal = db.propose(batch).await.unwrap();
+    // handed out after the proposal was created, so not committed with it
+    assert_eq!(db.next_sequence("orders").unwrap(), 3);
+    proposal.commit_sync().unwrap();
+
+    // the last numbers handed out before the proposal are committed with it
+    let root = db.root_hash().await.unwrap();
+    let rev = db.revision(root).await.unwrap();
+    assert_eq!(rev.sequence("orders").unwrap(), 2);
+    assert_eq!(rev.sequence("invoices").unwrap(), 1);
+    assert_eq!(rev.sequence("refunds").unwrap(), 0);
+    assert_eq!(rev.kv_get(b"order2").as_deref(), Some(&b"paid"[..]),);
+
+    // the numbers not committed are handed out again after a reopen
+    let db = db.reopen().await;
+    assert_eq!(db.next_sequence("orders").unwrap(), 3);
+    assert_eq!(db.next_sequence("invoices").unwrap(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn batch_validators() {
+    let db = TestDbCreator::builder()
+        .test_name("batch_validators")
+        .build()
+        .create()
+        .await;
+
+    let validated = Arc::new(AtomicUsize::new(0));
+    let counter = validated.clone();
+    db.register_batch_validator(move |batch| {
+        counter.fetch_add(batch.len(), Ordering::Relaxed);
+        Ok(())
+    });
+    db.register_batch_validator(|batch| {
+        batch.iter().try_for_each(|op| match op {
+            BatchOp::Put { key, .. } | BatchOp::Move { new_key: key, .. }
+                if key.starts_with(b"reserved/") =>
+            {
+                Err(format!("{key:?} is reserved"))
+            }
+            _ => Ok(()),
+        })
+    });
+
+    let put = |key: &'static [u8]| {
+        vec![BatchOp::Put {
+            key,
+            value: b"v".to_vec(),
+        }]
+    };
+
+    let proposal = Arc::new(db.propose(put(b"a")).await.unwrap());
+    assert_eq!(validated.load(Ordering::Relaxed), 1);
+
+    // the batches of child proposals are validated too
+    let child = proposal.clone().propose(put(b"reserved/a")).await;
+    assert!(matches!(child, Err(api::Error::BatchRejected { .. })));
+
+    let moved = vec![BatchOp::<&[u8], Vec<u8>>::Move {
+        key: b"a",
+        new_key: b"reserved/b",
+        overwrite: false,
+    }];
+    let rejected = db.propose(moved).await;
+    assert!(matches!(rejected, Err(api::Error::BatchRejected { .. })));
+    assert_eq!(validated.load(Ordering::Relaxed), 3);
+
+    // a rejected batch never reaches the trie
+    proposal.commit().await.unwrap();
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    assert_eq!(rev.val(b"a").await.unwrap(), Some(b"v".to_vec()));
+    assert!(rev.val(b"reserved/a").await.unwrap().is_none());
+}
+
+macro_rules! assert_val {
+    ($rev: ident, $key:literal, $expected_val:literal) => {
+        let actual = $rev.val($key.as_bytes()).await.unwrap().unwrap();
+        assert_eq!(actual, $expected_val.as_bytes().to_vec());
+    };
+}
+
+#[ignore = "ref: https://github.com/ava-labs/firewood/issues/457"]
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn db_proposal() -> Result<(), api::Error> {
+    let cfg = DbConfig::builder()
+        .wal(WalConfig::builder().max_revisions(10).build())
+        .build();
+
+    let db = TestDbCreator::builder()
+        .cfg(cfg)
+        .test_name("db_proposal")
+        .build()
+        .create()
+        .await;
+
+    let batch = vec![
+        BatchOp::Put {
+            key: b"k",
+            value: "v".as_bytes().to_vec(),
+        },
+        BatchOp::Delete { key: b"z" },
+    ];
+
+    let proposal = Arc::new(db.propose(batch).await?);
+    assert_val!(proposal, "k", "v");
+
+    let batch_2 = vec![BatchOp::Put {
+        key: b"k2",
+        value: "v2".as_bytes().to_vec(),
+    }];
+    let proposal_2 = Arc::new(proposal.clone().propose(batch_2).await?);
+    assert_val!(proposal_2, "k", "v");
+    assert_val!(proposal_2, "k2", "v2");
+
+    proposal.clone().commit().await?;
+    proposal_2.commit().await?;
+
+    let t1 = tokio::spawn({
+        let proposal = proposal.clone();
+        async move {
+            let another_batch = vec![BatchOp::Put {
+                key: b"another_k_t1",
+                value: "another_v_t1".as_bytes().to_vec(),
+            }];
+            let another_proposal = proposal.clone().propose(another_batch).await.unwrap();
+            let rev = another_proposal.get_revision();
+            assert_val!(rev, "k", "v");
+            assert_val!(rev, "another_k_t1", "another_v_t1");
+            // The proposal is invalid and cannot be committed
+            assert!(Arc::new(another_proposal).commit().await.is_err());
+        }
+    });
+    let t2 = tokio::spawn({
+        let proposal = proposal.clone();
+        async move {
+            let another_batch = vec![BatchOp::Put {
+                key: b"another_k_t2",
+                value: "another_v_t2".as_bytes().to_vec(),
+            }];
+            let another_proposal = proposal.clone().propose(another_batch).await.unwrap();
+            let rev = another_proposal.get_revision();
+            assert_val!(rev, "k", "v");
+            assert_val!(rev, "another_k_t2", "another_v_t2");
+            assert!(Arc::new(another_proposal).commit().await.is_err());
+        }
+    });
+    let (first, second) = tokio::join!(t1, t2);
+    first.unwrap();
+    second.unwrap();
+
+    // Recursive commit
+
+    let batch = vec![BatchOp::Put {
+        key: b"k3",
+        value: "v3".as_bytes().to_vec(),
+    }];
+    let proposal = Arc::new(db.propose(batch).await?);
+    assert_val!(proposal, "k", "v");
+    assert_val!(proposal, "k2", "v2");
+    assert_val!(proposal, "k3", "v3");
+
+    let batch_2 = vec![BatchOp::Put {
+        key: b"k4",
+        value: "v4".as_bytes().to_vec(),
+    }];
+    let proposal_2 = Arc::new(proposal.clone().propose(batch_2).await?);
+    assert_val!(proposal_2, "k", "v");
+    assert_val!(proposal_2, "k2", "v2");
+    assert_val!(proposal_2, "k3", "v3");
+    assert_val!(proposal_2, "k4", "v4");
+
+    proposal_2.commit().await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn memory_budget() {
+    const LIMIT: usize = 1 << 14;
+
+    let cfg = DbConfig::builder()
+        .truncate(true)
+        .memory_budget(LIMIT)
+        .build();
+    let db = TestDbCreator::builder()
+        .test_name("memory_budget")
+        .cfg(cfg)
+        .build()
+        .create()
+        .await;
+    assert_eq!(db.memory_budget().limit(), LIMIT);
+
+    let value = |i: u32| format!("{i:0>200}").into_bytes();
+    for round in 0..20u32 {
+        let batch: Vec<_> = (round * 100..(round + 1) * 100)
+            .map(|i| BatchOp::Put {
+                key: i.to_be_bytes(),
+                value: value(i),
+            })
+            .collect();
+        Arc::new(db.propose(batch).await.unwrap())
+            .commit()
+            .await
+            .unwrap();
+    }
+
+    // the caches stay under the limit, and evicting from them doesn't lose anything
+    let budget = db.memory_budget();
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    for i in 0..2000u32 {
+        assert_eq!(rev.val(i.to_be_bytes()).await.unwrap(), Some(value(i)));
+
+        let cached = budget.used(MemoryConsumer::ObjCache) + budget.used(MemoryConsumer::PageCache);
+        assert!(cached <= LIMIT, "{budget:?}");
+    }
+    assert!(budget.used(MemoryConsumer::ObjCache) > 0);
+
+    // the cache of a revision is released with it
+    let cached = budget.used(MemoryConsumer::ObjCache);
+    drop(rev);
+    assert!(budget.used(MemoryConsumer::ObjCache) < cached);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn overlay_spill() {
+    const THRESHOLD: usize = 1 << 16;
+
+    let value = |i: u32| format!("{i:0>100}").into_bytes();
+    let batch = || -> Vec<_> {
+        (0..5000u32)
+            .map(|i| BatchOp::Put {
+                key: i.to_be_bytes(),
+                value: value(i),
+            })
+            .collect()
+    };
+
+    let in_memory = TestDbCreator::builder()
+        .test_name("overlay_spill_in_memory")
+        .build()
+        .create()
+        .await;
+    in_memory
+        .propose(batch())
+        .await
+        .unwrap()
+        .commit_sync()
+        .unwrap();
+
+    let cfg = DbConfig::builder()
+        .truncate(true)
+        .overlay_spill_threshold(THRESHOLD)
+        .build();
+    let db = TestDbCreator::builder()
+        .test_name("overlay_spill")
+        .cfg(cfg)
+        .build()
+        .create()
+        .await;
+
+    // the proposal holds no more than the threshold, but is the same as one held in memory
+    let proposal = db.propose(batch()).await.unwrap();
+    let overlay = db.memory_budget().used(MemoryConsumer::Overlay);
+    assert!(overlay <= THRESHOLD + (1 << 12), "{overlay}");
+    assert_eq!(
+        proposal.root_hash().await.unwrap(),
+        in_memory.root_hash().await.unwrap()
+    );
+    assert_eq!(
+        proposal.val(7u32.to_be_bytes()).await.unwrap(),
+        Some(value(7))
+    );
+
+    proposal.commit_sync().unwrap();
+    let root = db.root_hash().await.unwrap();
+    let db = db.reopen().await;
+    assert_eq!(db.root_hash().await.unwrap(), root);
+    let rev = db.revision(root).await.unwrap();
+    for i in (0..5000u32).step_by(97) {
+        assert_eq!(rev.val(i.to_be_bytes()).await.unwrap(), Some(value(i)));
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn adaptive_cache() {
+    // any memory usage is above the high watermark, so the caches keep shrinking
+    let adaptive_cache = AdaptiveCacheConfig::builder()
+        .enabled(true)
+        .min_cache_bytes(1 << 16)
+        .max_cache_bytes(1 << 20)
+        .high_watermark(0.0)
+        .low_watermark(-1.0)
+        .interval(Duration::from_millis(10))
+        .build();
+    let cfg = DbConfig::builder()
+        .truncate(true)
+        .adaptive_cache(adaptive_cache)
+        .build();
+    let db = TestDbCreator::builder()
+        .test_name("adaptive_cache")
+        .cfg(cfg)
+        .build()
+        .create()
+        .await;
+    assert_eq!(db.cache_max_bytes(), Some(1 << 20));
+
+    for i in 0..100u32 {
+        let batch = vec![BatchOp::Put {
+            key: i.to_be_bytes(),
+            value: i.to_le_bytes(),
+        }];
+        Arc::new(db.propose(batch).await.unwrap())
+            .commit()
+            .await
+            .unwrap();
+
+        if db.cache_max_bytes() == Some(1 << 16) {
+            break;
+        }
+        block_in_place(|| std::thread::sleep(Duration::from_millis(10)));
+    }
+    assert_eq!(db.cache_max_bytes(), Some(1 << 16));
+
+    // shrinking the caches doesn't lose anything
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    assert_eq!(
+        rev.val(0u32.to_be_bytes()).await.unwrap().as_deref(),
+        Some(&0u32.to_le_bytes()[..])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn reference_trie() {
+    // inlining small values changes how the trie is stored, but not its root hash
+    for (seed, inline_value_threshold) in [(1, 0), (2, 0), (3, 8), (4, 8)] {
+        let cfg = DbConfig::builder()
+            .truncate(true)
+            .inline_value_threshold(inline_value_threshold)
+            .build();
+        let db = TestDbCreator::builder()
+            .cfg(cfg)
+            .test_name(format!("reference_trie_{seed}"))
+            .build()
+            .create()
+            .await;
+
+        let mut reference = ReferenceTrie::default();
+        block_in_place(|| {
+            check_against_reference(&db, &mut reference, RandomBatches::new(seed).take(200))
+        })
+        .unwrap();
+        assert!(!reference.is_empty());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn delayed_allocation() {
+    for (seed, allocator) in [
+        (5, Arc::new(NextFit) as Arc<dyn Allocator>),
+        (6, Arc::new(Bump)),
+    ] {
+        let cfg = DbConfig::builder()
+            .truncate(true)
+            .delayed_allocation(true)
+            .payload_allocator(allocator)
+            .build();
+        let db = TestDbCreator::builder()
+            .cfg(cfg)
+            .test_name(format!("delayed_allocation_{seed}"))
+            .build()
+            .create()
+            .await;
+
+        let mut reference = ReferenceTrie::default();
+        block_in_place(|| {
+            check_against_reference(&db, &mut reference, RandomBatches::new(seed).take(100))
+        })
+        .unwrap();
+
+        // the nodes were written where they were allocated
+        let root = db.root_hash().await.unwrap();
+        let db = db.reopen().await;
+        assert_eq!(db.root_hash().await.unwrap(), root);
+        let rev = db.revision(root).await.unwrap();
+        for key in reference.keys() {
+            assert_eq!(rev.val(key).await.unwrap().as_deref(), reference.get(key));
+        }
+    }
+}
+
+/// Collects the depth and address of every node stored on its own.
+#[derive(Default)]
+struct NodeDepths(Vec<(usize, usize)>);
+
+impl NodeDepths {
+    fn record(&mut self, context: &NodeContext<'_>) -> Visit {
+        if let Some(addr) = context.addr {
+            self.0.push((context.depth, addr.get()));
+        }
+        Visit::Continue
+    }
+}
+
+impl TrieVisitor for NodeDepths {
+    fn enter_branch(&mut self, context: &NodeContext<'_>, _branch: &BranchNode) -> Visit {
+        self.record(context)
+    }
+
+    fn enter_leaf(&mut self, context: &NodeContext<'_>, _leaf: &LeafNode) -> Visit {
+        self.record(context)
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn hot_levels() {
+    for (seed, delayed_allocation) in [(15, false), (16, true)] {
+        // the segment of the second DB has a directory of its own
+        let hot_segment_dir = delayed_allocation.then(|| temp_dir().join("hot_levels_segment"));
+        let cfg = DbConfig::builder()
+            .truncate(true)
+            .hot_levels(2)
+            .delayed_allocation(delayed_allocation)
+            .hot_segment_dir(hot_segment_dir.clone())
+            .build();
+        let db = TestDbCreator::builder()
+            .cfg(cfg)
+            .test_name(format!("hot_levels_{seed}"))
+            .build()
+            .create()
+            .await;
+
+        let mut reference = ReferenceTrie::default();
+        block_in_place(|| {
+            check_against_reference(&db, &mut reference, RandomBatches::new(seed).take(100))
+        })
+        .unwrap();
+        // the number of levels is recorded, and the segment is read back, when the DB is reopened
+        let db = db.reopen().await;
+        let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+        let hot_segment = rev.hot_segment().unwrap();
+        let mut depths = NodeDepths::default();
+        rev.walk(&mut depths).unwrap();
+        assert!(depths.0.iter().any(|&(depth, _)| depth >= 2));
+        for (depth, addr) in depths.0 {
+            assert_eq!(hot_segment.contains(&addr), depth < 2, "{depth} {addr:#x}");
+        }
+        for key in reference.keys() {
+            assert_eq!(rev.val(key).await.unwrap().as_deref(), reference.get(key));
+        }
+        if let Some(hot_segment_dir) = hot_segment_dir {
+            assert!(hot_segment_dir.read_dir().unwrap().next().is_some());
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn free_list_index() {
+    let cfg = DbConfig::builder()
+        .truncate(true)
+        .free_list_index(true)
+        .build();
+    let db = TestDbCreator::builder()
+        .cfg(cfg)
+        .test_name("free_list_index")
+        .build()
+        .create()
+        .await;
+
+    let mut reference = ReferenceTrie::default();
+    block_in_place(|| {
+        check_against_reference(&db, &mut reference, RandomBatches::new(13).take(100))
+    })
+    .unwrap();
+
+    // the index is built again, and checked against the free list, when the DB is reopened
+    let db = db.reopen().await;
+    block_in_place(|| {
+        check_against_reference(&db, &mut reference, RandomBatches::new(14).take(100))
+    })
+    .unwrap();
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    for key in reference.keys() {
+        assert_eq!(rev.val(key).await.unwrap().as_deref(), reference.get(key));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn node_encodings() {
+    // the encoding changes how the nodes are stored, but not the trie
+    for (seed, node_encoding, inline_value_threshold) in [
+        (7, NodeEncoding::Bincode, 0),
+        (8, NodeEncoding::Cbor, 0),
+        (9, NodeEncoding::Cbor, 8),
+    ] {
+        let cfg = DbConfig::builder()
+            .truncate(true)
+            .node_encoding(node_encoding)
+            .inline_value_threshold(inline_value_threshold)
+            .build();
+        let db = TestDbCreator::builder()
+            .cfg(cfg)
+            .test_name(format!("node_encodings_{seed}"))
+            .build()
+            .create()
+            .await;
+
+        let mut reference = ReferenceTrie::default();
+        block_in_place(|| {
+            check_against_reference(&db, &mut reference, RandomBatches::new(seed).take(100))
+        })
+        .unwrap();
+
+        let root = db.root_hash().await.unwrap();
+        let db = db.reopen().await;
+        let rev = db.revision(root).await.unwrap();
+        for key in reference.keys() {
+            assert_eq!(rev.val(key).await.unwrap().as_deref(), reference.get(key));
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+async fn multi_commit() {
+    let shards = [
+        TestDbCreator::builder()
+            .test_name("multi_commit_0")
+            .build()
+            .create()
+            .await,
+        TestDbCreator::builder()
+            .test_name("multi_commit_1")
+            .build()
+            .create()
+            .await,
+    ];
+    let journal = temp_dir().join("multi_commit_journal");
+    let _ = std::fs::remove_file(&journal);
+    let coordinator = MultiCommit::new(&journal);
+    let batch = |value: &'static [u8]| {
+        vec![BatchOp::Put {
+            key: b"shared",
+            value,
+        }]
+    };
+
+    let prepared = coordinator
+        .prepare(vec![
+            (&*shards[0], batch(b"a0")),
+            (&*shards[1], batch(b"b0")),
+        ])
+        .unwrap();
+    let root_hashes = prepared.root_hashes();
+    prepared.commit().unwrap();
+    for (shard, root_hash) in shards.iter().zip(&root_hashes) {
+        assert_eq!(shard.root_hash().await.unwrap(), root_hash.0);
+    }
+    assert!(!journal.exists());
+
+    // an aborted commit is never committed
+    let prepared = coordinator
+        .prepare(vec![
+            (&*shards[0], batch(b"a1")),
+            (&*shards[1], batch(b"b1")),
+        ])
+        .unwrap();
+    prepared.abort().unwrap();
+    assert!(!coordinator.recover(&[&shards[0], &shards[1]]).unwrap());
+
+    // a prepared commit the process didn't get to commit is committed on recovery
+    let prepared = coordinator
+        .prepare(vec![
+            (&*shards[0], batch(b"a2")),
+            (&*shards[1], batch(b"b2")),
+        ])
+        .unwrap();
+    let root_hashes = prepared.root_hashes();
+    drop(prepared);
+    assert!(matches!(
+        coordinator.prepare(vec![(&*shards[0], batch(b"a3"))]),
+        Err(DbError::IO(_))
+    ));
+    let [shard0, shard1] = shards;
+    let shards = [shard0.reopen().await, shard1.reopen().await];
+    assert!(matches!(
+        coordinator.recover(&[&shards[0]]),
+        Err(DbError::InvalidParams)
+    ));
+    assert!(coordinator.recover(&[&shards[0], &shards[1]]).unwrap());
+    for (shard, root_hash) in shards.iter().zip(&root_hashes) {
+        assert_eq!(shard.root_hash().await.unwrap(), root_hash.0);
+    }
+    let rev = shards[1].revision(root_hashes[1].0).await.unwrap();
+    assert_eq!(rev.kv_get(b"shared").as_deref(), Some(&b"b2"[..]));
+    assert!(!journal.exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+async fn kv_get_many() {
+    let db = TestDbCreator::builder()
+        .test_name("kv_get_many")
+        .build()
+        .create()
+        .await;
+    let mut reference = ReferenceTrie::default();
+    block_in_place(|| {
+        check_against_reference(&db, &mut reference, RandomBatches::new(12).take(200))
+    })
+    .unwrap();
+
+    // every other key, out of order, with missing and repeated keys
+    let mut keys: Vec<Vec<u8>> = reference.keys().step_by(2).map(<[u8]>::to_vec).collect();
+    keys.reverse();
+    keys.extend([b"missing".to_vec(), Vec::new(), keys[0].clone()]);
+
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    let values = rev.kv_get_many(&keys).await.unwrap();
+    assert_eq!(values.len(), keys.len());
+    for (key, value) in keys.iter().zip(values) {
+        assert_eq!(value.as_deref(), reference.get(key), "{key:?}");
+    }
+    assert!(rev.kv_get_many::<&[u8]>(&[]).await.unwrap().is_empty());
+
+    // the same through the api, on the revision and on a proposal on top of it
+    let values = rev.val_many(keys.clone()).await;
+    for (key, value) in keys.iter().zip(values) {
+        assert_eq!(value.unwrap().as_deref(), reference.get(key), "{key:?}");
+    }
+    let proposal = db
+        .propose(vec![BatchOp::Put {
+            key: b"missing".to_vec(),
+            value: b"found".to_vec(),
+        }])
+        .await
+        .unwrap();
+    let values = proposal
+        .val_many(vec![b"missing".to_vec(), keys[1].clone()])
+        .await;
+    assert_eq!(values[0].as_ref().unwrap().as_deref(), Some(&b"found"[..]));
+    assert_eq!(
+        values[1].as_ref().unwrap().as_deref(),
+        reference.get(&keys[1])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn audit() {
+    let db = TestDbCreator::builder()
+        .test_name("audit")
+        .build()
+        .create()
+        .await;
+    let mut reference = ReferenceTrie::default();
+    block_in_place(|| {
+        check_against_reference(&db, &mut reference, RandomBatches::new(11).take(50))
+    })
+    .unwrap();
+
+    // the trie operations never leave degenerate nodes behind, so there is nothing to repair
+    let root = db.root_hash().await.unwrap();
+    assert!(db.revision(root).await.unwrap().audit().unwrap().is_empty());
+    assert!(db.repair_trie().await.unwrap().is_empty());
+    assert_eq!(db.root_hash().await.unwrap(), root);
+}
+
+/// A sink writing into a DB, that fails once it has written `ranges` ranges.
+struct FailingSink<'a> {
+    replica: &'a Db,
+    ranges: AtomicUsize,
+}
+
+#[async_trait::async_trait]
+impl BatchSink for FailingSink<'_> {
+    async fn resume_after(&self) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        self.replica.resume_after().await
+    }
+
+    async fn write_range(
+        &self,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.ranges.fetch_sub(1, Ordering::Relaxed) == 0 {
+            return Err("connection lost".into());
+        }
+        self.replica.write_range(pairs).await
+    }
+
+    async fn finish(&self, root_hash: api::HashKey) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.replica.finish(root_hash).await
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn replicate_to() {
+    let source = TestDbCreator::builder()
+        .test_name("replicate_to_source")
+        .build()
+        .create()
+        .await;
+    let mut reference = ReferenceTrie::default();
+    block_in_place(|| {
+        check_against_reference(&source, &mut reference, RandomBatches::new(10).take(50))
+    })
+    .unwrap();
+    let root = TrieHash(source.root_hash().await.unwrap());
+    let replica = TestDbCreator::builder()
+        .test_name("replicate_to_replica")
+        .build()
+        .create()
+        .await;
+    assert!(matches!(
+        source.replicate_to(&root, &*replica, 0).await,
+        Err(DbError::InvalidParams)
+    ));
+    let unknown = TrieHash([1; 32]);
+    assert!(matches!(
+        source.replicate_to(&unknown, &*replica, 4).await,
+        Err(DbError::RevisionNotFound(hash)) if hash == unknown
+    ));
+
+    // the first run stops after two ranges
+    let sink = FailingSink {
+        replica: &replica,
+        ranges: AtomicUsize::new(2),
+    };
+    assert!(matches!(
+        source.replicate_to(&root, &sink, 4).await,
+        Err(DbError::Replication(_))
+    ));
+    assert_eq!(replica.counts().keys, 8);
+
+    // the second one resumes after them, from the same revision, whatever was committed since
+    let batch = vec![BatchOp::Put {
+        key: b"after",
+        value: b"the snapshot",
+    }];
+    source.propose(batch).await.unwrap().commit_sync().unwrap();
+    let written = source.replicate_to(&root, &*replica, 4).await.unwrap();
+    assert_eq!(written + 8, reference.keys().count() as u64);
+    assert_eq!(replica.root_hash().await.unwrap(), root.0);
+
+    // a replica that isn't a copy of the revision is caught at the end
+    let batch = vec![BatchOp::Put {
+        key: b"extra",
+        value: b"key",
+    }];
+    replica.propose(batch).await.unwrap().commit_sync().unwrap();
+    assert!(matches!(
+        source.replicate_to(&root, &*replica, 4).await,
+        Err(DbError::Replication(_))
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+async fn revision_index() {
+    let db = TestDbCreator::builder()
+        .test_name("revision_index")
+        .build()
+        .create()
+        .await;
+
+    let batches = [
+        (
+            vec![BatchOp::Put {
+                key: b"k1",
+                value: b"1234".to_vec(),
+            }],
+            Some("block 1"),
+        ),
+        (
+            vec![BatchOp::Put {
+                key: b"k2",
+                value: b"12".to_vec(),
+            }],
+            None,
+        ),
+        (vec![BatchOp::Delete { key: b"k1" }], Some("block 3")),
+    ];
+    let mut roots = Vec::new();
+    for (batch, annotation) in batches {
+        let mut proposal = db.propose(batch).await.unwrap();
+        if let Some(annotation) = annotation {
+            proposal = proposal.with_annotation(annotation);
+        }
+        Arc::new(proposal).commit().await.unwrap();
+        roots.push(db.root_hash().await.unwrap());
+    }
+
+    let entries = block_in_place(|| db.revision_index()).unwrap();
+    let listed: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            (
+                entry.index,
+                *entry.root_hash,
+                entry.annotation.as_deref(),
+                entry.size_delta,
+            )
+        })
+        .collect();
+    assert_eq!(
+        listed,
+        [
+            (0, roots[2], Some("block 3"), Some(-4)),
+            (1, roots[1], None, Some(2)),
+            // nothing older is retained to compare the first revision to
+            (2, roots[0], Some("block 1"), None),
+        ]
+    );
+    assert!(entries[..3]
+        .iter()
+        .all(|entry| entry.committed_at.is_some()));
+    assert_eq!(entries[0].counts.unwrap().keys, 1);
+
+    // every listed revision can be read
+    for entry in &entries {
+        assert!(db.get_revision(&entry.root_hash).is_some());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+async fn changed_since() {
+    let db = TestDbCreator::builder()
+        .test_name("changed_since")
+        .build()
+        .create()
+        .await;
+
+    let mut roots = Vec::new();
+    for batch in [
+        vec![BatchOp::Put {
+            key: b"k1",
+            value: b"1".to_vec(),
+        }],
+        vec![BatchOp::Put {
+            key: b"k2",
+            value: b"2".to_vec(),
+        }],
+        vec![BatchOp::Move {
+            key: b"k2",
+            new_key: b"k3",
+            overwrite: false,
+        }],
+    ] {
+        Arc::new(db.propose(batch).await.unwrap())
+            .commit()
+            .await
+            .unwrap();
+        roots.push(TrieHash(db.root_hash().await.unwrap()));
+    }
+
+    block_in_place(|| {
+        assert!(!db.changed_since(&roots[0], b"k1").unwrap());
+        assert!(db.changed_since(&roots[0], b"k2").unwrap());
+        assert!(db.changed_since(&roots[1], b"k3").unwrap());
+        assert!(!db.changed_since(&roots[1], b"k1").unwrap());
+        // nothing changed since the latest revision
+        assert!(!db.changed_since(&roots[2], b"k3").unwrap());
+        assert!(matches!(
+            db.changed_since(&TrieHash([1; 32]), b"k1"),
+            Err(DbError::RevisionNotFound(_))
+        ));
+    });
+
+    Arc::new(
+        db.propose(vec![BatchOp::<_, Vec<u8>>::DeletePrefix { prefix: b"k" }])
+            .await
+            .unwrap(),
+    )
+    .commit()
+    .await
+    .unwrap();
+    roots.push(TrieHash(db.root_hash().await.unwrap()));
+
+    block_in_place(|| {
+        // the keys under a dropped prefix changed, whether they were there or not
+        assert!(db.changed_since(&roots[2], b"k1").unwrap());
+        assert!(db.changed_since(&roots[2], b"k9").unwrap());
+        assert!(!db.changed_since(&roots[2], b"j1").unwrap());
+    });
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn hot_keys() {
+    let hot_keys = HotKeyConfig::builder()
+        .enabled(true)
+        .sample_rate(1)
+        .prefix_len(3)
+        .build();
+    let cfg = DbConfig::builder()
+        .truncate(true)
+        .hot_keys(hot_keys)
+        .build();
+    let db = TestDbCreator::builder()
+        .test_name("hot_keys")
+        .cfg(cfg)
+        .build()
+        .create()
+        .await;
+
+    let batch = vec![
+        BatchOp::Put {
+            key: b"acc1",
+            value: b"1".to_vec(),
+        },
+        BatchOp::Put {
+            key: b"acc2",
+            value: b"2".to_vec(),
+        },
+        BatchOp::Put {
+            key: b"sto1",
+            value: b"3".to_vec(),
+        },
+    ];
+    Arc::new(db.propose(batch).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    for _ in 0..3 {
+        rev.val(b"acc1").await.unwrap();
+    }
+    rev.val(b"sto1").await.unwrap();
+
+    assert_eq!(
+        db.hot_keys(10),
+        [
+            HotPrefix {
+                prefix: b"acc".to_vec(),
+                reads: 3,
+                writes: 2,
+            },
+            HotPrefix {
+                prefix: b"sto".to_vec(),
+                reads: 1,
+                writes: 1,
+            },
+        ]
+    );
+    assert_eq!(db.hot_keys(1).len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn op_stats() {
+    let op_stats = OpStatsConfig::builder()
+        .enabled(true)
+        .reservoir_size(4)
+        .build();
+    let cfg = DbConfig::builder()
+        .truncate(true)
+        .op_stats(op_stats)
+        .build();
+    let db = TestDbCreator::builder()
+        .test_name("op_stats")
+        .cfg(cfg)
+        .build()
+        .create()
+        .await;
+
+    let batch: Vec<_> = (0..10u8)
+        .map(|i| BatchOp::Put {
+            key: [i],
+            value: vec![i],
+        })
+        .collect();
+    Arc::new(db.propose(batch).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    for i in 0..3u8 {
+        rev.val([i]).await.unwrap();
+    }
+    rev.single_key_proof([1]).await.unwrap();
+    assert_eq!(rev.iter().unwrap().count().await, 10);
+
+    let stats = db.op_stats();
+    assert_eq!(stats.insert.count, 10);
+    assert_eq!(stats.insert.samples.len(), 4);
+    assert_eq!(stats.commit.count, 1);
+    assert_eq!(stats.get.count, 3);
+    assert_eq!(stats.prove.count, 1);
+    // the last step finds the end of the keys
+    assert_eq!(stats.iterate_next.count, 11);
+    for latency in [stats.insert, stats.commit, stats.iterate_next] {
+        assert!(latency.percentile(0.5).unwrap() <= latency.max);
+        assert!(latency.mean() <= latency.max);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn commit_without_space() {
+    let cfg = DbConfig::builder()
+        .truncate(true)
+        .min_free_space(u64::MAX / 2)
+        .build();
+    let db = TestDbCreator::builder()
+        .test_name("commit_without_space")
+        .cfg(cfg)
+        .build()
+        .create()
+        .await;
+    let root_hash = db.root_hash().await.unwrap();
+
+    let batch = vec![BatchOp::Put {
+        key: b"k",
+        value: b"v",
+    }];
+    let err = db.propose(batch).await.unwrap().commit_sync().unwrap_err();
+    assert!(matches!(err, DbError::NoSpace { needed, available } if needed > available));
+
+    // the DB stays at the revision before the commit
+    assert_eq!(db.root_hash().await.unwrap(), root_hash);
+    assert_eq!(db.counts(), TrieCounts::default());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn preallocated_store_files() {
+    let cfg = DbConfig::builder()
+        .truncate(true)
+        .payload_file_nbit(20)
+        .payload_regn_nbit(16)
+        .preallocate(true)
+        .build();
+    let path = std::env::temp_dir().join("preallocated_store_files");
+    let db = TestDbCreator::builder()
+        .test_name("preallocated_store_files")
+        .path(path.clone())
+        .cfg(cfg)
+        .build()
+        .create()
+        .await;
+
+    // the values span two files of the payload store
+    let batch: Vec<_> = (0..16u8)
+        .map(|i| BatchOp::Put {
+            key: [i],
+            value: vec![i; 1 << 16],
+        })
+        .collect();
+    db.propose(batch).await.unwrap().commit_sync().unwrap();
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    assert_eq!(rev.val([15]).await.unwrap(), Some(vec![15; 1 << 16]));
+
+    let payload_dir = path.join("merkle").join("compact");
+    for fid in 0..2 {
+        let file = payload_dir.join(format!("{fid:08x}.fw"));
+        assert_eq!(std::fs::metadata(file).unwrap().len(), 1 << 20);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn negative_cache() {
+    let negative_cache = NegativeCacheConfig::builder().enabled(true).build();
+    let cfg = DbConfig::builder()
+        .truncate(true)
+        .negative_cache(negative_cache)
+        .build();
+    let db = TestDbCreator::builder()
+        .test_name("negative_cache")
+        .cfg(cfg)
+        .build()
+        .create()
+        .await;
+
+    let put = |key: &'static [u8]| {
+        vec![BatchOp::Put {
+            key,
+            value: b"v".to_vec(),
+        }]
+    };
+    Arc::new(db.propose(put(b"k1")).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    for _ in 0..3 {
+        assert_eq!(rev.val(b"k2").await.unwrap(), None);
+    }
+    assert_eq!(rev.val(b"k1").await.unwrap(), Some(b"v".to_vec()));
+    assert_eq!(
+        db.negative_cache_stats(),
+        NegativeCacheStats {
+            hits: 2,
+            misses: 1,
+            entries: 1,
+        }
+    );
+
+    // the commit of the missing key starts the next revision over, and the past one still
+    // misses it
+    Arc::new(db.propose(put(b"k2")).await.unwrap())
+        .commit()
+        .await
+        .unwrap();
+    let latest = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    assert_eq!(latest.val(b"k2").await.unwrap(), Some(b"v".to_vec()));
+    assert_eq!(rev.val(b"k2").await.unwrap(), None);
+    let stats = db.negative_cache_stats();
+    assert_eq!(stats.entries, 0);
+    assert_eq!(stats.hits, 3);
+    assert_eq!(stats.hit_rate(), 0.75);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+async fn revision_subset() {
+    let db = TestDbCreator::builder()
+        .test_name("revision_subset")
+        .build()
+        .create()
+        .await;
+
+    let mut roots = Vec::new();
+    for batch in [
+        vec![BatchOp::Put {
+            key: b"k1",
+            value: b"1".to_vec(),
+        }],
+        vec![BatchOp::Put {
+            key: b"k2",
+            value: b"2".to_vec(),
+        }],
+        vec![BatchOp::Put {
+            key: b"k1",
+            value: b"3".to_vec(),
+        }],
+    ] {
+        db.propose(batch).await.unwrap().commit_sync().unwrap();
+        roots.push(db.root_hash().await.unwrap());
+    }
+    let revs: Vec<_> = roots
+        .iter()
+        .map(|root| db.get_revision(&TrieHash(*root)).unwrap())
+        .collect();
+
+    // adding a key keeps the keys there were, changing a value doesn't
+    assert!(revs[0].is_subset(&revs[1]).unwrap());
+    assert!(!revs[1].is_subset(&revs[0]).unwrap());
+    assert!(!revs[1].is_subset(&revs[2]).unwrap());
+    assert!(revs[2].is_subset(&revs[2]).unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn freeze_writes() {
+    let db = TestDbCreator::builder()
+        .test_name("freeze_writes")
+        .build()
+        .create()
+        .await;
+    db.propose(vec![BatchOp::Put {
+        key: b"k1",
+        value: b"1".to_vec(),
+    }])
+    .await
+    .unwrap()
+    .commit_sync()
+    .unwrap();
+    let root_hash = db.root_hash().await.unwrap();
+
+    let freeze = db.freeze_writes(Duration::from_secs(10)).await.unwrap();
+    let proposal = db
+        .propose(vec![BatchOp::Put {
+            key: b"k2",
+            value: b"2".to_vec(),
+        }])
+        .await
+        .unwrap();
+    let committing = tokio::task::spawn_blocking(move || proposal.commit_sync());
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!committing.is_finished());
+
+    // reads and proposals go on while the writes are frozen
+    assert_eq!(db.root_hash().await.unwrap(), root_hash);
+    let rev = db.revision(root_hash).await.unwrap();
+    assert_eq!(rev.val(b"k1").await.unwrap().unwrap(), b"1");
+    db.propose(vec![BatchOp::Put {
+        key: b"k1",
+        value: b"3".to_vec(),
+    }])
+    .await
+    .unwrap();
+
+    freeze.thaw();
+    committing.await.unwrap().unwrap();
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    assert_eq!(rev.val(b"k2").await.unwrap().unwrap(), b"2");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn dump_prefix() {
+    let db = TestDbCreator::builder()
+        .test_name("dump_prefix")
+        .build()
+        .create()
+        .await;
+
+    let account: Vec<_> = (0..10u8).map(|n| (vec![b'a', n], vec![n])).collect();
+    let batch = account
+        .iter()
+        .cloned()
+        .chain([(b"b".to_vec(), b"b".to_vec()), (b"`".to_vec(), vec![])])
+        .map(|(key, value)| BatchOp::Put { key, value })
+        .collect();
+    db.propose(batch).await.unwrap().commit_sync().unwrap();
+    let root_hash = db.root_hash().await.unwrap();
+    let rev = db.get_revision(&TrieHash(root_hash)).unwrap();
+
+    let pages: Vec<_> = rev
+        .dump_prefix(b"a", 4, true)
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(
+        pages
+            .iter()
+            .map(|page| page.pairs.len())
+            .collect::<Vec<_>>(),
+        [4, 4, 2]
+    );
+    for page in &pages {
+        let proof = page.proof.as_ref().unwrap();
+        for (key, value) in [page.pairs.first(), page.pairs.last()]
+            .into_iter()
+            .flatten()
+        {
+            assert_eq!(proof.verify(key, root_hash).unwrap().as_ref(), Some(value));
+        }
+    }
+    let pairs: Vec<_> = pages.into_iter().flat_map(|page| page.pairs).collect();
+    assert_eq!(pairs, account);
+
+    // a prefix no key has yields no page
+    let empty: Vec<_> = rev
+        .dump_prefix(b"c", 4, false)
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert!(empty.is_empty());
+    assert!(matches!(
+        rev.dump_prefix(b"a", 0, false).err(),
+        Some(DbError::InvalidParams)
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn write_batch() {
+    let db = TestDbCreator::builder()
+        .test_name("write_batch")
+        .build()
+        .create()
+        .await;
+    db.register_batch_validator(|batch| {
+        batch.iter().try_for_each(|op| match op {
+            BatchOp::Put { key, .. } if key.starts_with(b"reserved/") => {
+                Err(format!("{key:?} is reserved"))
+            }
+            _ => Ok(()),
+        })
+    });
+
+    // built without the DB, then sent to it
+    let mut batch = WriteBatch::new();
+    batch
+        .put(b"a", b"1")
+        .put(b"b", b"2")
+        .move_key(b"b", b"c", false)
+        .delete(b"missing");
+    let bytes = batch.serialize();
+
+    let received = WriteBatch::deserialize(&db, &bytes).unwrap();
+    assert_eq!(received, batch);
+    received.commit(&db).unwrap();
+    let rev = db.revision(db.root_hash().await.unwrap()).await.unwrap();
+    assert_eq!(rev.val(b"a").await.unwrap(), Some(b"1".to_vec()));
+    assert!(rev.val(b"b").await.unwrap().is_none());
+    assert_eq!(rev.val(b"c").await.unwrap(), Some(b"2".to_vec()));
+
+    // a batch the DB would reject fails where it is received
+    let mut reserved = WriteBatch::new();
+    reserved.put(b"reserved/a", b"1");
+    assert!(matches!(
+        WriteBatch::deserialize(&db, &reserved.serialize()),
+        Err(DbError::BatchRejected(_))
+    ));
+
+    // as do bytes that aren't a serialized batch
+    for bytes in [
+        bytes.split_last().unwrap().1,
+        &[bytes.as_slice(), b"x"].concat(),
+        b"fwwb\0",
+    ] {
+        assert!(matches!(
+            WriteBatch::deserialize(&db, bytes),
+            Err(DbError::IO(e)) if e.kind() == std::io::ErrorKind::InvalidData
+        ));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn stats_snapshot() {
+    let db = TestDbCreator::builder()
+        .test_name("stats_snapshot")
+        .build()
+        .create()
+        .await;
+
+    let before = db.stats_snapshot();
+    let batch = (0..100u8)
+        .map(|i| BatchOp::Put {
+            key: [i],
+            value: [i; 64],
+        })
+        .collect();
+    db.propose(batch).await.unwrap().commit_sync().unwrap();
+    let delta = db.stats_snapshot().diff(&before);
+
+    assert_eq!(delta.wal.records, 1);
+    assert!(delta.wal.record_bytes > 100 * 64);
+    assert!(delta.wal.pages_written > 0);
+    assert!(delta.allocator.payload_bytes > 100 * 64);
+
+    // nothing happens between two snapshots taken one after the other, but the node pools are
+    // shared with the other tests
+    let snapshot = db.stats_snapshot();
+    let mut delta = db.stats_snapshot().diff(&snapshot);
+    delta.cache.node_pool_bytes = 0;
+    assert_eq!(delta, StatsDelta::default());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn space_stats() {
+    let db = TestDbCreator::builder()
+        .test_name("space_stats")
+        .build()
+        .create()
+        .await;
+
+    // opening the DB read the headers of the trie from its files
+    let before = db.space_stats();
+    assert_eq!(before.len(), 3);
+    assert!(before.get(&MERKLE_META_STORE_ID).unwrap().syscalls > 0);
+
+    let batch = (0..100u8)
+        .map(|i| BatchOp::Put {
+            key: [i],
+            value: [i; 64],
+        })
+        .collect();
+    db.propose(batch).await.unwrap().commit_sync().unwrap();
+    let root_hash = db.root_hash().await.unwrap();
+    let after = db.space_stats();
+
+    // the nodes are written to the payload store, and the root hash to its own store
+    let written = |stats: &HashMap<_, StoreStats>, id| stats.get(&id).unwrap().bytes_written;
+    assert!(
+        written(&after, MERKLE_PAYLOAD_STORE_ID)
+            > written(&before, MERKLE_PAYLOAD_STORE_ID) + 100 * 64
+    );
+    assert!(written(&after, ROOT_HASH_STORE_ID) > written(&before, ROOT_HASH_STORE_ID));
+
+    // reading a key reads its nodes from the payload store
+    let rev = db.revision(root_hash).await.unwrap();
+    assert_eq!(rev.val([7]).await.unwrap(), Some(vec![7; 64]));
+    let read =
+        |stats: &HashMap<_, StoreStats>| stats.get(&MERKLE_PAYLOAD_STORE_ID).unwrap().bytes_read;
+    assert!(read(&db.space_stats()) > read(&after));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn kv_iter() {
+    let db = TestDbCreator::builder()
+        .test_name("kv_iter")
+        .build()
+        .create()
+        .await;
+
+    // an empty DB has no pairs
+    let pairs: Vec<_> = db
+        .kv_iter(None::<Vec<u8>>, None)
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert!(pairs.is_empty());
+
+    // more keys than a page, so that the pages are resumed
+    let keys: Vec<_> = (0..1000u16).map(u16::to_be_bytes).collect();
+    let batch = keys
+        .iter()
+        .map(|key| BatchOp::Put { key, value: key })
+        .collect();
+    db.propose(batch).await.unwrap().commit_sync().unwrap();
+
+    let range = |start: Option<u16>, end: Option<u16>| {
+        let db = &db;
+        async move {
+            db.kv_iter(start.map(u16::to_be_bytes), end.map(u16::to_be_bytes))
+                .await
+                .unwrap()
+                .map_ok(|(key, value)| {
+                    assert_eq!(*key, *value);
+                    u16::from_be_bytes((*key).try_into().unwrap())
+                })
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap()
+        }
+    };
+    assert_eq!(range(None, None).await, (0..1000).collect::<Vec<_>>());
+    assert_eq!(
+        range(Some(10), Some(700)).await,
+        (10..=700).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        range(Some(990), None).await,
+        (990..1000).collect::<Vec<_>>()
+    );
+    assert_eq!(range(None, Some(3)).await, (0..=3).collect::<Vec<_>>());
+    assert!(range(Some(5), Some(4)).await.is_empty());
+
+    // the stream keeps reading the version it started with
+    let mut pairs = db.kv_iter(None::<Vec<u8>>, None).await.unwrap();
+    let first = pairs.next().await.unwrap().unwrap();
+    let batch = vec![
+        BatchOp::Delete { key: [0u8, 1] },
+        BatchOp::Delete { key: [3, 0] },
+    ];
+    db.propose::<_, Vec<u8>>(batch)
+        .await
+        .unwrap()
+        .commit_sync()
+        .unwrap();
+    assert_eq!(*first.0, [0, 0]);
+    assert_eq!(pairs.count().await, 999);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn shared_revisions() {
+    let db = Arc::new(
+        TestDbCreator::builder()
+            .test_name("shared_revisions")
+            .build()
+            .create()
+            .await,
+    );
+
+    let mut root_hashes = Vec::new();
+    for i in 0..3u8 {
+        let batch = vec![BatchOp::Put {
+            key: [i],
+            value: [i],
+        }];
+        db.propose(batch).await.unwrap().commit_sync().unwrap();
+        root_hashes.push(db.root_hash().await.unwrap());
+    }
+
+    // the handles to a revision share it, and it is opened again once they are all dropped
+    let first = *root_hashes.first().unwrap();
+    let rev = db.revision(first).await.unwrap();
+    let same = db.revision(first).await.unwrap();
+    assert!(Arc::ptr_eq(&rev, &same));
+    let weak = Arc::downgrade(&rev);
+    drop((rev, same));
+    assert!(weak.upgrade().is_none());
+
+    // past revisions are read while the writer keeps committing
+    let readers: Vec<_> = root_hashes
+        .iter()
+        .enumerate()
+        .map(|(i, root_hash)| {
+            let (db, root_hash) = (db.clone(), *root_hash);
+            tokio::spawn(async move {
+                for _ in 0..20 {
+                    let rev = db.revision(root_hash).await.unwrap();
+                    assert_eq!(rev.val([i as u8]).await.unwrap(), Some(vec![i as u8]));
+                    assert_eq!(rev.val([i as u8 + 1]).await.unwrap(), None);
+                }
+            })
+        })
+        .collect();
+    for i in 3..10u8 {
+        let batch = vec![BatchOp::Put {
+            key: [i],
+            value: [i],
+        }];
+        db.propose(batch).await.unwrap().commit_sync().unwrap();
+    }
+    for reader in readers {
+        reader.await.unwrap();
+    }
+
+    assert!(matches!(
+        db.revision([1; 32]).await,
+        Err(api::Error::HashNotFound { .. })
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn shrink_caches() {
+    let db = TestDbCreator::builder()
+        .test_name("shrink_caches")
+        .build()
+        .create()
+        .await;
+
+    let batch = (0..500u16)
+        .map(|i| BatchOp::Put {
+            key: i.to_be_bytes(),
+            value: [0; 32],
+        })
+        .collect();
+    db.propose(batch).await.unwrap().commit_sync().unwrap();
+
+    let root_hash = db.root_hash().await.unwrap();
+    let rev = db.revision(root_hash).await.unwrap();
+    for i in 0..500u16 {
+        assert!(rev.val(i.to_be_bytes()).await.unwrap().is_some());
+    }
+    assert!(db.shrink_caches() > 0);
+    assert_eq!(db.shrink_caches(), 0);
+
+    // the nodes are read again, and cached again
+    for i in 0..500u16 {
+        assert_eq!(rev.val(i.to_be_bytes()).await.unwrap(), Some(vec![0; 32]));
+    }
+    assert!(db.shrink_caches() > 0);
 }
@@ -2,11 +2,23 @@
 // See the file LICENSE.md for licensing terms.
 
 use firewood::{
-    merkle::{Bincode, Proof, ProofError},
+    merkle::{
+        standalone::{Trie, TrieConfig},
+        Bincode, Merkle, MerkleError, Proof, ProofError,
+    },
     merkle_util::{DataStoreError, InMemoryMerkle},
+    reference::{check_order_independence, OrderError, UnorderedOps},
+    shale::{LinearStore, LinearStoreView, SendSyncDerefMut, ShaleError, StoreId},
 };
+use parking_lot::RwLock;
 use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng as _};
-use std::{collections::HashMap, fmt::Write};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Write,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
 
 fn merkle_build_test<
     K: AsRef<[u8]> + std::cmp::Ord + Clone + std::fmt::Debug,
@@ -221,6 +233,14 @@ fn test_root_hash_random_deletions() -> Result<(), DataStoreError> {
     Ok(())
 }
 
+#[test]
+fn test_root_hash_order_independence() -> Result<(), OrderError> {
+    for seed in 0..100 {
+        check_order_independence(&UnorderedOps::random(seed, 24), 8, seed)?;
+    }
+    Ok(())
+}
+
 #[test]
 #[allow(clippy::unwrap_used, clippy::indexing_slicing)]
 fn test_proof() -> Result<(), DataStoreError> {
@@ -306,6 +326,100 @@ fn test_bad_proof() -> Result<(), DataStoreError> {
     Ok(())
 }
 
+#[test]
+#[allow(clippy::indexing_slicing)]
+fn test_minimize_proof() -> Result<(), ProofError> {
+    let set = fixed_and_pseudorandom_data(500);
+    let mut items = Vec::from_iter(set.iter());
+    items.sort();
+    let merkle = merkle_build_test(items.clone(), 0x10000, 0x10000)?;
+    let root_hash = *merkle.root_hash()?;
+    let (keys, vals): (Vec<&[u8; 32]>, Vec<&[u8; 20]>) = items.into_iter().unzip();
+
+    // the proofs of many keys combined, minimized to the proof of a few of them
+    let mut combined = merkle.prove(keys[0])?;
+    for key in &keys[1..100] {
+        combined.extend(merkle.prove(key)?);
+    }
+    let wanted = [keys[3], keys[42]];
+    let minimized = combined.clone().minimize(&wanted, root_hash)?;
+    assert!(minimized.0.len() < combined.0.len());
+    for (key, val) in wanted.iter().zip([vals[3], vals[42]]) {
+        assert_eq!(minimized.verify(key, root_hash)?.as_deref(), Some(&val[..]));
+    }
+
+    // a single proof is as small as it gets
+    let proof = merkle.prove(keys[7])?;
+    assert_eq!(proof.clone().minimize(&[keys[7]], root_hash)?, proof);
+
+    // without a proof of a key, there is nothing to minimize to
+    assert!(matches!(
+        minimized.minimize(&[keys[7]], root_hash),
+        Err(ProofError::ProofNodeMissing)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_streaming_proof() -> Result<(), ProofError> {
+    let set = fixed_and_pseudorandom_data(500);
+    let mut items = Vec::from_iter(set.iter());
+    items.sort();
+    let merkle = merkle_build_test(items.clone(), 0x10000, 0x10000)?;
+    let root_hash = *merkle.root_hash()?;
+
+    let node_stream =
+        |nodes: Vec<Vec<u8>>| futures::stream::iter(nodes.into_iter().map(Ok::<_, Infallible>));
+
+    for (key, val) in items {
+        let nodes = merkle.prove_path(key)?;
+        assert!(!nodes.is_empty());
+
+        let proven = Proof::verify_streaming(key, root_hash, node_stream(nodes.clone())).await?;
+        assert_eq!(proven.as_deref(), Some(&val[..]));
+
+        // A node that doesn't match its hash is rejected as soon as it arrives.
+        let mut tampered = nodes.clone();
+        #[allow(clippy::unwrap_used)]
+        let byte = tampered
+            .last_mut()
+            .and_then(|node| node.first_mut())
+            .unwrap();
+        *byte ^= 0xff;
+        assert!(matches!(
+            Proof::verify_streaming(key, root_hash, node_stream(tampered)).await,
+            Err(ProofError::NodeHashMismatch)
+        ));
+
+        // The stream ending before the key is reached is an error too.
+        let mut truncated = nodes;
+        truncated.pop();
+        assert!(matches!(
+            Proof::verify_streaming(key, root_hash, node_stream(truncated)).await,
+            Err(ProofError::ProofNodeMissing)
+        ));
+    }
+
+    // Errors from the stream itself abort the verification.
+    let failing = futures::stream::iter([Err::<Vec<u8>, _>(std::io::Error::other("reset"))]);
+    assert!(matches!(
+        Proof::verify_streaming(b"key", root_hash, failing).await,
+        Err(ProofError::StreamError(_))
+    ));
+
+    // Missing keys can be proven.
+    let merkle = merkle_build_test(vec![("k", "v")], 0x10000, 0x10000)?;
+    let root_hash = *merkle.root_hash()?;
+    for key in ["a", "l"] {
+        let nodes = merkle.prove_path(key)?;
+        let proven = Proof::verify_streaming(key, root_hash, node_stream(nodes)).await?;
+        assert!(proven.is_none());
+    }
+
+    Ok(())
+}
+
 #[test]
 // Tests that missing keys can also be proven. The test explicitly uses a single
 // entry trie and checks for missing keys both before and after the single entry.
@@ -578,6 +692,75 @@ fn test_range_proof_with_invalid_non_existent_proof() -> Result<(), ProofError>
     Ok(())
 }
 
+#[test]
+// Tests the errors telling why a range doesn't verify, and whether keys follow it
+fn test_range_proof_errors() -> Result<(), ProofError> {
+    let items: Vec<_> = (u8::MIN..=u8::MAX)
+        .step_by(2)
+        .map(|key| (vec![key; 4], vec![key; 20]))
+        .collect();
+    let merkle = merkle_build_test(items, 0x10000, 0x10000)?;
+    // the pairs of the trie from `first` to `last`
+    let range = |first: u8, last: u8| -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        (first..=last)
+            .filter(|key| key % 2 == 0)
+            .map(|key| (vec![key; 4], vec![key; 20]))
+            .unzip()
+    };
+    let verify = |first: u8, last: u8, keys, vals| -> Result<bool, ProofError> {
+        let mut proof = merkle.prove([first; 4])?;
+        proof.extend(merkle.prove([last; 4])?);
+        merkle.verify_range_proof(&proof, vec![first; 4], vec![last; 4], keys, vals)
+    };
+
+    // valid ranges, followed by more keys or not
+    let (keys, vals) = range(10, 20);
+    assert!(verify(10, 20, keys, vals)?);
+    let (keys, vals) = range(240, 255);
+    assert!(!verify(240, 255, keys, vals)?);
+
+    // a key of the trie at an edge of the range, left out of it
+    let (keys, vals) = range(12, 20);
+    assert!(matches!(
+        verify(10, 20, keys, vals),
+        Err(ProofError::MissingKey(key)) if key == [10; 4]
+    ));
+
+    // a key the trie doesn't have at an edge of the range
+    let (mut keys, mut vals) = range(10, 20);
+    keys.push(vec![21; 4]);
+    vals.push(vec![21; 20]);
+    assert!(matches!(
+        verify(10, 21, keys, vals),
+        Err(ProofError::ExtraKey(key)) if key == [21; 4]
+    ));
+
+    // a key out of the range
+    let (keys, vals) = range(10, 22);
+    assert!(matches!(
+        verify(10, 20, keys, vals),
+        Err(ProofError::ExtraKey(key)) if key == [22; 4]
+    ));
+
+    // a gap in the middle of the range
+    let (mut keys, mut vals) = range(10, 20);
+    keys.remove(2);
+    vals.remove(2);
+    assert!(matches!(
+        verify(10, 20, keys, vals),
+        Err(ProofError::RangeMismatch)
+    ));
+
+    // an empty range over keys of the trie, and one that is really empty
+    assert!(matches!(
+        verify(11, 15, vec![], vec![]),
+        Err(ProofError::RangeMismatch)
+    ));
+    assert!(verify(11, 11, vec![], vec![])?);
+
+    Ok(())
+}
+
 #[test]
 // Tests the proof with only one element. The first edge proof can be existent one or
 // non-existent one.
@@ -1144,3 +1327,118 @@ fn decrease_key(key: &[u8; 32]) -> [u8; 32] {
     }
     new_key
 }
+
+/// A user-provided [LinearStore]: a growable byte vector shared between clones, so that a trie
+/// can be reopened on the same bytes.
+#[derive(Debug, Clone, Default)]
+struct SharedStore(Arc<RwLock<Vec<u8>>>);
+
+impl SharedStore {
+    fn grow(&self, len: usize) {
+        let mut bytes = self.0.write();
+        if bytes.len() < len {
+            bytes.resize(len, 0);
+        }
+    }
+}
+
+struct SharedStoreView(Vec<u8>);
+
+impl LinearStoreView for SharedStoreView {
+    type DerefReturn = Vec<u8>;
+
+    fn as_deref(&self) -> Self::DerefReturn {
+        self.0.clone()
+    }
+}
+
+struct SharedStoreHandle(SharedStore);
+
+impl Deref for SharedStoreHandle {
+    type Target = dyn LinearStore;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for SharedStoreHandle {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl LinearStore for SharedStore {
+    fn get_view(
+        &self,
+        offset: usize,
+        length: u64,
+    ) -> Option<Box<dyn LinearStoreView<DerefReturn = Vec<u8>>>> {
+        let end = offset + length as usize;
+        self.grow(end);
+        let bytes = self.0.read().get(offset..end)?.to_vec();
+        Some(Box::new(SharedStoreView(bytes)))
+    }
+
+    fn get_shared(&self) -> Box<dyn SendSyncDerefMut<Target = dyn LinearStore>> {
+        Box::new(SharedStoreHandle(self.clone()))
+    }
+
+    fn write(&mut self, offset: usize, change: &[u8]) -> Result<(), ShaleError> {
+        let end = offset + change.len();
+        self.grow(end);
+        #[allow(clippy::indexing_slicing)]
+        self.0.write()[offset..end].copy_from_slice(change);
+        Ok(())
+    }
+
+    fn id(&self) -> StoreId {
+        0
+    }
+
+    fn is_writeable(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn test_standalone_trie() -> Result<(), MerkleError> {
+    let (meta, data) = (SharedStore::default(), SharedStore::default());
+    let cfg = TrieConfig::builder().build();
+
+    let mut trie = Trie::<_, Bincode>::create(meta.clone(), data.clone(), &cfg)?;
+    assert_eq!(
+        trie.root_hash()?,
+        *Merkle::<SharedStore, Bincode>::empty_root()
+    );
+
+    for i in 0..100u32 {
+        trie.insert(i.to_be_bytes(), i.to_le_bytes().to_vec())?;
+    }
+    assert_eq!(
+        trie.remove(0u32.to_be_bytes())?,
+        Some(0u32.to_le_bytes().to_vec())
+    );
+
+    let root_hash = trie.root_hash()?;
+    let proof = trie.prove(42u32.to_be_bytes())?;
+    assert!(matches!(
+        proof.verify(42u32.to_be_bytes(), *root_hash),
+        Ok(Some(value)) if value == 42u32.to_le_bytes()
+    ));
+
+    trie.flush_dirty()?;
+    let sentinel_addr = trie.sentinel_addr();
+    drop(trie);
+
+    // everything needed to get the trie back is in the stores
+    let trie = Trie::<_, Bincode>::open(meta, data, sentinel_addr, &cfg)?;
+    assert_eq!(trie.root_hash()?, root_hash);
+    assert_eq!(
+        trie.get(7u32.to_be_bytes())?.as_deref(),
+        Some(&7u32.to_le_bytes()[..])
+    );
+    assert!(trie.get(0u32.to_be_bytes())?.is_none());
+
+    Ok(())
+}
@@ -5,8 +5,13 @@ use std::sync::Arc;
 
 use firewood::{
     db::{BatchOp, DbConfig},
-    v2::api::{Db, DbView, Proposal},
+    v2::{
+        api::{Db, DbView, Proposal},
+        diff::{diff, KeyDiff},
+        namespace::NamespacedDb,
+    },
 };
+use futures::StreamExt;
 
 pub mod common;
 use common::TestDbCreator;
@@ -50,3 +55,163 @@ async fn smoke() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn namespaces() -> Result<(), Box<dyn std::error::Error>> {
+    let db = TestDbCreator::builder()
+        .test_name("namespaces")
+        .build()
+        .create()
+        .await;
+
+    let keys: [&[u8]; 3] = [b"a", b"b", b"c"];
+    let alice = NamespacedDb::new(&*db, b"alice/");
+    let bob = NamespacedDb::new(&*db, b"bob/");
+
+    let batch = keys
+        .map(|key| BatchOp::Put {
+            key,
+            value: b"alice",
+        })
+        .into();
+    Arc::new(alice.propose(batch).await?).commit().await?;
+    let batch = vec![BatchOp::Put {
+        key: b"a",
+        value: b"bob",
+    }];
+    Arc::new(bob.propose(batch).await?).commit().await?;
+
+    let root_hash = db.root_hash().await?;
+    assert_eq!(alice.root_hash().await?, root_hash);
+
+    let alice_view = alice.revision(root_hash).await?;
+    let bob_view = bob.revision(root_hash).await?;
+    assert_eq!(alice_view.val(b"a").await?.unwrap(), b"alice");
+    assert_eq!(bob_view.val(b"a").await?.unwrap(), b"bob");
+    assert!(bob_view.val(b"b").await?.is_none());
+
+    // iteration is scoped to the namespace and keys are returned without the prefix
+    let alice_keys: Vec<_> = alice_view
+        .iter()?
+        .map(|kv| kv.unwrap().0)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(alice_keys, keys.map(Box::<[u8]>::from));
+
+    // range proofs stop at the end of the namespace
+    let proof = alice_view
+        .range_proof::<&[u8], Vec<u8>>(Some(b"b"), None, None)
+        .await?
+        .unwrap();
+    let middle_keys: Vec<_> = proof.middle.iter().map(|(k, _)| k.as_slice()).collect();
+    assert_eq!(middle_keys, [b"b", b"c"]);
+
+    // and so do reverse iteration and range proofs collected from the end
+    let alice_keys_rev: Vec<_> = alice_view
+        .iter_rev()?
+        .map(|kv| kv.unwrap().0)
+        .collect::<Vec<_>>()
+        .await;
+    let mut keys_rev = keys;
+    keys_rev.reverse();
+    assert_eq!(alice_keys_rev, keys_rev.map(Box::<[u8]>::from));
+    let proof = alice_view
+        .range_proof_rev::<&[u8], Vec<u8>>(None, None, Some(2))
+        .await?
+        .unwrap();
+    let middle_keys: Vec<_> = proof.middle.iter().map(|(k, _)| k.as_slice()).collect();
+    assert_eq!(middle_keys, [b"b", b"c"]);
+
+    // proofs are for the prefixed keys in the shared trie
+    let proof = bob_view.single_key_proof(b"a").await?.unwrap();
+    assert_eq!(proof.verify(b"bob/a", root_hash)?.unwrap(), b"bob");
+
+    // the namespace root is the root of a trie holding only the namespace's keys
+    let alone = TestDbCreator::builder()
+        .test_name("namespaces_alone")
+        .build()
+        .create()
+        .await;
+    let batch = keys
+        .map(|key| BatchOp::Put {
+            key,
+            value: b"alice",
+        })
+        .into();
+    Arc::new(alone.propose(batch).await?).commit().await?;
+    assert_eq!(
+        alice_view.namespace_root_hash().await?,
+        alone.root_hash().await?
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::unwrap_used)]
+async fn revision_diff() -> Result<(), Box<dyn std::error::Error>> {
+    let db = TestDbCreator::builder()
+        .test_name("revision_diff")
+        .build()
+        .create()
+        .await;
+
+    let batch: Vec<BatchOp<&[u8], &[u8]>> = vec![
+        BatchOp::Put {
+            key: b"a",
+            value: b"1",
+        },
+        BatchOp::Put {
+            key: b"b",
+            value: b"2",
+        },
+        BatchOp::Put {
+            key: b"c",
+            value: b"3",
+        },
+    ];
+    Arc::new(db.propose(batch).await?).commit().await?;
+    let old_hash = db.root_hash().await?;
+
+    let batch: Vec<BatchOp<&[u8], &[u8]>> = vec![
+        BatchOp::Delete { key: b"a" },
+        BatchOp::Put {
+            key: b"b",
+            value: b"two",
+        },
+        BatchOp::Put {
+            key: b"d",
+            value: b"4",
+        },
+    ];
+    Arc::new(db.propose(batch).await?).commit().await?;
+    let old = db.revision(old_hash).await?;
+    let new = db.revision(db.root_hash().await?).await?;
+
+    let diffs: Vec<_> = diff(&*old, &*new)?.collect().await;
+    let diffs = diffs.into_iter().collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(
+        diffs,
+        vec![
+            KeyDiff::Removed {
+                key: b"a".to_vec().into(),
+                value: b"1".to_vec(),
+            },
+            KeyDiff::Changed {
+                key: b"b".to_vec().into(),
+                old: b"2".to_vec(),
+                new: b"two".to_vec(),
+            },
+            KeyDiff::Added {
+                key: b"d".to_vec().into(),
+                value: b"4".to_vec(),
+            },
+        ]
+    );
+
+    // a revision does not differ from itself
+    assert_eq!(diff(&*new, &*new)?.count().await, 0);
+
+    Ok(())
+}
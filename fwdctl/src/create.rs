@@ -1,11 +1,56 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
-use clap::{value_parser, Args};
+use clap::{value_parser, Args, ValueEnum};
 use firewood::{
-    db::{Db, DbConfig, DbRevConfig, DiskBufferConfig, WalConfig},
+    db::{
+        AdaptiveCacheConfig, BackgroundIoConfig, Db, DbConfig, DbRevConfig, DiskBufferConfig,
+        HotKeyConfig, NegativeCacheConfig, NodeEncoding, OpStatsConfig, WalConfig,
+    },
+    shale::allocator::{Allocator, BestFit, Bump, FirstFit, NextFit, SegregatedFit},
     v2::api,
 };
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+/// The allocation strategies of the item stash, see [firewood::shale::allocator].
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum AllocatorKind {
+    NextFit,
+    FirstFit,
+    BestFit,
+    SegregatedFit,
+    Bump,
+}
+
+impl AllocatorKind {
+    fn allocator(self) -> Arc<dyn Allocator> {
+        match self {
+            AllocatorKind::NextFit => Arc::new(NextFit),
+            AllocatorKind::FirstFit => Arc::new(FirstFit),
+            AllocatorKind::BestFit => Arc::new(BestFit),
+            AllocatorKind::SegregatedFit => Arc::new(SegregatedFit),
+            AllocatorKind::Bump => Arc::new(Bump),
+        }
+    }
+}
+
+/// The formats the trie nodes can be written in, see [NodeEncoding].
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum NodeEncodingKind {
+    Native,
+    Bincode,
+    Cbor,
+}
+
+impl NodeEncodingKind {
+    const fn encoding(self) -> NodeEncoding {
+        match self {
+            NodeEncodingKind::Native => NodeEncoding::Native,
+            NodeEncodingKind::Bincode => NodeEncoding::Bincode,
+            NodeEncodingKind::Cbor => NodeEncoding::Cbor,
+        }
+    }
+}
 
 #[derive(Args)]
 pub struct Options {
@@ -83,6 +128,16 @@ pub struct Options {
     )]
     pub payload_max_walk: u64,
 
+    #[arg(
+        long,
+        required = false,
+        value_enum,
+        default_value_t = AllocatorKind::NextFit,
+        value_name = "PAYLOAD_ALLOCATOR",
+        help = "Strategy for picking the freed space trie nodes are allocated from."
+    )]
+    pub payload_allocator: AllocatorKind,
+
     #[arg(
         long,
         required = false,
@@ -93,6 +148,16 @@ pub struct Options {
     )]
     pub payload_regn_nbit: u64,
 
+    #[arg(
+        long,
+        required = false,
+        default_value_t = 0,
+        value_name = "PAYLOAD_ALIGN_NBIT",
+        help = "Alignment in bits of the trie nodes in the payload files (should be not greater than
+    PAYLOAD_REGN_NBIT), for instance 12 to start every node at a 4K block. Zero packs the nodes."
+    )]
+    pub payload_align_nbit: u64,
+
     #[arg(
         long,
         required = false,
@@ -133,6 +198,130 @@ pub struct Options {
     )]
     pub truncate: bool,
 
+    #[arg(
+        long,
+        required = false,
+        value_parser = value_parser!(bool),
+        default_missing_value = "false",
+        default_value_t = false,
+        value_name = "VERIFY_HASHES_ON_READ",
+        help = "Whether to rehash every trie node as it is read and compare it against its recorded
+    hash. Intended for debugging. [default: false]"
+    )]
+    pub verify_hashes_on_read: bool,
+
+    #[arg(
+        long,
+        required = false,
+        default_value_t = 0,
+        value_name = "INLINE_VALUE_THRESHOLD",
+        help = "Values of at most this many bytes are stored inline in their parent branch node
+    instead of in a node of their own. Zero disables inlining. [default: 0]"
+    )]
+    pub inline_value_threshold: usize,
+
+    #[arg(
+        long,
+        required = false,
+        value_enum,
+        default_value_t = NodeEncodingKind::Native,
+        value_name = "NODE_ENCODING",
+        help = "Format the trie nodes are written to the store files in."
+    )]
+    pub node_encoding: NodeEncodingKind,
+
+    #[arg(
+        long,
+        required = false,
+        value_parser = value_parser!(bool),
+        default_missing_value = "false",
+        default_value_t = false,
+        value_name = "DELAYED_ALLOCATION",
+        help = "Whether to allocate the trie nodes of a batch together once the batch is applied,
+    instead of one by one as they are created. [default: false]"
+    )]
+    pub delayed_allocation: bool,
+
+    #[arg(
+        long,
+        required = false,
+        default_value_t = 0,
+        value_name = "HOT_LEVELS",
+        help = "Number of levels of the trie, from the root down, whose nodes are kept in a hot
+    segment of the item stash of their own. Zero keeps every node in the same segment. [default: 0]"
+    )]
+    pub hot_levels: usize,
+
+    #[arg(
+        long,
+        required = false,
+        value_name = "HOT_SEGMENT_DIR",
+        help = "Directory of the store files of the hot segment, such as one on faster media. By
+    default, they are kept with the other store files of the DB."
+    )]
+    pub hot_segment_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        required = false,
+        default_value_t = 1 << 16,
+        value_name = "CACHE_MANIFEST_NOBJS",
+        help = "Maximum number of hot trie node addresses saved when the DB is closed and
+    pre-loaded when it is opened again. Zero disables the cache manifest."
+    )]
+    pub cache_manifest_nobjs: usize,
+
+    #[arg(
+        long,
+        required = false,
+        default_value_t = 0,
+        value_name = "MEMORY_BUDGET",
+        help = "Maximum bytes held by the caches and buffers of the DB. Zero means no limit."
+    )]
+    pub memory_budget: usize,
+
+    #[arg(
+        long,
+        required = false,
+        default_value_t = 0,
+        value_name = "OVERLAY_SPILL_THRESHOLD",
+        help = "Bytes of changes a proposal stages in memory above which they are spilled to
+    temporary files. Zero never spills."
+    )]
+    pub overlay_spill_threshold: usize,
+
+    #[arg(
+        long,
+        required = false,
+        value_parser = value_parser!(bool),
+        default_missing_value = "false",
+        default_value_t = false,
+        value_name = "ADAPTIVE_CACHE",
+        help = "Whether to shrink and grow the trie node caches with the memory pressure of the
+    system or cgroup. [default: false]"
+    )]
+    pub adaptive_cache: bool,
+
+    #[arg(
+        long,
+        required = false,
+        default_value_t = 16 << 20,
+        value_name = "ADAPTIVE_CACHE_MIN_BYTES",
+        help = "Minimum estimated bytes of cached trie objects when the caches adapt to memory
+    pressure."
+    )]
+    pub adaptive_cache_min_bytes: usize,
+
+    #[arg(
+        long,
+        required = false,
+        default_value_t = 1 << 30,
+        value_name = "ADAPTIVE_CACHE_MAX_BYTES",
+        help = "Maximum estimated bytes of cached trie objects when the caches adapt to memory
+    pressure."
+    )]
+    pub adaptive_cache_max_bytes: usize,
+
     /// Revision options
     #[arg(
         long,
@@ -242,9 +431,29 @@ pub struct Options {
     of the past N commits to the database."
     )]
     max_revisions: u32,
+
+    #[arg(
+        long,
+        required = false,
+        default_value_t = 0,
+        value_name = "WAL_CHECKPOINT_BYTES",
+        help = "Size of the WAL records with unwritten pages that triggers a checkpoint. Zero
+    means no threshold."
+    )]
+    checkpoint_bytes: u64,
+
+    #[arg(
+        long,
+        required = false,
+        default_value_t = 0,
+        value_name = "WAL_CHECKPOINT_RECORDS",
+        help = "Number of WAL records with unwritten pages that triggers a checkpoint. Zero
+    means no threshold."
+    )]
+    checkpoint_records: usize,
 }
 
-pub(super) const fn initialize_db_config(opts: &Options) -> DbConfig {
+pub(super) fn initialize_db_config(opts: &Options) -> DbConfig {
     DbConfig {
         meta_ncached_pages: opts.meta_ncached_pages,
         meta_ncached_files: opts.meta_ncached_files,
@@ -253,14 +462,44 @@ pub(super) const fn initialize_db_config(opts: &Options) -> DbConfig {
         payload_ncached_files: opts.payload_ncached_files,
         payload_file_nbit: opts.payload_file_nbit,
         payload_max_walk: opts.payload_max_walk,
+        payload_allocator: opts.payload_allocator.allocator(),
         payload_regn_nbit: opts.payload_regn_nbit,
+        payload_align_nbit: opts.payload_align_nbit,
         root_hash_ncached_pages: opts.payload_ncached_pages,
         root_hash_ncached_files: opts.root_hash_ncached_files,
         root_hash_file_nbit: opts.root_hash_file_nbit,
         truncate: opts.truncate,
+        read_only: false,
+        verify_hashes_on_read: opts.verify_hashes_on_read,
+        inline_value_threshold: opts.inline_value_threshold,
+        node_encoding: opts.node_encoding.encoding(),
+        delayed_allocation: opts.delayed_allocation,
+        hot_levels: opts.hot_levels,
+        hot_segment_dir: opts.hot_segment_dir.clone(),
+        free_list_index: false,
+        preallocate: false,
+        min_free_space: 0,
+        cache_manifest_nobjs: opts.cache_manifest_nobjs,
+        memory_budget: opts.memory_budget,
+        overlay_spill_threshold: opts.overlay_spill_threshold,
+        overlay_spill_dir: std::env::temp_dir(),
         rev: DbRevConfig {
             merkle_ncached_objs: opts.merkle_ncached_objs,
+            merkle_cache_bytes: None,
+        },
+        adaptive_cache: AdaptiveCacheConfig {
+            enabled: opts.adaptive_cache,
+            min_cache_bytes: opts.adaptive_cache_min_bytes,
+            max_cache_bytes: opts.adaptive_cache_max_bytes,
+            high_watermark: 0.9,
+            low_watermark: 0.75,
+            stall_threshold: 10.0,
+            interval: Duration::from_secs(1),
         },
+        hot_keys: HotKeyConfig::builder().build(),
+        op_stats: OpStatsConfig::builder().build(),
+        negative_cache: NegativeCacheConfig::builder().build(),
+        background_io: BackgroundIoConfig::builder().build(),
         buffer: DiskBufferConfig {
             max_pending: opts.max_pending,
             max_aio_requests: opts.max_aio_requests,
@@ -274,6 +513,9 @@ pub(super) const fn initialize_db_config(opts: &Options) -> DbConfig {
             file_nbit: opts.file_nbit,
             block_nbit: opts.block_nbit,
             max_revisions: opts.max_revisions,
+            checkpoint_bytes: opts.checkpoint_bytes,
+            checkpoint_records: opts.checkpoint_records,
+            preallocate: false,
         },
     }
 }
@@ -0,0 +1,212 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use clap::Args;
+use firewood::{
+    db::{Db, DbConfig, WalConfig},
+    v2::{
+        api::{self, Db as _, HashKey},
+        diff::{diff, KeyDiff},
+    },
+};
+use futures_util::StreamExt;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// The database path (if no path is provided, return an error). Defaults to firewood.
+    #[arg(
+        required = true,
+        value_name = "DB_NAME",
+        default_value_t = String::from("firewood"),
+        help = "Name of the database"
+    )]
+    pub db: String,
+
+    /// The root hash of the revision to compare from
+    #[arg(
+        required = true,
+        value_name = "ROOT_A",
+        value_parser = root_parser,
+        help = "Hex encoded root hash of the old revision"
+    )]
+    pub root_a: HashKey,
+
+    /// The root hash of the revision to compare to
+    #[arg(
+        required = true,
+        value_name = "ROOT_B",
+        value_parser = root_parser,
+        help = "Hex encoded root hash of the new revision"
+    )]
+    pub root_b: HashKey,
+
+    /// Print the values, hex encoded, next to their sizes
+    #[arg(long, required = false, help = "Print the values in hex")]
+    pub hexdump: bool,
+
+    /// Print the differences as a JSON document
+    #[arg(long, required = false, help = "Output JSON")]
+    pub json: bool,
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    added: usize,
+    added_bytes: usize,
+    removed: usize,
+    removed_bytes: usize,
+    changed: usize,
+    changed_old_bytes: usize,
+    changed_new_bytes: usize,
+}
+
+impl Summary {
+    const fn add(&mut self, key_diff: &KeyDiff) {
+        match key_diff {
+            KeyDiff::Added { value, .. } => {
+                self.added += 1;
+                self.added_bytes += value.len();
+            }
+            KeyDiff::Removed { value, .. } => {
+                self.removed += 1;
+                self.removed_bytes += value.len();
+            }
+            KeyDiff::Changed { old, new, .. } => {
+                self.changed += 1;
+                self.changed_old_bytes += old.len();
+                self.changed_new_bytes += new.len();
+            }
+        }
+    }
+}
+
+pub(super) async fn run(opts: &Options) -> Result<(), api::Error> {
+    log::debug!("diff database {:?}", opts);
+    let cfg = DbConfig::builder()
+        .truncate(false)
+        .wal(WalConfig::builder().max_revisions(10).build());
+
+    let db = Db::new(opts.db.clone(), &cfg.build()).await?;
+    let old = db.revision(opts.root_a).await?;
+    let new = db.revision(opts.root_b).await?;
+
+    let mut summary = Summary::default();
+    let mut stream = diff(&*old, &*new)?;
+
+    if opts.json {
+        println!("{{\"diffs\":[");
+    }
+    let mut first = true;
+    while let Some(key_diff) = stream.next().await {
+        let key_diff = key_diff?;
+        summary.add(&key_diff);
+
+        if opts.json {
+            let separator = if first { "" } else { "," };
+            println!("{separator}{}", diff_to_json(&key_diff, opts.hexdump));
+        } else {
+            println!("{}", diff_to_text(&key_diff, opts.hexdump));
+        }
+        first = false;
+    }
+
+    if opts.json {
+        println!("],\"summary\":{}}}", summary_to_json(&summary));
+    } else {
+        println!(
+            "added: {} keys ({} bytes), removed: {} keys ({} bytes), changed: {} keys ({} -> {} bytes)",
+            summary.added,
+            summary.added_bytes,
+            summary.removed,
+            summary.removed_bytes,
+            summary.changed,
+            summary.changed_old_bytes,
+            summary.changed_new_bytes,
+        );
+    }
+
+    Ok(())
+}
+
+fn diff_to_text(key_diff: &KeyDiff, hexdump: bool) -> String {
+    let key = String::from_utf8_lossy(key_diff.key());
+    match key_diff {
+        KeyDiff::Added { value, .. } if hexdump => {
+            format!("+ '{key}' ({} bytes): {}", value.len(), hex::encode(value))
+        }
+        KeyDiff::Added { value, .. } => format!("+ '{key}' ({} bytes)", value.len()),
+        KeyDiff::Removed { value, .. } if hexdump => {
+            format!("- '{key}' ({} bytes): {}", value.len(), hex::encode(value))
+        }
+        KeyDiff::Removed { value, .. } => format!("- '{key}' ({} bytes)", value.len()),
+        KeyDiff::Changed { old, new, .. } if hexdump => format!(
+            "~ '{key}' ({} -> {} bytes): {} -> {}",
+            old.len(),
+            new.len(),
+            hex::encode(old),
+            hex::encode(new)
+        ),
+        KeyDiff::Changed { old, new, .. } => {
+            format!("~ '{key}' ({} -> {} bytes)", old.len(), new.len())
+        }
+    }
+}
+
+fn diff_to_json(key_diff: &KeyDiff, hexdump: bool) -> String {
+    let key = hex::encode(key_diff.key());
+    match key_diff {
+        KeyDiff::Added { value, .. } | KeyDiff::Removed { value, .. } => {
+            let op = if matches!(key_diff, KeyDiff::Added { .. }) {
+                "added"
+            } else {
+                "removed"
+            };
+            let value_field = if hexdump {
+                format!(",\"value\":\"{}\"", hex::encode(value))
+            } else {
+                String::new()
+            };
+            format!(
+                "{{\"op\":\"{op}\",\"key\":\"{key}\",\"size\":{}{value_field}}}",
+                value.len()
+            )
+        }
+        KeyDiff::Changed { old, new, .. } => {
+            let value_fields = if hexdump {
+                format!(
+                    ",\"old_value\":\"{}\",\"new_value\":\"{}\"",
+                    hex::encode(old),
+                    hex::encode(new)
+                )
+            } else {
+                String::new()
+            };
+            format!(
+                "{{\"op\":\"changed\",\"key\":\"{key}\",\"old_size\":{},\"new_size\":{}{value_fields}}}",
+                old.len(),
+                new.len()
+            )
+        }
+    }
+}
+
+fn summary_to_json(summary: &Summary) -> String {
+    format!(
+        "{{\"added\":{},\"added_bytes\":{},\"removed\":{},\"removed_bytes\":{},\"changed\":{},\"changed_old_bytes\":{},\"changed_new_bytes\":{}}}",
+        summary.added,
+        summary.added_bytes,
+        summary.removed,
+        summary.removed_bytes,
+        summary.changed,
+        summary.changed_old_bytes,
+        summary.changed_new_bytes,
+    )
+}
+
+pub(super) fn root_parser(s: &str) -> Result<HashKey, String> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| format!("expected 32 bytes, got {}", bytes.len()))
+}
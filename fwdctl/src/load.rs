@@ -0,0 +1,185 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, ErrorKind, Read},
+    sync::Arc,
+};
+
+use clap::{Args, ValueEnum};
+use firewood::{
+    db::{BatchOp, Db, DbConfig, WalConfig},
+    v2::api::{self, Db as _, Proposal},
+};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// One `key,value` pair per line, split at the first comma, without quoting
+    Csv,
+    /// One `{"key": ..., "value": ...}` object per line
+    Jsonl,
+    /// Records of a key and a value, each prefixed with its length as a 32-bit little-endian
+    /// integer
+    Binary,
+}
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// The database path (if no path is provided, return an error). Defaults to firewood.
+    #[arg(
+        required = true,
+        value_name = "DB_NAME",
+        default_value_t = String::from("firewood"),
+        help = "Name of the database"
+    )]
+    pub db: String,
+
+    /// The file to load the key/value pairs from
+    #[arg(required = true, value_name = "FILE", help = "File to load")]
+    pub file: String,
+
+    /// The format of the file
+    #[arg(long, value_enum, default_value_t = Format::Csv, help = "Format of the file")]
+    pub format: Format,
+
+    /// The number of key/value pairs committed at once
+    #[arg(
+        long,
+        default_value_t = 10000,
+        value_name = "BATCH_SIZE",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Number of key/value pairs per commit"
+    )]
+    pub batch_size: u64,
+
+    /// Whether the keys and values of a CSV or JSON lines file are hex encoded
+    #[arg(long, required = false, help = "Decode the keys and values from hex")]
+    pub hex: bool,
+}
+
+#[derive(Deserialize)]
+struct JsonRecord {
+    key: String,
+    value: String,
+}
+
+type KeyValue = (Vec<u8>, Vec<u8>);
+
+pub(super) async fn run(opts: &Options) -> Result<(), api::Error> {
+    log::debug!("loading key value pairs {:?}", opts);
+    let cfg = DbConfig::builder()
+        .truncate(false)
+        .wal(WalConfig::builder().max_revisions(10).build());
+
+    let db = Db::new(opts.db.clone(), &cfg.build()).await?;
+
+    let file = BufReader::new(File::open(&opts.file).map_err(api::Error::IO)?);
+    let records: Box<dyn Iterator<Item = io::Result<KeyValue>>> = match opts.format {
+        Format::Csv => Box::new(text_records(file, opts.hex, parse_csv)),
+        Format::Jsonl => Box::new(text_records(file, opts.hex, parse_json)),
+        Format::Binary => Box::new(BinaryRecords(file)),
+    };
+
+    let mut batch = Vec::new();
+    let mut total = 0;
+    for record in records {
+        let (key, value) = record.map_err(api::Error::IO)?;
+        batch.push(BatchOp::Put { key, value });
+
+        if batch.len() as u64 == opts.batch_size {
+            total += commit(&db, std::mem::take(&mut batch)).await?;
+        }
+    }
+    if !batch.is_empty() {
+        total += commit(&db, batch).await?;
+    }
+
+    log::info!("loaded {total} key value pairs");
+    let root = db.root_hash().await?;
+    println!("{root:X?}");
+    Ok(())
+}
+
+async fn commit(db: &Db, batch: Vec<BatchOp<Vec<u8>, Vec<u8>>>) -> Result<usize, api::Error> {
+    let len = batch.len();
+    let proposal = Arc::new(db.propose(batch).await?);
+    proposal.commit().await?;
+    log::debug!("committed {len} key value pairs");
+    Ok(len)
+}
+
+/// Parses the non-empty lines of `input` with `parse`, which gets the line and returns the key and
+/// the value, still hex encoded if `hex` is set.
+fn text_records<R: BufRead>(
+    input: R,
+    hex: bool,
+    parse: fn(&str) -> Result<(String, String), String>,
+) -> impl Iterator<Item = io::Result<KeyValue>> {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(move |(n, line)| {
+            let invalid =
+                |e: String| io::Error::new(ErrorKind::InvalidData, format!("line {}: {e}", n + 1));
+            let (key, value) = parse(&line?).map_err(invalid)?;
+            if hex {
+                let decode = |s: String| hex::decode(s).map_err(|e| invalid(e.to_string()));
+                Ok((decode(key)?, decode(value)?))
+            } else {
+                Ok((key.into_bytes(), value.into_bytes()))
+            }
+        })
+}
+
+fn parse_csv(line: &str) -> Result<(String, String), String> {
+    line.split_once(',')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| "expected a key and a value separated by a comma".to_string())
+}
+
+fn parse_json(line: &str) -> Result<(String, String), String> {
+    let record: JsonRecord = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    Ok((record.key, record.value))
+}
+
+/// Reads length-prefixed key/value records until the end of the input.
+struct BinaryRecords<R>(R);
+
+impl<R: Read> BinaryRecords<R> {
+    /// Reads a length-prefixed field, or returns `None` if the input ends before the length.
+    fn field(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut len = [0; 4];
+        match self.0.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let mut data = vec![0; u32::from_le_bytes(len) as usize];
+        self.0.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+}
+
+impl<R: Read> Iterator for BinaryRecords<R> {
+    type Item = io::Result<KeyValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = match self.field().transpose()? {
+            Ok(key) => key,
+            Err(e) => return Some(Err(e)),
+        };
+        let record = match self.field() {
+            Ok(Some(value)) => Ok((key, value)),
+            Ok(None) => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "key without a value at the end of the file",
+            )),
+            Err(e) => Err(e),
+        };
+        Some(record)
+    }
+}
@@ -6,10 +6,15 @@ use firewood::v2::api;
 
 pub mod create;
 pub mod delete;
+pub mod diff;
 pub mod dump;
 pub mod get;
 pub mod insert;
+pub mod load;
+pub mod prove;
 pub mod root;
+pub mod roots;
+pub mod verify;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -42,8 +47,18 @@ enum Commands {
     Delete(delete::Options),
     /// Display key/value trie root hash
     Root(root::Options),
+    /// List the retained revisions, with their root hashes
+    Roots(roots::Options),
     /// Dump contents of key/value store
     Dump(dump::Options),
+    /// Display the keys that differ between two revisions
+    Diff(diff::Options),
+    /// Load key/value pairs from a file into the database
+    Load(load::Options),
+    /// Write a proof of the value of a key to a file
+    Prove(prove::Options),
+    /// Check a proof written by prove against a root hash
+    Verify(verify::Options),
 }
 
 #[tokio::main]
@@ -61,6 +76,11 @@ async fn main() -> Result<(), api::Error> {
         Commands::Get(opts) => get::run(opts).await,
         Commands::Delete(opts) => delete::run(opts).await,
         Commands::Root(opts) => root::run(opts).await,
+        Commands::Roots(opts) => roots::run(opts).await,
         Commands::Dump(opts) => dump::run(opts).await,
+        Commands::Diff(opts) => diff::run(opts).await,
+        Commands::Load(opts) => load::run(opts).await,
+        Commands::Prove(opts) => prove::run(opts).await,
+        Commands::Verify(opts) => verify::run(opts).await,
     }
 }
@@ -0,0 +1,77 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use crate::diff::root_parser;
+use clap::Args;
+use firewood::{
+    db::{Db, DbConfig, WalConfig},
+    v2::api::{self, Db as _, DbView, HashKey},
+};
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// The database path (if no path is provided, return an error). Defaults to firewood.
+    #[arg(
+        required = true,
+        value_name = "DB_NAME",
+        default_value_t = String::from("firewood"),
+        help = "Name of the database"
+    )]
+    pub db: String,
+
+    /// The key to prove
+    #[arg(required = true, value_name = "KEY", help = "Key to prove")]
+    pub key: String,
+
+    /// The root hash of the revision to prove the key in. Defaults to the latest revision.
+    #[arg(
+        long,
+        required = false,
+        value_name = "ROOT",
+        value_parser = root_parser,
+        help = "Hex encoded root hash of the revision"
+    )]
+    pub root: Option<HashKey>,
+
+    /// The file the proof is written to
+    #[arg(
+        long,
+        short = 'o',
+        required = false,
+        value_name = "FILE",
+        default_value_t = String::from("proof.json"),
+        help = "File to write the proof to"
+    )]
+    pub output: String,
+}
+
+pub(super) async fn run(opts: &Options) -> Result<(), api::Error> {
+    log::debug!("prove key {:?}", opts);
+    let cfg = DbConfig::builder()
+        .truncate(false)
+        .wal(WalConfig::builder().max_revisions(10).build());
+
+    let db = Db::new(opts.db.clone(), &cfg.build()).await?;
+    let root = match opts.root {
+        Some(root) => root,
+        None => db.root_hash().await?,
+    };
+    let rev = db.revision(root).await?;
+    let proof = rev
+        .single_key_proof(opts.key.as_bytes())
+        .await?
+        .ok_or(api::Error::HashNotFound { provided: root })?;
+
+    // the nodes are sorted so that the same proof always gives the same file
+    let mut nodes: Vec<_> = proof.0.into_values().map(hex::encode).collect();
+    nodes.sort_unstable();
+    let document = serde_json::json!({
+        "root_hash": hex::encode(root),
+        "key": hex::encode(&opts.key),
+        "nodes": nodes,
+    });
+    std::fs::write(&opts.output, document.to_string()).map_err(api::Error::IO)?;
+
+    println!("{}", hex::encode(root));
+    Ok(())
+}
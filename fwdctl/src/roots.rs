@@ -0,0 +1,78 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use clap::Args;
+use firewood::{
+    db::{Db, DbConfig, RevisionEntry, WalConfig},
+    v2::api,
+};
+use std::time::UNIX_EPOCH;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// The database path (if no path is provided, return an error). Defaults to firewood.
+    #[arg(
+        required = true,
+        value_name = "DB_NAME",
+        default_value_t = String::from("firewood"),
+        help = "Name of the database"
+    )]
+    pub db: String,
+
+    /// Print the revisions as a JSON document
+    #[arg(long, required = false, help = "Output JSON")]
+    pub json: bool,
+}
+
+pub(super) async fn run(opts: &Options) -> Result<(), api::Error> {
+    log::debug!("list revisions {:?}", opts);
+    let cfg = DbConfig::builder()
+        .truncate(false)
+        .wal(WalConfig::builder().max_revisions(10).build());
+
+    let db = Db::new(opts.db.clone(), &cfg.build()).await?;
+    let entries = db.revision_index()?;
+
+    if opts.json {
+        let entries: Vec<_> = entries.iter().map(entry_to_json).collect();
+        println!("{}", serde_json::Value::Array(entries));
+        return Ok(());
+    }
+
+    println!("index\troot hash\tcommitted at\tsize delta\tannotation");
+    for entry in &entries {
+        let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            entry.index,
+            hex::encode(*entry.root_hash),
+            or_dash(
+                committed_at_millis(entry)
+                    .map(|millis| { format!("{}.{:03}", millis / 1000, millis % 1000) })
+            ),
+            or_dash(entry.size_delta.map(|delta| format!("{delta:+}"))),
+            or_dash(entry.annotation.clone()),
+        );
+    }
+    Ok(())
+}
+
+/// When the revision was committed, in milliseconds since the Unix epoch.
+fn committed_at_millis(entry: &RevisionEntry) -> Option<u128> {
+    entry
+        .committed_at
+        .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_millis())
+}
+
+fn entry_to_json(entry: &RevisionEntry) -> serde_json::Value {
+    serde_json::json!({
+        "index": entry.index,
+        "root_hash": hex::encode(*entry.root_hash),
+        "committed_at_ms": committed_at_millis(entry).map(|millis| millis as u64),
+        "keys": entry.counts.map(|counts| counts.keys),
+        "value_bytes": entry.counts.map(|counts| counts.value_bytes),
+        "size_delta": entry.size_delta,
+        "annotation": entry.annotation,
+    })
+}
@@ -0,0 +1,99 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use crate::diff::root_parser;
+use clap::Args;
+use firewood::{
+    merkle::{Proof, TrieHash},
+    v2::api::{self, HashKey},
+};
+use serde::Deserialize;
+use std::io::{self, ErrorKind};
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// The proof file written by `fwdctl prove`
+    #[arg(
+        required = true,
+        value_name = "PROOF_FILE",
+        help = "Proof file to verify"
+    )]
+    pub proof: String,
+
+    /// The root hash the proof is checked against
+    #[arg(
+        long,
+        required = true,
+        value_name = "ROOT",
+        value_parser = root_parser,
+        help = "Hex encoded root hash to verify against"
+    )]
+    pub root: HashKey,
+
+    /// The key the proof is for
+    #[arg(long, required = true, value_name = "KEY", help = "Key to verify")]
+    pub key: String,
+
+    /// The value the key is expected to have. Without it, the proven value is printed.
+    #[arg(
+        long,
+        required = false,
+        value_name = "VALUE",
+        help = "Hex encoded value to expect"
+    )]
+    pub value: Option<String>,
+}
+
+/// The parts of a proof file `fwdctl verify` reads. The root hash and the key recorded next to
+/// the nodes are informational: the proof is always checked against the ones given on the
+/// command line.
+#[derive(Deserialize)]
+struct ProofFile {
+    nodes: Vec<String>,
+}
+
+pub(super) async fn run(opts: &Options) -> Result<(), api::Error> {
+    log::debug!("verify proof {:?}", opts);
+    let invalid = |message: String| api::Error::IO(io::Error::new(ErrorKind::InvalidData, message));
+
+    let file = std::fs::read(&opts.proof).map_err(api::Error::IO)?;
+    let file: ProofFile =
+        serde_json::from_slice(&file).map_err(|e| invalid(format!("malformed proof file: {e}")))?;
+
+    // the nodes are keyed by their own hash, so that a node can't be passed off as another
+    let mut proof = Proof(Default::default());
+    for node in file.nodes {
+        let node = hex::decode(node).map_err(|e| invalid(format!("malformed proof node: {e}")))?;
+        proof.0.insert(TrieHash::of(&node).0, node);
+    }
+
+    let expected = opts
+        .value
+        .as_deref()
+        .map(|value| hex::decode(value.strip_prefix("0x").unwrap_or(value)))
+        .transpose()
+        .map_err(|e| invalid(format!("malformed value: {e}")))?;
+
+    let proven = proof
+        .verify(opts.key.as_bytes(), opts.root)
+        .map_err(|e| invalid(format!("invalid proof: {e}")))?;
+
+    match (expected, proven) {
+        (None, Some(value)) => println!("{}", hex::encode(value)),
+        (None, None) => println!("Key '{}' not found", opts.key),
+        (Some(expected), Some(value)) if expected == value => println!("valid"),
+        (Some(_), Some(value)) => {
+            return Err(invalid(format!(
+                "value mismatch: the proof is for {}",
+                hex::encode(value)
+            )))
+        }
+        (Some(_), None) => {
+            return Err(invalid(format!(
+                "value mismatch: the proof is for the absence of '{}'",
+                opts.key
+            )))
+        }
+    }
+    Ok(())
+}
@@ -110,6 +110,40 @@ fn fwdctl_get_successful() -> Result<()> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn fwdctl_hot_segment_dir() -> Result<()> {
+    let hot_segment_dir = tmpdb::path().with_extension("hot");
+    Command::cargo_bin(PRG)?
+        .arg("create")
+        .args(["--hot-levels", "2", "--hot-segment-dir"])
+        .args([&hot_segment_dir, &tmpdb::path()])
+        .assert()
+        .success();
+
+    Command::cargo_bin(PRG)?
+        .arg("insert")
+        .args(["year", "2023", "--db"])
+        .args([tmpdb::path()])
+        .assert()
+        .success();
+
+    // the upper levels were moved to the hot segment, which is found without being passed again
+    assert!(hot_segment_dir.read_dir()?.next().is_some());
+    Command::cargo_bin(PRG)?
+        .arg("get")
+        .args(["year", "--db"])
+        .args([tmpdb::path()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("2023"));
+
+    fwdctl_delete_db().map_err(|e| anyhow!(e))?;
+    remove_dir_all(hot_segment_dir)?;
+
+    Ok(())
+}
+
 #[test]
 #[serial]
 fn fwdctl_delete_successful() -> Result<()> {
@@ -209,6 +243,283 @@ fn fwdctl_dump() -> Result<()> {
     Ok(())
 }
 
+// Returns the hex encoded root hash of the database, as printed by `fwdctl root`
+fn fwdctl_root() -> Result<String> {
+    let output = Command::cargo_bin(PRG)?
+        .arg("root")
+        .args(["--db"])
+        .args([tmpdb::path()])
+        .output()?;
+
+    String::from_utf8(output.stdout)?
+        .trim()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .split(", ")
+        .map(|byte| Ok(format!("{:02x}", u8::from_str_radix(byte, 16)?)))
+        .collect()
+}
+
+#[test]
+#[serial]
+fn fwdctl_diff() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .arg("create")
+        .arg(tmpdb::path())
+        .assert()
+        .success();
+
+    for (key, value) in [("year", "2023"), ("month", "10")] {
+        Command::cargo_bin(PRG)?
+            .arg("insert")
+            .args([key, value])
+            .args(["--db"])
+            .args([tmpdb::path()])
+            .assert()
+            .success();
+    }
+    let old_root = fwdctl_root()?;
+
+    Command::cargo_bin(PRG)?
+        .arg("insert")
+        .args(["year", "2024"])
+        .args(["--db"])
+        .args([tmpdb::path()])
+        .assert()
+        .success();
+    Command::cargo_bin(PRG)?
+        .arg("delete")
+        .args(["month"])
+        .args(["--db"])
+        .args([tmpdb::path()])
+        .assert()
+        .success();
+    let new_root = fwdctl_root()?;
+
+    Command::cargo_bin(PRG)?
+        .arg("diff")
+        .args([tmpdb::path()])
+        .args([&old_root, &new_root])
+        .arg("--hexdump")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("- 'month' (2 bytes): 3130"))
+        .stdout(predicate::str::contains(
+            "~ 'year' (4 -> 4 bytes): 32303233 -> 32303234",
+        ))
+        .stdout(predicate::str::contains(
+            "added: 0 keys (0 bytes), removed: 1 keys (2 bytes), changed: 1 keys (4 -> 4 bytes)",
+        ));
+
+    Command::cargo_bin(PRG)?
+        .arg("diff")
+        .args([tmpdb::path()])
+        .args([&old_root, &new_root])
+        .arg("--json")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            r#"{"op":"removed","key":"6d6f6e7468","size":2}"#,
+        ))
+        .stdout(predicate::str::contains(r#""summary":{"added":0,"#));
+
+    fwdctl_delete_db().map_err(|e| anyhow!(e))?;
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+#[allow(clippy::indexing_slicing)]
+fn fwdctl_roots() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .arg("create")
+        .arg(tmpdb::path())
+        .assert()
+        .success();
+
+    let mut roots = Vec::new();
+    for (key, value) in [("year", "2023"), ("month", "10")] {
+        Command::cargo_bin(PRG)?
+            .arg("insert")
+            .args([key, value])
+            .args(["--db"])
+            .args([tmpdb::path()])
+            .assert()
+            .success();
+        roots.push(fwdctl_root()?);
+    }
+
+    // the latest revision comes first
+    Command::cargo_bin(PRG)?
+        .arg("roots")
+        .args([tmpdb::path()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!("0\t{}\t", roots[1])))
+        .stdout(predicate::str::contains(format!("1\t{}\t", roots[0])))
+        .stdout(predicate::str::contains("\t+2\t-"));
+
+    Command::cargo_bin(PRG)?
+        .arg("roots")
+        .args([tmpdb::path()])
+        .arg("--json")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            r#""index":0,"keys":2,"root_hash":"{}","size_delta":2"#,
+            roots[1]
+        )));
+
+    fwdctl_delete_db().map_err(|e| anyhow!(e))?;
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn fwdctl_load() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .arg("create")
+        .arg(tmpdb::path())
+        .assert()
+        .success();
+
+    let csv = tmpdb::path().with_extension("csv");
+    std::fs::write(&csv, "year,2023\nmonth,10\n\nday,31\n")?;
+    Command::cargo_bin(PRG)?
+        .arg("load")
+        .args([tmpdb::path(), csv.clone()])
+        .args(["--batch-size", "2"])
+        .assert()
+        .success();
+    let root = fwdctl_root()?;
+
+    // the same pairs, hex encoded, give the same root hash
+    let jsonl = tmpdb::path().with_extension("jsonl");
+    std::fs::write(
+        &jsonl,
+        r#"{"key":"79656172","value":"32303234"}
+{"key":"79656172","value":"32303233"}
+"#,
+    )?;
+    Command::cargo_bin(PRG)?
+        .arg("load")
+        .args([tmpdb::path(), jsonl.clone()])
+        .args(["--format", "jsonl", "--hex"])
+        .assert()
+        .success();
+    assert_eq!(fwdctl_root()?, root);
+
+    let mut binary = Vec::new();
+    for field in [&b"week"[..], b"44"] {
+        binary.extend((field.len() as u32).to_le_bytes());
+        binary.extend(field);
+    }
+    let bin = tmpdb::path().with_extension("bin");
+    std::fs::write(&bin, &binary)?;
+    Command::cargo_bin(PRG)?
+        .arg("load")
+        .args([tmpdb::path(), bin.clone()])
+        .args(["--format", "binary"])
+        .assert()
+        .success();
+    Command::cargo_bin(PRG)?
+        .arg("get")
+        .args(["week"])
+        .args(["--db"])
+        .args([tmpdb::path()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("44"));
+
+    // a malformed line is reported
+    std::fs::write(&csv, "a,1\nb\n")?;
+    Command::cargo_bin(PRG)?
+        .arg("load")
+        .args([tmpdb::path(), csv.clone()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("line 2"));
+
+    for file in [csv, jsonl, bin] {
+        std::fs::remove_file(file)?;
+    }
+    fwdctl_delete_db().map_err(|e| anyhow!(e))?;
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn fwdctl_prove_verify() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .arg("create")
+        .arg(tmpdb::path())
+        .assert()
+        .success();
+
+    for (key, value) in [("year", "2023"), ("month", "10")] {
+        Command::cargo_bin(PRG)?
+            .arg("insert")
+            .args([key, value])
+            .args(["--db"])
+            .args([tmpdb::path()])
+            .assert()
+            .success();
+    }
+    let root = fwdctl_root()?;
+
+    let proof = tmpdb::path().with_extension("proof");
+    Command::cargo_bin(PRG)?
+        .arg("prove")
+        .args([tmpdb::path()])
+        .args(["year"])
+        .args(["--root", &root])
+        .arg("--output")
+        .arg(&proof)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(&root));
+
+    // "2023", hex encoded
+    Command::cargo_bin(PRG)?
+        .arg("verify")
+        .arg(&proof)
+        .args(["--root", &root, "--key", "year", "--value", "32303233"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("valid"));
+    Command::cargo_bin(PRG)?
+        .arg("verify")
+        .arg(&proof)
+        .args(["--root", &root, "--key", "year"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("32303233"));
+
+    // the wrong value, or another root hash, is rejected
+    Command::cargo_bin(PRG)?
+        .arg("verify")
+        .arg(&proof)
+        .args(["--root", &root, "--key", "year", "--value", "32303234"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("value mismatch"));
+    Command::cargo_bin(PRG)?
+        .arg("verify")
+        .arg(&proof)
+        .args(["--root", &"00".repeat(32), "--key", "year"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid proof"));
+
+    std::fs::remove_file(proof)?;
+    fwdctl_delete_db().map_err(|e| anyhow!(e))?;
+
+    Ok(())
+}
+
 // A module to create a temporary database name for use in
 // tests. The directory will be one of:
 // - cargo's compile-time CARGO_TARGET_TMPDIR, if that exists
@@ -54,6 +54,7 @@ pub mod walerror;
 use async_trait::async_trait;
 use std::fs;
 use std::io::SeekFrom;
+use std::os::fd::AsRawFd;
 use std::path::{Path, PathBuf};
 use tokio::{
     fs::{File, OpenOptions},
@@ -81,25 +82,29 @@ impl RawWalFile {
 
 pub struct WalFileImpl {
     file_mutex: Mutex<RawWalFile>,
+    preallocate: bool,
 }
 
 impl From<RawWalFile> for WalFileImpl {
     fn from(file: RawWalFile) -> Self {
         let file = Mutex::new(file);
-        Self { file_mutex: file }
+        Self {
+            file_mutex: file,
+            preallocate: false,
+        }
     }
 }
 
 #[async_trait(?Send)]
 impl WalFile for WalFileImpl {
     async fn allocate(&self, offset: WalPos, length: usize) -> Result<(), WalError> {
-        self.file_mutex
-            .lock()
-            .await
-            .0
-            .set_len(offset + length as u64)
-            .await
-            .map_err(Into::into)
+        let file = &self.file_mutex.lock().await.0;
+        file.set_len(offset + length as u64).await?;
+        if self.preallocate {
+            nix::fcntl::posix_fallocate(file.as_raw_fd(), offset as i64, length as i64)
+                .map_err(std::io::Error::from)?;
+        }
+        Ok(())
     }
 
     async fn truncate(&self, len: usize) -> Result<(), WalError> {
@@ -138,6 +143,7 @@ impl WalFile for WalFileImpl {
 
 pub struct WalStoreImpl {
     root_dir: PathBuf,
+    preallocate: bool,
 }
 
 impl WalStoreImpl {
@@ -156,8 +162,17 @@ impl WalStoreImpl {
 
         Ok(WalStoreImpl {
             root_dir: wal_dir.as_ref().to_path_buf(),
+            preallocate: false,
         })
     }
+
+    /// Allocates the blocks of the records on disk before writing them, rather than leaving
+    /// holes in the files for the writes to fill, so that running out of space fails the
+    /// allocation instead of the writes.
+    pub const fn with_preallocation(mut self, preallocate: bool) -> Self {
+        self.preallocate = preallocate;
+        self
+    }
 }
 
 #[async_trait(?Send)]
@@ -169,7 +184,10 @@ impl WalStore<WalFileImpl> for WalStoreImpl {
 
         let file = RawWalFile::open(path).await?;
 
-        Ok(file.into())
+        Ok(WalFileImpl {
+            file_mutex: Mutex::new(file),
+            preallocate: self.preallocate,
+        })
     }
 
     async fn remove_file(&self, filename: String) -> Result<(), WalError> {
@@ -190,6 +208,7 @@ impl WalStore<WalFileImpl> for WalStoreImpl {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::wal::{RecoverPolicy, WalLoadReport, WalLoader};
 
     #[tokio::test]
     async fn truncation_makes_a_file_smaller() {
@@ -252,6 +271,33 @@ mod tests {
         assert_eq!(result, Some(vec![0u8; LENGTH].into()))
     }
 
+    #[tokio::test]
+    async fn preallocation_allocates_the_blocks() {
+        use std::os::unix::fs::MetadataExt;
+        const LENGTH: usize = 1 << 16;
+
+        let walfile_path = get_temp_walfile_path(file!(), line!());
+
+        tokio::fs::remove_file(&walfile_path).await.ok();
+
+        #[allow(clippy::unwrap_used)]
+        let walfile = RawWalFile::open(&walfile_path).await.unwrap();
+
+        let walfile_impl = WalFileImpl {
+            file_mutex: Mutex::new(walfile),
+            preallocate: true,
+        };
+
+        #[allow(clippy::unwrap_used)]
+        walfile_impl.allocate(0, LENGTH).await.unwrap();
+
+        #[allow(clippy::unwrap_used)]
+        let metadata = tokio::fs::metadata(&walfile_path).await.unwrap();
+        assert_eq!(metadata.len(), LENGTH as u64);
+        // the blocks are counted in units of 512 bytes
+        assert!(metadata.blocks() * 512 >= LENGTH as u64);
+    }
+
     #[tokio::test]
     async fn write_and_read_full() {
         let walfile = {
@@ -360,6 +406,151 @@ mod tests {
         assert_eq!(result, Some(data.into()));
     }
 
+    #[tokio::test]
+    #[allow(clippy::unwrap_used)]
+    async fn load_reports_replayed_records() {
+        let wal_dir = get_temp_walfile_path(file!(), line!());
+        let mut loader = WalLoader::new();
+        loader.file_nbit(9).block_nbit(8);
+
+        let store = WalStoreImpl::new(&wal_dir, true).unwrap();
+        let mut wal = loader.load(store, |_, _| Ok(()), 0).await.unwrap();
+        for f in wal.grow(vec!["foo", "bar", "foobar"]) {
+            f.await.unwrap();
+        }
+        // reading the records back waits for the writes in flight
+        wal.read_recent_records(3, &RecoverPolicy::Strict)
+            .await
+            .unwrap();
+        drop(wal);
+
+        let store = WalStoreImpl::new(&wal_dir, false).unwrap();
+        let (_, report) = loader
+            .load_with_report(store, |_, _| Ok(()), 0)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            report,
+            WalLoadReport {
+                nrecords: 3,
+                truncated_tail: false
+            }
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::unwrap_used)]
+    async fn load_reports_a_truncated_tail() {
+        let wal_dir = get_temp_walfile_path(file!(), line!());
+        let mut loader = WalLoader::new();
+        loader.file_nbit(9).block_nbit(8);
+
+        let store = WalStoreImpl::new(&wal_dir, true).unwrap();
+        let mut wal = loader.load(store, |_, _| Ok(()), 0).await.unwrap();
+        // the second record doesn't fit in the first block, so it is split in two chunks
+        let long_record = "a".repeat(400);
+        for f in wal.grow(vec!["foo", long_record.as_str()]) {
+            f.await.unwrap();
+        }
+        // reading the records back waits for the writes in flight
+        wal.read_recent_records(2, &RecoverPolicy::Strict)
+            .await
+            .unwrap();
+        drop(wal);
+
+        // lose the header of the chunk in the second block, as if the Wal write was torn
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(wal_dir.join("00000000.log"))
+            .unwrap();
+        std::io::Seek::seek(&mut file, SeekFrom::Start(1 << 8)).unwrap();
+        std::io::Write::write_all(&mut file, &[0; 16]).unwrap();
+        drop(file);
+
+        let mut replayed = Vec::new();
+        let store = WalStoreImpl::new(&wal_dir, false).unwrap();
+        let (_, report) = loader
+            .load_with_report(
+                store,
+                |payload, _| {
+                    replayed.push(payload);
+                    Ok(())
+                },
+                0,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(replayed, [Box::from(*b"foo")]);
+        assert_eq!(
+            report,
+            WalLoadReport {
+                nrecords: 1,
+                truncated_tail: true
+            }
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::unwrap_used)]
+    async fn load_skips_the_replay() {
+        let wal_dir = get_temp_walfile_path(file!(), line!());
+        let mut loader = WalLoader::new();
+        loader.file_nbit(9).block_nbit(8);
+
+        let store = WalStoreImpl::new(&wal_dir, true).unwrap();
+        let mut wal = loader.load(store, |_, _| Ok(()), 0).await.unwrap();
+        for f in wal.grow(vec!["foo", "bar"]) {
+            f.await.unwrap();
+        }
+        wal.read_recent_records(2, &RecoverPolicy::Strict)
+            .await
+            .unwrap();
+        drop(wal);
+
+        let mut replayed = Vec::new();
+        let store = WalStoreImpl::new(&wal_dir, false).unwrap();
+        let (mut wal, report) = loader
+            .skip_replay(true)
+            .load_with_report(
+                store,
+                |payload, _| {
+                    replayed.push(payload);
+                    Ok(())
+                },
+                0,
+            )
+            .await
+            .unwrap();
+        assert!(replayed.is_empty());
+        assert_eq!(report, WalLoadReport::default());
+
+        // writing resumes after the records that were skipped
+        for f in wal.grow(vec!["baz"]) {
+            f.await.unwrap();
+        }
+        wal.read_recent_records(1, &RecoverPolicy::Strict)
+            .await
+            .unwrap();
+        drop(wal);
+
+        let store = WalStoreImpl::new(&wal_dir, false).unwrap();
+        loader
+            .skip_replay(false)
+            .load(
+                store,
+                |payload, _| {
+                    replayed.push(payload);
+                    Ok(())
+                },
+                0,
+            )
+            .await
+            .unwrap();
+        assert_eq!(replayed, [Box::from(*b"baz")]);
+    }
+
     #[allow(clippy::unwrap_used)]
     fn get_temp_walfile_path(file: &str, line: u32) -> PathBuf {
         let path = option_env!("CARGO_TARGET_TMPDIR")
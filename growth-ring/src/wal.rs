@@ -2,7 +2,7 @@
 // See the file LICENSE.md for licensing terms.
 
 use async_trait::async_trait;
-use bytemuck::{cast_slice, Pod, Zeroable};
+use bytemuck::{Pod, Zeroable};
 use crc32fast::Hasher;
 use futures::{
     future::{self, FutureExt, TryFutureExt},
@@ -46,6 +46,40 @@ struct WalRingBlob {
     // payload follows
 }
 
+impl WalRingBlob {
+    const SIZE: usize = std::mem::size_of::<Self>();
+
+    /// The header as it is written in a block, with its integers in little-endian.
+    fn to_le_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        #[allow(clippy::indexing_slicing)]
+        {
+            bytes[0..4].copy_from_slice(&{ self.counter }.to_le_bytes());
+            bytes[4..8].copy_from_slice(&{ self.crc32 }.to_le_bytes());
+            bytes[8..12].copy_from_slice(&{ self.rsize }.to_le_bytes());
+            bytes[12] = self.rtype;
+        }
+        bytes
+    }
+
+    /// Reads a header written by [Self::to_le_bytes], `None` if `bytes` is too short.
+    fn from_le_bytes(bytes: &[u8]) -> Option<Self> {
+        let u32_at = |at: usize| {
+            bytes
+                .get(at..at + 4)?
+                .try_into()
+                .ok()
+                .map(u32::from_le_bytes)
+        };
+        Some(Self {
+            counter: u32_at(0)?,
+            crc32: u32_at(4)?,
+            rsize: u32_at(8)?,
+            rtype: *bytes.get(12)?,
+        })
+    }
+}
+
 type WalFileId = u64;
 pub type WalBytes = Box<[u8]>;
 pub type WalPos = u64;
@@ -109,6 +143,17 @@ struct Header {
 
 const HEADER_SIZE: usize = std::mem::size_of::<Header>();
 
+impl Header {
+    const fn to_le_bytes(self) -> [u8; HEADER_SIZE] {
+        self.recover_fid.to_le_bytes()
+    }
+
+    fn from_le_bytes(bytes: &[u8]) -> Option<Self> {
+        let recover_fid = u64::from_le_bytes(bytes.get(..HEADER_SIZE)?.try_into().ok()?);
+        Some(Self { recover_fid })
+    }
+}
+
 #[repr(C)]
 #[derive(Eq, PartialEq, Copy, Clone, Debug, Hash)]
 pub struct WalRingId {
@@ -282,16 +327,12 @@ impl<F: WalFile + 'static, S: WalStore<F>> WalFilePool<F, S> {
             .read(0, HEADER_SIZE)
             .await?
             .ok_or(WalError::Other("EOF".to_string()))?;
-        let slice = cast_slice::<_, Header>(&bytes);
-        slice
-            .first()
-            .copied()
-            .ok_or(WalError::Other("short read".to_string()))
+        Header::from_le_bytes(&bytes).ok_or(WalError::Other("short read".to_string()))
     }
 
     async fn write_header(&self, header: Header) -> Result<(), WalError> {
         self.header_file
-            .write(0, cast_slice(&[header]).into())
+            .write(0, header.to_le_bytes().into())
             .await?;
         Ok(())
     }
@@ -552,13 +593,8 @@ impl<F: WalFile + 'static, S: WalStore<F>> WalWriter<F, S> {
                     let d = remain - msize;
                     let rs0 = self.state.next + (bbuff_cur - bbuff_start) as u64;
 
-                    #[allow(unsafe_code)]
-                    let blob = unsafe {
-                        #[allow(clippy::indexing_slicing)]
-                        &mut *self.block_buffer[bbuff_cur as usize..]
-                            .as_mut_ptr()
-                            .cast::<WalRingBlob>()
-                    };
+                    let blob_start = bbuff_cur as usize;
+                    let mut blob = WalRingBlob::zeroed();
 
                     bbuff_cur += msize;
 
@@ -583,6 +619,9 @@ impl<F: WalFile + 'static, S: WalStore<F>> WalWriter<F, S> {
 
                         blob.rtype = rt as u8;
                         #[allow(clippy::indexing_slicing)]
+                        self.block_buffer[blob_start..blob_start + WalRingBlob::SIZE]
+                            .copy_from_slice(&blob.to_le_bytes());
+                        #[allow(clippy::indexing_slicing)]
                         self.block_buffer[bbuff_cur as usize..bbuff_cur as usize + payload.len()]
                             .copy_from_slice(payload);
                         bbuff_cur += rsize;
@@ -616,6 +655,9 @@ impl<F: WalFile + 'static, S: WalStore<F>> WalWriter<F, S> {
                             WalRingType::First
                         } as u8;
 
+                        #[allow(clippy::indexing_slicing)]
+                        self.block_buffer[blob_start..blob_start + WalRingBlob::SIZE]
+                            .copy_from_slice(&blob.to_le_bytes());
                         #[allow(clippy::indexing_slicing)]
                         self.block_buffer[bbuff_cur as usize..bbuff_cur as usize + payload.len()]
                             .copy_from_slice(payload);
@@ -869,11 +911,23 @@ pub enum RecoverPolicy {
     BestEffort,
 }
 
+/// What [WalLoader::load_with_report] found while replaying the Wal files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WalLoadReport {
+    /// Number of records passed to the recover function.
+    pub nrecords: usize,
+    /// Whether the replay stopped at a record that was only partially written, or that is
+    /// corrupted under [RecoverPolicy::BestEffort]. That record, and whatever follows it, is
+    /// discarded.
+    pub truncated_tail: bool,
+}
+
 pub struct WalLoader {
     file_nbit: u64,
     block_nbit: u64,
     cache_size: NonZeroUsize,
     recover_policy: RecoverPolicy,
+    skip_replay: bool,
 }
 
 impl Default for WalLoader {
@@ -884,6 +938,7 @@ impl Default for WalLoader {
             block_nbit: 15, // 32KB,
             cache_size: NonZeroUsize::new(16).unwrap(),
             recover_policy: RecoverPolicy::Strict,
+            skip_replay: false,
         }
     }
 }
@@ -913,6 +968,14 @@ impl WalLoader {
         self
     }
 
+    /// Don't pass the records written since the Wal was last loaded to the recover function,
+    /// because whatever they hold is known to be applied already. The files are still scanned to
+    /// resume writing after the last record.
+    pub const fn skip_replay(&mut self, v: bool) -> &mut Self {
+        self.skip_replay = v;
+        self
+    }
+
     fn verify_checksum_(data: &[u8], checksum: u32, p: &RecoverPolicy) -> Result<bool, WalError> {
         let mut hasher = Hasher::new();
         hasher.update(data);
@@ -1005,8 +1068,7 @@ impl WalLoader {
                     None => _yield!(),
                 };
                 v.off += msize as u64;
-                let header: &[WalRingBlob] = cast_slice(&header_raw);
-                let header = *header.first()?;
+                let header = WalRingBlob::from_le_bytes(&header_raw)?;
 
                 let payload;
                 match WalRingType::from_repr(header.rtype as usize) {
@@ -1115,7 +1177,7 @@ impl WalLoader {
                     };
                     let ringid_start = (fid << file_nbit) + v.off;
                     v.off += msize as u64;
-                    let header: WalRingBlob = *cast_slice(&header_raw).first()?;
+                    let header = WalRingBlob::from_le_bytes(&header_raw)?;
                     let rsize = header.rsize;
                     match WalRingType::from_repr(header.rtype as usize) {
                         Some(WalRingType::Full) => {
@@ -1214,9 +1276,25 @@ impl WalLoader {
     >(
         &self,
         store: S,
-        mut recover_func: Func,
+        recover_func: Func,
         keep_nrecords: u32,
     ) -> Result<WalWriter<F, S>, WalError> {
+        self.load_with_report(store, recover_func, keep_nrecords)
+            .await
+            .map(|(writer, _)| writer)
+    }
+
+    /// Like [WalLoader::load], also reporting what was found while replaying the Wal files.
+    pub async fn load_with_report<
+        F: WalFile + 'static,
+        S: WalStore<F>,
+        Func: FnMut(WalBytes, WalRingId) -> Result<(), WalError>,
+    >(
+        &self,
+        store: S,
+        mut recover_func: Func,
+        keep_nrecords: u32,
+    ) -> Result<(WalWriter<F, S>, WalLoadReport), WalError> {
         let msize = std::mem::size_of::<WalRingBlob>();
         assert!(self.file_nbit > self.block_nbit);
         assert!(msize < 1 << self.block_nbit);
@@ -1237,12 +1315,13 @@ impl WalLoader {
         let mut pre_skip = true;
         let mut scanned: Vec<(String, WalFileHandle<F, S>)> = Vec::new();
         let mut counter = 0;
+        let mut report = WalLoadReport::default();
 
         // TODO: check for missing logfiles
         'outer: for (_, fid) in logfiles.into_iter() {
             let fname = get_fname(fid);
             let f = file_pool.get_file(fid, false).await?;
-            if header.recover_fid == fid {
+            if header.recover_fid == fid && !self.skip_replay {
                 pre_skip = false;
             }
             if pre_skip {
@@ -1260,17 +1339,24 @@ impl WalLoader {
                                     "error loading from storage".to_string(),
                                 ));
                             } else {
+                                report.truncated_tail = true;
                                 break 'outer;
                             }
                         }
                         Ok(t) => t,
                     };
                     recover_func(bytes, ring_id)?;
+                    report.nrecords += 1;
                 }
             }
             scanned.push((fname, f));
         }
 
+        // the last record was only partially written
+        if chunks.is_some() {
+            report.truncated_tail = true;
+        }
+
         'outer: for (_, f) in scanned.iter().rev() {
             let records: Vec<_> = Self::read_rings(f, false, self.block_nbit, &self.recover_policy)
                 .collect()
@@ -1325,7 +1411,7 @@ impl WalLoader {
             end: next,
             counter,
         };
-        Ok(WalWriter::new(
+        let writer = WalWriter::new(
             WalState {
                 counter,
                 next_complete,
@@ -1335,7 +1421,9 @@ impl WalLoader {
                 pending_removal,
             },
             file_pool,
-        ))
+        );
+
+        Ok((writer, report))
     }
 }
 
@@ -1360,4 +1448,43 @@ mod test {
             Ok(val) => assert_eq!(got.unwrap(), val),
         }
     }
+
+    #[test]
+    fn headers_are_little_endian() {
+        let blob = WalRingBlob {
+            counter: 0x0102_0304,
+            crc32: 0xdead_beef,
+            rsize: 0x10,
+            rtype: WalRingType::Full as u8,
+        };
+        let bytes = blob.to_le_bytes();
+        assert_eq!(
+            bytes,
+            [4, 3, 2, 1, 0xef, 0xbe, 0xad, 0xde, 0x10, 0, 0, 0, 1]
+        );
+        let read = WalRingBlob::from_le_bytes(&bytes).unwrap();
+        assert_eq!(
+            ({ read.counter }, { read.crc32 }, { read.rsize }, read.rtype),
+            (0x0102_0304, 0xdead_beef, 0x10, 1)
+        );
+        // the bytes a big-endian host would have written with a transmute don't read back
+        let mut swapped = bytes;
+        swapped[..4].reverse();
+        assert_ne!(
+            { WalRingBlob::from_le_bytes(&swapped).unwrap().counter },
+            0x0102_0304
+        );
+        assert!(WalRingBlob::from_le_bytes(&bytes[..12]).is_none());
+
+        let header = Header {
+            recover_fid: 0x0a0b,
+        };
+        assert_eq!(header.to_le_bytes(), [0x0b, 0x0a, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            Header::from_le_bytes(&header.to_le_bytes())
+                .unwrap()
+                .recover_fid,
+            0x0a0b
+        );
+    }
 }
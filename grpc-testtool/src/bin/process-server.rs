@@ -7,8 +7,8 @@ use env_logger::Builder;
 use log::{info, LevelFilter};
 use rpc::{
     process_server::process_server_service_server::ProcessServerServiceServer,
-    rpcdb::database_server::DatabaseServer as RpcServer, sync::db_server::DbServer as SyncServer,
-    DatabaseService,
+    rpcdb::database_server::DatabaseServer as RpcServer, service::fairness::FairnessConfig,
+    sync::db_server::DbServer as SyncServer, DatabaseService,
 };
 use serde::Deserialize;
 use std::{
@@ -25,6 +25,9 @@ use tonic::transport::Server;
 struct Options {
     #[serde(default = "Options::history_length_default")]
     history_length: u32,
+    /// Rate limits the requests of each tenant, see [rpc::service::fairness].
+    #[serde(default)]
+    fairness: Option<FairnessConfig>,
 }
 
 impl Options {
@@ -89,15 +92,16 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // log to the file and to stderr
     info!("Starting up: Listening on {}", args.grpc_port);
 
-    let svc = Arc::new(
-        DatabaseService::new(
-            args.db_dir,
-            args.config
-                .map(|o| o.history_length)
-                .unwrap_or_else(Options::history_length_default),
-        )
-        .await?,
-    );
+    let history_length = args
+        .config
+        .as_ref()
+        .map(|o| o.history_length)
+        .unwrap_or_else(Options::history_length_default);
+    let mut svc = DatabaseService::new(args.db_dir, history_length).await?;
+    if let Some(fairness) = args.config.and_then(|o| o.fairness) {
+        svc = svc.with_fairness(fairness);
+    }
+    let svc = Arc::new(svc);
 
     // TODO: graceful shutdown
     Ok(Server::builder()
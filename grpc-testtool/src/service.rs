@@ -15,10 +15,13 @@ use std::{
     },
 };
 use tokio::sync::Mutex;
-use tonic::Status;
+use tonic::{Request, Status};
+
+use fairness::{Fairness, FairnessConfig, RequestKind};
 
 pub mod database;
 pub mod db;
+pub mod fairness;
 pub mod process;
 
 trait IntoStatusResultExt<T> {
@@ -44,6 +47,7 @@ impl<T> IntoStatusResultExt<T> for Result<T, Error> {
 pub struct Database {
     db: Db,
     iterators: Arc<Mutex<Iterators>>,
+    fairness: Option<Fairness>,
 }
 
 impl Database {
@@ -62,8 +66,15 @@ impl Database {
         Ok(Self {
             db,
             iterators: Default::default(),
+            fairness: None,
         })
     }
+
+    /// Schedules the requests of the clients by tenant, see [fairness].
+    pub fn with_fairness(mut self, config: FairnessConfig) -> Self {
+        self.fairness = Some(Fairness::new(config));
+        self
+    }
 }
 
 impl Deref for Database {
@@ -79,6 +90,14 @@ impl Database {
         let root_hash = self.root_hash().await?;
         self.revision(root_hash).await
     }
+
+    /// Waits until `request` can be served under the fairness between tenants, if any.
+    async fn admit<T>(&self, request: &Request<T>, kind: RequestKind) -> Result<(), Status> {
+        match &self.fairness {
+            Some(fairness) => fairness.admit(request, kind).await,
+            None => Ok(()),
+        }
+    }
 }
 
 // TODO: implement Iterator
@@ -1,7 +1,7 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
-use super::{Database as DatabaseService, IntoStatusResultExt, Iter};
+use super::{Database as DatabaseService, IntoStatusResultExt, Iter, RequestKind};
 use crate::rpcdb::{
     database_server::Database, CloseRequest, CloseResponse, CompactRequest, CompactResponse,
     DeleteRequest, DeleteResponse, GetRequest, GetResponse, HasRequest, HasResponse,
@@ -17,6 +17,8 @@ use tonic::{async_trait, Request, Response, Status};
 #[async_trait]
 impl Database for DatabaseService {
     async fn has(&self, request: Request<HasRequest>) -> Result<Response<HasResponse>, Status> {
+        self.admit(&request, RequestKind::Read).await?;
+
         let key = request.into_inner().key;
         let revision = self.latest().await.into_status_result()?;
 
@@ -31,6 +33,8 @@ impl Database for DatabaseService {
     }
 
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        self.admit(&request, RequestKind::Read).await?;
+
         let key = request.into_inner().key;
         let revision = self.latest().await.into_status_result()?;
 
@@ -53,6 +57,8 @@ impl Database for DatabaseService {
     }
 
     async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        self.admit(&request, RequestKind::Commit).await?;
+
         let PutRequest { key, value } = request.into_inner();
         let batch = BatchOp::Put { key, value };
         let proposal = Arc::new(self.db.propose(vec![batch]).await.into_status_result()?);
@@ -65,6 +71,8 @@ impl Database for DatabaseService {
         &self,
         request: Request<DeleteRequest>,
     ) -> Result<Response<DeleteResponse>, Status> {
+        self.admit(&request, RequestKind::Commit).await?;
+
         let DeleteRequest { key } = request.into_inner();
         let batch = BatchOp::<_, Vec<u8>>::Delete { key };
         let propoal = Arc::new(self.db.propose(vec![batch]).await.into_status_result()?);
@@ -99,6 +107,8 @@ impl Database for DatabaseService {
         &self,
         request: Request<WriteBatchRequest>,
     ) -> Result<Response<WriteBatchResponse>, Status> {
+        self.admit(&request, RequestKind::Commit).await?;
+
         let WriteBatchRequest { puts, deletes } = request.into_inner();
         let batch = puts
             .into_iter()
@@ -115,6 +125,8 @@ impl Database for DatabaseService {
         &self,
         request: Request<NewIteratorWithStartAndPrefixRequest>,
     ) -> Result<Response<NewIteratorWithStartAndPrefixResponse>, Status> {
+        self.admit(&request, RequestKind::Read).await?;
+
         let NewIteratorWithStartAndPrefixRequest {
             start: _,
             prefix: _,
@@ -1,7 +1,7 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
-use super::{Database, IntoStatusResultExt};
+use super::{Database, IntoStatusResultExt, RequestKind};
 use crate::sync::{
     db_server::Db as DbServerTrait, CommitChangeProofRequest, CommitRangeProofRequest,
     GetChangeProofRequest, GetChangeProofResponse, GetMerkleRootResponse, GetProofRequest,
@@ -16,8 +16,9 @@ impl DbServerTrait for Database {
     #[tracing::instrument(level = "trace")]
     async fn get_merkle_root(
         &self,
-        _request: Request<()>,
+        request: Request<()>,
     ) -> Result<Response<GetMerkleRootResponse>, Status> {
+        self.admit(&request, RequestKind::Read).await?;
         let root_hash = self.db.root_hash().await.into_status_result()?.to_vec();
 
         let response = GetMerkleRootResponse { root_hash };
@@ -30,6 +31,8 @@ impl DbServerTrait for Database {
         &self,
         request: Request<GetProofRequest>,
     ) -> Result<Response<GetProofResponse>, Status> {
+        self.admit(&request, RequestKind::Prove).await?;
+
         let GetProofRequest { key: _ } = request.into_inner();
         let _revision = self.latest().await.into_status_result()?;
 
@@ -41,6 +44,8 @@ impl DbServerTrait for Database {
         &self,
         request: Request<GetChangeProofRequest>,
     ) -> Result<Response<GetChangeProofResponse>, Status> {
+        self.admit(&request, RequestKind::Prove).await?;
+
         let GetChangeProofRequest {
             start_root_hash: _,
             end_root_hash: _,
@@ -59,6 +64,8 @@ impl DbServerTrait for Database {
         &self,
         request: Request<VerifyChangeProofRequest>,
     ) -> Result<Response<VerifyChangeProofResponse>, Status> {
+        self.admit(&request, RequestKind::Prove).await?;
+
         let VerifyChangeProofRequest {
             proof: _,
             start_key: _,
@@ -76,6 +83,8 @@ impl DbServerTrait for Database {
         &self,
         request: Request<CommitChangeProofRequest>,
     ) -> Result<Response<()>, Status> {
+        self.admit(&request, RequestKind::Commit).await?;
+
         let CommitChangeProofRequest { proof: _ } = request.into_inner();
 
         todo!()
@@ -86,6 +95,8 @@ impl DbServerTrait for Database {
         &self,
         request: Request<GetRangeProofRequest>,
     ) -> Result<Response<GetRangeProofResponse>, Status> {
+        self.admit(&request, RequestKind::Prove).await?;
+
         let GetRangeProofRequest {
             root_hash: _,
             start_key: _,
@@ -101,6 +112,8 @@ impl DbServerTrait for Database {
         &self,
         request: Request<CommitRangeProofRequest>,
     ) -> Result<Response<()>, Status> {
+        self.admit(&request, RequestKind::Commit).await?;
+
         let CommitRangeProofRequest {
             start_key: _,
             range_proof: _,
@@ -0,0 +1,221 @@
+// Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Fairness between the clients of a shared server: every tenant has its own token bucket, which
+//! refills at a rate proportional to the weight of the tenant, and each request takes the
+//! tokens its kind costs before it is served. A tenant that sends more than its share only waits
+//! on its own bucket, so it can't starve the others.
+//!
+//! A request that finds the bucket of its tenant short of tokens takes them anyway, leaving the
+//! bucket in debt, and waits for the debt to be refilled. The requests of a tenant are so
+//! served in the order they arrive, and a request that would wait longer than the maximum wait
+//! is turned away instead.
+
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tonic::{Request, Status};
+
+/// The metadata key the tenant of a request is read from. The requests without one are all
+/// from the same, anonymous, tenant.
+pub const TENANT_HEADER: &str = "x-firewood-tenant";
+
+/// The kinds of requests, by what they cost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Read,
+    Prove,
+    Commit,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FairnessConfig {
+    /// Tokens per second the bucket of a tenant of weight 1 refills with.
+    pub rate: f64,
+    /// Tokens the bucket of a tenant of weight 1 holds at most, and starts with.
+    pub burst: f64,
+    /// The weights of the tenants, by name.
+    pub weights: HashMap<String, f64>,
+    /// The weight of the tenants not in `weights`.
+    pub default_weight: f64,
+    pub read_cost: f64,
+    pub prove_cost: f64,
+    pub commit_cost: f64,
+    /// The longest a request waits for tokens before it is turned away, in milliseconds.
+    pub max_wait_ms: u64,
+}
+
+impl Default for FairnessConfig {
+    fn default() -> Self {
+        Self {
+            rate: 1000.0,
+            burst: 1000.0,
+            weights: HashMap::new(),
+            default_weight: 1.0,
+            read_cost: 1.0,
+            prove_cost: 4.0,
+            commit_cost: 10.0,
+            max_wait_ms: 1000,
+        }
+    }
+}
+
+impl FairnessConfig {
+    const fn cost(&self, kind: RequestKind) -> f64 {
+        match kind {
+            RequestKind::Read => self.read_cost,
+            RequestKind::Prove => self.prove_cost,
+            RequestKind::Commit => self.commit_cost,
+        }
+    }
+
+    fn weight(&self, tenant: &str) -> f64 {
+        self.weights
+            .get(tenant)
+            .copied()
+            .unwrap_or(self.default_weight)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    /// Takes `cost` tokens at `now`, returning how long to wait until the bucket is out of
+    /// debt, or `None` if that's longer than `max_wait`, in which case nothing is taken.
+    fn take(
+        &mut self,
+        now: Instant,
+        cost: f64,
+        rate: f64,
+        burst: f64,
+        max_wait: Duration,
+    ) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.refilled = now;
+
+        let debt = cost - self.tokens;
+        let wait = if debt > 0.0 {
+            Duration::try_from_secs_f64(debt / rate).ok()?
+        } else {
+            Duration::ZERO
+        };
+        if wait > max_wait {
+            return None;
+        }
+        self.tokens -= cost;
+        Some(wait)
+    }
+}
+
+/// The token buckets of the tenants of a server, see the [module documentation](self).
+#[derive(Debug)]
+pub struct Fairness {
+    config: FairnessConfig,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Fairness {
+    pub fn new(config: FairnessConfig) -> Self {
+        Self {
+            config,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes the tokens of a request of `kind` from the bucket of `tenant` at `now`, see
+    /// [Bucket::take].
+    fn reserve(&self, tenant: &str, kind: RequestKind, now: Instant) -> Option<Duration> {
+        let weight = self.config.weight(tenant);
+        let rate = self.config.rate * weight;
+        let burst = self.config.burst * weight;
+        let max_wait = Duration::from_millis(self.config.max_wait_ms);
+
+        #[allow(clippy::unwrap_used)]
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(tenant.to_string()).or_insert(Bucket {
+            tokens: burst,
+            refilled: now,
+        });
+        bucket.take(now, self.config.cost(kind), rate, burst, max_wait)
+    }
+
+    /// Waits until `request` can be served, or fails with [Status::resource_exhausted] if its
+    /// tenant is too far over its share.
+    pub async fn admit<T>(&self, request: &Request<T>, kind: RequestKind) -> Result<(), Status> {
+        let tenant = request
+            .metadata()
+            .get(TENANT_HEADER)
+            .and_then(|tenant| tenant.to_str().ok())
+            .unwrap_or_default();
+
+        let wait = self.reserve(tenant, kind, Instant::now()).ok_or_else(|| {
+            Status::resource_exhausted(format!("tenant {tenant:?} is rate limited"))
+        })?;
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenants_wait_on_their_own_bucket() {
+        let fairness = Fairness::new(FairnessConfig {
+            rate: 10.0,
+            burst: 10.0,
+            weights: [("heavy".to_string(), 2.0)].into(),
+            max_wait_ms: 1500,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        // the burst is served right away, the requests after it wait for the refill
+        for _ in 0..10 {
+            assert_eq!(
+                fairness.reserve("noisy", RequestKind::Read, now),
+                Some(Duration::ZERO)
+            );
+        }
+        let wait = fairness.reserve("noisy", RequestKind::Commit, now).unwrap();
+        assert_eq!(wait, Duration::from_secs(1));
+        // waiting longer than the maximum turns the request away, and takes nothing
+        assert_eq!(fairness.reserve("noisy", RequestKind::Commit, now), None);
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            fairness.reserve("noisy", RequestKind::Read, later),
+            Some(Duration::from_millis(100))
+        );
+
+        // the other tenants still have their burst, in proportion to their weight
+        assert_eq!(
+            fairness.reserve("quiet", RequestKind::Commit, now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            fairness.reserve("heavy", RequestKind::Commit, now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            fairness.reserve("heavy", RequestKind::Commit, now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            fairness.reserve("heavy", RequestKind::Prove, now),
+            Some(Duration::from_millis(200))
+        );
+    }
+}